@@ -0,0 +1,54 @@
+//! Tokenizing time for input that's mostly comments or blank space, where the `simd` feature's
+//! skipping pays off, and for ordinary code, where it shouldn't cost anything. Compare the
+//! two builds by saving a baseline from one and measuring the other against it:
+//!
+//! ```text
+//! cargo bench --bench lexer -- --save-baseline plain
+//! cargo bench --bench lexer --features simd -- --baseline plain
+//! ```
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_compiler::lexer::tokenize_with_spans;
+use std::hint::black_box;
+
+/// A function with a statement per line, repeated `count` times, each line preceded by
+/// `filler`
+fn program(count: usize, filler: &str) -> String {
+    let mut source = String::new();
+    for index in 0..count {
+        source.push_str(filler);
+        source.push_str(&format!("int f{}(int x) {{\n", index));
+        for line in ["int y = x * 3 + 1;\n", "while (y > x) { y--; }\n"] {
+            source.push_str(filler);
+            source.push_str("    ");
+            source.push_str(line);
+        }
+        source.push_str(filler);
+        source.push_str("    return y;\n}\n");
+    }
+    source
+}
+
+fn lexer(c: &mut Criterion) {
+    let line_comment = "// ".to_string() + &"a line comment, ".repeat(8) + "\n";
+    let block_comment = "/* ".to_string() + &"a block comment\n * ".repeat(6) + "*/\n";
+    let blank = " ".repeat(40) + "\n\t\t\t\t\n\n" + &" ".repeat(24);
+    let inputs = [
+        ("code", program(200, "")),
+        ("line-comments", program(200, &line_comment)),
+        ("block-comments", program(200, &block_comment)),
+        ("blank", program(200, &blank)),
+    ];
+
+    let mut group = c.benchmark_group("tokenize");
+    for (name, source) in &inputs {
+        group.throughput(Throughput::Bytes(source.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), source, |b, source| {
+            b.iter(|| tokenize_with_spans(black_box(source)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lexer);
+criterion_main!(benches);
@@ -0,0 +1,1268 @@
+//! Translation of the checked AST into portable C99, for `--emit=c`.
+//!
+//! Runs after semantic analysis, on the desugared program before it's lowered, so that the
+//! output stays close to the source and can serve as an oracle for the rest of the compiler. Ints are `int32_t` and chars `unsigned char`, which compare like C0's, and strings
+//! are `const char *`. C leaves signed overflow undefined, so int arithmetic goes through small
+//! helpers that wrap around, and dividing by zero, or the least int by -1, aborts, as does
+//! converting a double that no int can hold, as on every target. Only the helpers the program
+//! uses are written out.
+//!
+//! C evaluates the operands of an operator and the arguments of a call in no particular order,
+//! while C0 evaluates them from left to right. Where that could be told apart, because one of
+//! them calls a function or assigns a variable and another isn't a literal, they're saved in
+//! temps in order first, with the comma operator.
+//!
+//! With `-d`, contracts are checked as codegen checks them, printing which one failed before
+//! aborting. C has one namespace where C0 keeps functions and variables apart, so a function
+//! or global whose name C reserves is prefixed with `c0_f_` or `c0_g_`, and a variable whose name
+//! C reserves, or which is a function's symbol, with `c0_v_`. The helpers and temps
+//! start with `c0_` too, which no name of the program's does.
+
+use crate::codegen::Mangling;
+use crate::constant::{evaluate, Constant};
+use crate::lexer::Token;
+use crate::parser::{
+    BinOp, Expr, FnDeclaration, FormatPart, FormatSpec, LValue, Parameter, Program, Statement,
+    UnOp, VarDeclaration,
+};
+use crate::sema::{type_of, Type};
+use crate::source_map::Spanned;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Writes `program` to `outpath` as C, with the symbols `mangling` gives
+pub fn emit_c(program: &Program, mangling: &Mangling, outpath: &Path) -> io::Result<()> {
+    fs::write(outpath, translate(program, mangling)?)
+}
+
+/// `program` as a C translation unit, with the symbols `mangling` gives. It fails if the
+/// program reads a variable that isn't declared, which sema rejects.
+pub fn translate(program: &Program, mangling: &Mangling) -> io::Result<String> {
+    let mut translator = Translator::new(program, mangling);
+
+    // Every function is declared first, since C0 functions can call those defined after them
+    let mut declarations = String::new();
+    for function in &program.externs {
+        let prototype = translator.prototype(
+            &function.return_type,
+            &function.identifier,
+            &function.params,
+        );
+        declarations += &format!("{};\n", prototype);
+    }
+    for function in &program.fns {
+        let prototype = translator.prototype(
+            &function.return_type,
+            &function.identifier,
+            &function.params,
+        );
+        let linkage = if function.is_static { "static " } else { "" };
+        declarations += &format!("{}{};\n", linkage, prototype);
+    }
+    let mut constants = HashMap::new();
+    for global in &program.decl {
+        declarations += &translator.global(global, &mut constants);
+    }
+
+    let definitions: Vec<String> = program
+        .fns
+        .iter()
+        .map(|function| translator.function(function))
+        .collect();
+
+    let mut text = String::from("#include <inttypes.h>\n#include <stdio.h>\n");
+    for helper in &translator.helpers {
+        text.push('\n');
+        text += helper.definition();
+    }
+    if !declarations.is_empty() {
+        text.push('\n');
+        text += &declarations;
+    }
+    for definition in definitions {
+        text.push('\n');
+        text += &definition;
+    }
+    match translator.undeclared.first() {
+        Some(name) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the variable '{}' isn't declared", name),
+        )),
+        None => Ok(text),
+    }
+}
+
+/// A function of the prelude, written out only if the program uses it. Each only calls the
+/// ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Helper {
+    Abort,
+    Wrap,
+    Add,
+    Sub,
+    Mul,
+    Neg,
+    Div,
+    D2I,
+    Check,
+    ScanInt,
+    ScanDouble,
+}
+
+impl Helper {
+    /// Helpers this one calls
+    fn requires(self) -> &'static [Helper] {
+        match self {
+            Helper::Add | Helper::Sub | Helper::Mul | Helper::Neg => &[Helper::Wrap],
+            Helper::Div | Helper::D2I | Helper::Check | Helper::ScanDouble => &[Helper::Abort],
+            Helper::ScanInt => &[Helper::Abort, Helper::Wrap],
+            Helper::Abort | Helper::Wrap => &[],
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Helper::Abort => "c0_abort",
+            Helper::Wrap => "c0_wrap",
+            Helper::Add => "c0_add",
+            Helper::Sub => "c0_sub",
+            Helper::Mul => "c0_mul",
+            Helper::Neg => "c0_neg",
+            Helper::Div => "c0_div",
+            Helper::D2I => "c0_d2i",
+            Helper::Check => "c0_check",
+            Helper::ScanInt => "c0_scan_int",
+            Helper::ScanDouble => "c0_scan_double",
+        }
+    }
+
+    fn definition(self) -> &'static str {
+        match self {
+            // What was printed is flushed first, as the runtime does
+            Helper::Abort => {
+                "void abort(void);\n\n\
+                 static void c0_abort(void) {\n    fflush(stdout);\n    abort();\n}\n"
+            }
+            // Converting an out of range unsigned value to a signed type isn't portable
+            Helper::Wrap => {
+                "static int32_t c0_wrap(uint32_t value) {\n    return value <= INT32_MAX ? \
+                 (int32_t)value : -(int32_t)(UINT32_MAX - value) - 1;\n}\n"
+            }
+            Helper::Add => {
+                "static int32_t c0_add(int32_t a, int32_t b) {\n    \
+                 return c0_wrap((uint32_t)a + (uint32_t)b);\n}\n"
+            }
+            Helper::Sub => {
+                "static int32_t c0_sub(int32_t a, int32_t b) {\n    \
+                 return c0_wrap((uint32_t)a - (uint32_t)b);\n}\n"
+            }
+            Helper::Mul => {
+                "static int32_t c0_mul(int32_t a, int32_t b) {\n    \
+                 return c0_wrap((uint32_t)a * (uint32_t)b);\n}\n"
+            }
+            Helper::Neg => {
+                "static int32_t c0_neg(int32_t a) {\n    return c0_wrap(0u - (uint32_t)a);\n}\n"
+            }
+            Helper::Div => {
+                "static int32_t c0_div(int32_t a, int32_t b) {\n    \
+                 if (b == 0 || (a == INT32_MIN && b == -1)) {\n        c0_abort();\n    }\n    \
+                 return a / b;\n}\n"
+            }
+            // Converting NaN, or a double out of range once truncated, is undefined in C. The
+            // comparisons are false for NaN.
+            Helper::D2I => {
+                "static int32_t c0_d2i(double value) {\n    \
+                 if (!(value > -2147483649.0 && value < 2147483648.0)) {\n        \
+                 c0_abort();\n    }\n    \
+                 return (int32_t)value;\n}\n"
+            }
+            Helper::Check => {
+                "static void c0_check(int32_t holds, const char *message) {\n    \
+                 if (!holds) {\n        fputs(message, stdout);\n        c0_abort();\n    }\n}\n"
+            }
+            // An int too big for `long long` reads as the nearest one, and then wraps around,
+            // as `%d` does with the GNU C library
+            Helper::ScanInt => {
+                "static int32_t c0_scan_int(void) {\n    long long value;\n    \
+                 if (scanf(\"%lld\", &value) != 1) {\n        c0_abort();\n    }\n    \
+                 return c0_wrap((uint32_t)value);\n}\n"
+            }
+            Helper::ScanDouble => {
+                "static double c0_scan_double(void) {\n    double value;\n    \
+                 if (scanf(\"%lf\", &value) != 1) {\n        c0_abort();\n    }\n    \
+                 return value;\n}\n"
+            }
+        }
+    }
+}
+
+/// What a function takes and returns
+struct Signature {
+    return_type: Type,
+    params: Vec<Type>,
+    is_extern: bool,
+}
+
+impl Signature {
+    fn new(return_type: &Token, params: &[Parameter], is_extern: bool) -> Self {
+        Signature {
+            return_type: type_of(return_type).unwrap_or(Type::Void),
+            params: params.iter().map(param_type).collect(),
+            is_extern,
+        }
+    }
+}
+
+// Precedence of the C expressions written, from the loosest
+const ASSIGNMENT: u8 = 1;
+const EQUALITY: u8 = 2;
+const RELATIONAL: u8 = 3;
+const ADDITIVE: u8 = 4;
+const MULTIPLICATIVE: u8 = 5;
+const UNARY: u8 = 6;
+const POSTFIX: u8 = 7;
+
+/// A C expression, and the precedence of its outermost operator
+struct Code {
+    text: String,
+    precedence: u8,
+}
+
+impl Code {
+    fn new(text: String, precedence: u8) -> Self {
+        Code { text, precedence }
+    }
+
+    /// The expression as an operand of an operator of precedence `precedence`
+    fn at(self, precedence: u8) -> String {
+        if self.precedence < precedence {
+            format!("({})", self.text)
+        } else {
+            self.text
+        }
+    }
+}
+
+/// Names C reserves, and those the output declares or uses
+const RESERVED: [&str; 60] = [
+    "auto",
+    "break",
+    "case",
+    "char",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extern",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "inline",
+    "int",
+    "long",
+    "register",
+    "restrict",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "struct",
+    "switch",
+    "typedef",
+    "union",
+    "unsigned",
+    "void",
+    "volatile",
+    "while",
+    "asm",
+    "typeof",
+    "stdin",
+    "stdout",
+    "stderr",
+    "errno",
+    "EOF",
+    "NULL",
+    "BUFSIZ",
+    "FILE",
+    "size_t",
+    "int32_t",
+    "uint32_t",
+    "INT32_MIN",
+    "INT32_MAX",
+    "UINT32_MAX",
+    "PRId32",
+    "printf",
+    "fputs",
+    "fflush",
+    "abort",
+    "SEEK_SET",
+    "SEEK_CUR",
+    "SEEK_END",
+    "main",
+    "bool",
+];
+
+/// Whether C reserves `name` or the output uses it. Names starting with `c0_` are kept for the
+/// output's own.
+fn reserved(name: &str) -> bool {
+    RESERVED.contains(&name)
+        || name.starts_with("c0_")
+        || name.starts_with("__")
+        || (name.starts_with('_') && name[1..].starts_with(|c: char| c.is_ascii_uppercase()))
+}
+
+/// Translates the program's declarations, a function at a time
+struct Translator<'a> {
+    mangling: &'a Mangling,
+    functions: HashMap<String, Signature>,
+    /// Symbols of the functions
+    symbols: HashSet<String>,
+    helpers: BTreeSet<Helper>,
+    /// Types of the variables in scope, innermost last
+    scopes: Vec<HashMap<String, Type>>,
+    /// Types of the globals, which the variables in scope hide
+    globals: HashMap<String, Type>,
+    /// Variables used that are neither in scope nor globals
+    undeclared: BTreeSet<String>,
+    /// Name and return type of the function being translated
+    name: String,
+    return_type: Type,
+    /// Postconditions of the function being translated, with each `\old` replaced by the
+    /// variable its value is saved in
+    ensures: Vec<Spanned<Expr>>,
+    /// Types of the temps the function's operands are saved in
+    temps: Vec<Type>,
+}
+
+impl<'a> Translator<'a> {
+    fn new(program: &Program, mangling: &'a Mangling) -> Self {
+        let mut functions = HashMap::new();
+        for function in &program.externs {
+            if let Token::Identifier(name) = &function.identifier {
+                let signature = Signature::new(&function.return_type, &function.params, true);
+                functions.insert(name.clone(), signature);
+            }
+        }
+        for function in &program.fns {
+            if let Token::Identifier(name) = &function.identifier {
+                let signature = Signature::new(&function.return_type, &function.params, false);
+                functions.insert(name.clone(), signature);
+            }
+        }
+        let mut translator = Translator {
+            mangling,
+            functions,
+            symbols: HashSet::new(),
+            helpers: BTreeSet::new(),
+            scopes: Vec::new(),
+            globals: HashMap::new(),
+            undeclared: BTreeSet::new(),
+            name: String::new(),
+            return_type: Type::Void,
+            ensures: Vec::new(),
+            temps: Vec::new(),
+        };
+        translator.symbols = translator
+            .functions
+            .keys()
+            .map(|name| translator.symbol(name))
+            .collect();
+        translator
+    }
+
+    /// Marks `helper` as used, with the helpers it calls
+    fn helper(&mut self, helper: Helper) -> &'static str {
+        for required in helper.requires() {
+            self.helper(*required);
+        }
+        self.helpers.insert(helper);
+        helper.name()
+    }
+
+    /// Symbol of the function `name`. Extern functions keep their names.
+    fn symbol(&self, name: &str) -> String {
+        if self.functions[name].is_extern {
+            return name.to_string();
+        }
+        let symbol = self.mangling.symbol(name);
+        if symbol != "main" && reserved(&symbol) {
+            format!("c0_f_{}", symbol)
+        } else {
+            symbol
+        }
+    }
+
+    /// Symbol of the global `name`, which is mangled like a function's
+    fn global_symbol(&self, name: &str) -> String {
+        let symbol = self.mangling.symbol(name);
+        if reserved(&symbol) {
+            format!("c0_g_{}", symbol)
+        } else {
+            symbol
+        }
+    }
+
+    /// C name of the variable `name`, which may be one saving an `\old` value
+    fn variable(&self, name: &str) -> String {
+        if let Some(index) = name.strip_prefix("\\old") {
+            return format!("c0_old{}", index);
+        }
+        // A variable would hide the function
+        if reserved(name) || self.symbols.contains(name) {
+            format!("c0_v_{}", name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn declare(&mut self, name: &str, ty: Type) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), ty);
+        }
+    }
+
+    /// Type of the variable `name`, which is taken as an int if it isn't declared
+    fn lookup(&self, name: &str) -> Type {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+            .or_else(|| self.globals.get(name).copied())
+            .unwrap_or(Type::Int)
+    }
+
+    /// C name of the variable `name` where it's used: the local in scope, or else the global.
+    /// A name that's neither is recorded as undeclared.
+    fn reference(&mut self, name: &str) -> String {
+        if self.scopes.iter().any(|scope| scope.contains_key(name)) {
+            return self.variable(name);
+        }
+        if self.globals.contains_key(name) {
+            return self.global_symbol(name);
+        }
+        self.undeclared.insert(name.to_string());
+        self.variable(name)
+    }
+
+    /// A new temp of type `ty`, declared at the top of the function
+    fn temp(&mut self, ty: Type) -> String {
+        self.temps.push(ty);
+        format!("c0_t{}", self.temps.len() - 1)
+    }
+
+    fn prototype(&self, return_type: &Token, identifier: &Token, params: &[Parameter]) -> String {
+        let name = identifier_name(identifier);
+        let is_extern = self.functions[name].is_extern;
+        let params: Vec<String> = params
+            .iter()
+            .map(|param| {
+                let ty = c_type(param_type(param));
+                match &param.identifier {
+                    // The C library's declarations are written without names
+                    Token::Identifier(param) if !is_extern => {
+                        declaration(ty, &self.variable(param))
+                    }
+                    _ => ty.to_string(),
+                }
+            })
+            .collect();
+        let params = if params.is_empty() {
+            "void".to_string()
+        } else {
+            params.join(", ")
+        };
+        // `main` returns an int, whatever size that is
+        let return_type = match name {
+            "main" => "int",
+            _ => c_type(type_of(return_type).unwrap_or(Type::Void)),
+        };
+        declaration(return_type, &format!("{}({})", self.symbol(name), params))
+    }
+
+    /// A global's definition. Desugaring has folded its initializer into a literal, which C
+    /// takes where it only takes constant expressions.
+    fn global(
+        &mut self,
+        global: &VarDeclaration,
+        constants: &mut HashMap<String, Constant>,
+    ) -> String {
+        let name = identifier_name(&global.identifier);
+        let ty = type_of(&global.type_token).unwrap_or(Type::Int);
+        self.globals.insert(name.to_string(), ty);
+        let linkage = if global.is_static { "static " } else { "" };
+        let mut definition = format!(
+            "{}{}",
+            linkage,
+            qualified(ty, global.is_const, &self.global_symbol(name))
+        );
+        if let Some(value) = &global.value {
+            let lookup = |name: &str| constants.get(name).cloned();
+            let value = match evaluate(&value.node, &lookup) {
+                Some(Constant::Int(number)) if ty == Type::Double => {
+                    Constant::Double(number as f64)
+                }
+                Some(constant) => constant,
+                None => unreachable!("desugaring folds the constants sema allows"),
+            };
+            let code = match &value {
+                Constant::Int(number) => int_literal(*number),
+                Constant::Double(number) => double_literal(*number),
+                Constant::String(string) => Code::new(string_literal(string), POSTFIX),
+            };
+            definition += &format!(" = {}", code.at(ASSIGNMENT));
+            if global.is_const {
+                constants.insert(name.to_string(), value);
+            }
+        }
+        definition + ";\n"
+    }
+
+    fn function(&mut self, function: &FnDeclaration) -> String {
+        self.name = identifier_name(&function.identifier).to_string();
+        self.return_type = type_of(&function.return_type).unwrap_or(Type::Void);
+        self.temps.clear();
+        self.scopes.push(HashMap::new());
+        for param in &function.params {
+            self.declare(identifier_name(&param.identifier), param_type(param));
+        }
+
+        let mut body = String::new();
+        for condition in &function.requires {
+            self.check(condition, "@requires", 1, &mut body);
+        }
+        // The values `\old` stands for are saved on entry, once the preconditions hold
+        let mut olds = Vec::new();
+        self.ensures = function
+            .ensures
+            .iter()
+            .map(|condition| self.save_old_values(condition, &mut olds))
+            .collect();
+        for (index, (ty, value)) in olds.iter().enumerate() {
+            let name = format!("\\old{}", index);
+            let value = self.coerced(&value.node, *ty).at(ASSIGNMENT);
+            let saved = declaration(c_type(*ty), &self.variable(&name));
+            line(&mut body, 1, &format!("{} = {};", saved, value));
+            self.declare(&name, *ty);
+        }
+        for statement in &function.body.statements {
+            self.statement(statement, 1, &mut body);
+        }
+        // A void function may also return by reaching the end of its body
+        let ends_in_return = matches!(
+            function.body.statements.last().map(|s| &s.node),
+            Some(Statement::Return(_))
+        );
+        if self.return_type == Type::Void && !self.ensures.is_empty() && !ends_in_return {
+            self.return_statement(None, 1, &mut body);
+        }
+        self.scopes.pop();
+
+        let prototype = self.prototype(
+            &function.return_type,
+            &function.identifier,
+            &function.params,
+        );
+        let linkage = if function.is_static { "static " } else { "" };
+        let mut text = format!("{}{} {{\n", linkage, prototype);
+        for (index, ty) in self.temps.iter().enumerate() {
+            let temp = declaration(c_type(*ty), &format!("c0_t{}", index));
+            line(&mut text, 1, &format!("{};", temp));
+        }
+        text + &body + "}\n"
+    }
+
+    /// Copies `expr` with each `\old(inner)` replaced by a variable its value is saved in, and
+    /// adds the types and values of those variables to `olds`
+    fn save_old_values(
+        &self,
+        expr: &Spanned<Expr>,
+        olds: &mut Vec<(Type, Spanned<Expr>)>,
+    ) -> Spanned<Expr> {
+        let mut save = |expr: &Spanned<Expr>| Box::new(self.save_old_values(expr, olds));
+        let node = match &expr.node {
+            Expr::Old(inner) => {
+                olds.push((self.type_of(&inner.node), (**inner).clone()));
+                Expr::Variable(Token::Identifier(format!("\\old{}", olds.len() - 1)))
+            }
+            Expr::Unary(op, operand) => Expr::Unary(*op, save(operand)),
+            Expr::Binary(left, op, right) => {
+                let left = save(left);
+                Expr::Binary(left, *op, save(right))
+            }
+            Expr::Parentheses(inner) => Expr::Parentheses(save(inner)),
+            Expr::Call(callee, args) => {
+                Expr::Call(callee.clone(), args.iter().map(|arg| *save(arg)).collect())
+            }
+            Expr::Cast(type_token, operand) => Expr::Cast(type_token.clone(), save(operand)),
+            Expr::Assign(target, value) => Expr::Assign(target.clone(), save(value)),
+            Expr::CompoundAssign(..) => unreachable!("{:?} is desugared before translation", expr),
+            Expr::Literal(_) | Expr::Variable(_) | Expr::Result => expr.node.clone(),
+        };
+        Spanned::new(node, expr.span)
+    }
+
+    /// Writes a check that `condition` holds, naming `annotation` if it doesn't
+    fn check(
+        &mut self,
+        condition: &Spanned<Expr>,
+        annotation: &str,
+        indent: usize,
+        out: &mut String,
+    ) {
+        let check = self.check_call(condition, annotation);
+        line(out, indent, &format!("{};", check));
+    }
+
+    fn check_call(&mut self, condition: &Spanned<Expr>, annotation: &str) -> String {
+        let message = format!("{} annotation failed in {}\n", annotation, self.name);
+        self.check_message(condition, &message)
+    }
+
+    /// A call checking `condition`, which prints `message` and aborts if it doesn't hold
+    fn check_message(&mut self, condition: &Spanned<Expr>, message: &str) -> String {
+        let check = self.helper(Helper::Check);
+        let condition = self.expr(&condition.node).at(ASSIGNMENT);
+        format!("{}({}, {})", check, condition, string_literal(message))
+    }
+
+    fn statement(&mut self, statement: &Spanned<Statement>, indent: usize, out: &mut String) {
+        match &statement.node {
+            Statement::Expression(expr) => {
+                let code = self.expr(&expr.node).text;
+                line(out, indent, &format!("{};", code));
+            }
+            Statement::VarDecl(declaration) => {
+                let code = self.local(declaration);
+                line(out, indent, &format!("{};", code));
+            }
+            Statement::If(condition, then_branch, else_branch) => {
+                let condition = self.expr(&condition.node).text;
+                line(out, indent, &format!("if ({}) {{", condition));
+                self.branches(then_branch, else_branch.as_deref(), indent, out);
+            }
+            Statement::While(condition, invariants, body) => {
+                let condition = self.loop_condition(condition, invariants);
+                line(out, indent, &format!("while ({}) {{", condition));
+                self.body(body, indent + 1, out);
+                line(out, indent, "}");
+            }
+            Statement::For(..) | Statement::Postfix(..) => {
+                unreachable!("{:?} is desugared before translation", statement)
+            }
+            Statement::Return(value) => self.return_statement(value.as_deref(), indent, out),
+            Statement::Block(block) => {
+                line(out, indent, "{");
+                self.scopes.push(HashMap::new());
+                for statement in &block.statements {
+                    self.statement(statement, indent + 1, out);
+                }
+                self.scopes.pop();
+                line(out, indent, "}");
+            }
+            Statement::Print(value) => {
+                let format = match self.type_of(&value.node) {
+                    Type::Int => "\"%\" PRId32",
+                    Type::Double => "\"%f\"",
+                    Type::Char => "\"%c\"",
+                    Type::String => "\"%s\"",
+                    Type::Void => unreachable!("sema rejects printing a void value"),
+                };
+                let value = self.expr(&value.node).at(ASSIGNMENT);
+                line(out, indent, &format!("printf({}, {});", format, value));
+            }
+            Statement::PrintFormat(format, args) => self.print_format(format, args, indent, out),
+            Statement::Scan(LValue::Variable(name)) => {
+                let helper = match self.lookup(identifier_name(name)) {
+                    Type::Double => Helper::ScanDouble,
+                    _ => Helper::ScanInt,
+                };
+                let scan = self.helper(helper);
+                let target = self.reference(identifier_name(name));
+                line(out, indent, &format!("{} = {}();", target, scan));
+            }
+            Statement::Break => line(out, indent, "break;"),
+            Statement::Continue => line(out, indent, "continue;"),
+            Statement::Assert(condition) => self.check(condition, "@assert", indent, out),
+            Statement::Check(condition) => {
+                let message = format!("assert failed in {}\n", self.name);
+                let check = self.check_message(condition, &message);
+                line(out, indent, &format!("{};", check));
+            }
+            Statement::Asm(template, output, inputs, constraints) => {
+                let inputs: Vec<&Expr> = inputs.iter().map(|input| &input.node).collect();
+                let types: Vec<Type> = inputs.iter().map(|input| self.type_of(input)).collect();
+                let (saves, inputs) = self.operands(&inputs, &types);
+                for save in saves {
+                    line(out, indent, &format!("{};", save));
+                }
+                let first_input = output.iter().count();
+                let inputs: Vec<String> = inputs
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, input)| {
+                        let letter = constraints.operands[first_input + index];
+                        format!("\"{}\"({})", letter, input.at(ASSIGNMENT))
+                    })
+                    .collect();
+                let output = output.as_ref().map(|target| match target {
+                    LValue::Variable(name) => format!(
+                        "\"={}\"({})",
+                        constraints.operands[0],
+                        self.reference(identifier_name(name))
+                    ),
+                });
+                let clobbers: Vec<String> = constraints
+                    .clobbers
+                    .iter()
+                    .map(|clobber| string_literal(clobber))
+                    .collect();
+                let template = string_literal(template);
+                // Sections are left out from the end, as long as they're empty
+                let sections = [
+                    output.unwrap_or_default(),
+                    inputs.join(", "),
+                    clobbers.join(", "),
+                ];
+                let used = sections.iter().rposition(|section| !section.is_empty());
+                let operands: String = sections[..used.map_or(0, |last| last + 1)]
+                    .iter()
+                    .map(|section| match section.is_empty() {
+                        true => " :".to_string(),
+                        false => format!(" : {}", section),
+                    })
+                    .collect();
+                line(
+                    out,
+                    indent,
+                    &format!("__asm__ __volatile__({}{});", template, operands),
+                );
+            }
+        }
+    }
+
+    /// Writes the branches of an `if` whose condition has been written, with an `else if`
+    /// for each `if` in the `else` branch
+    fn branches(
+        &mut self,
+        then_branch: &Spanned<Statement>,
+        else_branch: Option<&Spanned<Statement>>,
+        indent: usize,
+        out: &mut String,
+    ) {
+        self.body(then_branch, indent + 1, out);
+        match else_branch {
+            Some(Spanned {
+                node: Statement::If(condition, then_branch, else_branch),
+                ..
+            }) => {
+                let condition = self.expr(&condition.node).text;
+                line(out, indent, &format!("}} else if ({}) {{", condition));
+                self.branches(then_branch, else_branch.as_deref(), indent, out);
+            }
+            Some(else_branch) => {
+                line(out, indent, "} else {");
+                self.body(else_branch, indent + 1, out);
+                line(out, indent, "}");
+            }
+            None => line(out, indent, "}"),
+        }
+    }
+
+    /// Writes the statements of an `if` branch or a loop's body, which gets its own scope
+    /// even without braces
+    fn body(&mut self, body: &Spanned<Statement>, indent: usize, out: &mut String) {
+        self.scopes.push(HashMap::new());
+        match &body.node {
+            Statement::Block(block) => {
+                for statement in &block.statements {
+                    self.statement(statement, indent, out);
+                }
+            }
+            _ => self.statement(body, indent, out),
+        }
+        self.scopes.pop();
+    }
+
+    /// A loop's condition, checking its invariants each time before it's tested
+    fn loop_condition(
+        &mut self,
+        condition: &Spanned<Expr>,
+        invariants: &[Spanned<Expr>],
+    ) -> String {
+        let mut parts: Vec<String> = invariants
+            .iter()
+            .map(|invariant| self.check_call(invariant, "@loop_invariant"))
+            .collect();
+        if parts.is_empty() {
+            return self.expr(&condition.node).text;
+        }
+        parts.push(self.expr(&condition.node).at(ASSIGNMENT));
+        parts.join(", ")
+    }
+
+    /// A local variable's declaration, without the `;`
+    fn local(&mut self, declaration: &VarDeclaration) -> String {
+        let name = identifier_name(&declaration.identifier);
+        let ty = type_of(&declaration.type_token).unwrap_or(Type::Int);
+        let value = declaration
+            .value
+            .as_ref()
+            .map(|value| self.coerced(&value.node, ty).at(ASSIGNMENT));
+        self.declare(name, ty);
+        let declared = qualified(ty, declaration.is_const, &self.variable(name));
+        match value {
+            Some(value) => format!("{} = {}", declared, value),
+            None => declared,
+        }
+    }
+
+    /// Writes a return, checking the postconditions first if there are any
+    fn return_statement(&mut self, value: Option<&Spanned<Expr>>, indent: usize, out: &mut String) {
+        let value = value.map(|value| self.coerced(&value.node, self.return_type).text);
+        if self.ensures.is_empty() {
+            match value {
+                Some(value) => line(out, indent, &format!("return {};", value)),
+                None => line(out, indent, "return;"),
+            }
+            return;
+        }
+        // The return value is computed before the postconditions are checked, and `\result`
+        // reads it
+        line(out, indent, "{");
+        if let Some(value) = &value {
+            let result = declaration(c_type(self.return_type), "c0_result");
+            line(out, indent + 1, &format!("{} = {};", result, value));
+        }
+        let ensures = std::mem::take(&mut self.ensures);
+        for condition in &ensures {
+            self.check(condition, "@ensures", indent + 1, out);
+        }
+        self.ensures = ensures;
+        match value {
+            Some(_) => line(out, indent + 1, "return c0_result;"),
+            None => line(out, indent + 1, "return;"),
+        }
+        line(out, indent, "}");
+    }
+
+    /// Writes `print` with a format as one call to `printf`, after every argument is evaluated
+    fn print_format(
+        &mut self,
+        format: &[FormatPart],
+        args: &[Spanned<Expr>],
+        indent: usize,
+        out: &mut String,
+    ) {
+        if format.is_empty() {
+            return;
+        }
+        let specs: Vec<FormatSpec> = format
+            .iter()
+            .filter_map(|part| match part {
+                FormatPart::Arg(spec) => Some(*spec),
+                FormatPart::Text(_) => None,
+            })
+            .collect();
+        let args: Vec<&Expr> = args.iter().map(|arg| &arg.node).collect();
+        // An int may be printed as a double
+        let types: Vec<Type> = specs
+            .iter()
+            .zip(&args)
+            .map(|(spec, arg)| match spec {
+                FormatSpec::Double => Type::Double,
+                _ => self.type_of(arg),
+            })
+            .collect();
+        let (saves, args) = self.operands(&args, &types);
+        for save in saves {
+            line(out, indent, &format!("{};", save));
+        }
+
+        // An int's conversion is a macro, which the literal is split around
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        for part in format {
+            match part {
+                FormatPart::Text(part) => text += &part.replace('%', "%%"),
+                FormatPart::Arg(FormatSpec::Int) => {
+                    text.push('%');
+                    pieces.push(string_literal(&std::mem::take(&mut text)));
+                    pieces.push("PRId32".to_string());
+                }
+                FormatPart::Arg(FormatSpec::Double) => text += "%f",
+                FormatPart::Arg(FormatSpec::Char) => text += "%c",
+                FormatPart::Arg(FormatSpec::String) => text += "%s",
+            }
+        }
+        if !text.is_empty() {
+            pieces.push(string_literal(&text));
+        }
+        let mut call = format!("printf({}", pieces.join(" "));
+        for arg in args {
+            call += &format!(", {}", arg.at(ASSIGNMENT));
+        }
+        line(out, indent, &format!("{});", call));
+    }
+
+    /// `exprs` as operands of the types `types`. If telling the order they're evaluated in
+    /// apart could matter, each but a literal is saved in a temp first, in order, and the
+    /// assignments to do that are returned too.
+    fn operands(&mut self, exprs: &[&Expr], types: &[Type]) -> (Vec<String>, Vec<Code>) {
+        // A call can write a global that another operand reads, so any effect orders them
+        let effects = exprs.iter().any(|expr| has_effects(expr));
+        let values = exprs.iter().filter(|expr| !is_literal(expr)).count();
+        let ordered = effects && values > 1;
+        let mut saves = Vec::new();
+        let mut codes = Vec::new();
+        for (expr, ty) in exprs.iter().zip(types) {
+            let code = self.coerced(expr, *ty);
+            if ordered && !is_literal(expr) {
+                let temp = self.temp(*ty);
+                saves.push(format!("{} = {}", temp, code.at(ASSIGNMENT)));
+                codes.push(Code::new(temp, POSTFIX));
+            } else {
+                codes.push(code);
+            }
+        }
+        (saves, codes)
+    }
+
+    /// `expr` as a value of type `ty`. Only an int ever needs converting, to a double.
+    fn coerced(&mut self, expr: &Expr, ty: Type) -> Code {
+        if ty != Type::Double || self.type_of(expr) != Type::Int {
+            return self.expr(expr);
+        }
+        // Literals are converted at compile time, a negated one after wrapping around
+        let literal = match expr {
+            Expr::Unary(UnOp::Neg, operand) => {
+                int_literal_value(&operand.node).map(|value| f64::from(wrap(value).wrapping_neg()))
+            }
+            _ => int_literal_value(expr),
+        };
+        if let Some(value) = literal {
+            return double_literal(value);
+        }
+        Code::new(format!("(double){}", self.expr(expr).at(UNARY)), UNARY)
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Code {
+        match expr {
+            Expr::Literal(Token::Number(value)) => int_literal(wrap(*value)),
+            Expr::Literal(Token::DoubleLiteral(value)) => double_literal(*value),
+            Expr::Literal(Token::StringLiteral(string)) => {
+                Code::new(string_literal(string), POSTFIX)
+            }
+            Expr::Literal(other) => unreachable!("sema rejects the literal {:?}", other),
+            Expr::Unary(op, operand) => {
+                let ty = self.type_of(&operand.node);
+                match op {
+                    UnOp::Neg if ty == Type::Int => match int_literal_value(&operand.node) {
+                        Some(value) => int_literal(wrap(value).wrapping_neg()),
+                        None => {
+                            let neg = self.helper(Helper::Neg);
+                            let operand = self.expr(&operand.node).at(ASSIGNMENT);
+                            Code::new(format!("{}({})", neg, operand), POSTFIX)
+                        }
+                    },
+                    UnOp::Neg => {
+                        let operand = self.expr(&operand.node).at(UNARY);
+                        // Not `--`, which is another operator
+                        match operand.starts_with('-') {
+                            true => Code::new(format!("-({})", operand), UNARY),
+                            false => Code::new(format!("-{}", operand), UNARY),
+                        }
+                    }
+                    UnOp::Not => {
+                        let operand = self.expr(&operand.node).at(UNARY);
+                        Code::new(format!("!{}", operand), UNARY)
+                    }
+                    UnOp::BitNot => {
+                        let operand = self.expr(&operand.node).at(UNARY);
+                        Code::new(format!("~{}", operand), UNARY)
+                    }
+                }
+            }
+            Expr::Binary(left, op, right) => {
+                let ty = match (self.type_of(&left.node), self.type_of(&right.node)) {
+                    (Type::Double, _) | (_, Type::Double) => Type::Double,
+                    (ty, _) => ty,
+                };
+                let (saves, mut operands) = self.operands(&[&left.node, &right.node], &[ty, ty]);
+                let right = operands.pop().unwrap();
+                let left = operands.pop().unwrap();
+                let helper = match op {
+                    BinOp::Add => Helper::Add,
+                    BinOp::Sub => Helper::Sub,
+                    BinOp::Mul => Helper::Mul,
+                    BinOp::Div => Helper::Div,
+                    _ => Helper::Check,
+                };
+                let code = if ty == Type::Int && helper != Helper::Check {
+                    let helper = self.helper(helper);
+                    let (left, right) = (left.at(ASSIGNMENT), right.at(ASSIGNMENT));
+                    Code::new(format!("{}({}, {})", helper, left, right), POSTFIX)
+                } else {
+                    let (symbol, precedence) = match op {
+                        BinOp::Add => ("+", ADDITIVE),
+                        BinOp::Sub => ("-", ADDITIVE),
+                        BinOp::Mul => ("*", MULTIPLICATIVE),
+                        BinOp::Div => ("/", MULTIPLICATIVE),
+                        BinOp::Equal => ("==", EQUALITY),
+                        BinOp::NotEqual => ("!=", EQUALITY),
+                        BinOp::Less => ("<", RELATIONAL),
+                        BinOp::LessEqual => ("<=", RELATIONAL),
+                        BinOp::Greater => (">", RELATIONAL),
+                        BinOp::GreaterEqual => (">=", RELATIONAL),
+                    };
+                    let (left, right) = (left.at(precedence), right.at(precedence + 1));
+                    Code::new(format!("{} {} {}", left, symbol, right), precedence)
+                };
+                sequence(saves, code)
+            }
+            Expr::Parentheses(inner) => self.expr(&inner.node),
+            Expr::Variable(name) => Code::new(self.reference(identifier_name(name)), POSTFIX),
+            Expr::Call(callee, args) => {
+                let Expr::Variable(name) = &callee.node else {
+                    unreachable!("the parser only produces calls to names");
+                };
+                let name = identifier_name(name);
+                let params = self.functions[name].params.clone();
+                let args: Vec<&Expr> = args.iter().map(|arg| &arg.node).collect();
+                let (saves, args) = self.operands(&args, &params);
+                let args: Vec<String> = args.into_iter().map(|arg| arg.at(ASSIGNMENT)).collect();
+                let call = format!("{}({})", self.symbol(name), args.join(", "));
+                sequence(saves, Code::new(call, POSTFIX))
+            }
+            Expr::Cast(type_token, operand) => {
+                let target = type_of(type_token).unwrap_or(Type::Int);
+                let ty = self.type_of(&operand.node);
+                match (ty, target) {
+                    (Type::Double, Type::Int | Type::Char) => {
+                        let d2i = self.helper(Helper::D2I);
+                        let operand = self.expr(&operand.node).at(ASSIGNMENT);
+                        let int = format!("{}({})", d2i, operand);
+                        match target {
+                            Type::Char => Code::new(format!("(unsigned char){}", int), UNARY),
+                            _ => Code::new(int, POSTFIX),
+                        }
+                    }
+                    // A cast to the same type does nothing
+                    _ if ty == target => self.expr(&operand.node),
+                    _ => {
+                        let operand = self.expr(&operand.node).at(UNARY);
+                        Code::new(format!("({}){}", c_type(target), operand), UNARY)
+                    }
+                }
+            }
+            Expr::Assign(target, value) => {
+                let LValue::Variable(name) = target;
+                let ty = self.lookup(identifier_name(name));
+                let variable = self.reference(identifier_name(name));
+                let value_code = self.coerced(&value.node, ty).at(ASSIGNMENT);
+                // An assignment in the value must happen before this one
+                if assigns(&value.node) {
+                    let temp = self.temp(ty);
+                    let code = format!("({} = {}, {} = {})", temp, value_code, variable, temp);
+                    return Code::new(code, POSTFIX);
+                }
+                Code::new(format!("{} = {}", variable, value_code), ASSIGNMENT)
+            }
+            Expr::CompoundAssign(..) => unreachable!("{:?} is desugared before translation", expr),
+            Expr::Result => Code::new("c0_result".to_string(), POSTFIX),
+            Expr::Old(_) => unreachable!("\\old is saved on entry to the function"),
+        }
+    }
+
+    /// Type of `expr`, which sema has checked
+    fn type_of(&self, expr: &Expr) -> Type {
+        match expr {
+            Expr::Literal(Token::Number(_)) => Type::Int,
+            Expr::Literal(Token::DoubleLiteral(_)) => Type::Double,
+            Expr::Literal(_) => Type::String,
+            Expr::Unary(UnOp::Neg, operand) => self.type_of(&operand.node),
+            Expr::Unary(..) => Type::Int,
+            Expr::Binary(left, op, right) => match op {
+                BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div => {
+                    match (self.type_of(&left.node), self.type_of(&right.node)) {
+                        (Type::Double, _) | (_, Type::Double) => Type::Double,
+                        (ty, _) => ty,
+                    }
+                }
+                // Comparisons produce a truth value
+                _ => Type::Int,
+            },
+            Expr::Parentheses(inner) | Expr::Old(inner) => self.type_of(&inner.node),
+            Expr::Variable(name) => self.lookup(identifier_name(name)),
+            Expr::Call(callee, _) => match &callee.node {
+                Expr::Variable(name) => self.functions[identifier_name(name)].return_type,
+                _ => unreachable!("the parser only produces calls to names"),
+            },
+            Expr::Cast(type_token, _) => type_of(type_token).unwrap_or(Type::Int),
+            Expr::Assign(LValue::Variable(name), _) => self.lookup(identifier_name(name)),
+            Expr::CompoundAssign(..) => unreachable!("{:?} is desugared before translation", expr),
+            Expr::Result => self.return_type,
+        }
+    }
+}
+
+/// `code`, after the assignments in `saves`
+fn sequence(saves: Vec<String>, code: Code) -> Code {
+    if saves.is_empty() {
+        return code;
+    }
+    Code::new(format!("({}, {})", saves.join(", "), code.text), POSTFIX)
+}
+
+/// Whether evaluating `expr` calls a function or assigns a variable
+fn has_effects(expr: &Expr) -> bool {
+    any_subexpression(expr, &|expr| {
+        matches!(expr, Expr::Call(..) | Expr::Assign(..))
+    })
+}
+
+/// Whether evaluating `expr` assigns a variable
+fn assigns(expr: &Expr) -> bool {
+    any_subexpression(expr, &|expr| matches!(expr, Expr::Assign(..)))
+}
+
+fn any_subexpression(expr: &Expr, predicate: &dyn Fn(&Expr) -> bool) -> bool {
+    predicate(expr)
+        || match expr {
+            Expr::Unary(_, operand)
+            | Expr::Parentheses(operand)
+            | Expr::Cast(_, operand)
+            | Expr::Old(operand)
+            | Expr::Assign(_, operand)
+            | Expr::CompoundAssign(_, _, operand) => any_subexpression(&operand.node, predicate),
+            Expr::Binary(left, _, right) => {
+                any_subexpression(&left.node, predicate)
+                    || any_subexpression(&right.node, predicate)
+            }
+            Expr::Call(_, args) => args
+                .iter()
+                .any(|arg| any_subexpression(&arg.node, predicate)),
+            Expr::Literal(_) | Expr::Variable(_) | Expr::Result => false,
+        }
+}
+
+/// Whether `expr` is a literal, maybe negated, whose value doesn't depend on when it's
+/// evaluated
+fn is_literal(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal(_) => true,
+        Expr::Parentheses(inner) | Expr::Unary(UnOp::Neg, inner) => is_literal(&inner.node),
+        _ => false,
+    }
+}
+
+/// Value of `expr` if it's an int literal
+fn int_literal_value(expr: &Expr) -> Option<f64> {
+    match expr {
+        Expr::Literal(Token::Number(value)) => Some(*value),
+        Expr::Parentheses(inner) => int_literal_value(&inner.node),
+        _ => None,
+    }
+}
+
+/// An int literal's value, wrapping around like codegen's constants
+fn wrap(value: f64) -> i32 {
+    value as i128 as i32
+}
+
+fn int_literal(value: i32) -> Code {
+    match value {
+        // Its negation doesn't fit an int in C
+        i32::MIN => Code::new("INT32_MIN".to_string(), POSTFIX),
+        _ if value < 0 => Code::new(value.to_string(), UNARY),
+        _ => Code::new(value.to_string(), POSTFIX),
+    }
+}
+
+/// A double as C writes it, exactly
+fn double_literal(value: f64) -> Code {
+    let text = match value {
+        _ if value.is_nan() => "(0.0 / 0.0)".to_string(),
+        f64::INFINITY => "(1.0 / 0.0)".to_string(),
+        f64::NEG_INFINITY => "(-1.0 / 0.0)".to_string(),
+        // Rust's shortest representation that reads back the same, which C reads too
+        _ => format!("{:?}", value),
+    };
+    match text.starts_with('-') {
+        true => Code::new(text, UNARY),
+        false => Code::new(text, POSTFIX),
+    }
+}
+
+/// `string` as a C string literal. Bytes other than printable ASCII are escaped in octal, and a
+/// `?` after another, which could start a trigraph.
+fn string_literal(string: &str) -> String {
+    let mut literal = String::from("\"");
+    let mut previous = 0;
+    for byte in string.bytes() {
+        match byte {
+            b'"' => literal += "\\\"",
+            b'\\' => literal += "\\\\",
+            b'\n' => literal += "\\n",
+            b'\t' => literal += "\\t",
+            b'?' if previous == b'?' => literal += "\\?",
+            b' '..=b'~' => literal.push(byte as char),
+            _ => literal += &format!("\\{:03o}", byte),
+        }
+        previous = byte;
+    }
+    literal.push('"');
+    literal
+}
+
+fn c_type(ty: Type) -> &'static str {
+    match ty {
+        Type::Int => "int32_t",
+        Type::Double => "double",
+        Type::Char => "unsigned char",
+        Type::String => "const char *",
+        Type::Void => "void",
+    }
+}
+
+/// `name` declared with the type `ty`, which may be a pointer
+fn declaration(ty: &str, name: &str) -> String {
+    if ty.ends_with('*') {
+        format!("{}{}", ty, name)
+    } else {
+        format!("{} {}", ty, name)
+    }
+}
+
+/// `name` declared with the type `ty`, as a constant if `is_const`
+fn qualified(ty: Type, is_const: bool, name: &str) -> String {
+    match (ty, is_const) {
+        (_, false) => declaration(c_type(ty), name),
+        (Type::String, true) => format!("const char *const {}", name),
+        (_, true) => format!("const {}", declaration(c_type(ty), name)),
+    }
+}
+
+fn param_type(param: &Parameter) -> Type {
+    type_of(&param.type_token).unwrap_or(Type::Int)
+}
+
+fn identifier_name(token: &Token) -> &str {
+    match token {
+        Token::Identifier(name) => name,
+        other => unreachable!("{:?} isn't an identifier", other),
+    }
+}
+
+/// Adds `text` to `out` as a line indented `indent` levels
+fn line(out: &mut String, indent: usize, text: &str) {
+    out.push_str(&"    ".repeat(indent));
+    out.push_str(text);
+    out.push('\n');
+}
@@ -0,0 +1,271 @@
+//! Assembles x86 ops whose jumps, calls and addresses name symbols into machine code, in two
+//! passes: the first places the labels, and the second writes the ops with the offsets between
+//! them. Jumps start out short, and those that can't reach their targets are lengthened until
+//! every one can. References to symbols not defined here are left to the linker as relocations.
+
+use super::x86_encoding::{encode_jump, serialize_op, Condition, Op};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Item {
+    /// An op that doesn't refer to any symbol
+    Op(Op),
+    /// Defines the symbol `name` where the next item starts
+    Label(String),
+    Jmp(String),
+    Jcc(Condition, String),
+    Call(String),
+    /// An op with a %rip-relative address, whose displacement is from the symbol instead of
+    /// from the end of the instruction
+    Rip(Op, String),
+}
+
+/// How the linker fills in a relocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    /// The symbol's address, plus the addend, relative to the relocation's; ELF's
+    /// R_X86_64_PC32
+    Pc32,
+    /// Like `Pc32`, for a call, which may go through the procedure linkage table to reach a
+    /// function in a shared library; ELF's R_X86_64_PLT32
+    Plt32,
+    /// The symbol's address, plus the addend, in 8 bytes, as data holding a pointer needs;
+    /// ELF's R_X86_64_64
+    Abs64,
+}
+
+/// Bytes of the machine code or data to fill in with the address of a symbol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    /// Where the bytes start
+    pub offset: usize,
+    pub symbol: String,
+    pub kind: RelocationKind,
+    pub addend: i64,
+}
+
+/// Machine code, with the offsets of the symbols defined in it, in the order they're defined,
+/// and the relocations for the others
+#[derive(Debug, Default)]
+pub struct Assembly {
+    pub bytes: Vec<u8>,
+    pub labels: Vec<(String, usize)>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Assembly {
+    /// Offset of the symbol `name`, if it's defined here
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.labels
+            .iter()
+            .find(|(label, _)| label == name)
+            .map(|(_, offset)| *offset)
+    }
+
+    fn relocate(&mut self, offset: usize, symbol: String, kind: RelocationKind, addend: i64) {
+        self.relocations.push(Relocation {
+            offset,
+            symbol,
+            kind,
+            addend,
+        });
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssembleError {
+    DuplicateLabel(String),
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssembleError::DuplicateLabel(name) => write!(f, "'{}' is defined twice", name),
+        }
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// An item, with the ops already encoded
+enum Chunk {
+    Bytes(Vec<u8>),
+    Label(String),
+    Jump {
+        condition: Option<Condition>,
+        target: String,
+        near: bool,
+    },
+    Call(String),
+    /// Encoded with the displacement from the symbol, which starts at `displacement_at`
+    Rip {
+        bytes: Vec<u8>,
+        displacement_at: usize,
+        symbol: String,
+    },
+}
+
+impl Chunk {
+    fn size(&self) -> usize {
+        match self {
+            Chunk::Bytes(bytes) | Chunk::Rip { bytes, .. } => bytes.len(),
+            Chunk::Label(_) => 0,
+            Chunk::Jump {
+                condition, near, ..
+            } => match (condition, near) {
+                (_, false) => 2,
+                (None, true) => 5,
+                (Some(_), true) => 6,
+            },
+            Chunk::Call(_) => 5,
+        }
+    }
+}
+
+pub fn assemble(items: Vec<Item>) -> Result<Assembly, AssembleError> {
+    let mut chunks: Vec<Chunk> = items.into_iter().map(encode).collect();
+    let mut defined = HashSet::new();
+    for chunk in &chunks {
+        if let Chunk::Label(name) = chunk {
+            if !defined.insert(name.clone()) {
+                return Err(AssembleError::DuplicateLabel(name.clone()));
+            }
+        }
+    }
+
+    // Jumps to other objects are near, since their targets could be anywhere
+    for chunk in &mut chunks {
+        if let Chunk::Jump { target, near, .. } = chunk {
+            *near = !defined.contains(target);
+        }
+    }
+    // Lengthening a jump only moves others' targets further away, so this ends, with each
+    // jump lengthened at most once
+    let labels = loop {
+        let labels = place_labels(&chunks);
+        let mut offset = 0;
+        let mut lengthened = false;
+        for chunk in &mut chunks {
+            offset += chunk.size();
+            match chunk {
+                Chunk::Jump { target, near, .. } if !*near => {
+                    let distance = labels[target] as i64 - offset as i64;
+                    if !(-128..=127).contains(&distance) {
+                        *near = true;
+                        lengthened = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !lengthened {
+            break labels;
+        }
+    };
+
+    let mut assembly = Assembly::default();
+    for chunk in chunks {
+        let start = assembly.bytes.len();
+        let end = start + chunk.size();
+        // Distance to the symbol from the end of the instruction, if it's defined here
+        let distance = |symbol: &str| {
+            labels
+                .get(symbol)
+                .map(|&offset| i32::try_from(offset as i64 - end as i64).unwrap())
+        };
+        match chunk {
+            Chunk::Bytes(bytes) => assembly.bytes.extend(bytes),
+            Chunk::Label(name) => assembly.labels.push((name, start)),
+            Chunk::Jump {
+                condition,
+                target,
+                near,
+            } => {
+                let offset = distance(&target);
+                encode_jump(&mut assembly.bytes, condition, offset.unwrap_or(0), near);
+                if offset.is_none() {
+                    assembly.relocate(end - 4, target, RelocationKind::Pc32, -4);
+                }
+            }
+            Chunk::Call(target) => {
+                let offset = distance(&target);
+                serialize_op(&mut assembly.bytes, Op::Call(offset.unwrap_or(0)));
+                if offset.is_none() {
+                    assembly.relocate(end - 4, target, RelocationKind::Plt32, -4);
+                }
+            }
+            Chunk::Rip {
+                mut bytes,
+                displacement_at,
+                symbol,
+            } => {
+                let field = displacement_at..displacement_at + 4;
+                let displacement = i32::from_le_bytes(bytes[field.clone()].try_into().unwrap());
+                match distance(&symbol) {
+                    Some(distance) => {
+                        bytes[field].copy_from_slice(&(distance + displacement).to_le_bytes())
+                    }
+                    None => {
+                        // The field is relative to the end of the instruction, which may be
+                        // past it, if an immediate follows
+                        let addend = displacement as i64 - (bytes.len() - displacement_at) as i64;
+                        bytes[field].fill(0);
+                        assembly.relocate(
+                            start + displacement_at,
+                            symbol,
+                            RelocationKind::Pc32,
+                            addend,
+                        );
+                    }
+                }
+                assembly.bytes.extend(bytes);
+            }
+        }
+    }
+    Ok(assembly)
+}
+
+fn encode(item: Item) -> Chunk {
+    match item {
+        Item::Op(op) => {
+            let mut bytes = Vec::new();
+            serialize_op(&mut bytes, op);
+            Chunk::Bytes(bytes)
+        }
+        Item::Label(name) => Chunk::Label(name),
+        Item::Jmp(target) => Chunk::Jump {
+            condition: None,
+            target,
+            near: false,
+        },
+        Item::Jcc(condition, target) => Chunk::Jump {
+            condition: Some(condition),
+            target,
+            near: false,
+        },
+        Item::Call(target) => Chunk::Call(target),
+        Item::Rip(op, symbol) => {
+            let mut bytes = Vec::new();
+            let displacement_at = serialize_op(&mut bytes, op).expect("no %rip-relative address");
+            Chunk::Rip {
+                bytes,
+                displacement_at,
+                symbol,
+            }
+        }
+    }
+}
+
+/// Offset of each label, with the jumps as long as they are so far
+fn place_labels(chunks: &[Chunk]) -> HashMap<String, usize> {
+    let mut labels = HashMap::new();
+    let mut offset = 0;
+    for chunk in chunks {
+        if let Chunk::Label(name) = chunk {
+            labels.insert(name.clone(), offset);
+        }
+        offset += chunk.size();
+    }
+    labels
+}
@@ -0,0 +1,586 @@
+//! The targets code is generated for. Each is a `Backend`, found by its target triple in the
+//! registry, so a new target only has to implement the trait and be added to `BACKENDS`.
+
+use super::context::{Global, Operand};
+use super::emit::{emit_abstract, emit_m6502, emit_riscv, emit_x86};
+use super::incremental::{function_hash, CachedFunction, FunctionCache};
+use super::m6502::M6502Instruction;
+use super::object::Format;
+use super::register_allocator::{self, RegisterDescription};
+use super::riscv::RiscvInstruction;
+use super::x86::{self, CallingConvention, X86Instruction};
+use super::x86_object::{assemble_function, emit_x86_object};
+use super::{
+    cfg, frame, function_stats, isel, m6502, riscv, runtime, two_address, CodegenOptions, IrModule,
+    Mangling, OutputFormat,
+};
+use crate::stats::FunctionStats;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// A target the program can be written for
+pub trait Backend: Sync {
+    /// Target triple naming the backend, like `x86_64-pc-windows`, which `--target` takes
+    fn triple(&self) -> &'static str;
+
+    /// The machine registers temps are assigned under `options`, for a target that has them
+    fn registers(&self, _options: &CodegenOptions) -> Option<RegisterDescription> {
+        None
+    }
+
+    /// Rewrites the optimized program into what the target can write, like operations it has
+    /// no instruction for. Nothing is rewritten by default.
+    fn legalize(&self, _module: &mut IrModule) {}
+
+    /// Kinds of file the output can be
+    fn formats(&self) -> &'static [OutputFormat] {
+        &[OutputFormat::Assembly]
+    }
+
+    /// Writes the program to `outpath`, as a file of the kind `options.format` names, and
+    /// returns the statistics of each function, with what the target did to it
+    fn emit(
+        &self,
+        module: &IrModule,
+        options: &CodegenOptions,
+        outpath: &Path,
+    ) -> io::Result<Vec<FunctionStats>>;
+
+    /// Format of the objects the output is assembled into, for a target whose output can be
+    /// linked into an executable
+    fn object_format(&self) -> Option<Format> {
+        None
+    }
+
+    /// Target triple LLVM knows the target by, which `--emit=llvm-ir` writes in the module so
+    /// that `clang` compiles it for the same machine
+    fn llvm_triple(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Writes the runtime the output is linked with to `outpath`, as assembly
+    fn emit_runtime(&self, _outpath: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} has no runtime", self.triple()),
+        ))
+    }
+}
+
+/// Every backend, the default first
+static BACKENDS: [&dyn Backend; 5] = [
+    &AbstractBackend,
+    &X86Backend {
+        triple: "x86_64",
+        llvm_triple: "x86_64-unknown-linux-gnu",
+        convention: CallingConvention::SystemV,
+        format: Format::Elf,
+    },
+    &X86Backend {
+        triple: "x86_64-pc-windows",
+        llvm_triple: "x86_64-pc-windows-gnu",
+        convention: CallingConvention::Microsoft,
+        format: Format::Coff,
+    },
+    &M6502Backend,
+    &RiscvBackend,
+];
+
+/// The backend for `triple`, if there's one
+pub fn backend(triple: &str) -> Option<&'static dyn Backend> {
+    BACKENDS
+        .iter()
+        .find(|backend| backend.triple() == triple)
+        .copied()
+}
+
+/// The backend used without `--target`, which writes abstract assembly
+pub fn default_backend() -> &'static dyn Backend {
+    BACKENDS[0]
+}
+
+/// Triples of every backend
+pub fn triples() -> Vec<&'static str> {
+    BACKENDS.iter().map(|backend| backend.triple()).collect()
+}
+
+/// Writes the abstract assembly itself, which `--from-ir` reads back
+struct AbstractBackend;
+
+impl Backend for AbstractBackend {
+    fn triple(&self) -> &'static str {
+        "abstract"
+    }
+
+    fn emit(
+        &self,
+        module: &IrModule,
+        options: &CodegenOptions,
+        outpath: &Path,
+    ) -> io::Result<Vec<FunctionStats>> {
+        let IrModule {
+            globals,
+            strings,
+            functions,
+        } = module;
+        emit_abstract(outpath, functions, globals, strings, options.debug_names)?;
+        Ok(function_stats(functions))
+    }
+}
+
+/// x86-64 assembly, for the operating system with this calling convention and object format
+struct X86Backend {
+    triple: &'static str,
+    llvm_triple: &'static str,
+    convention: CallingConvention,
+    format: Format,
+}
+
+impl X86Backend {
+    /// What a function's code depends on besides its own abstract assembly, for its hash: the
+    /// target and the options code generation takes, the globals reached through the global
+    /// offset table, and with mangling, which names the program defines, which are renamed
+    fn environment(
+        &self,
+        options: &CodegenOptions,
+        through_got: &HashSet<String>,
+        defined: &[&str],
+    ) -> String {
+        let mut through_got: Vec<_> = through_got.iter().collect();
+        through_got.sort();
+        let mut defined = match options.mangling {
+            Mangling::None => Vec::new(),
+            Mangling::Prefix(_) => defined.to_vec(),
+        };
+        defined.sort();
+        format!(
+            "{} {:?} {} {} {} {:?} {:?} {:?}",
+            self.triple,
+            options.register_allocator,
+            options.omit_frame_pointer,
+            options.red_zone,
+            options.pic,
+            options.mangling,
+            defined,
+            through_got
+        )
+    }
+}
+
+impl Backend for X86Backend {
+    fn triple(&self) -> &'static str {
+        self.triple
+    }
+
+    fn registers(&self, _options: &CodegenOptions) -> Option<RegisterDescription> {
+        Some(x86::register_description(self.convention))
+    }
+
+    fn formats(&self) -> &'static [OutputFormat] {
+        &[OutputFormat::Assembly, OutputFormat::Object]
+    }
+
+    fn emit(
+        &self,
+        module: &IrModule,
+        options: &CodegenOptions,
+        outpath: &Path,
+    ) -> io::Result<Vec<FunctionStats>> {
+        let IrModule {
+            globals,
+            strings,
+            functions,
+        } = module;
+        let object = options.format == OutputFormat::Object;
+        if object && options.line_table.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "debug information is only written in assembly",
+            ));
+        }
+        for function in functions {
+            isel::check_asm(function, self.convention)?;
+        }
+        let mut stats = function_stats(functions);
+        // Position-independent ELF code reaches the globals another object could define, or
+        // override, through the global offset table, as it calls functions through the PLT
+        let through_got: HashSet<String> = globals
+            .iter()
+            .filter(|global| options.pic && self.format == Format::Elf && !global.is_static)
+            .map(|global| global.name.clone())
+            .collect();
+        let defined: Vec<&str> = functions
+            .iter()
+            .map(|function| function.name.as_str())
+            .chain(globals.iter().map(|global| global.name.as_str()))
+            .collect();
+
+        // An object reuses the cached code of each function whose hash hasn't changed, and
+        // only the others go through the rest of code generation, or `--dump-regalloc`'s report
+        let cache = options
+            .cache
+            .clone()
+            .filter(|_| object)
+            .map(FunctionCache::new);
+        let hashes: Vec<u64> = match &cache {
+            Some(_) => {
+                let environment = self.environment(options, &through_got, &defined);
+                functions
+                    .iter()
+                    .map(|function| function_hash(function, &environment))
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        let cached: Vec<Option<CachedFunction>> = functions
+            .iter()
+            .enumerate()
+            .map(|(index, function)| {
+                let cached = cache.as_ref()?.load(hashes[index]);
+                if cached.is_some() {
+                    crate::debug!("reusing the machine code of '{}'", function.name);
+                }
+                cached
+            })
+            .collect();
+
+        let mut functions: Vec<_> = functions
+            .iter()
+            .zip(&cached)
+            .filter(|(_, cached)| cached.is_none())
+            .map(|(function, _)| isel::select_instructions(function, self.convention, &through_got))
+            .collect();
+        mangle(&mut functions, &defined, &options.mangling);
+        let globals: Vec<Global> = globals
+            .iter()
+            .map(|global| Global {
+                name: options.mangling.symbol(&global.name),
+                ..global.clone()
+            })
+            .collect();
+        functions
+            .iter_mut()
+            .for_each(two_address::convert_to_two_address);
+        let registers = x86::register_description(self.convention);
+        let dump = options.dump_regalloc.is_some();
+        let reports: Vec<_> = functions
+            .iter_mut()
+            .filter_map(|function| {
+                let allocator = options.register_allocator;
+                register_allocator::assign_registers(function, &registers, allocator, dump)
+            })
+            .collect();
+        for function in &mut functions {
+            frame::place(function, options.omit_frame_pointer, options.red_zone);
+        }
+        if let Some(path) = &options.dump_regalloc {
+            dump_regalloc(path, &reports)?;
+        }
+
+        if !object {
+            for (stats, function) in stats.iter_mut().zip(&functions) {
+                stats.spills = Some(function.frame.spill_slots);
+                stats.emitted = Some(emitted(function));
+            }
+            let line_table = options.line_table.as_deref();
+            emit_x86(
+                outpath,
+                &functions,
+                &globals,
+                strings,
+                self.format,
+                line_table,
+                options.pic,
+            )?;
+            return Ok(stats);
+        }
+        let mut functions = functions.into_iter();
+        let mut codes = Vec::new();
+        for (index, (stats, cached)) in stats.iter_mut().zip(cached).enumerate() {
+            let cached = match cached {
+                Some(cached) => cached,
+                None => {
+                    let function = functions.next().unwrap();
+                    let compiled = CachedFunction {
+                        code: assemble_function(&function)?,
+                        spills: function.frame.spill_slots,
+                        emitted: emitted(&function),
+                    };
+                    if let Some(cache) = &cache {
+                        cache.store(hashes[index], &compiled)?;
+                    }
+                    compiled
+                }
+            };
+            stats.spills = Some(cached.spills);
+            stats.emitted = Some(cached.emitted);
+            codes.push(cached.code);
+        }
+        emit_x86_object(outpath, &codes, &globals, strings, self.format)?;
+        Ok(stats)
+    }
+
+    fn object_format(&self) -> Option<Format> {
+        Some(self.format)
+    }
+
+    fn llvm_triple(&self) -> Option<&'static str> {
+        Some(self.llvm_triple)
+    }
+
+    fn emit_runtime(&self, outpath: &Path) -> io::Result<()> {
+        runtime::emit_runtime(outpath, self.convention, self.format)
+    }
+}
+
+/// 6502 assembly, for ca65, or machine code in a C64 or NES program, with temps kept in the
+/// zero page
+struct M6502Backend;
+
+impl Backend for M6502Backend {
+    fn triple(&self) -> &'static str {
+        "m6502"
+    }
+
+    /// The zero-page window's bytes, past those the backend keeps its own state in
+    fn registers(&self, options: &CodegenOptions) -> Option<RegisterDescription> {
+        Some(m6502::register_description(&options.zero_page))
+    }
+
+    fn formats(&self) -> &'static [OutputFormat] {
+        &[OutputFormat::Assembly, OutputFormat::Prg, OutputFormat::Nes]
+    }
+
+    fn legalize(&self, module: &mut IrModule) {
+        module.functions.iter_mut().for_each(cfg::leave_ssa);
+    }
+
+    fn emit(
+        &self,
+        module: &IrModule,
+        options: &CodegenOptions,
+        outpath: &Path,
+    ) -> io::Result<Vec<FunctionStats>> {
+        let IrModule {
+            globals,
+            strings,
+            functions,
+        } = module;
+        let Some(zero_page) = m6502::ZeroPage::new(&options.zero_page) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the zero-page window needs at least {} bytes",
+                    m6502::ZeroPage::RESERVED
+                ),
+            ));
+        };
+        if globals
+            .iter()
+            .any(|global| matches!(global.value, Operand::Double(_)))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the 6502 target can't compile doubles",
+            ));
+        }
+        let registers = m6502::register_description(&options.zero_page);
+        let mut functions = functions
+            .iter()
+            .map(|function| m6502::select_instructions(function, &registers, zero_page))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut stats = function_stats(&module.functions);
+        for (stats, function) in stats.iter_mut().zip(&functions) {
+            stats.spills = Some(function.spill_bytes);
+            stats.emitted = Some(
+                function
+                    .instructions
+                    .iter()
+                    .filter(|instruction| !matches!(instruction, M6502Instruction::Label(_)))
+                    .count(),
+            );
+        }
+        // BASIC keeps its own state in the zero page, for when the program returns to it
+        let save_zero_page = options.format == OutputFormat::Prg;
+        // A cartridge's globals can't be written in its ROM, so they're kept in RAM
+        let ram_globals: &[Global] = match options.format {
+            OutputFormat::Nes => globals,
+            _ => &[],
+        };
+        functions.insert(0, m6502::startup(zero_page, save_zero_page, ram_globals));
+        if options.format == OutputFormat::Nes {
+            functions.push(m6502::nes_handlers());
+        }
+        let routines = m6502::runtime::routines(&functions, zero_page, options.format);
+        functions.extend(routines);
+        let variables = m6502::variables(&functions, ram_globals, zero_page, save_zero_page);
+        emit_m6502(
+            outpath,
+            &functions,
+            globals,
+            strings,
+            zero_page,
+            &variables,
+            options.format,
+        )?;
+        Ok(stats)
+    }
+}
+
+/// RISC-V assembly, for RV32IM under the ilp32 calling convention, with temps kept in the
+/// registers the backend's own allocator assigns
+struct RiscvBackend;
+
+impl Backend for RiscvBackend {
+    fn triple(&self) -> &'static str {
+        "riscv32"
+    }
+
+    fn registers(&self, _options: &CodegenOptions) -> Option<RegisterDescription> {
+        Some(riscv::register_description())
+    }
+
+    fn legalize(&self, module: &mut IrModule) {
+        module.functions.iter_mut().for_each(cfg::leave_ssa);
+    }
+
+    fn emit(
+        &self,
+        module: &IrModule,
+        options: &CodegenOptions,
+        outpath: &Path,
+    ) -> io::Result<Vec<FunctionStats>> {
+        let IrModule {
+            globals,
+            strings,
+            functions,
+        } = module;
+        let registers = riscv::register_description();
+        let mut functions = functions
+            .iter()
+            .map(|function| riscv::select_instructions(function, &registers))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut stats = function_stats(&module.functions);
+        for (stats, function) in stats.iter_mut().zip(&functions) {
+            stats.spills = Some(function.spill_slots);
+            stats.emitted = Some(
+                function
+                    .instructions
+                    .iter()
+                    .filter(|instruction| !matches!(instruction, RiscvInstruction::Label(_)))
+                    .count(),
+            );
+        }
+        mangle_riscv(&mut functions, globals, &options.mangling);
+        let globals: Vec<Global> = globals
+            .iter()
+            .map(|global| Global {
+                name: options.mangling.symbol(&global.name),
+                ..global.clone()
+            })
+            .collect();
+        emit_riscv(outpath, &functions, &globals, strings)?;
+        Ok(stats)
+    }
+
+    fn object_format(&self) -> Option<Format> {
+        Some(Format::Elf)
+    }
+
+    fn llvm_triple(&self) -> Option<&'static str> {
+        Some("riscv32-unknown-linux-gnu")
+    }
+
+    fn emit_runtime(&self, outpath: &Path) -> io::Result<()> {
+        runtime::emit_riscv_runtime(outpath)
+    }
+}
+
+/// Writes the interference graphs and the report of what register allocation did next to
+/// `path`, for `--dump-regalloc`
+fn dump_regalloc(path: &Path, reports: &[register_allocator::AllocationReport]) -> io::Result<()> {
+    let mut file = File::create(path.with_extension("regalloc.dot"))?;
+    register_allocator::write_interference_graphs(&mut file, reports)?;
+    let mut file = File::create(path.with_extension("regalloc.txt"))?;
+    register_allocator::write_allocation_report(&mut file, reports)
+}
+
+/// Number of instructions `function` was selected into, without the labels
+fn emitted(function: &x86::X86Function) -> usize {
+    function
+        .instructions
+        .iter()
+        .filter(|instruction| !matches!(instruction, X86Instruction::Label(_)))
+        .count()
+}
+
+/// Names each function, and each call to one, by its symbol under `mangling`, and renames each
+/// access to a global the same way. Only the functions and globals the program defines, named
+/// in `defined`, are renamed: the runtime's functions, which instruction selection calls, keep
+/// their names.
+fn mangle(functions: &mut [x86::X86Function], defined: &[&str], mangling: &Mangling) {
+    let symbols: HashMap<&str, String> = defined
+        .iter()
+        .map(|name| (*name, mangling.symbol(name)))
+        .collect();
+    for function in functions {
+        function.symbol = symbols[function.name.as_str()].clone();
+        for instruction in &mut function.instructions {
+            match instruction {
+                x86::X86Instruction::Call { function, .. } => {
+                    if let Some(symbol) = symbols.get(function.as_str()) {
+                        *function = symbol.clone();
+                    }
+                }
+                x86::X86Instruction::GotLoad { symbol, .. } => {
+                    *symbol = symbols[symbol.as_str()].clone();
+                }
+                x86::X86Instruction::Mov { dest, src, .. }
+                | x86::X86Instruction::Movsd { dest, src } => {
+                    for operand in [dest, src] {
+                        if let x86::X86Operand::Mem(x86::Address {
+                            symbol: Some(symbol),
+                            ..
+                        }) = operand
+                        {
+                            if let Some(mangled) = symbols.get(symbol.as_str()) {
+                                *symbol = mangled.clone();
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Names each RISC-V function, each call to one and each address taken of one of the
+/// `globals` by its symbol under `mangling`, as `mangle` does for x86
+fn mangle_riscv(functions: &mut [riscv::RiscvFunction], globals: &[Global], mangling: &Mangling) {
+    let names = functions
+        .iter()
+        .map(|function| &function.name)
+        .chain(globals.iter().map(|global| &global.name));
+    let symbols: HashMap<String, String> = names
+        .map(|name| (name.clone(), mangling.symbol(name)))
+        .collect();
+    for function in functions {
+        function.symbol = symbols[&function.name].clone();
+        for instruction in &mut function.instructions {
+            if let riscv::RiscvInstruction::Op(
+                riscv::Mnemonic::Call | riscv::Mnemonic::Lla,
+                operands,
+            ) = instruction
+            {
+                if let Some(riscv::RiscvOperand::Symbol(symbol)) = operands.last_mut() {
+                    if let Some(mangled) = symbols.get(symbol) {
+                        *symbol = mangled.clone();
+                    }
+                }
+            }
+        }
+    }
+}
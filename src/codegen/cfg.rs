@@ -0,0 +1,257 @@
+//! Control flow graph of a function's abstract assembly, shared by the analyses and
+//! transformations that work on basic blocks, like SSA construction.
+
+use super::context::{AbstractAssemblyInstruction, AsmLabel, Context, Dest, Operand};
+use std::collections::HashMap;
+
+/// A run of instructions entered only at the top and left only at the bottom
+#[derive(Debug)]
+pub struct BasicBlock {
+    pub label: AsmLabel,
+    /// Instructions after the label
+    pub instructions: Vec<AbstractAssemblyInstruction>,
+    successors: Vec<usize>,
+    predecessors: Vec<usize>,
+}
+
+impl BasicBlock {
+    fn new(label: AsmLabel) -> Self {
+        BasicBlock {
+            label,
+            instructions: Vec::new(),
+            successors: Vec::new(),
+            predecessors: Vec::new(),
+        }
+    }
+}
+
+/// Basic blocks of a function, by index, in the order they're laid out. The entry is block 0.
+#[derive(Debug)]
+pub struct ControlFlowGraph {
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl ControlFlowGraph {
+    /// Splits `instructions` into blocks. A block starts at each label and ends after each
+    /// jump, return or abort. Every block gets a label, taken from `new_label` when the
+    /// instructions don't have one, so that later passes can name it. Blocks that can't be
+    /// reached from the entry are dropped.
+    pub fn new(
+        instructions: Vec<AbstractAssemblyInstruction>,
+        mut new_label: impl FnMut() -> AsmLabel,
+    ) -> Self {
+        let mut blocks = Vec::new();
+        let mut current: Option<BasicBlock> = None;
+        for instruction in instructions {
+            if let AbstractAssemblyInstruction::Lbl(label) = instruction {
+                blocks.extend(current.take());
+                current = Some(BasicBlock::new(label));
+                continue;
+            }
+            let block = current.get_or_insert_with(|| BasicBlock::new(new_label()));
+            let ends_block = instruction.is_terminator();
+            block.instructions.push(instruction);
+            if ends_block {
+                blocks.extend(current.take());
+            }
+        }
+        blocks.extend(current);
+        if blocks.is_empty() {
+            blocks.push(BasicBlock::new(new_label()));
+        }
+
+        // A block that isn't reachable never falls through into a reachable one, so dropping
+        // it keeps the fallthrough edges of the others
+        let reachable = reachable_blocks(&blocks);
+        let mut index = 0;
+        blocks.retain(|_| {
+            index += 1;
+            reachable[index - 1]
+        });
+
+        let mut cfg = ControlFlowGraph { blocks };
+        cfg.connect();
+        cfg
+    }
+
+    /// Fills in the edges between blocks
+    fn connect(&mut self) {
+        let successors = block_successors(&self.blocks);
+        for (block, targets) in successors.into_iter().enumerate() {
+            for &target in &targets {
+                self.blocks[target].predecessors.push(block);
+            }
+            self.blocks[block].successors = targets;
+        }
+    }
+
+    /// Lays the blocks back out as instructions, each starting with its label
+    pub fn into_instructions(self) -> Vec<AbstractAssemblyInstruction> {
+        let mut instructions = Vec::new();
+        for block in self.blocks {
+            instructions.push(AbstractAssemblyInstruction::Lbl(block.label));
+            instructions.extend(block.instructions);
+        }
+        instructions
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Always false, since even an empty function has an entry block
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Blocks control can go to from the end of `block`, in the order its jump names them
+    pub fn successors(&self, block: usize) -> &[usize] {
+        &self.blocks[block].successors
+    }
+
+    /// Blocks control can come to `block` from
+    pub fn predecessors(&self, block: usize) -> &[usize] {
+        &self.blocks[block].predecessors
+    }
+
+    /// Index of the block starting with `label`
+    pub fn block_of(&self, label: AsmLabel) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|block| block.label.0 == label.0)
+    }
+
+    /// Blocks in reverse postorder of a depth-first walk from the entry, so that every block
+    /// comes before its successors, back edges aside
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut postorder = Vec::new();
+        // (block, index of the next successor to visit)
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some((block, next)) = stack.pop() {
+            if let Some(&successor) = self.blocks[block].successors.get(next) {
+                stack.push((block, next + 1));
+                if !visited[successor] {
+                    visited[successor] = true;
+                    stack.push((successor, 0));
+                }
+            } else {
+                postorder.push(block);
+            }
+        }
+        postorder.reverse();
+        postorder
+    }
+
+    /// Replaces each phi with a move from a fresh temp, made by `new_copy` from the phi's own
+    /// temp, which each predecessor sets just before it leaves. The fresh temp is only read at
+    /// the phi, so setting it on an edge that doesn't lead there is harmless, and edges don't
+    /// need splitting.
+    pub fn remove_phis(&mut self, mut new_copy: impl FnMut(usize) -> Dest) {
+        let blocks: HashMap<usize, usize> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(index, block)| (block.label.0, index))
+            .collect();
+        let mut copies: Vec<Vec<AbstractAssemblyInstruction>> = vec![Vec::new(); self.blocks.len()];
+        for block in &mut self.blocks {
+            for instruction in &mut block.instructions {
+                let AbstractAssemblyInstruction::Phi { dest, srcs } = instruction else {
+                    continue;
+                };
+                let copy = match dest {
+                    Dest::Temp(temp) => new_copy(*temp),
+                    Dest::Register(_) => unreachable!("phis write temps"),
+                };
+                for (src, label) in srcs.iter() {
+                    // A predecessor dropped as unreachable never leaves for the phi
+                    if let Some(&predecessor) = blocks.get(&label.0) {
+                        copies[predecessor].push(AbstractAssemblyInstruction::Mov {
+                            dest: copy.clone(),
+                            src: src.clone(),
+                        });
+                    }
+                }
+                *instruction = AbstractAssemblyInstruction::Mov {
+                    dest: dest.clone(),
+                    src: Operand::Var(copy),
+                };
+            }
+        }
+        for (block, copies) in self.blocks.iter_mut().zip(copies) {
+            // Before the jump that leaves, and the comparison it tests
+            let instructions = &block.instructions;
+            let mut end = instructions.len();
+            if instructions.last().is_some_and(|last| last.is_terminator()) {
+                end -= 1;
+                if end > 0 {
+                    if let AbstractAssemblyInstruction::Compare { .. } = instructions[end - 1] {
+                        end -= 1;
+                    }
+                }
+            }
+            block.instructions.splice(end..end, copies);
+        }
+    }
+}
+
+/// Successors of each block, found from its last instruction
+fn block_successors(blocks: &[BasicBlock]) -> Vec<Vec<usize>> {
+    let by_label: HashMap<usize, usize> = blocks
+        .iter()
+        .enumerate()
+        .map(|(index, block)| (block.label.0, index))
+        .collect();
+    blocks
+        .iter()
+        .enumerate()
+        .map(|(index, block)| match block.instructions.last() {
+            Some(AbstractAssemblyInstruction::Jmp(target)) => vec![by_label[&target.0]],
+            Some(AbstractAssemblyInstruction::JmpCondition {
+                tgt_true,
+                tgt_false,
+                ..
+            }) => {
+                let mut targets = vec![by_label[&tgt_true.0]];
+                if tgt_false.0 != tgt_true.0 {
+                    targets.push(by_label[&tgt_false.0]);
+                }
+                targets
+            }
+            Some(instruction) if instruction.is_terminator() => Vec::new(),
+            // Falls through into the next block
+            _ if index + 1 < blocks.len() => vec![index + 1],
+            _ => Vec::new(),
+        })
+        .collect()
+}
+
+fn reachable_blocks(blocks: &[BasicBlock]) -> Vec<bool> {
+    let successors = block_successors(blocks);
+    let mut reachable = vec![false; blocks.len()];
+    let mut worklist = vec![0];
+    reachable[0] = true;
+    while let Some(block) = worklist.pop() {
+        for &successor in &successors[block] {
+            if !reachable[successor] {
+                reachable[successor] = true;
+                worklist.push(successor);
+            }
+        }
+    }
+    reachable
+}
+
+/// Takes `context` out of SSA form, if it's in it, replacing its phis with moves, for a
+/// backend that selects instructions from the abstract assembly as it is
+pub fn leave_ssa(context: &mut Context) {
+    if !context.is_ssa() {
+        return;
+    }
+    let instructions = std::mem::take(&mut context.instructions);
+    let mut cfg = ControlFlowGraph::new(instructions, || AsmLabel(context.new_label()));
+    cfg.remove_phis(|temp| Dest::Temp(context.new_temp_like(temp)));
+    context.instructions = cfg.into_instructions();
+}
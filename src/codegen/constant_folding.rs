@@ -0,0 +1,196 @@
+//! Constant folding: instructions whose operands are all constants are replaced with a move of
+//! their result, and jumps on comparisons of constants become unconditional.
+//!
+//! Constants are propagated within a basic block, so that `2 + 3 * 4` folds to 14 through the
+//! temp holding `3 * 4`. Moves left unused are removed by dead code elimination, not here.
+//! Ints are 32 bits and wrap around, like in `constant.rs`; a division that would fail at
+//! runtime is left for the program to report.
+
+use super::context::{
+    AbstractAssemblyInstruction, Arithmetic, Context, Conversion, Dest, Operand, ShiftKind,
+};
+use super::pass::Pass;
+use super::undefined::Undefined;
+use super::CodegenOptions;
+use crate::parser::{BinOp, UnOp};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+pub struct ConstantFolding;
+
+impl Pass for ConstantFolding {
+    fn name(&self) -> &'static str {
+        "fold-constants"
+    }
+
+    fn level(&self) -> u8 {
+        1
+    }
+
+    fn run(&self, context: &mut Context, _options: &CodegenOptions) {
+        // Value of each temp known to hold a constant at this point of the block
+        let mut known: HashMap<usize, Value> = HashMap::new();
+        // How the operands of the last comparison are ordered, if they're constants
+        let mut flags: Option<Ordering> = None;
+        for instruction in &mut context.instructions {
+            if let AbstractAssemblyInstruction::Lbl(_) = instruction {
+                known.clear();
+                flags = None;
+                continue;
+            }
+            if !matches!(instruction, AbstractAssemblyInstruction::Phi { .. }) {
+                for operand in instruction.operands_mut() {
+                    if let Operand::Var(Dest::Temp(temp)) = operand {
+                        if let Some(value) = known.get(temp) {
+                            *operand = value.operand();
+                        }
+                    }
+                }
+            }
+
+            if let Some(folded) = fold(instruction, &mut flags) {
+                *instruction = folded;
+            }
+            match instruction {
+                AbstractAssemblyInstruction::Mov {
+                    dest: Dest::Temp(temp),
+                    src,
+                } => match Value::of(src) {
+                    Some(value) => known.insert(*temp, value),
+                    None => known.remove(temp),
+                },
+                _ => match instruction.dest() {
+                    Some(Dest::Temp(temp)) => known.remove(temp),
+                    _ => None,
+                },
+            };
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    Int(i32),
+    Double(f64),
+}
+
+impl Value {
+    fn of(operand: &Operand) -> Option<Self> {
+        match operand {
+            Operand::Const(value) => Some(Value::Int(*value as i32)),
+            Operand::Double(value) => Some(Value::Double(*value)),
+            Operand::Var(_) | Operand::Str(_) => None,
+        }
+    }
+
+    fn operand(self) -> Operand {
+        match self {
+            Value::Int(value) => Operand::Const(value as i128),
+            Value::Double(value) => Operand::Double(value),
+        }
+    }
+}
+
+/// Simpler instruction computing the same thing as `instruction`, if its operands are known.
+/// Comparisons of constants update `flags` for the instructions reading them.
+fn fold(
+    instruction: &AbstractAssemblyInstruction,
+    flags: &mut Option<Ordering>,
+) -> Option<AbstractAssemblyInstruction> {
+    let result = match instruction {
+        AbstractAssemblyInstruction::BinOp {
+            op,
+            arithmetic,
+            src1,
+            src2,
+            ..
+        } => binary(*op, *arithmetic, Value::of(src1)?, Value::of(src2)?)?,
+        AbstractAssemblyInstruction::UnOp { op, src, .. } => match (op, Value::of(src)?) {
+            (UnOp::Neg, Value::Int(value)) => Value::Int(value.wrapping_neg()),
+            (UnOp::Neg, Value::Double(value)) => Value::Double(-value),
+            (UnOp::Not, Value::Int(value)) => Value::Int((value == 0) as i32),
+            (UnOp::BitNot, Value::Int(value)) => Value::Int(!value),
+            _ => return None,
+        },
+        AbstractAssemblyInstruction::Convert {
+            conversion, src, ..
+        } => match (conversion, Value::of(src)?) {
+            (Conversion::I2D, Value::Int(value)) => Value::Double(value as f64),
+            // Rust's conversion truncates toward zero like C0's. A double no int can hold
+            // aborts the program when it runs.
+            (Conversion::D2I, Value::Double(value)) if Undefined::of_d2i(value).is_none() => {
+                Value::Int(value as i32)
+            }
+            _ => return None,
+        },
+        AbstractAssemblyInstruction::Shift {
+            kind, src, amount, ..
+        } => match Value::of(src)? {
+            Value::Int(value) => Value::Int(match kind {
+                ShiftKind::Left => value.wrapping_shl(*amount),
+                ShiftKind::ArithmeticRight => value.wrapping_shr(*amount),
+                ShiftKind::LogicalRight => (value as u32).wrapping_shr(*amount) as i32,
+            }),
+            Value::Double(_) => return None,
+        },
+        AbstractAssemblyInstruction::Compare { left, right, .. } => {
+            *flags = match (Value::of(left), Value::of(right)) {
+                (Some(Value::Int(left)), Some(Value::Int(right))) => Some(left.cmp(&right)),
+                (Some(Value::Double(left)), Some(Value::Double(right))) => left.partial_cmp(&right),
+                _ => None,
+            };
+            return None;
+        }
+        AbstractAssemblyInstruction::SetIf { dest, condition } => {
+            let holds = condition.holds((*flags)?);
+            return Some(AbstractAssemblyInstruction::Mov {
+                dest: dest.clone(),
+                src: Operand::Const(holds as i128),
+            });
+        }
+        AbstractAssemblyInstruction::JmpCondition {
+            condition,
+            tgt_true,
+            tgt_false,
+        } => {
+            let target = if condition.holds((*flags)?) {
+                tgt_true
+            } else {
+                tgt_false
+            };
+            return Some(AbstractAssemblyInstruction::Jmp(*target));
+        }
+        _ => return None,
+    };
+    Some(AbstractAssemblyInstruction::Mov {
+        dest: instruction.dest()?.clone(),
+        src: result.operand(),
+    })
+}
+
+fn binary(op: BinOp, arithmetic: Arithmetic, left: Value, right: Value) -> Option<Value> {
+    match (arithmetic, left, right) {
+        // What's undefined is left for the program to trap on when it runs
+        (Arithmetic::Int, Value::Int(left), Value::Int(right))
+            if Undefined::of_int(op, left, right).is_none() =>
+        {
+            Some(Value::Int(match op {
+                BinOp::Add => left.wrapping_add(right),
+                BinOp::Sub => left.wrapping_sub(right),
+                BinOp::Mul => left.wrapping_mul(right),
+                BinOp::Div => left / right,
+                _ => return None,
+            }))
+        }
+        (Arithmetic::Double, Value::Double(left), Value::Double(right)) => {
+            Some(Value::Double(match op {
+                BinOp::Add => left + right,
+                BinOp::Sub => left - right,
+                BinOp::Mul => left * right,
+                BinOp::Div => left / right,
+                _ => return None,
+            }))
+        }
+        _ => None,
+    }
+}
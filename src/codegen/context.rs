@@ -1,18 +1,27 @@
 use crate::lexer::Token;
-use crate::parser::{Block, Expr, FnDeclaration, Program, Statement, VarDeclaration};
+use crate::parser::{
+    AsmConstraints, BinOp, Expr, FnDeclaration, FormatPart, FormatSpec, LValue, Parameter,
+    Statement, UnOp, VarDeclaration,
+};
+use crate::sema::{type_of, Type};
+use crate::source_map::{LineTable, Span, Spanned};
+use crate::symbol_table::SymbolTable;
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::mem::uninitialized;
+use std::rc::Rc;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum AbstractAssemblyInstruction {
     BinOp {
-        op: Token,
+        op: BinOp,
+        arithmetic: Arithmetic,
         dest: Dest,
         src1: Operand,
         src2: Operand,
     },
     UnOp {
-        op: Token,
+        op: UnOp,
+        arithmetic: Arithmetic,
         dest: Dest,
         src: Operand,
     },
@@ -20,7 +29,21 @@ pub enum AbstractAssemblyInstruction {
         dest: Dest,
         src: Operand,
     },
+    Convert {
+        conversion: Conversion,
+        dest: Dest,
+        src: Operand,
+    },
+    /// Shifts an int by a constant number of bits. Only produced by optimizations, since C0
+    /// has no shift operators.
+    Shift {
+        kind: ShiftKind,
+        dest: Dest,
+        src: Operand,
+        amount: u32,
+    },
     Compare {
+        arithmetic: Arithmetic,
         left: Operand,
         right: Operand,
         condition: Condition,
@@ -36,29 +59,218 @@ pub enum AbstractAssemblyInstruction {
     },
     Jmp(AsmLabel),
     Lbl(AsmLabel),
+    /// Marks the instructions up to the next `Loc` as compiled from line `line` of the file
+    /// numbered `file` in the program's `LineTable`; only generated with `-g`
+    Loc {
+        file: usize,
+        line: usize,
+    },
+    /// Takes the operand coming from the block labeled with its label; only in SSA form
     Phi {
         dest: Dest,
         srcs: Vec<(Operand, AsmLabel)>,
     },
+    /// Prints one value, formatted as `spec`
+    Print {
+        spec: FormatSpec,
+        src: Operand,
+    },
+    /// Reads a number, formatted as `spec`, which is `%d` or `%f`, from the input into `dest`.
+    /// The program aborts if the input doesn't go on with one.
+    Scan {
+        dest: Dest,
+        spec: FormatSpec,
+    },
+    /// Calls `function` with `args`, which are already of its parameters' types, keeping what
+    /// it returns in `dest` if there's one
+    Call {
+        dest: Option<Dest>,
+        function: String,
+        args: Vec<Operand>,
+    },
+    /// Reads the global named `global` into `dest`
+    Load {
+        dest: Dest,
+        global: String,
+    },
+    /// Writes `src` to the global named `global`
+    Store {
+        global: String,
+        src: Operand,
+    },
+    /// Inline assembly, passed through to the target's assembly with `%0` standing for
+    /// `output`'s register, if there's one, and the inputs' registers numbered after it. The
+    /// constraints may name the register an operand is in, and the registers the template
+    /// overwrites.
+    Asm {
+        template: String,
+        output: Option<Dest>,
+        inputs: Vec<Operand>,
+        constraints: AsmConstraints,
+    },
+    /// Ends the program after a failed contract
+    Abort,
     Return(Operand),
     ReturnVoid,
 }
 
-#[derive(Debug, Clone)]
+impl AbstractAssemblyInstruction {
+    /// Temp or register the instruction writes, if any
+    pub fn dest(&self) -> Option<&Dest> {
+        match self {
+            AbstractAssemblyInstruction::BinOp { dest, .. }
+            | AbstractAssemblyInstruction::UnOp { dest, .. }
+            | AbstractAssemblyInstruction::Mov { dest, .. }
+            | AbstractAssemblyInstruction::Convert { dest, .. }
+            | AbstractAssemblyInstruction::Shift { dest, .. }
+            | AbstractAssemblyInstruction::SetIf { dest, .. }
+            | AbstractAssemblyInstruction::Load { dest, .. }
+            | AbstractAssemblyInstruction::Scan { dest, .. }
+            | AbstractAssemblyInstruction::Phi { dest, .. } => Some(dest),
+            AbstractAssemblyInstruction::Call { dest, .. }
+            | AbstractAssemblyInstruction::Asm { output: dest, .. } => dest.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn dest_mut(&mut self) -> Option<&mut Dest> {
+        match self {
+            AbstractAssemblyInstruction::BinOp { dest, .. }
+            | AbstractAssemblyInstruction::UnOp { dest, .. }
+            | AbstractAssemblyInstruction::Mov { dest, .. }
+            | AbstractAssemblyInstruction::Convert { dest, .. }
+            | AbstractAssemblyInstruction::Shift { dest, .. }
+            | AbstractAssemblyInstruction::SetIf { dest, .. }
+            | AbstractAssemblyInstruction::Load { dest, .. }
+            | AbstractAssemblyInstruction::Scan { dest, .. }
+            | AbstractAssemblyInstruction::Phi { dest, .. } => Some(dest),
+            AbstractAssemblyInstruction::Call { dest, .. }
+            | AbstractAssemblyInstruction::Asm { output: dest, .. } => dest.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Operands the instruction reads
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            AbstractAssemblyInstruction::BinOp { src1, src2, .. } => vec![src1, src2],
+            AbstractAssemblyInstruction::Compare { left, right, .. } => vec![left, right],
+            AbstractAssemblyInstruction::UnOp { src, .. }
+            | AbstractAssemblyInstruction::Mov { src, .. }
+            | AbstractAssemblyInstruction::Convert { src, .. }
+            | AbstractAssemblyInstruction::Shift { src, .. }
+            | AbstractAssemblyInstruction::Print { src, .. }
+            | AbstractAssemblyInstruction::Store { src, .. }
+            | AbstractAssemblyInstruction::Return(src) => vec![src],
+            AbstractAssemblyInstruction::Phi { srcs, .. } => {
+                srcs.iter().map(|(operand, _)| operand).collect()
+            }
+            AbstractAssemblyInstruction::Call { args, .. }
+            | AbstractAssemblyInstruction::Asm { inputs: args, .. } => args.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            AbstractAssemblyInstruction::BinOp { src1, src2, .. } => vec![src1, src2],
+            AbstractAssemblyInstruction::Compare { left, right, .. } => vec![left, right],
+            AbstractAssemblyInstruction::UnOp { src, .. }
+            | AbstractAssemblyInstruction::Mov { src, .. }
+            | AbstractAssemblyInstruction::Convert { src, .. }
+            | AbstractAssemblyInstruction::Shift { src, .. }
+            | AbstractAssemblyInstruction::Print { src, .. }
+            | AbstractAssemblyInstruction::Store { src, .. }
+            | AbstractAssemblyInstruction::Return(src) => vec![src],
+            AbstractAssemblyInstruction::Phi { srcs, .. } => {
+                srcs.iter_mut().map(|(operand, _)| operand).collect()
+            }
+            AbstractAssemblyInstruction::Call { args, .. }
+            | AbstractAssemblyInstruction::Asm { inputs: args, .. } => args.iter_mut().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Labels the instruction jumps to
+    pub fn jump_targets_mut(&mut self) -> Vec<&mut AsmLabel> {
+        match self {
+            AbstractAssemblyInstruction::Jmp(target) => vec![target],
+            AbstractAssemblyInstruction::JmpCondition {
+                tgt_true,
+                tgt_false,
+                ..
+            } => vec![tgt_true, tgt_false],
+            _ => Vec::new(),
+        }
+    }
+
+    /// True if control never goes on to the next instruction
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            AbstractAssemblyInstruction::Jmp(_)
+                | AbstractAssemblyInstruction::JmpCondition { .. }
+                | AbstractAssemblyInstruction::Return(_)
+                | AbstractAssemblyInstruction::ReturnVoid
+                | AbstractAssemblyInstruction::Abort
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Dest {
+    #[allow(dead_code)] // Not produced until register allocation lands
     Register(usize),
     Temp(usize),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Operand {
     Const(i128),
+    Double(f64),
     Var(Dest),
+    /// Address of a string constant, by its index in the program's `StringTable`
+    Str(usize),
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct AsmLabel(pub usize);
 
+/// Whether an instruction works on ints or doubles. Chars compare like ints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arithmetic {
+    Int,
+    Double,
+}
+
+impl Arithmetic {
+    fn of(ty: Type) -> Self {
+        match ty {
+            Type::Double => Arithmetic::Double,
+            _ => Arithmetic::Int,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Conversion {
+    /// int to double
+    I2D,
+    /// double to int, truncating toward zero
+    D2I,
+    /// int to char, keeping the low byte
+    I2C,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShiftKind {
+    Left,
+    /// Fills in copies of the sign bit, so that it divides by a power of two rounding down
+    ArithmeticRight,
+    /// Fills in zeros
+    LogicalRight,
+}
+
 #[derive(Debug, Clone)]
 pub enum Condition {
     Greater,
@@ -69,60 +281,401 @@ pub enum Condition {
     LessOrEqual,
 }
 
+impl Condition {
+    /// Whether the condition holds for compared operands ordered as `ordering`
+    pub fn holds(&self, ordering: Ordering) -> bool {
+        match self {
+            Condition::Equal => ordering == Ordering::Equal,
+            Condition::NotEqual => ordering != Ordering::Equal,
+            Condition::Less => ordering == Ordering::Less,
+            Condition::LessOrEqual => ordering != Ordering::Greater,
+            Condition::Greater => ordering == Ordering::Greater,
+            Condition::GreaterOrEqual => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// Condition tested by a comparison operator, or None for arithmetic operators
+fn comparison_condition(op: &BinOp) -> Option<Condition> {
+    match op {
+        BinOp::Equal => Some(Condition::Equal),
+        BinOp::NotEqual => Some(Condition::NotEqual),
+        BinOp::Less => Some(Condition::Less),
+        BinOp::LessEqual => Some(Condition::LessOrEqual),
+        BinOp::Greater => Some(Condition::Greater),
+        BinOp::GreaterEqual => Some(Condition::GreaterOrEqual),
+        BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div => None,
+    }
+}
+
+/// A global variable and the value it starts with
+#[derive(Debug, Clone)]
+pub struct Global {
+    pub name: String,
+    /// True if the global is `static`, and so not exported
+    pub is_static: bool,
+    pub value: Operand,
+}
+
+impl Global {
+    /// The global `declaration` declares. Globals without an initializer are zero-initialized.
+    pub fn new(declaration: &VarDeclaration, strings: &StringTable) -> Option<Self> {
+        let Token::Identifier(name) = &declaration.identifier else {
+            return None;
+        };
+        let is_double = declaration.type_token == Token::Double;
+        let value = match declaration.value.as_ref().map(|value| &value.node) {
+            None if is_double => Operand::Double(0.0),
+            None => Operand::Const(0),
+            Some(Expr::Literal(Token::Number(num))) if is_double => Operand::Double(*num),
+            Some(Expr::Literal(Token::Number(num))) => Operand::Const(*num as i128),
+            Some(Expr::Literal(Token::DoubleLiteral(num))) => Operand::Double(*num),
+            Some(Expr::Literal(Token::StringLiteral(string))) => Operand::Str(
+                strings
+                    .get(string)
+                    .expect("global string initializers are interned before codegen"),
+            ),
+            Some(_) => unreachable!("global initializers are folded to literals before codegen"),
+        };
+        Some(Global {
+            name: name.clone(),
+            is_static: declaration.is_static,
+            value,
+        })
+    }
+}
+
+/// String constants of the whole program, in the order they were first used.
+/// Identical literals share one entry.
+#[derive(Debug, Default)]
+pub struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, usize>,
+}
+
+impl StringTable {
+    pub fn new() -> Self {
+        StringTable::default()
+    }
+
+    /// Index of `string` in the table, adding it if it's new
+    pub fn intern(&mut self, string: &str) -> usize {
+        if let Some(&index) = self.indices.get(string) {
+            return index;
+        }
+        let index = self.strings.len();
+        self.strings.push(string.to_string());
+        self.indices.insert(string.to_string(), index);
+        index
+    }
+
+    pub fn get(&self, string: &str) -> Option<usize> {
+        self.indices.get(string).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.strings.iter().map(String::as_str).enumerate()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+/// Parameter and return types of a function, which its callers convert their arguments to and
+/// keep its result as
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub params: Vec<Type>,
+    pub return_type: Type,
+}
+
+impl Signature {
+    pub fn new(return_type: &Token, params: &[Parameter]) -> Self {
+        Signature {
+            params: params
+                .iter()
+                .map(|param| variable_type(&param.type_token))
+                .collect(),
+            return_type: type_of(return_type).unwrap_or(Type::Void),
+        }
+    }
+}
+
+/// Where a variable is kept
+enum Variable {
+    /// A local or parameter, in a temp
+    Local(Dest),
+    /// A global, in memory, by name
+    Global(String),
+}
+
 /// Context for a function
 pub struct Context {
     /// Name of function this context is for
     pub name: String,
+    /// True if the function is `static`, and so not exported
+    pub is_static: bool,
+    /// Number of parameters, which are passed in the first temps
+    pub params: usize,
     /// Abstract assembly instructions this function compiles into
     pub instructions: Vec<AbstractAssemblyInstruction>,
     /// Largest temp number that has not been used
     temp_counter: usize,
     /// Largest label number that has not been used
     label_counter: usize,
-    /// Given a variable name, get the associated temp
-    var_to_temp: HashMap<String, usize>,
+    /// Given a variable name, get the associated temp of its innermost declaration
+    var_to_temp: SymbolTable<usize>,
+    /// Type of the value each temp holds
+    temp_types: HashMap<usize, Type>,
+    /// Source variable each temp holds, for temps that hold one
+    temp_names: HashMap<usize, String>,
+    /// Type the function returns
+    return_type: Type,
+    /// Signature of every function in the program, by name
+    signatures: HashMap<String, Signature>,
+    /// Postconditions to check before each return, with `\old` already snapshotted
+    ensures: Vec<Spanned<Expr>>,
+    /// Holds the value being returned while the postconditions are checked
+    result: Option<Dest>,
+    /// (continue, break) targets of the loops we're in, innermost last
+    loops: Vec<(AsmLabel, AsmLabel)>,
+    /// Type of each global, by name, for the variables no scope declares
+    globals: HashMap<String, Type>,
+    /// Where each offset of the program is in its source files, with `-g`, so that each
+    /// statement's instructions are marked with its line
+    line_table: Option<Rc<LineTable>>,
 }
 
 impl Context {
-    pub fn new(name: &str) -> Self {
+    pub fn new(name: &str, is_static: bool) -> Self {
         Context {
             name: name.to_string(),
+            is_static,
+            params: 0,
             instructions: Vec::new(),
             temp_counter: 0,
             label_counter: 0,
-            /// TODO: if we're converting to SSA, then we'd want to create a new version of each variable
-            /// for each assignment, as well as for each branch. Also some way of placing phi nodes
-            var_to_temp: HashMap::new(),
+            var_to_temp: SymbolTable::new(),
+            temp_types: HashMap::new(),
+            temp_names: HashMap::new(),
+            return_type: Type::Void,
+            signatures: HashMap::new(),
+            ensures: Vec::new(),
+            result: None,
+            loops: Vec::new(),
+            globals: HashMap::new(),
+            line_table: None,
         }
     }
 
-    pub fn generate(&mut self, fn_declaration: &FnDeclaration) {
-        // Assign parameters to temps
-        for (param_idx, param) in fn_declaration.params.iter().enumerate() {
+    /// Context for instructions read back from text, with the type of each temp they use and
+    /// the variables of those that hold one. New temps and labels are numbered after the
+    /// largest ones in `instructions`.
+    pub(super) fn from_instructions(
+        name: &str,
+        is_static: bool,
+        params: usize,
+        instructions: Vec<AbstractAssemblyInstruction>,
+        temp_types: HashMap<usize, Type>,
+        temp_names: HashMap<usize, String>,
+    ) -> Self {
+        let mut context = Context::new(name, is_static);
+        context.params = params;
+        context.temp_counter = temp_types.keys().max().map_or(0, |&temp| temp + 1);
+        context.label_counter = instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                AbstractAssemblyInstruction::Lbl(label) => Some(label.0 + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        context.temp_types = temp_types;
+        context.temp_names = temp_names;
+        context.instructions = instructions;
+        context
+    }
+
+    /// True if the instructions are in SSA form, which only they have phis in
+    pub(super) fn is_ssa(&self) -> bool {
+        self.instructions
+            .iter()
+            .any(|instruction| matches!(instruction, AbstractAssemblyInstruction::Phi { .. }))
+    }
+
+    /// Generates the function body, calling the functions in `signatures` and using the
+    /// `globals` its scopes don't shadow. String literals are added to the program-wide
+    /// `strings`. With a `line_table`, the instructions of each
+    /// statement are marked with its line.
+    pub fn generate(
+        &mut self,
+        fn_declaration: &FnDeclaration,
+        signatures: &HashMap<String, Signature>,
+        globals: &HashMap<String, Type>,
+        strings: &mut StringTable,
+        line_table: Option<Rc<LineTable>>,
+    ) {
+        self.line_table = line_table;
+        self.mark_line(fn_declaration.span);
+        // Sema has checked every type, so none are missing here
+        self.return_type = type_of(&fn_declaration.return_type).unwrap_or(Type::Void);
+        self.signatures = signatures.clone();
+        self.globals = globals.clone();
+        self.params = fn_declaration.params.len();
+
+        // Assign parameters to temps, in the function's outermost scope
+        self.var_to_temp.push_scope();
+        for param in &fn_declaration.params {
             if let Token::Identifier(param_name) = &param.identifier {
-                let dest_temp = self.new_temp();
-                self.var_to_temp.insert(param_name.clone(), dest_temp);
+                let ty = variable_type(&param.type_token);
+                let dest_temp = self.new_variable_temp(ty, param_name);
+                self.var_to_temp.insert(param_name, dest_temp);
             }
         }
 
+        for condition in &fn_declaration.requires {
+            self.generate_assert(&condition.node, "@requires", strings);
+        }
+        self.ensures = fn_declaration
+            .ensures
+            .iter()
+            .map(|condition| self.snapshot_old_values(condition, strings))
+            .collect();
+
         for statement in &fn_declaration.body.statements {
-            self.generate_statement(statement);
+            self.generate_line(statement, strings);
+        }
+
+        // A void function may also return by reaching the end of its body
+        let ends_in_return = matches!(
+            fn_declaration.body.statements.last().map(|s| &s.node),
+            Some(Statement::Return(_))
+        );
+        if fn_declaration.return_type == Token::Void && !ends_in_return {
+            // At the closing brace
+            let end = fn_declaration.body.span.end.saturating_sub(1);
+            self.mark_line(Span::new(end, end + 1));
+            self.generate_statement(&Statement::Return(None), strings);
+        }
+        self.var_to_temp.pop_scope();
+    }
+
+    /// Copies `expr`, saving the value of each `\old(inner)` in a fresh temp and replacing it
+    /// with a variable bound to that temp. The names start with `\`, so they can't clash with
+    /// the program's own variables.
+    fn snapshot_old_values(
+        &mut self,
+        expr: &Spanned<Expr>,
+        strings: &mut StringTable,
+    ) -> Spanned<Expr> {
+        let mut snapshot = |expr: &Spanned<Expr>| Box::new(self.snapshot_old_values(expr, strings));
+        let node = match &expr.node {
+            Expr::Old(inner) => {
+                let value = self.generate_expr(&inner.node, strings);
+                let temp = self.new_temp(self.operand_type(&value));
+                self.instructions.push(AbstractAssemblyInstruction::Mov {
+                    dest: Dest::Temp(temp),
+                    src: value,
+                });
+                let name = format!("\\old{}", temp);
+                self.var_to_temp.insert(&name, temp);
+                Expr::Variable(Token::Identifier(name))
+            }
+            Expr::Unary(op, operand) => Expr::Unary(*op, snapshot(operand)),
+            Expr::Binary(left, op, right) => {
+                let left = snapshot(left);
+                Expr::Binary(left, *op, snapshot(right))
+            }
+            Expr::Parentheses(inner) => Expr::Parentheses(snapshot(inner)),
+            Expr::Call(callee, args) => Expr::Call(
+                snapshot(callee),
+                args.iter().map(|arg| *snapshot(arg)).collect(),
+            ),
+            Expr::Cast(type_token, operand) => Expr::Cast(type_token.clone(), snapshot(operand)),
+            Expr::Assign(target, value) => Expr::Assign(target.clone(), snapshot(value)),
+            Expr::CompoundAssign(target, op, value) => {
+                Expr::CompoundAssign(target.clone(), *op, snapshot(value))
+            }
+            Expr::Literal(_) | Expr::Variable(_) | Expr::Result => expr.node.clone(),
+        };
+        Spanned::new(node, expr.span)
+    }
+
+    /// Checks `condition`, printing a message and aborting if it doesn't hold
+    fn generate_assert(&mut self, condition: &Expr, annotation: &str, strings: &mut StringTable) {
+        let message = format!("{} annotation failed in {}\n", annotation, self.name);
+        self.generate_check(condition, &message, strings);
+    }
+
+    /// Checks `condition`, printing `message` and aborting if it doesn't hold
+    fn generate_check(&mut self, condition: &Expr, message: &str, strings: &mut StringTable) {
+        let ok_label = AsmLabel(self.new_label());
+        let fail_label = AsmLabel(self.new_label());
+        self.generate_condition(condition, ok_label, fail_label, strings);
+
+        self.instructions
+            .push(AbstractAssemblyInstruction::Lbl(fail_label));
+        self.instructions.push(AbstractAssemblyInstruction::Print {
+            spec: FormatSpec::String,
+            src: Operand::Str(strings.intern(message)),
+        });
+        self.instructions.push(AbstractAssemblyInstruction::Abort);
+        self.instructions
+            .push(AbstractAssemblyInstruction::Lbl(ok_label));
+    }
+
+    /// Marks the instructions generated next as compiled from the line `span` starts on, if
+    /// there's a line table
+    fn mark_line(&mut self, span: Span) {
+        let Some((file, line)) = self
+            .line_table
+            .as_ref()
+            .and_then(|lines| lines.locate(span.start))
+        else {
+            return;
+        };
+        let loc = AbstractAssemblyInstruction::Loc { file, line };
+        // Statements sharing a line need only one mark
+        if let Some(AbstractAssemblyInstruction::Loc {
+            file: last_file,
+            line: last_line,
+        }) = self.instructions.last()
+        {
+            if (*last_file, *last_line) == (file, line) {
+                return;
+            }
+        }
+        self.instructions.push(loc);
+    }
+
+    /// Generates `statement`, marked with its line. A block's statements are marked with
+    /// their own.
+    fn generate_line(&mut self, statement: &Spanned<Statement>, strings: &mut StringTable) {
+        if !matches!(statement.node, Statement::Block(_)) {
+            self.mark_line(statement.span);
         }
+        self.generate_statement(&statement.node, strings);
     }
 
-    fn generate_statement(&mut self, statement: &Statement) {
+    fn generate_statement(&mut self, statement: &Statement, strings: &mut StringTable) {
         match statement {
             Statement::VarDecl(declr) => {
                 if let Token::Identifier(varname) = &declr.identifier {
                     // Create temp for new variable
-                    let dest_temp = self.new_temp();
-                    self.var_to_temp.insert(varname.clone(), dest_temp);
+                    let ty = variable_type(&declr.type_token);
+                    let dest_temp = self.new_variable_temp(ty, varname);
+                    self.var_to_temp.insert(varname, dest_temp);
                     let dest = Dest::Temp(dest_temp);
 
                     // Compute the expression, populate in temp
-                    let src = self.generate_expr(&declr.value);
-                    self.instructions
-                        .push(AbstractAssemblyInstruction::Mov { dest, src });
+                    // Without an initializer, the variable stays unassigned until its first assignment
+                    if let Some(value) = &declr.value {
+                        let src = self.generate_expr(&value.node, strings);
+                        let src = self.convert(src, ty);
+                        self.instructions
+                            .push(AbstractAssemblyInstruction::Mov { dest, src });
+                    }
                 } else {
                     panic!("Invalid identifier"); // Better error handling here
                 }
@@ -143,14 +696,14 @@ impl Context {
                 };
 
                 // Generate condition evaluation
-                self.generate_condition(condition_expr, then_label, else_label);
+                self.generate_condition(&condition_expr.node, then_label, else_label, strings);
 
                 // 2. Generate code for "then" branch
                 // If the "else" branch exists, we must jump to end_label when done
                 // Otherwise, we can just fall into the end_label
                 self.instructions
                     .push(AbstractAssemblyInstruction::Lbl(then_label));
-                self.generate_statement(then_branch);
+                self.generate_line(then_branch, strings);
                 if has_else {
                     self.instructions
                         .push(AbstractAssemblyInstruction::Jmp(end_label));
@@ -160,31 +713,200 @@ impl Context {
                 if let Some(else_branch) = else_branch {
                     self.instructions
                         .push(AbstractAssemblyInstruction::Lbl(else_label));
-                    self.generate_statement(else_branch);
-                    self.instructions
-                        .push(AbstractAssemblyInstruction::Lbl(end_label));
+                    self.generate_line(else_branch, strings);
+                }
+                self.instructions
+                    .push(AbstractAssemblyInstruction::Lbl(end_label));
+            }
+            Statement::While(condition_expr, invariants, body) => {
+                // Invariants are checked every time the condition is about to be tested
+                let head_label = AsmLabel(self.new_label());
+                let body_label = AsmLabel(self.new_label());
+                let end_label = AsmLabel(self.new_label());
+
+                self.instructions
+                    .push(AbstractAssemblyInstruction::Lbl(head_label));
+                // Each time around, control comes back to the condition's line
+                self.mark_line(condition_expr.span);
+                for invariant in invariants {
+                    self.generate_assert(&invariant.node, "@loop_invariant", strings);
                 }
+                self.generate_condition(&condition_expr.node, body_label, end_label, strings);
+
+                self.instructions
+                    .push(AbstractAssemblyInstruction::Lbl(body_label));
+                self.loops.push((head_label, end_label));
+                self.generate_line(body, strings);
+                self.loops.pop();
+                self.instructions
+                    .push(AbstractAssemblyInstruction::Jmp(head_label));
+                self.instructions
+                    .push(AbstractAssemblyInstruction::Lbl(end_label));
+            }
+            Statement::Break => {
+                let (_, break_label) = *self.loops.last().expect("break outside of a loop");
+                self.instructions
+                    .push(AbstractAssemblyInstruction::Jmp(break_label));
+            }
+            Statement::Continue => {
+                let (continue_label, _) = *self.loops.last().expect("continue outside of a loop");
+                self.instructions
+                    .push(AbstractAssemblyInstruction::Jmp(continue_label));
+            }
+            Statement::Assert(condition) => {
+                self.generate_assert(&condition.node, "@assert", strings);
+            }
+            Statement::Check(condition) => {
+                let message = format!("assert failed in {}\n", self.name);
+                self.generate_check(&condition.node, &message, strings);
             }
             Statement::Block(block) => {
-                // Handle blocks by generating all their statements
+                // Variables declared in the block end with it
+                self.var_to_temp.push_scope();
                 for stmt in &block.statements {
-                    self.generate_statement(stmt);
+                    self.generate_line(stmt, strings);
                 }
+                self.var_to_temp.pop_scope();
             }
             Statement::Return(value) => {
-                if let Some(expr) = value {
-                    let operand = self.generate_expr(expr);
-                    self.instructions
-                        .push(AbstractAssemblyInstruction::Return(operand));
-                } else {
-                    self.instructions
-                        .push(AbstractAssemblyInstruction::ReturnVoid);
+                // The return value is computed before the postconditions are checked
+                let mut operand = value.as_ref().map(|expr| {
+                    let value = self.generate_expr(&expr.node, strings);
+                    self.convert(value, self.return_type)
+                });
+                if !self.ensures.is_empty() {
+                    // Both `\result` and the return itself read the value, so keep it in a temp
+                    if let Some(value) = operand {
+                        let dest = Dest::Temp(self.new_temp(self.return_type));
+                        self.instructions.push(AbstractAssemblyInstruction::Mov {
+                            dest: dest.clone(),
+                            src: value,
+                        });
+                        self.result = Some(dest.clone());
+                        operand = Some(Operand::Var(dest));
+                    }
+                    let ensures = std::mem::take(&mut self.ensures);
+                    for condition in &ensures {
+                        self.generate_assert(&condition.node, "@ensures", strings);
+                    }
+                    self.ensures = ensures;
+                    self.result = None;
+                }
+                match operand {
+                    Some(operand) => self
+                        .instructions
+                        .push(AbstractAssemblyInstruction::Return(operand)),
+                    None => self
+                        .instructions
+                        .push(AbstractAssemblyInstruction::ReturnVoid),
                 }
             }
             Statement::Expression(expr) => {
-                self.generate_expr(expr);
+                self.generate_expr(&expr.node, strings);
+            }
+            Statement::Print(expr) => {
+                let src = self.generate_expr(&expr.node, strings);
+                let spec = match self.operand_type(&src) {
+                    Type::Int => FormatSpec::Int,
+                    Type::Double => FormatSpec::Double,
+                    Type::Char => FormatSpec::Char,
+                    Type::String => FormatSpec::String,
+                    Type::Void => unreachable!("sema rejects printing a void value"),
+                };
+                self.instructions
+                    .push(AbstractAssemblyInstruction::Print { spec, src });
+            }
+            Statement::PrintFormat(format, args) => {
+                // Arguments are all evaluated before anything is printed
+                let mut values = Vec::new();
+                let specs = format.iter().filter_map(|part| match part {
+                    FormatPart::Arg(spec) => Some(spec),
+                    FormatPart::Text(_) => None,
+                });
+                for (arg, spec) in args.iter().zip(specs) {
+                    let value = self.generate_expr(&arg.node, strings);
+                    // A whole number literal may be printed as a double
+                    let ty = match spec {
+                        FormatSpec::Double => Type::Double,
+                        _ => self.operand_type(&value),
+                    };
+                    values.push(self.convert(value, ty));
+                }
+                let mut values = values.into_iter();
+                for part in format {
+                    let (spec, src) = match part {
+                        FormatPart::Text(text) => {
+                            (FormatSpec::String, Operand::Str(strings.intern(text)))
+                        }
+                        FormatPart::Arg(spec) => (*spec, values.next().unwrap()),
+                    };
+                    self.instructions
+                        .push(AbstractAssemblyInstruction::Print { spec, src });
+                }
+            }
+            Statement::Scan(LValue::Variable(token)) => {
+                // A global is read into a temp, and stored after
+                let (dest, global) = match self.variable(token) {
+                    Variable::Local(dest) => (dest, None),
+                    Variable::Global(name) => {
+                        let temp = Dest::Temp(self.new_temp(self.globals[&name]));
+                        (temp, Some(name))
+                    }
+                };
+                let spec = match self.operand_type(&Operand::Var(dest.clone())) {
+                    Type::Double => FormatSpec::Double,
+                    _ => FormatSpec::Int,
+                };
+                self.instructions.push(AbstractAssemblyInstruction::Scan {
+                    dest: dest.clone(),
+                    spec,
+                });
+                if let Some(global) = global {
+                    self.instructions.push(AbstractAssemblyInstruction::Store {
+                        global,
+                        src: Operand::Var(dest),
+                    });
+                }
+            }
+            Statement::Asm(template, output, inputs, constraints) => {
+                let inputs: Vec<Operand> = inputs
+                    .iter()
+                    .map(|input| self.generate_expr(&input.node, strings))
+                    .collect();
+                // A global output is written by the template to a temp, and stored after it
+                let mut global = None;
+                let output = output.as_ref().map(|target| match target {
+                    LValue::Variable(token) => match self.variable(token) {
+                        Variable::Local(dest) => dest,
+                        Variable::Global(name) => {
+                            let temp = Dest::Temp(self.new_temp(self.globals[&name]));
+                            global = Some((name, temp.clone()));
+                            temp
+                        }
+                    },
+                });
+                // As in GCC, a template with clobbers writes `%` as `%%` even without operands,
+                // while the targets write one without operands as it is
+                let template = match output.is_none() && inputs.is_empty() {
+                    true if !constraints.clobbers.is_empty() => template.replace("%%", "%"),
+                    _ => template.clone(),
+                };
+                self.instructions.push(AbstractAssemblyInstruction::Asm {
+                    template,
+                    output,
+                    inputs,
+                    constraints: constraints.clone(),
+                });
+                if let Some((global, temp)) = global {
+                    self.instructions.push(AbstractAssemblyInstruction::Store {
+                        global,
+                        src: Operand::Var(temp),
+                    });
+                }
+            }
+            Statement::For(..) | Statement::Postfix(..) => {
+                unreachable!("{:?} is desugared before codegen", statement)
             }
-            _ => unimplemented!("Unsupported statement {:?}", statement),
         }
     }
 
@@ -193,25 +915,18 @@ impl Context {
         condition_expr: &Expr,
         then_label: AsmLabel,
         else_label: AsmLabel,
+        strings: &mut StringTable,
     ) {
         match condition_expr {
-            Expr::Binary(left, op, right) => {
-                let condition = match op {
-                    Token::Less => Condition::Less,
-                    Token::Greater => Condition::Greater,
-                    Token::EqualEqual => Condition::Equal,
-                    Token::BangEqual => Condition::NotEqual,
-                    Token::LessEqual => Condition::LessOrEqual,
-                    Token::GreaterEqual => Condition::GreaterOrEqual,
-                    _ => panic!("Unsupported binary operation in condition"),
-                };
+            Expr::Binary(left, op, right) if comparison_condition(op).is_some() => {
+                let condition = comparison_condition(op).unwrap();
 
-                let left_op = self.generate_expr(left);
-                let right_op = self.generate_expr(right);
+                let (left_op, right_op, ty) = self.generate_operands(left, right, strings);
 
                 // Emit compare instruction
                 self.instructions
                     .push(AbstractAssemblyInstruction::Compare {
+                        arithmetic: Arithmetic::of(ty),
                         left: left_op,
                         right: right_op,
                         condition: condition.clone(),
@@ -226,11 +941,12 @@ impl Context {
                     });
             }
             other_expr => {
-                let result = self.generate_expr(other_expr);
+                let result = self.generate_expr(other_expr, strings);
 
                 // Assume result is a boolean (0 = false, anything else = true)
                 self.instructions
                     .push(AbstractAssemblyInstruction::Compare {
+                        arithmetic: Arithmetic::Int,
                         left: result,
                         right: Operand::Const(0),
                         condition: Condition::NotEqual,
@@ -247,60 +963,40 @@ impl Context {
     }
 
     /// Returns the location that the result is stored in
-    fn generate_expr(&mut self, expr: &Expr) -> Operand {
+    fn generate_expr(&mut self, expr: &Expr, strings: &mut StringTable) -> Operand {
         match expr {
             Expr::Literal(literal) => match literal {
-                // TODO: handle Doubles
+                // An int literal is converted when it's combined with a double
                 Token::Number(num) => Operand::Const(*num as i128),
+                Token::DoubleLiteral(num) => Operand::Double(*num),
+                Token::StringLiteral(string) => Operand::Str(strings.intern(string)),
                 _ => panic!("Invalid literal"),
             },
             // Basic arithmetic expressions
             Expr::Unary(op, src) => {
-                let src_operand = self.generate_expr(src);
-                let dest_temp = self.new_temp();
+                let src_operand = self.generate_expr(&src.node, strings);
+                let ty = self.operand_type(&src_operand);
+                let dest_temp = self.new_temp(ty);
                 let dest = Dest::Temp(dest_temp);
                 self.instructions.push(AbstractAssemblyInstruction::UnOp {
-                    op: op.clone(),
+                    op: *op,
+                    arithmetic: Arithmetic::of(ty),
                     dest,
                     src: src_operand,
                 });
                 Operand::Var(Dest::Temp(dest_temp))
             }
             Expr::Binary(left, op, right) => {
-                let left_operand = self.generate_expr(left);
-                let right_operand = self.generate_expr(right);
-                let dest_temp = self.new_temp();
-                let dest = Dest::Temp(dest_temp);
-                match op {
-                    // TODO: distinguish mutable from immutable variables
-                    Token::Equal => {
-                        if let Operand::Var(left_dest) = left_operand {
-                            self.instructions.push(AbstractAssemblyInstruction::Mov {
-                                dest: left_dest,
-                                src: right_operand,
-                            })
-                        } else {
-                            panic!("left side of assignment must be variable");
-                        }
-                    }
-                    Token::Greater
-                    | Token::Less
-                    | Token::EqualEqual
-                    | Token::BangEqual
-                    | Token::GreaterEqual
-                    | Token::LessEqual => {
-                        let condition = match op {
-                            Token::Greater => Condition::Greater,
-                            Token::Less => Condition::Less,
-                            Token::EqualEqual => Condition::Equal,
-                            Token::BangEqual => Condition::NotEqual,
-                            Token::GreaterEqual => Condition::GreaterOrEqual,
-                            Token::LessEqual => Condition::LessOrEqual,
-                            _ => unreachable!(),
-                        };
-
+                let (left_operand, right_operand, ty) =
+                    self.generate_operands(left, right, strings);
+                let arithmetic = Arithmetic::of(ty);
+                match comparison_condition(op) {
+                    Some(condition) => {
+                        // A comparison produces a truth value
+                        let dest = Dest::Temp(self.new_temp(Type::Int));
                         self.instructions
                             .push(AbstractAssemblyInstruction::Compare {
+                                arithmetic,
                                 left: left_operand,
                                 right: right_operand,
                                 condition: condition.clone(),
@@ -311,52 +1007,267 @@ impl Context {
                             condition,
                         });
 
-                        return Operand::Var(dest);
+                        Operand::Var(dest)
                     }
-                    _ => {
+                    None => {
+                        let dest = Dest::Temp(self.new_temp(ty));
                         self.instructions.push(AbstractAssemblyInstruction::BinOp {
-                            op: op.clone(),
-                            dest,
+                            op: *op,
+                            arithmetic,
+                            dest: dest.clone(),
                             src1: left_operand,
                             src2: right_operand,
                         });
+                        Operand::Var(dest)
                     }
                 }
-
-                Operand::Var(Dest::Temp(dest_temp))
             }
-            Expr::Parentheses(expr) => self.generate_expr(expr),
-            Expr::Variable(token) => {
-                if let Token::Identifier(varname) = token {
-                    if let Some(&temp) = self.var_to_temp.get(varname) {
-                        Operand::Var(Dest::Temp(temp))
-                    } else {
-                        panic!("Undefined variable: {}", varname);
+            Expr::Parentheses(expr) => self.generate_expr(&expr.node, strings),
+            Expr::Variable(token) => match self.variable(token) {
+                Variable::Local(dest) => Operand::Var(dest),
+                Variable::Global(global) => {
+                    let dest = Dest::Temp(self.new_temp(self.globals[&global]));
+                    self.instructions.push(AbstractAssemblyInstruction::Load {
+                        dest: dest.clone(),
+                        global,
+                    });
+                    Operand::Var(dest)
+                }
+            },
+            Expr::Assign(target, value) => {
+                // TODO: distinguish mutable from immutable variables
+                let src = self.generate_expr(&value.node, strings);
+                let LValue::Variable(token) = target;
+                match self.variable(token) {
+                    Variable::Local(dest) => {
+                        let src = self.convert(src, self.dest_type(&dest));
+                        self.instructions.push(AbstractAssemblyInstruction::Mov {
+                            dest: dest.clone(),
+                            src,
+                        });
+                        Operand::Var(dest)
                     }
-                } else {
-                    panic!("Invalid variable token");
+                    // The assignment's value is the one stored, which is still at hand
+                    Variable::Global(global) => {
+                        let src = self.convert(src, self.globals[&global]);
+                        self.instructions.push(AbstractAssemblyInstruction::Store {
+                            global,
+                            src: src.clone(),
+                        });
+                        src
+                    }
+                }
+            }
+            Expr::CompoundAssign(..) => {
+                unreachable!("{:?} is desugared before codegen", expr)
+            }
+            Expr::Result => Operand::Var(
+                self.result
+                    .clone()
+                    .expect("\\result is only used in //@ensures of non-void functions"),
+            ),
+            Expr::Old(..) => {
+                unreachable!("\\old is snapshotted on entry to the function")
+            }
+            Expr::Call(identifier, args) => self.generate_function_call(identifier, args, strings),
+            Expr::Cast(type_token, expr) => {
+                let target = variable_type(type_token);
+                let src = self.generate_expr(&expr.node, strings);
+                match (self.operand_type(&src), target) {
+                    (Type::Double, Type::Char) => {
+                        let int = self.generate_conversion(Conversion::D2I, src, Type::Int);
+                        self.generate_conversion(Conversion::I2C, int, Type::Char)
+                    }
+                    (Type::Double, Type::Int) => {
+                        self.generate_conversion(Conversion::D2I, src, Type::Int)
+                    }
+                    (Type::Int, Type::Char) => {
+                        self.generate_conversion(Conversion::I2C, src, Type::Char)
+                    }
+                    (Type::Int | Type::Char, Type::Double) => {
+                        self.generate_conversion(Conversion::I2D, src, Type::Double)
+                    }
+                    // A char is already a valid int, and a cast to the same type does nothing
+                    _ => src,
                 }
             }
-            Expr::Call(identifier, args) => self.generate_function_call(identifier, args),
-            _ => panic!("Unsupported expression"),
         }
     }
 
-    fn generate_function_call(&mut self, identifier: &Expr, args: &Vec<Expr>) -> Operand {
-        unimplemented!("Function calls not implemented");
+    /// Generates both operands of a binary operator, converting an int operand to a double if
+    /// the other one is a double. Also returns the type they now share.
+    fn generate_operands(
+        &mut self,
+        left: &Spanned<Expr>,
+        right: &Spanned<Expr>,
+        strings: &mut StringTable,
+    ) -> (Operand, Operand, Type) {
+        let left = self.generate_expr(&left.node, strings);
+        let right = self.generate_expr(&right.node, strings);
+        let ty = match (self.operand_type(&left), self.operand_type(&right)) {
+            (Type::Double, _) | (_, Type::Double) => Type::Double,
+            (ty, _) => ty,
+        };
+        (self.convert(left, ty), self.convert(right, ty), ty)
+    }
+
+    /// `operand` as a value of type `ty`. Only an int ever needs converting, to a double.
+    fn convert(&mut self, operand: Operand, ty: Type) -> Operand {
+        if self.operand_type(&operand) != Type::Int || ty != Type::Double {
+            return operand;
+        }
+        match operand {
+            // Constants are converted at compile time
+            Operand::Const(value) => Operand::Double(value as f64),
+            operand => self.generate_conversion(Conversion::I2D, operand, Type::Double),
+        }
+    }
+
+    /// Converts `src` into a new temp of type `ty`
+    fn generate_conversion(&mut self, conversion: Conversion, src: Operand, ty: Type) -> Operand {
+        let dest = Dest::Temp(self.new_temp(ty));
+        self.instructions
+            .push(AbstractAssemblyInstruction::Convert {
+                conversion,
+                dest: dest.clone(),
+                src,
+            });
+        Operand::Var(dest)
     }
 
-    /// Generates a new temp
-    fn new_temp(&mut self) -> usize {
-        let temp = self.temp_counter.clone();
+    /// Type of the value `operand` holds
+    fn operand_type(&self, operand: &Operand) -> Type {
+        match operand {
+            Operand::Const(_) => Type::Int,
+            Operand::Double(_) => Type::Double,
+            Operand::Str(_) => Type::String,
+            Operand::Var(dest) => self.dest_type(dest),
+        }
+    }
+
+    pub(super) fn dest_type(&self, dest: &Dest) -> Type {
+        match dest {
+            Dest::Temp(temp) => self.temp_types[temp],
+            Dest::Register(_) => unreachable!("registers are only assigned after codegen"),
+        }
+    }
+
+    /// Where the given variable is kept
+    fn variable(&self, token: &Token) -> Variable {
+        let Token::Identifier(varname) = token else {
+            panic!("Invalid variable token");
+        };
+        match self.var_to_temp.get(varname) {
+            Some(&temp) => Variable::Local(Dest::Temp(temp)),
+            // Sema reports undefined names, so only a global can be missing here
+            None => Variable::Global(varname.clone()),
+        }
+    }
+
+    /// Calls the function, with the arguments evaluated left to right and converted to its
+    /// parameters' types. A void function's result is never used, so it's returned as 0.
+    fn generate_function_call(
+        &mut self,
+        identifier: &Spanned<Expr>,
+        args: &[Spanned<Expr>],
+        strings: &mut StringTable,
+    ) -> Operand {
+        let Expr::Variable(Token::Identifier(name)) = &identifier.node else {
+            unreachable!("the parser only produces calls to names");
+        };
+        // Sema reports calls to undeclared functions, and linking those to undefined ones
+        let signature = self.signatures[name].clone();
+        let args = args
+            .iter()
+            .zip(&signature.params)
+            .map(|(arg, &ty)| {
+                let operand = self.generate_expr(&arg.node, strings);
+                self.convert(operand, ty)
+            })
+            .collect();
+        let dest = match signature.return_type {
+            Type::Void => None,
+            ty => Some(Dest::Temp(self.new_temp(ty))),
+        };
+        self.instructions.push(AbstractAssemblyInstruction::Call {
+            dest: dest.clone(),
+            function: name.clone(),
+            args,
+        });
+        dest.map_or(Operand::Const(0), Operand::Var)
+    }
+
+    /// Generates a new temp holding values of type `ty`
+    pub(super) fn new_temp(&mut self, ty: Type) -> usize {
+        let temp = self.temp_counter;
         self.temp_counter += 1;
+        self.temp_types.insert(temp, ty);
         temp
     }
 
+    /// Generates a new temp for the variable `name`
+    fn new_variable_temp(&mut self, ty: Type, name: &str) -> usize {
+        let temp = self.new_temp(ty);
+        self.temp_names.insert(temp, name.to_string());
+        temp
+    }
+
+    /// Generates a new temp holding the same type and variable as `temp`, such as another
+    /// version of it in SSA form
+    pub(super) fn new_temp_like(&mut self, temp: usize) -> usize {
+        let version = self.new_temp(self.temp_types[&temp]);
+        if let Some(name) = self.temp_names.get(&temp).cloned() {
+            self.temp_names.insert(version, name);
+        }
+        version
+    }
+
+    /// Name of the source variable `temp` holds, if it holds one
+    pub(super) fn temp_name(&self, temp: usize) -> Option<&str> {
+        self.temp_names.get(&temp).map(String::as_str)
+    }
+
+    /// Type the function returns. Functions read back from text don't know it, and are void.
+    pub(super) fn return_type(&self) -> Type {
+        self.return_type
+    }
+
+    /// Temps holding a source variable, with its name
+    pub(super) fn variable_temps(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.temp_names
+            .iter()
+            .map(|(&temp, name)| (temp, name.as_str()))
+    }
+
+    /// Type of each temp allocated so far
+    pub(super) fn temp_types(&self) -> &HashMap<usize, Type> {
+        &self.temp_types
+    }
+
+    /// Number of temps allocated so far; later ones are numbered from it
+    pub(super) fn temp_count(&self) -> usize {
+        self.temp_counter
+    }
+
+    /// Number of labels allocated so far; later ones are numbered from it
+    pub(super) fn label_count(&self) -> usize {
+        self.label_counter
+    }
+
+    /// True if `temp` was allocated by `new_temp`
+    pub(super) fn has_temp(&self, temp: usize) -> bool {
+        self.temp_types.contains_key(&temp)
+    }
+
     /// Generates a new label name
-    fn new_label(&mut self) -> usize {
-        let label = self.label_counter.clone();
+    pub(super) fn new_label(&mut self) -> usize {
+        let label = self.label_counter;
         self.label_counter += 1;
         label
     }
 }
+
+/// Type of a variable declared with `token`, which sema has already checked
+pub(super) fn variable_type(token: &Token) -> Type {
+    type_of(token).expect("sema rejects unsupported types")
+}
@@ -0,0 +1,239 @@
+//! Dead code elimination: instructions whose result is never read are deleted, as long as they
+//! can't fail or print. Copies are propagated within each basic block first, and a temp computed
+//! only to be moved into another one is computed into it directly, so the moves that variable
+//! assignments produce disappear too.
+//!
+//! Liveness is computed per instruction, so that the pass keeps the layout of the code.
+
+use super::context::{AbstractAssemblyInstruction, Arithmetic, Context, Dest, Operand};
+use super::pass::Pass;
+use super::CodegenOptions;
+use crate::parser::BinOp;
+use std::collections::{HashMap, HashSet};
+
+pub struct DeadCodeElimination;
+
+impl Pass for DeadCodeElimination {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+
+    fn level(&self) -> u8 {
+        1
+    }
+
+    fn run(&self, context: &mut Context, _options: &CodegenOptions) {
+        propagate_copies(&mut context.instructions);
+        // Removing an instruction can make the ones computing its operands dead too
+        loop {
+            let coalesced = coalesce_moves(context);
+            let removed = remove_dead_instructions(&mut context.instructions);
+            if !coalesced && !removed {
+                break;
+            }
+        }
+    }
+}
+
+/// Temp an operand reads, if any
+fn operand_temp(operand: &Operand) -> Option<usize> {
+    match operand {
+        Operand::Var(Dest::Temp(temp)) => Some(*temp),
+        _ => None,
+    }
+}
+
+fn dest_temp(instruction: &AbstractAssemblyInstruction) -> Option<usize> {
+    match instruction.dest() {
+        Some(Dest::Temp(temp)) => Some(*temp),
+        _ => None,
+    }
+}
+
+/// Replaces reads of a temp that was copied from another one in the same block with reads of
+/// the original
+fn propagate_copies(instructions: &mut [AbstractAssemblyInstruction]) {
+    // Temps holding a copy of another temp, with the temp they copy
+    let mut copies: HashMap<usize, usize> = HashMap::new();
+    for instruction in instructions {
+        if let AbstractAssemblyInstruction::Lbl(_) = instruction {
+            copies.clear();
+            continue;
+        }
+        if !matches!(instruction, AbstractAssemblyInstruction::Phi { .. }) {
+            for operand in instruction.operands_mut() {
+                if let Operand::Var(Dest::Temp(temp)) = operand {
+                    if let Some(&original) = copies.get(temp) {
+                        *temp = original;
+                    }
+                }
+            }
+        }
+        if let Some(dest) = dest_temp(instruction) {
+            copies.retain(|&copy, &mut original| copy != dest && original != dest);
+            if let AbstractAssemblyInstruction::Mov {
+                src: Operand::Var(Dest::Temp(src)),
+                ..
+            } = instruction
+            {
+                if *src != dest {
+                    copies.insert(dest, *src);
+                }
+            }
+        }
+    }
+}
+
+/// Turns `a <- x op y; b <- a` into `b <- x op y` when `a` isn't read afterwards
+fn coalesce_moves(context: &mut Context) -> bool {
+    let live_out = live_out(&context.instructions);
+    let mut removed = vec![false; context.instructions.len()];
+    for index in 1..context.instructions.len() {
+        let AbstractAssemblyInstruction::Mov {
+            dest: Dest::Temp(dest),
+            src: Operand::Var(Dest::Temp(src)),
+        } = context.instructions[index]
+        else {
+            continue;
+        };
+        let previous = &context.instructions[index - 1];
+        if removed[index - 1]
+            || dest_temp(previous) != Some(src)
+            || matches!(previous, AbstractAssemblyInstruction::Phi { .. })
+            || live_out[index].contains(&src)
+            || context.dest_type(&Dest::Temp(src)) != context.dest_type(&Dest::Temp(dest))
+        {
+            continue;
+        }
+        if let Some(previous_dest) = context.instructions[index - 1].dest_mut() {
+            *previous_dest = Dest::Temp(dest);
+        }
+        removed[index] = true;
+    }
+    retain_unremoved(&mut context.instructions, &removed)
+}
+
+/// Deletes moves of a temp into itself, and instructions without effects whose result is
+/// never read
+fn remove_dead_instructions(instructions: &mut Vec<AbstractAssemblyInstruction>) -> bool {
+    let live_out = live_out(instructions);
+    let removed: Vec<bool> = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| match instruction {
+            AbstractAssemblyInstruction::Mov {
+                dest: Dest::Temp(dest),
+                src: Operand::Var(Dest::Temp(src)),
+            } if dest == src => true,
+            AbstractAssemblyInstruction::Compare { .. } => !flags_read(&instructions[index + 1..]),
+            _ => match dest_temp(instruction) {
+                Some(dest) => !live_out[index].contains(&dest) && !has_effect(instruction),
+                None => false,
+            },
+        })
+        .collect();
+    retain_unremoved(instructions, &removed)
+}
+
+/// Deletes the instructions marked as removed, returning true if there were any
+fn retain_unremoved(instructions: &mut Vec<AbstractAssemblyInstruction>, removed: &[bool]) -> bool {
+    let before = instructions.len();
+    let mut index = 0;
+    instructions.retain(|_| {
+        index += 1;
+        !removed[index - 1]
+    });
+    instructions.len() != before
+}
+
+/// True if an instruction writing a temp does something besides, like failing
+fn has_effect(instruction: &AbstractAssemblyInstruction) -> bool {
+    match instruction {
+        // An int division by zero, or of the smallest int by -1, is a runtime error
+        AbstractAssemblyInstruction::BinOp {
+            op: BinOp::Div,
+            arithmetic: Arithmetic::Int,
+            src2,
+            ..
+        } => !matches!(src2, Operand::Const(divisor) if *divisor != 0 && *divisor != -1),
+        // The function called may print, abort or never return
+        AbstractAssemblyInstruction::Call { .. } => true,
+        // Reading moves on through the input, and aborts at its end
+        AbstractAssemblyInstruction::Scan { .. } => true,
+        // Nothing is known about what the template does
+        AbstractAssemblyInstruction::Asm { .. } => true,
+        _ => false,
+    }
+}
+
+/// True if the flags set by a comparison just before `rest` are read
+fn flags_read(rest: &[AbstractAssemblyInstruction]) -> bool {
+    for instruction in rest {
+        match instruction {
+            AbstractAssemblyInstruction::SetIf { .. }
+            | AbstractAssemblyInstruction::JmpCondition { .. } => return true,
+            AbstractAssemblyInstruction::Compare { .. } | AbstractAssemblyInstruction::Lbl(_) => {
+                return false
+            }
+            _ if instruction.is_terminator() => return false,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Temps live after each instruction, meaning some path from it reads them before writing them
+fn live_out(instructions: &[AbstractAssemblyInstruction]) -> Vec<HashSet<usize>> {
+    let labels: HashMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, instruction)| match instruction {
+            AbstractAssemblyInstruction::Lbl(label) => Some((label.0, index)),
+            _ => None,
+        })
+        .collect();
+    let successors: Vec<Vec<usize>> = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| match instruction {
+            AbstractAssemblyInstruction::Jmp(target) => vec![labels[&target.0]],
+            AbstractAssemblyInstruction::JmpCondition {
+                tgt_true,
+                tgt_false,
+                ..
+            } => vec![labels[&tgt_true.0], labels[&tgt_false.0]],
+            _ if instruction.is_terminator() => Vec::new(),
+            _ if index + 1 < instructions.len() => vec![index + 1],
+            _ => Vec::new(),
+        })
+        .collect();
+
+    let mut live_in: Vec<HashSet<usize>> = vec![HashSet::new(); instructions.len()];
+    let mut live_out: Vec<HashSet<usize>> = vec![HashSet::new(); instructions.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in (0..instructions.len()).rev() {
+            let out: HashSet<usize> = successors[index]
+                .iter()
+                .flat_map(|&successor| live_in[successor].iter().copied())
+                .collect();
+            let mut live: HashSet<usize> = out.clone();
+            if let Some(dest) = dest_temp(&instructions[index]) {
+                live.remove(&dest);
+            }
+            live.extend(
+                instructions[index]
+                    .operands()
+                    .into_iter()
+                    .filter_map(operand_temp),
+            );
+            if live != live_in[index] {
+                live_in[index] = live;
+                changed = true;
+            }
+            live_out[index] = out;
+        }
+    }
+    live_out
+}
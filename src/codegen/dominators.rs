@@ -0,0 +1,150 @@
+//! Dominator tree of a control flow graph. Block `a` dominates block `b` when every path from
+//! the entry to `b` goes through `a`.
+//!
+//! Immediate dominators are found with the iterative algorithm of Cooper, Harvey and Kennedy,
+//! "A Simple, Fast Dominance Algorithm", which is simpler than Lengauer-Tarjan and as fast on
+//! graphs of the size functions have.
+
+use super::cfg::ControlFlowGraph;
+
+#[derive(Debug)]
+pub struct DominatorTree {
+    /// Immediate dominator of each block; the entry is its own
+    idom: Vec<usize>,
+    /// Blocks each block immediately dominates, in index order
+    children: Vec<Vec<usize>>,
+    /// Dominance frontier of each block, in index order
+    frontiers: Vec<Vec<usize>>,
+    /// When each block is entered and left in a preorder walk of the tree, so that `a`
+    /// dominates `b` exactly when `b`'s interval is inside `a`'s
+    entered: Vec<usize>,
+    left: Vec<usize>,
+}
+
+impl DominatorTree {
+    /// Every block of `cfg` must be reachable from the entry, which `ControlFlowGraph::new`
+    /// ensures
+    pub fn new(cfg: &ControlFlowGraph) -> Self {
+        let idom = immediate_dominators(cfg);
+        let mut children = vec![Vec::new(); cfg.len()];
+        for (block, &dominator) in idom.iter().enumerate().skip(1) {
+            children[dominator].push(block);
+        }
+
+        // The frontier of a block is where its dominance stops: the blocks it doesn't strictly
+        // dominate, but dominates a predecessor of
+        let mut frontiers = vec![Vec::new(); cfg.len()];
+        for block in 0..cfg.len() {
+            let predecessors = cfg.predecessors(block);
+            if predecessors.len() < 2 {
+                continue;
+            }
+            for &predecessor in predecessors {
+                let mut runner = predecessor;
+                while runner != idom[block] {
+                    if !frontiers[runner].contains(&block) {
+                        frontiers[runner].push(block);
+                    }
+                    runner = idom[runner];
+                }
+            }
+        }
+
+        let mut entered = vec![0; cfg.len()];
+        let mut left = vec![0; cfg.len()];
+        let mut clock = 0;
+        // (block, true once its children have been pushed)
+        let mut stack = vec![(0, false)];
+        while let Some((block, expanded)) = stack.pop() {
+            clock += 1;
+            if expanded {
+                left[block] = clock;
+            } else {
+                entered[block] = clock;
+                stack.push((block, true));
+                stack.extend(children[block].iter().rev().map(|&child| (child, false)));
+            }
+        }
+
+        DominatorTree {
+            idom,
+            children,
+            frontiers,
+            entered,
+            left,
+        }
+    }
+
+    /// The closest block strictly dominating `block`, or None for the entry
+    pub fn idom(&self, block: usize) -> Option<usize> {
+        match block {
+            0 => None,
+            _ => Some(self.idom[block]),
+        }
+    }
+
+    /// True if every path from the entry to `b` goes through `a`. A block dominates itself.
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        self.entered[a] <= self.entered[b] && self.left[b] <= self.left[a]
+    }
+
+    pub fn strictly_dominates(&self, a: usize, b: usize) -> bool {
+        a != b && self.dominates(a, b)
+    }
+
+    /// Blocks whose immediate dominator is `block`
+    pub fn children(&self, block: usize) -> &[usize] {
+        &self.children[block]
+    }
+
+    /// Blocks where the dominance of `block` ends, and so where values it defines meet values
+    /// from other paths
+    pub fn frontier(&self, block: usize) -> &[usize] {
+        &self.frontiers[block]
+    }
+}
+
+fn immediate_dominators(cfg: &ControlFlowGraph) -> Vec<usize> {
+    let order = cfg.reverse_postorder();
+    let mut position = vec![0; cfg.len()];
+    for (index, &block) in order.iter().enumerate() {
+        position[block] = index;
+    }
+
+    let mut idom: Vec<Option<usize>> = vec![None; cfg.len()];
+    idom[0] = Some(0);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in order.iter().skip(1) {
+            let mut processed = cfg
+                .predecessors(block)
+                .iter()
+                .copied()
+                .filter(|&predecessor| idom[predecessor].is_some());
+            let Some(first) = processed.next() else {
+                continue;
+            };
+            let new_idom = processed.fold(first, |mut finger1, mut finger2| {
+                // Walk up from both blocks until they meet at their common dominator
+                while finger1 != finger2 {
+                    while position[finger1] > position[finger2] {
+                        finger1 = idom[finger1].unwrap();
+                    }
+                    while position[finger2] > position[finger1] {
+                        finger2 = idom[finger2].unwrap();
+                    }
+                }
+                finger1
+            });
+            if idom[block] != Some(new_idom) {
+                idom[block] = Some(new_idom);
+                changed = true;
+            }
+        }
+    }
+
+    idom.into_iter()
+        .map(|dominator| dominator.expect("every block is reachable from the entry"))
+        .collect()
+}
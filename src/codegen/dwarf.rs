@@ -0,0 +1,368 @@
+//! Debug information for the x86 output, in DWARF, so that a debugger can step through the
+//! compiled program line by line and show its variables.
+//!
+//! The assembler builds the line table, `.debug_line`, from the `.file` directives written at
+//! the top and the `.loc` directives written with the instructions. This writes the rest:
+//! `.debug_info`, with an entry for the program, one for each function, and under it one for
+//! each variable held in a single register or stack slot, and `.debug_abbrev`, the layout of
+//! those entries. Both are written as data directives, so that the assembler resolves the
+//! addresses.
+
+use super::context::Dest;
+use super::emit::escape_x86_string;
+use super::object::Format;
+use super::x86::{Address, Variable, X86Function, X86Operand, X86Register};
+use crate::sema::Type;
+use crate::source_map::LineTable;
+use std::io::{self, Write};
+
+const DW_TAG_FORMAL_PARAMETER: u8 = 0x05;
+const DW_TAG_POINTER_TYPE: u8 = 0x0f;
+const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+const DW_TAG_BASE_TYPE: u8 = 0x24;
+const DW_TAG_SUBPROGRAM: u8 = 0x2e;
+const DW_TAG_VARIABLE: u8 = 0x34;
+
+const DW_AT_LOCATION: u8 = 0x02;
+const DW_AT_NAME: u8 = 0x03;
+const DW_AT_BYTE_SIZE: u8 = 0x0b;
+const DW_AT_STMT_LIST: u8 = 0x10;
+const DW_AT_LOW_PC: u8 = 0x11;
+const DW_AT_HIGH_PC: u8 = 0x12;
+const DW_AT_LANGUAGE: u8 = 0x13;
+const DW_AT_COMP_DIR: u8 = 0x1b;
+const DW_AT_PRODUCER: u8 = 0x25;
+const DW_AT_ENCODING: u8 = 0x3e;
+const DW_AT_EXTERNAL: u8 = 0x3f;
+const DW_AT_TYPE: u8 = 0x49;
+
+const DW_FORM_ADDR: u8 = 0x01;
+const DW_FORM_DATA2: u8 = 0x05;
+const DW_FORM_DATA4: u8 = 0x06;
+const DW_FORM_STRING: u8 = 0x08;
+const DW_FORM_DATA1: u8 = 0x0b;
+const DW_FORM_FLAG: u8 = 0x0c;
+const DW_FORM_REF4: u8 = 0x13;
+const DW_FORM_SEC_OFFSET: u8 = 0x17;
+const DW_FORM_EXPRLOC: u8 = 0x18;
+
+const DW_ATE_FLOAT: u8 = 0x04;
+const DW_ATE_SIGNED: u8 = 0x05;
+const DW_ATE_SIGNED_CHAR: u8 = 0x06;
+
+const DW_OP_REG0: u8 = 0x50;
+const DW_OP_BREG0: u8 = 0x70;
+const DW_OP_REGX: u8 = 0x90;
+
+/// C0 has no language code of its own, and C's expressions read the same
+const DW_LANG_C99: u16 = 0x0c;
+
+/// Abbreviation codes of the entries, which `ABBREVIATIONS` lays out in this order from 1
+const COMPILE_UNIT: u8 = 1;
+const SUBPROGRAM: u8 = 2;
+const VOID_SUBPROGRAM: u8 = 3;
+const FORMAL_PARAMETER: u8 = 4;
+const VARIABLE: u8 = 5;
+const BASE_TYPE: u8 = 6;
+const POINTER_TYPE: u8 = 7;
+
+type Abbreviation = (u8, bool, &'static [(u8, u8)]);
+
+/// Tag of each kind of entry, whether entries of that kind have children, and their
+/// attributes with the form of each
+const ABBREVIATIONS: [Abbreviation; 7] = [
+    (
+        DW_TAG_COMPILE_UNIT,
+        true,
+        &[
+            (DW_AT_PRODUCER, DW_FORM_STRING),
+            (DW_AT_LANGUAGE, DW_FORM_DATA2),
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_COMP_DIR, DW_FORM_STRING),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+            (DW_AT_HIGH_PC, DW_FORM_DATA4),
+            (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+        ],
+    ),
+    (
+        DW_TAG_SUBPROGRAM,
+        true,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_EXTERNAL, DW_FORM_FLAG),
+            (DW_AT_TYPE, DW_FORM_REF4),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+            (DW_AT_HIGH_PC, DW_FORM_DATA4),
+        ],
+    ),
+    (
+        DW_TAG_SUBPROGRAM,
+        true,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_EXTERNAL, DW_FORM_FLAG),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+            (DW_AT_HIGH_PC, DW_FORM_DATA4),
+        ],
+    ),
+    (
+        DW_TAG_FORMAL_PARAMETER,
+        false,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_TYPE, DW_FORM_REF4),
+            (DW_AT_LOCATION, DW_FORM_EXPRLOC),
+        ],
+    ),
+    (
+        DW_TAG_VARIABLE,
+        false,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_TYPE, DW_FORM_REF4),
+            (DW_AT_LOCATION, DW_FORM_EXPRLOC),
+        ],
+    ),
+    (
+        DW_TAG_BASE_TYPE,
+        false,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_ENCODING, DW_FORM_DATA1),
+            (DW_AT_BYTE_SIZE, DW_FORM_DATA1),
+        ],
+    ),
+    (
+        DW_TAG_POINTER_TYPE,
+        false,
+        &[(DW_AT_BYTE_SIZE, DW_FORM_DATA1), (DW_AT_TYPE, DW_FORM_REF4)],
+    ),
+];
+
+/// The base types, by the name C0 gives them, with their encoding and size. A string is a
+/// pointer to chars.
+const BASE_TYPES: [(&str, u8, u8); 3] = [
+    ("int", DW_ATE_SIGNED, 4),
+    ("double", DW_ATE_FLOAT, 8),
+    ("char", DW_ATE_SIGNED_CHAR, 1),
+];
+
+/// Label of the first byte past the end of `function`
+pub fn end_symbol(function: &str) -> String {
+    format!(".L{}_end", function)
+}
+
+/// Numbers the source files for the `.loc` directives, from 1 as the assembler does
+pub fn write_files(file: &mut impl Write, line_table: &LineTable) -> io::Result<()> {
+    for (index, name) in line_table.files().iter().enumerate() {
+        writeln!(
+            file,
+            "\t.file {} \"{}\"",
+            index + 1,
+            escape_x86_string(name)
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes `.debug_abbrev` and `.debug_info` for `functions`, which start with the program's
+/// first function and end with its last
+pub fn write_debug_info(
+    file: &mut impl Write,
+    functions: &[X86Function],
+    line_table: &LineTable,
+    format: Format,
+) -> io::Result<()> {
+    let (Some(first), Some(last)) = (functions.first(), functions.last()) else {
+        return Ok(());
+    };
+    // Offsets into other sections are relative to their start, which COFF spells differently
+    let section_offset = match format {
+        Format::Elf => ".long",
+        Format::Coff => ".secrel32",
+    };
+
+    write_section(file, ".debug_abbrev", format)?;
+    writeln!(file, ".Ldebug_abbrev0:")?;
+    for (code, (tag, children, attributes)) in (1..).zip(ABBREVIATIONS) {
+        writeln!(file, "\t.uleb128 {}\n\t.uleb128 0x{:x}", code, tag)?;
+        writeln!(file, "\t.byte {}", u8::from(children))?;
+        for (attribute, form) in attributes {
+            writeln!(
+                file,
+                "\t.uleb128 0x{:x}\n\t.uleb128 0x{:x}",
+                attribute, form
+            )?;
+        }
+        writeln!(file, "\t.byte 0, 0")?;
+    }
+    writeln!(file, "\t.byte 0")?;
+
+    // The line program the assembler writes is appended after this label
+    write_section(file, ".debug_line", format)?;
+    writeln!(file, ".Ldebug_line0:")?;
+
+    write_section(file, ".debug_info", format)?;
+    writeln!(file, ".Ldebug_info0:")?;
+    writeln!(file, "\t.long .Ldebug_info_end - .Ldebug_info_start")?;
+    writeln!(file, ".Ldebug_info_start:")?;
+    // DWARF 4, and 8-byte addresses
+    writeln!(
+        file,
+        "\t.short 4\n\t{} .Ldebug_abbrev0\n\t.byte 8",
+        section_offset
+    )?;
+
+    let name = line_table.files().first().map_or("", String::as_str);
+    writeln!(file, "\t.uleb128 {}", COMPILE_UNIT)?;
+    write_string(
+        file,
+        concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")),
+    )?;
+    writeln!(file, "\t.short 0x{:x}", DW_LANG_C99)?;
+    write_string(file, name)?;
+    write_string(file, line_table.directory())?;
+    writeln!(file, "\t.quad {}", first.symbol)?;
+    writeln!(
+        file,
+        "\t.long {} - {}",
+        end_symbol(&last.symbol),
+        first.symbol
+    )?;
+    writeln!(file, "\t{} .Ldebug_line0", section_offset)?;
+
+    for (name, encoding, size) in BASE_TYPES {
+        writeln!(file, ".Ldebug_type_{}:", name)?;
+        writeln!(file, "\t.uleb128 {}", BASE_TYPE)?;
+        write_string(file, name)?;
+        writeln!(file, "\t.byte 0x{:x}\n\t.byte {}", encoding, size)?;
+    }
+    writeln!(file, ".Ldebug_type_string:")?;
+    writeln!(file, "\t.uleb128 {}\n\t.byte 8", POINTER_TYPE)?;
+    writeln!(file, "\t.long .Ldebug_type_char - .Ldebug_info0")?;
+
+    for function in functions {
+        let return_type = function.return_type;
+        match return_type {
+            Type::Void => writeln!(file, "\t.uleb128 {}", VOID_SUBPROGRAM)?,
+            _ => writeln!(file, "\t.uleb128 {}", SUBPROGRAM)?,
+        }
+        write_string(file, &function.name)?;
+        writeln!(file, "\t.byte {}", u8::from(!function.is_static))?;
+        if return_type != Type::Void {
+            write_type(file, return_type)?;
+        }
+        writeln!(file, "\t.quad {}", function.symbol)?;
+        writeln!(
+            file,
+            "\t.long {} - {}",
+            end_symbol(&function.symbol),
+            function.symbol
+        )?;
+        for variable in &function.variables {
+            write_variable(file, variable)?;
+        }
+        writeln!(file, "\t.byte 0")?;
+    }
+    writeln!(file, "\t.byte 0")?;
+    writeln!(file, ".Ldebug_info_end:")
+}
+
+/// Switches to the debug section `name`, which isn't loaded with the program
+fn write_section(file: &mut impl Write, name: &str, format: Format) -> io::Result<()> {
+    match format {
+        Format::Elf => writeln!(file, "\t.section {},\"\",@progbits", name),
+        Format::Coff => writeln!(file, "\t.section {},\"dr\"", name),
+    }
+}
+
+fn write_string(file: &mut impl Write, string: &str) -> io::Result<()> {
+    writeln!(file, "\t.string \"{}\"", escape_x86_string(string))
+}
+
+/// Refers to the entry of the type `ty`
+fn write_type(file: &mut impl Write, ty: Type) -> io::Result<()> {
+    let name = match ty {
+        Type::Int => "int",
+        Type::Double => "double",
+        Type::Char => "char",
+        Type::String => "string",
+        Type::Void => unreachable!("no value is void"),
+    };
+    writeln!(file, "\t.long .Ldebug_type_{} - .Ldebug_info0", name)
+}
+
+/// Writes the entry of `variable`, unless it has no home left, as a temp that was recomputed
+/// wherever it was read
+fn write_variable(file: &mut impl Write, variable: &Variable) -> io::Result<()> {
+    let Some(location) = location(&variable.home) else {
+        return Ok(());
+    };
+    match variable.parameter {
+        true => writeln!(file, "\t.uleb128 {}", FORMAL_PARAMETER)?,
+        false => writeln!(file, "\t.uleb128 {}", VARIABLE)?,
+    }
+    write_string(file, &variable.name)?;
+    write_type(file, variable.ty)?;
+    let bytes: Vec<String> = location
+        .iter()
+        .map(|byte| format!("0x{:x}", byte))
+        .collect();
+    writeln!(
+        file,
+        "\t.uleb128 {}\n\t.byte {}",
+        location.len(),
+        bytes.join(", ")
+    )
+}
+
+/// The DWARF expression naming where `home` is: a register, or a stack slot at an offset
+/// from one
+fn location(home: &X86Operand) -> Option<Vec<u8>> {
+    match home {
+        X86Operand::Reg(Dest::Register(index)) => {
+            let number = register_number(X86Register::from_index(*index));
+            match number {
+                0..=31 => Some(vec![DW_OP_REG0 + number]),
+                _ => Some(vec![DW_OP_REGX, number]),
+            }
+        }
+        X86Operand::Mem(Address {
+            base: Some(Dest::Register(index)),
+            index: None,
+            displacement,
+            symbol: None,
+        }) => {
+            let number = register_number(X86Register::from_index(*index));
+            let mut bytes = vec![DW_OP_BREG0 + number];
+            write_sleb128(&mut bytes, *displacement as i64);
+            Some(bytes)
+        }
+        _ => None,
+    }
+}
+
+/// The number the x86-64 System V ABI gives `register` in debug information, which Windows
+/// uses too
+fn register_number(register: X86Register) -> u8 {
+    const LEGACY: [u8; 8] = [0, 2, 1, 3, 7, 6, 4, 5];
+    let index = register as usize;
+    match index {
+        0..=7 => LEGACY[index],
+        8..=15 => index as u8,
+        // %xmm0 is 17
+        _ => (index - X86Register::Xmm0 as usize + 17) as u8,
+    }
+}
+
+fn write_sleb128(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        bytes.push(if done { byte } else { byte | 0x80 });
+        if done {
+            return;
+        }
+    }
+}
@@ -1,32 +1,104 @@
-use super::context::{AbstractAssemblyInstruction, AsmLabel, Condition, Context, Dest, Operand};
-use crate::lexer::Token;
-use crate::parser::VarDeclaration;
+use super::context::{
+    AbstractAssemblyInstruction, Arithmetic, AsmLabel, Condition, Context, Conversion, Dest,
+    Global, Operand, ShiftKind, StringTable,
+};
+use super::dwarf;
+use super::frame::Frame;
+use super::m6502::{
+    self, container, Byte, M6502Function, M6502Instruction, M6502Operand, ZeroPage,
+};
+use super::object::Format;
+use super::riscv::{self, RiscvFunction, RiscvInstruction, RiscvOperand};
+use super::x86::{
+    double_symbol, string_symbol, Address, AluOp, Size, SseOp, UnaryOp, X86Function,
+    X86Instruction, X86Operand, X86Register,
+};
+use super::OutputFormat;
+use crate::parser::{AsmConstraints, BinOp, FormatSpec, UnOp};
+use crate::sema::Type;
+use crate::source_map::LineTable;
+use std::collections::{BTreeSet, HashSet};
 use std::fs::File;
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::path::Path;
 
-fn serialize_dest(dest: &Dest) -> String {
+/// Writes a temp as `%t4`, or as `%t4.sum` if `names` is the context of a function whose temp
+/// holds the variable `sum`
+fn serialize_dest(dest: &Dest, names: Option<&Context>) -> String {
     match dest {
         Dest::Register(reg) => format!("({})", reg),
-        Dest::Temp(temp) => format!("%t{}", temp),
+        Dest::Temp(temp) => match names.and_then(|context| context.temp_name(*temp)) {
+            Some(name) => format!("%t{}.{}", temp, name),
+            None => format!("%t{}", temp),
+        },
     }
 }
 
-fn serialize_operand(operand: &Operand) -> String {
+fn serialize_operand(operand: &Operand, names: Option<&Context>) -> String {
     match operand {
         Operand::Const(value) => format!("${}", value),
-        Operand::Var(dest) => format!("{}", serialize_dest(dest)),
+        // Debug formatting keeps the decimal point of whole numbers
+        Operand::Double(value) => format!("${:?}", value),
+        Operand::Var(dest) => serialize_dest(dest, names),
+        Operand::Str(index) => format!("${}", serialize_string_label(*index)),
     }
 }
 
+fn serialize_string_label(index: usize) -> String {
+    format!("S{}", index)
+}
+
+/// Constraints of inline assembly written the way LLVM reads them, like `=a,r,~{rcx}`: the
+/// output's, if there's one, then the inputs', then the registers it overwrites
+pub(super) fn serialize_asm_constraints(has_output: bool, constraints: &AsmConstraints) -> String {
+    let operands = constraints
+        .operands
+        .iter()
+        .enumerate()
+        .map(|(index, letter)| match index == 0 && has_output {
+            true => format!("={}", letter),
+            false => letter.to_string(),
+        });
+    let clobbers = constraints
+        .clobbers
+        .iter()
+        .map(|clobber| format!("~{{{}}}", clobber));
+    operands.chain(clobbers).collect::<Vec<_>>().join(",")
+}
+
 fn serialize_condition(condition: &Condition) -> String {
     match condition {
-        Condition::Greater => format!("is_g"),
-        Condition::Less => format!("is_l"),
-        Condition::Equal => format!("is_eq"),
-        Condition::NotEqual => format!("is_neq"),
-        Condition::GreaterOrEqual => format!("is_geq"),
-        Condition::LessOrEqual => format!("is_leq"),
+        Condition::Greater => "is_g".to_string(),
+        Condition::Less => "is_l".to_string(),
+        Condition::Equal => "is_eq".to_string(),
+        Condition::NotEqual => "is_neq".to_string(),
+        Condition::GreaterOrEqual => "is_geq".to_string(),
+        Condition::LessOrEqual => "is_leq".to_string(),
+    }
+}
+
+fn serialize_conversion(conversion: &Conversion) -> String {
+    match conversion {
+        Conversion::I2D => "i2d".to_string(),
+        Conversion::D2I => "d2i".to_string(),
+        Conversion::I2C => "i2c".to_string(),
+    }
+}
+
+pub(super) fn serialize_format_spec(spec: &FormatSpec) -> String {
+    match spec {
+        FormatSpec::Int => "%d".to_string(),
+        FormatSpec::Double => "%f".to_string(),
+        FormatSpec::Char => "%c".to_string(),
+        FormatSpec::String => "%s".to_string(),
+    }
+}
+
+/// Suffix marking an operator that works on doubles
+fn serialize_arithmetic(arithmetic: &Arithmetic) -> &'static str {
+    match arithmetic {
+        Arithmetic::Int => "",
+        Arithmetic::Double => "d",
     }
 }
 
@@ -35,55 +107,145 @@ fn serialize_label(label: &AsmLabel) -> String {
 }
 
 pub fn emit_abstract(
-    outpath: &PathBuf,
-    func_contexts: &Vec<Context>,
-    _globals: &Vec<VarDeclaration>,
+    outpath: &Path,
+    func_contexts: &[Context],
+    globals: &[Global],
+    strings: &StringTable,
+    debug_names: bool,
+) -> io::Result<()> {
+    let mut file = File::create(outpath)?;
+    write_abstract(&mut file, func_contexts, globals, strings, debug_names)
+}
+
+/// Writes the program as abstract assembly to `file`, in the format `parse_ir` reads. With
+/// `debug_names`, temps holding a variable are written with its name.
+pub fn write_abstract(
+    file: &mut impl Write,
+    func_contexts: &[Context],
+    globals: &[Global],
+    strings: &StringTable,
+    debug_names: bool,
 ) -> io::Result<()> {
-    let mut file = File::create(&outpath)?;
+    if !globals.is_empty() {
+        file.write_all(b".data\n")?;
+        for global in globals {
+            if !global.is_static {
+                file.write_all(format!(".globl {}\n", global.name).as_bytes())?;
+            }
+            let line = format!(
+                "{} <- {}\n",
+                global.name,
+                serialize_operand(&global.value, None)
+            );
+            file.write_all(line.as_bytes())?;
+        }
+    }
+    if !strings.is_empty() {
+        file.write_all(b".rodata\n")?;
+        for (index, string) in strings.iter() {
+            // Debug formatting quotes the string and escapes quotes and control characters
+            let line = format!("{} <- {:?}\n", serialize_string_label(index), string);
+            file.write_all(line.as_bytes())?;
+        }
+    }
     for context in func_contexts {
-        file.write_all(format!(".{}\n", context.name).as_bytes());
+        // Static functions stay out of the exported symbol table
+        if !context.is_static {
+            file.write_all(format!(".globl {}\n", context.name).as_bytes())?;
+        }
+        // The parameters follow the name, as the temps they're passed in
+        let names = debug_names.then_some(context);
+        let header: String = (0..context.params)
+            .map(|temp| format!(" {}", serialize_dest(&Dest::Temp(temp), names)))
+            .collect();
+        file.write_all(format!(".{}{}\n", context.name, header).as_bytes())?;
         for instruction in &context.instructions {
             let line = match instruction {
                 AbstractAssemblyInstruction::BinOp {
                     op,
+                    arithmetic,
                     dest,
                     src1,
                     src2,
                 } => {
                     format!(
-                        "{} <- {} {} {}\n",
-                        serialize_dest(&dest),
-                        serialize_operand(&src1),
+                        "{} <- {} {}{} {}\n",
+                        serialize_dest(dest, names),
+                        serialize_operand(src1, names),
                         match op {
-                            Token::Plus => "+",
-                            Token::Minus => "-",
-                            Token::Star => "*",
-                            Token::Slash => "/",
-                            Token::EqualEqual => "==",
-                            Token::Greater => ">",
-                            Token::GreaterEqual => ">=",
-                            Token::Less => "<",
-                            Token::LessEqual => "<=",
-                            _ => unimplemented!("Unsupported binary operation {:?}", op),
+                            BinOp::Add => "+",
+                            BinOp::Sub => "-",
+                            BinOp::Mul => "*",
+                            BinOp::Div => "/",
+                            BinOp::Equal => "==",
+                            BinOp::NotEqual => "!=",
+                            BinOp::Greater => ">",
+                            BinOp::GreaterEqual => ">=",
+                            BinOp::Less => "<",
+                            BinOp::LessEqual => "<=",
                         },
-                        serialize_operand(&src2)
+                        serialize_arithmetic(arithmetic),
+                        serialize_operand(src2, names)
                     )
                 }
-                AbstractAssemblyInstruction::UnOp { op, dest, src } => {
+                AbstractAssemblyInstruction::UnOp {
+                    op,
+                    arithmetic,
+                    dest,
+                    src,
+                } => {
                     format!(
-                        "{} <- {}{}\n",
-                        serialize_dest(&dest),
+                        "{} <- {}{}{}\n",
+                        serialize_dest(dest, names),
                         match op {
-                            Token::Bang => "!",
-                            Token::Minus => "-",
-                            Token::Tilde => "~",
-                            _ => unimplemented!("Unsupported unary operation"),
+                            UnOp::Not => "!",
+                            UnOp::Neg => "-",
+                            UnOp::BitNot => "~",
+                        },
+                        // Separated so that `-d` isn't read as part of the operand
+                        match arithmetic {
+                            Arithmetic::Int => "",
+                            Arithmetic::Double => "d ",
                         },
-                        serialize_operand(&src)
+                        serialize_operand(src, names)
                     )
                 }
                 AbstractAssemblyInstruction::Mov { dest, src } => {
-                    format!("{} <- {}\n", serialize_dest(&dest), serialize_operand(&src))
+                    format!(
+                        "{} <- {}\n",
+                        serialize_dest(dest, names),
+                        serialize_operand(src, names)
+                    )
+                }
+                AbstractAssemblyInstruction::Convert {
+                    conversion,
+                    dest,
+                    src,
+                } => {
+                    format!(
+                        "{} <- {} {}\n",
+                        serialize_dest(dest, names),
+                        serialize_conversion(conversion),
+                        serialize_operand(src, names)
+                    )
+                }
+                AbstractAssemblyInstruction::Shift {
+                    kind,
+                    dest,
+                    src,
+                    amount,
+                } => {
+                    format!(
+                        "{} <- {} {} ${}\n",
+                        serialize_dest(dest, names),
+                        serialize_operand(src, names),
+                        match kind {
+                            ShiftKind::Left => "<<",
+                            ShiftKind::ArithmeticRight => ">>",
+                            ShiftKind::LogicalRight => ">>>",
+                        },
+                        amount
+                    )
                 }
                 AbstractAssemblyInstruction::JmpCondition {
                     condition,
@@ -98,21 +260,23 @@ pub fn emit_abstract(
                     )
                 }
                 AbstractAssemblyInstruction::Compare {
+                    arithmetic,
                     left,
                     right,
                     condition,
                 } => {
                     format!(
-                        "cmp {} {} {}\n",
-                        serialize_operand(left),
+                        "cmp{} {} {} {}\n",
+                        serialize_arithmetic(arithmetic),
+                        serialize_operand(left, names),
                         serialize_condition(condition),
-                        serialize_operand(right)
+                        serialize_operand(right, names)
                     )
                 }
                 AbstractAssemblyInstruction::SetIf { dest, condition } => {
                     format!(
                         "set {} {}\n",
-                        serialize_dest(dest),
+                        serialize_dest(dest, names),
                         serialize_condition(condition)
                     )
                 }
@@ -122,20 +286,86 @@ pub fn emit_abstract(
                 AbstractAssemblyInstruction::Lbl(label) => {
                     format!("{}:\n", serialize_label(label))
                 }
+                AbstractAssemblyInstruction::Loc { file, line } => {
+                    format!("loc {} {}\n", file, line)
+                }
                 AbstractAssemblyInstruction::Return(operand) => {
-                    format!("%eax <- {}\nret\n", serialize_operand(operand))
+                    format!("%eax <- {}\nret\n", serialize_operand(operand, names))
+                }
+                AbstractAssemblyInstruction::Print { spec, src } => {
+                    format!(
+                        "print {} {}\n",
+                        serialize_format_spec(spec),
+                        serialize_operand(src, names)
+                    )
+                }
+                AbstractAssemblyInstruction::Scan { dest, spec } => {
+                    format!(
+                        "{} <- scan {}\n",
+                        serialize_dest(dest, names),
+                        serialize_format_spec(spec)
+                    )
+                }
+                AbstractAssemblyInstruction::Call {
+                    dest,
+                    function,
+                    args,
+                } => {
+                    let assignment = match dest {
+                        Some(dest) => format!("{} <- ", serialize_dest(dest, names)),
+                        None => String::new(),
+                    };
+                    let args: String = args
+                        .iter()
+                        .map(|arg| format!(" {}", serialize_operand(arg, names)))
+                        .collect();
+                    format!("{}call {}{}\n", assignment, function, args)
                 }
-                AbstractAssemblyInstruction::ReturnVoid => {
-                    format!("ret\n")
+                AbstractAssemblyInstruction::Load { dest, global } => {
+                    format!("{} <- load {}\n", serialize_dest(dest, names), global)
                 }
+                AbstractAssemblyInstruction::Store { global, src } => {
+                    format!("store {} {}\n", global, serialize_operand(src, names))
+                }
+                AbstractAssemblyInstruction::Asm {
+                    template,
+                    output,
+                    inputs,
+                    constraints,
+                } => {
+                    let assignment = match output {
+                        Some(output) => format!("{} <- ", serialize_dest(output, names)),
+                        None => String::new(),
+                    };
+                    let inputs: String = inputs
+                        .iter()
+                        .map(|input| format!(" {}", serialize_operand(input, names)))
+                        .collect();
+                    // Quoted and escaped like the strings, and the constraints after the
+                    // template only if there are any
+                    let constraints = match constraints.is_unconstrained() {
+                        true => String::new(),
+                        false => {
+                            let constraints =
+                                serialize_asm_constraints(output.is_some(), constraints);
+                            format!(" {:?}", constraints)
+                        }
+                    };
+                    format!(
+                        "{}asm {:?}{}{}\n",
+                        assignment, template, constraints, inputs
+                    )
+                }
+                AbstractAssemblyInstruction::Abort => "abort\n".to_string(),
+                AbstractAssemblyInstruction::ReturnVoid => "ret\n".to_string(),
                 AbstractAssemblyInstruction::Phi { dest, srcs } => {
                     format!(
                         "phi {} {}\n",
-                        serialize_dest(dest),
+                        serialize_dest(dest, names),
                         srcs.iter()
                             .map(|(operand, label)| format!(
                                 "({}, {})",
-                                serialize_operand(operand),
+                                serialize_operand(operand, names),
                                 serialize_label(label)
                             ))
                             .collect::<Vec<_>>()
@@ -151,26 +381,783 @@ pub fn emit_abstract(
     Ok(())
 }
 
+/// Writes the functions, after instruction selection and two-address conversion, as x86-64
+/// assembly in AT&T syntax, with the directives of an assembler writing objects of `format`.
+/// Temps are written as `%t4` until registers are allocated. With a `line_table`, the debug
+/// information is written too: the source line of each statement, where each variable is, and
+/// how to find each frame's caller.
 pub fn emit_x86(
-    outpath: &PathBuf,
-    _func_contexts: &Vec<Context>,
-    _globals: &Vec<VarDeclaration>,
+    outpath: &Path,
+    functions: &[X86Function],
+    globals: &[Global],
+    strings: &StringTable,
+    format: Format,
+    line_table: Option<&LineTable>,
+    pic: bool,
 ) -> io::Result<()> {
-    let _file = File::create(&outpath)?;
-    // ...
-    // for each context of each function, iterate context.instructions and emit as x86 code
-    // ...
-    Ok(())
+    let mut file = File::create(outpath)?;
+    write_x86(
+        &mut file, functions, globals, strings, format, line_table, pic,
+    )
+}
+
+fn write_x86(
+    file: &mut impl Write,
+    functions: &[X86Function],
+    globals: &[Global],
+    strings: &StringTable,
+    format: Format,
+    line_table: Option<&LineTable>,
+    pic: bool,
+) -> io::Result<()> {
+    let debug = line_table.is_some();
+    // Position-independent ELF code calls the functions another object could define, or
+    // override, through the procedure linkage table. Static functions can only be the ones
+    // here, and Windows always links calls to the definition or an import stub.
+    let local: HashSet<&str> = functions
+        .iter()
+        .filter(|function| function.is_static)
+        .map(|function| function.symbol.as_str())
+        .collect();
+    let through_plt = |callee: &str| pic && format == Format::Elf && !local.contains(callee);
+    if let Some(line_table) = line_table {
+        dwarf::write_files(file, line_table)?;
+    }
+    file.write_all(b"\t.text\n")?;
+    for function in functions {
+        if !function.is_static {
+            writeln!(file, "\t.globl {}", function.symbol)?;
+        }
+        match format {
+            Format::Elf => writeln!(file, "\t.type {}, @function", function.symbol)?,
+            // An external or static symbol of type function, in COFF's terms
+            Format::Coff => writeln!(
+                file,
+                "\t.def {}; .scl {}; .type 32; .endef",
+                function.symbol,
+                if function.is_static { 3 } else { 2 }
+            )?,
+        }
+        writeln!(file, "{}:", function.symbol)?;
+        if debug {
+            file.write_all(b"\t.cfi_startproc\n")?;
+        }
+        // The function's first line covers the prologue, which comes after its mark
+        let marks = function
+            .instructions
+            .iter()
+            .take_while(|instruction| matches!(instruction, X86Instruction::Loc { .. }))
+            .count();
+        let (marks, body) = function
+            .instructions
+            .split_at(if debug { marks } else { 0 });
+        for instruction in marks {
+            file.write_all(serialize_x86_instruction(instruction, &function.symbol).as_bytes())?;
+        }
+        write_x86_prologue(file, &function.frame, debug)?;
+        for instruction in body {
+            match instruction {
+                X86Instruction::Loc { .. } if !debug => continue,
+                X86Instruction::Ret(_) => write_x86_epilogue(file, &function.frame, debug)?,
+                X86Instruction::Call {
+                    function: callee, ..
+                } if through_plt(callee) => {
+                    writeln!(file, "\tcall {}@PLT", callee)?;
+                    continue;
+                }
+                _ => {}
+            }
+            file.write_all(serialize_x86_instruction(instruction, &function.symbol).as_bytes())?;
+            // The code after a return is still inside the frame
+            if debug && matches!(instruction, X86Instruction::Ret(_)) {
+                file.write_all(b"\t.cfi_restore_state\n")?;
+            }
+        }
+        if debug {
+            writeln!(file, "{}:", dwarf::end_symbol(&function.symbol))?;
+            file.write_all(b"\t.cfi_endproc\n")?;
+        }
+        if format == Format::Elf {
+            writeln!(file, "\t.size {0}, .-{0}", function.symbol)?;
+        }
+    }
+
+    let mut doubles: Vec<f64> = Vec::new();
+    for double in functions.iter().flat_map(|function| &function.doubles) {
+        if !doubles
+            .iter()
+            .any(|seen| seen.to_bits() == double.to_bits())
+        {
+            doubles.push(*double);
+        }
+    }
+    if !strings.is_empty() || !doubles.is_empty() {
+        match format {
+            Format::Elf => file.write_all(b"\t.section .rodata\n")?,
+            Format::Coff => file.write_all(b"\t.section .rdata,\"dr\"\n")?,
+        }
+        for (index, string) in strings.iter() {
+            writeln!(file, "{}:", string_symbol(index))?;
+            writeln!(file, "\t.string \"{}\"", escape_x86_string(string))?;
+        }
+        if !doubles.is_empty() {
+            file.write_all(b"\t.align 8\n")?;
+        }
+        for double in doubles {
+            writeln!(file, "{}:", double_symbol(double))?;
+            // The bits, so that the value is exact whatever the assembler's rounding
+            writeln!(file, "\t.quad 0x{:016x}", double.to_bits())?;
+        }
+    }
+
+    if !globals.is_empty() {
+        file.write_all(b"\t.data\n")?;
+        for global in globals {
+            if !global.is_static {
+                writeln!(file, "\t.globl {}", global.name)?;
+            }
+            writeln!(file, "{}:", global.name)?;
+            let line = match &global.value {
+                Operand::Const(value) => format!("\t.long {}\n", *value as i32),
+                Operand::Double(value) => format!("\t.quad 0x{:016x}\n", value.to_bits()),
+                Operand::Str(index) => format!("\t.quad {}\n", string_symbol(*index)),
+                Operand::Var(_) => unreachable!("globals are initialized with constants"),
+            };
+            file.write_all(line.as_bytes())?;
+        }
+    }
+
+    if let Some(line_table) = line_table {
+        dwarf::write_debug_info(file, functions, line_table, format)?;
+    }
+
+    // Nothing here runs code from the stack, which the linker otherwise assumes it may
+    match format {
+        Format::Elf => file.write_all(b"\t.section .note.GNU-stack,\"\",@progbits\n"),
+        Format::Coff => Ok(()),
+    }
+}
+
+/// Sets up `frame`: %rbp points at the caller's, unless the frame pointer is omitted, after
+/// which the callee-saved registers the function writes are saved, and the rest of the frame
+/// reserved under them. With `cfi`, each step is described for the debugger, as the distance
+/// from %rsp or %rbp to the canonical frame address, the caller's %rsp before the call, and
+/// where each saved register went.
+fn write_x86_prologue(file: &mut impl Write, frame: &Frame, cfi: bool) -> io::Result<()> {
+    // The return address is already pushed
+    let mut cfa_offset = 8;
+    if !frame.omit_frame_pointer {
+        file.write_all(b"\tpushq %rbp\n")?;
+        cfa_offset += 8;
+        if cfi {
+            writeln!(file, "\t.cfi_def_cfa_offset {}", cfa_offset)?;
+            writeln!(file, "\t.cfi_offset %rbp, -{}", cfa_offset)?;
+        }
+        file.write_all(b"\tmovq %rsp, %rbp\n")?;
+        if cfi {
+            file.write_all(b"\t.cfi_def_cfa_register %rbp\n")?;
+        }
+    }
+    for register in &frame.saved_registers {
+        let name = register.name(Size::Quad);
+        writeln!(file, "\tpushq {}", name)?;
+        cfa_offset += 8;
+        if cfi && frame.omit_frame_pointer {
+            writeln!(file, "\t.cfi_def_cfa_offset {}", cfa_offset)?;
+        }
+        if cfi {
+            writeln!(file, "\t.cfi_offset {}, -{}", name, cfa_offset)?;
+        }
+    }
+    match frame.size() {
+        0 => Ok(()),
+        size => {
+            writeln!(file, "\tsubq ${}, %rsp", size)?;
+            match cfi && frame.omit_frame_pointer {
+                true => writeln!(file, "\t.cfi_def_cfa_offset {}", cfa_offset + size),
+                false => Ok(()),
+            }
+        }
+    }
+}
+
+/// Undoes `write_x86_prologue`, before a `ret`. With `cfi`, the frame as the prologue left it
+/// is remembered, to be restored after the `ret`.
+fn write_x86_epilogue(file: &mut impl Write, frame: &Frame, cfi: bool) -> io::Result<()> {
+    let frame_pointer = usize::from(!frame.omit_frame_pointer);
+    let mut cfa_offset = 8 * (1 + frame_pointer + frame.saved_registers.len()) + frame.size();
+    if cfi {
+        file.write_all(b"\t.cfi_remember_state\n")?;
+    }
+    let cfa_moves = cfi && frame.omit_frame_pointer;
+    match frame.size() {
+        0 => {}
+        size => {
+            writeln!(file, "\taddq ${}, %rsp", size)?;
+            cfa_offset -= size;
+            if cfa_moves {
+                writeln!(file, "\t.cfi_def_cfa_offset {}", cfa_offset)?;
+            }
+        }
+    }
+    for register in frame.saved_registers.iter().rev() {
+        writeln!(file, "\tpopq {}", register.name(Size::Quad))?;
+        cfa_offset -= 8;
+        if cfa_moves {
+            writeln!(file, "\t.cfi_def_cfa_offset {}", cfa_offset)?;
+        }
+    }
+    if frame.omit_frame_pointer {
+        return Ok(());
+    }
+    file.write_all(b"\tpopq %rbp\n")?;
+    match cfi {
+        true => file.write_all(b"\t.cfi_def_cfa %rsp, 8\n"),
+        false => Ok(()),
+    }
+}
+
+/// Escapes `string` for a `.string` directive, writing bytes other than printable ASCII in
+/// octal
+pub(super) fn escape_x86_string(string: &str) -> String {
+    let mut escaped = String::new();
+    for byte in string.bytes() {
+        match byte {
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    escaped
+}
+
+/// Writes a temp as `%t4`, and a register by its name for a value of `size`
+fn serialize_x86_dest(dest: &Dest, size: Size) -> String {
+    match dest {
+        Dest::Register(index) => X86Register::from_index(*index).name(size),
+        Dest::Temp(temp) => format!("%t{}", temp),
+    }
 }
 
+fn serialize_x86_address(address: &Address) -> String {
+    let displacement = match (&address.symbol, address.displacement) {
+        (Some(symbol), 0) => symbol.clone(),
+        (Some(symbol), displacement) => format!("{}{:+}", symbol, displacement),
+        (None, 0) => String::new(),
+        (None, displacement) => displacement.to_string(),
+    };
+    if address.symbol.is_some() {
+        return format!("{}(%rip)", displacement);
+    }
+    let base = address
+        .base
+        .as_ref()
+        .map_or(String::new(), |base| serialize_x86_dest(base, Size::Quad));
+    match &address.index {
+        Some((index, scale)) => format!(
+            "{}({},{},{})",
+            displacement,
+            base,
+            serialize_x86_dest(index, Size::Quad),
+            scale
+        ),
+        None => format!("{}({})", displacement, base),
+    }
+}
+
+fn serialize_x86_operand(operand: &X86Operand, size: Size) -> String {
+    match operand {
+        X86Operand::Reg(dest) => serialize_x86_dest(dest, size),
+        X86Operand::Imm(value) => format!("${}", value),
+        X86Operand::Mem(address) => serialize_x86_address(address),
+    }
+}
+
+fn serialize_x86_label(label: &AsmLabel, function: &str) -> String {
+    format!(".L{}_{}", function, label.0)
+}
+
+/// Operands of an instruction writing `dest` from `dest` and `right`, in AT&T order, after
+/// two-address conversion
+fn serialize_x86_operands(
+    dest: &Dest,
+    left: &X86Operand,
+    right: &X86Operand,
+    size: Size,
+) -> String {
+    debug_assert_eq!(
+        left,
+        &X86Operand::Reg(dest.clone()),
+        "not in two-address form"
+    );
+    format!(
+        "{}, {}",
+        serialize_x86_operand(right, size),
+        serialize_x86_dest(dest, size)
+    )
+}
+
+/// Operand of a one-operand instruction writing `dest` from itself, after two-address
+/// conversion
+fn serialize_x86_unary_operand(dest: &Dest, src: &X86Operand, size: Size) -> String {
+    debug_assert_eq!(
+        src,
+        &X86Operand::Reg(dest.clone()),
+        "not in two-address form"
+    );
+    serialize_x86_dest(dest, size)
+}
+
+fn serialize_x86_instruction(instruction: &X86Instruction, function: &str) -> String {
+    match instruction {
+        X86Instruction::Mov { size, dest, src } => format!(
+            "\tmov{} {}, {}\n",
+            size.suffix(),
+            serialize_x86_operand(src, *size),
+            serialize_x86_operand(dest, *size)
+        ),
+        X86Instruction::Movsd { dest, src } => format!(
+            "\tmovsd {}, {}\n",
+            serialize_x86_operand(src, Size::Quad),
+            serialize_x86_operand(dest, Size::Quad)
+        ),
+        X86Instruction::GotLoad { dest, symbol } => format!(
+            "\tmovq {}@GOTPCREL(%rip), {}\n",
+            symbol,
+            serialize_x86_dest(dest, Size::Quad)
+        ),
+        X86Instruction::Lea {
+            size,
+            dest,
+            address,
+        } => format!(
+            "\tlea{} {}, {}\n",
+            size.suffix(),
+            serialize_x86_address(address),
+            serialize_x86_dest(dest, *size)
+        ),
+        X86Instruction::Alu {
+            op,
+            size,
+            dest,
+            left,
+            right,
+        } => format!(
+            "\t{}{} {}\n",
+            match op {
+                AluOp::Add => "add",
+                AluOp::Sub => "sub",
+                AluOp::Imul => "imul",
+                AluOp::And => "and",
+                AluOp::Or => "or",
+            },
+            size.suffix(),
+            serialize_x86_operands(dest, left, right, *size)
+        ),
+        X86Instruction::ImulImm { dest, src, imm } => format!(
+            "\timull ${}, {}, {}\n",
+            imm,
+            serialize_x86_operand(src, Size::Long),
+            serialize_x86_dest(dest, Size::Long)
+        ),
+        X86Instruction::Shift {
+            kind,
+            dest,
+            src,
+            amount,
+        } => format!(
+            "\t{}l ${}, {}\n",
+            match kind {
+                ShiftKind::Left => "sal",
+                ShiftKind::ArithmeticRight => "sar",
+                ShiftKind::LogicalRight => "shr",
+            },
+            amount,
+            serialize_x86_unary_operand(dest, src, Size::Long)
+        ),
+        X86Instruction::Unary { op, dest, src } => format!(
+            "\t{}l {}\n",
+            match op {
+                UnaryOp::Neg => "neg",
+                UnaryOp::Not => "not",
+            },
+            serialize_x86_unary_operand(dest, src, Size::Long)
+        ),
+        X86Instruction::Cdq => "\tcltd\n".to_string(),
+        X86Instruction::Idiv { divisor } => {
+            format!("\tidivl {}\n", serialize_x86_operand(divisor, Size::Long))
+        }
+        X86Instruction::Sse {
+            op,
+            dest,
+            left,
+            right,
+        } => format!(
+            "\t{}sd {}\n",
+            match op {
+                SseOp::Add => "add",
+                SseOp::Sub => "sub",
+                SseOp::Mul => "mul",
+                SseOp::Div => "div",
+            },
+            serialize_x86_operands(dest, left, right, Size::Quad)
+        ),
+        X86Instruction::Cvtsi2sd { dest, src } => format!(
+            "\tcvtsi2sdl {}, {}\n",
+            serialize_x86_operand(src, Size::Long),
+            serialize_x86_dest(dest, Size::Quad)
+        ),
+        X86Instruction::Cvttsd2si { dest, src } => format!(
+            "\tcvttsd2si {}, {}\n",
+            serialize_x86_operand(src, Size::Quad),
+            serialize_x86_dest(dest, Size::Long)
+        ),
+        X86Instruction::Cmp { size, left, right } => format!(
+            "\tcmp{} {}, {}\n",
+            size.suffix(),
+            serialize_x86_operand(right, *size),
+            serialize_x86_operand(left, *size)
+        ),
+        X86Instruction::Ucomisd { left, right } => format!(
+            "\tucomisd {}, {}\n",
+            serialize_x86_operand(right, Size::Quad),
+            serialize_x86_dest(left, Size::Quad)
+        ),
+        X86Instruction::Set { condition, dest } => format!(
+            "\tset{} {}\n\tmovzbl {}, {}\n",
+            condition.suffix(),
+            serialize_x86_dest(dest, Size::Byte),
+            serialize_x86_dest(dest, Size::Byte),
+            serialize_x86_dest(dest, Size::Long)
+        ),
+        X86Instruction::Jmp(target) => {
+            format!("\tjmp {}\n", serialize_x86_label(target, function))
+        }
+        X86Instruction::Jcc { condition, target } => format!(
+            "\tj{} {}\n",
+            condition.suffix(),
+            serialize_x86_label(target, function)
+        ),
+        X86Instruction::Label(label) => format!("{}:\n", serialize_x86_label(label, function)),
+        // The assembler numbers files from 1
+        X86Instruction::Loc { file, line } => format!("\t.loc {} {}\n", file + 1, line),
+        X86Instruction::StoreArgument { ty, index, src } => {
+            let address = serialize_x86_address(&Frame::outgoing_argument(*index));
+            match ty {
+                Type::Double => format!(
+                    "\tmovsd {}, {}\n",
+                    serialize_x86_operand(src, Size::Quad),
+                    address
+                ),
+                ty => format!(
+                    "\tmov{} {}, {}\n",
+                    Size::of(*ty).suffix(),
+                    serialize_x86_operand(src, Size::of(*ty)),
+                    address
+                ),
+            }
+        }
+        // The arguments are already in the registers they're passed in
+        X86Instruction::Call { function, .. } => format!("\tcall {}\n", function),
+        X86Instruction::Asm {
+            template,
+            output,
+            inputs,
+            ..
+        } => {
+            let operands: Vec<&(Type, Dest)> = output.iter().chain(inputs).collect();
+            format!("\t{}\n", serialize_x86_template(template, &operands))
+        }
+        X86Instruction::Ret(_) => "\tret\n".to_string(),
+    }
+}
+
+/// Inline assembly's `template`, with each `%0`, `%1`, ... replaced by the register of that
+/// operand, named for its type, and `%%` by `%`. Without operands, the template is written as
+/// is, so registers can be named with a single `%`.
+fn serialize_x86_template(template: &str, operands: &[&(Type, Dest)]) -> String {
+    if operands.is_empty() {
+        return template.to_string();
+    }
+    let mut serialized = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('%') {
+        serialized.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if let Some(after) = after.strip_prefix('%') {
+            serialized.push('%');
+            rest = after;
+        } else if let Some((ty, dest)) = after[..digits]
+            .parse()
+            .ok()
+            .and_then(|index: usize| operands.get(index))
+        {
+            let size = match ty {
+                // An xmm register has one name, whatever its size
+                Type::Double => Size::Quad,
+                _ => Size::of(*ty),
+            };
+            serialized.push_str(&serialize_x86_dest(dest, size));
+            rest = &after[digits..];
+        } else {
+            serialized.push('%');
+            rest = after;
+        }
+    }
+    serialized.push_str(rest);
+    serialized
+}
+
+/// Writes the 6502 `functions`, the startup code first, as assembly for ca65 or as machine
+/// code in the file `format` names. The zero-page state is given its addresses in
+/// `zero_page`, and `variables` are reserved in absolute memory.
 pub fn emit_m6502(
-    outpath: &PathBuf,
-    _func_contexts: &Vec<Context>,
-    _globals: &Vec<VarDeclaration>,
+    outpath: &Path,
+    functions: &[M6502Function],
+    globals: &[Global],
+    strings: &StringTable,
+    zero_page: ZeroPage,
+    variables: &[(String, usize)],
+    format: OutputFormat,
 ) -> io::Result<()> {
-    let _file = File::create(&outpath)?;
-    // ...
-    // for each context of each function, iterate context.instructions and emit as x86 code
-    // ...
+    match format {
+        OutputFormat::Assembly => {}
+        OutputFormat::Prg | OutputFormat::Nes => {
+            // A cartridge keeps its globals in RAM, with the variables
+            let rom_globals = match format {
+                OutputFormat::Nes => &[],
+                _ => globals,
+            };
+            let program = container::program_items(functions, rom_globals, strings)?;
+            let bytes = match format {
+                OutputFormat::Prg => container::prg(program, variables)?,
+                _ => container::nes(program, variables)?,
+            };
+            return std::fs::write(outpath, bytes);
+        }
+        OutputFormat::Object => unreachable!("the 6502 target writes no objects"),
+        OutputFormat::LlvmIr => unreachable!("LLVM IR is written without the backend"),
+        OutputFormat::C => unreachable!("C is written without codegen"),
+    }
+
+    let mut file = File::create(outpath)?;
+    for symbol in m6502_imports(functions) {
+        writeln!(file, "\t.import {}", symbol)?;
+    }
+    writeln!(file, "c0_sp = ${:02x}", zero_page.stack_pointer())?;
+    writeln!(file, "c0_result = ${:02x}", zero_page.result())?;
+    writeln!(file, "c0_ordering = ${:02x}", zero_page.ordering())?;
+
+    file.write_all(b"\t.code\n")?;
+    for function in functions {
+        if !function.is_static {
+            writeln!(file, "\t.export {}", function.symbol)?;
+        }
+        writeln!(file, "{}:", function.symbol)?;
+        for instruction in &function.instructions {
+            file.write_all(serialize_m6502_instruction(instruction).as_bytes())?;
+        }
+    }
+
+    if !strings.is_empty() {
+        file.write_all(b"\t.rodata\n")?;
+        for (index, string) in strings.iter() {
+            writeln!(file, "{}:", m6502::string_symbol(index))?;
+            writeln!(file, "\t.byte {}", m6502_string_bytes(string))?;
+        }
+    }
+
+    if !globals.is_empty() {
+        file.write_all(b"\t.data\n")?;
+        for global in globals {
+            let symbol = m6502::symbol(&global.name);
+            if !global.is_static {
+                writeln!(file, "\t.export {}", symbol)?;
+            }
+            writeln!(file, "{}:", symbol)?;
+            let line = match &global.value {
+                Operand::Const(value) => format!("\t.dword {}\n", *value as i32),
+                Operand::Str(index) => format!("\t.addr {}\n", m6502::string_symbol(*index)),
+                Operand::Double(_) => unreachable!("the backend rejects doubles"),
+                Operand::Var(_) => unreachable!("globals are initialized with constants"),
+            };
+            file.write_all(line.as_bytes())?;
+        }
+    }
+
+    file.write_all(b"\t.bss\n")?;
+    for (symbol, size) in variables {
+        match size {
+            0 => writeln!(file, "{}:", symbol)?,
+            _ => writeln!(file, "{}:\t.res {}", symbol, size)?,
+        }
+    }
     Ok(())
 }
+
+/// Symbols `functions` call or jump to that none of them defines, like `c0_putchar`, which
+/// the platform supplies
+fn m6502_imports(functions: &[M6502Function]) -> BTreeSet<&str> {
+    let defined: HashSet<&str> = functions
+        .iter()
+        .flat_map(|function| {
+            let labels = function
+                .instructions
+                .iter()
+                .filter_map(|instruction| match instruction {
+                    M6502Instruction::Label(label) => Some(label.as_str()),
+                    _ => None,
+                });
+            labels.chain([function.symbol.as_str()])
+        })
+        .collect();
+    functions
+        .iter()
+        .flat_map(|function| &function.instructions)
+        .filter_map(|instruction| match instruction {
+            M6502Instruction::Op(_, M6502Operand::Target(symbol))
+                if !symbol.starts_with('@') && !defined.contains(symbol.as_str()) =>
+            {
+                Some(symbol.as_str())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn serialize_m6502_instruction(instruction: &M6502Instruction) -> String {
+    match instruction {
+        M6502Instruction::Op(mnemonic, M6502Operand::Implied) => format!("\t{}\n", mnemonic),
+        M6502Instruction::Op(mnemonic, operand) => {
+            format!("\t{} {}\n", mnemonic, serialize_m6502_operand(operand))
+        }
+        M6502Instruction::Label(label) => format!("{}:\n", label),
+        M6502Instruction::Asm(template) => template
+            .lines()
+            .map(|line| format!("\t{}\n", line.trim()))
+            .collect(),
+    }
+}
+
+fn serialize_m6502_operand(operand: &M6502Operand) -> String {
+    match operand {
+        M6502Operand::Implied => String::new(),
+        M6502Operand::Immediate(Byte::Literal(byte)) => format!("#${:02x}", byte),
+        M6502Operand::Immediate(Byte::Low(symbol)) => format!("#<{}", symbol),
+        M6502Operand::Immediate(Byte::High(symbol)) => format!("#>{}", symbol),
+        M6502Operand::Memory(address) => address.to_string(),
+        M6502Operand::IndexedX(address) => format!("{},x", address),
+        M6502Operand::IndexedY(address) => format!("{},y", address),
+        M6502Operand::IndirectX(address) => format!("(${:02x},x)", address),
+        M6502Operand::IndirectY(address) => format!("(${:02x}),y", address),
+        M6502Operand::Indirect(address) => format!("({})", address),
+        M6502Operand::Target(target) => target.clone(),
+    }
+}
+
+/// The bytes of `string` and the zero ending it, for `.byte`: runs of printable characters
+/// quoted, and the rest as numbers
+fn m6502_string_bytes(string: &str) -> String {
+    let mut items = Vec::new();
+    let mut run = String::new();
+    for byte in string.bytes() {
+        if (b' '..=b'~').contains(&byte) && byte != b'"' {
+            run.push(byte as char);
+            continue;
+        }
+        if !run.is_empty() {
+            items.push(format!("\"{}\"", std::mem::take(&mut run)));
+        }
+        items.push(byte.to_string());
+    }
+    if !run.is_empty() {
+        items.push(format!("\"{}\"", run));
+    }
+    items.push("0".to_string());
+    items.join(", ")
+}
+
+pub fn emit_riscv(
+    outpath: &Path,
+    functions: &[RiscvFunction],
+    globals: &[Global],
+    strings: &StringTable,
+) -> io::Result<()> {
+    if globals
+        .iter()
+        .any(|global| matches!(global.value, Operand::Double(_)))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the RISC-V target can't compile doubles",
+        ));
+    }
+    let mut file = File::create(outpath)?;
+    file.write_all(b"\t.text\n")?;
+    for function in functions {
+        if !function.is_static {
+            writeln!(file, "\t.globl {}", function.symbol)?;
+        }
+        writeln!(file, "\t.type {}, @function", function.symbol)?;
+        writeln!(file, "{}:", function.symbol)?;
+        for instruction in &function.instructions {
+            file.write_all(serialize_riscv_instruction(instruction).as_bytes())?;
+        }
+        writeln!(file, "\t.size {0}, .-{0}", function.symbol)?;
+    }
+
+    if !strings.is_empty() {
+        file.write_all(b"\t.section .rodata\n")?;
+        for (index, string) in strings.iter() {
+            writeln!(file, "{}:", riscv::string_symbol(index))?;
+            writeln!(file, "\t.string \"{}\"", escape_x86_string(string))?;
+        }
+    }
+
+    if !globals.is_empty() {
+        file.write_all(b"\t.data\n\t.p2align 2\n")?;
+        for global in globals {
+            if !global.is_static {
+                writeln!(file, "\t.globl {}", global.name)?;
+            }
+            writeln!(file, "{}:", global.name)?;
+            let line = match &global.value {
+                Operand::Const(value) => format!("\t.word {}\n", *value as i32),
+                Operand::Str(index) => format!("\t.word {}\n", riscv::string_symbol(*index)),
+                Operand::Double(_) => unreachable!("doubles are rejected above"),
+                Operand::Var(_) => unreachable!("globals are initialized with constants"),
+            };
+            file.write_all(line.as_bytes())?;
+        }
+    }
+
+    file.write_all(b"\t.section .note.GNU-stack,\"\",@progbits\n")
+}
+
+fn serialize_riscv_instruction(instruction: &RiscvInstruction) -> String {
+    match instruction {
+        RiscvInstruction::Op(mnemonic, operands) if operands.is_empty() => {
+            format!("\t{}\n", mnemonic)
+        }
+        RiscvInstruction::Op(mnemonic, operands) => {
+            let operands: Vec<String> = operands.iter().map(serialize_riscv_operand).collect();
+            format!("\t{} {}\n", mnemonic, operands.join(", "))
+        }
+        RiscvInstruction::Label(label) => format!("{}:\n", label),
+        RiscvInstruction::Asm(template) => template
+            .lines()
+            .map(|line| format!("\t{}\n", line.trim()))
+            .collect(),
+    }
+}
+
+fn serialize_riscv_operand(operand: &RiscvOperand) -> String {
+    match operand {
+        RiscvOperand::Register(register) => register.to_string(),
+        RiscvOperand::Immediate(value) => value.to_string(),
+        RiscvOperand::Memory(offset, base) => format!("{}({})", offset, base),
+        RiscvOperand::Symbol(symbol) => symbol.clone(),
+    }
+}
@@ -0,0 +1,180 @@
+//! Layout of the stack frame of an x86-64 function. From the top, at the higher addresses:
+//!
+//! ```text
+//! 16(%rbp)  arguments the caller passed on the stack, above the shadow space it reserved
+//!  8(%rbp)  return address
+//!   (%rbp)  the caller's %rbp
+//!           callee-saved registers the function writes, in the order they're pushed
+//!           padding, keeping %rsp a multiple of 16 at calls
+//!           spill slots
+//!   (%rsp)  arguments the function passes on the stack, and shadow space for its callees
+//! ```
+//!
+//! Every slot takes 8 bytes. Spill slots are addressed from %rsp, and the caller's arguments
+//! from %rbp, until `place` settles the layout after register allocation. Without a frame
+//! pointer, %rbp isn't pushed or set, and the caller's arguments are found from %rsp. A leaf
+//! function under the System V calling convention may keep its spill slots in the red zone,
+//! the 128 bytes under %rsp that signal handlers leave alone, without reserving anything.
+
+use super::x86::{
+    Address, CallingConvention, X86Function, X86Instruction, X86Operand, X86Register,
+};
+
+/// Bytes each slot takes
+const SLOT_SIZE: i32 = 8;
+
+/// Bytes under %rsp a function may use without moving it, under the System V calling
+/// convention
+const RED_ZONE_SIZE: i32 = 128;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Frame {
+    /// Callee-saved registers the function writes, which it saves on entry and restores
+    /// before returning, in the order they're pushed
+    pub saved_registers: Vec<X86Register>,
+    /// Number of slots holding the arguments of calls that don't fit in registers and the
+    /// shadow space, enough for any call the function makes
+    pub outgoing_slots: usize,
+    /// Number of slots holding spilled temps
+    pub spill_slots: usize,
+    /// True if %rbp is left as the caller had it, with `--fomit-frame-pointer`
+    pub omit_frame_pointer: bool,
+    /// True if the spill slots are in the red zone, so that nothing is reserved for them
+    pub red_zone: bool,
+}
+
+impl Frame {
+    /// Address of the argument the caller passed in the stack slot `slot`, counted from the
+    /// caller's %rsp at the call, as instruction selection addresses it
+    pub fn incoming_argument(slot: usize) -> Address {
+        Frame::address(X86Register::Rbp, 2 * SLOT_SIZE + slot as i32 * SLOT_SIZE)
+    }
+
+    /// Address of the stack slot `slot` of a call's arguments, as the callee counts them
+    pub fn outgoing_argument(slot: usize) -> Address {
+        Frame::address(X86Register::Rsp, slot as i32 * SLOT_SIZE)
+    }
+
+    /// Address of the spill slot `slot`
+    pub fn spill_slot(&self, slot: usize) -> Address {
+        let offset = match self.red_zone {
+            true => -(slot as i32 + 1) * SLOT_SIZE,
+            false => (self.outgoing_slots + slot) as i32 * SLOT_SIZE,
+        };
+        Frame::address(X86Register::Rsp, offset)
+    }
+
+    /// Address of a new spill slot
+    pub fn new_spill_slot(&mut self) -> Address {
+        self.spill_slots += 1;
+        self.spill_slot(self.spill_slots - 1)
+    }
+
+    /// Number of the spill slot at `address`, if it's one
+    pub fn spill_slot_at(&self, address: &Address) -> Option<usize> {
+        let offset = Frame::offset(X86Register::Rsp, address)?;
+        let slot = match self.red_zone {
+            true => -offset / SLOT_SIZE - 1,
+            false => offset / SLOT_SIZE - self.outgoing_slots as i32,
+        };
+        (0..self.spill_slots as i32)
+            .contains(&slot)
+            .then_some(slot as usize)
+    }
+
+    /// Bytes the prologue reserves under the saved registers, for the spill slots and the
+    /// outgoing arguments, with the padding that keeps %rsp a multiple of 16 at calls, as both
+    /// calling conventions require. On entry, the return address leaves it 8 bytes off, and
+    /// the frame pointer and saved registers are pushed before the rest is reserved.
+    pub fn size(&self) -> usize {
+        if self.red_zone {
+            return 0;
+        }
+        let pushed = self.pushed() as usize;
+        let slots = SLOT_SIZE as usize * (self.outgoing_slots + self.spill_slots);
+        (pushed + slots).next_multiple_of(16) - pushed
+    }
+
+    /// Bytes on the stack above the reserved part of the frame: the return address, %rbp and
+    /// the saved registers
+    fn pushed(&self) -> i32 {
+        let frame_pointer = usize::from(!self.omit_frame_pointer);
+        SLOT_SIZE * (1 + frame_pointer + self.saved_registers.len()) as i32
+    }
+
+    /// Where `address`, as register allocation left it, is once the frame is laid out
+    fn placed(&self, allocated: &Frame, address: &Address) -> Option<Address> {
+        if let Some(slot) = allocated.spill_slot_at(address) {
+            return Some(self.spill_slot(slot));
+        }
+        // The caller's arguments, 16(%rbp) and up, are past everything the function pushed
+        let offset = Frame::offset(X86Register::Rbp, address)?;
+        let from_rsp = self.size() as i32 + self.pushed() - 2 * SLOT_SIZE + offset;
+        self.omit_frame_pointer
+            .then(|| Frame::address(X86Register::Rsp, from_rsp))
+    }
+
+    fn address(base: X86Register, offset: i32) -> Address {
+        Address {
+            base: Some(base.dest()),
+            index: None,
+            displacement: offset,
+            symbol: None,
+        }
+    }
+
+    /// Offset of `address` from `base`, if that's all it is
+    fn offset(base: X86Register, address: &Address) -> Option<i32> {
+        match address {
+            Address {
+                base: Some(from),
+                index: None,
+                displacement,
+                symbol: None,
+            } if *from == base.dest() => Some(*displacement),
+            _ => None,
+        }
+    }
+}
+
+/// Settles the layout of the frame of `function`, once its registers are allocated: whether
+/// it keeps a frame pointer, and whether its spill slots go in the red zone, which they only
+/// may when it calls nothing and they fit. The stack slots the instructions read and write
+/// are moved to match.
+pub fn place(function: &mut X86Function, omit_frame_pointer: bool, red_zone: bool) {
+    let allocated = function.frame.clone();
+    let frame = &mut function.frame;
+    frame.omit_frame_pointer = omit_frame_pointer;
+    let leaf = !function
+        .instructions
+        .iter()
+        .any(|instruction| matches!(instruction, X86Instruction::Call { .. }));
+    frame.red_zone = red_zone
+        && leaf
+        && function.convention == CallingConvention::SystemV
+        && frame.spill_slots > 0
+        && frame.spill_slots as i32 * SLOT_SIZE <= RED_ZONE_SIZE;
+    if *frame == allocated {
+        return;
+    }
+    for instruction in &mut function.instructions {
+        if let X86Instruction::Mov { dest, src, .. } | X86Instruction::Movsd { dest, src } =
+            instruction
+        {
+            for operand in [dest, src] {
+                if let X86Operand::Mem(address) = operand {
+                    if let Some(placed) = frame.placed(&allocated, address) {
+                        *address = placed;
+                    }
+                }
+            }
+        }
+    }
+    for variable in &mut function.variables {
+        if let X86Operand::Mem(address) = &mut variable.home {
+            if let Some(placed) = frame.placed(&allocated, address) {
+                *address = placed;
+            }
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Incremental builds of x86 objects. Each function's machine code is cached under a hash of
+//! its abstract assembly, once the passes have run, and of everything else its code depends
+//! on, so that the next build reuses the code of each function that hasn't changed instead of
+//! selecting its instructions, allocating its registers and assembling it again.
+
+use super::assembler::{Relocation, RelocationKind};
+use super::context::{Context, StringTable};
+use super::emit::write_abstract;
+use super::x86_object::FunctionCode;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Bumped whenever the entries' format or the code they hold changes, so that older caches
+/// are never read as newer ones
+const VERSION: u32 = 1;
+
+/// Machine code of a function, with what the statistics report about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedFunction {
+    pub code: FunctionCode,
+    /// Stack slots the function spills temps to
+    pub spills: usize,
+    /// Instructions the function was selected into, without the labels
+    pub emitted: usize,
+}
+
+/// A directory holding a file for each function compiled so far, named by its hash
+#[derive(Debug, Clone)]
+pub struct FunctionCache {
+    dir: PathBuf,
+}
+
+impl FunctionCache {
+    pub fn new(dir: PathBuf) -> Self {
+        FunctionCache { dir }
+    }
+
+    /// The function cached under `hash`, if there is one. An entry that can't be read is
+    /// compiled again, like a missing one.
+    pub fn load(&self, hash: u64) -> Option<CachedFunction> {
+        let text = fs::read_to_string(self.path(hash)).ok()?;
+        parse_entry(&text)
+    }
+
+    /// Caches `function` under `hash`. The entry is written next to its place, then moved
+    /// there, so that a build stopped partway never leaves half of one.
+    pub fn store(&self, hash: u64, function: &CachedFunction) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(hash);
+        let partial = path.with_extension("partial");
+        fs::write(&partial, write_entry(function))?;
+        fs::rename(partial, path)
+    }
+
+    fn path(&self, hash: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.fn", hash))
+    }
+}
+
+/// Stable hash of `context` as abstract assembly, with the types of its temps and
+/// `environment`, which holds whatever else the backend's code for it depends on. Strings are
+/// hashed by their labels, which is all the code holds of them.
+pub fn function_hash(context: &Context, environment: &str) -> u64 {
+    let mut bytes = format!(
+        "{}\n{}\n{}\n",
+        VERSION,
+        env!("CARGO_PKG_VERSION"),
+        environment
+    );
+    let mut text = Vec::new();
+    let strings = StringTable::default();
+    write_abstract(
+        &mut text,
+        std::slice::from_ref(context),
+        &[],
+        &strings,
+        false,
+    )
+    .expect("writing to memory can't fail");
+    bytes.push_str(&String::from_utf8_lossy(&text));
+    let mut types: Vec<_> = context.temp_types().iter().collect();
+    types.sort_by_key(|(temp, _)| **temp);
+    for (temp, ty) in types {
+        bytes.push_str(&format!("%t{}: {:?}\n", temp, ty));
+    }
+    bytes.push_str(&format!(
+        "temps {} labels {} returns {:?}\n",
+        context.temp_count(),
+        context.label_count(),
+        context.return_type()
+    ));
+    fnv1a(bytes.as_bytes())
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is the same in every build of the compiler
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// `function` as the lines of an entry: its symbol, whether it's static, its statistics, its
+/// bytes in hex, then each double it reads and each relocation
+fn write_entry(function: &CachedFunction) -> String {
+    let code = &function.code;
+    let mut text = format!(
+        "symbol {}\nstatic {}\nspills {}\nemitted {}\nbytes ",
+        code.symbol, code.is_static, function.spills, function.emitted
+    );
+    for byte in &code.bytes {
+        text.push_str(&format!("{:02x}", byte));
+    }
+    text.push('\n');
+    for double in &code.doubles {
+        text.push_str(&format!("double {:016x}\n", double.to_bits()));
+    }
+    for relocation in &code.relocations {
+        let kind = match relocation.kind {
+            RelocationKind::Pc32 => "pc32",
+            RelocationKind::Plt32 => "plt32",
+            RelocationKind::Abs64 => "abs64",
+        };
+        text.push_str(&format!(
+            "relocation {} {} {} {}\n",
+            relocation.offset, kind, relocation.addend, relocation.symbol
+        ));
+    }
+    text
+}
+
+/// The function `write_entry` wrote as `text`, if it's well formed
+fn parse_entry(text: &str) -> Option<CachedFunction> {
+    let mut lines = text.lines();
+    let mut field = |name: &str| {
+        let (key, value) = lines.next()?.split_once(' ')?;
+        (key == name).then_some(value)
+    };
+    let symbol = field("symbol")?.to_string();
+    let is_static = field("static")?.parse().ok()?;
+    let spills = field("spills")?.parse().ok()?;
+    let emitted = field("emitted")?.parse().ok()?;
+    let hex = field("bytes")?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let mut doubles = Vec::new();
+    let mut relocations = Vec::new();
+    for line in lines {
+        match line.split_once(' ')? {
+            ("double", bits) => doubles.push(f64::from_bits(u64::from_str_radix(bits, 16).ok()?)),
+            ("relocation", fields) => {
+                let mut fields = fields.splitn(4, ' ');
+                let offset: usize = fields.next()?.parse().ok()?;
+                let kind = match fields.next()? {
+                    "pc32" => RelocationKind::Pc32,
+                    "plt32" => RelocationKind::Plt32,
+                    "abs64" => RelocationKind::Abs64,
+                    _ => return None,
+                };
+                let addend = fields.next()?.parse().ok()?;
+                let symbol = fields.next()?.to_string();
+                if offset + 4 > bytes.len() {
+                    return None;
+                }
+                relocations.push(Relocation {
+                    offset,
+                    symbol,
+                    kind,
+                    addend,
+                });
+            }
+            _ => return None,
+        }
+    }
+    Some(CachedFunction {
+        code: FunctionCode {
+            symbol,
+            is_static,
+            bytes,
+            relocations,
+            doubles,
+        },
+        spills,
+        emitted,
+    })
+}
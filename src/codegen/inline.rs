@@ -0,0 +1,234 @@
+//! Function inlining. A call to a small function is replaced with a copy of its body: the
+//! callee's temps and labels are renamed to new ones of the caller's, its parameters are set
+//! from the arguments, and each return becomes a move of the value returned and a jump past
+//! the copy.
+//!
+//! Only functions of at most `MAX_INLINED_SIZE` instructions are copied, and inlining adds at
+//! most `MAX_GROWTH` instructions to a caller. Recursive functions, which can reach a call to
+//! themselves through any chain of calls, are never copied, so inlining always stops. Neither
+//! are functions in SSA form, nor ones with inline assembly, whose labels would be defined
+//! twice.
+
+use super::context::{AbstractAssemblyInstruction, AsmLabel, Context, Dest, Operand};
+use super::pass::Pass;
+use super::CodegenOptions;
+use crate::sema::Type;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+/// Largest number of instructions a function may have to be inlined
+const MAX_INLINED_SIZE: usize = 32;
+
+/// Largest number of instructions inlining may add to one caller
+const MAX_GROWTH: usize = 256;
+
+pub struct Inlining;
+
+impl Pass for Inlining {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+
+    fn level(&self) -> u8 {
+        2
+    }
+
+    /// With no other functions to read, there's nothing to inline
+    fn run(&self, context: &mut Context, options: &CodegenOptions) {
+        self.run_in_program(context, &[], options)
+    }
+
+    fn run_in_program(
+        &self,
+        context: &mut Context,
+        functions: &[&Context],
+        _options: &CodegenOptions,
+    ) {
+        let calls = call_graph(context, functions);
+        let callees: HashMap<&str, &Context> = functions
+            .iter()
+            .map(|&function| (function.name.as_str(), function))
+            .filter(|(name, function)| can_inline(function) && !is_recursive(name, &calls))
+            .collect();
+        let mut budget = MAX_GROWTH;
+        // Line the caller's instructions are marked with, to mark them again after a copy
+        let mut line = None;
+        let mut instructions = Vec::with_capacity(context.instructions.len());
+        for instruction in mem::take(&mut context.instructions) {
+            if let AbstractAssemblyInstruction::Loc { .. } = instruction {
+                line = Some(instruction.clone());
+            }
+            if let AbstractAssemblyInstruction::Call {
+                dest,
+                function,
+                args,
+            } = &instruction
+            {
+                let callee = callees
+                    .get(function.as_str())
+                    .filter(|callee| size(callee) <= budget);
+                if let Some(callee) = callee {
+                    budget -= size(callee);
+                    inline(context, callee, dest.as_ref(), args, &mut instructions);
+                    instructions.extend(line.clone());
+                    continue;
+                }
+            }
+            instructions.push(instruction);
+        }
+        context.instructions = instructions;
+    }
+}
+
+/// Number of instructions `function` compiles into, not counting line markers
+fn size(function: &Context) -> usize {
+    function
+        .instructions
+        .iter()
+        .filter(|instruction| !matches!(instruction, AbstractAssemblyInstruction::Loc { .. }))
+        .count()
+}
+
+/// Whether `function` is small enough to copy, and can be copied at all
+fn can_inline(function: &Context) -> bool {
+    size(function) <= MAX_INLINED_SIZE
+        && !function.is_ssa()
+        && !function
+            .instructions
+            .iter()
+            .any(|instruction| matches!(instruction, AbstractAssemblyInstruction::Asm { .. }))
+}
+
+/// Names of the functions each of `context` and `functions` calls, by name
+fn call_graph<'a>(
+    context: &'a Context,
+    functions: &[&'a Context],
+) -> HashMap<&'a str, HashSet<&'a str>> {
+    std::iter::once(context)
+        .chain(functions.iter().copied())
+        .map(|function| {
+            let called = function
+                .instructions
+                .iter()
+                .filter_map(|instruction| match instruction {
+                    AbstractAssemblyInstruction::Call { function, .. } => Some(function.as_str()),
+                    _ => None,
+                })
+                .collect();
+            (function.name.as_str(), called)
+        })
+        .collect()
+}
+
+/// Whether `function` can reach a call to itself in `calls`
+fn is_recursive(function: &str, calls: &HashMap<&str, HashSet<&str>>) -> bool {
+    let mut seen = HashSet::new();
+    let mut pending: Vec<&str> = calls.get(function).into_iter().flatten().copied().collect();
+    while let Some(name) = pending.pop() {
+        if name == function {
+            return true;
+        }
+        if seen.insert(name) {
+            pending.extend(calls.get(name).into_iter().flatten().copied());
+        }
+    }
+    false
+}
+
+/// New names in the caller for the callee's temps and labels, made as they're first seen
+struct Renaming<'a> {
+    caller: &'a mut Context,
+    types: &'a HashMap<usize, Type>,
+    temps: HashMap<usize, usize>,
+    labels: HashMap<usize, usize>,
+}
+
+impl Renaming<'_> {
+    fn temp(&mut self, temp: usize) -> usize {
+        let Renaming {
+            caller,
+            types,
+            temps,
+            ..
+        } = self;
+        *temps
+            .entry(temp)
+            .or_insert_with(|| caller.new_temp(types[&temp]))
+    }
+
+    fn label(&mut self, label: &mut AsmLabel) {
+        let caller = &mut self.caller;
+        label.0 = *self
+            .labels
+            .entry(label.0)
+            .or_insert_with(|| caller.new_label());
+    }
+
+    fn dest(&mut self, dest: &mut Dest) {
+        if let Dest::Temp(temp) = dest {
+            *temp = self.temp(*temp);
+        }
+    }
+
+    fn instruction(&mut self, instruction: &mut AbstractAssemblyInstruction) {
+        for operand in instruction.operands_mut() {
+            if let Operand::Var(dest) = operand {
+                self.dest(dest);
+            }
+        }
+        if let Some(dest) = instruction.dest_mut() {
+            self.dest(dest);
+        }
+        for target in instruction.jump_targets_mut() {
+            self.label(target);
+        }
+        if let AbstractAssemblyInstruction::Lbl(label) = instruction {
+            self.label(label);
+        }
+    }
+}
+
+/// Appends to `out` a copy of `callee`'s body in place of a call from `caller` that passes
+/// `args` and keeps what it returns in `dest`
+fn inline(
+    caller: &mut Context,
+    callee: &Context,
+    dest: Option<&Dest>,
+    args: &[Operand],
+    out: &mut Vec<AbstractAssemblyInstruction>,
+) {
+    let end = AsmLabel(caller.new_label());
+    let mut renaming = Renaming {
+        caller,
+        types: callee.temp_types(),
+        temps: HashMap::new(),
+        labels: HashMap::new(),
+    };
+    // The parameters are passed in the callee's first temps
+    for (param, arg) in args.iter().enumerate() {
+        out.push(AbstractAssemblyInstruction::Mov {
+            dest: Dest::Temp(renaming.temp(param)),
+            src: arg.clone(),
+        });
+    }
+    for instruction in &callee.instructions {
+        let mut copy = instruction.clone();
+        renaming.instruction(&mut copy);
+        match copy {
+            AbstractAssemblyInstruction::Return(value) => {
+                if let Some(dest) = dest {
+                    out.push(AbstractAssemblyInstruction::Mov {
+                        dest: dest.clone(),
+                        src: value,
+                    });
+                }
+                out.push(AbstractAssemblyInstruction::Jmp(end));
+            }
+            AbstractAssemblyInstruction::ReturnVoid => {
+                out.push(AbstractAssemblyInstruction::Jmp(end))
+            }
+            copy => out.push(copy),
+        }
+    }
+    out.push(AbstractAssemblyInstruction::Lbl(end));
+}
@@ -0,0 +1,616 @@
+//! Interpreter for abstract assembly, so that tests can check what a program computes without
+//! a native backend. Each call runs with a value for each of its function's temps, and what the
+//! program prints is collected instead of written out. A program run as a whole reads the input
+//! it's given, and ends with an exit code, as its executable would.
+//!
+//! Arithmetic follows C0: ints are 32 bits and wrap around, and what `Undefined` lists is a
+//! runtime error, reported with the source line it happened on when the program was compiled
+//! with `-g`. Doubles print with six decimals, like `%f` does in C.
+
+use super::context::{
+    AbstractAssemblyInstruction, Arithmetic, Condition, Context, Conversion, Dest, Operand,
+    ShiftKind,
+};
+use super::undefined::Undefined;
+use super::IrModule;
+use crate::parser::{BinOp, FormatSpec, UnOp};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Most instructions a run may take, counting those of the functions it calls, before it's
+/// stopped, in case it never returns
+const MAX_STEPS: usize = 10_000_000;
+
+/// Most calls that may be running at once, in case a function recurses without end
+const MAX_DEPTH: usize = 100_000;
+
+/// A value a temp can hold. Chars and bools are ints.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Double(f64),
+    Str(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Double(value) => write!(f, "{:.6}", value),
+            Value::Str(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// What running a function did
+#[derive(Debug, Clone, PartialEq)]
+pub struct Execution {
+    /// The value returned, or None for a `void` function
+    pub value: Option<Value>,
+    /// Everything printed
+    pub output: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    UnknownFunction {
+        name: String,
+    },
+    UnknownGlobal {
+        name: String,
+    },
+    // A temp read before anything was written to it
+    Uninitialized {
+        temp: usize,
+    },
+    // An operand of the wrong kind for its instruction, like a string added to an int
+    TypeMismatch {
+        instruction: String,
+    },
+    // An operation from the `Undefined` table, with the index of the file in the program's
+    // `LineTable` and the line it was compiled from, if known
+    Undefined {
+        behavior: Undefined,
+        location: Option<(usize, usize)>,
+    },
+    // A failed contract or assertion
+    Aborted {
+        output: String,
+    },
+    // The run took more than `MAX_STEPS` instructions
+    StepLimit,
+    // More than `MAX_DEPTH` calls were running at once
+    DepthLimit,
+    // Registers are only assigned by the backends
+    Register,
+    // Inline assembly only means something to its target
+    InlineAssembly,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::UnknownFunction { name } => write!(f, "No function '{}'", name),
+            RuntimeError::UnknownGlobal { name } => write!(f, "No global '{}'", name),
+            RuntimeError::Uninitialized { temp } => {
+                write!(f, "%t{} is read before it's written", temp)
+            }
+            RuntimeError::TypeMismatch { instruction } => {
+                write!(f, "Operands of the wrong type in {}", instruction)
+            }
+            RuntimeError::Undefined { behavior, .. } => write!(
+                f,
+                "Undefined behavior: `{}` where {}",
+                behavior.operation(),
+                behavior.condition()
+            ),
+            RuntimeError::Aborted { .. } => write!(f, "The program aborted"),
+            RuntimeError::StepLimit => {
+                write!(f, "Stopped after {} instructions", MAX_STEPS)
+            }
+            RuntimeError::DepthLimit => {
+                write!(f, "Stopped after {} nested calls", MAX_DEPTH)
+            }
+            RuntimeError::Register => write!(f, "Registers can't be interpreted"),
+            RuntimeError::InlineAssembly => write!(f, "Inline assembly can't be interpreted"),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// What running a program did, as its executable would have
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunResult {
+    /// Everything printed
+    pub stdout: String,
+    /// The low byte of what `main` returned, as the exit status keeps it, or None if the
+    /// program aborted, as it would be killed by a signal
+    pub exit_code: Option<i32>,
+    /// Instructions run, counting those of the functions called
+    pub steps: usize,
+}
+
+/// Runs `function` of `module` with `args` in its first temps, which is where parameters live
+pub fn interpret(
+    module: &IrModule,
+    function: &str,
+    args: &[Value],
+) -> Result<Execution, RuntimeError> {
+    let mut machine = Machine::start(module, function, args, &[])?;
+    let value = machine.run()?;
+    Ok(Execution {
+        value,
+        output: machine.output,
+    })
+}
+
+/// Runs the `main` of `module` as a program, with `input` for `scan` to read. An abort ends
+/// the run like `main` returning does; any other runtime error is returned.
+pub fn run_with_io(module: &IrModule, input: &[u8]) -> Result<RunResult, RuntimeError> {
+    let mut machine = Machine::start(module, "main", &[], input)?;
+    let exit_code = match machine.run() {
+        Ok(Some(Value::Int(value))) => Some(value & 0xff),
+        Ok(_) => Some(0),
+        Err(RuntimeError::Aborted { .. }) => None,
+        Err(error) => return Err(error),
+    };
+    Ok(RunResult {
+        stdout: machine.output,
+        exit_code,
+        steps: machine.steps,
+    })
+}
+
+fn find<'a>(module: &'a IrModule, function: &str) -> Result<&'a Context, RuntimeError> {
+    module
+        .functions
+        .iter()
+        .find(|context| context.name == function)
+        .ok_or_else(|| RuntimeError::UnknownFunction {
+            name: function.to_string(),
+        })
+}
+
+/// A call being run
+struct Frame<'a> {
+    context: &'a Context,
+    /// Index of the instruction defining each label
+    labels: HashMap<usize, usize>,
+    pc: usize,
+    /// Label of the block being run, and of the one before it, which phis pick from
+    block: Option<usize>,
+    previous_block: Option<usize>,
+    temps: HashMap<usize, Value>,
+    /// How the operands of the last comparison are ordered; None if they're unordered, like
+    /// a NaN and anything else
+    flags: Option<Ordering>,
+    /// File index and line of the last `Loc` run
+    location: Option<(usize, usize)>,
+}
+
+impl<'a> Frame<'a> {
+    fn new(context: &'a Context, args: Vec<Value>) -> Self {
+        let labels = context
+            .instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(index, instruction)| match instruction {
+                AbstractAssemblyInstruction::Lbl(label) => Some((label.0, index)),
+                _ => None,
+            })
+            .collect();
+        Frame {
+            context,
+            labels,
+            pc: 0,
+            block: None,
+            previous_block: None,
+            temps: args.into_iter().enumerate().collect(),
+            flags: None,
+            location: None,
+        }
+    }
+}
+
+/// State of a running program. Calls are kept on a stack of their own, rather than Rust's, so
+/// that deep recursion can't overflow it.
+struct Machine<'a> {
+    module: &'a IrModule,
+    strings: Vec<&'a str>,
+    frame: Frame<'a>,
+    /// Calls waiting for the one above them to return, innermost last, with where each keeps
+    /// the value returned
+    callers: Vec<(Frame<'a>, Option<&'a Dest>)>,
+    /// Value of each global, by name
+    globals: HashMap<&'a str, Value>,
+    output: String,
+    /// What the program reads, and how much of it it's read
+    input: &'a [u8],
+    position: usize,
+    /// Instructions run so far
+    steps: usize,
+}
+
+impl<'a> Machine<'a> {
+    /// A machine about to run `function` of `module` with `args`, reading `input`
+    fn start(
+        module: &'a IrModule,
+        function: &str,
+        args: &[Value],
+        input: &'a [u8],
+    ) -> Result<Self, RuntimeError> {
+        let mut machine = Machine {
+            module,
+            strings: module.strings.iter().map(|(_, string)| string).collect(),
+            frame: Frame::new(find(module, function)?, args.to_vec()),
+            callers: Vec::new(),
+            globals: HashMap::new(),
+            output: String::new(),
+            input,
+            position: 0,
+            steps: 0,
+        };
+        // Each global starts with its initializer, which is a constant
+        for global in &module.globals {
+            let value = machine.read(&global.value)?;
+            machine.globals.insert(&global.name, value);
+        }
+        Ok(machine)
+    }
+
+    fn run(&mut self) -> Result<Option<Value>, RuntimeError> {
+        loop {
+            let instructions = &self.frame.context.instructions;
+            let value = match instructions.get(self.frame.pc) {
+                // Falling off the end of a function returns nothing
+                None => None,
+                Some(instruction) => {
+                    self.steps += 1;
+                    if self.steps > MAX_STEPS {
+                        return Err(RuntimeError::StepLimit);
+                    }
+                    self.frame.pc += 1;
+                    match instruction {
+                        AbstractAssemblyInstruction::Return(value) => Some(self.read(value)?),
+                        AbstractAssemblyInstruction::ReturnVoid => None,
+                        _ => {
+                            self.execute(instruction)?;
+                            continue;
+                        }
+                    }
+                }
+            };
+            // The call returns to its caller, if it has one
+            let Some((caller, dest)) = self.callers.pop() else {
+                return Ok(value);
+            };
+            self.frame = caller;
+            if let (Some(dest), Some(value)) = (dest, value) {
+                self.write(dest, value)?;
+            }
+        }
+    }
+
+    /// Runs an instruction that doesn't return
+    fn execute(
+        &mut self,
+        instruction: &'a AbstractAssemblyInstruction,
+    ) -> Result<(), RuntimeError> {
+        match instruction {
+            AbstractAssemblyInstruction::Lbl(label) => {
+                self.frame.previous_block = self.frame.block;
+                self.frame.block = Some(label.0);
+            }
+            AbstractAssemblyInstruction::Jmp(target) => {
+                self.frame.pc = self.frame.labels[&target.0]
+            }
+            AbstractAssemblyInstruction::JmpCondition {
+                condition,
+                tgt_true,
+                tgt_false,
+            } => {
+                let target = if self.holds(condition) {
+                    tgt_true
+                } else {
+                    tgt_false
+                };
+                self.frame.pc = self.frame.labels[&target.0];
+            }
+            AbstractAssemblyInstruction::Call {
+                dest,
+                function,
+                args,
+            } => {
+                if self.callers.len() + 1 == MAX_DEPTH {
+                    return Err(RuntimeError::DepthLimit);
+                }
+                let args = args
+                    .iter()
+                    .map(|arg| self.read(arg))
+                    .collect::<Result<_, _>>()?;
+                let callee = Frame::new(find(self.module, function)?, args);
+                let caller = std::mem::replace(&mut self.frame, callee);
+                self.callers.push((caller, dest.as_ref()));
+            }
+            AbstractAssemblyInstruction::Loc { file, line } => {
+                self.frame.location = Some((*file, *line))
+            }
+            AbstractAssemblyInstruction::Asm { .. } => return Err(RuntimeError::InlineAssembly),
+            AbstractAssemblyInstruction::Abort => {
+                return Err(RuntimeError::Aborted {
+                    output: self.output.clone(),
+                })
+            }
+            AbstractAssemblyInstruction::Phi { .. } => {
+                // The phis at the start of a block all read the values from before it
+                let instructions = &self.frame.context.instructions;
+                let mut values = Vec::new();
+                let mut end = self.frame.pc - 1;
+                while let Some(AbstractAssemblyInstruction::Phi { dest, srcs }) =
+                    instructions.get(end)
+                {
+                    let source = srcs
+                        .iter()
+                        .find(|(_, label)| Some(label.0) == self.frame.previous_block)
+                        .map(|(operand, _)| operand)
+                        .ok_or_else(|| mismatch(instruction))?;
+                    values.push((dest, self.read(source)?));
+                    end += 1;
+                }
+                for (dest, value) in values {
+                    self.write(dest, value)?;
+                }
+                self.frame.pc = end;
+            }
+            _ => self.step(instruction)?,
+        }
+        Ok(())
+    }
+
+    /// Runs an instruction that doesn't change where control goes
+    fn step(&mut self, instruction: &AbstractAssemblyInstruction) -> Result<(), RuntimeError> {
+        let value = match instruction {
+            AbstractAssemblyInstruction::BinOp {
+                op,
+                arithmetic,
+                src1,
+                src2,
+                ..
+            } => match (arithmetic, self.read(src1)?, self.read(src2)?) {
+                (Arithmetic::Int, Value::Int(left), Value::Int(right)) => {
+                    let result = int_binary(*op, left, right);
+                    Value::Int(result.map_err(|behavior| RuntimeError::Undefined {
+                        behavior,
+                        location: self.frame.location,
+                    })?)
+                }
+                (Arithmetic::Double, Value::Double(left), Value::Double(right)) => {
+                    double_binary(*op, left, right)
+                }
+                _ => return Err(mismatch(instruction)),
+            },
+            AbstractAssemblyInstruction::UnOp { op, src, .. } => match (op, self.read(src)?) {
+                (UnOp::Neg, Value::Int(value)) => Value::Int(value.wrapping_neg()),
+                (UnOp::Neg, Value::Double(value)) => Value::Double(-value),
+                (UnOp::Not, Value::Int(value)) => Value::Int((value == 0) as i32),
+                (UnOp::BitNot, Value::Int(value)) => Value::Int(!value),
+                _ => return Err(mismatch(instruction)),
+            },
+            AbstractAssemblyInstruction::Mov { src, .. } => self.read(src)?,
+            AbstractAssemblyInstruction::Convert {
+                conversion, src, ..
+            } => match (conversion, self.read(src)?) {
+                (Conversion::I2D, Value::Int(value)) => Value::Double(value as f64),
+                (Conversion::D2I, Value::Double(value)) => {
+                    if let Some(behavior) = Undefined::of_d2i(value) {
+                        return Err(RuntimeError::Undefined {
+                            behavior,
+                            location: self.frame.location,
+                        });
+                    }
+                    Value::Int(value as i32)
+                }
+                (Conversion::I2C, Value::Int(value)) => Value::Int(value & 0xff),
+                _ => return Err(mismatch(instruction)),
+            },
+            AbstractAssemblyInstruction::Shift {
+                kind, src, amount, ..
+            } => match self.read(src)? {
+                Value::Int(value) => Value::Int(match kind {
+                    ShiftKind::Left => value.wrapping_shl(*amount),
+                    ShiftKind::ArithmeticRight => value.wrapping_shr(*amount),
+                    ShiftKind::LogicalRight => (value as u32).wrapping_shr(*amount) as i32,
+                }),
+                _ => return Err(mismatch(instruction)),
+            },
+            AbstractAssemblyInstruction::Compare { left, right, .. } => {
+                self.frame.flags = match (self.read(left)?, self.read(right)?) {
+                    (Value::Int(left), Value::Int(right)) => Some(left.cmp(&right)),
+                    (Value::Double(left), Value::Double(right)) => left.partial_cmp(&right),
+                    _ => return Err(mismatch(instruction)),
+                };
+                return Ok(());
+            }
+            AbstractAssemblyInstruction::SetIf { condition, .. } => {
+                Value::Int(self.holds(condition) as i32)
+            }
+            AbstractAssemblyInstruction::Load { global, .. } => self
+                .globals
+                .get(global.as_str())
+                .cloned()
+                .ok_or_else(|| unknown_global(global))?,
+            AbstractAssemblyInstruction::Store { global, src } => {
+                let value = self.read(src)?;
+                *self
+                    .globals
+                    .get_mut(global.as_str())
+                    .ok_or_else(|| unknown_global(global))? = value;
+                return Ok(());
+            }
+            AbstractAssemblyInstruction::Print { spec, src } => {
+                let printed = match (spec, self.read(src)?) {
+                    (FormatSpec::Char, Value::Int(value)) => (value as u8 as char).to_string(),
+                    (FormatSpec::Int, value @ Value::Int(_))
+                    | (FormatSpec::Double, value @ Value::Double(_))
+                    | (FormatSpec::String, value @ Value::Str(_)) => value.to_string(),
+                    _ => return Err(mismatch(instruction)),
+                };
+                self.output.push_str(&printed);
+                return Ok(());
+            }
+            AbstractAssemblyInstruction::Scan { spec, .. } => {
+                let rest = &self.input[self.position..];
+                let start = rest
+                    .iter()
+                    .position(|byte| !is_space(*byte))
+                    .unwrap_or(rest.len());
+                let (value, length) =
+                    scan_number(spec, &rest[start..]).ok_or_else(|| RuntimeError::Aborted {
+                        output: self.output.clone(),
+                    })?;
+                self.position += start + length;
+                value
+            }
+            _ => unreachable!("control flow is handled by `run`"),
+        };
+        let dest = instruction
+            .dest()
+            .expect("every other instruction writes a temp");
+        self.write(dest, value)
+    }
+
+    fn read(&self, operand: &Operand) -> Result<Value, RuntimeError> {
+        match operand {
+            Operand::Const(value) => Ok(Value::Int(*value as i32)),
+            Operand::Double(value) => Ok(Value::Double(*value)),
+            Operand::Str(index) => Ok(Value::Str(self.strings[*index].to_string())),
+            Operand::Var(Dest::Temp(temp)) => self
+                .frame
+                .temps
+                .get(temp)
+                .cloned()
+                .ok_or(RuntimeError::Uninitialized { temp: *temp }),
+            Operand::Var(Dest::Register(_)) => Err(RuntimeError::Register),
+        }
+    }
+
+    fn write(&mut self, dest: &Dest, value: Value) -> Result<(), RuntimeError> {
+        match dest {
+            Dest::Temp(temp) => {
+                self.frame.temps.insert(*temp, value);
+                Ok(())
+            }
+            Dest::Register(_) => Err(RuntimeError::Register),
+        }
+    }
+
+    /// True if the last comparison satisfied `condition`
+    fn holds(&self, condition: &Condition) -> bool {
+        match self.frame.flags {
+            Some(ordering) => condition.holds(ordering),
+            // Only "not equal" holds for unordered operands
+            None => matches!(condition, Condition::NotEqual),
+        }
+    }
+}
+
+/// True for the bytes C's `isspace` is true for
+fn is_space(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\n' | b'\r' | 0x0b | 0x0c)
+}
+
+/// Value of the number `input` starts with, formatted as `spec`, and how many bytes it takes
+/// up. An int out of range reads as the nearest `i64`, and then wraps around, as `%d` does with
+/// the GNU C library.
+fn scan_number(spec: &FormatSpec, input: &[u8]) -> Option<(Value, usize)> {
+    let digits = |from: usize| {
+        input[from.min(input.len())..]
+            .iter()
+            .take_while(|byte| byte.is_ascii_digit())
+            .count()
+    };
+    let mut length = usize::from(matches!(input.first(), Some(b'+' | b'-')));
+    let whole = digits(length);
+    length += whole;
+    if *spec == FormatSpec::Int {
+        if whole == 0 {
+            return None;
+        }
+        let negative = input[0] == b'-';
+        let value = input[length - whole..length]
+            .iter()
+            .fold(0i64, |value, digit| {
+                let digit = i64::from(digit - b'0');
+                match negative {
+                    true => value.saturating_mul(10).saturating_sub(digit),
+                    false => value.saturating_mul(10).saturating_add(digit),
+                }
+            });
+        return Some((Value::Int(value as i32), length));
+    }
+    let mut fraction = 0;
+    if input.get(length) == Some(&b'.') {
+        fraction = digits(length + 1);
+        length += 1 + fraction;
+    }
+    if whole + fraction == 0 {
+        return None;
+    }
+    // An exponent only counts if it has digits
+    if let Some(b'e' | b'E') = input.get(length) {
+        let sign = usize::from(matches!(input.get(length + 1), Some(b'+' | b'-')));
+        let exponent = digits(length + 1 + sign);
+        if exponent > 0 {
+            length += 1 + sign + exponent;
+        }
+    }
+    let text = std::str::from_utf8(&input[..length]).ok()?;
+    Some((Value::Double(text.parse().ok()?), length))
+}
+
+fn unknown_global(name: &str) -> RuntimeError {
+    RuntimeError::UnknownGlobal {
+        name: name.to_string(),
+    }
+}
+
+fn mismatch(instruction: &AbstractAssemblyInstruction) -> RuntimeError {
+    RuntimeError::TypeMismatch {
+        instruction: format!("{:?}", instruction),
+    }
+}
+
+fn int_binary(op: BinOp, left: i32, right: i32) -> Result<i32, Undefined> {
+    if let Some(behavior) = Undefined::of_int(op, left, right) {
+        return Err(behavior);
+    }
+    Ok(match op {
+        BinOp::Add => left.wrapping_add(right),
+        BinOp::Sub => left.wrapping_sub(right),
+        BinOp::Mul => left.wrapping_mul(right),
+        BinOp::Div => left / right,
+        BinOp::Equal => (left == right) as i32,
+        BinOp::NotEqual => (left != right) as i32,
+        BinOp::Greater => (left > right) as i32,
+        BinOp::GreaterEqual => (left >= right) as i32,
+        BinOp::Less => (left < right) as i32,
+        BinOp::LessEqual => (left <= right) as i32,
+    })
+}
+
+fn double_binary(op: BinOp, left: f64, right: f64) -> Value {
+    match op {
+        BinOp::Add => Value::Double(left + right),
+        BinOp::Sub => Value::Double(left - right),
+        BinOp::Mul => Value::Double(left * right),
+        BinOp::Div => Value::Double(left / right),
+        BinOp::Equal => Value::Int((left == right) as i32),
+        BinOp::NotEqual => Value::Int((left != right) as i32),
+        BinOp::Greater => Value::Int((left > right) as i32),
+        BinOp::GreaterEqual => Value::Int((left >= right) as i32),
+        BinOp::Less => Value::Int((left < right) as i32),
+        BinOp::LessEqual => Value::Int((left <= right) as i32),
+    }
+}
@@ -0,0 +1,778 @@
+//! Parser for the abstract assembly `emit_abstract` writes, so that a program can be read back,
+//! optimized and written out again. Every line `emit_abstract` produces is accepted, and
+//! blank lines and lines starting with `#` are skipped.
+//!
+//! The text doesn't say what type each temp holds, so it's worked out from the instructions:
+//! a temp written by a double operation is a double, one moved from another temp has its type,
+//! and any other is an int, unless it's read as a double. A function's header lists the temps
+//! its parameters are passed in after its name, as `.f %t0 %t1`. A temp may carry the name of its source variable, as `%t4.sum`,
+//! which `-g` writes.
+
+use super::context::{
+    AbstractAssemblyInstruction, Arithmetic, AsmLabel, Condition, Context, Conversion, Dest,
+    Global, Operand, ShiftKind, StringTable,
+};
+use super::verify::{verify, VerifyErrorKind};
+use super::IrModule;
+use crate::parser::{AsmConstraints, BinOp, FormatSpec, UnOp, ASM_REGISTER_CONSTRAINTS};
+use crate::sema::Type;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IrParseError {
+    /// 1-based line the error is on
+    pub line: usize,
+    pub kind: IrParseErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IrParseErrorKind {
+    UnknownInstruction {
+        text: String,
+    },
+    InvalidOperand {
+        text: String,
+    },
+    // An instruction before the first function header
+    OutsideFunction,
+    // `%eax <- x` not followed by `ret`
+    MissingRet,
+    // A string constant whose label isn't the next one in order
+    StringOutOfOrder {
+        label: String,
+    },
+    // The function's instructions break an invariant the passes rely on
+    Invalid {
+        function: String,
+        kind: VerifyErrorKind,
+    },
+}
+
+impl fmt::Display for IrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            IrParseErrorKind::UnknownInstruction { text } => {
+                write!(f, "'{}' isn't an instruction", text)
+            }
+            IrParseErrorKind::InvalidOperand { text } => {
+                write!(f, "'{}' isn't a valid operand", text)
+            }
+            IrParseErrorKind::OutsideFunction => {
+                write!(
+                    f,
+                    "instruction outside a function; start one with '.<name>'"
+                )
+            }
+            IrParseErrorKind::MissingRet => write!(f, "'%eax <- ...' must be followed by 'ret'"),
+            IrParseErrorKind::StringOutOfOrder { label } => {
+                write!(f, "string constant '{}' is out of order", label)
+            }
+            IrParseErrorKind::Invalid { function, kind } => {
+                write!(f, "in function '{}': {}", function, kind)
+            }
+        }
+    }
+}
+
+impl std::error::Error for IrParseError {}
+
+#[derive(PartialEq)]
+enum Section {
+    Data,
+    Rodata,
+    Text,
+}
+
+/// A function being parsed, with the line of each of its instructions
+struct Function {
+    name: String,
+    is_static: bool,
+    params: usize,
+    instructions: Vec<AbstractAssemblyInstruction>,
+    lines: Vec<usize>,
+    temp_names: HashMap<usize, String>,
+}
+
+/// Reads a whole program written by `emit_abstract`
+pub fn parse_ir(text: &str) -> Result<IrModule, IrParseError> {
+    let mut globals = Vec::new();
+    let mut strings = StringTable::new();
+    let mut functions: Vec<Function> = Vec::new();
+    let mut section = Section::Text;
+    // Name given by the last `.globl`, which exports the global or function that follows
+    let mut exported: Option<String> = None;
+
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .peekable();
+    while let Some((number, line)) = lines.next() {
+        let error = |kind| IrParseError { line: number, kind };
+        if line == ".data" {
+            section = Section::Data;
+        } else if line == ".rodata" {
+            section = Section::Rodata;
+        } else if let Some(name) = line.strip_prefix(".globl ") {
+            exported = Some(name.trim().to_string());
+        } else if let Some(header) = line.strip_prefix('.') {
+            section = Section::Text;
+            let mut temp_names = HashMap::new();
+            let header = strip_temp_names(header, &mut temp_names);
+            let mut words = header.split_whitespace();
+            let name = words.next().unwrap_or_default();
+            // The parameters are the first temps, in order
+            let mut params = 0;
+            for param in words {
+                match parse_operand(param) {
+                    Some(Operand::Var(Dest::Temp(temp))) if temp == params => params += 1,
+                    _ => return Err(error(invalid_operand(param))),
+                }
+            }
+            functions.push(Function {
+                name: name.to_string(),
+                is_static: exported.take().as_deref() != Some(name),
+                params,
+                instructions: Vec::new(),
+                lines: Vec::new(),
+                temp_names,
+            });
+        } else if section == Section::Data {
+            let (name, value) =
+                split_assignment(line).ok_or_else(|| error(unknown_instruction(line)))?;
+            globals.push(Global {
+                name: name.to_string(),
+                is_static: exported.take().as_deref() != Some(name),
+                value: parse_operand(value).ok_or_else(|| error(invalid_operand(value)))?,
+            });
+        } else if section == Section::Rodata {
+            let (label, literal) =
+                split_assignment(line).ok_or_else(|| error(unknown_instruction(line)))?;
+            let string = parse_string(literal).ok_or_else(|| error(invalid_operand(literal)))?;
+            let expected = format!("S{}", strings.iter().count());
+            if label != expected || strings.get(&string).is_some() {
+                let label = label.to_string();
+                return Err(error(IrParseErrorKind::StringOutOfOrder { label }));
+            }
+            strings.intern(&string);
+        } else {
+            let Some(function) = functions.last_mut() else {
+                return Err(error(IrParseErrorKind::OutsideFunction));
+            };
+            let line = strip_temp_names(line, &mut function.temp_names);
+            let instruction = match line.strip_prefix("%eax <- ") {
+                // A return is written as a move to %eax and a `ret`
+                Some(value) => {
+                    if lines.next_if(|(_, next)| *next == "ret").is_none() {
+                        return Err(error(IrParseErrorKind::MissingRet));
+                    }
+                    let value =
+                        parse_operand(value).ok_or_else(|| error(invalid_operand(value)))?;
+                    AbstractAssemblyInstruction::Return(value)
+                }
+                None => parse_instruction(&line).map_err(error)?,
+            };
+            function.instructions.push(instruction);
+            function.lines.push(number);
+        }
+    }
+
+    // Loads and stores give their temps the type of the global's starting value
+    let global_types: HashMap<&str, Type> = globals
+        .iter()
+        .map(|global| {
+            let ty = match global.value {
+                Operand::Double(_) => Type::Double,
+                Operand::Str(_) => Type::String,
+                _ => Type::Int,
+            };
+            (global.name.as_str(), ty)
+        })
+        .collect();
+    let functions = functions
+        .into_iter()
+        .map(|function| {
+            let temp_types = temp_types(function.params, &function.instructions, &global_types);
+            let context = Context::from_instructions(
+                &function.name,
+                function.is_static,
+                function.params,
+                function.instructions,
+                temp_types,
+                function.temp_names,
+            );
+            match verify(&context, context.is_ssa()) {
+                Ok(()) => Ok(context),
+                Err(invalid) => Err(IrParseError {
+                    line: function.lines.get(invalid.index).copied().unwrap_or(0),
+                    kind: IrParseErrorKind::Invalid {
+                        function: function.name,
+                        kind: invalid.kind,
+                    },
+                }),
+            }
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(IrModule {
+        globals,
+        strings,
+        functions,
+    })
+}
+
+/// Removes the variable names from temps like `%t4.sum` in `line`, recording them in `names`
+fn strip_temp_names(line: &str, names: &mut HashMap<usize, String>) -> String {
+    let mut stripped = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find("%t") {
+        let after = &rest[start + 2..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        stripped.push_str(&rest[..start + 2 + digits]);
+        rest = &after[digits..];
+        let Some(name) = rest.strip_prefix('.') else {
+            continue;
+        };
+        let length = name
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(name.len());
+        let starts_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+        if let (true, Ok(temp)) = (starts_name, after[..digits].parse()) {
+            names.insert(temp, name[..length].to_string());
+            rest = &name[length..];
+        }
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+fn unknown_instruction(text: &str) -> IrParseErrorKind {
+    IrParseErrorKind::UnknownInstruction {
+        text: text.to_string(),
+    }
+}
+
+fn invalid_operand(text: &str) -> IrParseErrorKind {
+    IrParseErrorKind::InvalidOperand {
+        text: text.to_string(),
+    }
+}
+
+/// `name` and `value` of `name <- value`
+fn split_assignment(line: &str) -> Option<(&str, &str)> {
+    let (name, value) = line.split_once(" <- ")?;
+    Some((name.trim(), value.trim()))
+}
+
+fn parse_instruction(line: &str) -> Result<AbstractAssemblyInstruction, IrParseErrorKind> {
+    let operand = |text: &str| parse_operand(text).ok_or_else(|| invalid_operand(text));
+    let dest = |text: &str| match parse_operand(text) {
+        Some(Operand::Var(dest)) => Ok(dest),
+        _ => Err(invalid_operand(text)),
+    };
+    let label = |text: &str| parse_label(text).ok_or_else(|| invalid_operand(text));
+    let condition = |text: &str| parse_condition(text).ok_or_else(|| invalid_operand(text));
+
+    if let Some(name) = line.strip_suffix(':') {
+        return Ok(AbstractAssemblyInstruction::Lbl(label(name)?));
+    }
+    // Checked before assignments, since the template may contain anything
+    if let Some(rest) = line.strip_prefix("asm ") {
+        return parse_asm(None, rest);
+    }
+    if let Some((dest_text, value)) = split_assignment(line) {
+        return parse_assignment(dest(dest_text)?, value);
+    }
+    if let Some(rest) = line.strip_prefix("phi ") {
+        let (dest_text, sources) = rest
+            .split_once(' ')
+            .ok_or_else(|| unknown_instruction(line))?;
+        let mut srcs = Vec::new();
+        for source in sources.split("),") {
+            let source = source.trim().trim_start_matches('(').trim_end_matches(')');
+            let (value, block) = source
+                .split_once(", ")
+                .ok_or_else(|| invalid_operand(source))?;
+            srcs.push((operand(value)?, label(block)?));
+        }
+        return Ok(AbstractAssemblyInstruction::Phi {
+            dest: dest(dest_text)?,
+            srcs,
+        });
+    }
+
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["call", function, args @ ..] => Ok(AbstractAssemblyInstruction::Call {
+            dest: None,
+            function: function.to_string(),
+            args: args
+                .iter()
+                .map(|arg| operand(arg))
+                .collect::<Result<_, _>>()?,
+        }),
+        ["store", global, src] => Ok(AbstractAssemblyInstruction::Store {
+            global: global.to_string(),
+            src: operand(src)?,
+        }),
+        ["ret"] => Ok(AbstractAssemblyInstruction::ReturnVoid),
+        ["abort"] => Ok(AbstractAssemblyInstruction::Abort),
+        ["loc", file, line] => Ok(AbstractAssemblyInstruction::Loc {
+            file: file.parse().map_err(|_| invalid_operand(file))?,
+            line: line.parse().map_err(|_| invalid_operand(line))?,
+        }),
+        ["jmp", target] => Ok(AbstractAssemblyInstruction::Jmp(label(target)?)),
+        ["jmp", test, tgt_true, tgt_false] => Ok(AbstractAssemblyInstruction::JmpCondition {
+            condition: condition(test)?,
+            tgt_true: label(tgt_true)?,
+            tgt_false: label(tgt_false)?,
+        }),
+        [compare @ ("cmp" | "cmpd"), left, test, right] => {
+            Ok(AbstractAssemblyInstruction::Compare {
+                arithmetic: match *compare {
+                    "cmp" => Arithmetic::Int,
+                    _ => Arithmetic::Double,
+                },
+                left: operand(left)?,
+                right: operand(right)?,
+                condition: condition(test)?,
+            })
+        }
+        ["set", dest_text, test] => Ok(AbstractAssemblyInstruction::SetIf {
+            dest: dest(dest_text)?,
+            condition: condition(test)?,
+        }),
+        ["print", spec, src] => Ok(AbstractAssemblyInstruction::Print {
+            spec: match *spec {
+                "%d" => FormatSpec::Int,
+                "%f" => FormatSpec::Double,
+                "%c" => FormatSpec::Char,
+                "%s" => FormatSpec::String,
+                _ => return Err(invalid_operand(spec)),
+            },
+            src: operand(src)?,
+        }),
+        _ => Err(unknown_instruction(line)),
+    }
+}
+
+/// The instruction writing `dest` in `dest <- value`
+fn parse_assignment(
+    dest: Dest,
+    value: &str,
+) -> Result<AbstractAssemblyInstruction, IrParseErrorKind> {
+    let operand = |text: &str| parse_operand(text).ok_or_else(|| invalid_operand(text));
+    if let Some(rest) = value.strip_prefix("asm ") {
+        return parse_asm(Some(dest), rest);
+    }
+    let words: Vec<&str> = value.split_whitespace().collect();
+    match words.as_slice() {
+        ["call", function, args @ ..] => Ok(AbstractAssemblyInstruction::Call {
+            dest: Some(dest),
+            function: function.to_string(),
+            args: args
+                .iter()
+                .map(|arg| operand(arg))
+                .collect::<Result<_, _>>()?,
+        }),
+        ["load", global] => Ok(AbstractAssemblyInstruction::Load {
+            dest,
+            global: global.to_string(),
+        }),
+        ["scan", spec @ ("%d" | "%f")] => Ok(AbstractAssemblyInstruction::Scan {
+            dest,
+            spec: match *spec {
+                "%d" => FormatSpec::Int,
+                _ => FormatSpec::Double,
+            },
+        }),
+        [src] => match parse_operand(src) {
+            Some(src) => Ok(AbstractAssemblyInstruction::Mov { dest, src }),
+            // `-%t1`, an int operator written against its operand
+            None => {
+                let mut chars = src.chars();
+                let op = chars.next().and_then(parse_unary);
+                Ok(AbstractAssemblyInstruction::UnOp {
+                    op: op.ok_or_else(|| invalid_operand(value))?,
+                    arithmetic: Arithmetic::Int,
+                    dest,
+                    src: operand(chars.as_str())?,
+                })
+            }
+        },
+        [conversion @ ("i2d" | "d2i" | "i2c"), src] => Ok(AbstractAssemblyInstruction::Convert {
+            conversion: match *conversion {
+                "i2d" => Conversion::I2D,
+                "d2i" => Conversion::D2I,
+                _ => Conversion::I2C,
+            },
+            dest,
+            src: operand(src)?,
+        }),
+        // `-d %t1`, a double operator
+        [op, src] => {
+            let op = match op
+                .strip_suffix('d')
+                .map(|op| op.chars().collect::<Vec<_>>())
+            {
+                Some(chars) if chars.len() == 1 => parse_unary(chars[0]),
+                _ => None,
+            }
+            .ok_or_else(|| invalid_operand(value))?;
+            Ok(AbstractAssemblyInstruction::UnOp {
+                op,
+                arithmetic: Arithmetic::Double,
+                dest,
+                src: operand(src)?,
+            })
+        }
+        [src, kind @ ("<<" | ">>" | ">>>"), amount] => Ok(AbstractAssemblyInstruction::Shift {
+            kind: match *kind {
+                "<<" => ShiftKind::Left,
+                ">>" => ShiftKind::ArithmeticRight,
+                _ => ShiftKind::LogicalRight,
+            },
+            dest,
+            src: operand(src)?,
+            amount: amount
+                .strip_prefix('$')
+                .and_then(|amount| amount.parse().ok())
+                .ok_or_else(|| invalid_operand(amount))?,
+        }),
+        [src1, op, src2] => {
+            let (op, arithmetic) = match parse_binary(op) {
+                Some(op) => (op, Arithmetic::Int),
+                None => (
+                    op.strip_suffix('d')
+                        .and_then(parse_binary)
+                        .ok_or_else(|| invalid_operand(op))?,
+                    Arithmetic::Double,
+                ),
+            };
+            Ok(AbstractAssemblyInstruction::BinOp {
+                op,
+                arithmetic,
+                dest,
+                src1: operand(src1)?,
+                src2: operand(src2)?,
+            })
+        }
+        _ => Err(invalid_operand(value)),
+    }
+}
+
+fn parse_operand(text: &str) -> Option<Operand> {
+    if let Some(temp) = text.strip_prefix("%t") {
+        return Some(Operand::Var(Dest::Temp(temp.parse().ok()?)));
+    }
+    if let Some(register) = text
+        .strip_prefix('(')
+        .and_then(|text| text.strip_suffix(')'))
+    {
+        return Some(Operand::Var(Dest::Register(register.parse().ok()?)));
+    }
+    let constant = text.strip_prefix('$')?;
+    if let Some(index) = constant.strip_prefix('S') {
+        return Some(Operand::Str(index.parse().ok()?));
+    }
+    // Doubles are written with a decimal point or an exponent, or as `inf` or `NaN`
+    match constant.parse() {
+        Ok(value) => Some(Operand::Const(value)),
+        Err(_) => constant.parse().ok().map(Operand::Double),
+    }
+}
+
+fn parse_label(text: &str) -> Option<AsmLabel> {
+    Some(AsmLabel(text.strip_prefix('L')?.parse().ok()?))
+}
+
+fn parse_condition(text: &str) -> Option<Condition> {
+    match text {
+        "is_g" => Some(Condition::Greater),
+        "is_l" => Some(Condition::Less),
+        "is_eq" => Some(Condition::Equal),
+        "is_neq" => Some(Condition::NotEqual),
+        "is_geq" => Some(Condition::GreaterOrEqual),
+        "is_leq" => Some(Condition::LessOrEqual),
+        _ => None,
+    }
+}
+
+fn parse_unary(op: char) -> Option<UnOp> {
+    match op {
+        '!' => Some(UnOp::Not),
+        '-' => Some(UnOp::Neg),
+        '~' => Some(UnOp::BitNot),
+        _ => None,
+    }
+}
+
+fn parse_binary(text: &str) -> Option<BinOp> {
+    match text {
+        "+" => Some(BinOp::Add),
+        "-" => Some(BinOp::Sub),
+        "*" => Some(BinOp::Mul),
+        "/" => Some(BinOp::Div),
+        "==" => Some(BinOp::Equal),
+        "!=" => Some(BinOp::NotEqual),
+        ">" => Some(BinOp::Greater),
+        ">=" => Some(BinOp::GreaterEqual),
+        "<" => Some(BinOp::Less),
+        "<=" => Some(BinOp::LessEqual),
+        _ => None,
+    }
+}
+
+/// The inline assembly in `"template" inputs...`, after `asm`, with its constraints in a
+/// second string after the template if it has any, like `"=a,r,~{rcx}"`
+fn parse_asm(
+    output: Option<Dest>,
+    text: &str,
+) -> Result<AbstractAssemblyInstruction, IrParseErrorKind> {
+    let (template, rest) = split_string(text)?;
+    let mut constraints = AsmConstraints::default();
+    let rest = match rest.trim_start().starts_with('"') {
+        true => {
+            let (text, rest) = split_string(rest)?;
+            constraints = parse_asm_constraints(output.is_some(), &text)
+                .ok_or_else(|| invalid_operand(&text))?;
+            rest
+        }
+        false => rest,
+    };
+    let inputs: Vec<Operand> = rest
+        .split_whitespace()
+        .map(|input| parse_operand(input).ok_or_else(|| invalid_operand(input)))
+        .collect::<Result<_, _>>()?;
+    let operands = output.iter().count() + inputs.len();
+    if constraints.operands.is_empty() {
+        constraints.operands = vec!['r'; operands];
+    } else if constraints.operands.len() != operands {
+        return Err(invalid_operand(text));
+    }
+    Ok(AbstractAssemblyInstruction::Asm {
+        template,
+        output,
+        inputs,
+        constraints,
+    })
+}
+
+/// The quoted string `text` starts with, after any spaces, and the text after it
+fn split_string(text: &str) -> Result<(String, &str), IrParseErrorKind> {
+    let text = text.trim_start();
+    // The string ends at the first quote that isn't escaped
+    let mut escaped = false;
+    let end = text
+        .char_indices()
+        .skip(1)
+        .find(|&(_, c)| {
+            let closes = c == '"' && !escaped;
+            escaped = c == '\\' && !escaped;
+            closes
+        })
+        .map(|(index, _)| index + 1)
+        .ok_or_else(|| invalid_operand(text))?;
+    let string = parse_string(&text[..end]).ok_or_else(|| invalid_operand(text))?;
+    Ok((string, &text[end..]))
+}
+
+/// Constraints written like `=a,r,~{rcx}`, the way `serialize_asm_constraints` writes them
+fn parse_asm_constraints(has_output: bool, text: &str) -> Option<AsmConstraints> {
+    let mut constraints = AsmConstraints::default();
+    for (index, item) in text.split(',').enumerate() {
+        if let Some(clobber) = item
+            .strip_prefix("~{")
+            .and_then(|item| item.strip_suffix('}'))
+        {
+            constraints.clobbers.push(clobber.to_string());
+            continue;
+        }
+        let letter = match index == 0 && has_output {
+            true => item.strip_prefix('=')?,
+            false => item,
+        };
+        let mut chars = letter.chars();
+        match (chars.next(), chars.next()) {
+            (Some(letter), None) if letter == 'r' || ASM_REGISTER_CONSTRAINTS.contains(letter) => {
+                constraints.operands.push(letter)
+            }
+            _ => return None,
+        }
+    }
+    Some(constraints)
+}
+
+/// Reads a string written with Rust's debug formatting, the way `emit_abstract` quotes them
+fn parse_string(text: &str) -> Option<String> {
+    let inner = text.strip_prefix('"')?.strip_suffix('"')?;
+    let mut string = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        string.push(match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            'u' => {
+                let code: String = chars.by_ref().skip(1).take_while(|&c| c != '}').collect();
+                char::from_u32(u32::from_str_radix(&code, 16).ok()?)?
+            }
+            // `\\`, `\"` and `\'`
+            c => c,
+        });
+    }
+    Some(string)
+}
+
+/// Type of each temp `instructions` use, and of the first `params`, which hold the parameters
+/// even if they're never used. Temps loaded from or stored to a global have its type in
+/// `globals`.
+fn temp_types(
+    params: usize,
+    instructions: &[AbstractAssemblyInstruction],
+    globals: &HashMap<&str, Type>,
+) -> HashMap<usize, Type> {
+    let temp = |operand: &Operand| match operand {
+        Operand::Var(Dest::Temp(temp)) => Some(*temp),
+        _ => None,
+    };
+    let constant_type = |operand: &Operand| match operand {
+        Operand::Double(_) => Some(Type::Double),
+        Operand::Str(_) => Some(Type::String),
+        Operand::Const(_) => Some(Type::Int),
+        Operand::Var(_) => None,
+    };
+    let arithmetic_type = |arithmetic: &Arithmetic| match arithmetic {
+        Arithmetic::Int => Type::Int,
+        Arithmetic::Double => Type::Double,
+    };
+
+    let mut types: HashMap<usize, Type> = (0..params).map(|temp| (temp, Type::Int)).collect();
+    // Moves and phis copying one temp into another, which give the copy the original's type
+    let mut copies: Vec<(usize, usize)> = Vec::new();
+    for instruction in instructions {
+        for operand in instruction.operands() {
+            if let Some(temp) = temp(operand) {
+                types.entry(temp).or_insert(Type::Int);
+            }
+        }
+        // Temps read by double operations are doubles, even if nothing here writes them
+        let read_as = match instruction {
+            AbstractAssemblyInstruction::BinOp {
+                arithmetic: Arithmetic::Double,
+                src1,
+                src2,
+                ..
+            }
+            | AbstractAssemblyInstruction::Compare {
+                arithmetic: Arithmetic::Double,
+                left: src1,
+                right: src2,
+                ..
+            } => vec![(src1, Type::Double), (src2, Type::Double)],
+            AbstractAssemblyInstruction::UnOp {
+                arithmetic: Arithmetic::Double,
+                src,
+                ..
+            }
+            | AbstractAssemblyInstruction::Convert {
+                conversion: Conversion::D2I,
+                src,
+                ..
+            } => vec![(src, Type::Double)],
+            AbstractAssemblyInstruction::Print { spec, src } => vec![(
+                src,
+                match spec {
+                    FormatSpec::Int => Type::Int,
+                    FormatSpec::Double => Type::Double,
+                    FormatSpec::Char => Type::Char,
+                    FormatSpec::String => Type::String,
+                },
+            )],
+            AbstractAssemblyInstruction::Store { global, src } => globals
+                .get(global.as_str())
+                .map(|&ty| (src, ty))
+                .into_iter()
+                .collect(),
+            _ => Vec::new(),
+        };
+        for (operand, ty) in read_as {
+            if let Some(temp) = temp(operand) {
+                types.insert(temp, ty);
+            }
+        }
+
+        let Some(Dest::Temp(dest)) = instruction.dest() else {
+            continue;
+        };
+        let written_as = match instruction {
+            AbstractAssemblyInstruction::BinOp { op, arithmetic, .. } => Some(match op {
+                BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div => arithmetic_type(arithmetic),
+                _ => Type::Int,
+            }),
+            AbstractAssemblyInstruction::UnOp { op, arithmetic, .. } => Some(match op {
+                UnOp::Neg => arithmetic_type(arithmetic),
+                UnOp::Not | UnOp::BitNot => Type::Int,
+            }),
+            AbstractAssemblyInstruction::Convert { conversion, .. } => Some(match conversion {
+                Conversion::I2D => Type::Double,
+                Conversion::D2I => Type::Int,
+                Conversion::I2C => Type::Char,
+            }),
+            AbstractAssemblyInstruction::Mov { src, .. } => {
+                if let Some(src) = temp(src) {
+                    copies.push((*dest, src));
+                }
+                constant_type(src)
+            }
+            AbstractAssemblyInstruction::Phi { srcs, .. } => {
+                copies.extend(
+                    srcs.iter()
+                        .filter_map(|(src, _)| temp(src))
+                        .map(|src| (*dest, src)),
+                );
+                srcs.iter().find_map(|(src, _)| constant_type(src))
+            }
+            AbstractAssemblyInstruction::Load { global, .. } => {
+                globals.get(global.as_str()).copied()
+            }
+            AbstractAssemblyInstruction::Scan {
+                spec: FormatSpec::Double,
+                ..
+            } => Some(Type::Double),
+            // What a function or inline assembly returns is only known from how it's used
+            AbstractAssemblyInstruction::Call { .. } | AbstractAssemblyInstruction::Asm { .. } => {
+                None
+            }
+            _ => Some(Type::Int),
+        };
+        match written_as {
+            Some(ty) => {
+                types.insert(*dest, ty);
+            }
+            None => {
+                types.entry(*dest).or_insert(Type::Int);
+            }
+        }
+    }
+
+    // A copy of a double is a double, and so on along chains of copies
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &(dest, src) in &copies {
+            if types[&src] != Type::Int && types[&dest] == Type::Int {
+                types.insert(dest, types[&src]);
+                changed = true;
+            }
+        }
+    }
+    types
+}
@@ -0,0 +1,1316 @@
+//! Instruction selection for x86-64, by tiling expression trees.
+//!
+//! Within each block, a temp written once and read once, later in the same block, is folded
+//! into the instruction reading it, so that the block becomes a list of trees. Each tree is
+//! then covered by maximal munch: the largest x86 pattern matching its root is picked first,
+//! and its subtrees are covered the same way. This way `%t2 <- %t1 << $2` followed by
+//! `%t3 <- %t0 + %t2` becomes a single `lea (%t0,%t1,4)`, constants become immediates, and
+//! double constants are read straight from memory.
+//!
+//! A tree is only folded into a later instruction if nothing in between writes a temp it
+//! reads. Int divisions are never folded, since they can trap and so mustn't move past
+//! output.
+
+use super::cfg::ControlFlowGraph;
+use super::context::{
+    AbstractAssemblyInstruction, Arithmetic, AsmLabel, Condition, Context, Conversion, Dest,
+    Operand, ShiftKind,
+};
+use super::dominators::DominatorTree;
+use super::frame::Frame;
+use super::loops::LoopInfo;
+use super::runtime;
+use super::x86::{
+    double_symbol, string_symbol, Address, AluOp, ArgumentLocation, CallingConvention, Size, SseOp,
+    UnaryOp, Variable, X86Condition, X86Function, X86Instruction, X86Operand, X86Register,
+};
+use crate::parser::{BinOp, FormatSpec, UnOp};
+use crate::sema::Type;
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+/// A value computed by a run of abstract instructions
+#[derive(Debug)]
+enum Tree {
+    Leaf(Operand),
+    Binary {
+        op: BinOp,
+        arithmetic: Arithmetic,
+        left: Box<Tree>,
+        right: Box<Tree>,
+    },
+    Unary {
+        op: UnOp,
+        arithmetic: Arithmetic,
+        src: Box<Tree>,
+    },
+    Convert {
+        conversion: Conversion,
+        src: Box<Tree>,
+    },
+    Shift {
+        kind: ShiftKind,
+        src: Box<Tree>,
+        amount: u32,
+    },
+}
+
+impl Tree {
+    /// Temps the tree reads
+    fn temps(&self, temps: &mut Vec<usize>) {
+        match self {
+            Tree::Leaf(Operand::Var(Dest::Temp(temp))) => temps.push(*temp),
+            Tree::Leaf(_) => {}
+            Tree::Binary { left, right, .. } => {
+                left.temps(temps);
+                right.temps(temps);
+            }
+            Tree::Unary { src, .. } | Tree::Convert { src, .. } | Tree::Shift { src, .. } => {
+                src.temps(temps)
+            }
+        }
+    }
+
+    fn constant(&self) -> Option<i32> {
+        match self {
+            Tree::Leaf(Operand::Const(value)) => Some(*value as i32),
+            _ => None,
+        }
+    }
+}
+
+/// One part of a sum that may fit in an address
+enum Term<'t> {
+    Displacement(i32),
+    Scaled(&'t Tree, u8),
+    Plain(&'t Tree),
+}
+
+/// Fails if an `asm` statement of `context` clobbers a register it can't, under `convention`:
+/// one that isn't a register, the stack or frame pointer, an xmm register the convention has
+/// calls keep, since the prologue only saves general-purpose ones, or the register an operand
+/// is constrained to
+pub fn check_asm(context: &Context, convention: CallingConvention) -> io::Result<()> {
+    let invalid = |message: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: inline assembly {}", context.name, message),
+        ))
+    };
+    for instruction in &context.instructions {
+        let AbstractAssemblyInstruction::Asm { constraints, .. } = instruction else {
+            continue;
+        };
+        let operands: Vec<X86Register> = constraints
+            .operands
+            .iter()
+            .filter_map(|&letter| X86Register::from_constraint(letter))
+            .collect();
+        for clobber in &constraints.clobbers {
+            if matches!(clobber.as_str(), "cc" | "memory") {
+                continue;
+            }
+            let Some(register) = X86Register::from_name(clobber) else {
+                return invalid(format!("clobbers `{}`, which isn't a register", clobber));
+            };
+            let name = register.name(Size::Quad);
+            if matches!(register, X86Register::Rsp | X86Register::Rbp)
+                || (register.is_xmm() && !convention.caller_saved().contains(&register))
+            {
+                return invalid(format!("can't clobber {}", name));
+            }
+            if operands.contains(&register) {
+                return invalid(format!("clobbers {}, which an operand is in", name));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Selects the x86 instructions for `context`, which may be in SSA form, receiving the
+/// parameters and passing arguments by `convention`
+pub fn select_instructions(
+    context: &Context,
+    convention: CallingConvention,
+    through_got: &HashSet<String>,
+) -> X86Function {
+    let mut selector = Selector {
+        through_got: through_got.clone(),
+        temp_types: context.temp_types().clone(),
+        next_temp: context.temp_count(),
+        instructions: Vec::new(),
+        doubles: Vec::new(),
+        pending: HashMap::new(),
+        comparison: None,
+        next_label: 0,
+    };
+    let mut next_label = context.label_count();
+    let mut cfg = ControlFlowGraph::new(context.instructions.clone(), || {
+        next_label += 1;
+        AsmLabel(next_label - 1)
+    });
+    cfg.remove_phis(|temp| {
+        let ty = selector.temp_types[&temp];
+        selector.new_temp(ty)
+    });
+    selector.next_label = next_label;
+    let dominators = DominatorTree::new(&cfg);
+    let loops = LoopInfo::new(&cfg, &dominators);
+    let loop_depths = (0..cfg.len())
+        .filter(|&block| loops.loop_depth(block) > 0)
+        .map(|block| (cfg.blocks[block].label.0, loops.loop_depth(block)))
+        .collect();
+
+    let instructions: Vec<AbstractAssemblyInstruction> = cfg
+        .blocks
+        .iter()
+        .flat_map(|block| {
+            std::iter::once(AbstractAssemblyInstruction::Lbl(block.label))
+                .chain(block.instructions.iter().cloned())
+        })
+        .collect();
+    // The function's first line covers receiving the parameters too
+    if let Some(&AbstractAssemblyInstruction::Loc { file, line }) = context.instructions.first() {
+        selector.emit(X86Instruction::Loc { file, line });
+    }
+    selector.receive_params(context.params, convention);
+    let folded = foldable_temps(&instructions);
+    for (index, instruction) in instructions.iter().enumerate() {
+        let next = instructions.get(index + 1);
+        selector.select(instruction, next, &folded);
+    }
+
+    X86Function {
+        name: context.name.clone(),
+        symbol: context.name.clone(),
+        is_static: context.is_static,
+        instructions: selector.instructions,
+        temp_types: selector.temp_types,
+        temp_counter: selector.next_temp,
+        doubles: selector.doubles,
+        loop_depths,
+        frame: Frame::default(),
+        convention,
+        return_type: context.return_type(),
+        variables: variables(context),
+    }
+}
+
+/// The variables of `context` held in a single temp. One split across several, as SSA form or
+/// a shadowing declaration leaves it, has no single home, and is left out.
+fn variables(context: &Context) -> Vec<Variable> {
+    let mut temps: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (temp, name) in context.variable_temps() {
+        temps.entry(name).or_default().push(temp);
+    }
+    let mut single: Vec<(usize, &str)> = temps
+        .into_iter()
+        .filter_map(|(name, temps)| match temps[..] {
+            [temp] => Some((temp, name)),
+            _ => None,
+        })
+        .collect();
+    // Parameters first, in order, then the rest as they're declared
+    single.sort();
+    single
+        .into_iter()
+        .map(|(temp, name)| Variable {
+            name: name.to_string(),
+            ty: context.temp_types()[&temp],
+            parameter: temp < context.params,
+            home: X86Operand::Reg(Dest::Temp(temp)),
+        })
+        .collect()
+}
+
+/// Temps that can be folded into their only read: those written once, by an instruction
+/// computing a value without side effects, and read once, later in the same block
+fn foldable_temps(instructions: &[AbstractAssemblyInstruction]) -> Vec<bool> {
+    // Block and position of each temp's writes and reads
+    let mut writes: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    let mut reads: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    let mut block = 0;
+    for (index, instruction) in instructions.iter().enumerate() {
+        if let AbstractAssemblyInstruction::Lbl(_) = instruction {
+            block += 1;
+        }
+        for operand in instruction.operands() {
+            if let Operand::Var(Dest::Temp(temp)) = operand {
+                reads.entry(*temp).or_default().push((block, index));
+            }
+        }
+        if let Some(Dest::Temp(temp)) = instruction.dest() {
+            writes.entry(*temp).or_default().push((block, index));
+        }
+    }
+
+    let mut folded = Vec::new();
+    for (temp, writes) in &writes {
+        let ([write], Some([read])) = (writes.as_slice(), reads.get(temp).map(Vec::as_slice))
+        else {
+            continue;
+        };
+        let pure = match &instructions[write.1] {
+            AbstractAssemblyInstruction::BinOp {
+                op: BinOp::Div,
+                arithmetic: Arithmetic::Int,
+                ..
+            } => false,
+            AbstractAssemblyInstruction::BinOp { .. }
+            | AbstractAssemblyInstruction::UnOp { .. }
+            | AbstractAssemblyInstruction::Mov { .. }
+            | AbstractAssemblyInstruction::Convert { .. }
+            | AbstractAssemblyInstruction::Shift { .. } => true,
+            _ => false,
+        };
+        if pure && write.0 == read.0 && write.1 < read.1 {
+            if folded.len() <= *temp {
+                folded.resize(*temp + 1, false);
+            }
+            folded[*temp] = true;
+        }
+    }
+    folded
+}
+
+/// The last comparison, whose flags the conditional jumps and sets after it test
+struct Comparison {
+    arithmetic: Arithmetic,
+    left: X86Operand,
+    right: X86Operand,
+}
+
+struct Selector {
+    temp_types: HashMap<usize, Type>,
+    next_temp: usize,
+    instructions: Vec<X86Instruction>,
+    doubles: Vec<f64>,
+    /// Trees of temps folded into their read, which hasn't been reached yet
+    pending: HashMap<usize, Tree>,
+    comparison: Option<Comparison>,
+    /// Globals reached through the global offset table
+    through_got: HashSet<String>,
+    /// Number of labels so far, in the abstract assembly or added since; later ones are
+    /// numbered from it
+    next_label: usize,
+}
+
+impl Selector {
+    fn new_temp(&mut self, ty: Type) -> Dest {
+        let temp = self.next_temp;
+        self.next_temp += 1;
+        self.temp_types.insert(temp, ty);
+        Dest::Temp(temp)
+    }
+
+    fn emit(&mut self, instruction: X86Instruction) {
+        self.instructions.push(instruction);
+    }
+
+    fn new_label(&mut self) -> AsmLabel {
+        self.next_label += 1;
+        AsmLabel(self.next_label - 1)
+    }
+
+    fn select(
+        &mut self,
+        instruction: &AbstractAssemblyInstruction,
+        next: Option<&AbstractAssemblyInstruction>,
+        folded: &[bool],
+    ) {
+        use AbstractAssemblyInstruction as A;
+
+        // Trees of the operands, with the temps folded into them
+        let mut trees: Vec<Tree> = instruction
+            .operands()
+            .into_iter()
+            .map(|operand| self.tree(operand))
+            .collect();
+
+        // Whatever reads the temp about to be written has to be computed first
+        if let Some(Dest::Temp(written)) = instruction.dest() {
+            let stale: Vec<usize> = self
+                .pending
+                .iter()
+                .filter(|(_, tree)| {
+                    let mut temps = Vec::new();
+                    tree.temps(&mut temps);
+                    temps.contains(written)
+                })
+                .map(|(&temp, _)| temp)
+                .collect();
+            for temp in stale {
+                let tree = self.pending.remove(&temp).unwrap();
+                self.compute(&tree, Dest::Temp(temp));
+            }
+        }
+
+        match instruction {
+            A::BinOp {
+                op,
+                arithmetic,
+                dest,
+                ..
+            } => {
+                let right = Box::new(trees.pop().unwrap());
+                let left = Box::new(trees.pop().unwrap());
+                let tree = Tree::Binary {
+                    op: *op,
+                    arithmetic: *arithmetic,
+                    left,
+                    right,
+                };
+                self.define(dest, tree, folded);
+            }
+            A::UnOp {
+                op,
+                arithmetic,
+                dest,
+                ..
+            } => {
+                let src = Box::new(trees.pop().unwrap());
+                let tree = Tree::Unary {
+                    op: *op,
+                    arithmetic: *arithmetic,
+                    src,
+                };
+                self.define(dest, tree, folded);
+            }
+            A::Mov { dest, .. } => self.define(dest, trees.pop().unwrap(), folded),
+            A::Convert {
+                conversion, dest, ..
+            } => {
+                let src = Box::new(trees.pop().unwrap());
+                let tree = Tree::Convert {
+                    conversion: *conversion,
+                    src,
+                };
+                self.define(dest, tree, folded);
+            }
+            A::Shift {
+                kind, dest, amount, ..
+            } => {
+                let src = Box::new(trees.pop().unwrap());
+                let tree = Tree::Shift {
+                    kind: *kind,
+                    src,
+                    amount: *amount,
+                };
+                self.define(dest, tree, folded);
+            }
+            A::Compare { arithmetic, .. } => {
+                let right = trees.pop().unwrap();
+                let left = trees.pop().unwrap();
+                let (mut left, mut right) = match arithmetic {
+                    Arithmetic::Int => (self.int_operand(&left), self.int_operand(&right)),
+                    Arithmetic::Double => (
+                        X86Operand::Reg(self.double_register(&left)),
+                        self.double_operand(&right),
+                    ),
+                };
+                // The flags are set again right before each instruction testing them, so the
+                // operands have to survive whatever comes in between
+                let tested_next = matches!(next, Some(A::JmpCondition { .. } | A::SetIf { .. }));
+                if !tested_next {
+                    left = self.copy(left, *arithmetic);
+                    right = self.copy(right, *arithmetic);
+                }
+                self.comparison = Some(Comparison {
+                    arithmetic: *arithmetic,
+                    left,
+                    right,
+                });
+            }
+            A::SetIf { dest, condition } => {
+                let comparison = self.comparison.take().expect("a set follows a comparison");
+                self.set(&comparison, condition, dest.clone());
+                self.comparison = Some(comparison);
+            }
+            A::JmpCondition {
+                condition,
+                tgt_true,
+                tgt_false,
+            } => {
+                let comparison = self.comparison.take().expect("a jump follows a comparison");
+                self.jump(&comparison, condition, *tgt_true, *tgt_false);
+                self.comparison = Some(comparison);
+            }
+            A::Jmp(target) => self.emit(X86Instruction::Jmp(*target)),
+            A::Lbl(label) => self.emit(X86Instruction::Label(*label)),
+            A::Loc { file, line } => self.emit(X86Instruction::Loc {
+                file: *file,
+                line: *line,
+            }),
+            A::Print { spec, .. } => {
+                let src = trees.pop().unwrap();
+                let arg = match spec {
+                    FormatSpec::Int => (Type::Int, self.int_operand(&src)),
+                    FormatSpec::Char => (Type::Char, self.int_operand(&src)),
+                    FormatSpec::Double => {
+                        let value = X86Operand::Reg(self.double_register(&src));
+                        (Type::Double, value)
+                    }
+                    FormatSpec::String => {
+                        let value = X86Operand::Reg(self.register(&src, Type::String));
+                        (Type::String, value)
+                    }
+                };
+                self.emit(X86Instruction::Call {
+                    function: runtime::print_function(spec).to_string(),
+                    args: vec![arg],
+                });
+            }
+            A::Call { dest, function, .. } => {
+                let mut args = Vec::new();
+                for arg in &trees {
+                    let ty = self.tree_type(arg);
+                    let operand = match ty {
+                        Type::Double => X86Operand::Reg(self.double_register(arg)),
+                        Type::String => X86Operand::Reg(self.register(arg, ty)),
+                        _ => self.int_operand(arg),
+                    };
+                    args.push((ty, operand));
+                }
+                self.emit(X86Instruction::Call {
+                    function: function.clone(),
+                    args,
+                });
+                if let Some(Dest::Temp(temp)) = dest {
+                    let value = match self.temp_types[temp] {
+                        Type::Double => X86Register::Xmm0,
+                        _ => X86Register::Rax,
+                    };
+                    self.receive(*temp, X86Operand::Reg(value.dest()));
+                }
+            }
+            A::Scan { dest, spec } => {
+                self.emit(X86Instruction::Call {
+                    function: runtime::scan_function(spec).to_string(),
+                    args: Vec::new(),
+                });
+                let Dest::Temp(temp) = dest else {
+                    unreachable!("registers are only assigned after instruction selection");
+                };
+                let value = match spec {
+                    FormatSpec::Double => X86Register::Xmm0,
+                    _ => X86Register::Rax,
+                };
+                self.receive(*temp, X86Operand::Reg(value.dest()));
+            }
+            A::Load { dest, global } => {
+                let Dest::Temp(temp) = dest else {
+                    unreachable!("registers are only assigned after instruction selection");
+                };
+                let address = X86Operand::Mem(self.global_address(global));
+                self.receive(*temp, address);
+            }
+            A::Store { global, .. } => {
+                let src = trees.pop().unwrap();
+                let dest = X86Operand::Mem(self.global_address(global));
+                let instruction = match self.tree_type(&src) {
+                    Type::Double => X86Instruction::Movsd {
+                        dest,
+                        src: X86Operand::Reg(self.double_register(&src)),
+                    },
+                    ty => X86Instruction::Mov {
+                        size: Size::of(ty),
+                        dest,
+                        src: match ty {
+                            Type::String => X86Operand::Reg(self.register(&src, ty)),
+                            _ => self.int_operand(&src),
+                        },
+                    },
+                };
+                self.emit(instruction);
+            }
+            A::Asm {
+                template,
+                output,
+                constraints,
+                ..
+            } => {
+                let mut inputs: Vec<(Type, Dest)> = trees
+                    .iter()
+                    .map(|input| {
+                        let ty = self.tree_type(input);
+                        (ty, self.register(input, ty))
+                    })
+                    .collect();
+                // Inputs constrained to a register are moved into it once they're all computed,
+                // so that computing one doesn't overwrite another
+                let first_input = output.iter().count();
+                for (index, (ty, input)) in inputs.iter_mut().enumerate() {
+                    let constraint = constraints.register(first_input + index);
+                    if let Some(register) = constraint.and_then(X86Register::from_constraint) {
+                        self.emit(X86Instruction::Mov {
+                            size: Size::of(*ty),
+                            dest: X86Operand::Reg(register.dest()),
+                            src: X86Operand::Reg(input.clone()),
+                        });
+                        *input = register.dest();
+                    }
+                }
+                let output = output.as_ref().map(|output| {
+                    let ty = match output {
+                        Dest::Temp(temp) => self.temp_types[temp],
+                        Dest::Register(_) => Type::Int,
+                    };
+                    (ty, output.clone())
+                });
+                // An output constrained to a register is moved out of it after
+                let fixed_output = output.as_ref().and_then(|(ty, output)| {
+                    let register = X86Register::from_constraint(constraints.register(0)?)?;
+                    Some((*ty, output.clone(), register))
+                });
+                let clobbers = constraints
+                    .clobbers
+                    .iter()
+                    .filter_map(|clobber| X86Register::from_name(clobber))
+                    .collect();
+                self.emit(X86Instruction::Asm {
+                    template: template.clone(),
+                    output: match &fixed_output {
+                        Some((ty, _, register)) => Some((*ty, register.dest())),
+                        None => output,
+                    },
+                    inputs,
+                    clobbers,
+                });
+                if let Some((ty, output, register)) = fixed_output {
+                    self.emit(X86Instruction::Mov {
+                        size: Size::of(ty),
+                        dest: X86Operand::Reg(output),
+                        src: X86Operand::Reg(register.dest()),
+                    });
+                }
+            }
+            A::Abort => self.emit(X86Instruction::Call {
+                function: runtime::ABORT.to_string(),
+                args: Vec::new(),
+            }),
+            A::Return(_) => {
+                let value = trees.pop().unwrap();
+                let register = match self.tree_type(&value) {
+                    Type::Double => X86Register::Xmm0,
+                    _ => X86Register::Rax,
+                };
+                self.compute(&value, register.dest());
+                self.emit(X86Instruction::Ret(Some(register)));
+            }
+            A::ReturnVoid => self.emit(X86Instruction::Ret(None)),
+            A::Phi { .. } => unreachable!("phis are removed before instruction selection"),
+        }
+    }
+
+    /// Moves the parameters from where `convention` passes them to the first temps: from
+    /// registers, or from the caller's frame, above the return address and the saved frame
+    /// pointer
+    fn receive_params(&mut self, params: usize, convention: CallingConvention) {
+        let types: Vec<Type> = (0..params).map(|temp| self.temp_types[&temp]).collect();
+        for (temp, location) in convention.arguments(types).into_iter().enumerate() {
+            let src = match location {
+                ArgumentLocation::Register(register) => X86Operand::Reg(register.dest()),
+                ArgumentLocation::Stack(slot) => X86Operand::Mem(Frame::incoming_argument(slot)),
+            };
+            self.receive(temp, src);
+        }
+    }
+
+    /// Address of the global `name`: %rip-relative, or through the pointer the global offset
+    /// table holds, loaded into a new temp
+    fn global_address(&mut self, name: &str) -> Address {
+        if !self.through_got.contains(name) {
+            return Address::symbol(name.to_string());
+        }
+        let pointer = self.new_temp(Type::String);
+        self.emit(X86Instruction::GotLoad {
+            dest: pointer.clone(),
+            symbol: name.to_string(),
+        });
+        Address {
+            base: Some(pointer),
+            index: None,
+            displacement: 0,
+            symbol: None,
+        }
+    }
+
+    /// Moves the value at `src`, where the calling convention passes it or a global is kept,
+    /// to `temp`
+    fn receive(&mut self, temp: usize, src: X86Operand) {
+        let dest = Dest::Temp(temp);
+        let instruction = match self.temp_types[&temp] {
+            Type::Double => X86Instruction::Movsd {
+                dest: X86Operand::Reg(dest),
+                src,
+            },
+            ty => X86Instruction::Mov {
+                size: Size::of(ty),
+                dest: X86Operand::Reg(dest),
+                src,
+            },
+        };
+        self.emit(instruction);
+    }
+
+    /// Tree for `operand`, taking the tree of a temp folded into it
+    fn tree(&mut self, operand: &Operand) -> Tree {
+        match operand {
+            Operand::Var(Dest::Temp(temp)) => self
+                .pending
+                .remove(temp)
+                .unwrap_or_else(|| Tree::Leaf(operand.clone())),
+            _ => Tree::Leaf(operand.clone()),
+        }
+    }
+
+    /// Writes `tree` to `dest`, or holds on to it to fold into the temp's only read
+    fn define(&mut self, dest: &Dest, tree: Tree, folded: &[bool]) {
+        match dest {
+            Dest::Temp(temp) if folded.get(*temp).copied().unwrap_or(false) => {
+                self.pending.insert(*temp, tree);
+            }
+            _ => self.compute(&tree, dest.clone()),
+        }
+    }
+
+    fn tree_type(&self, tree: &Tree) -> Type {
+        match tree {
+            Tree::Leaf(Operand::Const(_)) => Type::Int,
+            Tree::Leaf(Operand::Double(_)) => Type::Double,
+            Tree::Leaf(Operand::Str(_)) => Type::String,
+            Tree::Leaf(Operand::Var(Dest::Temp(temp))) => self.temp_types[temp],
+            Tree::Leaf(Operand::Var(Dest::Register(_))) => Type::Int,
+            Tree::Binary { op, arithmetic, .. } => match (arithmetic, op) {
+                (Arithmetic::Double, BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div) => {
+                    Type::Double
+                }
+                _ => Type::Int,
+            },
+            Tree::Unary { arithmetic, .. } => match arithmetic {
+                Arithmetic::Double => Type::Double,
+                Arithmetic::Int => Type::Int,
+            },
+            Tree::Convert { conversion, .. } => match conversion {
+                Conversion::I2D => Type::Double,
+                Conversion::D2I => Type::Int,
+                Conversion::I2C => Type::Char,
+            },
+            Tree::Shift { .. } => Type::Int,
+        }
+    }
+
+    /// An int operand holding the value of `tree`: an immediate for a constant, the temp itself
+    /// for a temp, and otherwise a fresh temp it's computed into
+    fn int_operand(&mut self, tree: &Tree) -> X86Operand {
+        match tree {
+            Tree::Leaf(Operand::Const(value)) => X86Operand::Imm(*value as i32),
+            _ => X86Operand::Reg(self.register(tree, Type::Int)),
+        }
+    }
+
+    /// A temp holding the value of `tree`, which is of type `ty` unless it says otherwise
+    fn register(&mut self, tree: &Tree, ty: Type) -> Dest {
+        match tree {
+            Tree::Leaf(Operand::Var(dest)) => dest.clone(),
+            _ => {
+                let ty = match tree {
+                    Tree::Leaf(Operand::Const(_)) => ty,
+                    _ => self.tree_type(tree),
+                };
+                let dest = self.new_temp(ty);
+                self.compute(tree, dest.clone());
+                dest
+            }
+        }
+    }
+
+    /// A double operand holding the value of `tree`, in memory for a constant
+    fn double_operand(&mut self, tree: &Tree) -> X86Operand {
+        match tree {
+            Tree::Leaf(Operand::Double(value)) => X86Operand::Mem(self.double_constant(*value)),
+            _ => X86Operand::Reg(self.double_register(tree)),
+        }
+    }
+
+    /// Aborts unless the int `converted` converted from the double `src` is in range.
+    /// `cvttsd2si` gives the smallest int for NaN and for a double out of range, so that's
+    /// only right if `src` is above -2147483649, which NaN isn't.
+    fn check_conversion(&mut self, converted: Dest, src: Dest) {
+        let done = self.new_label();
+        self.emit(X86Instruction::Cmp {
+            size: Size::Long,
+            left: X86Operand::Reg(converted),
+            right: X86Operand::Imm(i32::MIN),
+        });
+        self.emit(X86Instruction::Jcc {
+            condition: X86Condition::Ne,
+            target: done,
+        });
+        let below = X86Operand::Mem(self.double_constant(i32::MIN as f64 - 1.0));
+        self.emit(X86Instruction::Ucomisd {
+            left: src,
+            right: below,
+        });
+        self.emit(X86Instruction::Jcc {
+            condition: X86Condition::A,
+            target: done,
+        });
+        self.emit(X86Instruction::Call {
+            function: runtime::ABORT.to_string(),
+            args: Vec::new(),
+        });
+        self.emit(X86Instruction::Label(done));
+    }
+
+    fn double_register(&mut self, tree: &Tree) -> Dest {
+        self.register(tree, Type::Double)
+    }
+
+    /// Address of a read-only copy of `value`
+    fn double_constant(&mut self, value: f64) -> Address {
+        if !self
+            .doubles
+            .iter()
+            .any(|double| double.to_bits() == value.to_bits())
+        {
+            self.doubles.push(value);
+        }
+        Address::symbol(double_symbol(value))
+    }
+
+    /// A copy of `operand` in a fresh temp, unless it's a constant
+    fn copy(&mut self, operand: X86Operand, arithmetic: Arithmetic) -> X86Operand {
+        let X86Operand::Reg(src) = operand else {
+            return operand;
+        };
+        let dest = match arithmetic {
+            Arithmetic::Int => {
+                let dest = self.new_temp(Type::Int);
+                self.emit(X86Instruction::Mov {
+                    size: Size::Long,
+                    dest: X86Operand::Reg(dest.clone()),
+                    src: X86Operand::Reg(src),
+                });
+                dest
+            }
+            Arithmetic::Double => {
+                let dest = self.new_temp(Type::Double);
+                self.emit(X86Instruction::Movsd {
+                    dest: X86Operand::Reg(dest.clone()),
+                    src: X86Operand::Reg(src),
+                });
+                dest
+            }
+        };
+        X86Operand::Reg(dest)
+    }
+
+    /// Writes the value of `tree` to `dest`, picking the largest pattern matching its root
+    fn compute(&mut self, tree: &Tree, dest: Dest) {
+        match tree {
+            Tree::Leaf(operand) => self.load(operand, dest),
+            Tree::Binary {
+                op,
+                arithmetic: Arithmetic::Int,
+                left,
+                right,
+            } => self.int_binary(*op, left, right, dest),
+            Tree::Binary {
+                op,
+                arithmetic: Arithmetic::Double,
+                left,
+                right,
+            } => self.double_binary(*op, left, right, dest),
+            Tree::Unary {
+                op,
+                arithmetic: Arithmetic::Int,
+                src,
+            } => {
+                let src = self.int_operand(src);
+                let instruction = match op {
+                    UnOp::Neg => X86Instruction::Unary {
+                        op: UnaryOp::Neg,
+                        dest,
+                        src,
+                    },
+                    UnOp::BitNot => X86Instruction::Unary {
+                        op: UnaryOp::Not,
+                        dest,
+                        src,
+                    },
+                    // Any int but 0 is true, so `!x` is `x == 0`
+                    UnOp::Not => {
+                        let comparison = Comparison {
+                            arithmetic: Arithmetic::Int,
+                            left: src,
+                            right: X86Operand::Imm(0),
+                        };
+                        self.set(&comparison, &Condition::Equal, dest);
+                        return;
+                    }
+                };
+                self.emit(instruction);
+            }
+            Tree::Unary {
+                arithmetic: Arithmetic::Double,
+                src,
+                ..
+            } => {
+                // Multiplying by -1 flips the sign of zeros and NaNs too
+                let src = self.double_operand(src);
+                let minus_one = X86Operand::Mem(self.double_constant(-1.0));
+                self.emit(X86Instruction::Sse {
+                    op: SseOp::Mul,
+                    dest,
+                    left: src,
+                    right: minus_one,
+                });
+            }
+            Tree::Convert { conversion, src } => {
+                let instruction = match conversion {
+                    Conversion::I2D => X86Instruction::Cvtsi2sd {
+                        dest,
+                        src: X86Operand::Reg(self.register(src, Type::Int)),
+                    },
+                    Conversion::D2I => {
+                        let src = self.double_register(src);
+                        self.emit(X86Instruction::Cvttsd2si {
+                            dest: dest.clone(),
+                            src: X86Operand::Reg(src.clone()),
+                        });
+                        self.check_conversion(dest, src);
+                        return;
+                    }
+                    Conversion::I2C => X86Instruction::Alu {
+                        op: AluOp::And,
+                        size: Size::Long,
+                        dest,
+                        left: self.int_operand(src),
+                        right: X86Operand::Imm(0xff),
+                    },
+                };
+                self.emit(instruction);
+            }
+            Tree::Shift { kind, src, amount } => {
+                let src = self.int_operand(src);
+                self.emit(X86Instruction::Shift {
+                    kind: *kind,
+                    dest,
+                    src,
+                    amount: *amount,
+                });
+            }
+        }
+    }
+
+    /// Moves a constant, temp or string address to `dest`
+    fn load(&mut self, operand: &Operand, dest: Dest) {
+        let instruction = match operand {
+            Operand::Const(value) => X86Instruction::Mov {
+                size: Size::Long,
+                dest: X86Operand::Reg(dest),
+                src: X86Operand::Imm(*value as i32),
+            },
+            Operand::Double(value) => X86Instruction::Movsd {
+                dest: X86Operand::Reg(dest),
+                src: X86Operand::Mem(self.double_constant(*value)),
+            },
+            Operand::Str(index) => X86Instruction::Lea {
+                size: Size::Quad,
+                dest,
+                address: Address::symbol(string_symbol(*index)),
+            },
+            Operand::Var(src) => match self.operand_type(operand) {
+                Type::Double => X86Instruction::Movsd {
+                    dest: X86Operand::Reg(dest),
+                    src: X86Operand::Reg(src.clone()),
+                },
+                ty => X86Instruction::Mov {
+                    size: Size::of(ty),
+                    dest: X86Operand::Reg(dest),
+                    src: X86Operand::Reg(src.clone()),
+                },
+            },
+        };
+        self.emit(instruction);
+    }
+
+    fn operand_type(&self, operand: &Operand) -> Type {
+        self.tree_type(&Tree::Leaf(operand.clone()))
+    }
+
+    fn int_binary(&mut self, op: BinOp, left: &Tree, right: &Tree, dest: Dest) {
+        if let Some(condition) = comparison_condition(op) {
+            let comparison = Comparison {
+                arithmetic: Arithmetic::Int,
+                left: self.int_operand(left),
+                right: self.int_operand(right),
+            };
+            self.set(&comparison, &condition, dest);
+            return;
+        }
+        match op {
+            BinOp::Add | BinOp::Sub => {
+                if let Some(address) = self.address(op, left, right) {
+                    self.emit(X86Instruction::Lea {
+                        size: Size::Long,
+                        dest,
+                        address,
+                    });
+                    return;
+                }
+                let (mut left, mut right) = (self.int_operand(left), self.int_operand(right));
+                if op == BinOp::Add && matches!(left, X86Operand::Imm(_)) {
+                    std::mem::swap(&mut left, &mut right);
+                }
+                let op = match op {
+                    BinOp::Add => AluOp::Add,
+                    _ => AluOp::Sub,
+                };
+                self.emit(X86Instruction::Alu {
+                    op,
+                    size: Size::Long,
+                    dest,
+                    left,
+                    right,
+                });
+            }
+            BinOp::Mul => {
+                let (factor, imm) = match (left.constant(), right.constant()) {
+                    (_, Some(imm)) => (left, imm),
+                    (Some(imm), None) => (right, imm),
+                    (None, None) => {
+                        let (left, right) = (self.int_operand(left), self.int_operand(right));
+                        self.emit(X86Instruction::Alu {
+                            op: AluOp::Imul,
+                            size: Size::Long,
+                            dest,
+                            left,
+                            right,
+                        });
+                        return;
+                    }
+                };
+                let src = X86Operand::Reg(self.register(factor, Type::Int));
+                self.emit(X86Instruction::ImulImm { dest, src, imm });
+            }
+            BinOp::Div => {
+                let dividend = self.int_operand(left);
+                // `idiv` doesn't take an immediate
+                let divisor = X86Operand::Reg(self.register(right, Type::Int));
+                let eax = X86Register::Rax.dest();
+                self.emit(X86Instruction::Mov {
+                    size: Size::Long,
+                    dest: X86Operand::Reg(eax.clone()),
+                    src: dividend,
+                });
+                self.emit(X86Instruction::Cdq);
+                self.emit(X86Instruction::Idiv { divisor });
+                self.emit(X86Instruction::Mov {
+                    size: Size::Long,
+                    dest: X86Operand::Reg(dest),
+                    src: X86Operand::Reg(eax),
+                });
+            }
+            _ => unreachable!("comparisons are handled above"),
+        }
+    }
+
+    /// The address computing `left op right`, if it has more parts than a plain sum of two
+    /// temps, which `add` computes as well
+    fn address(&mut self, op: BinOp, left: &Tree, right: &Tree) -> Option<Address> {
+        let mut terms = Vec::new();
+        address_terms(left, &mut terms);
+        match (op, right.constant()) {
+            (BinOp::Add, _) => address_terms(right, &mut terms),
+            (BinOp::Sub, Some(value)) => terms.push(Term::Displacement(value.wrapping_neg())),
+            _ => return None,
+        }
+
+        let mut displacement: i32 = 0;
+        let mut plain = Vec::new();
+        let mut scaled = Vec::new();
+        for term in terms {
+            match term {
+                Term::Displacement(value) => displacement = displacement.wrapping_add(value),
+                Term::Plain(tree) => plain.push(tree),
+                Term::Scaled(tree, scale) => scaled.push((tree, scale)),
+            }
+        }
+        let parts = plain.len() + scaled.len() + (displacement != 0) as usize;
+        // A plain sum of two temps is left to `add`
+        if parts < 2 || (scaled.is_empty() && displacement == 0 && plain.len() == 2) {
+            return None;
+        }
+
+        // Only one term can be scaled, and the others are summed into the base
+        let index = scaled
+            .pop()
+            .map(|(tree, scale)| (self.register(tree, Type::Int), scale));
+        let mut registers: Vec<Dest> = plain
+            .into_iter()
+            .map(|tree| self.register(tree, Type::Int))
+            .collect();
+        for (tree, scale) in scaled {
+            let src = X86Operand::Reg(self.register(tree, Type::Int));
+            let dest = self.new_temp(Type::Int);
+            self.emit(X86Instruction::Shift {
+                kind: ShiftKind::Left,
+                dest: dest.clone(),
+                src,
+                amount: scale.trailing_zeros(),
+            });
+            registers.push(dest);
+        }
+        let bases = if index.is_some() { 1 } else { 2 };
+        while registers.len() > bases {
+            let right = X86Operand::Reg(registers.pop().unwrap());
+            let left = X86Operand::Reg(registers.pop().unwrap());
+            let dest = self.new_temp(Type::Int);
+            self.emit(X86Instruction::Alu {
+                op: AluOp::Add,
+                size: Size::Long,
+                dest: dest.clone(),
+                left,
+                right,
+            });
+            registers.push(dest);
+        }
+        let index = match index {
+            None if registers.len() == 2 => Some((registers.pop().unwrap(), 1)),
+            index => index,
+        };
+        Some(Address {
+            base: registers.pop(),
+            index,
+            displacement,
+            symbol: None,
+        })
+    }
+
+    fn double_binary(&mut self, op: BinOp, left: &Tree, right: &Tree, dest: Dest) {
+        if let Some(condition) = comparison_condition(op) {
+            let comparison = Comparison {
+                arithmetic: Arithmetic::Double,
+                left: X86Operand::Reg(self.double_register(left)),
+                right: self.double_operand(right),
+            };
+            self.set(&comparison, &condition, dest);
+            return;
+        }
+        let op = match op {
+            BinOp::Add => SseOp::Add,
+            BinOp::Sub => SseOp::Sub,
+            BinOp::Mul => SseOp::Mul,
+            BinOp::Div => SseOp::Div,
+            _ => unreachable!("comparisons are handled above"),
+        };
+        let (mut left, mut right) = (self.double_operand(left), self.double_operand(right));
+        // A constant is best read from memory as the second operand
+        if matches!(op, SseOp::Add | SseOp::Mul) && matches!(left, X86Operand::Mem(_)) {
+            std::mem::swap(&mut left, &mut right);
+        }
+        self.emit(X86Instruction::Sse {
+            op,
+            dest,
+            left,
+            right,
+        });
+    }
+
+    /// Sets the flags for `comparison`, returning what to test for `condition`: one x86
+    /// condition, or for doubles compared for (in)equality, the one to test on ordered
+    /// operands and the parity flag telling if they're unordered
+    fn compare(&mut self, comparison: &Comparison, condition: &Condition) -> X86Condition {
+        match comparison.arithmetic {
+            Arithmetic::Int => {
+                let (mut left, mut right) = (comparison.left.clone(), comparison.right.clone());
+                let mut condition = condition.clone();
+                if let X86Operand::Imm(_) = left {
+                    if let X86Operand::Imm(_) = right {
+                        left = X86Operand::Reg(self.register(
+                            &Tree::Leaf(Operand::Const(imm_value(&left) as i128)),
+                            Type::Int,
+                        ));
+                    } else {
+                        std::mem::swap(&mut left, &mut right);
+                        condition = swapped(&condition);
+                    }
+                }
+                self.emit(X86Instruction::Cmp {
+                    size: Size::Long,
+                    left,
+                    right,
+                });
+                match condition {
+                    Condition::Equal => X86Condition::E,
+                    Condition::NotEqual => X86Condition::Ne,
+                    Condition::Less => X86Condition::L,
+                    Condition::LessOrEqual => X86Condition::Le,
+                    Condition::Greater => X86Condition::G,
+                    Condition::GreaterOrEqual => X86Condition::Ge,
+                }
+            }
+            Arithmetic::Double => {
+                // `a` and `ae` are false on unordered operands, so less-than is tested as
+                // greater-than with the operands swapped
+                let (left, right, condition) = match condition {
+                    Condition::Less | Condition::LessOrEqual => {
+                        let right = match &comparison.right {
+                            X86Operand::Reg(right) => right.clone(),
+                            right => {
+                                let dest = self.new_temp(Type::Double);
+                                self.emit(X86Instruction::Movsd {
+                                    dest: X86Operand::Reg(dest.clone()),
+                                    src: right.clone(),
+                                });
+                                dest
+                            }
+                        };
+                        (right, comparison.left.clone(), swapped(condition))
+                    }
+                    _ => {
+                        let X86Operand::Reg(left) = &comparison.left else {
+                            unreachable!("the left operand of a double comparison is a temp");
+                        };
+                        (left.clone(), comparison.right.clone(), condition.clone())
+                    }
+                };
+                self.emit(X86Instruction::Ucomisd { left, right });
+                match condition {
+                    Condition::Greater => X86Condition::A,
+                    Condition::GreaterOrEqual => X86Condition::Ae,
+                    Condition::Equal => X86Condition::E,
+                    Condition::NotEqual => X86Condition::Ne,
+                    Condition::Less | Condition::LessOrEqual => {
+                        unreachable!("swapped above")
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes 1 to `dest` if `condition` holds for `comparison`, else 0
+    fn set(&mut self, comparison: &Comparison, condition: &Condition, dest: Dest) {
+        let tested = self.compare(comparison, condition);
+        // Unordered doubles are neither equal nor ordered, but they set the zero flag
+        let (parity, op) = match (comparison.arithmetic, tested) {
+            (Arithmetic::Double, X86Condition::E) => (X86Condition::Np, AluOp::And),
+            (Arithmetic::Double, X86Condition::Ne) => (X86Condition::P, AluOp::Or),
+            _ => {
+                self.emit(X86Instruction::Set {
+                    condition: tested,
+                    dest,
+                });
+                return;
+            }
+        };
+        let ordered = self.new_temp(Type::Int);
+        self.emit(X86Instruction::Set {
+            condition: tested,
+            dest: dest.clone(),
+        });
+        self.emit(X86Instruction::Set {
+            condition: parity,
+            dest: ordered.clone(),
+        });
+        self.emit(X86Instruction::Alu {
+            op,
+            size: Size::Long,
+            dest: dest.clone(),
+            left: X86Operand::Reg(dest),
+            right: X86Operand::Reg(ordered),
+        });
+    }
+
+    /// Jumps to `tgt_true` if `condition` holds for `comparison`, else to `tgt_false`
+    fn jump(
+        &mut self,
+        comparison: &Comparison,
+        condition: &Condition,
+        tgt_true: AsmLabel,
+        tgt_false: AsmLabel,
+    ) {
+        let tested = self.compare(comparison, condition);
+        match (comparison.arithmetic, tested) {
+            (Arithmetic::Double, X86Condition::E) => self.emit(X86Instruction::Jcc {
+                condition: X86Condition::P,
+                target: tgt_false,
+            }),
+            (Arithmetic::Double, X86Condition::Ne) => self.emit(X86Instruction::Jcc {
+                condition: X86Condition::P,
+                target: tgt_true,
+            }),
+            _ => {}
+        }
+        self.emit(X86Instruction::Jcc {
+            condition: tested,
+            target: tgt_true,
+        });
+        self.emit(X86Instruction::Jmp(tgt_false));
+    }
+}
+
+/// Splits a sum into the parts an address can add up
+fn address_terms<'t>(tree: &'t Tree, terms: &mut Vec<Term<'t>>) {
+    match tree {
+        Tree::Leaf(Operand::Const(value)) => terms.push(Term::Displacement(*value as i32)),
+        Tree::Binary {
+            op: BinOp::Add,
+            arithmetic: Arithmetic::Int,
+            left,
+            right,
+        } => {
+            address_terms(left, terms);
+            address_terms(right, terms);
+        }
+        Tree::Shift {
+            kind: ShiftKind::Left,
+            src,
+            amount: amount @ 0..=3,
+        } => terms.push(Term::Scaled(src, 1 << amount)),
+        Tree::Binary {
+            op: BinOp::Mul,
+            arithmetic: Arithmetic::Int,
+            left,
+            right,
+        } => match (left.constant(), right.constant()) {
+            (_, Some(scale @ (1 | 2 | 4 | 8))) => terms.push(Term::Scaled(left, scale as u8)),
+            (Some(scale @ (1 | 2 | 4 | 8)), _) => terms.push(Term::Scaled(right, scale as u8)),
+            _ => terms.push(Term::Plain(tree)),
+        },
+        _ => terms.push(Term::Plain(tree)),
+    }
+}
+
+fn imm_value(operand: &X86Operand) -> i32 {
+    match operand {
+        X86Operand::Imm(value) => *value,
+        _ => unreachable!("only called on immediates"),
+    }
+}
+
+/// The condition that holds for `b ? a` when `condition` holds for `a ? b`
+fn swapped(condition: &Condition) -> Condition {
+    match condition {
+        Condition::Less => Condition::Greater,
+        Condition::LessOrEqual => Condition::GreaterOrEqual,
+        Condition::Greater => Condition::Less,
+        Condition::GreaterOrEqual => Condition::LessOrEqual,
+        Condition::Equal => Condition::Equal,
+        Condition::NotEqual => Condition::NotEqual,
+    }
+}
+
+fn comparison_condition(op: BinOp) -> Option<Condition> {
+    match op {
+        BinOp::Equal => Some(Condition::Equal),
+        BinOp::NotEqual => Some(Condition::NotEqual),
+        BinOp::Less => Some(Condition::Less),
+        BinOp::LessEqual => Some(Condition::LessOrEqual),
+        BinOp::Greater => Some(Condition::Greater),
+        BinOp::GreaterEqual => Some(Condition::GreaterOrEqual),
+        BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div => None,
+    }
+}
@@ -0,0 +1,894 @@
+//! Translation of the abstract assembly into LLVM IR text, for `--emit=llvm-ir`, so that the
+//! program can be compiled with `clang` or optimized with `opt` and compared with what the
+//! crate's own optimizer and backends make of it.
+//!
+//! Each temp is kept in a stack slot of its own, loaded before each use and stored after each
+//! write, as clang writes unoptimized code, so that the IR doesn't have to be in SSA form;
+//! `opt -passes=mem2reg` puts it back in registers. Ints are `i32`, chars `i8`, strings `ptr`
+//! and doubles `double`. Comparisons leave their outcome in a slot of their own, as -1, 0 or 1
+//! for less, equal or greater and 2 for unordered doubles, which conditions are then tested
+//! against. Printing calls `printf` with the format of each of C0's conversions, reading calls
+//! `scanf`, and aborting flushes what was printed and calls `abort`, as the runtime does, so
+//! the IR needs nothing but the C library to link.
+
+use super::context::{
+    AbstractAssemblyInstruction, Arithmetic, AsmLabel, Condition, Context, Conversion, Dest,
+    Global, Operand, ShiftKind, StringTable,
+};
+use super::emit::{serialize_asm_constraints, serialize_format_spec};
+use super::undefined::Undefined;
+use super::Mangling;
+use crate::parser::{AsmConstraints, BinOp, FormatSpec, UnOp};
+use crate::sema::Type;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Writes `functions`, `globals` and `strings` to `outpath` as an LLVM module, for the target
+/// LLVM knows as `triple` if there's one, with the symbols `mangling` gives
+pub fn emit_llvm_ir(
+    outpath: &Path,
+    functions: &[Context],
+    globals: &[Global],
+    strings: &StringTable,
+    triple: Option<&str>,
+    mangling: &Mangling,
+) -> io::Result<()> {
+    let mut file = File::create(outpath)?;
+    write_llvm_ir(&mut file, functions, globals, strings, triple, mangling)
+}
+
+fn write_llvm_ir(
+    file: &mut impl Write,
+    functions: &[Context],
+    globals: &[Global],
+    strings: &StringTable,
+    triple: Option<&str>,
+    mangling: &Mangling,
+) -> io::Result<()> {
+    if let Some(triple) = triple {
+        writeln!(file, "target triple = \"{}\"\n", triple)?;
+    }
+    for (index, string) in strings.iter() {
+        let mut bytes = string.as_bytes().to_vec();
+        bytes.push(0);
+        writeln!(
+            file,
+            "{} = private unnamed_addr constant [{} x i8] c\"{}\"",
+            string_symbol(index),
+            bytes.len(),
+            escape(&bytes)
+        )?;
+    }
+    for spec in FORMATS {
+        let mut bytes = serialize_format_spec(&spec).into_bytes();
+        bytes.push(0);
+        writeln!(
+            file,
+            "{} = private unnamed_addr constant [{} x i8] c\"{}\"",
+            format_symbol(&spec),
+            bytes.len(),
+            escape(&bytes)
+        )?;
+    }
+    writeln!(
+        file,
+        "{} = private unnamed_addr constant [4 x i8] c\"%lf\\00\"",
+        SCAN_DOUBLE_FORMAT
+    )?;
+    // Type each global is kept as, which a char's int initializer makes an int
+    let mut global_types: HashMap<&str, Type> = HashMap::new();
+    for global in globals {
+        let linkage = if global.is_static { "internal " } else { "" };
+        let (ty, value) = match &global.value {
+            Operand::Const(value) => (Type::Int, (*value as i32).to_string()),
+            Operand::Double(value) => (Type::Double, double(*value)),
+            Operand::Str(index) => (Type::String, string_symbol(*index)),
+            Operand::Var(_) => unreachable!("globals are initialized with constants"),
+        };
+        let symbol = mangling.symbol(&global.name);
+        let ty_name = llvm_type(ty);
+        writeln!(
+            file,
+            "@{} = {}global {} {}",
+            symbol, linkage, ty_name, value
+        )?;
+        global_types.insert(&global.name, ty);
+    }
+
+    // Every function's signature, and those of the functions called that aren't defined here,
+    // as the first call to each passes its arguments
+    let mut signatures: HashMap<&str, Signature> = functions
+        .iter()
+        .map(|context| (context.name.as_str(), Signature::of(context)))
+        .collect();
+    let mut declared: BTreeMap<&str, Signature> = BTreeMap::new();
+    for context in functions {
+        for instruction in &context.instructions {
+            if let AbstractAssemblyInstruction::Call {
+                dest,
+                function,
+                args,
+            } = instruction
+            {
+                if !signatures.contains_key(function.as_str()) {
+                    let signature = Signature {
+                        return_type: dest.as_ref().map_or(Type::Void, |d| context.dest_type(d)),
+                        params: args
+                            .iter()
+                            .map(|arg| operand_type(context, arg).unwrap_or(Type::Int))
+                            .collect(),
+                    };
+                    declared.entry(function).or_insert(signature);
+                }
+            }
+        }
+    }
+    signatures.extend(
+        declared
+            .iter()
+            .map(|(name, signature)| (*name, signature.clone())),
+    );
+
+    for context in functions {
+        let mut translator = Translator {
+            context,
+            signatures: &signatures,
+            declared: &declared,
+            globals: &global_types,
+            mangling,
+            lines: Vec::new(),
+            next_value: 0,
+            next_block: 0,
+            open: true,
+        };
+        translator.function();
+        file.write_all(b"\n")?;
+        for line in translator.lines {
+            writeln!(file, "{}", line)?;
+        }
+    }
+
+    file.write_all(b"\n")?;
+    for (name, signature) in &declared {
+        let params: Vec<&str> = signature.params.iter().map(|ty| llvm_type(*ty)).collect();
+        writeln!(
+            file,
+            "declare {} @{}({})",
+            llvm_type(signature.return_type),
+            name,
+            params.join(", ")
+        )?;
+    }
+    file.write_all(b"declare i32 @printf(ptr, ...)\n")?;
+    file.write_all(b"declare i32 @scanf(ptr, ...)\n")?;
+    file.write_all(b"declare i32 @fflush(ptr)\n")?;
+    file.write_all(b"declare void @abort()\n")
+}
+
+/// The conversions `printf` is called with
+const FORMATS: [FormatSpec; 4] = [
+    FormatSpec::Int,
+    FormatSpec::Char,
+    FormatSpec::Double,
+    FormatSpec::String,
+];
+
+/// What a function takes and returns
+#[derive(Debug, Clone)]
+struct Signature {
+    return_type: Type,
+    params: Vec<Type>,
+}
+
+impl Signature {
+    /// The signature of the function `context` defines. One read back from text returns what
+    /// its returns do, since it doesn't know its return type.
+    fn of(context: &Context) -> Self {
+        let returned = context
+            .instructions
+            .iter()
+            .find_map(|instruction| match instruction {
+                AbstractAssemblyInstruction::Return(operand) => {
+                    Some(operand_type(context, operand).unwrap_or(Type::Int))
+                }
+                _ => None,
+            });
+        let return_type = match context.return_type() {
+            Type::Void => returned.unwrap_or(Type::Void),
+            ty => ty,
+        };
+        Signature {
+            return_type,
+            params: (0..context.params)
+                .map(|param| context.temp_types()[&param])
+                .collect(),
+        }
+    }
+}
+
+fn llvm_type(ty: Type) -> &'static str {
+    match ty {
+        Type::Int => "i32",
+        Type::Char => "i8",
+        Type::String => "ptr",
+        Type::Double => "double",
+        Type::Void => "void",
+    }
+}
+
+/// Type of `operand`, or `None` for an int constant, which takes the type it's used at
+fn operand_type(context: &Context, operand: &Operand) -> Option<Type> {
+    match operand {
+        Operand::Var(dest) => Some(context.dest_type(dest)),
+        Operand::Str(_) => Some(Type::String),
+        Operand::Double(_) => Some(Type::Double),
+        Operand::Const(_) => None,
+    }
+}
+
+fn string_symbol(index: usize) -> String {
+    format!("@.str.{}", index)
+}
+
+/// The format `scanf` reads a double with, which printing's `%f` would read as a float
+const SCAN_DOUBLE_FORMAT: &str = "@.format.scan.double";
+
+fn format_symbol(spec: &FormatSpec) -> &'static str {
+    match spec {
+        FormatSpec::Int => "@.format.int",
+        FormatSpec::Char => "@.format.char",
+        FormatSpec::Double => "@.format.double",
+        FormatSpec::String => "@.format.string",
+    }
+}
+
+/// A double as LLVM writes it exactly, by its bits
+fn double(value: f64) -> String {
+    format!("0x{:016X}", value.to_bits())
+}
+
+/// Escapes `bytes` for a `c"..."` constant, writing bytes other than printable ASCII, quotes
+/// and backslashes in hex
+fn escape(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            b'"' | b'\\' => format!("\\{:02X}", byte),
+            b' '..=b'~' => (byte as char).to_string(),
+            _ => format!("\\{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Inline assembly's `template` as LLVM writes it, with `%0`, `%1`, ... as `$0`, `$1`, ...,
+/// `%%` as `%` and a literal `$` doubled
+fn asm_template(template: &str) -> String {
+    let mut translated = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' if chars.peek() == Some(&'%') => {
+                chars.next();
+                translated.push('%');
+            }
+            '%' if chars.peek().is_some_and(char::is_ascii_digit) => translated.push('$'),
+            '$' => translated.push_str("$$"),
+            _ => translated.push(c),
+        }
+    }
+    escape(translated.as_bytes())
+}
+
+/// Translates one function, a line at a time
+struct Translator<'a> {
+    context: &'a Context,
+    signatures: &'a HashMap<&'a str, Signature>,
+    /// The functions called that aren't defined here
+    declared: &'a BTreeMap<&'a str, Signature>,
+    /// Type each global is kept as, by name
+    globals: &'a HashMap<&'a str, Type>,
+    mangling: &'a Mangling,
+    lines: Vec<String>,
+    next_value: usize,
+    next_block: usize,
+    /// Whether the current block has yet to end in a terminator
+    open: bool,
+}
+
+impl Translator<'_> {
+    /// Adds an instruction, starting a block for it if the last one ended, as it does after
+    /// an abort
+    fn emit(&mut self, line: String) {
+        if !self.open {
+            let block = self.new_block();
+            self.label(&block);
+        }
+        self.lines.push(format!("  {}", line));
+    }
+
+    /// Adds an instruction ending the block
+    fn terminate(&mut self, line: String) {
+        self.emit(line);
+        self.open = false;
+    }
+
+    /// Starts the block `label`, falling into it from the block before if that hasn't ended
+    fn label(&mut self, label: &str) {
+        if self.open {
+            self.lines.push(format!("  br label %{}", label));
+        }
+        self.lines.push(format!("{}:", label));
+        self.open = true;
+    }
+
+    /// Computes `expression` into a new value, and returns its name
+    fn value(&mut self, expression: String) -> String {
+        let name = format!("%v{}", self.next_value);
+        self.next_value += 1;
+        self.emit(format!("{} = {}", name, expression));
+        name
+    }
+
+    fn new_block(&mut self) -> String {
+        self.next_block += 1;
+        format!("b{}", self.next_block - 1)
+    }
+
+    fn block(label: AsmLabel) -> String {
+        format!("L{}", label.0)
+    }
+
+    fn temp(dest: &Dest) -> String {
+        match dest {
+            Dest::Temp(temp) => format!("%t{}", temp),
+            Dest::Register(_) => unreachable!("registers are only assigned by the backends"),
+        }
+    }
+
+    /// Symbol of `function`: mangled if it's one of the module's, and as it is if it's extern
+    fn symbol(&self, function: &str) -> String {
+        if self.declared.contains_key(function) {
+            function.to_string()
+        } else {
+            self.mangling.symbol(function)
+        }
+    }
+
+    fn type_of(&self, operand: &Operand) -> Option<Type> {
+        operand_type(self.context, operand)
+    }
+
+    /// `operand` as a value of type `ty`, loading it from its slot and widening or narrowing
+    /// a char or an int to the other
+    fn operand(&mut self, operand: &Operand, ty: Type) -> String {
+        match operand {
+            Operand::Const(0) if ty == Type::String => "null".to_string(),
+            Operand::Const(value) if ty == Type::Char => (*value as u8 as i8).to_string(),
+            Operand::Const(value) => (*value as i32).to_string(),
+            Operand::Double(value) => double(*value),
+            Operand::Str(index) => string_symbol(*index),
+            Operand::Var(dest) => {
+                let own = self.context.dest_type(dest);
+                let loaded =
+                    self.value(format!("load {}, ptr {}", llvm_type(own), Self::temp(dest)));
+                self.convert(loaded, own, ty)
+            }
+        }
+    }
+
+    /// `value`, of type `from`, as a value of type `to`
+    fn convert(&mut self, value: String, from: Type, to: Type) -> String {
+        match (from, to) {
+            (Type::Char, Type::Int) => self.value(format!("zext i8 {} to i32", value)),
+            (Type::Int, Type::Char) => self.value(format!("trunc i32 {} to i8", value)),
+            _ => value,
+        }
+    }
+
+    /// Stores `value`, of type `ty`, in `dest`'s slot
+    fn store(&mut self, dest: &Dest, value: String, ty: Type) {
+        let own = self.context.dest_type(dest);
+        let value = self.convert(value, ty, own);
+        self.emit(format!(
+            "store {} {}, ptr {}",
+            llvm_type(own),
+            value,
+            Self::temp(dest)
+        ));
+    }
+
+    /// Stores the `i1` `value` in `dest`, as 0 or 1
+    fn store_bool(&mut self, dest: &Dest, value: String) {
+        let widened = self.value(format!("zext i1 {} to i32", value));
+        self.store(dest, widened, Type::Int);
+    }
+
+    /// The type two operands are compared or combined at: that of whichever has one, or int
+    /// if they differ, like a char compared with an int
+    fn common_type(&self, left: &Operand, right: &Operand) -> Type {
+        match (self.type_of(left), self.type_of(right)) {
+            (Some(left), Some(right)) if left == right => left,
+            (Some(ty), None) | (None, Some(ty)) => ty,
+            _ => Type::Int,
+        }
+    }
+
+    fn function(&mut self) {
+        let context = self.context;
+        let signature = self.signatures[context.name.as_str()].clone();
+        let params: Vec<String> = signature
+            .params
+            .iter()
+            .enumerate()
+            .map(|(index, ty)| format!("{} %p{}", llvm_type(*ty), index))
+            .collect();
+        let linkage = if context.is_static { "internal " } else { "" };
+        self.lines.push(format!(
+            "define {}{} @{}({}) {{",
+            linkage,
+            llvm_type(signature.return_type),
+            self.mangling.symbol(&context.name),
+            params.join(", ")
+        ));
+        self.lines.push("entry:".to_string());
+        let mut temps: Vec<(&usize, &Type)> = context.temp_types().iter().collect();
+        temps.sort_by_key(|(temp, _)| **temp);
+        for (temp, ty) in temps {
+            if *ty != Type::Void {
+                self.emit(format!("%t{} = alloca {}", temp, llvm_type(*ty)));
+            }
+        }
+        self.emit("%ordering = alloca i32".to_string());
+        for (index, ty) in signature.params.iter().enumerate() {
+            self.store(&Dest::Temp(index), format!("%p{}", index), *ty);
+        }
+        for instruction in &context.instructions {
+            self.instruction(instruction, signature.return_type);
+        }
+        // A function that can run off its end returns nothing, or has nothing to return
+        if self.open {
+            match signature.return_type {
+                Type::Void => self.terminate("ret void".to_string()),
+                _ => self.terminate("unreachable".to_string()),
+            }
+        }
+        self.lines.push("}".to_string());
+    }
+
+    fn instruction(&mut self, instruction: &AbstractAssemblyInstruction, return_type: Type) {
+        use AbstractAssemblyInstruction as A;
+        match instruction {
+            A::Mov { dest, src } => {
+                let ty = self.type_of(src).unwrap_or(self.context.dest_type(dest));
+                let value = self.operand(src, ty);
+                self.store(dest, value, ty);
+            }
+            A::BinOp {
+                op,
+                arithmetic,
+                dest,
+                src1,
+                src2,
+            } => self.binary(*op, *arithmetic, dest, src1, src2),
+            A::UnOp {
+                op,
+                arithmetic,
+                dest,
+                src,
+            } => match (op, arithmetic) {
+                (UnOp::Neg, Arithmetic::Double) => {
+                    let value = self.operand(src, Type::Double);
+                    let negated = self.value(format!("fneg double {}", value));
+                    self.store(dest, negated, Type::Double);
+                }
+                (UnOp::Neg, Arithmetic::Int) => {
+                    let value = self.operand(src, Type::Int);
+                    let negated = self.value(format!("sub i32 0, {}", value));
+                    self.store(dest, negated, Type::Int);
+                }
+                (UnOp::BitNot, _) => {
+                    let value = self.operand(src, Type::Int);
+                    let inverted = self.value(format!("xor i32 {}, -1", value));
+                    self.store(dest, inverted, Type::Int);
+                }
+                (UnOp::Not, _) => {
+                    let ty = self.type_of(src).unwrap_or(Type::Int);
+                    let value = self.operand(src, ty);
+                    let zero = self.operand(&Operand::Const(0), ty);
+                    let test = self.value(format!("icmp eq {} {}, {}", llvm_type(ty), value, zero));
+                    self.store_bool(dest, test);
+                }
+            },
+            A::Convert {
+                conversion,
+                dest,
+                src,
+            } => {
+                let (from, to, operation) = match conversion {
+                    Conversion::I2D => (Type::Int, Type::Double, "sitofp"),
+                    Conversion::D2I => (Type::Double, Type::Int, "fptosi"),
+                    Conversion::I2C => (Type::Int, Type::Char, "trunc"),
+                };
+                let value = self.operand(src, from);
+                if let Conversion::D2I = conversion {
+                    self.check_conversion(src, &value);
+                }
+                let converted = self.value(format!(
+                    "{} {} {} to {}",
+                    operation,
+                    llvm_type(from),
+                    value,
+                    llvm_type(to)
+                ));
+                self.store(dest, converted, to);
+            }
+            A::Shift {
+                kind,
+                dest,
+                src,
+                amount,
+            } => {
+                let operation = match kind {
+                    ShiftKind::Left => "shl",
+                    ShiftKind::ArithmeticRight => "ashr",
+                    ShiftKind::LogicalRight => "lshr",
+                };
+                let value = self.operand(src, Type::Int);
+                let shifted = self.value(format!("{} i32 {}, {}", operation, value, amount));
+                self.store(dest, shifted, Type::Int);
+            }
+            A::Compare {
+                arithmetic,
+                left,
+                right,
+                ..
+            } => self.compare(*arithmetic, left, right),
+            A::SetIf { dest, condition } => {
+                let holds = self.holds(condition);
+                self.store_bool(dest, holds);
+            }
+            A::JmpCondition {
+                condition,
+                tgt_true,
+                tgt_false,
+            } => {
+                let holds = self.holds(condition);
+                self.terminate(format!(
+                    "br i1 {}, label %{}, label %{}",
+                    holds,
+                    Self::block(*tgt_true),
+                    Self::block(*tgt_false)
+                ));
+            }
+            A::Jmp(label) => self.terminate(format!("br label %{}", Self::block(*label))),
+            A::Lbl(label) => self.label(&Self::block(*label)),
+            A::Loc { .. } => {}
+            A::Phi { .. } => unreachable!("phis are removed before translation"),
+            A::Print { spec, src } => {
+                // Chars are promoted to ints, as variadic arguments are
+                let ty = match spec {
+                    FormatSpec::Int | FormatSpec::Char => Type::Int,
+                    FormatSpec::Double => Type::Double,
+                    FormatSpec::String => Type::String,
+                };
+                let value = self.operand(src, ty);
+                self.value(format!(
+                    "call i32 (ptr, ...) @printf(ptr {}, {} {})",
+                    format_symbol(spec),
+                    llvm_type(ty),
+                    value
+                ));
+            }
+            A::Scan { dest, spec } => {
+                // `scanf` reads straight into the temp's slot, and aborts without a number
+                let format = match spec {
+                    FormatSpec::Double => SCAN_DOUBLE_FORMAT,
+                    _ => format_symbol(spec),
+                };
+                let read = self.value(format!(
+                    "call i32 (ptr, ...) @scanf(ptr {}, ptr {})",
+                    format,
+                    Self::temp(dest)
+                ));
+                let fails = self.value(format!("icmp ne i32 {}, 1", read));
+                let fail = self.new_block();
+                let done = self.new_block();
+                self.terminate(format!("br i1 {}, label %{}, label %{}", fails, fail, done));
+                self.label(&fail);
+                self.abort();
+                self.label(&done);
+            }
+            A::Call {
+                dest,
+                function,
+                args,
+            } => self.call(dest.as_ref(), function, args),
+            A::Load { dest, global } => {
+                let ty = self.globals[global.as_str()];
+                let symbol = self.mangling.symbol(global);
+                let value = self.value(format!("load {}, ptr @{}", llvm_type(ty), symbol));
+                self.store(dest, value, ty);
+            }
+            A::Store { global, src } => {
+                let ty = self.globals[global.as_str()];
+                let value = self.operand(src, ty);
+                let symbol = self.mangling.symbol(global);
+                self.emit(format!(
+                    "store {} {}, ptr @{}",
+                    llvm_type(ty),
+                    value,
+                    symbol
+                ));
+            }
+            A::Asm {
+                template,
+                output,
+                inputs,
+                constraints,
+            } => self.asm(template, output.as_ref(), inputs, constraints),
+            A::Abort => self.abort(),
+            A::Return(operand) => {
+                let value = self.operand(operand, return_type);
+                self.terminate(format!("ret {} {}", llvm_type(return_type), value));
+            }
+            A::ReturnVoid => self.terminate("ret void".to_string()),
+        }
+    }
+
+    fn binary(
+        &mut self,
+        op: BinOp,
+        arithmetic: Arithmetic,
+        dest: &Dest,
+        src1: &Operand,
+        src2: &Operand,
+    ) {
+        let ty = match arithmetic {
+            Arithmetic::Double => Type::Double,
+            Arithmetic::Int => match op {
+                BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div => Type::Int,
+                _ => self.common_type(src1, src2),
+            },
+        };
+        let left = self.operand(src1, ty);
+        let right = self.operand(src2, ty);
+        let double = ty == Type::Double;
+        let operation = match op {
+            BinOp::Add if double => "fadd",
+            BinOp::Sub if double => "fsub",
+            BinOp::Mul if double => "fmul",
+            BinOp::Div if double => "fdiv",
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => {
+                self.check_division(src2, &left, &right);
+                "sdiv"
+            }
+            comparison => {
+                let predicate = predicate(comparison, ty);
+                let compare = if double { "fcmp" } else { "icmp" };
+                let test = self.value(format!(
+                    "{} {} {} {}, {}",
+                    compare,
+                    predicate,
+                    llvm_type(ty),
+                    left,
+                    right
+                ));
+                self.store_bool(dest, test);
+                return;
+            }
+        };
+        let result = self.value(format!(
+            "{} {} {}, {}",
+            operation,
+            llvm_type(ty),
+            left,
+            right
+        ));
+        self.store(dest, result, ty);
+    }
+
+    /// Aborts before dividing `left` by `right` if the divisor is zero, or if it's -1 and the
+    /// dividend the least int, which `sdiv` leaves undefined. A constant divisor other than
+    /// those needs no checks.
+    fn check_division(&mut self, divisor: &Operand, left: &str, right: &str) {
+        if let Operand::Const(value) = divisor {
+            if *value as i32 != 0 && *value as i32 != -1 {
+                return;
+            }
+        }
+        let zero = self.value(format!("icmp eq i32 {}, 0", right));
+        let minus_one = self.value(format!("icmp eq i32 {}, -1", right));
+        let least = self.value(format!("icmp eq i32 {}, {}", left, i32::MIN));
+        let overflow = self.value(format!("and i1 {}, {}", minus_one, least));
+        let fails = self.value(format!("or i1 {}, {}", zero, overflow));
+        let fail = self.new_block();
+        let divide = self.new_block();
+        self.terminate(format!(
+            "br i1 {}, label %{}, label %{}",
+            fails, fail, divide
+        ));
+        self.label(&fail);
+        self.abort();
+        self.label(&divide);
+    }
+
+    /// Aborts before converting the double `value` to an int if it's NaN or out of range once
+    /// truncated, which `fptosi` leaves undefined. A constant in range needs no checks.
+    fn check_conversion(&mut self, src: &Operand, value: &str) {
+        if let Operand::Double(constant) = src {
+            if Undefined::of_d2i(*constant).is_none() {
+                return;
+            }
+        }
+        // Ordered comparisons are false for NaN
+        let above = self.value(format!(
+            "fcmp ogt double {}, {}",
+            value,
+            double(i32::MIN as f64 - 1.0)
+        ));
+        let below = self.value(format!(
+            "fcmp olt double {}, {}",
+            value,
+            double(i32::MAX as f64 + 1.0)
+        ));
+        let fits = self.value(format!("and i1 {}, {}", above, below));
+        let convert = self.new_block();
+        let fail = self.new_block();
+        self.terminate(format!(
+            "br i1 {}, label %{}, label %{}",
+            fits, convert, fail
+        ));
+        self.label(&fail);
+        self.abort();
+        self.label(&convert);
+    }
+
+    /// Stores the outcome of comparing `left` with `right` in the ordering slot. Ints compare
+    /// signed, and chars and strings, which are never negative, unsigned.
+    fn compare(&mut self, arithmetic: Arithmetic, left: &Operand, right: &Operand) {
+        let ty = match arithmetic {
+            Arithmetic::Double => Type::Double,
+            Arithmetic::Int => self.common_type(left, right),
+        };
+        let left = self.operand(left, ty);
+        let right = self.operand(right, ty);
+        let ty_name = llvm_type(ty);
+        let (compare, less, greater) = match ty {
+            Type::Double => ("fcmp", "olt", "ogt"),
+            Type::Int => ("icmp", "slt", "sgt"),
+            _ => ("icmp", "ult", "ugt"),
+        };
+        let less = self.value(format!(
+            "{} {} {} {}, {}",
+            compare, less, ty_name, left, right
+        ));
+        let greater = self.value(format!(
+            "{} {} {} {}, {}",
+            compare, greater, ty_name, left, right
+        ));
+        let less = self.value(format!("zext i1 {} to i32", less));
+        let greater = self.value(format!("zext i1 {} to i32", greater));
+        let mut ordering = self.value(format!("sub i32 {}, {}", greater, less));
+        if ty == Type::Double {
+            let unordered = self.value(format!("fcmp uno double {}, {}", left, right));
+            let unordered = self.value(format!("select i1 {}, i32 2, i32 0", unordered));
+            ordering = self.value(format!("add i32 {}, {}", ordering, unordered));
+        }
+        self.emit(format!("store i32 {}, ptr %ordering", ordering));
+    }
+
+    /// An `i1` that's true if `condition` holds for the last comparison. Only "not equal"
+    /// holds for unordered doubles.
+    fn holds(&mut self, condition: &Condition) -> String {
+        let ordering = self.value("load i32, ptr %ordering".to_string());
+        let test = match condition {
+            Condition::Equal => "eq i32 {}, 0",
+            Condition::NotEqual => "ne i32 {}, 0",
+            Condition::Less => "eq i32 {}, -1",
+            Condition::Greater => "eq i32 {}, 1",
+            Condition::LessOrEqual => "sle i32 {}, 0",
+            // 0 or 1, and not -1 taken as unsigned
+            Condition::GreaterOrEqual => "ult i32 {}, 2",
+        };
+        self.value(format!("icmp {}", test.replace("{}", &ordering)))
+    }
+
+    fn call(&mut self, dest: Option<&Dest>, function: &str, args: &[Operand]) {
+        let signature = self.signatures[function].clone();
+        let args: Vec<String> = args
+            .iter()
+            .zip(&signature.params)
+            .map(|(arg, ty)| {
+                let value = self.operand(arg, *ty);
+                format!("{} {}", llvm_type(*ty), value)
+            })
+            .collect();
+        let call = format!(
+            "call {} @{}({})",
+            llvm_type(signature.return_type),
+            self.symbol(function),
+            args.join(", ")
+        );
+        match (dest, signature.return_type) {
+            (_, Type::Void) => self.emit(call),
+            (Some(dest), ty) => {
+                let result = self.value(call);
+                self.store(dest, result, ty);
+            }
+            (None, _) => {
+                self.value(call);
+            }
+        }
+    }
+
+    /// Inline assembly, with the output, if there's one, and the inputs passed in registers
+    fn asm(
+        &mut self,
+        template: &str,
+        output: Option<&Dest>,
+        inputs: &[Operand],
+        constraints: &AsmConstraints,
+    ) {
+        let output_type = output.map(|dest| self.context.dest_type(dest));
+        let inputs: Vec<String> = inputs
+            .iter()
+            .map(|input| {
+                let ty = self.type_of(input).unwrap_or(Type::Int);
+                let value = self.operand(input, ty);
+                format!("{} {}", llvm_type(ty), value)
+            })
+            .collect();
+        let call = format!(
+            "call {} asm sideeffect \"{}\", \"{}\"({})",
+            llvm_type(output_type.unwrap_or(Type::Void)),
+            asm_template(template),
+            serialize_asm_constraints(output.is_some(), constraints),
+            inputs.join(", ")
+        );
+        match (output, output_type) {
+            (Some(dest), Some(ty)) => {
+                let result = self.value(call);
+                self.store(dest, result, ty);
+            }
+            _ => self.emit(call),
+        }
+    }
+
+    /// Flushes what was printed and aborts, ending the block
+    fn abort(&mut self) {
+        self.value("call i32 @fflush(ptr null)".to_string());
+        self.emit("call void @abort()".to_string());
+        self.terminate("unreachable".to_string());
+    }
+}
+
+/// The predicate of `icmp` or `fcmp` for the comparison `op` of values of type `ty`. Doubles
+/// compare ordered, but for "not equal", which holds for NaN; chars and strings unsigned.
+fn predicate(op: BinOp, ty: Type) -> &'static str {
+    match (op, ty) {
+        (BinOp::Equal, Type::Double) => "oeq",
+        (BinOp::NotEqual, Type::Double) => "une",
+        (BinOp::Less, Type::Double) => "olt",
+        (BinOp::LessEqual, Type::Double) => "ole",
+        (BinOp::Greater, Type::Double) => "ogt",
+        (BinOp::GreaterEqual, Type::Double) => "oge",
+        (BinOp::Equal, _) => "eq",
+        (BinOp::NotEqual, _) => "ne",
+        (BinOp::Less, Type::Int) => "slt",
+        (BinOp::LessEqual, Type::Int) => "sle",
+        (BinOp::Greater, Type::Int) => "sgt",
+        (BinOp::GreaterEqual, Type::Int) => "sge",
+        (BinOp::Less, _) => "ult",
+        (BinOp::LessEqual, _) => "ule",
+        (BinOp::Greater, _) => "ugt",
+        (BinOp::GreaterEqual, _) => "uge",
+        (BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div, _) => {
+            unreachable!("arithmetic is translated on its own")
+        }
+    }
+}
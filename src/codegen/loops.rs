@@ -0,0 +1,133 @@
+//! Natural loops of a control flow graph, and how deeply they nest.
+//!
+//! A back edge is an edge whose target dominates its source. The target is the loop's header,
+//! and the loop is the header with every block that reaches the edge's source without going
+//! through the header. Back edges to the same header make one loop. Cycles entered at more
+//! than one block (irreducible control flow) have no header, and aren't loops here; the
+//! compiler doesn't produce them.
+
+use super::cfg::ControlFlowGraph;
+use super::dominators::DominatorTree;
+
+#[derive(Debug)]
+pub struct Loop {
+    pub header: usize,
+    /// Blocks of the loop, the header included, in index order
+    pub blocks: Vec<usize>,
+    /// Sources of the back edges to the header
+    pub latches: Vec<usize>,
+    /// Innermost loop containing this one, by index in `LoopInfo::loops`
+    pub parent: Option<usize>,
+    /// 1 for an outermost loop, 2 for a loop inside it, and so on
+    pub depth: usize,
+}
+
+impl Loop {
+    pub fn contains(&self, block: usize) -> bool {
+        self.blocks.binary_search(&block).is_ok()
+    }
+}
+
+#[derive(Debug)]
+pub struct LoopInfo {
+    /// Outer loops come before the loops they contain
+    loops: Vec<Loop>,
+    /// Innermost loop of each block
+    innermost: Vec<Option<usize>>,
+}
+
+impl LoopInfo {
+    pub fn new(cfg: &ControlFlowGraph, dominators: &DominatorTree) -> Self {
+        let mut loops: Vec<Loop> = Vec::new();
+        for block in 0..cfg.len() {
+            let latches: Vec<usize> = cfg
+                .predecessors(block)
+                .iter()
+                .copied()
+                .filter(|&predecessor| dominators.dominates(block, predecessor))
+                .collect();
+            if latches.is_empty() {
+                continue;
+            }
+
+            // Walk backwards from the latches; the header dominates them, so the walk stays
+            // inside the loop
+            let mut in_loop = vec![false; cfg.len()];
+            in_loop[block] = true;
+            let mut worklist = latches.clone();
+            while let Some(member) = worklist.pop() {
+                if in_loop[member] {
+                    continue;
+                }
+                in_loop[member] = true;
+                worklist.extend(cfg.predecessors(member));
+            }
+            let blocks = (0..cfg.len()).filter(|&b| in_loop[b]).collect();
+            loops.push(Loop {
+                header: block,
+                blocks,
+                latches,
+                parent: None,
+                depth: 1,
+            });
+        }
+
+        // A loop nested in another has fewer blocks, so sorting by size puts outer loops first
+        loops.sort_by(|a, b| {
+            b.blocks
+                .len()
+                .cmp(&a.blocks.len())
+                .then(a.header.cmp(&b.header))
+        });
+        for index in 0..loops.len() {
+            // Of the earlier loops containing this header, the last one is the innermost
+            let parent = (0..index)
+                .rev()
+                .find(|&outer| loops[outer].contains(loops[index].header));
+            loops[index].parent = parent;
+            loops[index].depth = parent.map_or(1, |parent| loops[parent].depth + 1);
+        }
+
+        let mut innermost = vec![None; cfg.len()];
+        for (index, natural_loop) in loops.iter().enumerate() {
+            for &block in &natural_loop.blocks {
+                innermost[block] = Some(index);
+            }
+        }
+
+        LoopInfo { loops, innermost }
+    }
+
+    pub fn loops(&self) -> &[Loop] {
+        &self.loops
+    }
+
+    /// Index of the innermost loop containing `block`, if any
+    pub fn innermost_loop(&self, block: usize) -> Option<usize> {
+        self.innermost[block]
+    }
+
+    /// Number of loops containing `block`, which is the loop depth of each of its instructions
+    pub fn loop_depth(&self, block: usize) -> usize {
+        self.innermost[block].map_or(0, |index| self.loops[index].depth)
+    }
+
+    pub fn is_header(&self, block: usize) -> bool {
+        self.loops
+            .iter()
+            .any(|natural_loop| natural_loop.header == block)
+    }
+
+    /// Every back edge, as (latch, header)
+    pub fn back_edges(&self) -> Vec<(usize, usize)> {
+        self.loops
+            .iter()
+            .flat_map(|natural_loop| {
+                natural_loop
+                    .latches
+                    .iter()
+                    .map(move |&latch| (latch, natural_loop.header))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,1149 @@
+//! 6502 code generation. Besides the accumulator and the X and Y index registers, the 6502
+//! has nothing to keep values in, so temps live in memory: in a window of the zero page, which
+//! is a byte shorter and a cycle quicker to reach, and past the end of it, in a spill area in
+//! absolute memory. Ints are four bytes, little-endian, chars one, and strings the two-byte
+//! address of their first character. Doubles aren't supported.
+//!
+//! Every byte a function's temps take is callee-saved: the function pushes the bytes it uses
+//! on a software stack before it writes them, and pops them back before it returns. Arguments
+//! are passed in an area in absolute memory, four bytes each, and values returned in four
+//! zero-page bytes the window starts with. Multiplying, dividing and printing call routines
+//! of the runtime, which programs include only as they need them.
+
+pub mod container;
+pub mod encoding;
+pub mod runtime;
+mod zero_page;
+
+use super::context::{
+    AbstractAssemblyInstruction, Arithmetic, AsmLabel, Condition, Context, Conversion, Dest,
+    Global, Operand, ShiftKind,
+};
+use super::register_allocator::RegisterDescription;
+use crate::parser::{BinOp, FormatSpec, UnOp};
+use crate::sema::Type;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::ops::RangeInclusive;
+use zero_page::Allocation;
+
+/// Symbol of the area arguments are passed in
+pub const ARGUMENTS: &str = "c0_args";
+/// Symbol of the area in absolute memory temps are spilled to
+pub const SPILL_AREA: &str = "c0_spill";
+/// Symbol of the software stack
+pub const STACK: &str = "c0_stack";
+/// Symbol of the end of the software stack, where the stack pointer starts
+pub const STACK_END: &str = "c0_stack_end";
+/// Bytes of the software stack
+pub const STACK_SIZE: usize = 1024;
+/// Symbol of the byte the startup code keeps the hardware stack pointer in, for aborting
+pub const EXIT_STACK_POINTER: &str = "c0_exit_sp";
+/// Where a failed contract jumps to end the program
+pub const ABORT: &str = "c0_abort";
+/// Where the program starts, which calls `main`
+pub const START: &str = "c0_start";
+/// Symbol of the copy of the zero-page window the startup code restores
+pub const SAVED_ZERO_PAGE: &str = "c0_saved_zp";
+/// The NES's reset handler
+pub const RESET: &str = "c0_reset";
+/// The NES's handler of both kinds of interrupt
+pub const INTERRUPT: &str = "c0_interrupt";
+
+/// The zero-page bytes the backend keeps its own state in, which the window starts with. The
+/// rest of the window is the registers temps are assigned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZeroPage {
+    start: u8,
+    /// Bytes of the whole window
+    size: usize,
+}
+
+impl ZeroPage {
+    /// Bytes the backend's own state takes
+    pub const RESERVED: usize = 7;
+
+    /// The state at the start of `window`, if it's big enough to hold it
+    pub fn new(window: &RangeInclusive<u8>) -> Option<Self> {
+        let size = window.clone().count();
+        (size >= Self::RESERVED).then_some(ZeroPage {
+            start: *window.start(),
+            size,
+        })
+    }
+
+    /// The first byte of the window
+    pub fn start(self) -> u8 {
+        self.start
+    }
+
+    /// Bytes of the whole window
+    pub fn size(self) -> usize {
+        self.size
+    }
+
+    /// Two bytes pointing at the top of the software stack, which grows down
+    pub fn stack_pointer(self) -> u8 {
+        self.start
+    }
+
+    /// Four bytes a function returns its value in
+    pub fn result(self) -> u8 {
+        self.start + 2
+    }
+
+    /// Outcome of the last comparison: $ff if the left operand was less, 0 if they were equal
+    /// and 1 if it was greater
+    pub fn ordering(self) -> u8 {
+        self.start + 6
+    }
+}
+
+/// The registers of the zero-page `window`, by their addresses: every byte but those of the
+/// backend's own state, all callee-saved
+pub fn register_description(window: &RangeInclusive<u8>) -> RegisterDescription {
+    let first = *window.start() as usize + ZeroPage::RESERVED;
+    let int: Vec<usize> = (first..=*window.end() as usize).collect();
+    RegisterDescription {
+        callee_saved: int.clone(),
+        int,
+        double: Vec::new(),
+    }
+}
+
+/// The 6502's instructions, all 56 of them, since inline assembly may use any
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mnemonic {
+    Adc,
+    And,
+    Asl,
+    Bcc,
+    Bcs,
+    Beq,
+    Bit,
+    Bmi,
+    Bne,
+    Bpl,
+    Brk,
+    Bvc,
+    Bvs,
+    Clc,
+    Cld,
+    Cli,
+    Clv,
+    Cmp,
+    Cpx,
+    Cpy,
+    Dec,
+    Dex,
+    Dey,
+    Eor,
+    Inc,
+    Inx,
+    Iny,
+    Jmp,
+    Jsr,
+    Lda,
+    Ldx,
+    Ldy,
+    Lsr,
+    Nop,
+    Ora,
+    Pha,
+    Php,
+    Pla,
+    Plp,
+    Rol,
+    Ror,
+    Rti,
+    Rts,
+    Sbc,
+    Sec,
+    Sed,
+    Sei,
+    Sta,
+    Stx,
+    Sty,
+    Tax,
+    Tay,
+    Tsx,
+    Txa,
+    Txs,
+    Tya,
+}
+
+impl Mnemonic {
+    pub const ALL: [Mnemonic; 56] = [
+        Mnemonic::Adc,
+        Mnemonic::And,
+        Mnemonic::Asl,
+        Mnemonic::Bcc,
+        Mnemonic::Bcs,
+        Mnemonic::Beq,
+        Mnemonic::Bit,
+        Mnemonic::Bmi,
+        Mnemonic::Bne,
+        Mnemonic::Bpl,
+        Mnemonic::Brk,
+        Mnemonic::Bvc,
+        Mnemonic::Bvs,
+        Mnemonic::Clc,
+        Mnemonic::Cld,
+        Mnemonic::Cli,
+        Mnemonic::Clv,
+        Mnemonic::Cmp,
+        Mnemonic::Cpx,
+        Mnemonic::Cpy,
+        Mnemonic::Dec,
+        Mnemonic::Dex,
+        Mnemonic::Dey,
+        Mnemonic::Eor,
+        Mnemonic::Inc,
+        Mnemonic::Inx,
+        Mnemonic::Iny,
+        Mnemonic::Jmp,
+        Mnemonic::Jsr,
+        Mnemonic::Lda,
+        Mnemonic::Ldx,
+        Mnemonic::Ldy,
+        Mnemonic::Lsr,
+        Mnemonic::Nop,
+        Mnemonic::Ora,
+        Mnemonic::Pha,
+        Mnemonic::Php,
+        Mnemonic::Pla,
+        Mnemonic::Plp,
+        Mnemonic::Rol,
+        Mnemonic::Ror,
+        Mnemonic::Rti,
+        Mnemonic::Rts,
+        Mnemonic::Sbc,
+        Mnemonic::Sec,
+        Mnemonic::Sed,
+        Mnemonic::Sei,
+        Mnemonic::Sta,
+        Mnemonic::Stx,
+        Mnemonic::Sty,
+        Mnemonic::Tax,
+        Mnemonic::Tay,
+        Mnemonic::Tsx,
+        Mnemonic::Txa,
+        Mnemonic::Txs,
+        Mnemonic::Tya,
+    ];
+
+    /// True for the branches, which take a target relative to the next instruction
+    pub fn is_branch(self) -> bool {
+        matches!(
+            self,
+            Mnemonic::Bcc
+                | Mnemonic::Bcs
+                | Mnemonic::Beq
+                | Mnemonic::Bmi
+                | Mnemonic::Bne
+                | Mnemonic::Bpl
+                | Mnemonic::Bvc
+                | Mnemonic::Bvs
+        )
+    }
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+/// A byte taken as an immediate operand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Byte {
+    Literal(u8),
+    /// Low byte of a symbol's address
+    Low(String),
+    /// High byte of a symbol's address
+    High(String),
+}
+
+/// A byte of memory: one at a fixed address, like those of the zero page, or one past a symbol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Fixed(u16),
+    Symbol(String, u16),
+}
+
+impl Address {
+    /// The address `bytes` further on
+    pub fn offset(&self, bytes: u16) -> Address {
+        match self {
+            Address::Fixed(address) => Address::Fixed(address + bytes),
+            Address::Symbol(symbol, offset) => Address::Symbol(symbol.clone(), offset + bytes),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Fixed(address) if *address < 0x100 => write!(f, "${:02x}", address),
+            Address::Fixed(address) => write!(f, "${:04x}", address),
+            Address::Symbol(symbol, 0) => write!(f, "{}", symbol),
+            Address::Symbol(symbol, offset) => write!(f, "{}+{}", symbol, offset),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum M6502Operand {
+    Implied,
+    Immediate(Byte),
+    /// The byte at an address: zero-page addressing for a fixed address under $100, absolute
+    /// otherwise
+    Memory(Address),
+    /// The byte at an address plus X
+    IndexedX(Address),
+    /// The byte at an address plus Y
+    IndexedY(Address),
+    /// The byte at the address held in the two zero-page bytes X past this one
+    IndirectX(u8),
+    /// The byte Y past the address held in the two zero-page bytes at this one
+    IndirectY(u8),
+    /// The address held in the two bytes at this one, which `jmp` jumps to
+    Indirect(Address),
+    /// A label jumped or branched to, or a function called
+    Target(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum M6502Instruction {
+    Op(Mnemonic, M6502Operand),
+    Label(String),
+    /// Inline assembly, its operands already written in
+    Asm(String),
+}
+
+/// A function, as the 6502 instructions it compiles into
+#[derive(Debug)]
+pub struct M6502Function {
+    pub symbol: String,
+    /// True if the function is `static`, and so not exported
+    pub is_static: bool,
+    pub instructions: Vec<M6502Instruction>,
+    /// Bytes of the argument area the function reads or writes
+    pub argument_bytes: usize,
+    /// Bytes of the spill area the function uses
+    pub spill_bytes: usize,
+    /// Bytes of the runtime's scratch area the function uses
+    pub scratch_bytes: usize,
+}
+
+/// Symbol of the function or global `name`: the name after an underscore, as C compilers for
+/// the 6502 name them, so that they can't collide with the backend's own
+pub fn symbol(name: &str) -> String {
+    format!("_{}", name)
+}
+
+pub fn string_symbol(index: usize) -> String {
+    format!("c0_string{}", index)
+}
+
+/// Bytes a value of type `ty` takes
+pub fn size_of(ty: Type) -> usize {
+    match ty {
+        Type::Int => 4,
+        Type::Char => 1,
+        Type::String => 2,
+        Type::Double | Type::Void => 0,
+    }
+}
+
+/// Selects the 6502 instructions for `context`, keeping its temps in the zero-page registers
+/// of `registers` as far as they go. Fails on what the 6502 has no instructions for.
+pub fn select_instructions(
+    context: &Context,
+    registers: &RegisterDescription,
+    zero_page: ZeroPage,
+) -> io::Result<M6502Function> {
+    for instruction in &context.instructions {
+        check_supported(context, instruction)?;
+    }
+    let allocation = zero_page::allocate(context, registers);
+    let mut selector = Selector {
+        temp_types: context.temp_types(),
+        allocation: &allocation,
+        zero_page,
+        instructions: Vec::new(),
+        next_label: context.label_count(),
+        argument_bytes: 4 * context.params,
+    };
+    selector.prologue(context.params);
+    for instruction in &context.instructions {
+        selector.select(instruction);
+    }
+    Ok(M6502Function {
+        symbol: symbol(&context.name),
+        is_static: context.is_static,
+        instructions: selector.instructions,
+        argument_bytes: selector.argument_bytes,
+        spill_bytes: allocation.spill_bytes,
+        scratch_bytes: 0,
+    })
+}
+
+/// The code the program starts at, which calls `main` and returns what it does, and the
+/// abort that a failed contract jumps to, which returns from there at once. With
+/// `save_zero_page`, the window is saved first and restored before returning, for a caller
+/// like BASIC that keeps its own state there. The globals in `ram_globals`, which `variables`
+/// reserves, are given their initial values before `main` is called.
+pub fn startup(zero_page: ZeroPage, save_zero_page: bool, ram_globals: &[Global]) -> M6502Function {
+    use M6502Operand::{Immediate, Implied, IndexedY, Memory, Target};
+    let op = M6502Instruction::Op;
+    let stack_pointer = Address::Fixed(zero_page.stack_pointer() as u16);
+    let exit_stack_pointer = Address::Symbol(EXIT_STACK_POINTER.to_string(), 0);
+    let window = Address::Fixed(zero_page.start() as u16);
+    let saved = Address::Symbol(SAVED_ZERO_PAGE.to_string(), 0);
+    // Copies the window between it and the saved copy, a byte at a time counting up in Y
+    let copy = |top: &str, from: &Address, to: &Address| {
+        vec![
+            op(Mnemonic::Ldy, Immediate(Byte::Literal(0))),
+            M6502Instruction::Label(top.to_string()),
+            op(Mnemonic::Lda, IndexedY(from.clone())),
+            op(Mnemonic::Sta, IndexedY(to.clone())),
+            op(Mnemonic::Iny, Implied),
+            op(
+                Mnemonic::Cpy,
+                Immediate(Byte::Literal(zero_page.size() as u8)),
+            ),
+            op(Mnemonic::Bne, Target(top.to_string())),
+        ]
+    };
+
+    let mut instructions = vec![
+        op(Mnemonic::Tsx, Implied),
+        op(Mnemonic::Stx, Memory(exit_stack_pointer.clone())),
+    ];
+    if save_zero_page {
+        instructions.extend(copy("@L0", &window, &saved));
+    }
+    for global in ram_globals {
+        let bytes = match &global.value {
+            Operand::Const(value) => (*value as i32).to_le_bytes().map(Byte::Literal).to_vec(),
+            Operand::Str(index) => vec![
+                Byte::Low(string_symbol(*index)),
+                Byte::High(string_symbol(*index)),
+            ],
+            Operand::Double(_) | Operand::Var(_) => {
+                unreachable!("globals are initialized with ints, chars and strings")
+            }
+        };
+        let start = Address::Symbol(symbol(&global.name), 0);
+        for (index, byte) in bytes.into_iter().enumerate() {
+            instructions.push(op(Mnemonic::Lda, Immediate(byte)));
+            instructions.push(op(Mnemonic::Sta, Memory(start.offset(index as u16))));
+        }
+    }
+    instructions.extend([
+        op(Mnemonic::Lda, Immediate(Byte::Low(STACK_END.to_string()))),
+        op(Mnemonic::Sta, Memory(stack_pointer.clone())),
+        op(Mnemonic::Lda, Immediate(Byte::High(STACK_END.to_string()))),
+        op(Mnemonic::Sta, Memory(stack_pointer.offset(1))),
+        op(Mnemonic::Jsr, Target(symbol("main"))),
+        // Returning from `main` leaves the stack pointer where an abort resets it to
+        M6502Instruction::Label(ABORT.to_string()),
+        op(Mnemonic::Ldx, Memory(exit_stack_pointer)),
+        op(Mnemonic::Txs, Implied),
+    ]);
+    if save_zero_page {
+        instructions.extend(copy("@L1", &saved, &window));
+    }
+    instructions.push(op(Mnemonic::Rts, Implied));
+    M6502Function {
+        symbol: START.to_string(),
+        is_static: false,
+        instructions,
+        argument_bytes: 0,
+        spill_bytes: 0,
+        scratch_bytes: 0,
+    }
+}
+
+/// The NES's reset handler, which sets up the CPU, runs the program and then waits forever,
+/// and its interrupt handler, which returns at once
+pub fn nes_handlers() -> M6502Function {
+    use M6502Operand::{Immediate, Implied, Target};
+    let op = M6502Instruction::Op;
+    let instructions = vec![
+        op(Mnemonic::Sei, Implied),
+        op(Mnemonic::Cld, Implied),
+        op(Mnemonic::Ldx, Immediate(Byte::Literal(0xff))),
+        op(Mnemonic::Txs, Implied),
+        op(Mnemonic::Jsr, Target(START.to_string())),
+        M6502Instruction::Label("@L0".to_string()),
+        op(Mnemonic::Jmp, Target("@L0".to_string())),
+        M6502Instruction::Label(INTERRUPT.to_string()),
+        op(Mnemonic::Rti, Implied),
+    ];
+    M6502Function {
+        symbol: RESET.to_string(),
+        is_static: true,
+        instructions,
+        argument_bytes: 0,
+        spill_bytes: 0,
+        scratch_bytes: 0,
+    }
+}
+
+/// The areas of memory the program keeps its variables in, and their sizes: those of the
+/// startup code, and as much argument, spill and scratch area as the function needing the
+/// most of them, and the `ram_globals`. The software stack comes last, with a label at its end.
+pub fn variables(
+    functions: &[M6502Function],
+    ram_globals: &[Global],
+    zero_page: ZeroPage,
+    save_zero_page: bool,
+) -> Vec<(String, usize)> {
+    let arguments = functions.iter().map(|f| f.argument_bytes).max();
+    let spills = functions.iter().map(|f| f.spill_bytes).max();
+    let scratch = functions.iter().map(|f| f.scratch_bytes).max();
+    let mut variables = vec![(EXIT_STACK_POINTER.to_string(), 1)];
+    if save_zero_page {
+        variables.push((SAVED_ZERO_PAGE.to_string(), zero_page.size()));
+    }
+    for global in ram_globals {
+        let size = match global.value {
+            Operand::Str(_) => 2,
+            _ => 4,
+        };
+        variables.push((symbol(&global.name), size));
+    }
+    let areas = [
+        (ARGUMENTS, arguments),
+        (SPILL_AREA, spills),
+        (runtime::SCRATCH, scratch),
+    ];
+    for (symbol, size) in areas {
+        if let Some(size) = size.filter(|&size| size > 0) {
+            variables.push((symbol.to_string(), size));
+        }
+    }
+    variables.extend([(STACK.to_string(), STACK_SIZE), (STACK_END.to_string(), 0)]);
+    variables
+}
+
+/// Fails if `instruction` needs what the 6502 backend can't compile
+fn check_supported(context: &Context, instruction: &AbstractAssemblyInstruction) -> io::Result<()> {
+    use AbstractAssemblyInstruction as A;
+    let unsupported = |what: &str| {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{}: the 6502 target can't compile {}", context.name, what),
+        ))
+    };
+    let doubles = instruction
+        .operands()
+        .iter()
+        .any(|operand| matches!(operand, Operand::Double(_)))
+        || instruction
+            .dest()
+            .is_some_and(|dest| context.dest_type(dest) == Type::Double);
+    match instruction {
+        _ if doubles => unsupported("doubles"),
+        A::BinOp {
+            arithmetic: Arithmetic::Double,
+            ..
+        }
+        | A::UnOp {
+            arithmetic: Arithmetic::Double,
+            ..
+        }
+        | A::Compare {
+            arithmetic: Arithmetic::Double,
+            ..
+        }
+        | A::Convert {
+            conversion: Conversion::I2D | Conversion::D2I,
+            ..
+        } => unsupported("doubles"),
+        A::Print {
+            spec: FormatSpec::Double,
+            ..
+        } => unsupported("doubles"),
+        A::Scan { .. } => unsupported("`scan`, since the machine has no input"),
+        A::Asm { constraints, .. } if constraints.names_registers() => {
+            unsupported("inline assembly naming registers")
+        }
+        _ => Ok(()),
+    }
+}
+
+struct Selector<'a> {
+    temp_types: &'a HashMap<usize, Type>,
+    allocation: &'a Allocation,
+    zero_page: ZeroPage,
+    instructions: Vec<M6502Instruction>,
+    /// Labels from this number on are free for the selector's own branches
+    next_label: usize,
+    argument_bytes: usize,
+}
+
+impl Selector<'_> {
+    fn emit(&mut self, mnemonic: Mnemonic, operand: M6502Operand) {
+        self.instructions
+            .push(M6502Instruction::Op(mnemonic, operand));
+    }
+
+    fn implied(&mut self, mnemonic: Mnemonic) {
+        self.emit(mnemonic, M6502Operand::Implied);
+    }
+
+    fn immediate(&mut self, mnemonic: Mnemonic, byte: u8) {
+        self.emit(mnemonic, M6502Operand::Immediate(Byte::Literal(byte)));
+    }
+
+    fn memory(&mut self, mnemonic: Mnemonic, address: Address) {
+        self.emit(mnemonic, M6502Operand::Memory(address));
+    }
+
+    fn branch(&mut self, mnemonic: Mnemonic, label: &str) {
+        self.emit(mnemonic, M6502Operand::Target(label.to_string()));
+    }
+
+    fn label(&mut self, label: &str) {
+        self.instructions
+            .push(M6502Instruction::Label(label.to_string()));
+    }
+
+    /// A label for the selector's own branches, local to the function
+    fn new_label(&mut self) -> String {
+        self.next_label += 1;
+        asm_label(AsmLabel(self.next_label - 1))
+    }
+
+    fn zero_page(&self, address: u8) -> Address {
+        Address::Fixed(address as u16)
+    }
+
+    fn argument(&self, index: usize, byte: usize) -> Address {
+        Address::Symbol(ARGUMENTS.to_string(), (4 * index + byte) as u16)
+    }
+
+    fn size(&self, dest: &Dest) -> usize {
+        match dest {
+            Dest::Temp(temp) => size_of(self.temp_types[temp]),
+            Dest::Register(_) => unreachable!("registers are only assigned by the backend"),
+        }
+    }
+
+    /// Bytes of `operand`, or `None` for a constant, which takes whatever size it's used at
+    fn operand_size(&self, operand: &Operand) -> Option<usize> {
+        match operand {
+            Operand::Var(dest) => Some(self.size(dest)),
+            Operand::Str(_) => Some(2),
+            Operand::Const(_) | Operand::Double(_) => None,
+        }
+    }
+
+    fn location(&self, dest: &Dest) -> Address {
+        match dest {
+            Dest::Temp(temp) => self.allocation.locations[temp].clone(),
+            Dest::Register(_) => unreachable!("registers are only assigned by the backend"),
+        }
+    }
+
+    /// Byte `index` of `operand`, zero past its end, as the operand of a load
+    fn byte(&self, operand: &Operand, index: usize) -> M6502Operand {
+        match operand {
+            Operand::Const(value) => {
+                M6502Operand::Immediate(Byte::Literal((*value as i32 >> (8 * index)) as u8))
+            }
+            Operand::Str(string) => M6502Operand::Immediate(match index {
+                0 => Byte::Low(string_symbol(*string)),
+                1 => Byte::High(string_symbol(*string)),
+                _ => Byte::Literal(0),
+            }),
+            Operand::Var(dest) if index < self.size(dest) => {
+                M6502Operand::Memory(self.location(dest).offset(index as u16))
+            }
+            Operand::Var(_) => M6502Operand::Immediate(Byte::Literal(0)),
+            Operand::Double(_) => unreachable!("doubles are rejected before selection"),
+        }
+    }
+
+    /// Byte `index` of `dest`
+    fn dest_byte(&self, dest: &Dest, index: usize) -> Address {
+        self.location(dest).offset(index as u16)
+    }
+
+    /// Copies `src` into `dest` a byte at a time
+    fn copy(&mut self, dest: &Dest, src: &Operand) {
+        for index in 0..self.size(dest) {
+            self.emit(Mnemonic::Lda, self.byte(src, index));
+            self.memory(Mnemonic::Sta, self.dest_byte(dest, index));
+        }
+    }
+
+    /// Pushes the callee-saved bytes the function uses, then receives its parameters
+    fn prologue(&mut self, params: usize) {
+        for (start, size) in self.saved_areas() {
+            self.push(start, size);
+        }
+        for param in 0..params {
+            // A parameter that's never read has no place to go
+            let Some(location) = self.allocation.locations.get(&param).cloned() else {
+                continue;
+            };
+            for index in 0..size_of(self.temp_types[&param]) {
+                self.memory(Mnemonic::Lda, self.argument(param, index));
+                self.memory(Mnemonic::Sta, location.offset(index as u16));
+            }
+        }
+    }
+
+    /// Pops what the prologue pushed, and returns
+    fn epilogue(&mut self) {
+        for (start, size) in self.saved_areas().into_iter().rev() {
+            self.pop(start, size);
+        }
+        self.implied(Mnemonic::Rts);
+    }
+
+    /// Runs of at most 255 bytes the function has to save: the zero-page registers it uses,
+    /// then the spill area it uses
+    fn saved_areas(&self) -> Vec<(Address, usize)> {
+        let mut areas = Vec::new();
+        let mut add = |start: Address, size: usize| {
+            for offset in (0..size).step_by(255) {
+                areas.push((start.offset(offset as u16), (size - offset).min(255)));
+            }
+        };
+        if let Some(first) = self.allocation.first_register {
+            add(self.zero_page(first), self.allocation.zero_page_bytes);
+        }
+        add(
+            Address::Symbol(SPILL_AREA.to_string(), 0),
+            self.allocation.spill_bytes,
+        );
+        areas
+    }
+
+    /// Pushes the `size` bytes at `start` on the software stack
+    fn push(&mut self, start: Address, size: usize) {
+        let stack_pointer = self.zero_page(self.zero_page.stack_pointer());
+        let skip = self.new_label();
+        self.implied(Mnemonic::Sec);
+        self.memory(Mnemonic::Lda, stack_pointer.clone());
+        self.immediate(Mnemonic::Sbc, size as u8);
+        self.memory(Mnemonic::Sta, stack_pointer.clone());
+        self.branch(Mnemonic::Bcs, &skip);
+        self.memory(Mnemonic::Dec, stack_pointer.offset(1));
+        self.label(&skip);
+        self.copy_loop(size, M6502Operand::IndexedY(start), self.stack_operand());
+    }
+
+    /// Pops `size` bytes off the software stack back to `start`
+    fn pop(&mut self, start: Address, size: usize) {
+        let stack_pointer = self.zero_page(self.zero_page.stack_pointer());
+        let skip = self.new_label();
+        self.copy_loop(size, self.stack_operand(), M6502Operand::IndexedY(start));
+        self.implied(Mnemonic::Clc);
+        self.memory(Mnemonic::Lda, stack_pointer.clone());
+        self.immediate(Mnemonic::Adc, size as u8);
+        self.memory(Mnemonic::Sta, stack_pointer.clone());
+        self.branch(Mnemonic::Bcc, &skip);
+        self.memory(Mnemonic::Inc, stack_pointer.offset(1));
+        self.label(&skip);
+    }
+
+    fn stack_operand(&self) -> M6502Operand {
+        M6502Operand::IndirectY(self.zero_page.stack_pointer())
+    }
+
+    /// Copies `size` bytes, indexed by Y, from `from` to `to`
+    fn copy_loop(&mut self, size: usize, from: M6502Operand, to: M6502Operand) {
+        let top = self.new_label();
+        self.immediate(Mnemonic::Ldy, 0);
+        self.label(&top);
+        self.emit(Mnemonic::Lda, from);
+        self.emit(Mnemonic::Sta, to);
+        self.implied(Mnemonic::Iny);
+        self.immediate(Mnemonic::Cpy, size as u8);
+        self.branch(Mnemonic::Bne, &top);
+    }
+
+    fn select(&mut self, instruction: &AbstractAssemblyInstruction) {
+        use AbstractAssemblyInstruction as A;
+        match instruction {
+            A::Mov { dest, src } => self.copy(dest, src),
+            A::BinOp {
+                op: op @ (BinOp::Add | BinOp::Sub),
+                dest,
+                src1,
+                src2,
+                ..
+            } => {
+                let (carry, mnemonic) = match op {
+                    BinOp::Add => (Mnemonic::Clc, Mnemonic::Adc),
+                    _ => (Mnemonic::Sec, Mnemonic::Sbc),
+                };
+                self.implied(carry);
+                for index in 0..self.size(dest) {
+                    self.emit(Mnemonic::Lda, self.byte(src1, index));
+                    self.emit(mnemonic, self.byte(src2, index));
+                    self.memory(Mnemonic::Sta, self.dest_byte(dest, index));
+                }
+            }
+            A::BinOp {
+                op: op @ (BinOp::Mul | BinOp::Div),
+                dest,
+                src1,
+                src2,
+                ..
+            } => {
+                let routine = match op {
+                    BinOp::Mul => runtime::MULTIPLY,
+                    _ => runtime::DIVIDE,
+                };
+                self.call(routine, &[src1.clone(), src2.clone()], Some(dest));
+            }
+            A::BinOp {
+                op,
+                dest,
+                src1,
+                src2,
+                ..
+            } => {
+                let condition = comparison_condition(*op);
+                self.compare(src1, src2);
+                self.set_if(dest, &condition);
+            }
+            A::UnOp {
+                op: UnOp::Neg,
+                dest,
+                src,
+                ..
+            } => {
+                self.implied(Mnemonic::Sec);
+                for index in 0..self.size(dest) {
+                    self.immediate(Mnemonic::Lda, 0);
+                    self.emit(Mnemonic::Sbc, self.byte(src, index));
+                    self.memory(Mnemonic::Sta, self.dest_byte(dest, index));
+                }
+            }
+            A::UnOp {
+                op: UnOp::BitNot,
+                dest,
+                src,
+                ..
+            } => {
+                for index in 0..self.size(dest) {
+                    self.emit(Mnemonic::Lda, self.byte(src, index));
+                    self.immediate(Mnemonic::Eor, 0xff);
+                    self.memory(Mnemonic::Sta, self.dest_byte(dest, index));
+                }
+            }
+            A::UnOp {
+                op: UnOp::Not,
+                dest,
+                src,
+                ..
+            } => {
+                // 1 if every byte is zero, else 0
+                let nonzero = self.new_label();
+                self.immediate(Mnemonic::Ldx, 0);
+                self.emit(Mnemonic::Lda, self.byte(src, 0));
+                for index in 1..self.operand_size(src).unwrap_or(4) {
+                    self.emit(Mnemonic::Ora, self.byte(src, index));
+                }
+                self.branch(Mnemonic::Bne, &nonzero);
+                self.implied(Mnemonic::Inx);
+                self.label(&nonzero);
+                self.store_x_widened(dest);
+            }
+            A::Convert { dest, src, .. } => self.copy(dest, src),
+            A::Shift {
+                kind,
+                dest,
+                src,
+                amount,
+            } => self.shift(*kind, dest, src, *amount),
+            A::Compare { left, right, .. } => self.compare(left, right),
+            A::SetIf { dest, condition } => self.set_if(dest, condition),
+            A::JmpCondition {
+                condition,
+                tgt_true,
+                tgt_false,
+            } => {
+                let taken = self.new_label();
+                self.memory(Mnemonic::Lda, self.zero_page(self.zero_page.ordering()));
+                self.branch_if(condition, &taken);
+                self.branch(Mnemonic::Jmp, &asm_label(*tgt_false));
+                self.label(&taken);
+                self.branch(Mnemonic::Jmp, &asm_label(*tgt_true));
+            }
+            A::Jmp(label) => self.branch(Mnemonic::Jmp, &asm_label(*label)),
+            A::Lbl(label) => self.label(&asm_label(*label)),
+            A::Loc { .. } => {}
+            A::Call {
+                dest,
+                function,
+                args,
+            } => self.call(&symbol(function), args, dest.as_ref()),
+            A::Load { dest, global } => {
+                let global = Address::Symbol(symbol(global), 0);
+                for index in 0..self.size(dest) {
+                    self.memory(Mnemonic::Lda, global.offset(index as u16));
+                    self.memory(Mnemonic::Sta, self.dest_byte(dest, index));
+                }
+            }
+            A::Store { global, src } => {
+                // A char is kept in an int's four bytes, whose others stay zero
+                let global = Address::Symbol(symbol(global), 0);
+                for index in 0..self.operand_size(src).unwrap_or(4) {
+                    self.emit(Mnemonic::Lda, self.byte(src, index));
+                    self.memory(Mnemonic::Sta, global.offset(index as u16));
+                }
+            }
+            A::Asm {
+                template,
+                output,
+                inputs,
+                ..
+            } => {
+                let output = output.iter().map(|dest| self.location(dest).to_string());
+                let inputs = inputs.iter().map(|input| match input {
+                    Operand::Var(dest) => self.location(dest).to_string(),
+                    Operand::Const(value) => (*value as i32).to_string(),
+                    Operand::Str(string) => string_symbol(*string),
+                    Operand::Double(_) => unreachable!("doubles are rejected before selection"),
+                });
+                let operands: Vec<String> = output.chain(inputs).collect();
+                self.instructions
+                    .push(M6502Instruction::Asm(substitute_operands(
+                        template, &operands,
+                    )));
+            }
+            A::Abort => self.branch(Mnemonic::Jmp, ABORT),
+            A::Return(operand) => {
+                for index in 0..4 {
+                    self.emit(Mnemonic::Lda, self.byte(operand, index));
+                    self.memory(
+                        Mnemonic::Sta,
+                        self.zero_page(self.zero_page.result() + index as u8),
+                    );
+                }
+                self.epilogue();
+            }
+            A::ReturnVoid => self.epilogue(),
+            A::Phi { .. } => unreachable!("phis are removed before instruction selection"),
+            A::Scan { .. } => unreachable!("reading is rejected before instruction selection"),
+            A::Print { spec, src } => self.call(
+                runtime::print_routine(*spec),
+                std::slice::from_ref(src),
+                None,
+            ),
+        }
+    }
+
+    /// Passes `args` to the function or runtime routine `symbol`, four bytes each, calls it,
+    /// and copies what it returns to `dest`
+    fn call(&mut self, symbol: &str, args: &[Operand], dest: Option<&Dest>) {
+        for (param, arg) in args.iter().enumerate() {
+            for index in 0..4 {
+                self.emit(Mnemonic::Lda, self.byte(arg, index));
+                self.memory(Mnemonic::Sta, self.argument(param, index));
+            }
+        }
+        self.argument_bytes = self.argument_bytes.max(4 * args.len());
+        self.branch(Mnemonic::Jsr, symbol);
+        if let Some(dest) = dest {
+            for index in 0..self.size(dest) {
+                self.memory(
+                    Mnemonic::Lda,
+                    self.zero_page(self.zero_page.result() + index as u8),
+                );
+                self.memory(Mnemonic::Sta, self.dest_byte(dest, index));
+            }
+        }
+    }
+
+    /// Stores X, which is 0 or 1, in `dest`, zeroing the rest of it
+    fn store_x_widened(&mut self, dest: &Dest) {
+        self.memory(Mnemonic::Stx, self.dest_byte(dest, 0));
+        if self.size(dest) > 1 {
+            self.immediate(Mnemonic::Lda, 0);
+            for index in 1..self.size(dest) {
+                self.memory(Mnemonic::Sta, self.dest_byte(dest, index));
+            }
+        }
+    }
+
+    /// Copies `src` into `dest` and shifts it there a bit at a time, counting down in X
+    fn shift(&mut self, kind: ShiftKind, dest: &Dest, src: &Operand, amount: u32) {
+        self.copy(dest, src);
+        if amount == 0 {
+            return;
+        }
+        let size = self.size(dest);
+        let top = self.new_label();
+        self.immediate(Mnemonic::Ldx, amount as u8);
+        self.label(&top);
+        match kind {
+            ShiftKind::Left => {
+                self.memory(Mnemonic::Asl, self.dest_byte(dest, 0));
+                for index in 1..size {
+                    self.memory(Mnemonic::Rol, self.dest_byte(dest, index));
+                }
+            }
+            ShiftKind::ArithmeticRight | ShiftKind::LogicalRight => {
+                let high = self.dest_byte(dest, size - 1);
+                if kind == ShiftKind::ArithmeticRight {
+                    // The sign bit goes into the carry, and is rotated back in
+                    self.memory(Mnemonic::Lda, high);
+                    self.immediate(Mnemonic::Cmp, 0x80);
+                    self.memory(Mnemonic::Ror, self.dest_byte(dest, size - 1));
+                } else {
+                    self.memory(Mnemonic::Lsr, high);
+                }
+                for index in (0..size - 1).rev() {
+                    self.memory(Mnemonic::Ror, self.dest_byte(dest, index));
+                }
+            }
+        }
+        self.implied(Mnemonic::Dex);
+        self.branch(Mnemonic::Bne, &top);
+    }
+
+    /// Compares `left` with `right`, leaving the outcome in the ordering byte. Ints compare
+    /// signed; chars and strings, which are never negative, unsigned.
+    fn compare(&mut self, left: &Operand, right: &Operand) {
+        let size = match (self.operand_size(left), self.operand_size(right)) {
+            (None, None) => 4,
+            (left, right) => left.max(right).unwrap_or(4),
+        };
+        let less = self.new_label();
+        let greater = self.new_label();
+        let done = self.new_label();
+        if size == 4 {
+            // The sign of left - right, corrected for overflow, says if left is less
+            let sign = self.new_label();
+            self.emit(Mnemonic::Lda, self.byte(left, 0));
+            self.emit(Mnemonic::Cmp, self.byte(right, 0));
+            for index in 1..size {
+                self.emit(Mnemonic::Lda, self.byte(left, index));
+                self.emit(Mnemonic::Sbc, self.byte(right, index));
+            }
+            self.branch(Mnemonic::Bvc, &sign);
+            self.immediate(Mnemonic::Eor, 0x80);
+            self.label(&sign);
+            self.branch(Mnemonic::Bmi, &less);
+            for index in 0..size {
+                self.emit(Mnemonic::Lda, self.byte(left, index));
+                self.emit(Mnemonic::Cmp, self.byte(right, index));
+                self.branch(Mnemonic::Bne, &greater);
+            }
+            self.immediate(Mnemonic::Lda, 0);
+            self.branch(Mnemonic::Beq, &done);
+        } else {
+            // From the highest byte down, the first that differs decides
+            let differ = self.new_label();
+            for index in (0..size).rev() {
+                self.emit(Mnemonic::Lda, self.byte(left, index));
+                self.emit(Mnemonic::Cmp, self.byte(right, index));
+                self.branch(Mnemonic::Bne, &differ);
+            }
+            self.immediate(Mnemonic::Lda, 0);
+            self.branch(Mnemonic::Beq, &done);
+            self.label(&differ);
+            self.branch(Mnemonic::Bcc, &less);
+        }
+        self.label(&greater);
+        self.immediate(Mnemonic::Lda, 1);
+        self.branch(Mnemonic::Bne, &done);
+        self.label(&less);
+        self.immediate(Mnemonic::Lda, 0xff);
+        self.label(&done);
+        self.memory(Mnemonic::Sta, self.zero_page(self.zero_page.ordering()));
+    }
+
+    /// Branches to `label` if `condition` holds for the ordering byte just loaded
+    fn branch_if(&mut self, condition: &Condition, label: &str) {
+        match condition {
+            Condition::Equal => self.branch(Mnemonic::Beq, label),
+            Condition::NotEqual => self.branch(Mnemonic::Bne, label),
+            Condition::Less => self.branch(Mnemonic::Bmi, label),
+            Condition::GreaterOrEqual => self.branch(Mnemonic::Bpl, label),
+            Condition::Greater => {
+                let equal = self.new_label();
+                self.branch(Mnemonic::Beq, &equal);
+                self.branch(Mnemonic::Bpl, label);
+                self.label(&equal);
+            }
+            Condition::LessOrEqual => {
+                self.branch(Mnemonic::Beq, label);
+                self.branch(Mnemonic::Bmi, label);
+            }
+        }
+    }
+
+    /// Sets `dest` to 1 if `condition` holds for the last comparison, else 0
+    fn set_if(&mut self, dest: &Dest, condition: &Condition) {
+        let holds = self.new_label();
+        self.immediate(Mnemonic::Ldx, 1);
+        self.memory(Mnemonic::Lda, self.zero_page(self.zero_page.ordering()));
+        self.branch_if(condition, &holds);
+        self.immediate(Mnemonic::Ldx, 0);
+        self.label(&holds);
+        self.store_x_widened(dest);
+    }
+}
+
+/// A label of the function, local to it
+pub fn asm_label(label: AsmLabel) -> String {
+    format!("@L{}", label.0)
+}
+
+fn comparison_condition(op: BinOp) -> Condition {
+    match op {
+        BinOp::Equal => Condition::Equal,
+        BinOp::NotEqual => Condition::NotEqual,
+        BinOp::Less => Condition::Less,
+        BinOp::LessEqual => Condition::LessOrEqual,
+        BinOp::Greater => Condition::Greater,
+        BinOp::GreaterEqual => Condition::GreaterOrEqual,
+        BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div => {
+            unreachable!("arithmetic is selected on its own")
+        }
+    }
+}
+
+/// Inline assembly's `template`, with each `%0`, `%1`, ... replaced by that operand as
+/// `operands` writes it, here the address of its first byte or a constant's value, and `%%`
+/// by `%`. Without operands, the template is written as is.
+pub(super) fn substitute_operands(template: &str, operands: &[String]) -> String {
+    if operands.is_empty() {
+        return template.to_string();
+    }
+    let mut substituted = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('%') {
+        substituted.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if let Some(after) = after.strip_prefix('%') {
+            substituted.push('%');
+            rest = after;
+        } else if let Some(operand) = after[..digits]
+            .parse()
+            .ok()
+            .and_then(|index: usize| operands.get(index))
+        {
+            substituted.push_str(operand);
+            rest = &after[digits..];
+        } else {
+            substituted.push('%');
+            rest = after;
+        }
+    }
+    substituted.push_str(rest);
+    substituted
+}
@@ -0,0 +1,106 @@
+//! The files 6502 programs are run from. A C64 program is a PRG file: the address it loads at,
+//! then a line of BASIC, `10 SYS 2061`, that runs the machine code right after it. A NES
+//! program is an iNES file for mapper 0: a header, then 32K of ROM at $8000 ending with the
+//! vectors the CPU starts and takes interrupts from, with the variables in the NES's 2K of RAM.
+
+use super::encoding::{assemble, items, Item};
+use super::{string_symbol, symbol, M6502Function, INTERRUPT, RESET, STACK_END};
+use crate::codegen::context::{Global, Operand, StringTable};
+use std::io;
+
+/// Where a C64 program loads, at the start of BASIC's memory
+const PRG_ORIGIN: u16 = 0x0801;
+/// Where BASIC's memory ends, under the BASIC ROM
+const PRG_END: u16 = 0xa000;
+/// Where the NES's ROM starts
+const NES_ORIGIN: u16 = 0x8000;
+/// Where the NES's RAM the variables go in starts, past the zero page and the CPU's stack
+const NES_VARIABLES: u16 = 0x0200;
+/// Where the NES's RAM ends
+const NES_RAM_END: u16 = 0x0800;
+/// Where the NMI, reset and IRQ vectors are
+const NES_VECTORS: u16 = 0xfffa;
+
+/// The items of the whole program: the functions, then the strings and the globals
+pub fn program_items(
+    functions: &[M6502Function],
+    globals: &[Global],
+    strings: &StringTable,
+) -> io::Result<Vec<Item>> {
+    let mut program = Vec::new();
+    for function in functions {
+        program.push(Item::Label(function.symbol.clone()));
+        program.extend(items(&function.instructions, &function.symbol)?);
+    }
+    for (index, string) in strings.iter() {
+        program.push(Item::Label(string_symbol(index)));
+        program.push(Item::Bytes(string.bytes().chain([0]).collect()));
+    }
+    for global in globals {
+        program.push(Item::Label(symbol(&global.name)));
+        program.push(match &global.value {
+            Operand::Const(value) => Item::Bytes((*value as i32).to_le_bytes().to_vec()),
+            Operand::Str(index) => Item::Word(string_symbol(*index)),
+            Operand::Double(_) | Operand::Var(_) => {
+                unreachable!("globals are initialized with ints, chars and strings")
+            }
+        });
+    }
+    Ok(program)
+}
+
+/// A C64 PRG file running `program`
+pub fn prg(program: Vec<Item>, variables: &[(String, usize)]) -> io::Result<Vec<u8>> {
+    // The BASIC line: the address of the next, its number, SYS's token and the address of
+    // the code after the line and the empty one ending the program
+    let start = PRG_ORIGIN + 12;
+    let mut stub = (PRG_ORIGIN + 10).to_le_bytes().to_vec();
+    stub.extend(10u16.to_le_bytes());
+    stub.push(0x9e);
+    stub.extend(start.to_string().bytes());
+    stub.extend([0, 0, 0]);
+    let items: Vec<Item> = std::iter::once(Item::Bytes(stub)).chain(program).collect();
+
+    let image = assemble(&items, PRG_ORIGIN, variables, None)?;
+    fits("BASIC's memory", image.symbol(STACK_END)?.into(), PRG_END)?;
+    let mut file = PRG_ORIGIN.to_le_bytes().to_vec();
+    file.extend(image.bytes);
+    Ok(file)
+}
+
+/// An iNES file running `program`, whose reset handler is `RESET`
+pub fn nes(program: Vec<Item>, variables: &[(String, usize)]) -> io::Result<Vec<u8>> {
+    let image = assemble(&program, NES_ORIGIN, variables, Some(NES_VARIABLES))?;
+    fits(
+        "the NES's RAM",
+        image.symbol(STACK_END)?.into(),
+        NES_RAM_END,
+    )?;
+    let rom_end = NES_ORIGIN as usize + image.bytes.len();
+    fits("32K of ROM", rom_end, NES_VECTORS)?;
+
+    let mut rom = vec![0xff; 0x8000];
+    rom[..image.bytes.len()].copy_from_slice(&image.bytes);
+    let interrupt = image.symbol(INTERRUPT)?.to_le_bytes();
+    let reset = image.symbol(RESET)?.to_le_bytes();
+    let vectors = (NES_VECTORS - NES_ORIGIN) as usize;
+    rom[vectors..].copy_from_slice(&[interrupt, reset, interrupt].concat());
+
+    // Two 16K banks of ROM, no character ROM, so the cartridge has RAM for it, and mapper 0
+    let mut file = b"NES\x1a".to_vec();
+    file.extend([2, 0, 0, 0]);
+    file.extend([0; 8]);
+    file.extend(rom);
+    Ok(file)
+}
+
+/// Fails if what ends at `end` goes past `limit`
+fn fits(memory: &str, end: usize, limit: u16) -> io::Result<()> {
+    if end > limit.into() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the program doesn't fit in {}", memory),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,476 @@
+//! Assembles 6502 instructions into machine code at a fixed address, in two passes: the first
+//! places the labels, and the second writes the instructions with their addresses. Nothing is
+//! left to a linker, so every symbol has to be defined here. Labels starting with `@` are local
+//! to the label before them, like ca65's cheap local labels.
+
+use super::{Address, Byte, M6502Instruction, M6502Operand, Mnemonic};
+use std::collections::HashMap;
+use std::io;
+
+/// Something taking up bytes of the image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Op(Mnemonic, M6502Operand),
+    /// Defines the symbol where the next item starts
+    Label(String),
+    Bytes(Vec<u8>),
+    /// The two-byte address of a symbol
+    Word(String),
+}
+
+/// Machine code, and the address of each symbol
+#[derive(Debug)]
+pub struct Image {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    pub symbols: HashMap<String, u16>,
+}
+
+impl Image {
+    pub fn symbol(&self, name: &str) -> io::Result<u16> {
+        self.symbols
+            .get(name)
+            .copied()
+            .ok_or_else(|| invalid(format!("undefined symbol '{}'", name)))
+    }
+}
+
+/// How an instruction finds its operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+impl Mode {
+    /// Bytes of operand after the opcode
+    fn size(self) -> u16 {
+        match self {
+            Mode::Implied | Mode::Accumulator => 0,
+            Mode::Immediate
+            | Mode::ZeroPage
+            | Mode::ZeroPageX
+            | Mode::ZeroPageY
+            | Mode::IndirectX
+            | Mode::IndirectY
+            | Mode::Relative => 1,
+            Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY | Mode::Indirect => 2,
+        }
+    }
+}
+
+/// Opcode of `mnemonic` in `mode`, if it has one
+fn opcode(mnemonic: Mnemonic, mode: Mode) -> Option<u8> {
+    use Mnemonic as M;
+    use Mode::*;
+    // The eight modes of the ALU instructions, whose opcodes differ by `base`
+    let alu = |base: u8| match mode {
+        IndirectX => Some(base + 0x01),
+        ZeroPage => Some(base + 0x05),
+        Immediate => Some(base + 0x09),
+        Absolute => Some(base + 0x0d),
+        IndirectY => Some(base + 0x11),
+        ZeroPageX => Some(base + 0x15),
+        AbsoluteY => Some(base + 0x19),
+        AbsoluteX => Some(base + 0x1d),
+        _ => None,
+    };
+    // The shifts and rotates, and `inc` and `dec` without the accumulator
+    let read_modify_write = |base: u8| match mode {
+        ZeroPage => Some(base + 0x06),
+        Accumulator => Some(base + 0x0a),
+        Absolute => Some(base + 0x0e),
+        ZeroPageX => Some(base + 0x16),
+        AbsoluteX => Some(base + 0x1e),
+        _ => None,
+    };
+    let implied = |opcode: u8| (mode == Implied).then_some(opcode);
+    let relative = |opcode: u8| (mode == Relative).then_some(opcode);
+    match mnemonic {
+        M::Ora => alu(0x00),
+        M::And => alu(0x20),
+        M::Eor => alu(0x40),
+        M::Adc => alu(0x60),
+        M::Sta if mode != Immediate => alu(0x80),
+        M::Lda => alu(0xa0),
+        M::Cmp => alu(0xc0),
+        M::Sbc => alu(0xe0),
+        M::Sta => None,
+        M::Asl => read_modify_write(0x00),
+        M::Rol => read_modify_write(0x20),
+        M::Lsr => read_modify_write(0x40),
+        M::Ror => read_modify_write(0x60),
+        M::Dec if mode != Accumulator => read_modify_write(0xc0),
+        M::Inc if mode != Accumulator => read_modify_write(0xe0),
+        M::Dec | M::Inc => None,
+        M::Ldx => match mode {
+            Immediate => Some(0xa2),
+            ZeroPage => Some(0xa6),
+            Absolute => Some(0xae),
+            ZeroPageY => Some(0xb6),
+            AbsoluteY => Some(0xbe),
+            _ => None,
+        },
+        M::Ldy => match mode {
+            Immediate => Some(0xa0),
+            ZeroPage => Some(0xa4),
+            Absolute => Some(0xac),
+            ZeroPageX => Some(0xb4),
+            AbsoluteX => Some(0xbc),
+            _ => None,
+        },
+        M::Stx => match mode {
+            ZeroPage => Some(0x86),
+            Absolute => Some(0x8e),
+            ZeroPageY => Some(0x96),
+            _ => None,
+        },
+        M::Sty => match mode {
+            ZeroPage => Some(0x84),
+            Absolute => Some(0x8c),
+            ZeroPageX => Some(0x94),
+            _ => None,
+        },
+        M::Cpx | M::Cpy => {
+            let base = if mnemonic == M::Cpx { 0xe0 } else { 0xc0 };
+            match mode {
+                Immediate => Some(base),
+                ZeroPage => Some(base + 0x04),
+                Absolute => Some(base + 0x0c),
+                _ => None,
+            }
+        }
+        M::Bit => match mode {
+            ZeroPage => Some(0x24),
+            Absolute => Some(0x2c),
+            _ => None,
+        },
+        M::Jmp => match mode {
+            Absolute => Some(0x4c),
+            Indirect => Some(0x6c),
+            _ => None,
+        },
+        M::Jsr => (mode == Absolute).then_some(0x20),
+        M::Bpl => relative(0x10),
+        M::Bmi => relative(0x30),
+        M::Bvc => relative(0x50),
+        M::Bvs => relative(0x70),
+        M::Bcc => relative(0x90),
+        M::Bcs => relative(0xb0),
+        M::Bne => relative(0xd0),
+        M::Beq => relative(0xf0),
+        M::Brk => implied(0x00),
+        M::Php => implied(0x08),
+        M::Clc => implied(0x18),
+        M::Plp => implied(0x28),
+        M::Sec => implied(0x38),
+        M::Rti => implied(0x40),
+        M::Pha => implied(0x48),
+        M::Cli => implied(0x58),
+        M::Rts => implied(0x60),
+        M::Pla => implied(0x68),
+        M::Sei => implied(0x78),
+        M::Dey => implied(0x88),
+        M::Txa => implied(0x8a),
+        M::Tya => implied(0x98),
+        M::Txs => implied(0x9a),
+        M::Tay => implied(0xa8),
+        M::Tax => implied(0xaa),
+        M::Clv => implied(0xb8),
+        M::Tsx => implied(0xba),
+        M::Iny => implied(0xc8),
+        M::Dex => implied(0xca),
+        M::Cld => implied(0xd8),
+        M::Inx => implied(0xe8),
+        M::Nop => implied(0xea),
+        M::Sed => implied(0xf8),
+    }
+}
+
+/// The mode `mnemonic` reaches `operand` in: through the zero page where it can
+fn mode(mnemonic: Mnemonic, operand: &M6502Operand) -> io::Result<Mode> {
+    let zero_page = |address: &Address| matches!(address, Address::Fixed(byte) if *byte < 0x100);
+    let shorter = |address: &Address, short: Mode, long: Mode| match zero_page(address)
+        && opcode(mnemonic, short).is_some()
+    {
+        true => short,
+        false => long,
+    };
+    let mode = match operand {
+        M6502Operand::Implied if opcode(mnemonic, Mode::Implied).is_some() => Mode::Implied,
+        M6502Operand::Implied => Mode::Accumulator,
+        M6502Operand::Immediate(_) => Mode::Immediate,
+        M6502Operand::Memory(address) => shorter(address, Mode::ZeroPage, Mode::Absolute),
+        M6502Operand::IndexedX(address) => shorter(address, Mode::ZeroPageX, Mode::AbsoluteX),
+        M6502Operand::IndexedY(address) => shorter(address, Mode::ZeroPageY, Mode::AbsoluteY),
+        M6502Operand::IndirectX(_) => Mode::IndirectX,
+        M6502Operand::IndirectY(_) => Mode::IndirectY,
+        M6502Operand::Indirect(_) => Mode::Indirect,
+        M6502Operand::Target(_) if mnemonic.is_branch() => Mode::Relative,
+        M6502Operand::Target(_) => Mode::Absolute,
+    };
+    match opcode(mnemonic, mode) {
+        Some(_) => Ok(mode),
+        None => Err(invalid(format!(
+            "'{}' can't take the operand {:?}",
+            mnemonic, operand
+        ))),
+    }
+}
+
+/// The items of a function's `instructions`, with its inline assembly parsed and its local
+/// labels named after the label before them
+pub fn items(instructions: &[M6502Instruction], scope: &str) -> io::Result<Vec<Item>> {
+    let mut items = Vec::new();
+    let mut scope = scope.to_string();
+    for instruction in instructions {
+        match instruction {
+            M6502Instruction::Op(mnemonic, operand) => {
+                items.push(Item::Op(*mnemonic, operand.clone()))
+            }
+            M6502Instruction::Label(label) => items.push(Item::Label(label.clone())),
+            M6502Instruction::Asm(template) => {
+                for line in template.lines() {
+                    if let Some(item) = parse_line(line)? {
+                        items.push(item);
+                    }
+                }
+            }
+        }
+    }
+    for item in &mut items {
+        match item {
+            Item::Label(label) if !label.starts_with('@') => scope = label.clone(),
+            Item::Label(label) | Item::Word(label) => *label = qualify(&scope, label),
+            Item::Op(_, operand) => qualify_operand(&scope, operand),
+            Item::Bytes(_) => {}
+        }
+    }
+    Ok(items)
+}
+
+fn qualify(scope: &str, symbol: &str) -> String {
+    match symbol.starts_with('@') {
+        true => format!("{}{}", scope, symbol),
+        false => symbol.to_string(),
+    }
+}
+
+fn qualify_operand(scope: &str, operand: &mut M6502Operand) {
+    match operand {
+        M6502Operand::Target(symbol)
+        | M6502Operand::Immediate(Byte::Low(symbol) | Byte::High(symbol)) => {
+            *symbol = qualify(scope, symbol)
+        }
+        M6502Operand::Memory(Address::Symbol(symbol, _))
+        | M6502Operand::IndexedX(Address::Symbol(symbol, _))
+        | M6502Operand::IndexedY(Address::Symbol(symbol, _))
+        | M6502Operand::Indirect(Address::Symbol(symbol, _)) => *symbol = qualify(scope, symbol),
+        _ => {}
+    }
+}
+
+/// Parses a line of inline assembly in ca65's syntax: a label, or an instruction whose
+/// operand is a number or a symbol plus a number
+pub fn parse_line(line: &str) -> io::Result<Option<Item>> {
+    let line = line.split(';').next().unwrap_or_default().trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if let Some(label) = line.strip_suffix(':') {
+        return Ok(Some(Item::Label(label.trim().to_string())));
+    }
+    let cant_assemble = || invalid(format!("can't assemble '{}'", line));
+    let (word, operand) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let mnemonic = Mnemonic::ALL
+        .into_iter()
+        .find(|mnemonic| mnemonic.to_string().eq_ignore_ascii_case(word))
+        .ok_or_else(cant_assemble)?;
+    let operand: String = operand.chars().filter(|c| !c.is_whitespace()).collect();
+    let lower = operand.to_ascii_lowercase();
+    let zero_page = |text: &str| match number(text) {
+        Some(address) if address < 0x100 => Ok(address as u8),
+        _ => Err(cant_assemble()),
+    };
+    let operand = if operand.is_empty() || lower == "a" {
+        M6502Operand::Implied
+    } else if let Some(byte) = operand.strip_prefix('#') {
+        M6502Operand::Immediate(if let Some(symbol) = byte.strip_prefix('<') {
+            Byte::Low(symbol.to_string())
+        } else if let Some(symbol) = byte.strip_prefix('>') {
+            Byte::High(symbol.to_string())
+        } else {
+            match number(byte) {
+                Some(value) if value < 0x100 => Byte::Literal(value as u8),
+                _ => return Err(cant_assemble()),
+            }
+        })
+    } else if lower.starts_with('(') && lower.ends_with("),y") {
+        M6502Operand::IndirectY(zero_page(&operand[1..operand.len() - 3])?)
+    } else if lower.starts_with('(') && lower.ends_with(",x)") {
+        M6502Operand::IndirectX(zero_page(&operand[1..operand.len() - 3])?)
+    } else if let Some(address) = operand.strip_prefix('(').and_then(|a| a.strip_suffix(')')) {
+        M6502Operand::Indirect(address_of(address).ok_or_else(cant_assemble)?)
+    } else if lower.ends_with(",x") {
+        M6502Operand::IndexedX(address_of(&operand[..operand.len() - 2]).ok_or_else(cant_assemble)?)
+    } else if lower.ends_with(",y") {
+        M6502Operand::IndexedY(address_of(&operand[..operand.len() - 2]).ok_or_else(cant_assemble)?)
+    } else {
+        match address_of(&operand).ok_or_else(cant_assemble)? {
+            Address::Symbol(symbol, 0)
+                if mnemonic.is_branch() || matches!(mnemonic, Mnemonic::Jmp | Mnemonic::Jsr) =>
+            {
+                M6502Operand::Target(symbol)
+            }
+            address => M6502Operand::Memory(address),
+        }
+    };
+    mode(mnemonic, &operand)?;
+    Ok(Some(Item::Op(mnemonic, operand)))
+}
+
+/// A number in hex after `$`, binary after `%`, or else decimal
+fn number(text: &str) -> Option<u16> {
+    if let Some(hex) = text.strip_prefix('$') {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix('%') {
+        u16::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+/// A number, or a symbol plus a number
+fn address_of(text: &str) -> Option<Address> {
+    if let Some(address) = number(text) {
+        return Some(Address::Fixed(address));
+    }
+    let (symbol, offset) = match text.split_once('+') {
+        Some((symbol, offset)) => (symbol, number(offset)?),
+        None => (text, 0),
+    };
+    let identifier = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '@';
+    match !symbol.is_empty() && symbol.chars().all(identifier) {
+        true => Some(Address::Symbol(symbol.to_string(), offset)),
+        false => None,
+    }
+}
+
+/// Assembles `items` at `origin`, then places each of `variables`, a symbol and its size in
+/// bytes, at `variables_at` or else right after the image
+pub fn assemble(
+    items: &[Item],
+    origin: u16,
+    variables: &[(String, usize)],
+    variables_at: Option<u16>,
+) -> io::Result<Image> {
+    // Labels, and the mode of each instruction
+    let mut symbols = HashMap::new();
+    let mut modes = Vec::new();
+    let mut address = origin as usize;
+    for item in items {
+        match item {
+            Item::Op(mnemonic, operand) => {
+                let mode = mode(*mnemonic, operand)?;
+                modes.push(mode);
+                address += 1 + mode.size() as usize;
+            }
+            Item::Label(label) => define(&mut symbols, label, address)?,
+            Item::Bytes(bytes) => address += bytes.len(),
+            Item::Word(_) => address += 2,
+        }
+    }
+    let mut variable = variables_at.map_or(address, usize::from);
+    for (symbol, size) in variables {
+        define(&mut symbols, symbol, variable)?;
+        variable += size;
+    }
+    if address.max(variable) > 0x10000 {
+        return Err(invalid("the program doesn't fit in 64K".to_string()));
+    }
+
+    let mut image = Image {
+        origin,
+        bytes: Vec::new(),
+        symbols,
+    };
+    let mut modes = modes.into_iter();
+    for item in items {
+        match item {
+            Item::Op(mnemonic, operand) => {
+                let mode = modes.next().expect("every op has its mode");
+                encode(&mut image, *mnemonic, mode, operand)?;
+            }
+            Item::Label(_) => {}
+            Item::Bytes(bytes) => image.bytes.extend(bytes),
+            Item::Word(symbol) => {
+                let address = image.symbol(symbol)?;
+                image.bytes.extend(address.to_le_bytes());
+            }
+        }
+    }
+    Ok(image)
+}
+
+fn define(symbols: &mut HashMap<String, u16>, symbol: &str, address: usize) -> io::Result<()> {
+    if symbols.insert(symbol.to_string(), address as u16).is_some() {
+        return Err(invalid(format!("symbol '{}' is defined twice", symbol)));
+    }
+    Ok(())
+}
+
+fn encode(
+    image: &mut Image,
+    mnemonic: Mnemonic,
+    mode: Mode,
+    operand: &M6502Operand,
+) -> io::Result<()> {
+    let opcode = opcode(mnemonic, mode).expect("the mode was checked to have an opcode");
+    let address = |address: &Address| -> io::Result<u16> {
+        match address {
+            Address::Fixed(address) => Ok(*address),
+            Address::Symbol(symbol, offset) => Ok(image.symbol(symbol)?.wrapping_add(*offset)),
+        }
+    };
+    let value = match operand {
+        M6502Operand::Implied => None,
+        M6502Operand::Immediate(Byte::Literal(byte)) => Some(*byte as u16),
+        M6502Operand::Immediate(Byte::Low(symbol)) => Some(image.symbol(symbol)? & 0xff),
+        M6502Operand::Immediate(Byte::High(symbol)) => Some(image.symbol(symbol)? >> 8),
+        M6502Operand::Memory(a)
+        | M6502Operand::IndexedX(a)
+        | M6502Operand::IndexedY(a)
+        | M6502Operand::Indirect(a) => Some(address(a)?),
+        M6502Operand::IndirectX(byte) | M6502Operand::IndirectY(byte) => Some(*byte as u16),
+        M6502Operand::Target(symbol) if mode == Mode::Relative => {
+            let next = image.origin as i32 + image.bytes.len() as i32 + 2;
+            let offset = image.symbol(symbol)? as i32 - next;
+            if !(-128..=127).contains(&offset) {
+                return Err(invalid(format!("branch to '{}' is out of range", symbol)));
+            }
+            Some(offset as u8 as u16)
+        }
+        M6502Operand::Target(symbol) => Some(image.symbol(symbol)?),
+    };
+    image.bytes.push(opcode);
+    if let Some(value) = value {
+        let bytes = value.to_le_bytes();
+        image.bytes.extend(&bytes[..mode.size() as usize]);
+    }
+    Ok(())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
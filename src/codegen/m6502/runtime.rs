@@ -0,0 +1,241 @@
+//! The runtime 6502 programs need for what the CPU has no instructions for: multiplying,
+//! dividing and printing. Each routine is written here in ca65's syntax and parsed into
+//! instructions, and only those a program calls, directly or through another routine, are
+//! added to it. They take their operands in the argument area and return in the result
+//! bytes, as functions do, but save nothing, since they only touch those and a scratch area of
+//! their own.
+//!
+//! Printing goes through `c0_putchar`, which writes the character in A and keeps X and Y. A C64
+//! program calls the KERNAL's CHROUT for it, and a NES cartridge, with no console, writes each
+//! character to a port an emulator can watch. Plain assembly leaves it to the platform.
+
+use super::encoding::{self, Item};
+use super::{M6502Function, M6502Instruction, M6502Operand, Mnemonic, ZeroPage, ABORT, ARGUMENTS};
+use crate::codegen::OutputFormat;
+use crate::parser::FormatSpec;
+use std::collections::HashSet;
+
+/// Multiplies the ints in the first two arguments
+pub const MULTIPLY: &str = "c0_mul";
+/// Divides the int in the first argument by the one in the second, truncating toward zero.
+/// Dividing by zero, or the least int by -1, aborts.
+pub const DIVIDE: &str = "c0_div";
+/// Writes the character in A
+pub const PUTCHAR: &str = "c0_putchar";
+/// Symbol of the area routines keep what doesn't fit in the argument and result bytes
+pub const SCRATCH: &str = "c0_scratch";
+/// The C64 KERNAL's routine that writes the character in A to the screen
+const CHROUT: u16 = 0xffd2;
+/// The port a NES cartridge writes printed characters to: one of the APU's test registers,
+/// which the console ignores
+const NES_OUTPUT_PORT: u16 = 0x4018;
+
+/// Routine that prints a value as `spec` formats it
+pub fn print_routine(spec: FormatSpec) -> &'static str {
+    match spec {
+        FormatSpec::Int => "c0_print_int",
+        FormatSpec::Char => "c0_print_char",
+        FormatSpec::String => "c0_print_string",
+        FormatSpec::Double => unreachable!("doubles are rejected before selection"),
+    }
+}
+
+/// The routines `functions` call, and those they call in turn, for a program written as
+/// `format`
+pub fn routines(
+    functions: &[M6502Function],
+    zero_page: ZeroPage,
+    format: OutputFormat,
+) -> Vec<M6502Function> {
+    let mut seen = HashSet::new();
+    let mut unvisited: Vec<String> = functions.iter().flat_map(called).collect();
+    let mut routines = Vec::new();
+    while let Some(symbol) = unvisited.pop() {
+        if !seen.insert(symbol.clone()) {
+            continue;
+        }
+        if let Some(routine) = routine(&symbol, zero_page, format) {
+            unvisited.extend(called(&routine));
+            routines.push(routine);
+        }
+    }
+    routines.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+    routines
+}
+
+/// Symbols `function` calls or jumps to, other than its own labels
+fn called(function: &M6502Function) -> Vec<String> {
+    function
+        .instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            M6502Instruction::Op(Mnemonic::Jsr | Mnemonic::Jmp, M6502Operand::Target(symbol))
+                if !symbol.starts_with('@') =>
+            {
+                Some(symbol.clone())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// The routine `symbol` names, if it's one of the runtime's and there's one for `format`
+fn routine(symbol: &str, zero_page: ZeroPage, format: OutputFormat) -> Option<M6502Function> {
+    let (source, scratch_bytes) = match symbol {
+        MULTIPLY => (multiply(zero_page), 0),
+        DIVIDE => (divide(zero_page), 5),
+        "c0_print_int" => (print_int(), 0),
+        "c0_print_char" => (format!("lda {ARGUMENTS}\njmp {PUTCHAR}\n"), 0),
+        "c0_print_string" => (print_string(zero_page), 0),
+        PUTCHAR => (putchar(format)?, 0),
+        _ => return None,
+    };
+    let instructions = source
+        .lines()
+        .map(
+            |line| match encoding::parse_line(line).expect("the runtime assembles") {
+                Some(Item::Op(mnemonic, operand)) => M6502Instruction::Op(mnemonic, operand),
+                Some(Item::Label(label)) => M6502Instruction::Label(label),
+                _ => unreachable!("the runtime is written without data"),
+            },
+        )
+        .collect();
+    Some(M6502Function {
+        symbol: symbol.to_string(),
+        is_static: false,
+        instructions,
+        argument_bytes: 8,
+        spill_bytes: 0,
+        scratch_bytes,
+    })
+}
+
+/// Lines applying `mnemonic` to the four bytes from `start` on, lowest first, with `first`
+/// for the lowest if it differs
+fn each_byte(first: &str, mnemonic: &str, start: &str) -> String {
+    (0..4)
+        .map(|index| match index {
+            0 => format!("{} {}\n", first, start),
+            _ => format!("{} {}+{}\n", mnemonic, start, index),
+        })
+        .collect()
+}
+
+/// Lines negating the int `offset` bytes into `area`, in place
+fn negate(area: &str, offset: usize) -> String {
+    let mut lines = String::from("sec\n");
+    for index in offset..offset + 4 {
+        lines += &format!("lda #0\nsbc {area}+{index}\nsta {area}+{index}\n");
+    }
+    lines
+}
+
+/// Shift-and-add: for each bit of the second argument, from the lowest, the first argument
+/// is added if it's set, and then doubled
+fn multiply(zero_page: ZeroPage) -> String {
+    let result = zero_page.result();
+    let mut lines = String::from("lda #0\n");
+    for index in 0..4 {
+        lines += &format!("sta ${:02x}\n", result + index);
+    }
+    lines += "ldx #32\n@bit:\n";
+    lines +=
+        &format!("lsr {ARGUMENTS}+7\nror {ARGUMENTS}+6\nror {ARGUMENTS}+5\nror {ARGUMENTS}+4\n");
+    lines += "bcc @next\nclc\n";
+    for index in 0..4 {
+        lines += &format!(
+            "lda ${0:02x}\nadc {ARGUMENTS}+{1}\nsta ${0:02x}\n",
+            result + index,
+            index
+        );
+    }
+    lines += "@next:\n";
+    lines += &each_byte("asl", "rol", ARGUMENTS);
+    lines += "dex\nbne @bit\nrts\n";
+    lines
+}
+
+/// Long division of the magnitudes, a bit at a time, with the sign put back after. The
+/// quotient is shifted in where the dividend is shifted out, the remainder kept in the scratch
+/// area, and each trial subtraction written to the result bytes.
+fn divide(zero_page: ZeroPage) -> String {
+    let result = zero_page.result();
+    let mut lines = format!(
+        "lda {ARGUMENTS}+4\nora {ARGUMENTS}+5\nora {ARGUMENTS}+6\nora {ARGUMENTS}+7\n\
+         bne @nonzero\njmp {ABORT}\n@nonzero:\n"
+    );
+    // The quotient is negative if the signs differ
+    lines += &format!("lda {ARGUMENTS}+3\neor {ARGUMENTS}+7\nsta {SCRATCH}+4\n");
+    lines += &format!("lda {ARGUMENTS}+3\nbpl @dividend\n");
+    lines += &negate(ARGUMENTS, 0);
+    lines += &format!("@dividend:\nlda {ARGUMENTS}+7\nbpl @divisor\n");
+    lines += &negate(ARGUMENTS, 4);
+    lines += "@divisor:\nlda #0\n";
+    lines += &each_byte("sta", "sta", SCRATCH);
+    lines += "ldx #32\n@bit:\n";
+    lines += &each_byte("asl", "rol", ARGUMENTS);
+    lines += &each_byte("rol", "rol", SCRATCH);
+    lines += "sec\n";
+    for index in 0..4 {
+        lines += &format!(
+            "lda {SCRATCH}+{index}\nsbc {ARGUMENTS}+{}\nsta ${:02x}\n",
+            index + 4,
+            result + index
+        );
+    }
+    lines += "bcc @next\n";
+    for index in 0..4 {
+        lines += &format!("lda ${:02x}\nsta {SCRATCH}+{index}\n", result + index);
+    }
+    lines += &format!("inc {ARGUMENTS}\n@next:\ndex\nbne @bit\n");
+    // A positive quotient of 2^31 is the least int divided by -1
+    lines += &format!("lda {SCRATCH}+4\nbpl @positive\n");
+    lines += &negate(ARGUMENTS, 0);
+    lines += &format!("jmp @done\n@positive:\nlda {ARGUMENTS}+3\nbpl @done\njmp {ABORT}\n@done:\n");
+    for index in 0..4 {
+        lines += &format!("lda {ARGUMENTS}+{index}\nsta ${:02x}\n", result + index);
+    }
+    lines += "rts\n";
+    lines
+}
+
+/// A minus sign for a negative int, then its magnitude's digits, found by dividing by ten
+/// until it's zero and pushed on the hardware stack to be printed highest first
+fn print_int() -> String {
+    let mut lines = format!("lda {ARGUMENTS}+3\nbpl @positive\nlda #$2d\njsr {PUTCHAR}\n");
+    lines += &negate(ARGUMENTS, 0);
+    lines += "@positive:\nldy #0\n@digit:\nldx #32\nlda #0\n@bit:\n";
+    lines += &each_byte("asl", "rol", ARGUMENTS);
+    lines += &format!("rol a\ncmp #10\nbcc @next\nsbc #10\ninc {ARGUMENTS}\n@next:\n");
+    lines += "dex\nbne @bit\nora #$30\npha\niny\n";
+    lines += &each_byte("lda", "ora", ARGUMENTS);
+    lines += &format!("bne @digit\n@print:\npla\njsr {PUTCHAR}\ndey\nbne @print\nrts\n");
+    lines
+}
+
+/// The characters up to the string's terminating zero, read through the result bytes
+fn print_string(zero_page: ZeroPage) -> String {
+    let pointer = zero_page.result();
+    format!(
+        "lda {ARGUMENTS}\nsta ${pointer:02x}\nlda {ARGUMENTS}+1\nsta ${:02x}\nldy #0\n\
+         @char:\nlda (${pointer:02x}),y\nbeq @done\njsr {PUTCHAR}\niny\nbne @char\n\
+         inc ${:02x}\njmp @char\n@done:\nrts\n",
+        pointer + 1,
+        pointer + 1,
+    )
+}
+
+/// `c0_putchar` for `format`, if the runtime supplies it. The C64 shows letters in uppercase,
+/// and starts a new line on a carriage return.
+fn putchar(format: OutputFormat) -> Option<String> {
+    match format {
+        OutputFormat::Assembly | OutputFormat::Object | OutputFormat::LlvmIr | OutputFormat::C => {
+            None
+        }
+        OutputFormat::Prg => Some(format!(
+            "cmp #$0a\nbne @letter\nlda #$0d\n@letter:\ncmp #$61\nbcc @write\ncmp #$7b\n\
+             bcs @write\nand #$df\n@write:\njmp ${CHROUT:04x}\n"
+        )),
+        OutputFormat::Nes => Some(format!("sta ${NES_OUTPUT_PORT:04x}\nrts\n")),
+    }
+}
@@ -0,0 +1,214 @@
+//! Zero-page allocation. Each temp is given a run of consecutive bytes, as many as its type
+//! takes, among the zero-page registers of the target's register description, or in the spill
+//! area in absolute memory once no run there is free. Temps that are live at the same time, or
+//! that an instruction writes while it reads the other, get bytes that don't overlap, since the
+//! 6502 reads and writes them a byte at a time. The temps used most get the zero page first.
+
+use super::{size_of, Address, SPILL_AREA};
+use crate::codegen::context::{AbstractAssemblyInstruction, Context, Dest, Operand};
+use crate::codegen::register_allocator::RegisterDescription;
+use std::collections::{BTreeSet, HashMap};
+
+/// Where each temp of a function is kept
+#[derive(Debug)]
+pub struct Allocation {
+    /// Address of each temp's first byte
+    pub locations: HashMap<usize, Address>,
+    /// Address of the first zero-page register, if there are any
+    pub first_register: Option<u8>,
+    /// Bytes of the zero page in use, from the first register on
+    pub zero_page_bytes: usize,
+    /// Bytes of the spill area in use
+    pub spill_bytes: usize,
+}
+
+/// Assigns each temp of `context` its bytes, in the zero-page registers of `registers` if
+/// there's room
+pub fn allocate(context: &Context, registers: &RegisterDescription) -> Allocation {
+    let instructions = &context.instructions;
+    let live_out = live_out(instructions);
+    let interference = interference(context, &live_out);
+
+    // The most used temps first, so that they're the ones in the zero page
+    let mut uses: HashMap<usize, usize> = interference.keys().map(|&temp| (temp, 0)).collect();
+    for instruction in instructions {
+        for temp in instruction_temps(instruction) {
+            *uses.entry(temp).or_default() += 1;
+        }
+    }
+    let mut temps: Vec<usize> = uses.keys().copied().collect();
+    temps.sort_by_key(|temp| (std::cmp::Reverse(uses[temp]), *temp));
+
+    // Bytes each assigned temp takes: zero-page addresses, or offsets into the spill area
+    let mut zero_page: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut spilled: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut locations = HashMap::new();
+    for temp in temps {
+        let size = size_of(context.temp_types()[&temp]).max(1);
+        let neighbors = interference.get(&temp);
+        let overlaps = |taken: &HashMap<usize, (usize, usize)>, start: usize| {
+            neighbors.into_iter().flatten().any(|neighbor| {
+                taken.get(neighbor).is_some_and(|&(other, other_size)| {
+                    start < other + other_size && other < start + size
+                })
+            })
+        };
+        let run = registers
+            .int
+            .windows(size)
+            .filter(|run| run.iter().zip(run[0]..).all(|(&byte, next)| byte == next))
+            .map(|run| run[0])
+            .find(|&start| !overlaps(&zero_page, start));
+        if let Some(start) = run {
+            zero_page.insert(temp, (start, size));
+            locations.insert(temp, Address::Fixed(start as u16));
+        } else {
+            let start = (0..)
+                .find(|&start| !overlaps(&spilled, start))
+                .expect("the spill area has room past every temp");
+            spilled.insert(temp, (start, size));
+            locations.insert(temp, Address::Symbol(SPILL_AREA.to_string(), start as u16));
+        }
+    }
+
+    let first_register = registers.int.first().map(|&first| first as u8);
+    let zero_page_bytes = zero_page
+        .values()
+        .map(|&(start, size)| start + size - registers.int[0])
+        .max()
+        .unwrap_or(0);
+    let spill_bytes = spilled
+        .values()
+        .map(|&(start, size)| start + size)
+        .max()
+        .unwrap_or(0);
+    Allocation {
+        locations,
+        first_register,
+        zero_page_bytes,
+        spill_bytes,
+    }
+}
+
+/// Temps `instruction` reads
+fn used_temps(instruction: &AbstractAssemblyInstruction) -> impl Iterator<Item = usize> + '_ {
+    instruction
+        .operands()
+        .into_iter()
+        .filter_map(|operand| match operand {
+            Operand::Var(Dest::Temp(temp)) => Some(*temp),
+            _ => None,
+        })
+}
+
+/// Temp `instruction` writes, if any
+fn defined_temp(instruction: &AbstractAssemblyInstruction) -> Option<usize> {
+    match instruction.dest() {
+        Some(Dest::Temp(temp)) => Some(*temp),
+        _ => None,
+    }
+}
+
+fn instruction_temps(instruction: &AbstractAssemblyInstruction) -> Vec<usize> {
+    used_temps(instruction)
+        .chain(defined_temp(instruction))
+        .collect()
+}
+
+/// Lines that may run right after each line
+fn successors(instructions: &[AbstractAssemblyInstruction]) -> Vec<Vec<usize>> {
+    use AbstractAssemblyInstruction as A;
+    let labels: HashMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, instruction)| match instruction {
+            A::Lbl(label) => Some((label.0, index)),
+            _ => None,
+        })
+        .collect();
+    instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| match instruction {
+            A::Jmp(target) => vec![labels[&target.0]],
+            A::JmpCondition {
+                tgt_true,
+                tgt_false,
+                ..
+            } => vec![labels[&tgt_true.0], labels[&tgt_false.0]],
+            A::Return(_) | A::ReturnVoid | A::Abort => Vec::new(),
+            _ if index + 1 < instructions.len() => vec![index + 1],
+            _ => Vec::new(),
+        })
+        .collect()
+}
+
+/// Temps live after each line, and before the first, found by iterating the dataflow
+/// equations backward until nothing changes
+fn live_out(instructions: &[AbstractAssemblyInstruction]) -> Vec<BTreeSet<usize>> {
+    let successors = successors(instructions);
+    let mut live_in: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); instructions.len()];
+    let mut live_out: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); instructions.len() + 1];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in (0..instructions.len()).rev() {
+            let out: BTreeSet<usize> = successors[index]
+                .iter()
+                .flat_map(|&successor| live_in[successor].iter().copied())
+                .collect();
+            let mut in_ = out.clone();
+            if let Some(temp) = defined_temp(&instructions[index]) {
+                in_.remove(&temp);
+            }
+            in_.extend(used_temps(&instructions[index]));
+            if in_ != live_in[index] {
+                live_in[index] = in_;
+                changed = true;
+            }
+            live_out[index] = out;
+        }
+    }
+    // The parameters are all live on entry, as they're received
+    live_out[instructions.len()] = live_in.first().cloned().unwrap_or_default();
+    live_out
+}
+
+/// The temps each temp can't share bytes with
+fn interference(
+    context: &Context,
+    live_out: &[BTreeSet<usize>],
+) -> HashMap<usize, BTreeSet<usize>> {
+    let mut interference: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+    let mut add = |a: usize, b: usize| {
+        interference.entry(a).or_default();
+        interference.entry(b).or_default();
+        if a != b {
+            interference.get_mut(&a).unwrap().insert(b);
+            interference.get_mut(&b).unwrap().insert(a);
+        }
+    };
+    let mut together = |temps: Vec<usize>| {
+        for (index, &a) in temps.iter().enumerate() {
+            for &b in &temps[index..] {
+                add(a, b);
+            }
+        }
+    };
+    for (instruction, live) in context.instructions.iter().zip(live_out) {
+        let mut temps: Vec<usize> = live.iter().copied().collect();
+        if let Some(temp) = defined_temp(instruction) {
+            // Written a byte at a time, so it can't overlap what it's computed from
+            temps.extend(used_temps(instruction));
+            temps.push(temp);
+        }
+        together(temps);
+    }
+    together(
+        live_out[context.instructions.len()]
+            .iter()
+            .copied()
+            .collect(),
+    );
+    interference
+}
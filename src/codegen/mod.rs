@@ -1,35 +1,459 @@
 use crate::lexer::Token;
-use crate::parser::Program;
-use emit::{emit_abstract, emit_m6502, emit_x86};
-use std::io::{self};
-use std::path::PathBuf;
+use crate::parser::{Expr, Program};
+use crate::sema::Type;
+use crate::source_map::LineTable;
+use crate::stats::FunctionStats;
+use emit::write_abstract;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+pub mod assembler;
+pub mod cfg;
+pub mod dominators;
+pub mod loops;
+pub mod object;
+pub mod x86_encoding;
+
+mod backend;
+pub use backend::{backend, default_backend, triples, Backend};
 
 mod context;
-use context::Context;
+mod dwarf;
+use context::{variable_type, Context, Global, Signature, StringTable};
+pub use context::{
+    AbstractAssemblyInstruction, Arithmetic, AsmLabel, Condition, Conversion, Dest, Operand,
+    ShiftKind,
+};
 
 mod emit;
+pub mod frame;
+mod incremental;
+mod isel;
+mod llvm;
+mod m6502;
+mod register_allocator;
+pub use register_allocator::RegisterDescription;
+mod riscv;
+mod runtime;
+mod two_address;
+mod x86;
+pub use x86::X86Register;
+mod x86_object;
+
+mod interpreter;
+pub use interpreter::{interpret, run_with_io, Execution, RunResult, RuntimeError, Value};
+
+mod undefined;
+pub use undefined::Undefined;
+
+mod ir_parser;
+pub use ir_parser::{parse_ir, IrParseError, IrParseErrorKind};
+
+mod constant_folding;
+mod dead_code;
+mod inline;
+mod pass;
+mod simplify_cfg;
+mod strength_reduction;
+mod unroll;
+use pass::PassManager;
+pub use pass::{pass_names, pipeline, PassTiming, MAX_OPT_LEVEL};
+
+mod ssa;
+use ssa::SSABuilder;
+
+mod verify;
+pub use verify::{VerifyError, VerifyErrorKind};
+
+/// Why code generation failed
+#[derive(Debug)]
+pub enum CodegenFailure {
+    Io(io::Error),
+    // A step left abstract assembly that breaks an invariant, which is a compiler bug; only
+    // checked in debug builds
+    Invalid { after: String, error: VerifyError },
+}
+
+/// Optional steps of code generation
+#[derive(Debug, Clone)]
+pub struct CodegenOptions {
+    /// Names of the optimization passes to run, from `pass_names`
+    pub passes: Vec<String>,
+    /// Convert every function to SSA form before emitting it
+    pub ssa: bool,
+    /// Copies of its body a loop without a known trip count is unrolled into; 1 leaves such
+    /// loops alone
+    pub unroll_factor: usize,
+    /// Where to write the abstract assembly after each pass, if anywhere
+    pub dump_ir: Option<IrDump>,
+    /// Write temps holding a source variable with its name, like `%t4.sum`
+    pub debug_names: bool,
+    /// How temps are assigned machine registers, for targets that have them
+    pub register_allocator: RegisterAllocator,
+    /// Where to write the interference graphs and a report of what register allocation did, if
+    /// anywhere: next to this path, as `.regalloc.dot` and `.regalloc.txt`
+    pub dump_regalloc: Option<PathBuf>,
+    /// Leave %rbp alone instead of pointing it at each function's frame
+    pub omit_frame_pointer: bool,
+    /// Keep the spill slots of functions that call nothing in the red zone under %rsp
+    pub red_zone: bool,
+    /// Call the functions another object could define through the procedure linkage table,
+    /// so that the output can go in a shared library
+    pub pic: bool,
+    /// Where each offset of the program is in its source files, with `-g`, for the debug
+    /// information mapping the x86 output back to them
+    pub line_table: Option<Rc<LineTable>>,
+    /// How the program's functions and globals are named in the x86 output
+    pub mangling: Mangling,
+    /// Zero-page bytes the 6502 backend keeps its own state and then temps in, before it
+    /// spills them to absolute memory
+    pub zero_page: RangeInclusive<u8>,
+    /// What kind of file the output is
+    pub format: OutputFormat,
+    /// Directory an object caches each function's machine code in, if any, for the next
+    /// build to reuse for those that haven't changed
+    pub cache: Option<PathBuf>,
+}
+
+/// What kind of file the output is, of those the target can write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Assembly,
+    /// A C64 program, with a line of BASIC that runs it
+    Prg,
+    /// A NES cartridge image in the iNES format
+    Nes,
+    /// A relocatable object, which the x86 targets assemble themselves
+    Object,
+    /// LLVM IR text, which any target can write
+    LlvmIr,
+    /// C source, which `c99` translates the program into before codegen
+    C,
+}
+
+impl OutputFormat {
+    /// Extension of the output file
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Assembly => "S",
+            OutputFormat::Prg => "prg",
+            OutputFormat::Nes => "nes",
+            OutputFormat::Object => "o",
+            OutputFormat::LlvmIr => "ll",
+            OutputFormat::C => "c",
+        }
+    }
+}
+
+/// Algorithm assigning temps to machine registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterAllocator {
+    /// Coloring the interference graph, which spills the fewest temps
+    Graph,
+    /// Scanning the temps' live intervals in order, which is quicker but may spill more
+    Linear,
+}
+
+/// Where `--dump-ir=after-all` writes the program after each pass
+#[derive(Debug, Clone)]
+pub enum IrDump {
+    Stdout,
+    /// A file for each pass, named like this path with the pass's number and name in place of
+    /// its extension
+    Files(PathBuf),
+}
+
+/// How the names of the program's functions and globals become the symbols the linker sees
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mangling {
+    /// Each symbol is the name itself
+    None,
+    /// Each symbol is the name with this prefix, like `_c0_read`, so that it can't collide
+    /// with the C library's. `main`, which the C startup code calls, keeps its name.
+    Prefix(String),
+}
+
+impl Mangling {
+    /// Symbol of the function or global `name`
+    pub fn symbol(&self, name: &str) -> String {
+        match self {
+            Mangling::Prefix(prefix) if name != "main" => format!("{}{}", prefix, name),
+            _ => name.to_string(),
+        }
+    }
+}
+
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        CodegenOptions {
+            passes: Vec::new(),
+            ssa: false,
+            unroll_factor: 2,
+            dump_ir: None,
+            debug_names: false,
+            register_allocator: RegisterAllocator::Graph,
+            dump_regalloc: None,
+            omit_frame_pointer: false,
+            red_zone: false,
+            pic: false,
+            line_table: None,
+            mangling: Mangling::None,
+            zero_page: 0x02..=0x7f,
+            format: OutputFormat::Assembly,
+            cache: None,
+        }
+    }
+}
+
+/// What writing the program found out about it
+#[derive(Debug, Default)]
+pub struct CodegenReport {
+    /// Time spent in each optimization pass, for `--time-passes`
+    pub timings: Vec<PassTiming>,
+    /// Statistics of each function, in the order they're written, for `--stats-json`
+    pub functions: Vec<FunctionStats>,
+}
+
+/// Statistics of the abstract assembly of `functions`, for a backend to add its own to
+fn function_stats(functions: &[Context]) -> Vec<FunctionStats> {
+    functions
+        .iter()
+        .map(|context| {
+            let instructions = context
+                .instructions
+                .iter()
+                .filter(|instruction| {
+                    !matches!(instruction, AbstractAssemblyInstruction::Loc { .. })
+                })
+                .count();
+            FunctionStats::new(&context.name, instructions)
+        })
+        .collect()
+}
 
-pub enum Target {
-    AbstractAssembly,
-    X86,
-    M6502,
+/// A program in abstract assembly, generated from C0 or read back by `parse_ir`
+pub struct IrModule {
+    globals: Vec<Global>,
+    strings: StringTable,
+    functions: Vec<Context>,
 }
 
-pub fn generate_code(program: Program, target: Target, outpath: &PathBuf) -> io::Result<()> {
+impl IrModule {
+    /// Names of the functions, in the order they're written
+    pub fn function_names(&self) -> Vec<&str> {
+        self.functions
+            .iter()
+            .map(|context| context.name.as_str())
+            .collect()
+    }
+}
+
+/// Writes the program to `outpath`, reporting how long each optimization pass took and what
+/// became of each function. As LLVM IR, it's written for the backend's target but without its
+/// instruction selection.
+pub fn generate_code(
+    program: Program,
+    backend: &dyn Backend,
+    options: CodegenOptions,
+    outpath: &Path,
+) -> Result<CodegenReport, CodegenFailure> {
+    let module = generate_module(program, &options);
+    generate_from_ir(module, backend, options, outpath)
+}
+
+/// Writes a program read back from abstract assembly by `parse_ir` to `outpath`, optimizing
+/// it like `generate_code` does. Functions already in SSA form are written as they are.
+pub fn generate_from_ir(
+    mut module: IrModule,
+    backend: &dyn Backend,
+    options: CodegenOptions,
+    outpath: &Path,
+) -> Result<CodegenReport, CodegenFailure> {
+    let timings = optimize(&mut module, &options)?;
+    if options.format == OutputFormat::LlvmIr {
+        module.functions.iter_mut().for_each(cfg::leave_ssa);
+        let IrModule {
+            globals,
+            strings,
+            functions,
+        } = &module;
+        llvm::emit_llvm_ir(
+            outpath,
+            functions,
+            globals,
+            strings,
+            backend.llvm_triple(),
+            &options.mangling,
+        )
+        .map_err(CodegenFailure::Io)?;
+        return Ok(CodegenReport {
+            timings,
+            functions: function_stats(functions),
+        });
+    }
+    backend.legalize(&mut module);
+    let functions = backend
+        .emit(&module, &options, outpath)
+        .map_err(CodegenFailure::Io)?;
+    Ok(CodegenReport { timings, functions })
+}
+
+/// The abstract assembly `generate_code` would write for `program`, for running it with
+/// `interpret` instead
+pub fn lower(program: Program, options: &CodegenOptions) -> Result<IrModule, CodegenFailure> {
+    let mut module = generate_module(program, options);
+    optimize(&mut module, options)?;
+    Ok(module)
+}
+
+fn generate_module(program: Program, options: &CodegenOptions) -> IrModule {
+    // String constants are shared by the whole program, starting with global initializers
+    let mut strings = StringTable::new();
+    for global in &program.decl {
+        if let Some(Expr::Literal(Token::StringLiteral(string))) =
+            global.value.as_ref().map(|value| &value.node)
+        {
+            strings.intern(string);
+        }
+    }
+
+    // Every function can call every other, wherever it's declared, and the `extern` ones,
+    // which are called like any other and left to the linker
+    let defined = program.fns.iter().map(|function| {
+        (
+            &function.identifier,
+            &function.return_type,
+            &function.params,
+        )
+    });
+    let declared = program.externs.iter().map(|function| {
+        (
+            &function.identifier,
+            &function.return_type,
+            &function.params,
+        )
+    });
+    let signatures: HashMap<String, Signature> = declared
+        .chain(defined)
+        .filter_map(|(identifier, return_type, params)| match identifier {
+            Token::Identifier(fname) => Some((fname.clone(), Signature::new(return_type, params))),
+            _ => None,
+        })
+        .collect();
+
+    // Functions use the globals their own scopes don't shadow
+    let global_types: HashMap<String, Type> = program
+        .decl
+        .iter()
+        .filter_map(|global| match &global.identifier {
+            Token::Identifier(name) => Some((name.clone(), variable_type(&global.type_token))),
+            _ => None,
+        })
+        .collect();
+
     // Generate function contexts
-    let mut func_contexts: Vec<Context> = Vec::new();
+    let mut functions: Vec<Context> = Vec::new();
     for function in program.fns {
         if let Token::Identifier(fname) = &function.identifier {
-            let mut context = Context::new(&fname);
-            context.generate(&function);
-            func_contexts.push(context);
+            let mut context = Context::new(fname, function.is_static);
+            let line_table = options.line_table.clone();
+            context.generate(
+                &function,
+                &signatures,
+                &global_types,
+                &mut strings,
+                line_table,
+            );
+            functions.push(context);
+        }
+    }
+
+    let globals: Vec<Global> = program
+        .decl
+        .iter()
+        .filter_map(|global| Global::new(global, &strings))
+        .collect();
+    IrModule {
+        globals,
+        strings,
+        functions,
+    }
+}
+
+/// Runs the optimization passes and SSA conversion on every function, returning how long each
+/// pass took
+fn optimize(
+    module: &mut IrModule,
+    options: &CodegenOptions,
+) -> Result<Vec<PassTiming>, CodegenFailure> {
+    // Functions already in SSA form are left as they are
+    let in_ssa: Vec<bool> = module
+        .functions
+        .iter()
+        .map(|context| context.is_ssa())
+        .collect();
+    for context in &module.functions {
+        if !context.is_ssa() {
+            verify::check(context, false, "code generation")?;
+        }
+    }
+
+    let mut passes = PassManager::new(&options.passes);
+    let mut dumped = 0;
+    let mut dump_error = None;
+    let IrModule {
+        globals,
+        strings,
+        functions,
+    } = module;
+    let run = passes.run(functions, options, &mut |name, functions| {
+        if let (Some(dump), None) = (&options.dump_ir, &dump_error) {
+            dumped += 1;
+            let debug_names = options.debug_names;
+            if let Err(error) =
+                dump_ir(dump, dumped, name, functions, globals, strings, debug_names)
+            {
+                dump_error = Some(error);
+            }
+        }
+    });
+    if let Some(error) = dump_error {
+        return Err(CodegenFailure::Io(error));
+    }
+    run?;
+
+    if options.ssa {
+        for (context, _) in functions.iter_mut().zip(in_ssa).filter(|(_, ssa)| !ssa) {
+            SSABuilder::convert_to_ssa(context);
+            verify::check(context, true, "SSA conversion")?;
         }
     }
+    Ok(passes.timings())
+}
 
-    // Finally, emit the program based on target
-    match target {
-        Target::AbstractAssembly => emit_abstract(&outpath, &func_contexts, &program.decl),
-        Target::X86 => emit_x86(&outpath, &func_contexts, &program.decl),
-        Target::M6502 => emit_m6502(&outpath, &func_contexts, &program.decl),
+/// Writes the program as it is after the `number`th pass, `name`
+fn dump_ir(
+    dump: &IrDump,
+    number: usize,
+    name: &str,
+    functions: &[Context],
+    globals: &[Global],
+    strings: &StringTable,
+    debug_names: bool,
+) -> io::Result<()> {
+    match dump {
+        IrDump::Stdout => {
+            let mut stdout = io::stdout().lock();
+            writeln!(stdout, "# After pass {} '{}'", number, name)?;
+            write_abstract(&mut stdout, functions, globals, strings, debug_names)
+        }
+        IrDump::Files(path) => {
+            let mut file = File::create(path.with_extension(format!("{:02}.{}.o0", number, name)))?;
+            write_abstract(&mut file, functions, globals, strings, debug_names)
+        }
     }
 }
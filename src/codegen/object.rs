@@ -0,0 +1,596 @@
+//! Relocatable object files for x86-64, holding assembled code and data for `ld` or `cc` to
+//! link: ELF64 ones, and COFF ones for Windows. The sections are `.text`, `.data`, `.bss` and
+//! `.rodata`, which COFF calls `.rdata`, each with its relocations if it has any, then the
+//! symbol table.
+//! Symbols the relocations name without defining them are added to it as undefined, for the
+//! linker to find in other objects.
+
+use super::assembler::{Relocation, RelocationKind};
+use std::collections::HashMap;
+
+/// Kinds of object files, as linkers on different systems read them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// For the linkers of Linux and the BSDs
+    Elf,
+    /// For Windows' linkers, like `link.exe` and MinGW's `ld`
+    Coff,
+}
+
+/// Sections an object holds code or data in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Section {
+    Text,
+    Data,
+    /// Zeroed data, which takes no space in the file
+    Bss,
+    Rodata,
+}
+
+impl Section {
+    const ALL: [Section; 4] = [Section::Text, Section::Data, Section::Bss, Section::Rodata];
+
+    fn name(self) -> &'static str {
+        match self {
+            Section::Text => ".text",
+            Section::Data => ".data",
+            Section::Bss => ".bss",
+            Section::Rodata => ".rodata",
+        }
+    }
+
+    /// Name of the section in a COFF object
+    fn coff_name(self) -> &'static str {
+        match self {
+            Section::Rodata => ".rdata",
+            _ => self.name(),
+        }
+    }
+
+    /// Index of the section's header. The sections' headers come first, then the headers of
+    /// the relocations of those that have any.
+    fn header_index(self) -> u16 {
+        1 + self as u16
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    /// A variable or constant
+    Object,
+    /// A label, such as that of a string constant
+    Label,
+}
+
+/// A symbol defined in one of the sections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Section,
+    pub offset: usize,
+    /// Bytes the function or object takes, or 0 if that's not known
+    pub size: usize,
+    pub kind: SymbolKind,
+    /// True if other objects can refer to it
+    pub global: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Object {
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    /// Size of the zeroed data
+    pub bss: usize,
+    pub rodata: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+    /// Relocations to apply to each section
+    pub relocations: Vec<(Section, Relocation)>,
+}
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+/// Size of the file header and of each section header, symbol and relocation
+const HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const RELOCATION_SIZE: usize = 24;
+
+/// A section header, before it's written
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+/// Names, each ending with a 0, as the string tables hold them
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        StringTable { bytes: vec![0] }
+    }
+
+    /// Offset of `name` in the table, after adding it
+    fn add(&mut self, name: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(name.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+/// Writes `object` as a relocatable ELF64 file for x86-64
+pub fn write_elf(object: &Object) -> Vec<u8> {
+    // The symbol table lists local symbols before global ones, and undefined symbols last
+    let mut symbols: Vec<&Symbol> = object
+        .symbols
+        .iter()
+        .filter(|symbol| !symbol.global)
+        .collect();
+    let first_global = symbols.len() + 1;
+    symbols.extend(object.symbols.iter().filter(|symbol| symbol.global));
+    let undefined = undefined_symbols(object);
+
+    let mut names = StringTable::new();
+    let mut symtab = vec![0; SYMBOL_SIZE];
+    let mut indices: HashMap<&str, u32> = HashMap::new();
+    for symbol in &symbols {
+        indices.insert(&symbol.name, (symtab.len() / SYMBOL_SIZE) as u32);
+        let (binding, kind) = (
+            if symbol.global { STB_GLOBAL } else { STB_LOCAL },
+            match symbol.kind {
+                SymbolKind::Function => STT_FUNC,
+                SymbolKind::Object => STT_OBJECT,
+                SymbolKind::Label => STT_NOTYPE,
+            },
+        );
+        let name = names.add(&symbol.name);
+        write_symbol(
+            &mut symtab,
+            name,
+            (binding << 4) | kind,
+            symbol.section.header_index(),
+            symbol.offset as u64,
+            symbol.size as u64,
+        );
+    }
+    for symbol in &undefined {
+        indices.insert(symbol, (symtab.len() / SYMBOL_SIZE) as u32);
+        let name = names.add(symbol);
+        write_symbol(&mut symtab, name, (STB_GLOBAL << 4) | STT_NOTYPE, 0, 0, 0);
+    }
+
+    let mut section_names = StringTable::new();
+    let mut headers = vec![SectionHeader {
+        name: 0,
+        kind: 0,
+        flags: 0,
+        offset: 0,
+        size: 0,
+        link: 0,
+        info: 0,
+        align: 0,
+        entry_size: 0,
+    }];
+    let mut contents: Vec<Vec<u8>> = vec![Vec::new()];
+    // A section without relocations gets no relocation section, which `.bss` never could use
+    let relocated: Vec<Section> = Section::ALL
+        .into_iter()
+        .filter(|section| {
+            object
+                .relocations
+                .iter()
+                .any(|(relocated, _)| relocated == section)
+        })
+        .collect();
+    // Section headers after the ones for the code, data and their relocations
+    let symtab_index = (1 + Section::ALL.len() + relocated.len()) as u32;
+    for section in Section::ALL {
+        let (bytes, flags, align) = match section {
+            Section::Text => (&object.text[..], SHF_ALLOC | SHF_EXECINSTR, 16),
+            Section::Data => (&object.data[..], SHF_ALLOC | SHF_WRITE, 8),
+            Section::Bss => (&[][..], SHF_ALLOC | SHF_WRITE, 8),
+            Section::Rodata => (&object.rodata[..], SHF_ALLOC, 8),
+        };
+        let (kind, size) = match section {
+            Section::Bss => (SHT_NOBITS, object.bss),
+            _ => (SHT_PROGBITS, bytes.len()),
+        };
+        headers.push(SectionHeader {
+            name: section_names.add(section.name()),
+            kind,
+            flags,
+            offset: 0,
+            size,
+            link: 0,
+            info: 0,
+            align,
+            entry_size: 0,
+        });
+        contents.push(bytes.to_vec());
+    }
+    for section in relocated {
+        let mut rela = Vec::new();
+        for (_, relocation) in object
+            .relocations
+            .iter()
+            .filter(|(relocated, _)| *relocated == section)
+        {
+            let kind: u64 = match relocation.kind {
+                RelocationKind::Abs64 => 1,
+                RelocationKind::Pc32 => 2,
+                RelocationKind::Plt32 => 4,
+            };
+            let symbol = indices[relocation.symbol.as_str()] as u64;
+            rela.extend_from_slice(&(relocation.offset as u64).to_le_bytes());
+            rela.extend_from_slice(&((symbol << 32) | kind).to_le_bytes());
+            rela.extend_from_slice(&relocation.addend.to_le_bytes());
+        }
+        headers.push(SectionHeader {
+            name: section_names.add(&format!(".rela{}", section.name())),
+            kind: SHT_RELA,
+            flags: SHF_INFO_LINK,
+            offset: 0,
+            size: rela.len(),
+            link: symtab_index,
+            info: section.header_index() as u32,
+            align: 8,
+            entry_size: RELOCATION_SIZE as u64,
+        });
+        contents.push(rela);
+    }
+
+    headers.push(SectionHeader {
+        name: section_names.add(".symtab"),
+        kind: SHT_SYMTAB,
+        flags: 0,
+        offset: 0,
+        size: symtab.len(),
+        link: symtab_index + 1,
+        info: first_global as u32,
+        align: 8,
+        entry_size: SYMBOL_SIZE as u64,
+    });
+    contents.push(symtab);
+    headers.push(SectionHeader {
+        name: section_names.add(".strtab"),
+        kind: SHT_STRTAB,
+        flags: 0,
+        offset: 0,
+        size: names.bytes.len(),
+        link: 0,
+        info: 0,
+        align: 1,
+        entry_size: 0,
+    });
+    contents.push(names.bytes);
+    // Nothing here runs code from the stack, which the linker otherwise assumes it may
+    headers.push(SectionHeader {
+        name: section_names.add(".note.GNU-stack"),
+        kind: SHT_PROGBITS,
+        flags: 0,
+        offset: 0,
+        size: 0,
+        link: 0,
+        info: 0,
+        align: 1,
+        entry_size: 0,
+    });
+    contents.push(Vec::new());
+    let shstrtab_index = headers.len() as u16;
+    let shstrtab_name = section_names.add(".shstrtab");
+    headers.push(SectionHeader {
+        name: shstrtab_name,
+        kind: SHT_STRTAB,
+        flags: 0,
+        offset: 0,
+        size: section_names.bytes.len(),
+        link: 0,
+        info: 0,
+        align: 1,
+        entry_size: 0,
+    });
+    contents.push(section_names.bytes);
+
+    // The contents follow the file header, each aligned, and the section headers follow them
+    let mut file = vec![0; HEADER_SIZE];
+    for (header, bytes) in headers.iter_mut().zip(&contents).skip(1) {
+        while !file.len().is_multiple_of(header.align as usize) {
+            file.push(0);
+        }
+        header.offset = file.len();
+        file.extend_from_slice(bytes);
+    }
+    while !file.len().is_multiple_of(8) {
+        file.push(0);
+    }
+    let section_headers = file.len();
+    for header in &headers {
+        write_section_header(&mut file, header);
+    }
+    write_file_header(
+        &mut file[..HEADER_SIZE],
+        section_headers as u64,
+        headers.len() as u16,
+        shstrtab_index,
+    );
+    file
+}
+
+/// Symbols the relocations of `object` name without it defining them, in the order they're
+/// first named
+fn undefined_symbols(object: &Object) -> Vec<&str> {
+    let mut undefined: Vec<&str> = Vec::new();
+    for (_, relocation) in &object.relocations {
+        let symbol = relocation.symbol.as_str();
+        if !object.symbols.iter().any(|defined| defined.name == symbol)
+            && !undefined.contains(&symbol)
+        {
+            undefined.push(symbol);
+        }
+    }
+    undefined
+}
+
+fn write_file_header(header: &mut [u8], section_headers: u64, count: u16, shstrtab: u16) {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE);
+    // Magic number, 64-bit, little-endian, version 1, System V ABI
+    bytes.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+    bytes.extend_from_slice(&[0; 8]);
+    bytes.extend_from_slice(&1u16.to_le_bytes()); // relocatable
+    bytes.extend_from_slice(&62u16.to_le_bytes()); // x86-64
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes()); // no entry point
+    bytes.extend_from_slice(&0u64.to_le_bytes()); // no program headers
+    bytes.extend_from_slice(&section_headers.to_le_bytes());
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    bytes.extend_from_slice(&0u16.to_le_bytes());
+    bytes.extend_from_slice(&0u16.to_le_bytes());
+    bytes.extend_from_slice(&(SECTION_HEADER_SIZE as u16).to_le_bytes());
+    bytes.extend_from_slice(&count.to_le_bytes());
+    bytes.extend_from_slice(&shstrtab.to_le_bytes());
+    header.copy_from_slice(&bytes);
+}
+
+fn write_section_header(file: &mut Vec<u8>, header: &SectionHeader) {
+    file.extend_from_slice(&header.name.to_le_bytes());
+    file.extend_from_slice(&header.kind.to_le_bytes());
+    file.extend_from_slice(&header.flags.to_le_bytes());
+    file.extend_from_slice(&0u64.to_le_bytes()); // not loaded at an address yet
+    file.extend_from_slice(&(header.offset as u64).to_le_bytes());
+    file.extend_from_slice(&(header.size as u64).to_le_bytes());
+    file.extend_from_slice(&header.link.to_le_bytes());
+    file.extend_from_slice(&header.info.to_le_bytes());
+    file.extend_from_slice(&header.align.to_le_bytes());
+    file.extend_from_slice(&header.entry_size.to_le_bytes());
+}
+
+fn write_symbol(symtab: &mut Vec<u8>, name: u32, info: u8, section: u16, value: u64, size: u64) {
+    symtab.extend_from_slice(&name.to_le_bytes());
+    symtab.push(info);
+    symtab.push(0); // default visibility
+    symtab.extend_from_slice(&section.to_le_bytes());
+    symtab.extend_from_slice(&value.to_le_bytes());
+    symtab.extend_from_slice(&size.to_le_bytes());
+}
+
+const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+
+const IMAGE_SCN_CNT_CODE: u32 = 0x20;
+const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x40;
+const IMAGE_SCN_CNT_UNINITIALIZED_DATA: u32 = 0x80;
+const IMAGE_SCN_ALIGN_8BYTES: u32 = 0x0040_0000;
+const IMAGE_SCN_ALIGN_16BYTES: u32 = 0x0050_0000;
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+const IMAGE_REL_AMD64_ADDR64: u16 = 1;
+const IMAGE_REL_AMD64_REL32: u16 = 4;
+
+const IMAGE_SYM_CLASS_EXTERNAL: u8 = 2;
+const IMAGE_SYM_CLASS_STATIC: u8 = 3;
+const IMAGE_SYM_DTYPE_FUNCTION: u16 = 0x20;
+
+/// Size of the COFF file header and of each section header, relocation and symbol
+const COFF_HEADER_SIZE: usize = 20;
+const COFF_SECTION_HEADER_SIZE: usize = 40;
+const COFF_RELOCATION_SIZE: usize = 10;
+const COFF_SYMBOL_SIZE: usize = 18;
+
+/// Writes `object` as a COFF object file for x86-64, as Windows' linkers read them. Each
+/// section's contents are followed by its relocations. COFF relocations have no addend of
+/// their own, so it's written into the bytes they fill in; a `%rip`-relative one is counted
+/// from the end of its 4 bytes rather than from their start, as in ELF. Calls go to their
+/// targets directly, as there's no procedure linkage table.
+pub fn write_coff(object: &Object) -> Vec<u8> {
+    let undefined = undefined_symbols(object);
+    let mut names = Vec::new();
+    let mut symtab = Vec::new();
+    let mut indices: HashMap<&str, u32> = HashMap::new();
+    for symbol in &object.symbols {
+        indices.insert(&symbol.name, (symtab.len() / COFF_SYMBOL_SIZE) as u32);
+        let kind = match symbol.kind {
+            SymbolKind::Function => IMAGE_SYM_DTYPE_FUNCTION,
+            SymbolKind::Object | SymbolKind::Label => 0,
+        };
+        let class = match symbol.global {
+            true => IMAGE_SYM_CLASS_EXTERNAL,
+            false => IMAGE_SYM_CLASS_STATIC,
+        };
+        let section = 1 + symbol.section as u16;
+        let value = symbol.offset as u32;
+        write_coff_symbol(
+            &mut symtab,
+            &mut names,
+            &symbol.name,
+            value,
+            section,
+            kind,
+            class,
+        );
+    }
+    for symbol in &undefined {
+        indices.insert(symbol, (symtab.len() / COFF_SYMBOL_SIZE) as u32);
+        write_coff_symbol(
+            &mut symtab,
+            &mut names,
+            symbol,
+            0,
+            0,
+            0,
+            IMAGE_SYM_CLASS_EXTERNAL,
+        );
+    }
+
+    let mut file = vec![0; COFF_HEADER_SIZE + COFF_SECTION_HEADER_SIZE * Section::ALL.len()];
+    let mut headers = Vec::new();
+    for section in Section::ALL {
+        let (mut bytes, characteristics) = match section {
+            Section::Text => (
+                object.text.clone(),
+                IMAGE_SCN_CNT_CODE
+                    | IMAGE_SCN_ALIGN_16BYTES
+                    | IMAGE_SCN_MEM_EXECUTE
+                    | IMAGE_SCN_MEM_READ,
+            ),
+            Section::Data => (
+                object.data.clone(),
+                IMAGE_SCN_CNT_INITIALIZED_DATA
+                    | IMAGE_SCN_ALIGN_8BYTES
+                    | IMAGE_SCN_MEM_READ
+                    | IMAGE_SCN_MEM_WRITE,
+            ),
+            Section::Bss => (
+                Vec::new(),
+                IMAGE_SCN_CNT_UNINITIALIZED_DATA
+                    | IMAGE_SCN_ALIGN_8BYTES
+                    | IMAGE_SCN_MEM_READ
+                    | IMAGE_SCN_MEM_WRITE,
+            ),
+            Section::Rodata => (
+                object.rodata.clone(),
+                IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_ALIGN_8BYTES | IMAGE_SCN_MEM_READ,
+            ),
+        };
+        let mut relocations = Vec::new();
+        for (_, relocation) in object
+            .relocations
+            .iter()
+            .filter(|(relocated, _)| *relocated == section)
+        {
+            let field = relocation.offset;
+            let kind = match relocation.kind {
+                RelocationKind::Abs64 => {
+                    bytes[field..field + 8].copy_from_slice(&relocation.addend.to_le_bytes());
+                    IMAGE_REL_AMD64_ADDR64
+                }
+                RelocationKind::Pc32 | RelocationKind::Plt32 => {
+                    let addend = relocation.addend as i32 + 4;
+                    bytes[field..field + 4].copy_from_slice(&addend.to_le_bytes());
+                    IMAGE_REL_AMD64_REL32
+                }
+            };
+            relocations.extend_from_slice(&(field as u32).to_le_bytes());
+            relocations.extend_from_slice(&indices[relocation.symbol.as_str()].to_le_bytes());
+            relocations.extend_from_slice(&kind.to_le_bytes());
+        }
+
+        // Zeroed data only has a size
+        let (size, contents) = match section {
+            Section::Bss => (object.bss, 0),
+            _ => (bytes.len(), if bytes.is_empty() { 0 } else { file.len() }),
+        };
+        file.extend_from_slice(&bytes);
+        let relocation_count = relocations.len() / COFF_RELOCATION_SIZE;
+        let relocations_at = if relocation_count == 0 { 0 } else { file.len() };
+        file.extend_from_slice(&relocations);
+
+        let mut header = Vec::with_capacity(COFF_SECTION_HEADER_SIZE);
+        let mut name = [0; 8];
+        name[..section.coff_name().len()].copy_from_slice(section.coff_name().as_bytes());
+        header.extend_from_slice(&name);
+        header.extend_from_slice(&0u32.to_le_bytes()); // not loaded at an address yet
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&(size as u32).to_le_bytes());
+        header.extend_from_slice(&(contents as u32).to_le_bytes());
+        header.extend_from_slice(&(relocations_at as u32).to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes()); // no line numbers
+        let relocation_count = u16::try_from(relocation_count).expect("too many relocations");
+        header.extend_from_slice(&relocation_count.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&characteristics.to_le_bytes());
+        headers.extend(header);
+    }
+    file[COFF_HEADER_SIZE..COFF_HEADER_SIZE + headers.len()].copy_from_slice(&headers);
+
+    let symbols_at = file.len();
+    file.extend_from_slice(&symtab);
+    // The string table starts with its size, which counts the size itself
+    file.extend_from_slice(&(4 + names.len() as u32).to_le_bytes());
+    file.extend_from_slice(&names);
+
+    let mut header = Vec::with_capacity(COFF_HEADER_SIZE);
+    header.extend_from_slice(&IMAGE_FILE_MACHINE_AMD64.to_le_bytes());
+    header.extend_from_slice(&(Section::ALL.len() as u16).to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes()); // no timestamp, so builds are reproducible
+    header.extend_from_slice(&(symbols_at as u32).to_le_bytes());
+    header.extend_from_slice(&((symtab.len() / COFF_SYMBOL_SIZE) as u32).to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes()); // no optional header
+    header.extend_from_slice(&0u16.to_le_bytes());
+    file[..COFF_HEADER_SIZE].copy_from_slice(&header);
+    file
+}
+
+/// Writes a symbol without auxiliary records. Names longer than 8 bytes go in the string
+/// table `names`, and the symbol holds their offset into it.
+fn write_coff_symbol(
+    symtab: &mut Vec<u8>,
+    names: &mut Vec<u8>,
+    name: &str,
+    value: u32,
+    section: u16,
+    kind: u16,
+    class: u8,
+) {
+    if name.len() <= 8 {
+        let mut short = [0; 8];
+        short[..name.len()].copy_from_slice(name.as_bytes());
+        symtab.extend_from_slice(&short);
+    } else {
+        symtab.extend_from_slice(&0u32.to_le_bytes());
+        symtab.extend_from_slice(&(4 + names.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+    symtab.extend_from_slice(&value.to_le_bytes());
+    symtab.extend_from_slice(&section.to_le_bytes());
+    symtab.extend_from_slice(&kind.to_le_bytes());
+    symtab.push(class);
+    symtab.push(0); // no auxiliary records
+}
@@ -0,0 +1,163 @@
+//! Optimization passes over the abstract assembly of each function. They run after code
+//! generation, before SSA conversion and emission. `-O<level>` picks a standard pipeline, and
+//! `-f<name>` or `-fno-<name>` turns a single pass on or off. When `simplify-cfg` is enabled,
+//! it runs first and again after each of the others. Each pass runs over every function before
+//! the next one starts. Debug builds verify the instructions after every pass.
+
+use super::constant_folding::ConstantFolding;
+use super::context::Context;
+use super::dead_code::DeadCodeElimination;
+use super::inline::Inlining;
+use super::simplify_cfg::SimplifyCfg;
+use super::strength_reduction::StrengthReduction;
+use super::unroll::LoopUnrolling;
+use super::verify;
+use super::{CodegenFailure, CodegenOptions};
+use std::time::{Duration, Instant};
+
+pub trait Pass {
+    /// Name enabling the pass with `-f<name>`
+    fn name(&self) -> &'static str;
+    /// Lowest `-O` level whose pipeline includes the pass
+    fn level(&self) -> u8;
+    fn run(&self, context: &mut Context, options: &CodegenOptions);
+    /// Runs the pass on `context`, which may read the program's other `functions`. Only the
+    /// inliner does; the other passes look at one function at a time.
+    fn run_in_program(
+        &self,
+        context: &mut Context,
+        _functions: &[&Context],
+        options: &CodegenOptions,
+    ) {
+        self.run(context, options)
+    }
+}
+
+/// Every pass, in the order they run
+const PASSES: &[&dyn Pass] = &[
+    &SimplifyCfg,
+    &Inlining,
+    &LoopUnrolling,
+    &ConstantFolding,
+    &StrengthReduction,
+    &DeadCodeElimination,
+];
+
+/// Highest level `-O` accepts
+pub const MAX_OPT_LEVEL: u8 = 2;
+
+/// Names of the passes that can be enabled
+pub fn pass_names() -> Vec<&'static str> {
+    PASSES.iter().map(|pass| pass.name()).collect()
+}
+
+/// Names of the passes `-O<level>` enables; `-O0` enables none
+pub fn pipeline(level: u8) -> Vec<&'static str> {
+    PASSES
+        .iter()
+        .filter(|pass| level >= pass.level())
+        .map(|pass| pass.name())
+        .collect()
+}
+
+/// Time spent in one pass, over every function
+#[derive(Debug, Clone)]
+pub struct PassTiming {
+    pub name: &'static str,
+    pub time: Duration,
+}
+
+/// The passes to run on each function
+pub struct PassManager {
+    passes: Vec<&'static dyn Pass>,
+    /// Run after each pass to clean up the control flow it leaves
+    cleanup: Option<&'static dyn Pass>,
+    /// Time spent in each pass so far, the cleanup's last
+    times: Vec<Duration>,
+}
+
+impl PassManager {
+    /// Runs the passes named in `enabled`, in pipeline order. Unknown names are ignored, since
+    /// the driver has already rejected them.
+    pub fn new(enabled: &[String]) -> Self {
+        let (cleanup, passes): (Vec<&'static dyn Pass>, Vec<&'static dyn Pass>) = PASSES
+            .iter()
+            .copied()
+            .filter(|pass| enabled.iter().any(|name| name == pass.name()))
+            .partition(|pass| pass.name() == SimplifyCfg.name());
+        PassManager {
+            times: vec![Duration::ZERO; passes.len() + 1],
+            passes,
+            cleanup: cleanup.first().copied(),
+        }
+    }
+
+    /// Runs each pass over every function not in SSA form, then calls `after_pass` with its
+    /// name and all the functions. Stops at the first pass that leaves invalid abstract assembly.
+    pub fn run(
+        &mut self,
+        functions: &mut [Context],
+        options: &CodegenOptions,
+        after_pass: &mut dyn FnMut(&'static str, &[Context]),
+    ) -> Result<(), CodegenFailure> {
+        // The passes don't keep SSA form, so they only run on functions not in it yet
+        let optimized: Vec<bool> = functions.iter().map(|context| !context.is_ssa()).collect();
+        if let Some(cleanup) = self.cleanup {
+            self.clean_up(functions, &optimized, options)?;
+            after_pass(cleanup.name(), functions);
+        }
+        for index in 0..self.passes.len() {
+            let pass = self.passes[index];
+            for (function, _) in optimized.iter().enumerate().filter(|(_, &on)| on) {
+                // The pass may read the other functions while it changes this one
+                let (before, rest) = functions.split_at_mut(function);
+                let (context, after) = rest.split_first_mut().unwrap();
+                let others: Vec<&Context> = before.iter().chain(after.iter()).collect();
+                let start = Instant::now();
+                pass.run_in_program(context, &others, options);
+                let time = start.elapsed();
+                crate::debug!("pass '{}' on {} took {:?}", pass.name(), context.name, time);
+                self.times[index] += time;
+                verify::check(context, false, &format!("pass '{}'", pass.name()))?;
+            }
+            self.clean_up(functions, &optimized, options)?;
+            after_pass(pass.name(), functions);
+        }
+        Ok(())
+    }
+
+    fn clean_up(
+        &mut self,
+        functions: &mut [Context],
+        optimized: &[bool],
+        options: &CodegenOptions,
+    ) -> Result<(), CodegenFailure> {
+        if let Some(cleanup) = self.cleanup {
+            for (context, _) in functions.iter_mut().zip(optimized).filter(|(_, &on)| on) {
+                let start = Instant::now();
+                cleanup.run(context, options);
+                *self.times.last_mut().unwrap() += start.elapsed();
+                verify::check(context, false, &format!("pass '{}'", cleanup.name()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Time spent in each enabled pass, in pipeline order
+    pub fn timings(&self) -> Vec<PassTiming> {
+        let mut passes = self.passes.clone();
+        let mut times = self.times[..self.passes.len()].to_vec();
+        if let Some(cleanup) = self.cleanup {
+            passes.insert(0, cleanup);
+            times.insert(0, *self.times.last().unwrap());
+        }
+        passes
+            .into_iter()
+            .zip(times)
+            .map(|(pass, time)| PassTiming {
+                name: pass.name(),
+                time,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,126 @@
+//! Linear scan register allocation, as an alternative to coloring the interference graph. Each
+//! temp is given one interval, from the first point it's live at to the last, and the intervals
+//! are assigned registers in the order they start. When the registers run out, the interval
+//! ending last is spilled. Intervals are rougher than liveness, so this may spill temps that
+//! coloring wouldn't, but it never builds the graph.
+//! See Poletto and Sarkar, "Linear Scan Register Allocation" (TOPLAS, 1999)
+
+use super::{Assignment, Dependency, Node, Output};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Points where a temp is live, in between its first and last. Each line has two points: one
+/// where it reads its uses, and one after it, where it writes its definition. A temp read for
+/// the last time on a line can then share a register with the temp the line defines. The
+/// registers a line clobbers are busy at both.
+#[derive(Debug, Clone, Copy)]
+struct Interval {
+    start: usize,
+    end: usize,
+}
+
+/// Assigns temps to at most `registers.len()` registers, like `_allocate_registers`, from the
+/// liveness in `dependencies`
+pub(super) fn allocate_registers(
+    registers: &[usize],
+    dependencies: &[Dependency],
+    unspillable: &HashSet<Node>,
+) -> Output {
+    // Temps are live over their intervals, while pre-colored registers are only busy at the
+    // exact points they're live at, which are found in order
+    let mut intervals: HashMap<Node, Interval> = HashMap::new();
+    let mut busy: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (line, dependency) in dependencies.iter().enumerate() {
+        let (read, write) = (2 * line, 2 * line + 1);
+        let reads = dependency.live_in.iter().chain(&dependency.uses);
+        let writes = dependency.live_out.iter().chain(&dependency.defines);
+        let clobbers = dependency.clobbers.iter();
+        let points = reads
+            .chain(clobbers.clone())
+            .map(|node| (node, read))
+            .chain(writes.chain(clobbers).map(|node| (node, write)));
+        for (node, point) in points {
+            match node {
+                Node::Temp(_) => {
+                    let interval = intervals.entry(*node).or_insert(Interval {
+                        start: point,
+                        end: point,
+                    });
+                    interval.end = interval.end.max(point);
+                }
+                Node::Register(register) => {
+                    let points = busy.entry(*register).or_default();
+                    if points.last() != Some(&point) {
+                        points.push(point);
+                    }
+                }
+            }
+        }
+    }
+    // Whether `register` is live at any point of `interval` for an instruction that needs it
+    let is_busy = |register: usize, interval: Interval| {
+        busy.get(&register).is_some_and(|points| {
+            let first = points.partition_point(|&point| point < interval.start);
+            points
+                .get(first)
+                .is_some_and(|&point| point <= interval.end)
+        })
+    };
+
+    let mut intervals: Vec<(Node, Interval)> = intervals.into_iter().collect();
+    intervals.sort_by_key(|(node, interval)| (interval.start, interval.end, *node));
+    let mut active: Vec<(Node, Interval, usize)> = Vec::new();
+    let mut assigned: HashMap<Node, usize> = HashMap::new();
+    let mut spillover = BTreeSet::new();
+    for (node, interval) in intervals {
+        // Intervals that ended before this one started give their registers back
+        active.retain(|(_, other, _)| other.end >= interval.start);
+
+        let free = registers.iter().copied().find(|register| {
+            !is_busy(*register, interval) && !active.iter().any(|(_, _, taken)| taken == register)
+        });
+        if let Some(register) = free {
+            active.push((node, interval, register));
+            assigned.insert(node, register);
+            continue;
+        }
+
+        // Out of registers: spill whichever ends last, this interval or an active one whose
+        // register it could take. Temps that can't be spilled are never picked.
+        let candidate = active
+            .iter()
+            .enumerate()
+            .filter(|(_, (other, _, register))| {
+                !unspillable.contains(other) && !is_busy(*register, interval)
+            })
+            .max_by_key(|(_, (_, other, _))| other.end)
+            .map(|(index, _)| index);
+        match candidate {
+            Some(index) if active[index].1.end > interval.end || unspillable.contains(&node) => {
+                let (spilled, _, register) = active.swap_remove(index);
+                assigned.remove(&spilled);
+                spillover.insert(spilled);
+                active.push((node, interval, register));
+                assigned.insert(node, register);
+            }
+            _ => {
+                spillover.insert(node);
+            }
+        }
+    }
+
+    let assignments = dependencies
+        .iter()
+        .map(|dependency| {
+            let temp = dependency.defines?;
+            let register = match temp {
+                Node::Temp(_) => *assigned.get(&temp)?,
+                Node::Register(register) => register,
+            };
+            Some(Assignment { temp, register })
+        })
+        .collect();
+    Output {
+        assignments,
+        spillover,
+    }
+}
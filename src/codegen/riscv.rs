@@ -0,0 +1,852 @@
+//! RISC-V code generation, for RV32IM under the ilp32 calling convention. Ints, chars and
+//! strings all take a 32-bit register, chars zero-extended. Doubles aren't supported, since
+//! RV32IM has no floating-point registers.
+//!
+//! Arguments are passed in a0 to a7, and past eight on the stack, a word each, with the value
+//! returned in a0. Temps are kept in t4 to t6 and in the callee-saved s1 to s11, which the
+//! function saves before it writes them, or in stack slots when those run out. The other
+//! temporaries are the selector's own: t0 and t1 hold operands loaded from slots or
+//! constants, t2 addresses and constants it needs in between, and t3 the outcome of the last
+//! comparison. Each function keeps s0 pointing where the stack pointer was on entry, so that
+//! its slots and the caller's stack arguments are at fixed offsets from it.
+
+pub mod allocation;
+
+use super::context::{
+    AbstractAssemblyInstruction, Arithmetic, AsmLabel, Condition, Context, Conversion, Dest,
+    Operand, ShiftKind,
+};
+use super::register_allocator::RegisterDescription;
+use super::runtime;
+use crate::parser::{BinOp, FormatSpec, UnOp};
+use crate::sema::Type;
+use allocation::{Allocation, Location};
+use std::fmt;
+use std::io;
+
+/// The 32 integer registers, in encoding order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Zero,
+    Ra,
+    Sp,
+    Gp,
+    Tp,
+    T0,
+    T1,
+    T2,
+    S0,
+    S1,
+    A0,
+    A1,
+    A2,
+    A3,
+    A4,
+    A5,
+    A6,
+    A7,
+    S2,
+    S3,
+    S4,
+    S5,
+    S6,
+    S7,
+    S8,
+    S9,
+    S10,
+    S11,
+    T3,
+    T4,
+    T5,
+    T6,
+}
+
+impl Register {
+    pub const ALL: [Register; 32] = [
+        Register::Zero,
+        Register::Ra,
+        Register::Sp,
+        Register::Gp,
+        Register::Tp,
+        Register::T0,
+        Register::T1,
+        Register::T2,
+        Register::S0,
+        Register::S1,
+        Register::A0,
+        Register::A1,
+        Register::A2,
+        Register::A3,
+        Register::A4,
+        Register::A5,
+        Register::A6,
+        Register::A7,
+        Register::S2,
+        Register::S3,
+        Register::S4,
+        Register::S5,
+        Register::S6,
+        Register::S7,
+        Register::S8,
+        Register::S9,
+        Register::S10,
+        Register::S11,
+        Register::T3,
+        Register::T4,
+        Register::T5,
+        Register::T6,
+    ];
+
+    /// Registers the first eight arguments are passed in
+    pub const ARGUMENTS: [Register; 8] = [
+        Register::A0,
+        Register::A1,
+        Register::A2,
+        Register::A3,
+        Register::A4,
+        Register::A5,
+        Register::A6,
+        Register::A7,
+    ];
+
+    /// Callee-saved registers temps are assigned; s0 is the frame pointer
+    pub const CALLEE_SAVED: [Register; 11] = [
+        Register::S1,
+        Register::S2,
+        Register::S3,
+        Register::S4,
+        Register::S5,
+        Register::S6,
+        Register::S7,
+        Register::S8,
+        Register::S9,
+        Register::S10,
+        Register::S11,
+    ];
+
+    /// The register's number, x0 to x31
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for Register {
+    /// Writes the register by its ABI name
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+/// The registers temps are assigned: three caller-saved temporaries, then the callee-saved
+/// registers, which are the only ones a temp live across a call can have
+pub fn register_description() -> RegisterDescription {
+    let temporaries = [Register::T4, Register::T5, Register::T6];
+    RegisterDescription {
+        int: temporaries
+            .iter()
+            .chain(&Register::CALLEE_SAVED)
+            .map(|register| register.index())
+            .collect(),
+        double: Vec::new(),
+        callee_saved: Register::CALLEE_SAVED
+            .iter()
+            .map(|register| register.index())
+            .collect(),
+    }
+}
+
+/// The instructions the selector writes, base RV32I and M's, and the assembler's
+/// pseudo-instructions among them like `li` and `call`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mnemonic {
+    Add,
+    Addi,
+    Sub,
+    Mul,
+    Div,
+    Andi,
+    Xor,
+    Xori,
+    Slli,
+    Srli,
+    Srai,
+    Slt,
+    Lw,
+    Sw,
+    Li,
+    Lla,
+    Mv,
+    Neg,
+    Not,
+    Seqz,
+    Snez,
+    Sltz,
+    Sgtz,
+    Beqz,
+    Bnez,
+    Bltz,
+    Bgez,
+    Bgtz,
+    Blez,
+    Bne,
+    J,
+    Call,
+    Ret,
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RiscvOperand {
+    Register(Register),
+    Immediate(i32),
+    /// The word at a register plus an offset
+    Memory(i32, Register),
+    /// A label branched to, a function called or a string whose address is taken
+    Symbol(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RiscvInstruction {
+    Op(Mnemonic, Vec<RiscvOperand>),
+    Label(String),
+    /// Inline assembly, its operands already written in
+    Asm(String),
+}
+
+/// A function, as the RISC-V instructions it compiles into, its prologue and epilogues
+/// included
+#[derive(Debug)]
+pub struct RiscvFunction {
+    pub name: String,
+    /// Symbol the function is defined as, which is its name unless it's mangled
+    pub symbol: String,
+    /// True if the function is `static`, and so not exported
+    pub is_static: bool,
+    pub instructions: Vec<RiscvInstruction>,
+    /// Stack slots the function's temps are spilled to
+    pub spill_slots: usize,
+}
+
+pub fn string_symbol(index: usize) -> String {
+    format!(".LS{}", index)
+}
+
+/// Scratch registers for operands that aren't in a register of their own
+const SCRATCH: [Register; 2] = [Register::T0, Register::T1];
+/// Scratch register for addresses and constants the selector needs between loading the
+/// operands and writing the result
+const ADDRESS: Register = Register::T2;
+/// Register the last comparison leaves -1, 0 or 1 in, as the left operand was less, equal or
+/// greater
+const ORDERING: Register = Register::T3;
+
+/// Bytes the return address and the caller's frame pointer take at the top of the frame
+const LINKAGE: i32 = 8;
+
+/// Selects the RISC-V instructions for `context`, keeping its temps in the registers of
+/// `registers` as far as they go. Fails on what RV32IM has no instructions for.
+pub fn select_instructions(
+    context: &Context,
+    registers: &RegisterDescription,
+) -> io::Result<RiscvFunction> {
+    for instruction in &context.instructions {
+        check_supported(context, instruction)?;
+    }
+    let allocation = allocation::allocate(context, registers);
+    let mut selector = Selector {
+        function: &context.name,
+        allocation: &allocation,
+        instructions: Vec::new(),
+        next_label: context.label_count(),
+        outgoing: 0,
+    };
+    for instruction in &context.instructions {
+        selector.select(context, instruction)?;
+    }
+    let body = std::mem::take(&mut selector.instructions);
+    selector.prologue(context.params);
+    selector.instructions.extend(body);
+    Ok(RiscvFunction {
+        name: context.name.clone(),
+        symbol: context.name.clone(),
+        is_static: context.is_static,
+        instructions: selector.instructions,
+        spill_slots: allocation.slots,
+    })
+}
+
+/// Fails if `instruction` needs what the RISC-V backend can't compile
+fn check_supported(context: &Context, instruction: &AbstractAssemblyInstruction) -> io::Result<()> {
+    use AbstractAssemblyInstruction as A;
+    let doubles = instruction
+        .operands()
+        .iter()
+        .any(|operand| matches!(operand, Operand::Double(_)))
+        || instruction
+            .dest()
+            .is_some_and(|dest| context.dest_type(dest) == Type::Double);
+    let unsupported = doubles
+        || matches!(
+            instruction,
+            A::BinOp {
+                arithmetic: Arithmetic::Double,
+                ..
+            } | A::UnOp {
+                arithmetic: Arithmetic::Double,
+                ..
+            } | A::Compare {
+                arithmetic: Arithmetic::Double,
+                ..
+            } | A::Convert {
+                conversion: Conversion::I2D | Conversion::D2I,
+                ..
+            } | A::Print {
+                spec: FormatSpec::Double,
+                ..
+            } | A::Scan {
+                spec: FormatSpec::Double,
+                ..
+            }
+        );
+    match unsupported {
+        true => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{}: the RISC-V target can't compile doubles", context.name),
+        )),
+        false => Ok(()),
+    }
+}
+
+struct Selector<'a> {
+    function: &'a str,
+    allocation: &'a Allocation,
+    instructions: Vec<RiscvInstruction>,
+    /// Labels from this number on are free for the selector's own branches
+    next_label: usize,
+    /// Words of stack arguments the function passes, at the bottom of its frame
+    outgoing: usize,
+}
+
+impl Selector<'_> {
+    fn emit(&mut self, mnemonic: Mnemonic, operands: Vec<RiscvOperand>) {
+        self.instructions
+            .push(RiscvInstruction::Op(mnemonic, operands));
+    }
+
+    /// An instruction on registers, like `add t0, t1, t2`
+    fn registers(&mut self, mnemonic: Mnemonic, registers: &[Register]) {
+        let operands = registers
+            .iter()
+            .map(|register| RiscvOperand::Register(*register))
+            .collect();
+        self.emit(mnemonic, operands);
+    }
+
+    /// An instruction on registers and an immediate last, like `addi t0, t1, 4`
+    fn immediate(&mut self, mnemonic: Mnemonic, registers: &[Register], immediate: i32) {
+        let mut operands: Vec<RiscvOperand> = registers
+            .iter()
+            .map(|register| RiscvOperand::Register(*register))
+            .collect();
+        operands.push(RiscvOperand::Immediate(immediate));
+        self.emit(mnemonic, operands);
+    }
+
+    /// A load or store of `register` at `offset` from `base`
+    fn memory(&mut self, mnemonic: Mnemonic, register: Register, offset: i32, base: Register) {
+        self.emit(
+            mnemonic,
+            vec![
+                RiscvOperand::Register(register),
+                RiscvOperand::Memory(offset, base),
+            ],
+        );
+    }
+
+    /// An instruction on registers and a symbol last, like `beqz t3, .Lmain_4`
+    fn symbol(&mut self, mnemonic: Mnemonic, registers: &[Register], symbol: &str) {
+        let mut operands: Vec<RiscvOperand> = registers
+            .iter()
+            .map(|register| RiscvOperand::Register(*register))
+            .collect();
+        operands.push(RiscvOperand::Symbol(symbol.to_string()));
+        self.emit(mnemonic, operands);
+    }
+
+    fn label(&mut self, label: &str) {
+        self.instructions
+            .push(RiscvInstruction::Label(label.to_string()));
+    }
+
+    /// A label of the function, local to it
+    fn asm_label(&self, label: AsmLabel) -> String {
+        format!(".L{}_{}", self.function, label.0)
+    }
+
+    /// A label for the selector's own branches
+    fn new_label(&mut self) -> String {
+        self.next_label += 1;
+        self.asm_label(AsmLabel(self.next_label - 1))
+    }
+
+    /// Callee-saved registers the function saves, each at its own word under the linkage
+    fn saved(&self) -> &[Register] {
+        &self.allocation.saved
+    }
+
+    /// Offset from s0 of the spill slot `slot`, under the saved registers
+    fn slot_offset(&self, slot: usize) -> i32 {
+        -(LINKAGE + 4 * self.saved().len() as i32 + 4 * (slot as i32 + 1))
+    }
+
+    /// Loads or stores `register` at `offset` from s0, going through t2 for an offset past
+    /// the twelve bits an instruction takes
+    fn frame_word(&mut self, mnemonic: Mnemonic, register: Register, offset: i32) {
+        if fits_immediate(offset) {
+            self.memory(mnemonic, register, offset, Register::S0);
+        } else {
+            self.immediate(Mnemonic::Li, &[ADDRESS], offset);
+            self.registers(Mnemonic::Add, &[ADDRESS, ADDRESS, Register::S0]);
+            self.memory(mnemonic, register, 0, ADDRESS);
+        }
+    }
+
+    fn location(&self, dest: &Dest) -> Location {
+        match dest {
+            Dest::Temp(temp) => self.allocation.locations[temp],
+            Dest::Register(_) => unreachable!("registers are only assigned by the backend"),
+        }
+    }
+
+    /// Puts `operand` in `register`
+    fn load_into(&mut self, operand: &Operand, register: Register) {
+        match operand {
+            Operand::Var(dest) => match self.location(dest) {
+                Location::Register(source) if source == register => {}
+                Location::Register(source) => self.registers(Mnemonic::Mv, &[register, source]),
+                Location::Slot(slot) => {
+                    self.frame_word(Mnemonic::Lw, register, self.slot_offset(slot))
+                }
+            },
+            Operand::Const(value) => self.immediate(Mnemonic::Li, &[register], *value as i32),
+            Operand::Str(index) => self.symbol(Mnemonic::Lla, &[register], &string_symbol(*index)),
+            Operand::Double(_) => unreachable!("doubles are rejected before selection"),
+        }
+    }
+
+    /// The register holding `operand`: its own, zero for 0, or `scratch` it's loaded into
+    fn read(&mut self, operand: &Operand, scratch: Register) -> Register {
+        match operand {
+            Operand::Var(dest) => match self.location(dest) {
+                Location::Register(register) => register,
+                Location::Slot(_) => {
+                    self.load_into(operand, scratch);
+                    scratch
+                }
+            },
+            Operand::Const(0) => Register::Zero,
+            _ => {
+                self.load_into(operand, scratch);
+                scratch
+            }
+        }
+    }
+
+    /// The register to compute `dest` in: its own, or t0 to be stored with `write`
+    fn target(&self, dest: &Dest) -> Register {
+        match self.location(dest) {
+            Location::Register(register) => register,
+            Location::Slot(_) => SCRATCH[0],
+        }
+    }
+
+    /// Stores `register`, which `target` gave for `dest`, in `dest`'s slot if it has one
+    fn write(&mut self, dest: &Dest, register: Register) {
+        if let Location::Slot(slot) = self.location(dest) {
+            self.frame_word(Mnemonic::Sw, register, self.slot_offset(slot));
+        }
+    }
+
+    /// Computes `dest` from `src` with `mnemonic`, like `neg`
+    fn unary(&mut self, mnemonic: Mnemonic, dest: &Dest, src: &Operand) {
+        let src = self.read(src, SCRATCH[0]);
+        let target = self.target(dest);
+        self.registers(mnemonic, &[target, src]);
+        self.write(dest, target);
+    }
+
+    /// Computes `dest` from `src` and an immediate with `mnemonic`, like `andi`
+    fn unary_immediate(&mut self, mnemonic: Mnemonic, dest: &Dest, src: &Operand, value: i32) {
+        let src = self.read(src, SCRATCH[0]);
+        let target = self.target(dest);
+        self.immediate(mnemonic, &[target, src], value);
+        self.write(dest, target);
+    }
+
+    /// Sets up the frame, saves the callee-saved registers the function writes and receives
+    /// its parameters. The frame holds the linkage, the saved registers, the spill slots and
+    /// the outgoing stack arguments, rounded up to keep the stack pointer 16-byte aligned.
+    fn prologue(&mut self, params: usize) {
+        use Register::{Ra, Sp, S0};
+        let words = self.saved().len() + self.allocation.slots + self.outgoing;
+        let size = (LINKAGE + 4 * words as i32 + 15) / 16 * 16;
+        self.immediate(Mnemonic::Addi, &[Sp, Sp], -16);
+        self.memory(Mnemonic::Sw, Ra, 12, Sp);
+        self.memory(Mnemonic::Sw, S0, 8, Sp);
+        self.immediate(Mnemonic::Addi, &[S0, Sp], 16);
+        let rest = size - 16;
+        if rest > 0 && fits_immediate(-rest) {
+            self.immediate(Mnemonic::Addi, &[Sp, Sp], -rest);
+        } else if rest > 0 {
+            self.immediate(Mnemonic::Li, &[SCRATCH[0]], rest);
+            self.registers(Mnemonic::Sub, &[Sp, Sp, SCRATCH[0]]);
+        }
+        for (index, register) in self.saved().to_vec().into_iter().enumerate() {
+            self.memory(Mnemonic::Sw, register, saved_offset(index), S0);
+        }
+        for param in 0..params {
+            // A parameter that's never read has no place to go
+            let Some(&location) = self.allocation.locations.get(&param) else {
+                continue;
+            };
+            let target = match location {
+                Location::Register(register) => register,
+                Location::Slot(_) => SCRATCH[0],
+            };
+            match Register::ARGUMENTS.get(param) {
+                Some(&argument) => self.registers(Mnemonic::Mv, &[target, argument]),
+                None => self.memory(Mnemonic::Lw, target, 4 * (param as i32 - 8), S0),
+            }
+            self.write(&Dest::Temp(param), target);
+        }
+    }
+
+    /// Restores the saved registers and the caller's frame, and returns
+    fn epilogue(&mut self) {
+        use Register::{Ra, Sp, S0};
+        for (index, register) in self.saved().to_vec().into_iter().enumerate() {
+            self.memory(Mnemonic::Lw, register, saved_offset(index), S0);
+        }
+        self.immediate(Mnemonic::Addi, &[Sp, S0], -16);
+        self.memory(Mnemonic::Lw, Ra, 12, Sp);
+        self.memory(Mnemonic::Lw, S0, 8, Sp);
+        self.immediate(Mnemonic::Addi, &[Sp, Sp], 16);
+        self.emit(Mnemonic::Ret, Vec::new());
+    }
+
+    fn select(
+        &mut self,
+        context: &Context,
+        instruction: &AbstractAssemblyInstruction,
+    ) -> io::Result<()> {
+        use AbstractAssemblyInstruction as A;
+        match instruction {
+            A::Mov { dest, src } => {
+                let target = self.target(dest);
+                self.load_into(src, target);
+                self.write(dest, target);
+            }
+            A::BinOp {
+                op: op @ (BinOp::Add | BinOp::Sub),
+                dest,
+                src1,
+                src2: Operand::Const(value),
+                ..
+            } if fits_immediate(immediate_addend(*op, *value)) => {
+                let addend = immediate_addend(*op, *value);
+                self.unary_immediate(Mnemonic::Addi, dest, src1, addend);
+            }
+            A::BinOp {
+                op: BinOp::Div,
+                dest,
+                src1,
+                src2,
+                ..
+            } => self.divide(dest, src1, src2),
+            A::BinOp {
+                op,
+                dest,
+                src1,
+                src2,
+                ..
+            } => {
+                let left = self.read(src1, SCRATCH[0]);
+                let right = self.read(src2, SCRATCH[1]);
+                let target = self.target(dest);
+                match op {
+                    BinOp::Add => self.registers(Mnemonic::Add, &[target, left, right]),
+                    BinOp::Sub => self.registers(Mnemonic::Sub, &[target, left, right]),
+                    BinOp::Mul => self.registers(Mnemonic::Mul, &[target, left, right]),
+                    BinOp::Equal | BinOp::NotEqual => {
+                        let test = match op {
+                            BinOp::Equal => Mnemonic::Seqz,
+                            _ => Mnemonic::Snez,
+                        };
+                        self.registers(Mnemonic::Xor, &[target, left, right]);
+                        self.registers(test, &[target, target]);
+                    }
+                    BinOp::Less | BinOp::GreaterEqual => {
+                        self.registers(Mnemonic::Slt, &[target, left, right]);
+                        if *op == BinOp::GreaterEqual {
+                            self.immediate(Mnemonic::Xori, &[target, target], 1);
+                        }
+                    }
+                    BinOp::Greater | BinOp::LessEqual => {
+                        self.registers(Mnemonic::Slt, &[target, right, left]);
+                        if *op == BinOp::LessEqual {
+                            self.immediate(Mnemonic::Xori, &[target, target], 1);
+                        }
+                    }
+                    BinOp::Div => unreachable!("division is selected on its own"),
+                }
+                self.write(dest, target);
+            }
+            A::UnOp { op, dest, src, .. } => {
+                let mnemonic = match op {
+                    UnOp::Neg => Mnemonic::Neg,
+                    UnOp::BitNot => Mnemonic::Not,
+                    UnOp::Not => Mnemonic::Seqz,
+                };
+                self.unary(mnemonic, dest, src);
+            }
+            A::Convert { dest, src, .. } => self.unary_immediate(Mnemonic::Andi, dest, src, 0xff),
+            A::Shift {
+                kind,
+                dest,
+                src,
+                amount,
+            } => {
+                let mnemonic = match kind {
+                    ShiftKind::Left => Mnemonic::Slli,
+                    ShiftKind::ArithmeticRight => Mnemonic::Srai,
+                    ShiftKind::LogicalRight => Mnemonic::Srli,
+                };
+                self.unary_immediate(mnemonic, dest, src, *amount as i32);
+            }
+            A::Compare { left, right, .. } => {
+                // (right < left) - (left < right)
+                let left = self.read(left, SCRATCH[0]);
+                let right = self.read(right, SCRATCH[1]);
+                self.registers(Mnemonic::Slt, &[ADDRESS, left, right]);
+                self.registers(Mnemonic::Slt, &[ORDERING, right, left]);
+                self.registers(Mnemonic::Sub, &[ORDERING, ORDERING, ADDRESS]);
+            }
+            A::SetIf { dest, condition } => {
+                let target = self.target(dest);
+                let (test, negate) = match condition {
+                    Condition::Equal => (Mnemonic::Seqz, false),
+                    Condition::NotEqual => (Mnemonic::Snez, false),
+                    Condition::Less => (Mnemonic::Sltz, false),
+                    Condition::Greater => (Mnemonic::Sgtz, false),
+                    Condition::LessOrEqual => (Mnemonic::Sgtz, true),
+                    Condition::GreaterOrEqual => (Mnemonic::Sltz, true),
+                };
+                self.registers(test, &[target, ORDERING]);
+                if negate {
+                    self.immediate(Mnemonic::Xori, &[target, target], 1);
+                }
+                self.write(dest, target);
+            }
+            A::JmpCondition {
+                condition,
+                tgt_true,
+                tgt_false,
+            } => {
+                // A conditional branch only reaches 4 KiB, so it jumps over the jump to the
+                // false target instead
+                let taken = self.new_label();
+                let branch = match condition {
+                    Condition::Equal => Mnemonic::Beqz,
+                    Condition::NotEqual => Mnemonic::Bnez,
+                    Condition::Less => Mnemonic::Bltz,
+                    Condition::Greater => Mnemonic::Bgtz,
+                    Condition::LessOrEqual => Mnemonic::Blez,
+                    Condition::GreaterOrEqual => Mnemonic::Bgez,
+                };
+                self.symbol(branch, &[ORDERING], &taken);
+                self.symbol(Mnemonic::J, &[], &self.asm_label(*tgt_false));
+                self.label(&taken);
+                self.symbol(Mnemonic::J, &[], &self.asm_label(*tgt_true));
+            }
+            A::Jmp(label) => self.symbol(Mnemonic::J, &[], &self.asm_label(*label)),
+            A::Lbl(label) => self.label(&self.asm_label(*label)),
+            A::Loc { .. } => {}
+            A::Call {
+                dest,
+                function,
+                args,
+            } => self.call(function, args, dest.as_ref()),
+            A::Load { dest, global } => {
+                let target = self.target(dest);
+                self.symbol(Mnemonic::Lla, &[ADDRESS], global);
+                self.memory(Mnemonic::Lw, target, 0, ADDRESS);
+                self.write(dest, target);
+            }
+            A::Store { global, src } => {
+                let src = self.read(src, SCRATCH[0]);
+                self.symbol(Mnemonic::Lla, &[ADDRESS], global);
+                self.memory(Mnemonic::Sw, src, 0, ADDRESS);
+            }
+            A::Asm {
+                template,
+                output,
+                inputs,
+                constraints,
+            } => {
+                if constraints.names_registers() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!(
+                            "{}: inline assembly can't name registers on the RISC-V target",
+                            context.name
+                        ),
+                    ));
+                }
+                self.asm(context, template, output.as_ref(), inputs)?
+            }
+            A::Abort => self.symbol(Mnemonic::Call, &[], runtime::ABORT),
+            A::Return(operand) => {
+                self.load_into(operand, Register::A0);
+                self.epilogue();
+            }
+            A::ReturnVoid => self.epilogue(),
+            A::Phi { .. } => unreachable!("phis are removed before instruction selection"),
+            A::Print { spec, src } => self.call(
+                runtime::print_function(spec),
+                std::slice::from_ref(src),
+                None,
+            ),
+            A::Scan { dest, spec } => self.call(runtime::scan_function(spec), &[], Some(dest)),
+        }
+        Ok(())
+    }
+
+    /// Divides, aborting first on a divisor of zero and on the least int divided by -1, for
+    /// which `div` doesn't trap. A constant divisor other than those needs no checks.
+    fn divide(&mut self, dest: &Dest, src1: &Operand, src2: &Operand) {
+        let left = self.read(src1, SCRATCH[0]);
+        let right = self.read(src2, SCRATCH[1]);
+        if !matches!(src2, Operand::Const(value) if *value as i32 != 0 && *value as i32 != -1) {
+            let fail = self.new_label();
+            let divide = self.new_label();
+            self.symbol(Mnemonic::Beqz, &[right], &fail);
+            self.immediate(Mnemonic::Li, &[ADDRESS], -1);
+            self.symbol(Mnemonic::Bne, &[right, ADDRESS], &divide);
+            self.immediate(Mnemonic::Li, &[ADDRESS], i32::MIN);
+            self.symbol(Mnemonic::Bne, &[left, ADDRESS], &divide);
+            self.label(&fail);
+            self.symbol(Mnemonic::Call, &[], runtime::ABORT);
+            self.label(&divide);
+        }
+        let target = self.target(dest);
+        self.registers(Mnemonic::Div, &[target, left, right]);
+        self.write(dest, target);
+    }
+
+    /// Passes `args` to `function`, the first eight in registers and the rest at the bottom
+    /// of the frame, calls it, and copies what it returns to `dest`
+    fn call(&mut self, function: &str, args: &[Operand], dest: Option<&Dest>) {
+        for (index, arg) in args.iter().enumerate().skip(Register::ARGUMENTS.len()) {
+            let value = self.read(arg, SCRATCH[0]);
+            let offset = 4 * (index - Register::ARGUMENTS.len()) as i32;
+            self.memory(Mnemonic::Sw, value, offset, Register::Sp);
+        }
+        self.outgoing = self
+            .outgoing
+            .max(args.len().saturating_sub(Register::ARGUMENTS.len()));
+        // No temp is kept in an argument register, so each can be written in turn
+        for (arg, register) in args.iter().zip(Register::ARGUMENTS) {
+            self.load_into(arg, register);
+        }
+        self.symbol(Mnemonic::Call, &[], function);
+        if let Some(dest) = dest {
+            let target = self.target(dest);
+            self.registers(Mnemonic::Mv, &[target, Register::A0]);
+            self.write(dest, target);
+        }
+    }
+
+    /// Inline assembly, each operand in its register, or in t0 and t1 for those that don't
+    /// have one: loaded before for an input, and stored after for the output
+    fn asm(
+        &mut self,
+        context: &Context,
+        template: &str,
+        output: Option<&Dest>,
+        inputs: &[Operand],
+    ) -> io::Result<()> {
+        let mut scratch = SCRATCH.iter().copied();
+        let mut scratch = |what: &str| {
+            scratch.next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!(
+                        "{}: inline assembly has more than {} {} without a register",
+                        context.name,
+                        SCRATCH.len(),
+                        what
+                    ),
+                )
+            })
+        };
+        let mut operands = Vec::new();
+        let output = match output {
+            Some(dest) => match self.location(dest) {
+                Location::Register(register) => Some((dest, register)),
+                Location::Slot(_) => Some((dest, scratch("operands")?)),
+            },
+            None => None,
+        };
+        operands.extend(output.map(|(_, register)| register.to_string()));
+        for input in inputs {
+            let register = match input {
+                Operand::Var(dest) => match self.location(dest) {
+                    Location::Register(register) => Some(register),
+                    Location::Slot(_) => None,
+                },
+                _ => None,
+            };
+            let register = match register {
+                Some(register) => register,
+                None => {
+                    let register = scratch("operands")?;
+                    self.load_into(input, register);
+                    register
+                }
+            };
+            operands.push(register.to_string());
+        }
+        self.instructions
+            .push(RiscvInstruction::Asm(super::m6502::substitute_operands(
+                template, &operands,
+            )));
+        if let Some((dest, register)) = output {
+            self.write(dest, register);
+        }
+        Ok(())
+    }
+}
+
+/// Offset from s0 of the `index`th saved register, under the linkage
+fn saved_offset(index: usize) -> i32 {
+    -(LINKAGE + 4 * (index as i32 + 1))
+}
+
+/// Whether `value` fits the twelve-bit signed immediate of an `addi` or a load
+fn fits_immediate(value: i32) -> bool {
+    (-2048..2048).contains(&value)
+}
+
+/// What `addi` adds to compute `op` with the constant `value`, if it's an addition or a
+/// subtraction
+fn immediate_addend(op: BinOp, value: i128) -> i32 {
+    match op {
+        BinOp::Sub => (value as i32).wrapping_neg(),
+        _ => value as i32,
+    }
+}
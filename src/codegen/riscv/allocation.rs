@@ -0,0 +1,227 @@
+//! Register allocation on the abstract assembly, by coloring the interference graph greedily.
+//! The temps used most are colored first, each with the first register of the target's
+//! description that no temp it interferes with has. Temps live across a call may only be
+//! given callee-saved registers, since the call may overwrite the others. Those left without
+//! a register are kept in stack slots, which temps that don't interfere share.
+
+use super::Register;
+use crate::codegen::context::{AbstractAssemblyInstruction, Context, Dest, Operand};
+use crate::codegen::register_allocator::RegisterDescription;
+use std::collections::{BTreeSet, HashMap};
+
+/// Where a temp is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Register(Register),
+    /// A word of the frame, by its index among the spill slots
+    Slot(usize),
+}
+
+/// Where each temp of a function is kept
+#[derive(Debug)]
+pub struct Allocation {
+    pub locations: HashMap<usize, Location>,
+    /// Spill slots in use
+    pub slots: usize,
+    /// Callee-saved registers given to temps, which the function saves and restores
+    pub saved: Vec<Register>,
+}
+
+/// Assigns each temp of `context` a register of `registers`, or a stack slot
+pub fn allocate(context: &Context, registers: &RegisterDescription) -> Allocation {
+    let instructions = &context.instructions;
+    let live_out = live_out(instructions);
+    let interference = interference(context, &live_out);
+    // Temps whose value a call has to keep
+    let across_calls: BTreeSet<usize> = instructions
+        .iter()
+        .zip(&live_out)
+        .filter(|(instruction, _)| is_call(instruction))
+        .flat_map(|(instruction, live)| {
+            let dest = defined_temp(instruction);
+            live.iter().copied().filter(move |&temp| Some(temp) != dest)
+        })
+        .collect();
+
+    let mut uses: HashMap<usize, usize> = interference.keys().map(|&temp| (temp, 0)).collect();
+    for instruction in instructions {
+        for temp in used_temps(instruction).chain(defined_temp(instruction)) {
+            *uses.entry(temp).or_default() += 1;
+        }
+    }
+    let mut temps: Vec<usize> = uses.keys().copied().collect();
+    temps.sort_by_key(|temp| (std::cmp::Reverse(uses[temp]), *temp));
+
+    let register = |index: usize| Register::ALL[index];
+    let mut locations: HashMap<usize, Location> = HashMap::new();
+    let mut slots = 0;
+    for temp in temps {
+        let neighbors: Vec<Location> = interference
+            .get(&temp)
+            .into_iter()
+            .flatten()
+            .filter_map(|neighbor| locations.get(neighbor).copied())
+            .collect();
+        let free = registers
+            .int
+            .iter()
+            .map(|&index| register(index))
+            .filter(|candidate| {
+                !across_calls.contains(&temp) || registers.callee_saved.contains(&candidate.index())
+            })
+            .find(|candidate| !neighbors.contains(&Location::Register(*candidate)));
+        let location = match free {
+            Some(candidate) => Location::Register(candidate),
+            None => {
+                let slot = (0..)
+                    .find(|slot| !neighbors.contains(&Location::Slot(*slot)))
+                    .expect("there's a slot past every temp's");
+                slots = slots.max(slot + 1);
+                Location::Slot(slot)
+            }
+        };
+        locations.insert(temp, location);
+    }
+
+    let saved = registers
+        .callee_saved
+        .iter()
+        .map(|&index| register(index))
+        .filter(|saved| {
+            locations
+                .values()
+                .any(|location| *location == Location::Register(*saved))
+        })
+        .collect();
+    Allocation {
+        locations,
+        slots,
+        saved,
+    }
+}
+
+/// Whether `instruction` calls a function, which may overwrite the caller-saved registers
+fn is_call(instruction: &AbstractAssemblyInstruction) -> bool {
+    matches!(
+        instruction,
+        AbstractAssemblyInstruction::Call { .. }
+            | AbstractAssemblyInstruction::Print { .. }
+            | AbstractAssemblyInstruction::Scan { .. }
+    )
+}
+
+/// Temps `instruction` reads
+fn used_temps(instruction: &AbstractAssemblyInstruction) -> impl Iterator<Item = usize> + '_ {
+    instruction
+        .operands()
+        .into_iter()
+        .filter_map(|operand| match operand {
+            Operand::Var(Dest::Temp(temp)) => Some(*temp),
+            _ => None,
+        })
+}
+
+/// Temp `instruction` writes, if any
+fn defined_temp(instruction: &AbstractAssemblyInstruction) -> Option<usize> {
+    match instruction.dest() {
+        Some(Dest::Temp(temp)) => Some(*temp),
+        _ => None,
+    }
+}
+
+/// Lines that may run right after each line
+fn successors(instructions: &[AbstractAssemblyInstruction]) -> Vec<Vec<usize>> {
+    use AbstractAssemblyInstruction as A;
+    let labels: HashMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, instruction)| match instruction {
+            A::Lbl(label) => Some((label.0, index)),
+            _ => None,
+        })
+        .collect();
+    instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| match instruction {
+            A::Jmp(target) => vec![labels[&target.0]],
+            A::JmpCondition {
+                tgt_true,
+                tgt_false,
+                ..
+            } => vec![labels[&tgt_true.0], labels[&tgt_false.0]],
+            A::Return(_) | A::ReturnVoid | A::Abort => Vec::new(),
+            _ if index + 1 < instructions.len() => vec![index + 1],
+            _ => Vec::new(),
+        })
+        .collect()
+}
+
+/// Temps live after each line, and, past the last, those live on entry, found by iterating
+/// the dataflow equations backward until nothing changes
+fn live_out(instructions: &[AbstractAssemblyInstruction]) -> Vec<BTreeSet<usize>> {
+    let successors = successors(instructions);
+    let mut live_in: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); instructions.len()];
+    let mut live_out: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); instructions.len() + 1];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in (0..instructions.len()).rev() {
+            let out: BTreeSet<usize> = successors[index]
+                .iter()
+                .flat_map(|&successor| live_in[successor].iter().copied())
+                .collect();
+            let mut in_ = out.clone();
+            if let Some(temp) = defined_temp(&instructions[index]) {
+                in_.remove(&temp);
+            }
+            in_.extend(used_temps(&instructions[index]));
+            if in_ != live_in[index] {
+                live_in[index] = in_;
+                changed = true;
+            }
+            live_out[index] = out;
+        }
+    }
+    live_out[instructions.len()] = live_in.first().cloned().unwrap_or_default();
+    live_out
+}
+
+/// The temps each temp can't share a location with: those live where it's written, and each
+/// other of the parameters and those live on entry, since the parameters are all received
+/// there
+fn interference(
+    context: &Context,
+    live_out: &[BTreeSet<usize>],
+) -> HashMap<usize, BTreeSet<usize>> {
+    let instructions = &context.instructions;
+    let mut interference: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+    let mut add = |a: usize, b: usize| {
+        interference.entry(a).or_default();
+        interference.entry(b).or_default();
+        if a != b {
+            interference.get_mut(&a).unwrap().insert(b);
+            interference.get_mut(&b).unwrap().insert(a);
+        }
+    };
+    for (instruction, live) in instructions.iter().zip(live_out) {
+        if let Some(temp) = defined_temp(instruction) {
+            add(temp, temp);
+            for &other in live {
+                add(temp, other);
+            }
+        }
+        for temp in used_temps(instruction) {
+            add(temp, temp);
+        }
+    }
+    let mut entry: BTreeSet<usize> = live_out[instructions.len()].clone();
+    entry.extend(0..context.params);
+    let entry: Vec<usize> = entry.into_iter().collect();
+    for (index, &a) in entry.iter().enumerate() {
+        for &b in &entry[index..] {
+            add(a, b);
+        }
+    }
+    interference
+}
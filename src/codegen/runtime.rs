@@ -0,0 +1,254 @@
+//! The runtime x86 programs link against: a function for each of C0's conversions, which
+//! prints one value, one for each of the numbers `scan` reads, and one that aborts. Each is an
+//! assembly stub calling into the C library, which prints the value with `printf` and the
+//! format string its conversion stands for, so that the program prints what the interpreter
+//! would, and reads a number with `scanf`, aborting if the input doesn't go on with one.
+//! Aborting flushes what was printed first, since `abort` doesn't. The stubs only address their format strings from %rip and call
+//! through the procedure linkage table, so they link into shared libraries too.
+//!
+//! RISC-V programs link against the same functions, but for those of doubles, which that
+//! target doesn't compile.
+
+use super::emit::{escape_x86_string, serialize_format_spec};
+use super::object::Format;
+use super::x86::CallingConvention;
+use crate::parser::FormatSpec;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// The conversions, in the order their functions are written
+const CONVERSIONS: [FormatSpec; 4] = [
+    FormatSpec::Int,
+    FormatSpec::Char,
+    FormatSpec::Double,
+    FormatSpec::String,
+];
+
+/// The numbers `scan` reads, in the order their functions are written, with the format
+/// `scanf` reads each with
+const SCANS: [(FormatSpec, &str); 2] = [(FormatSpec::Int, "%d"), (FormatSpec::Double, "%lf")];
+
+/// Function that aborts the program, when an assertion or a contract fails
+pub const ABORT: &str = "c0_abort";
+
+/// Function that prints a value as `spec` formats it
+pub fn print_function(spec: &FormatSpec) -> &'static str {
+    match spec {
+        FormatSpec::Int => "c0_print_int",
+        FormatSpec::Char => "c0_print_char",
+        FormatSpec::Double => "c0_print_double",
+        FormatSpec::String => "c0_print_string",
+    }
+}
+
+/// Function that reads a number formatted as `spec`, `%d` or `%f`, and returns it
+pub fn scan_function(spec: &FormatSpec) -> &'static str {
+    match spec {
+        FormatSpec::Double => "c0_scan_double",
+        _ => "c0_scan_int",
+    }
+}
+
+/// Writes the runtime for x86 code calling functions under `convention` and assembled into
+/// objects of `format` to `outpath`, as assembly
+pub fn emit_runtime(
+    outpath: &Path,
+    convention: CallingConvention,
+    format: Format,
+) -> io::Result<()> {
+    let mut file = File::create(outpath)?;
+    write_runtime(&mut file, convention, format)
+}
+
+fn write_runtime(
+    file: &mut impl Write,
+    convention: CallingConvention,
+    format: Format,
+) -> io::Result<()> {
+    // ELF calls go through the PLT, which COFF leaves to the linker's import stubs
+    let plt = match format {
+        Format::Elf => "@PLT",
+        Format::Coff => "",
+    };
+    file.write_all(b"\t.text\n")?;
+    for (index, spec) in CONVERSIONS.iter().enumerate() {
+        write_header(file, print_function(spec), format)?;
+        // The value moves over to make room for the format string, as printf's second argument.
+        // A double passed to a variadic function under the Microsoft calling convention goes in
+        // both registers of its position.
+        let moves: &[&str] = match (convention, spec) {
+            (CallingConvention::SystemV, FormatSpec::Double) => &["movl $1, %eax"],
+            (CallingConvention::SystemV, FormatSpec::String) => {
+                &["movq %rdi, %rsi", "xorl %eax, %eax"]
+            }
+            (CallingConvention::SystemV, _) => &["movl %edi, %esi", "xorl %eax, %eax"],
+            (CallingConvention::Microsoft, FormatSpec::Double) => {
+                &["movapd %xmm0, %xmm1", "movq %xmm0, %rdx"]
+            }
+            (CallingConvention::Microsoft, FormatSpec::String) => &["movq %rcx, %rdx"],
+            (CallingConvention::Microsoft, _) => &["movl %ecx, %edx"],
+        };
+        for line in moves {
+            writeln!(file, "\t{}", line)?;
+        }
+        let first = match convention {
+            CallingConvention::SystemV => "%rdi",
+            CallingConvention::Microsoft => "%rcx",
+        };
+        writeln!(file, "\tleaq {}(%rip), {}", format_symbol(index), first)?;
+        // printf returns straight to the caller, with the stack as the caller left it
+        writeln!(file, "\tjmp printf{}", plt)?;
+        write_footer(file, print_function(spec), format)?;
+    }
+
+    for (index, (spec, _)) in SCANS.iter().enumerate() {
+        write_header(file, scan_function(spec), format)?;
+        // The number is read into a slot above the aligned stack, and the shadow space under
+        // the Microsoft calling convention
+        let (reserved, slot, first, second) = match convention {
+            CallingConvention::SystemV => (24, 8, "%rdi", "%rsi"),
+            CallingConvention::Microsoft => (56, 40, "%rcx", "%rdx"),
+        };
+        writeln!(file, "\tsubq ${}, %rsp", reserved)?;
+        writeln!(file, "\tleaq {}(%rsp), {}", slot, second)?;
+        writeln!(file, "\tleaq {}(%rip), {}", scan_symbol(index), first)?;
+        if convention == CallingConvention::SystemV {
+            writeln!(file, "\txorl %eax, %eax")?;
+        }
+        writeln!(file, "\tcall scanf{}", plt)?;
+        writeln!(file, "\tcmpl $1, %eax")?;
+        writeln!(file, "\tje {}", scanned_label(index))?;
+        writeln!(file, "\tcall {}{}", ABORT, plt)?;
+        writeln!(file, "{}:", scanned_label(index))?;
+        match spec {
+            FormatSpec::Double => writeln!(file, "\tmovsd {}(%rsp), %xmm0", slot)?,
+            _ => writeln!(file, "\tmovl {}(%rsp), %eax", slot)?,
+        }
+        writeln!(file, "\taddq ${}, %rsp", reserved)?;
+        writeln!(file, "\tret")?;
+        write_footer(file, scan_function(spec), format)?;
+    }
+
+    write_header(file, ABORT, format)?;
+    // Aligns the stack for the calls, with the shadow space under the Microsoft calling
+    // convention, and flushes every stream
+    let (reserved, first) = match convention {
+        CallingConvention::SystemV => (8, "%edi"),
+        CallingConvention::Microsoft => (40, "%ecx"),
+    };
+    writeln!(file, "\tsubq ${}, %rsp", reserved)?;
+    writeln!(file, "\txorl {0}, {0}", first)?;
+    writeln!(file, "\tcall fflush{}", plt)?;
+    writeln!(file, "\tcall abort{}", plt)?;
+    write_footer(file, ABORT, format)?;
+
+    match format {
+        Format::Elf => file.write_all(b"\t.section .rodata\n")?,
+        Format::Coff => file.write_all(b"\t.section .rdata,\"dr\"\n")?,
+    }
+    for (index, spec) in CONVERSIONS.iter().enumerate() {
+        writeln!(file, "{}:", format_symbol(index))?;
+        let string = serialize_format_spec(spec);
+        writeln!(file, "\t.string \"{}\"", escape_x86_string(&string))?;
+    }
+    for (index, (_, string)) in SCANS.iter().enumerate() {
+        writeln!(file, "{}:", scan_symbol(index))?;
+        writeln!(file, "\t.string \"{}\"", string)?;
+    }
+    match format {
+        Format::Elf => file.write_all(b"\t.section .note.GNU-stack,\"\",@progbits\n"),
+        Format::Coff => Ok(()),
+    }
+}
+
+/// Writes the runtime for RISC-V code to `outpath`, as assembly
+pub fn emit_riscv_runtime(outpath: &Path) -> io::Result<()> {
+    let mut file = File::create(outpath)?;
+    write_riscv_runtime(&mut file)
+}
+
+fn write_riscv_runtime(file: &mut impl Write) -> io::Result<()> {
+    let conversions = || {
+        CONVERSIONS
+            .iter()
+            .enumerate()
+            .filter(|(_, spec)| **spec != FormatSpec::Double)
+    };
+    file.write_all(b"\t.text\n")?;
+    for (index, spec) in conversions() {
+        write_header(file, print_function(spec), Format::Elf)?;
+        // The value moves over to make room for the format string, and printf returns
+        // straight to the caller
+        writeln!(file, "\tmv a1, a0")?;
+        writeln!(file, "\tlla a0, {}", format_symbol(index))?;
+        writeln!(file, "\ttail printf")?;
+        write_footer(file, print_function(spec), Format::Elf)?;
+    }
+
+    let (spec, _) = &SCANS[0];
+    write_header(file, scan_function(spec), Format::Elf)?;
+    writeln!(file, "\taddi sp, sp, -16")?;
+    writeln!(file, "\tsw ra, 12(sp)")?;
+    writeln!(file, "\tmv a1, sp")?;
+    writeln!(file, "\tlla a0, {}", scan_symbol(0))?;
+    writeln!(file, "\tcall scanf")?;
+    writeln!(file, "\tli t0, 1")?;
+    writeln!(file, "\tbeq a0, t0, {}", scanned_label(0))?;
+    writeln!(file, "\tcall {}", ABORT)?;
+    writeln!(file, "{}:", scanned_label(0))?;
+    writeln!(file, "\tlw a0, 0(sp)")?;
+    writeln!(file, "\tlw ra, 12(sp)")?;
+    writeln!(file, "\taddi sp, sp, 16")?;
+    writeln!(file, "\tret")?;
+    write_footer(file, scan_function(spec), Format::Elf)?;
+
+    write_header(file, ABORT, Format::Elf)?;
+    writeln!(file, "\taddi sp, sp, -16")?;
+    writeln!(file, "\tli a0, 0")?;
+    writeln!(file, "\tcall fflush")?;
+    writeln!(file, "\tcall abort")?;
+    write_footer(file, ABORT, Format::Elf)?;
+
+    file.write_all(b"\t.section .rodata\n")?;
+    for (index, spec) in conversions() {
+        writeln!(file, "{}:", format_symbol(index))?;
+        let string = serialize_format_spec(spec);
+        writeln!(file, "\t.string \"{}\"", escape_x86_string(&string))?;
+    }
+    writeln!(file, "{}:", scan_symbol(0))?;
+    writeln!(file, "\t.string \"{}\"", SCANS[0].1)?;
+    file.write_all(b"\t.section .note.GNU-stack,\"\",@progbits\n")
+}
+
+/// Exports the function `name` and starts it
+fn write_header(file: &mut impl Write, name: &str, format: Format) -> io::Result<()> {
+    writeln!(file, "\t.globl {}", name)?;
+    match format {
+        Format::Elf => writeln!(file, "\t.type {}, @function", name)?,
+        Format::Coff => writeln!(file, "\t.def {}; .scl 2; .type 32; .endef", name)?,
+    }
+    writeln!(file, "{}:", name)
+}
+
+fn write_footer(file: &mut impl Write, name: &str, format: Format) -> io::Result<()> {
+    match format {
+        Format::Elf => writeln!(file, "\t.size {0}, .-{0}", name),
+        Format::Coff => Ok(()),
+    }
+}
+
+/// Symbol of the format string of the conversion at `index`
+fn format_symbol(index: usize) -> String {
+    format!(".Lc0_format{}", index)
+}
+
+/// Symbol of the format string `scan` reads the number at `index` of `SCANS` with
+fn scan_symbol(index: usize) -> String {
+    format!(".Lc0_scan{}", index)
+}
+
+/// Label the function reading the number at `index` of `SCANS` goes on from once it has
+fn scanned_label(index: usize) -> String {
+    format!(".Lc0_scanned{}", index)
+}
@@ -0,0 +1,247 @@
+//! Control flow graph cleanup: branches on comparisons of two constants become jumps, jumps to
+//! a block that only jumps on are sent straight to its target, blocks that can't be reached
+//! are deleted, and a block that is the only way into the next one on its path is merged with
+//! it. Jumps to the label right after them, and labels nothing jumps to, are removed last.
+//!
+//! When enabled, the pass manager runs this after every other pass, since their changes to
+//! branches are what leave blocks to clean up.
+
+use super::cfg::ControlFlowGraph;
+use super::context::{AbstractAssemblyInstruction, AsmLabel, Context, Operand};
+use super::pass::Pass;
+use super::CodegenOptions;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+pub struct SimplifyCfg;
+
+impl Pass for SimplifyCfg {
+    fn name(&self) -> &'static str {
+        "simplify-cfg"
+    }
+
+    fn level(&self) -> u8 {
+        1
+    }
+
+    fn run(&self, context: &mut Context, _options: &CodegenOptions) {
+        // Each step can give the others more to do, like a folded branch leaving a block
+        // unreachable, so they repeat until nothing changes
+        loop {
+            let mut changed = fold_constant_branches(&mut context.instructions);
+            let instructions = mem::take(&mut context.instructions);
+            let mut cfg = ControlFlowGraph::new(instructions, || AsmLabel(context.new_label()));
+            changed |= thread_jumps(&mut cfg);
+            // Rebuilt for the edges the new targets make
+            let instructions = cfg.into_instructions();
+            let cfg = ControlFlowGraph::new(instructions, || AsmLabel(context.new_label()));
+            let (instructions, merged) = merge_blocks(cfg);
+            context.instructions = instructions;
+            if !changed && !merged {
+                break;
+            }
+        }
+        remove_redundant_jumps(&mut context.instructions);
+        remove_unused_labels(&mut context.instructions);
+    }
+}
+
+/// Replaces branches whose outcome is known with jumps: either both targets are the same, or
+/// the comparison before the branch is of two constants. Returns true if any were replaced.
+fn fold_constant_branches(instructions: &mut Vec<AbstractAssemblyInstruction>) -> bool {
+    let mut changed = false;
+    let mut index = 0;
+    while index < instructions.len() {
+        let AbstractAssemblyInstruction::JmpCondition {
+            condition,
+            tgt_true,
+            tgt_false,
+        } = &instructions[index]
+        else {
+            index += 1;
+            continue;
+        };
+        let compared = match index.checked_sub(1).map(|previous| &instructions[previous]) {
+            Some(AbstractAssemblyInstruction::Compare { left, right, .. }) => match (left, right) {
+                (Operand::Const(left), Operand::Const(right)) => {
+                    Some((*left as i32).cmp(&(*right as i32)))
+                }
+                (Operand::Double(left), Operand::Double(right)) => left.partial_cmp(right),
+                _ => None,
+            },
+            _ => None,
+        };
+        let target = match compared {
+            Some(ordering) if condition.holds(ordering) => *tgt_true,
+            Some(_) => *tgt_false,
+            None if tgt_true.0 == tgt_false.0 => *tgt_true,
+            None => {
+                index += 1;
+                continue;
+            }
+        };
+        instructions[index] = AbstractAssemblyInstruction::Jmp(target);
+        // Nothing else reads the flags of a comparison right before a branch
+        if let Some(AbstractAssemblyInstruction::Compare { .. }) =
+            index.checked_sub(1).map(|previous| &instructions[previous])
+        {
+            instructions.remove(index - 1);
+            index -= 1;
+        }
+        changed = true;
+        index += 1;
+    }
+    changed
+}
+
+/// Sends jumps to a block that does nothing but go on to another one straight to where it
+/// goes. Returns true if any jump changed.
+fn thread_jumps(cfg: &mut ControlFlowGraph) -> bool {
+    // Where control goes from each block that does nothing else, by label. Line marks don't
+    // count, so that `-g` doesn't change the code.
+    let forwards: HashMap<usize, AsmLabel> = cfg
+        .blocks
+        .iter()
+        .enumerate()
+        .filter_map(|(index, block)| {
+            let work: Vec<_> = block
+                .instructions
+                .iter()
+                .filter(|instruction| {
+                    !matches!(instruction, AbstractAssemblyInstruction::Loc { .. })
+                })
+                .collect();
+            match work.as_slice() {
+                [AbstractAssemblyInstruction::Jmp(target)] => Some((block.label.0, *target)),
+                [] => cfg
+                    .blocks
+                    .get(index + 1)
+                    .map(|next| (block.label.0, next.label)),
+                _ => None,
+            }
+        })
+        .collect();
+    let destination = |label: AsmLabel| {
+        let mut seen = HashSet::new();
+        let mut current = label;
+        while let Some(&next) = forwards.get(&current.0) {
+            // A cycle of jumps is an infinite loop, and stays one
+            if !seen.insert(current.0) {
+                return label;
+            }
+            current = next;
+        }
+        current
+    };
+
+    let mut changed = false;
+    for block in &mut cfg.blocks {
+        for target in block
+            .instructions
+            .iter_mut()
+            .flat_map(|instruction| instruction.jump_targets_mut())
+        {
+            let destination = destination(*target);
+            if destination.0 != target.0 {
+                *target = destination;
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// Merges each block with its successor when it's the successor's only predecessor and the
+/// successor is its only one, laying out chains of such blocks in the place of their first
+/// block. Returns the instructions, and true if any blocks were merged.
+fn merge_blocks(cfg: ControlFlowGraph) -> (Vec<AbstractAssemblyInstruction>, bool) {
+    // The block each block is merged with, if any
+    let next: Vec<Option<usize>> = (0..cfg.len())
+        .map(|block| match cfg.successors(block) {
+            [successor]
+                if *successor != 0
+                    && *successor != block
+                    && cfg.predecessors(*successor) == [block]
+                    && !matches!(
+                        cfg.blocks[block].instructions.last(),
+                        Some(AbstractAssemblyInstruction::JmpCondition { .. })
+                    ) =>
+            {
+                Some(*successor)
+            }
+            _ => None,
+        })
+        .collect();
+    let merged: HashSet<usize> = next.iter().flatten().copied().collect();
+
+    let labels: Vec<AsmLabel> = cfg.blocks.iter().map(|block| block.label).collect();
+    let mut blocks: Vec<Option<Vec<AbstractAssemblyInstruction>>> = cfg
+        .blocks
+        .into_iter()
+        .map(|block| Some(block.instructions))
+        .collect();
+    let mut instructions = Vec::new();
+    for first in 0..blocks.len() {
+        if merged.contains(&first) {
+            continue;
+        }
+        instructions.push(AbstractAssemblyInstruction::Lbl(labels[first]));
+        let mut block = first;
+        loop {
+            let mut body = blocks[block].take().expect("each block is in one chain");
+            match next[block] {
+                Some(successor) => {
+                    if let Some(AbstractAssemblyInstruction::Jmp(_)) = body.last() {
+                        body.pop();
+                    }
+                    instructions.extend(body);
+                    block = successor;
+                }
+                None => {
+                    let falls_through = body.last().is_none_or(|last| !last.is_terminator());
+                    instructions.extend(body);
+                    // The block may no longer be laid out before the one it falls into
+                    if let (true, Some(&label)) = (falls_through, labels.get(block + 1)) {
+                        if block != first {
+                            instructions.push(AbstractAssemblyInstruction::Jmp(label));
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    (instructions, !merged.is_empty())
+}
+
+/// Removes jumps to a label that comes right after them
+fn remove_redundant_jumps(instructions: &mut Vec<AbstractAssemblyInstruction>) {
+    let mut index = 0;
+    while index < instructions.len() {
+        if let AbstractAssemblyInstruction::Jmp(target) = &instructions[index] {
+            let next_to_target = instructions[index + 1..]
+                .iter()
+                .map_while(|instruction| match instruction {
+                    AbstractAssemblyInstruction::Lbl(label) => Some(label.0),
+                    _ => None,
+                })
+                .any(|label| label == target.0);
+            if next_to_target {
+                instructions.remove(index);
+                continue;
+            }
+        }
+        index += 1;
+    }
+}
+
+fn remove_unused_labels(instructions: &mut Vec<AbstractAssemblyInstruction>) {
+    let targets: HashSet<usize> = instructions
+        .iter_mut()
+        .flat_map(|instruction| instruction.jump_targets_mut())
+        .map(|target| target.0)
+        .collect();
+    instructions.retain(
+        |instruction| !matches!(instruction, AbstractAssemblyInstruction::Lbl(label) if !targets.contains(&label.0)),
+    );
+}
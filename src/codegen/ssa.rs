@@ -0,0 +1,191 @@
+//! Conversion of a function's abstract assembly to static single assignment form, where every
+//! temp is written by exactly one instruction and `phi` instructions merge the values reaching
+//! a block from its predecessors.
+//!
+//! The construction is the classic one of Cytron et al.: split the instructions into basic
+//! blocks, place a `phi` at the iterated dominance frontier of every temp's definitions, and
+//! rename the temps along the dominator tree.
+//!
+//! Phis are only placed where their temp is live ("pruned" SSA), so that every source of a
+//! phi is written on the way to it; a phi for a temp that's written again before it's read
+//! would take its value from paths where it was never written. A temp read before any write,
+//! like a parameter, keeps its number as the value it has on entry.
+
+use super::cfg::ControlFlowGraph;
+use super::context::{AbstractAssemblyInstruction, AsmLabel, Context, Dest, Operand};
+use super::dominators::DominatorTree;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::mem;
+
+/// Temp an operand reads, if any
+fn operand_temp(operand: &Operand) -> Option<usize> {
+    match operand {
+        Operand::Var(Dest::Temp(temp)) => Some(*temp),
+        _ => None,
+    }
+}
+
+/// Rewrites one function into SSA form
+pub struct SSABuilder<'a> {
+    context: &'a mut Context,
+    cfg: ControlFlowGraph,
+    dominators: DominatorTree,
+    /// Original temp of each phi at the start of each block, in order
+    phi_temps: Vec<Vec<usize>>,
+    /// Current version of each temp being renamed, innermost last
+    versions: HashMap<usize, Vec<usize>>,
+}
+
+impl<'a> SSABuilder<'a> {
+    /// Converts the instructions of `context` to SSA form. Every block of the result starts
+    /// with a label, and its phis come right after it.
+    pub fn convert_to_ssa(context: &'a mut Context) {
+        let instructions = mem::take(&mut context.instructions);
+        let cfg = ControlFlowGraph::new(instructions, || AsmLabel(context.new_label()));
+        let mut builder = SSABuilder {
+            context,
+            dominators: DominatorTree::new(&cfg),
+            phi_temps: vec![Vec::new(); cfg.len()],
+            cfg,
+            versions: HashMap::new(),
+        };
+        builder.insert_phi_nodes();
+        builder.rename_variables(0);
+        builder.context.instructions = builder.cfg.into_instructions();
+    }
+
+    /// Places an empty phi for each temp at the iterated dominance frontier of its writes.
+    /// The sources are filled in by `rename_variables`.
+    fn insert_phi_nodes(&mut self) {
+        // Temps each block reads before writing them, and the blocks writing each temp
+        let mut upward_reads: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); self.cfg.len()];
+        let mut writes: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); self.cfg.len()];
+        let mut writers: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+        for (block, data) in self.cfg.blocks.iter().enumerate() {
+            for instruction in &data.instructions {
+                for temp in instruction.operands().into_iter().filter_map(operand_temp) {
+                    if !writes[block].contains(&temp) {
+                        upward_reads[block].insert(temp);
+                    }
+                }
+                if let Some(Dest::Temp(temp)) = instruction.dest() {
+                    writes[block].insert(*temp);
+                    writers.entry(*temp).or_default().insert(block);
+                }
+            }
+        }
+        let live_in = self.live_in(&upward_reads, &writes);
+
+        let live_across: BTreeSet<usize> = upward_reads.iter().flatten().copied().collect();
+        for temp in live_across {
+            let Some(blocks) = writers.get(&temp) else {
+                continue;
+            };
+            let mut worklist: Vec<usize> = blocks.iter().copied().collect();
+            let mut has_phi = BTreeSet::new();
+            while let Some(block) = worklist.pop() {
+                for &join in self.dominators.frontier(block) {
+                    if !live_in[join].contains(&temp) || !has_phi.insert(join) {
+                        continue;
+                    }
+                    let phis = &mut self.phi_temps[join];
+                    self.cfg.blocks[join].instructions.insert(
+                        phis.len(),
+                        AbstractAssemblyInstruction::Phi {
+                            dest: Dest::Temp(temp),
+                            srcs: Vec::new(),
+                        },
+                    );
+                    phis.push(temp);
+                    // The phi is a new write of the temp
+                    if !blocks.contains(&join) {
+                        worklist.push(join);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Temps live on entry to each block: read by it or a block after it before they're
+    /// written again
+    fn live_in(
+        &self,
+        upward_reads: &[BTreeSet<usize>],
+        writes: &[BTreeSet<usize>],
+    ) -> Vec<BTreeSet<usize>> {
+        let mut live_in = upward_reads.to_vec();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            // Liveness flows backwards, so later blocks go first
+            for block in (0..self.cfg.len()).rev() {
+                for &successor in self.cfg.successors(block) {
+                    let live: Vec<usize> = live_in[successor]
+                        .iter()
+                        .filter(|temp| !writes[block].contains(temp))
+                        .copied()
+                        .collect();
+                    for temp in live {
+                        changed |= live_in[block].insert(temp);
+                    }
+                }
+            }
+        }
+        live_in
+    }
+
+    /// Gives every write of a temp a fresh temp, and points every read at the version reaching
+    /// it, walking the dominator tree from `block`
+    fn rename_variables(&mut self, block: usize) {
+        let mut renamed = Vec::new();
+        let phi_count = self.phi_temps[block].len();
+        for (index, instruction) in self.cfg.blocks[block].instructions.iter_mut().enumerate() {
+            // The sources of phis are renamed from their predecessors
+            if index >= phi_count {
+                for operand in instruction.operands_mut() {
+                    if let Operand::Var(Dest::Temp(temp)) = operand {
+                        *temp = current_version(&self.versions, *temp);
+                    }
+                }
+            }
+            if let Some(Dest::Temp(temp)) = instruction.dest_mut() {
+                let version = self.context.new_temp_like(*temp);
+                self.versions.entry(*temp).or_default().push(version);
+                renamed.push(*temp);
+                *temp = version;
+            }
+        }
+
+        let label = self.cfg.blocks[block].label;
+        for successor in self.cfg.successors(block).to_vec() {
+            for (index, &temp) in self.phi_temps[successor].iter().enumerate() {
+                let version = current_version(&self.versions, temp);
+                if let AbstractAssemblyInstruction::Phi { srcs, .. } =
+                    &mut self.cfg.blocks[successor].instructions[index]
+                {
+                    srcs.push((Operand::Var(Dest::Temp(version)), label));
+                }
+            }
+        }
+
+        for child in self.dominators.children(block).to_vec() {
+            self.rename_variables(child);
+        }
+
+        for temp in renamed {
+            if let Some(stack) = self.versions.get_mut(&temp) {
+                stack.pop();
+            }
+        }
+    }
+}
+
+/// Version of `temp` reaching the current point. A temp not written yet is read as itself, which
+/// holds its value on entry.
+fn current_version(versions: &HashMap<usize, Vec<usize>>, temp: usize) -> usize {
+    versions
+        .get(&temp)
+        .and_then(|stack| stack.last())
+        .copied()
+        .unwrap_or(temp)
+}
@@ -0,0 +1,147 @@
+//! Strength reduction and algebraic simplification: multiplications and divisions by powers of
+//! two become shifts, and operations that give back one of their operands, like `x * 1` or
+//! `x + 0`, become moves.
+//!
+//! Doubles are only simplified where the result is exactly the same, including for NaN and
+//! negative zero, so `x + 0.0` and `x - x` are left alone for them.
+
+use super::context::{AbstractAssemblyInstruction, Arithmetic, Context, Dest, Operand, ShiftKind};
+use super::pass::Pass;
+use super::CodegenOptions;
+use crate::parser::BinOp;
+use crate::sema::Type;
+use std::mem;
+
+pub struct StrengthReduction;
+
+impl Pass for StrengthReduction {
+    fn name(&self) -> &'static str {
+        "strength-reduce"
+    }
+
+    fn level(&self) -> u8 {
+        2
+    }
+
+    fn run(&self, context: &mut Context, _options: &CodegenOptions) {
+        let instructions = mem::take(&mut context.instructions);
+        let mut reduced = Vec::with_capacity(instructions.len());
+        for instruction in instructions {
+            match instruction {
+                AbstractAssemblyInstruction::BinOp {
+                    op,
+                    arithmetic,
+                    dest,
+                    src1,
+                    src2,
+                } => reduced.extend(reduce(context, op, arithmetic, dest, src1, src2)),
+                _ => reduced.push(instruction),
+            }
+        }
+        context.instructions = reduced;
+    }
+}
+
+/// Instructions computing `dest <- src1 op src2` more cheaply
+fn reduce(
+    context: &mut Context,
+    op: BinOp,
+    arithmetic: Arithmetic,
+    dest: Dest,
+    src1: Operand,
+    src2: Operand,
+) -> Vec<AbstractAssemblyInstruction> {
+    let mov = |src| {
+        vec![AbstractAssemblyInstruction::Mov {
+            dest: dest.clone(),
+            src,
+        }]
+    };
+    let shift = |kind, src, amount| AbstractAssemblyInstruction::Shift {
+        kind,
+        dest: dest.clone(),
+        src,
+        amount,
+    };
+    match (arithmetic, op, &src1, &src2) {
+        (Arithmetic::Int, BinOp::Add, _, Operand::Const(0))
+        | (Arithmetic::Int, BinOp::Sub, _, Operand::Const(0))
+        | (Arithmetic::Int, BinOp::Mul | BinOp::Div, _, Operand::Const(1)) => mov(src1),
+        (Arithmetic::Int, BinOp::Add, Operand::Const(0), _)
+        | (Arithmetic::Int, BinOp::Mul, Operand::Const(1), _) => mov(src2),
+        (Arithmetic::Int, BinOp::Mul, _, Operand::Const(0))
+        | (Arithmetic::Int, BinOp::Mul, Operand::Const(0), _) => mov(Operand::Const(0)),
+        (
+            Arithmetic::Int,
+            BinOp::Sub,
+            Operand::Var(Dest::Temp(left)),
+            Operand::Var(Dest::Temp(right)),
+        ) if left == right => mov(Operand::Const(0)),
+        (Arithmetic::Int, BinOp::Mul, _, _) if power_of_two(&src2).is_some() => {
+            vec![shift(ShiftKind::Left, src1, power_of_two(&src2).unwrap())]
+        }
+        (Arithmetic::Int, BinOp::Mul, _, _) if power_of_two(&src1).is_some() => {
+            vec![shift(ShiftKind::Left, src2, power_of_two(&src1).unwrap())]
+        }
+        (Arithmetic::Int, BinOp::Div, _, _) if power_of_two(&src2).is_some() => {
+            let amount = power_of_two(&src2).unwrap();
+            // An arithmetic shift rounds down, but division truncates toward zero, so a
+            // negative dividend is first biased by 2^amount - 1: the sign, shifted into the
+            // low bits
+            let sign = Dest::Temp(context.new_temp(Type::Int));
+            let bias = Dest::Temp(context.new_temp(Type::Int));
+            let biased = Dest::Temp(context.new_temp(Type::Int));
+            vec![
+                AbstractAssemblyInstruction::Shift {
+                    kind: ShiftKind::ArithmeticRight,
+                    dest: sign.clone(),
+                    src: src1.clone(),
+                    amount: 31,
+                },
+                AbstractAssemblyInstruction::Shift {
+                    kind: ShiftKind::LogicalRight,
+                    dest: bias.clone(),
+                    src: Operand::Var(sign),
+                    amount: 32 - amount,
+                },
+                AbstractAssemblyInstruction::BinOp {
+                    op: BinOp::Add,
+                    arithmetic: Arithmetic::Int,
+                    dest: biased.clone(),
+                    src1,
+                    src2: Operand::Var(bias),
+                },
+                shift(ShiftKind::ArithmeticRight, Operand::Var(biased), amount),
+            ]
+        }
+        (Arithmetic::Double, BinOp::Mul | BinOp::Div, _, Operand::Double(value))
+            if *value == 1.0 =>
+        {
+            mov(src1)
+        }
+        (Arithmetic::Double, BinOp::Mul, Operand::Double(value), _) if *value == 1.0 => mov(src2),
+        // Subtracting negative zero would turn a negative zero positive
+        (Arithmetic::Double, BinOp::Sub, _, Operand::Double(value))
+            if value.to_bits() == 0.0f64.to_bits() =>
+        {
+            mov(src1)
+        }
+        _ => vec![AbstractAssemblyInstruction::BinOp {
+            op,
+            arithmetic,
+            dest,
+            src1,
+            src2,
+        }],
+    }
+}
+
+/// `k` if `operand` is the int 2^k, for k from 1 to 30
+fn power_of_two(operand: &Operand) -> Option<u32> {
+    match operand {
+        Operand::Const(value) if *value > 1 && *value <= 1 << 30 && value.count_ones() == 1 => {
+            Some(value.trailing_zeros())
+        }
+        _ => None,
+    }
+}
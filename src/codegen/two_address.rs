@@ -0,0 +1,136 @@
+//! Two-address conversion. Instruction selection writes arithmetic as `d <- s1 op s2`, but x86
+//! arithmetic overwrites its first operand, so each is rewritten as `d <- s1; d <- d op s2`.
+//! When `d` is `s2`, the move would overwrite `s2` before it's read: commutative operations
+//! swap their operands instead, and the others go through another temp, or for int
+//! subtraction, through a negation.
+
+use super::context::Dest;
+use super::x86::{AluOp, Size, SseOp, UnaryOp, X86Function, X86Instruction, X86Operand};
+use crate::sema::Type;
+
+/// Rewrites the instructions of `function` so that each writes its first operand
+pub fn convert_to_two_address(function: &mut X86Function) {
+    let instructions = std::mem::take(&mut function.instructions);
+    for instruction in instructions {
+        match instruction {
+            X86Instruction::Alu {
+                op,
+                size,
+                dest,
+                left,
+                right,
+            } => alu(function, op, size, dest, left, right),
+            X86Instruction::Sse {
+                op,
+                dest,
+                left,
+                right,
+            } => sse(function, op, dest, left, right),
+            X86Instruction::Shift {
+                kind,
+                dest,
+                src,
+                amount,
+            } => {
+                mov(function, Size::Long, &dest, src);
+                function.instructions.push(X86Instruction::Shift {
+                    kind,
+                    dest: dest.clone(),
+                    src: X86Operand::Reg(dest),
+                    amount,
+                });
+            }
+            X86Instruction::Unary { op, dest, src } => {
+                mov(function, Size::Long, &dest, src);
+                function.instructions.push(X86Instruction::Unary {
+                    op,
+                    dest: dest.clone(),
+                    src: X86Operand::Reg(dest),
+                });
+            }
+            instruction => function.instructions.push(instruction),
+        }
+    }
+}
+
+/// Moves `src` to `dest`, unless it's already there
+fn mov(function: &mut X86Function, size: Size, dest: &Dest, src: X86Operand) {
+    if src != X86Operand::Reg(dest.clone()) {
+        function.instructions.push(X86Instruction::Mov {
+            size,
+            dest: X86Operand::Reg(dest.clone()),
+            src,
+        });
+    }
+}
+
+fn movsd(function: &mut X86Function, dest: &Dest, src: X86Operand) {
+    if src != X86Operand::Reg(dest.clone()) {
+        function.instructions.push(X86Instruction::Movsd {
+            dest: X86Operand::Reg(dest.clone()),
+            src,
+        });
+    }
+}
+
+fn alu(
+    function: &mut X86Function,
+    op: AluOp,
+    size: Size,
+    dest: Dest,
+    mut left: X86Operand,
+    mut right: X86Operand,
+) {
+    let target = X86Operand::Reg(dest.clone());
+    if right == target && left != target {
+        if op == AluOp::Sub {
+            // d <- s1 - d is -d + s1
+            function.instructions.push(X86Instruction::Unary {
+                op: UnaryOp::Neg,
+                dest: dest.clone(),
+                src: target.clone(),
+            });
+            op_into(function, AluOp::Add, size, dest, left);
+            return;
+        }
+        // The others are commutative
+        std::mem::swap(&mut left, &mut right);
+    }
+    mov(function, size, &dest, left);
+    op_into(function, op, size, dest, right);
+}
+
+/// `dest <- dest op right`
+fn op_into(function: &mut X86Function, op: AluOp, size: Size, dest: Dest, right: X86Operand) {
+    function.instructions.push(X86Instruction::Alu {
+        op,
+        size,
+        dest: dest.clone(),
+        left: X86Operand::Reg(dest),
+        right,
+    });
+}
+
+fn sse(function: &mut X86Function, op: SseOp, dest: Dest, left: X86Operand, right: X86Operand) {
+    let target = X86Operand::Reg(dest.clone());
+    let (left, right) = if right == target && left != target {
+        match op {
+            SseOp::Add | SseOp::Mul => (right, left),
+            SseOp::Sub | SseOp::Div => {
+                // s2 is kept in another temp while d is set to s1
+                let copy = function.new_temp(Type::Double);
+                movsd(function, &copy, right);
+                (left, X86Operand::Reg(copy))
+            }
+        }
+    } else {
+        (left, right)
+    };
+    movsd(function, &dest, left);
+    function.instructions.push(X86Instruction::Sse {
+        op,
+        dest: dest.clone(),
+        left: X86Operand::Reg(dest),
+        right,
+    });
+}
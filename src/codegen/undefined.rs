@@ -0,0 +1,65 @@
+//! Which abstract assembly operations can go wrong at run time. C0 defines int arithmetic to
+//! wrap, so what's left is division, and converting a double that no int can hold. Every
+//! target aborts the program for these. This table is what the interpreter checks for, and
+//! what constant folding must leave for the program to do when it runs.
+
+use crate::parser::BinOp;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Undefined {
+    DivisionByZero,
+    // The smallest int divided by -1, whose quotient doesn't fit
+    DivisionOverflow,
+    // A double converted to an int, which is NaN or out of its range once truncated
+    ConversionOverflow,
+}
+
+impl Undefined {
+    pub const ALL: [Undefined; 3] = [
+        Undefined::DivisionByZero,
+        Undefined::DivisionOverflow,
+        Undefined::ConversionOverflow,
+    ];
+
+    /// Name used when reporting it, like `--check-ub` does
+    pub fn name(self) -> &'static str {
+        match self {
+            Undefined::DivisionByZero => "division-by-zero",
+            Undefined::DivisionOverflow => "division-overflow",
+            Undefined::ConversionOverflow => "conversion-overflow",
+        }
+    }
+
+    /// The instruction that can do it, as abstract assembly writes it
+    pub fn operation(self) -> &'static str {
+        match self {
+            Undefined::DivisionByZero | Undefined::DivisionOverflow => "int /",
+            Undefined::ConversionOverflow => "d2i",
+        }
+    }
+
+    /// When the operation does it
+    pub fn condition(self) -> &'static str {
+        match self {
+            Undefined::DivisionByZero => "the divisor is 0",
+            Undefined::DivisionOverflow => "-2147483648 is divided by -1",
+            Undefined::ConversionOverflow => "the double is NaN, or out of the range of int",
+        }
+    }
+
+    /// What `op` of the ints `left` and `right` would do wrong, if anything
+    pub fn of_int(op: BinOp, left: i32, right: i32) -> Option<Undefined> {
+        match op {
+            BinOp::Div if right == 0 => Some(Undefined::DivisionByZero),
+            BinOp::Div if left == i32::MIN && right == -1 => Some(Undefined::DivisionOverflow),
+            _ => None,
+        }
+    }
+
+    /// What converting `value` to an int would do wrong, if anything. It's truncated toward
+    /// zero first, so anything above -2147483649 and below 2147483648 fits.
+    pub fn of_d2i(value: f64) -> Option<Undefined> {
+        let fits = value > i32::MIN as f64 - 1.0 && value < i32::MAX as f64 + 1.0;
+        (!fits).then_some(Undefined::ConversionOverflow)
+    }
+}
@@ -0,0 +1,305 @@
+//! Loop unrolling. A loop whose trip count is known at compile time is fully unrolled into
+//! straight-line copies of its body, and any other loop is unrolled into `unroll_factor`
+//! copies, each still testing the loop condition. Only innermost loops are unrolled, and only
+//! while the unrolled code stays under `MAX_UNROLLED_SIZE` instructions.
+//!
+//! The trip count is known for loops like `for (int i = 0; i < 10; i = i + 1)`: the header
+//! compares a temp with a constant, the temp is set to a constant just before the loop, and
+//! it's changed exactly once per iteration, by adding or subtracting a constant.
+
+use super::cfg::ControlFlowGraph;
+use super::context::{AbstractAssemblyInstruction, Arithmetic, AsmLabel, Context, Dest, Operand};
+use super::dominators::DominatorTree;
+use super::loops::{Loop, LoopInfo};
+use super::pass::Pass;
+use super::CodegenOptions;
+use crate::parser::BinOp;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+/// Largest number of instructions an unrolled loop may have
+const MAX_UNROLLED_SIZE: usize = 64;
+
+pub struct LoopUnrolling;
+
+impl Pass for LoopUnrolling {
+    fn name(&self) -> &'static str {
+        "unroll-loops"
+    }
+
+    fn level(&self) -> u8 {
+        2
+    }
+
+    fn run(&self, context: &mut Context, options: &CodegenOptions) {
+        let original = context.instructions.clone();
+        let mut changed = false;
+        // Headers of the loops already unrolled, by label
+        let mut unrolled: HashSet<usize> = HashSet::new();
+        loop {
+            let instructions = mem::take(&mut context.instructions);
+            let cfg = ControlFlowGraph::new(instructions, || AsmLabel(context.new_label()));
+            let dominators = DominatorTree::new(&cfg);
+            let info = LoopInfo::new(&cfg, &dominators);
+            let loops = info.loops();
+            // Unrolling a loop nested in this one would copy its copies again
+            let innermost = (0..loops.len()).find(|&index| {
+                !loops.iter().any(|other| other.parent == Some(index))
+                    && !unrolled.contains(&cfg.blocks[loops[index].header].label.0)
+            });
+            let Some(index) = innermost else {
+                context.instructions = cfg.into_instructions();
+                break;
+            };
+            unrolled.insert(cfg.blocks[loops[index].header].label.0);
+            let natural_loop = &loops[index];
+            let instructions = unroll(context, &cfg, &dominators, natural_loop, options);
+            changed |= instructions.is_some();
+            context.instructions = instructions.unwrap_or_else(|| cfg.into_instructions());
+        }
+        // Keep the layout, without the labels the graph adds, if no loop could be unrolled
+        if !changed {
+            context.instructions = original;
+        }
+    }
+}
+
+/// Instructions of the function with `natural_loop` unrolled, or None if it's too big
+fn unroll(
+    context: &mut Context,
+    cfg: &ControlFlowGraph,
+    dominators: &DominatorTree,
+    natural_loop: &Loop,
+    options: &CodegenOptions,
+) -> Option<Vec<AbstractAssemblyInstruction>> {
+    let size: usize = natural_loop
+        .blocks
+        .iter()
+        .flat_map(|&block| &cfg.blocks[block].instructions)
+        .filter(|instruction| !matches!(instruction, AbstractAssemblyInstruction::Loc { .. }))
+        .count()
+        + natural_loop.blocks.len();
+    // A full unrolling tests the condition once more, to leave the loop
+    let trips = trip_count(cfg, dominators, natural_loop)
+        .filter(|trips| (trips + 1) * size <= MAX_UNROLLED_SIZE);
+    let copies = match trips {
+        Some(trips) => trips + 1,
+        None if options.unroll_factor > 1 && options.unroll_factor * size <= MAX_UNROLLED_SIZE => {
+            options.unroll_factor
+        }
+        None => return None,
+    };
+
+    let header = natural_loop.header;
+    // Label of each block of the loop in each copy; the first copy is the loop itself
+    let mut labels: Vec<HashMap<usize, AsmLabel>> = Vec::new();
+    for copy in 0..copies {
+        labels.push(
+            natural_loop
+                .blocks
+                .iter()
+                .map(|&block| match copy {
+                    0 => (block, cfg.blocks[block].label),
+                    _ => (block, AsmLabel(context.new_label())),
+                })
+                .collect(),
+        );
+    }
+    let in_loop: HashMap<usize, usize> = natural_loop
+        .blocks
+        .iter()
+        .map(|&block| (cfg.blocks[block].label.0, block))
+        .collect();
+
+    let mut copied: Vec<AbstractAssemblyInstruction> = Vec::new();
+    let mut first_copy: HashMap<usize, Vec<AbstractAssemblyInstruction>> = HashMap::new();
+    for (copy, copy_labels) in labels.iter().enumerate() {
+        for &block in &natural_loop.blocks {
+            let mut instructions = cfg.blocks[block].instructions.clone();
+            // The copies are laid out elsewhere, so falling through has to become a jump
+            let falls_through = instructions.last().is_none_or(|last| !last.is_terminator());
+            if let (true, Some(&successor)) = (falls_through, cfg.successors(block).first()) {
+                instructions.push(AbstractAssemblyInstruction::Jmp(
+                    cfg.blocks[successor].label,
+                ));
+            }
+            for instruction in &mut instructions {
+                for target in instruction.jump_targets_mut() {
+                    *target = match in_loop.get(&target.0) {
+                        // Going around the loop goes on to the next copy
+                        Some(&target_block) if target_block == header => {
+                            labels[(copy + 1) % copies][&header]
+                        }
+                        Some(target_block) => copy_labels[target_block],
+                        None => *target,
+                    };
+                }
+            }
+            // Each copy of a fully unrolled loop knows whether the condition holds
+            if trips.is_some() && block == header {
+                let Some(AbstractAssemblyInstruction::JmpCondition {
+                    tgt_true,
+                    tgt_false,
+                    ..
+                }) = instructions.pop()
+                else {
+                    unreachable!("the trip count is only known for headers ending in a test");
+                };
+                instructions.pop();
+                let (stay, leave) = if copy_labels.values().any(|label| label.0 == tgt_true.0) {
+                    (tgt_true, tgt_false)
+                } else {
+                    (tgt_false, tgt_true)
+                };
+                let target = if copy + 1 < copies { stay } else { leave };
+                instructions.push(AbstractAssemblyInstruction::Jmp(target));
+            }
+            if copy == 0 {
+                first_copy.insert(block, instructions);
+            } else {
+                copied.push(AbstractAssemblyInstruction::Lbl(copy_labels[&block]));
+                copied.extend(instructions);
+            }
+        }
+    }
+
+    // The first copy stays in place, and the others follow the loop's last block
+    let last = *natural_loop.blocks.last().unwrap();
+    let mut result = Vec::new();
+    for (index, block) in cfg.blocks.iter().enumerate() {
+        result.push(AbstractAssemblyInstruction::Lbl(block.label));
+        match first_copy.remove(&index) {
+            Some(instructions) => result.extend(instructions),
+            None => result.extend(block.instructions.iter().cloned()),
+        }
+        if index == last {
+            result.append(&mut copied);
+        }
+    }
+    Some(result)
+}
+
+/// Number of times the body of `natural_loop` runs, if it's known at compile time
+fn trip_count(
+    cfg: &ControlFlowGraph,
+    dominators: &DominatorTree,
+    natural_loop: &Loop,
+) -> Option<usize> {
+    let header = natural_loop.header;
+    // The condition's line mark, with `-g`, doesn't count
+    let tested: Vec<_> = cfg.blocks[header]
+        .instructions
+        .iter()
+        .filter(|instruction| !matches!(instruction, AbstractAssemblyInstruction::Loc { .. }))
+        .collect();
+    let [AbstractAssemblyInstruction::Compare {
+        arithmetic: Arithmetic::Int,
+        left: Operand::Var(Dest::Temp(counter)),
+        right: Operand::Const(limit),
+        ..
+    }, AbstractAssemblyInstruction::JmpCondition {
+        condition,
+        tgt_true,
+        tgt_false,
+    }] = tested[..]
+    else {
+        return None;
+    };
+    let stays_when = match (
+        natural_loop.contains(cfg.block_of(*tgt_true)?),
+        natural_loop.contains(cfg.block_of(*tgt_false)?),
+    ) {
+        (true, false) => true,
+        (false, true) => false,
+        _ => return None,
+    };
+
+    // The counter is changed once per iteration, by a constant step
+    let mut writes = natural_loop.blocks.iter().flat_map(|&block| {
+        cfg.blocks[block]
+            .instructions
+            .iter()
+            .enumerate()
+            .filter(|(_, instruction)| writes_temp(instruction, *counter))
+            .map(move |(index, _)| (block, index))
+    });
+    let (block, index) = writes.next()?;
+    if writes.next().is_some()
+        || block == header
+        || !natural_loop
+            .latches
+            .iter()
+            .all(|&latch| dominators.dominates(block, latch))
+    {
+        return None;
+    }
+    let instructions = &cfg.blocks[block].instructions;
+    let step = match &instructions[index] {
+        AbstractAssemblyInstruction::Mov {
+            src: Operand::Var(Dest::Temp(src)),
+            ..
+        } => {
+            // `counter <- src` after `src <- counter + step`, which is how `i = i + 1` compiles
+            let computed = instructions[..index]
+                .iter()
+                .rev()
+                .find(|instruction| writes_temp(instruction, *src))?;
+            constant_step(computed, *counter)?
+        }
+        instruction => constant_step(instruction, *counter)?,
+    };
+
+    // The counter's value on entry, set just before the loop
+    let mut entries = cfg
+        .predecessors(header)
+        .iter()
+        .filter(|&&predecessor| !natural_loop.contains(predecessor));
+    let entry = *entries.next()?;
+    if entries.next().is_some() {
+        return None;
+    }
+    let initial = match cfg.blocks[entry]
+        .instructions
+        .iter()
+        .rev()
+        .find(|instruction| writes_temp(instruction, *counter))?
+    {
+        AbstractAssemblyInstruction::Mov {
+            src: Operand::Const(value),
+            ..
+        } => *value as i32,
+        _ => return None,
+    };
+
+    // Ints are 32 bits and wrap around
+    let (limit, mut value) = (*limit as i32, initial);
+    for trips in 0..=MAX_UNROLLED_SIZE {
+        if condition.holds(value.cmp(&limit)) != stays_when {
+            return Some(trips);
+        }
+        value = value.wrapping_add(step);
+    }
+    None
+}
+
+fn writes_temp(instruction: &AbstractAssemblyInstruction, temp: usize) -> bool {
+    matches!(instruction.dest(), Some(Dest::Temp(dest)) if *dest == temp)
+}
+
+/// `step` if `instruction` computes `counter + step` or `counter - -step`
+fn constant_step(instruction: &AbstractAssemblyInstruction, counter: usize) -> Option<i32> {
+    match instruction {
+        AbstractAssemblyInstruction::BinOp {
+            op,
+            arithmetic: Arithmetic::Int,
+            src1: Operand::Var(Dest::Temp(src)),
+            src2: Operand::Const(step),
+            ..
+        } if *src == counter => match op {
+            BinOp::Add => Some(*step as i32),
+            BinOp::Sub => Some((*step as i32).wrapping_neg()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,337 @@
+//! Checks of the invariants the passes rely on, run on each function between passes in debug
+//! builds, so that a pass breaking the abstract assembly is caught where it happens instead of
+//! as wrong output.
+//!
+//! Sema rejects reading a local before it's assigned, so every temp read must be written on
+//! every path from the entry to the read; the parameters are written on entry.
+
+use super::context::{AbstractAssemblyInstruction, Context, Dest, Operand};
+use super::CodegenFailure;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    pub function: String,
+    /// Index of the offending instruction
+    pub index: usize,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyErrorKind {
+    // A temp the function never allocated, so it has no type
+    UnknownTemp { temp: usize },
+    // A jump to a label no instruction defines
+    UndefinedLabel { label: usize },
+    DuplicateLabel { label: usize },
+    // A phi after an instruction other than a label or another phi
+    MisplacedPhi,
+    PhiOutsideSsa,
+    // A phi whose sources don't name exactly the predecessors of its block
+    PhiSources,
+    // A temp written a second time in SSA form
+    Redefined { temp: usize },
+    // A temp read where some path from the entry hasn't written it
+    Undefined { temp: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "In function '{}', instruction {}: {}",
+            self.function, self.index, self.kind
+        )
+    }
+}
+
+impl fmt::Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyErrorKind::UnknownTemp { temp } => write!(f, "%t{} was never allocated", temp),
+            VerifyErrorKind::UndefinedLabel { label } => {
+                write!(f, "jump to L{}, which isn't defined", label)
+            }
+            VerifyErrorKind::DuplicateLabel { label } => write!(f, "L{} is defined twice", label),
+            VerifyErrorKind::MisplacedPhi => write!(f, "phi after the start of its block"),
+            VerifyErrorKind::PhiOutsideSsa => write!(f, "phi in a function not in SSA form"),
+            VerifyErrorKind::PhiSources => {
+                write!(f, "phi sources aren't the predecessors of its block")
+            }
+            VerifyErrorKind::Redefined { temp } => {
+                write!(f, "%t{} is written more than once in SSA form", temp)
+            }
+            VerifyErrorKind::Undefined { temp } => {
+                write!(f, "%t{} is read where it may not have been written", temp)
+            }
+        }
+    }
+}
+
+/// Checks the instructions of `context`, which are in SSA form if `ssa` is set
+pub fn verify(context: &Context, ssa: bool) -> Result<(), VerifyError> {
+    let instructions = &context.instructions;
+    let error = |index, kind| VerifyError {
+        function: context.name.clone(),
+        index,
+        kind,
+    };
+
+    let mut labels = HashSet::new();
+    for (index, instruction) in instructions.iter().enumerate() {
+        if let AbstractAssemblyInstruction::Lbl(label) = instruction {
+            if !labels.insert(label.0) {
+                return Err(error(
+                    index,
+                    VerifyErrorKind::DuplicateLabel { label: label.0 },
+                ));
+            }
+        }
+    }
+
+    let predecessors = block_predecessors(instructions);
+    let mut written = HashSet::new();
+    // Label of the block each instruction is in, and whether only phis follow it so far
+    let mut block: Option<usize> = None;
+    let mut at_block_start = true;
+    for (index, instruction) in instructions.iter().enumerate() {
+        let mut temps: Vec<usize> = instruction
+            .operands()
+            .into_iter()
+            .filter_map(|operand| match operand {
+                Operand::Var(Dest::Temp(temp)) => Some(*temp),
+                _ => None,
+            })
+            .collect();
+        if let Some(Dest::Temp(temp)) = instruction.dest() {
+            temps.push(*temp);
+            if ssa && !written.insert(*temp) {
+                return Err(error(index, VerifyErrorKind::Redefined { temp: *temp }));
+            }
+        }
+        if let Some(&temp) = temps.iter().find(|&&temp| !context.has_temp(temp)) {
+            return Err(error(index, VerifyErrorKind::UnknownTemp { temp }));
+        }
+
+        let targets = match instruction {
+            AbstractAssemblyInstruction::Jmp(target) => vec![*target],
+            AbstractAssemblyInstruction::JmpCondition {
+                tgt_true,
+                tgt_false,
+                ..
+            } => vec![*tgt_true, *tgt_false],
+            _ => Vec::new(),
+        };
+        if let Some(target) = targets.iter().find(|target| !labels.contains(&target.0)) {
+            return Err(error(
+                index,
+                VerifyErrorKind::UndefinedLabel { label: target.0 },
+            ));
+        }
+
+        match instruction {
+            AbstractAssemblyInstruction::Lbl(label) => {
+                block = Some(label.0);
+                at_block_start = true;
+            }
+            AbstractAssemblyInstruction::Phi { srcs, .. } => {
+                if !ssa {
+                    return Err(error(index, VerifyErrorKind::PhiOutsideSsa));
+                }
+                if !at_block_start {
+                    return Err(error(index, VerifyErrorKind::MisplacedPhi));
+                }
+                let sources: HashSet<Option<usize>> =
+                    srcs.iter().map(|(_, label)| Some(label.0)).collect();
+                let expected = block.map(|label| &predecessors[&label]);
+                if srcs.len() != sources.len() || expected != Some(&sources) {
+                    return Err(error(index, VerifyErrorKind::PhiSources));
+                }
+            }
+            _ => at_block_start = false,
+        }
+        if instruction.is_terminator() {
+            block = None;
+            at_block_start = false;
+        }
+    }
+    if let Some((index, temp)) = undefined_read(context) {
+        return Err(error(index, VerifyErrorKind::Undefined { temp }));
+    }
+    Ok(())
+}
+
+/// Runs `verify` in a debug build, blaming a problem on `after`, the step before it
+pub fn check(context: &Context, ssa: bool, after: &str) -> Result<(), CodegenFailure> {
+    if cfg!(debug_assertions) {
+        verify(context, ssa).map_err(|error| CodegenFailure::Invalid {
+            after: after.to_string(),
+            error,
+        })?;
+    }
+    Ok(())
+}
+
+/// Index of the first instruction reading a temp that some path from the entry reaches without
+/// writing, and the temp. A phi reads each source at the end of the block it names. Labels are
+/// already known to be defined.
+fn undefined_read(context: &Context) -> Option<(usize, usize)> {
+    let instructions = &context.instructions;
+    // A block starts at the entry, at each label and after each terminator
+    let mut starts = vec![0];
+    for (index, instruction) in instructions.iter().enumerate() {
+        let start = match instruction {
+            AbstractAssemblyInstruction::Lbl(_) => index,
+            _ if instruction.is_terminator() => index + 1,
+            _ => continue,
+        };
+        if start > *starts.last().unwrap() && start < instructions.len() {
+            starts.push(start);
+        }
+    }
+    let ends: Vec<usize> = starts
+        .iter()
+        .skip(1)
+        .copied()
+        .chain([instructions.len()])
+        .collect();
+    let block_of: HashMap<usize, usize> = starts
+        .iter()
+        .enumerate()
+        .filter_map(|(block, &start)| match instructions.get(start) {
+            Some(AbstractAssemblyInstruction::Lbl(label)) => Some((label.0, block)),
+            _ => None,
+        })
+        .collect();
+    let mut predecessors = vec![Vec::new(); starts.len()];
+    for (block, &end) in ends.iter().enumerate() {
+        let successors = match instructions.get(end.wrapping_sub(1)) {
+            Some(AbstractAssemblyInstruction::Jmp(target)) => vec![block_of[&target.0]],
+            Some(AbstractAssemblyInstruction::JmpCondition {
+                tgt_true,
+                tgt_false,
+                ..
+            }) => vec![block_of[&tgt_true.0], block_of[&tgt_false.0]],
+            Some(instruction) if instruction.is_terminator() => Vec::new(),
+            _ if block + 1 < starts.len() => vec![block + 1],
+            _ => Vec::new(),
+        };
+        for successor in successors {
+            predecessors[successor].push(block);
+        }
+    }
+    let written = |block: usize| {
+        instructions[starts[block]..ends[block]]
+            .iter()
+            .filter_map(|instruction| match instruction.dest() {
+                Some(Dest::Temp(temp)) => Some(*temp),
+                _ => None,
+            })
+    };
+
+    // Temps written on every path to the end of each block, or None for a block no path has
+    // been found to yet; these only shrink, so iterating until nothing changes terminates
+    let params: HashSet<usize> = (0..context.params).collect();
+    let mut defined_out: Vec<Option<HashSet<usize>>> = vec![None; starts.len()];
+    let mut defined_in = defined_out.clone();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in 0..starts.len() {
+            let incoming = predecessors[block]
+                .iter()
+                .filter_map(|&predecessor| defined_out[predecessor].as_ref());
+            let entry = if block == 0 {
+                Some(params.clone())
+            } else {
+                incoming.fold(None, |defined: Option<HashSet<usize>>, out| match defined {
+                    Some(defined) => Some(&defined & out),
+                    None => Some(out.clone()),
+                })
+            };
+            let out = entry.clone().map(|mut defined| {
+                defined.extend(written(block));
+                defined
+            });
+            if out != defined_out[block] {
+                defined_out[block] = out;
+                changed = true;
+            }
+            defined_in[block] = entry;
+        }
+    }
+
+    for (block, entry) in defined_in.into_iter().enumerate() {
+        // A block that can't be reached reads nothing
+        let Some(mut defined) = entry else {
+            continue;
+        };
+        let range = starts[block]..ends[block];
+        for (index, instruction) in range.clone().zip(&instructions[range]) {
+            let undefined = match instruction {
+                AbstractAssemblyInstruction::Phi { srcs, .. } => {
+                    srcs.iter().find_map(|(operand, label)| match operand {
+                        Operand::Var(Dest::Temp(temp)) => {
+                            let out = block_of
+                                .get(&label.0)
+                                .and_then(|&source| defined_out[source].as_ref());
+                            out.is_some_and(|out| !out.contains(temp)).then_some(*temp)
+                        }
+                        _ => None,
+                    })
+                }
+                _ => instruction
+                    .operands()
+                    .into_iter()
+                    .find_map(|operand| match operand {
+                        Operand::Var(Dest::Temp(temp)) if !defined.contains(temp) => Some(*temp),
+                        _ => None,
+                    }),
+            };
+            if let Some(temp) = undefined {
+                return Some((index, temp));
+            }
+            if let Some(Dest::Temp(temp)) = instruction.dest() {
+                defined.insert(*temp);
+            }
+        }
+    }
+    None
+}
+
+/// Labels of the blocks that can go to each labeled block, with None for a block without a
+/// label, like an unlabeled entry
+fn block_predecessors(
+    instructions: &[AbstractAssemblyInstruction],
+) -> HashMap<usize, HashSet<Option<usize>>> {
+    let mut predecessors: HashMap<usize, HashSet<Option<usize>>> = HashMap::new();
+    let mut block: Option<usize> = None;
+    // True if the previous instruction can go on to the next one
+    let mut falls_through = false;
+    for instruction in instructions {
+        match instruction {
+            AbstractAssemblyInstruction::Lbl(label) => {
+                let entry = predecessors.entry(label.0).or_default();
+                if falls_through {
+                    entry.insert(block);
+                }
+                block = Some(label.0);
+            }
+            AbstractAssemblyInstruction::Jmp(target) => {
+                predecessors.entry(target.0).or_default().insert(block);
+            }
+            AbstractAssemblyInstruction::JmpCondition {
+                tgt_true,
+                tgt_false,
+                ..
+            } => {
+                predecessors.entry(tgt_true.0).or_default().insert(block);
+                predecessors.entry(tgt_false.0).or_default().insert(block);
+            }
+            _ => {}
+        }
+        falls_through = !instruction.is_terminator();
+    }
+    predecessors
+}
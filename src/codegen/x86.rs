@@ -0,0 +1,640 @@
+//! x86-64 instructions, as instruction selection picks them. Their operands are still temps,
+//! until registers are allocated, and some are pseudo-instructions standing for a short
+//! sequence, like a call, whose details are settled later.
+
+use super::context::{AsmLabel, Dest, ShiftKind};
+use super::frame::Frame;
+use super::register_allocator::RegisterDescription;
+use crate::sema::Type;
+use std::collections::HashMap;
+
+/// The machine registers, in the order of their encodings; `Dest::Register` holds an index into
+/// this list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum X86Register {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+    Xmm0,
+    Xmm1,
+    Xmm2,
+    Xmm3,
+    Xmm4,
+    Xmm5,
+    Xmm6,
+    Xmm7,
+    Xmm8,
+    Xmm9,
+    Xmm10,
+    Xmm11,
+    Xmm12,
+    Xmm13,
+    Xmm14,
+    Xmm15,
+}
+
+impl X86Register {
+    pub const ALL: [X86Register; 32] = [
+        X86Register::Rax,
+        X86Register::Rcx,
+        X86Register::Rdx,
+        X86Register::Rbx,
+        X86Register::Rsp,
+        X86Register::Rbp,
+        X86Register::Rsi,
+        X86Register::Rdi,
+        X86Register::R8,
+        X86Register::R9,
+        X86Register::R10,
+        X86Register::R11,
+        X86Register::R12,
+        X86Register::R13,
+        X86Register::R14,
+        X86Register::R15,
+        X86Register::Xmm0,
+        X86Register::Xmm1,
+        X86Register::Xmm2,
+        X86Register::Xmm3,
+        X86Register::Xmm4,
+        X86Register::Xmm5,
+        X86Register::Xmm6,
+        X86Register::Xmm7,
+        X86Register::Xmm8,
+        X86Register::Xmm9,
+        X86Register::Xmm10,
+        X86Register::Xmm11,
+        X86Register::Xmm12,
+        X86Register::Xmm13,
+        X86Register::Xmm14,
+        X86Register::Xmm15,
+    ];
+
+    /// Registers the System V calling convention passes int arguments in, in order
+    pub const INT_ARGUMENTS: [X86Register; 6] = [
+        X86Register::Rdi,
+        X86Register::Rsi,
+        X86Register::Rdx,
+        X86Register::Rcx,
+        X86Register::R8,
+        X86Register::R9,
+    ];
+
+    /// Registers the System V calling convention passes double arguments in, in order
+    pub const DOUBLE_ARGUMENTS: [X86Register; 8] = [
+        X86Register::Xmm0,
+        X86Register::Xmm1,
+        X86Register::Xmm2,
+        X86Register::Xmm3,
+        X86Register::Xmm4,
+        X86Register::Xmm5,
+        X86Register::Xmm6,
+        X86Register::Xmm7,
+    ];
+
+    /// General-purpose registers a call may overwrite; it may overwrite every xmm register too
+    pub const CALLER_SAVED: [X86Register; 9] = [
+        X86Register::Rax,
+        X86Register::Rcx,
+        X86Register::Rdx,
+        X86Register::Rsi,
+        X86Register::Rdi,
+        X86Register::R8,
+        X86Register::R9,
+        X86Register::R10,
+        X86Register::R11,
+    ];
+
+    /// Registers a call leaves as they were, so that a function writing them has to restore them;
+    /// the System V calling convention keeps no xmm register
+    pub const CALLEE_SAVED: [X86Register; 6] = [
+        X86Register::Rbx,
+        X86Register::Rbp,
+        X86Register::R12,
+        X86Register::R13,
+        X86Register::R14,
+        X86Register::R15,
+    ];
+
+    /// Registers the Microsoft calling convention passes the first four arguments in, when
+    /// they're ints
+    pub const MICROSOFT_INT_ARGUMENTS: [X86Register; 4] = [
+        X86Register::Rcx,
+        X86Register::Rdx,
+        X86Register::R8,
+        X86Register::R9,
+    ];
+
+    /// Registers the Microsoft calling convention passes the first four arguments in, when
+    /// they're doubles
+    pub const MICROSOFT_DOUBLE_ARGUMENTS: [X86Register; 4] = [
+        X86Register::Xmm0,
+        X86Register::Xmm1,
+        X86Register::Xmm2,
+        X86Register::Xmm3,
+    ];
+
+    /// General-purpose registers a call may overwrite under the Microsoft calling convention;
+    /// it may overwrite %xmm0 to %xmm5 too
+    pub const MICROSOFT_CALLER_SAVED: [X86Register; 7] = [
+        X86Register::Rax,
+        X86Register::Rcx,
+        X86Register::Rdx,
+        X86Register::R8,
+        X86Register::R9,
+        X86Register::R10,
+        X86Register::R11,
+    ];
+
+    /// General-purpose registers a call leaves as they were under the Microsoft calling
+    /// convention, which also keeps %xmm6 to %xmm15
+    pub const MICROSOFT_CALLEE_SAVED: [X86Register; 8] = [
+        X86Register::Rbx,
+        X86Register::Rbp,
+        X86Register::Rsi,
+        X86Register::Rdi,
+        X86Register::R12,
+        X86Register::R13,
+        X86Register::R14,
+        X86Register::R15,
+    ];
+
+    pub fn from_index(index: usize) -> Self {
+        X86Register::ALL[index]
+    }
+
+    pub fn dest(self) -> Dest {
+        Dest::Register(self as usize)
+    }
+
+    pub fn is_xmm(self) -> bool {
+        self as usize >= X86Register::Xmm0 as usize
+    }
+
+    /// The register named `name` without its `%`, in any of its sizes, like `rcx` or `ecx`
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = format!("%{}", name);
+        X86Register::ALL.into_iter().find(|register| {
+            [Size::Byte, Size::Long, Size::Quad]
+                .iter()
+                .any(|&size| register.name(size) == name)
+        })
+    }
+
+    /// The register an inline assembly constraint names by its letter, like `a` for %rax
+    pub fn from_constraint(letter: char) -> Option<Self> {
+        match letter {
+            'a' => Some(X86Register::Rax),
+            'b' => Some(X86Register::Rbx),
+            'c' => Some(X86Register::Rcx),
+            'd' => Some(X86Register::Rdx),
+            'S' => Some(X86Register::Rsi),
+            'D' => Some(X86Register::Rdi),
+            _ => None,
+        }
+    }
+
+    /// Name of the register in AT&T syntax, holding a value of `size`; xmm registers have one
+    /// name whatever they hold
+    pub fn name(self, size: Size) -> String {
+        const LEGACY: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+        let index = self as usize;
+        if self.is_xmm() {
+            return format!("%xmm{}", index - X86Register::Xmm0 as usize);
+        }
+        match (index < 8, size) {
+            (true, Size::Byte) if index < 4 => format!("%{}l", &LEGACY[index][..1]),
+            (true, Size::Byte) => format!("%{}l", LEGACY[index]),
+            (true, Size::Long) => format!("%e{}", LEGACY[index]),
+            (true, Size::Quad) => format!("%r{}", LEGACY[index]),
+            (false, Size::Byte) => format!("%r{}b", index),
+            (false, Size::Long) => format!("%r{}d", index),
+            (false, Size::Quad) => format!("%r{}", index),
+        }
+    }
+}
+
+/// Where an argument is passed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgumentLocation {
+    Register(X86Register),
+    /// The 8-byte slot at this index from the stack pointer at the call
+    Stack(usize),
+}
+
+/// The rules functions call each other by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallingConvention {
+    /// Linux's, and that of the other Unix-like systems: ints and doubles take the next free
+    /// register of their own kind, and the rest go on the stack in order
+    SystemV,
+    /// Windows': the first four arguments are passed in %rcx, %rdx, %r8 and %r9, or in %xmm0 to
+    /// %xmm3 for doubles, by position, and the rest go on the stack above 32 bytes of shadow
+    /// space, which the caller reserves for the callee to store the first four in
+    Microsoft,
+}
+
+impl CallingConvention {
+    /// Where each argument of a call with arguments of `types` is passed
+    pub fn arguments(self, types: impl IntoIterator<Item = Type>) -> Vec<ArgumentLocation> {
+        let mut ints = 0;
+        let mut doubles = 0;
+        let mut stack = self.shadow_slots();
+        let mut locations = Vec::new();
+        for (position, ty) in types.into_iter().enumerate() {
+            let register = match (self, ty) {
+                (CallingConvention::SystemV, Type::Double) => {
+                    doubles += 1;
+                    X86Register::DOUBLE_ARGUMENTS.get(doubles - 1)
+                }
+                (CallingConvention::SystemV, _) => {
+                    ints += 1;
+                    X86Register::INT_ARGUMENTS.get(ints - 1)
+                }
+                (CallingConvention::Microsoft, Type::Double) => {
+                    X86Register::MICROSOFT_DOUBLE_ARGUMENTS.get(position)
+                }
+                (CallingConvention::Microsoft, _) => {
+                    X86Register::MICROSOFT_INT_ARGUMENTS.get(position)
+                }
+            };
+            locations.push(match register {
+                Some(register) => ArgumentLocation::Register(*register),
+                None => {
+                    stack += 1;
+                    ArgumentLocation::Stack(stack - 1)
+                }
+            });
+        }
+        locations
+    }
+
+    /// Number of 8-byte slots at the bottom of the frame a caller reserves for the callee,
+    /// below any arguments it passes on the stack
+    pub fn shadow_slots(self) -> usize {
+        match self {
+            CallingConvention::SystemV => 0,
+            CallingConvention::Microsoft => 4,
+        }
+    }
+
+    /// Registers a call may overwrite
+    pub fn caller_saved(self) -> Vec<X86Register> {
+        let (int, doubles): (&[X86Register], usize) = match self {
+            CallingConvention::SystemV => (&X86Register::CALLER_SAVED, 16),
+            CallingConvention::Microsoft => (&X86Register::MICROSOFT_CALLER_SAVED, 6),
+        };
+        let xmm = X86Register::ALL
+            .into_iter()
+            .filter(|register| register.is_xmm())
+            .take(doubles);
+        int.iter().copied().chain(xmm).collect()
+    }
+}
+
+/// The registers temps are assigned on x86-64 under `convention`: every general-purpose one but
+/// the stack and frame pointers for ints, and the xmm registers a call may overwrite for doubles,
+/// since the prologue only saves general-purpose registers. The caller-saved registers come
+/// first, so that a function only has to save and restore the others once it runs out of them,
+/// or for temps living across calls.
+pub fn register_description(convention: CallingConvention) -> RegisterDescription {
+    let caller_saved = convention.caller_saved();
+    let callee_saved: &[X86Register] = match convention {
+        CallingConvention::SystemV => &X86Register::CALLEE_SAVED,
+        CallingConvention::Microsoft => &X86Register::MICROSOFT_CALLEE_SAVED,
+    };
+    let mut int = vec![X86Register::Rax, X86Register::Rdx, X86Register::Rcx];
+    for register in caller_saved.iter().chain(callee_saved) {
+        if !register.is_xmm() && *register != X86Register::Rbp && !int.contains(register) {
+            int.push(*register);
+        }
+    }
+    let double = caller_saved.iter().filter(|register| register.is_xmm());
+    RegisterDescription {
+        int: int.into_iter().map(|register| register as usize).collect(),
+        double: double.map(|register| *register as usize).collect(),
+        callee_saved: callee_saved
+            .iter()
+            .map(|register| *register as usize)
+            .collect(),
+    }
+}
+
+/// Width of an integer operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    Byte,
+    /// 32 bits, for ints, chars and bools
+    Long,
+    /// 64 bits, for addresses like those of strings
+    Quad,
+}
+
+impl Size {
+    /// Width of a value of type `ty`, which isn't a double
+    pub fn of(ty: Type) -> Self {
+        match ty {
+            Type::String => Size::Quad,
+            _ => Size::Long,
+        }
+    }
+
+    /// Suffix of an instruction working on values of this size
+    pub fn suffix(self) -> char {
+        match self {
+            Size::Byte => 'b',
+            Size::Long => 'l',
+            Size::Quad => 'q',
+        }
+    }
+}
+
+/// A memory address, `symbol(%rip)` or `displacement(base, index, scale)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Address {
+    pub base: Option<Dest>,
+    /// Register scaled by 1, 2, 4 or 8
+    pub index: Option<(Dest, u8)>,
+    pub displacement: i32,
+    /// Label the address is relative to, reached through %rip
+    pub symbol: Option<String>,
+}
+
+impl Address {
+    /// Address of the data at `symbol`
+    pub fn symbol(symbol: String) -> Self {
+        Address {
+            base: None,
+            index: None,
+            displacement: 0,
+            symbol: Some(symbol),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum X86Operand {
+    Reg(Dest),
+    Imm(i32),
+    Mem(Address),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOp {
+    Add,
+    Sub,
+    Imul,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SseOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// Flags an x86 conditional jump or set tests; `A` and `B` are for the unsigned comparisons
+/// `ucomisd` makes of doubles, and `P` for its unordered result
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum X86Condition {
+    E,
+    Ne,
+    L,
+    Le,
+    G,
+    Ge,
+    A,
+    Ae,
+    P,
+    Np,
+}
+
+impl X86Condition {
+    /// Suffix of `j<cc>` and `set<cc>` testing the condition
+    pub fn suffix(self) -> &'static str {
+        match self {
+            X86Condition::E => "e",
+            X86Condition::Ne => "ne",
+            X86Condition::L => "l",
+            X86Condition::Le => "le",
+            X86Condition::G => "g",
+            X86Condition::Ge => "ge",
+            X86Condition::A => "a",
+            X86Condition::Ae => "ae",
+            X86Condition::P => "p",
+            X86Condition::Np => "np",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum X86Instruction {
+    Mov {
+        size: Size,
+        dest: X86Operand,
+        src: X86Operand,
+    },
+    /// Moves a double between xmm registers and memory
+    Movsd {
+        dest: X86Operand,
+        src: X86Operand,
+    },
+    /// Loads the address of `symbol` from the global offset table, where position-independent
+    /// code finds the globals another object may define
+    GotLoad {
+        dest: Dest,
+        symbol: String,
+    },
+    /// Computes an address, or any sum of a register, a scaled register and a constant
+    Lea {
+        size: Size,
+        dest: Dest,
+        address: Address,
+    },
+    /// `dest <- left op right`. x86 only has `dest <- dest op right`, which two-address
+    /// conversion leaves.
+    Alu {
+        op: AluOp,
+        size: Size,
+        dest: Dest,
+        left: X86Operand,
+        right: X86Operand,
+    },
+    /// `dest <- src * imm`, which x86 does in one instruction
+    ImulImm {
+        dest: Dest,
+        src: X86Operand,
+        imm: i32,
+    },
+    /// `dest <- src shifted`, with `src` the same as `dest` after two-address conversion
+    Shift {
+        kind: ShiftKind,
+        dest: Dest,
+        src: X86Operand,
+        amount: u32,
+    },
+    /// `dest <- op src`, with `src` the same as `dest` after two-address conversion
+    Unary {
+        op: UnaryOp,
+        dest: Dest,
+        src: X86Operand,
+    },
+    /// Sign-extends %eax into %edx, for `Idiv`
+    Cdq,
+    /// Divides %edx:%eax by `divisor`, leaving the quotient in %eax and the remainder in %edx
+    Idiv {
+        divisor: X86Operand,
+    },
+    /// `dest <- left op right` on doubles, with `left` the same as `dest` after two-address
+    /// conversion
+    Sse {
+        op: SseOp,
+        dest: Dest,
+        left: X86Operand,
+        right: X86Operand,
+    },
+    /// int to double
+    Cvtsi2sd {
+        dest: Dest,
+        src: X86Operand,
+    },
+    /// double to int, truncating toward zero
+    Cvttsd2si {
+        dest: Dest,
+        src: X86Operand,
+    },
+    /// Sets the flags from `left - right`
+    Cmp {
+        size: Size,
+        left: X86Operand,
+        right: X86Operand,
+    },
+    /// Sets the flags from comparing the doubles `left` and `right`
+    Ucomisd {
+        left: Dest,
+        right: X86Operand,
+    },
+    /// `dest <- 1` if `condition` holds, else 0; a `set<cc>` and a zero extension
+    Set {
+        condition: X86Condition,
+        dest: Dest,
+    },
+    Jmp(AsmLabel),
+    Jcc {
+        condition: X86Condition,
+        target: AsmLabel,
+    },
+    Label(AsmLabel),
+    /// Marks the instructions after it as compiled from a source line, like the abstract
+    /// assembly's `Loc`
+    Loc {
+        file: usize,
+        line: usize,
+    },
+    /// Stores an argument passed on the stack to the next call in the outgoing slot `index`,
+    /// where the callee finds it above its return address
+    StoreArgument {
+        ty: Type,
+        index: usize,
+        src: X86Operand,
+    },
+    /// Calls `function` with `args`, each of the given type. Register allocation moves them to
+    /// the registers the calling convention passes them in, leaving those as the operands, and
+    /// stores those that don't fit in registers with `StoreArgument`.
+    Call {
+        function: String,
+        args: Vec<(Type, X86Operand)>,
+    },
+    /// Inline assembly, with `%0` standing for the output's register and the inputs' registers
+    /// numbered after it. Each is of the given type, in a general register or an xmm register
+    /// for a double, and the output may share one of the inputs' registers. The template may
+    /// overwrite the clobbered registers, which none of them is in.
+    Asm {
+        template: String,
+        output: Option<(Type, Dest)>,
+        inputs: Vec<(Type, Dest)>,
+        clobbers: Vec<X86Register>,
+    },
+    /// Returns, with the value in the register, if there's one: %eax, or %xmm0 for a double
+    Ret(Option<X86Register>),
+}
+
+/// A function after instruction selection
+#[derive(Debug)]
+pub struct X86Function {
+    pub name: String,
+    /// Symbol the function is defined as, which is its name unless it's mangled
+    pub symbol: String,
+    /// True if the function is `static`, and so not exported
+    pub is_static: bool,
+    pub instructions: Vec<X86Instruction>,
+    /// Type of the value each temp holds
+    pub temp_types: HashMap<usize, Type>,
+    /// Number of temps allocated so far; later ones are numbered from it
+    pub temp_counter: usize,
+    /// Double constants the instructions read from memory, each at `double_symbol(value)`
+    pub doubles: Vec<f64>,
+    /// Loop nesting depth of the block at each label, for the blocks inside a loop
+    pub loop_depths: HashMap<usize, usize>,
+    /// The stack slots the function needs, and the registers it saves
+    pub frame: Frame,
+    /// How the function receives its parameters and passes arguments to the functions it calls
+    pub convention: CallingConvention,
+    /// Type the function returns, for the debug information
+    pub return_type: Type,
+    /// Source variables each held in a single temp, for the debug information
+    pub variables: Vec<Variable>,
+}
+
+/// A source variable, and where the function keeps it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub ty: Type,
+    /// True for a parameter of the function
+    pub parameter: bool,
+    /// The variable's temp, until register allocation replaces it with its register or its
+    /// stack slot
+    pub home: X86Operand,
+}
+
+impl X86Function {
+    /// Generates a new temp holding values of type `ty`
+    pub fn new_temp(&mut self, ty: Type) -> Dest {
+        let temp = self.temp_counter;
+        self.temp_counter += 1;
+        self.temp_types.insert(temp, ty);
+        Dest::Temp(temp)
+    }
+}
+
+/// Label of the read-only copy of the double `value`
+pub fn double_symbol(value: f64) -> String {
+    format!(".LD{:016x}", value.to_bits())
+}
+
+/// Label of the string constant at `index` in the program's `StringTable`
+pub fn string_symbol(index: usize) -> String {
+    format!(".LS{}", index)
+}
@@ -1,19 +1,48 @@
+//! Binary encoding of x86-64 instructions. Registers 8 through 15, 64-bit operands and the
+//! byte registers %spl, %bpl, %sil and %dil take a REX prefix, 16-bit operands a 0x66 prefix.
+//! A register or memory operand is encoded in a ModRM byte, followed for some addresses by a
+//! SIB byte and a displacement.
+
 #[derive(Debug)]
 pub enum Op {
     // Data Movement
-    Mov(RegOrMem, RegOrMem), // 0x88-0x8B
-    Push(RegOrMem),          // 0x50-0x57, 0xFF
-    Pop(RegOrMem),           // 0x58-0x5F
-    Lea(Register, Memory),   // 0x8D
+    Mov(RegOrMem, RegOrMem),   // 0x88-0x8B
+    Push(RegOrMem),            // 0x50-0x57, 0xFF
+    Pop(RegOrMem),             // 0x58-0x5F
+    Lea(Register, Memory),     // 0x8D
+    Movzx(Register, RegOrMem), // 0x0FB6
+    Set(Condition, RegOrMem),  // 0x0F90-0x0F9F
 
     // Arithmetic
-    Add(RegOrMem, RegOrMem), // 0x00-0x03
-    Sub(RegOrMem, RegOrMem), // 0x28-0x2B
-    Mul(RegOrMem),           // 0xF6-0xF7 /4
-    Div(RegOrMem),           // 0xF6-0xF7 /6
-    Inc(RegOrMem),           // 0x40-0x47, 0xFE-0xFF /0
-    Dec(RegOrMem),           // 0x48-0x4F, 0xFE-0xFF /1
-    Neg(RegOrMem),           // 0xF6-0xF7 /3
+    Add(RegOrMem, RegOrMem),          // 0x00-0x03
+    Sub(RegOrMem, RegOrMem),          // 0x28-0x2B
+    Mul(RegOrMem),                    // 0xF6-0xF7 /4
+    Div(RegOrMem),                    // 0xF6-0xF7 /6
+    Inc(RegOrMem),                    // 0x40-0x47, 0xFE-0xFF /0
+    Dec(RegOrMem),                    // 0x48-0x4F, 0xFE-0xFF /1
+    Neg(RegOrMem),                    // 0xF6-0xF7 /3
+    Imul(Register, RegOrMem),         // 0x0FAF
+    ImulImm(Register, RegOrMem, i32), // 0x69, 0x6B
+    Idiv(RegOrMem),                   // 0xF6-0xF7 /7
+    Cdq,                              // 0x99
+
+    // Logic
+    And(RegOrMem, RegOrMem), // 0x20-0x23
+    Or(RegOrMem, RegOrMem),  // 0x08-0x0B
+    Not(RegOrMem),           // 0xF6-0xF7 /2
+    Shl(RegOrMem, u8),       // 0xC0-0xC1, 0xD0-0xD1 /4
+    Shr(RegOrMem, u8),       // 0xC0-0xC1, 0xD0-0xD1 /5
+    Sar(RegOrMem, u8),       // 0xC0-0xC1, 0xD0-0xD1 /7
+
+    // Doubles, in the low half of an xmm register
+    MovsdXmm(RegOrMem, RegOrMem),  // 0xF20F10-0xF20F11
+    Addsd(Register, RegOrMem),     // 0xF20F58
+    Subsd(Register, RegOrMem),     // 0xF20F5C
+    Mulsd(Register, RegOrMem),     // 0xF20F59
+    Divsd(Register, RegOrMem),     // 0xF20F5E
+    Cvtsi2sd(Register, RegOrMem),  // 0xF20F2A
+    Cvttsd2si(Register, RegOrMem), // 0xF20F2C
+    Ucomisd(Register, RegOrMem),   // 0x660F2E
 
     // Control Flow
     Jmp(i32),  // 0xEB, 0xE9
@@ -48,7 +77,7 @@ pub enum Op {
     Nop, // 0x90
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Register {
     // 8-bit registers
     AL,
@@ -59,6 +88,18 @@ pub enum Register {
     BH,
     CH,
     DH,
+    SPL,
+    BPL,
+    SIL,
+    DIL,
+    R8B,
+    R9B,
+    R10B,
+    R11B,
+    R12B,
+    R13B,
+    R14B,
+    R15B,
     // 16-bit registers
     AX,
     BX,
@@ -68,6 +109,14 @@ pub enum Register {
     BP,
     SI,
     DI,
+    R8W,
+    R9W,
+    R10W,
+    R11W,
+    R12W,
+    R13W,
+    R14W,
+    R15W,
     // 32-bit registers
     EAX,
     EBX,
@@ -77,7 +126,15 @@ pub enum Register {
     EBP,
     ESI,
     EDI,
-    // 64-bit registers (if needed)
+    R8D,
+    R9D,
+    R10D,
+    R11D,
+    R12D,
+    R13D,
+    R14D,
+    R15D,
+    // 64-bit registers
     RAX,
     RBX,
     RCX,
@@ -86,6 +143,77 @@ pub enum Register {
     RBP,
     RSI,
     RDI,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+    // xmm registers, for doubles
+    XMM0,
+    XMM1,
+    XMM2,
+    XMM3,
+    XMM4,
+    XMM5,
+    XMM6,
+    XMM7,
+    XMM8,
+    XMM9,
+    XMM10,
+    XMM11,
+    XMM12,
+    XMM13,
+    XMM14,
+    XMM15,
+}
+
+impl Register {
+    /// Width of the register in bits
+    pub fn bits(self) -> u8 {
+        match self as usize {
+            index if index < Register::AX as usize => 8,
+            index if index < Register::EAX as usize => 16,
+            index if index < Register::RAX as usize => 32,
+            index if index < Register::XMM0 as usize => 64,
+            _ => 128,
+        }
+    }
+
+    /// True for the byte registers only reachable with a REX prefix, which takes the encodings
+    /// of %ah, %ch, %dh and %bh
+    fn is_rex_byte(self) -> bool {
+        matches!(
+            self,
+            Register::SPL | Register::BPL | Register::SIL | Register::DIL
+        )
+    }
+
+    /// True for %ah, %ch, %dh and %bh, which can't be encoded with a REX prefix
+    fn is_high_byte(self) -> bool {
+        matches!(
+            self,
+            Register::AH | Register::CH | Register::DH | Register::BH
+        )
+    }
+}
+
+/// Flags a conditional jump or set tests, numbered as in its opcode. `Ae` and `A` are the
+/// unsigned comparisons, and `P` and `Np` test for the unordered result of comparing doubles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Ae = 0x3,
+    E = 0x4,
+    Ne = 0x5,
+    A = 0x7,
+    P = 0xA,
+    Np = 0xB,
+    L = 0xC,
+    Ge = 0xD,
+    Le = 0xE,
+    G = 0xF,
 }
 
 #[derive(Debug)]
@@ -95,88 +223,266 @@ pub enum RegOrMem {
     Immediate(i32),
 }
 
+/// `displacement(base, index, scale)`, or `displacement(%rip)`, holding a value of `bits`.
+/// Registers in addresses are 64-bit.
 #[derive(Debug)]
 pub struct Memory {
-    base: Option<Register>,
-    index: Option<Register>,
-    scale: Option<u8>, // 1, 2, 4, or 8
-    displacement: i32,
+    pub base: Option<Register>,
+    pub index: Option<Register>,
+    pub scale: Option<u8>, // 1, 2, 4, or 8
+    pub displacement: i32,
+    /// True if the address is `displacement` from the end of the instruction, without a base
+    /// or index
+    pub rip_relative: bool,
+    /// Width of the value at the address, for instructions without a register operand to tell
+    pub bits: u8,
 }
 
-fn serialize_op(bytes: &mut Vec<u8>, op: Op) {
-    match op {
-        Op::Nop => {
-            bytes.push(0x90);
+impl Memory {
+    /// `displacement(base)`
+    pub fn base(base: Register, displacement: i32) -> Self {
+        Memory {
+            base: Some(base),
+            index: None,
+            scale: None,
+            displacement,
+            rip_relative: false,
+            bits: 64,
         }
+    }
 
-        Op::Mov(dest, src) => {
-            match (&dest, &src) {
-                (RegOrMem::Register(rd), RegOrMem::Register(rs)) => {
-                    // Register to register
-                    bytes.push(0x89);
-                    bytes.push(encode_modrm(rd, rs));
-                }
-                (RegOrMem::Register(rd), RegOrMem::Immediate(imm)) => {
-                    // Immediate to register
-                    bytes.push(0xB8 + register_index(rd));
-                    bytes.extend_from_slice(&imm.to_le_bytes());
-                }
-                // Add other mov variants as needed
-                _ => unimplemented!("Mov variant not implemented"),
-            }
+    /// `displacement(base, index, scale)`, or `displacement(, index, scale)` without a base
+    pub fn indexed(base: Option<Register>, index: Register, scale: u8, displacement: i32) -> Self {
+        Memory {
+            base,
+            index: Some(index),
+            scale: Some(scale),
+            displacement,
+            rip_relative: false,
+            bits: 64,
         }
+    }
+
+    /// `displacement(%rip)`
+    pub fn rip(displacement: i32) -> Self {
+        Memory {
+            base: None,
+            index: None,
+            scale: None,
+            displacement,
+            rip_relative: true,
+            bits: 64,
+        }
+    }
+
+    /// The same address, holding a value of `bits` instead
+    pub fn with_bits(mut self, bits: u8) -> Self {
+        self.bits = bits;
+        self
+    }
+}
+
+impl RegOrMem {
+    /// Width of the operand, or None for an immediate, which takes the width of the other
+    fn bits(&self) -> Option<u8> {
+        match self {
+            RegOrMem::Register(reg) => Some(reg.bits()),
+            RegOrMem::Memory(memory) => Some(memory.bits),
+            RegOrMem::Immediate(_) => None,
+        }
+    }
+}
+
+/// Writes `op` to `bytes`. For an address relative to %rip, returns where its displacement
+/// starts in `bytes`, so that it can be fixed up once the address it's relative to is known.
+pub fn serialize_op(bytes: &mut Vec<u8>, op: Op) -> Option<usize> {
+    match op {
+        Op::Mov(dest, src) => match (&dest, &src) {
+            (RegOrMem::Register(rd), RegOrMem::Immediate(imm)) if rd.bits() != 64 => {
+                // Immediate to register, in the short form with the register in the opcode
+                encode_prefixes(bytes, rd.bits(), None, *rd);
+                let opcode = if rd.bits() == 8 { 0xB0 } else { 0xB8 };
+                bytes.push(opcode + (register_index(rd) & 7));
+                encode_immediate(bytes, *imm, rd.bits());
+                None
+            }
+            // There's no 64-bit immediate in the other form; the 32-bit one is sign-extended
+            (_, RegOrMem::Immediate(imm)) => {
+                let bits = dest.bits().unwrap();
+                let displacement = encode_rm(bytes, 0xC7, RegField::Extension(0), bits, &dest);
+                encode_immediate(bytes, *imm, bits);
+                displacement
+            }
+            _ => encode_binary(bytes, 0x89, 0, &dest, &src),
+        },
 
         Op::Push(src) => match src {
             RegOrMem::Register(reg) => {
-                bytes.push(0x50 + register_index(&reg));
+                check_stack_operand(reg);
+                encode_stack_prefixes(bytes, reg);
+                bytes.push(0x50 + (register_index(&reg) & 7));
+                None
             }
             RegOrMem::Immediate(imm) => {
-                if imm >= -128 && imm <= 127 {
+                if fits_in_i8(imm) {
                     bytes.push(0x6A);
                     bytes.push(imm as u8);
                 } else {
                     bytes.push(0x68);
                     bytes.extend_from_slice(&imm.to_le_bytes());
                 }
+                None
             }
-            _ => unimplemented!("Push variant not implemented"),
+            // Push and pop move 64 bits without a REX.W prefix
+            RegOrMem::Memory(src) => encode_rm(
+                bytes,
+                0xFF,
+                RegField::Extension(6),
+                32,
+                &RegOrMem::Memory(src),
+            ),
         },
 
         Op::Pop(dest) => match dest {
             RegOrMem::Register(reg) => {
-                bytes.push(0x58 + register_index(&reg));
+                check_stack_operand(reg);
+                encode_stack_prefixes(bytes, reg);
+                bytes.push(0x58 + (register_index(&reg) & 7));
+                None
             }
-            _ => unimplemented!("Pop variant not implemented"),
+            RegOrMem::Memory(dest) => encode_rm(
+                bytes,
+                0x8F,
+                RegField::Extension(0),
+                32,
+                &RegOrMem::Memory(dest),
+            ),
+            RegOrMem::Immediate(_) => panic!("An immediate can't be popped"),
         },
 
-        Op::Add(dest, src) => match (&dest, &src) {
-            (RegOrMem::Register(rd), RegOrMem::Register(rs)) => {
-                bytes.push(0x01);
-                bytes.push(encode_modrm(rd, rs));
+        Op::Movzx(dest, src) => {
+            assert_eq!(src.bits(), Some(8), "movzx extends a byte");
+            encode_modrm(
+                bytes,
+                None,
+                &[0x0F, 0xB6],
+                RegField::Register(dest),
+                dest.bits(),
+                &src,
+            )
+        }
+
+        Op::Set(condition, dest) => {
+            assert_eq!(dest.bits(), Some(8), "set<cc> writes a byte");
+            let opcode = [0x0F, 0x90 | condition as u8];
+            encode_modrm(bytes, None, &opcode, RegField::Extension(0), 8, &dest)
+        }
+
+        Op::Lea(dest, address) => {
+            assert_ne!(dest.bits(), 8, "lea can't write a byte register");
+            let address = RegOrMem::Memory(address);
+            encode_rm(bytes, 0x8D, RegField::Register(dest), dest.bits(), &address)
+        }
+
+        Op::Add(dest, src) => encode_binary(bytes, 0x01, 0, &dest, &src),
+        Op::Sub(dest, src) => encode_binary(bytes, 0x29, 5, &dest, &src),
+        Op::Cmp(dest, src) => encode_binary(bytes, 0x39, 7, &dest, &src),
+
+        Op::Test(dest, src) => match (&dest, &src) {
+            // There's no sign-extended byte immediate, and no form with the operands swapped,
+            // which doesn't matter since they're only and-ed
+            (_, RegOrMem::Immediate(imm)) => {
+                let bits = dest
+                    .bits()
+                    .expect("test needs a register or memory operand");
+                let displacement = encode_rm(bytes, 0xF7, RegField::Extension(0), bits, &dest);
+                encode_immediate(bytes, *imm, bits);
+                displacement
             }
-            (RegOrMem::Register(rd), RegOrMem::Immediate(imm)) => {
-                if *imm >= -128 && *imm <= 127 {
-                    bytes.push(0x83);
-                    bytes.push(encode_modrm_opcode(rd, 0));
-                    bytes.push(*imm as u8);
-                } else {
-                    bytes.push(0x81);
-                    bytes.push(encode_modrm_opcode(rd, 0));
-                    bytes.extend_from_slice(&imm.to_le_bytes());
-                }
+            (RegOrMem::Register(rd), RegOrMem::Memory(_)) => {
+                encode_binary(bytes, 0x85, 0, &src, &RegOrMem::Register(*rd))
             }
-            _ => unimplemented!("Add variant not implemented"),
+            _ => encode_binary(bytes, 0x85, 0, &dest, &src),
         },
 
-        Op::Jmp(offset) => {
-            if offset >= -128 && offset <= 127 {
-                bytes.push(0xEB);
-                bytes.push(offset as u8);
-            } else {
-                bytes.push(0xE9);
-                bytes.extend_from_slice(&offset.to_le_bytes());
+        Op::Mul(src) => encode_unary(bytes, 0xF7, 4, &src),
+        Op::Div(src) => encode_unary(bytes, 0xF7, 6, &src),
+        Op::Neg(dest) => encode_unary(bytes, 0xF7, 3, &dest),
+        Op::Idiv(src) => encode_unary(bytes, 0xF7, 7, &src),
+        Op::Not(dest) => encode_unary(bytes, 0xF7, 2, &dest),
+        Op::And(dest, src) => encode_binary(bytes, 0x21, 4, &dest, &src),
+        Op::Or(dest, src) => encode_binary(bytes, 0x09, 1, &dest, &src),
+        Op::Shl(dest, amount) => encode_shift(bytes, 4, &dest, amount),
+        Op::Shr(dest, amount) => encode_shift(bytes, 5, &dest, amount),
+        Op::Sar(dest, amount) => encode_shift(bytes, 7, &dest, amount),
+
+        Op::Imul(dest, src) => {
+            if let RegOrMem::Register(src) = src {
+                check_same_size(dest, src);
             }
+            let reg = RegField::Register(dest);
+            encode_modrm(bytes, None, &[0x0F, 0xAF], reg, dest.bits(), &src)
         }
+        // `dest <- src * imm`, with a sign-extended byte when the immediate fits in one
+        Op::ImulImm(dest, src, imm) => {
+            if let RegOrMem::Register(src) = src {
+                check_same_size(dest, src);
+            }
+            let short = fits_in_i8(imm);
+            let opcode = if short { 0x6B } else { 0x69 };
+            let reg = RegField::Register(dest);
+            let displacement = encode_modrm(bytes, None, &[opcode], reg, dest.bits(), &src);
+            match short {
+                true => bytes.push(imm as u8),
+                false => encode_immediate(bytes, imm, dest.bits()),
+            }
+            displacement
+        }
+
+        Op::MovsdXmm(dest, src) => match (&dest, &src) {
+            (RegOrMem::Register(rd), _) => encode_sse(bytes, 0xF2, 0x10, *rd, 32, &src),
+            (_, RegOrMem::Register(rs)) => encode_sse(bytes, 0xF2, 0x11, *rs, 32, &dest),
+            _ => panic!("movsd moves to or from an xmm register"),
+        },
+        Op::Addsd(dest, src) => encode_sse(bytes, 0xF2, 0x58, dest, 32, &src),
+        Op::Subsd(dest, src) => encode_sse(bytes, 0xF2, 0x5C, dest, 32, &src),
+        Op::Mulsd(dest, src) => encode_sse(bytes, 0xF2, 0x59, dest, 32, &src),
+        Op::Divsd(dest, src) => encode_sse(bytes, 0xF2, 0x5E, dest, 32, &src),
+        // The general-purpose operand's width picks between the int and long forms
+        Op::Cvtsi2sd(dest, src) => {
+            let bits = src.bits().expect("cvtsi2sd converts a register or memory");
+            encode_sse(bytes, 0xF2, 0x2A, dest, bits, &src)
+        }
+        Op::Cvttsd2si(dest, src) => encode_sse(bytes, 0xF2, 0x2C, dest, dest.bits(), &src),
+        Op::Ucomisd(left, right) => encode_sse(bytes, 0x66, 0x2E, left, 32, &right),
+
+        // 0x40-0x4F are REX prefixes in 64-bit mode, so only the ModRM forms are left
+        Op::Inc(dest) => encode_unary(bytes, 0xFF, 0, &dest),
+        Op::Dec(dest) => encode_unary(bytes, 0xFF, 1, &dest),
+
+        op => {
+            serialize_operandless_op(bytes, op);
+            None
+        }
+    }
+}
+
+/// Writes an op without register or memory operands
+fn serialize_operandless_op(bytes: &mut Vec<u8>, op: Op) {
+    match op {
+        Op::Nop => {
+            bytes.push(0x90);
+        }
+
+        Op::Jmp(offset) => encode_jump(bytes, None, offset, !fits_in_i8(offset)),
+        Op::Je(offset) => encode_jump(bytes, Some(Condition::E), offset, !fits_in_i8(offset)),
+        Op::Jne(offset) => encode_jump(bytes, Some(Condition::Ne), offset, !fits_in_i8(offset)),
+        Op::Jl(offset) => encode_jump(bytes, Some(Condition::L), offset, !fits_in_i8(offset)),
+        Op::Jle(offset) => encode_jump(bytes, Some(Condition::Le), offset, !fits_in_i8(offset)),
+        Op::Jg(offset) => encode_jump(bytes, Some(Condition::G), offset, !fits_in_i8(offset)),
+        Op::Jge(offset) => encode_jump(bytes, Some(Condition::Ge), offset, !fits_in_i8(offset)),
+
+        Op::Cdq => bytes.push(0x99),
 
         Op::Call(offset) => {
             bytes.push(0xE8);
@@ -187,28 +493,354 @@ fn serialize_op(bytes: &mut Vec<u8>, op: Op) {
             bytes.push(0xC3);
         }
 
-        // Add other operations as needed...
-        _ => unimplemented!("Operation not implemented"),
+        Op::Enter(size, nesting) => {
+            bytes.push(0xC8);
+            bytes.extend_from_slice(&size.to_le_bytes());
+            bytes.push(nesting);
+        }
+        Op::Leave => bytes.push(0xC9),
+
+        // A prefix, repeating the string operation after it %rcx times
+        Op::Rep => bytes.push(0xF3),
+        Op::Movsb => bytes.push(0xA4),
+        Op::Movsw => bytes.extend_from_slice(&[0x66, 0xA5]),
+        Op::Movsd => bytes.push(0xA5),
+
+        Op::Int(vector) => bytes.extend_from_slice(&[0xCD, vector]),
+        Op::Syscall => bytes.extend_from_slice(&[0x0F, 0x05]),
+
+        op => unreachable!("{:?} has operands", op),
     }
 }
 
+fn fits_in_i8(imm: i32) -> bool {
+    (-128..=127).contains(&imm)
+}
+
+/// Number of the register, from 0 to 15. The low 3 bits go in the ModRM byte or the opcode, and
+/// the top one in a REX prefix.
 fn register_index(reg: &Register) -> u8 {
     match reg {
         Register::AL | Register::AX | Register::EAX | Register::RAX => 0,
         Register::CL | Register::CX | Register::ECX | Register::RCX => 1,
         Register::DL | Register::DX | Register::EDX | Register::RDX => 2,
         Register::BL | Register::BX | Register::EBX | Register::RBX => 3,
-        Register::AH | Register::SP | Register::ESP | Register::RSP => 4,
-        Register::CH | Register::BP | Register::EBP | Register::RBP => 5,
-        Register::DH | Register::SI | Register::ESI | Register::RSI => 6,
-        Register::BH | Register::DI | Register::EDI | Register::RDI => 7,
+        Register::AH | Register::SPL | Register::SP | Register::ESP | Register::RSP => 4,
+        Register::CH | Register::BPL | Register::BP | Register::EBP | Register::RBP => 5,
+        Register::DH | Register::SIL | Register::SI | Register::ESI | Register::RSI => 6,
+        Register::BH | Register::DIL | Register::DI | Register::EDI | Register::RDI => 7,
+        Register::R8B | Register::R8W | Register::R8D | Register::R8 => 8,
+        Register::R9B | Register::R9W | Register::R9D | Register::R9 => 9,
+        Register::R10B | Register::R10W | Register::R10D | Register::R10 => 10,
+        Register::R11B | Register::R11W | Register::R11D | Register::R11 => 11,
+        Register::R12B | Register::R12W | Register::R12D | Register::R12 => 12,
+        Register::R13B | Register::R13W | Register::R13D | Register::R13 => 13,
+        Register::R14B | Register::R14W | Register::R14D | Register::R14 => 14,
+        Register::R15B | Register::R15W | Register::R15D | Register::R15 => 15,
+        xmm => *xmm as u8 - Register::XMM0 as u8,
+    }
+}
+
+/// Push and pop only move 64 or 16 bits in 64-bit mode
+fn check_stack_operand(reg: Register) {
+    assert!(
+        matches!(reg.bits(), 16 | 64),
+        "{:?} can't be pushed or popped",
+        reg
+    );
+}
+
+/// Push and pop move 64 bits without a REX.W prefix
+fn encode_stack_prefixes(bytes: &mut Vec<u8>, reg: Register) {
+    if reg.bits() == 16 {
+        bytes.push(0x66);
+    }
+    encode_rex_prefix(bytes, false, None, reg);
+}
+
+/// The opcode of an instruction for operands of `bits`, from the one for 16 bits and more; the
+/// byte forms are one less
+fn sized_opcode(opcode: u8, bits: u8) -> u8 {
+    if bits == 8 {
+        opcode - 1
+    } else {
+        opcode
+    }
+}
+
+/// Writes an immediate for an operand of `bits`. 64-bit operands take a 32-bit immediate,
+/// sign-extended.
+fn encode_immediate(bytes: &mut Vec<u8>, imm: i32, bits: u8) {
+    match bits {
+        8 => bytes.push(imm as u8),
+        16 => bytes.extend_from_slice(&(imm as u16).to_le_bytes()),
+        _ => bytes.extend_from_slice(&imm.to_le_bytes()),
+    }
+}
+
+/// What the reg field of a ModRM byte holds: a register operand, or for instructions with one
+/// operand or an immediate, more of the opcode
+enum RegField {
+    Register(Register),
+    Extension(u8),
+}
+
+/// Writes an instruction with a ModRM byte, with its prefixes for operands of `bits`, and
+/// `opcode` for 16 bits and more, or the one before it for bytes. Returns where the
+/// displacement of a %rip-relative address starts.
+fn encode_rm(
+    bytes: &mut Vec<u8>,
+    opcode: u8,
+    reg: RegField,
+    bits: u8,
+    rm: &RegOrMem,
+) -> Option<usize> {
+    if let (RegField::Register(reg), RegOrMem::Register(rm)) = (&reg, rm) {
+        check_same_size(*reg, *rm);
+    }
+    encode_modrm(bytes, None, &[sized_opcode(opcode, bits)], reg, bits, rm)
+}
+
+/// Writes an instruction with a ModRM byte: `prefix`, if it needs one before the others, its
+/// prefixes for operands of `bits`, then `opcode`, whatever the size. Returns where the
+/// displacement of a %rip-relative address starts.
+fn encode_modrm(
+    bytes: &mut Vec<u8>,
+    prefix: Option<u8>,
+    opcode: &[u8],
+    reg: RegField,
+    bits: u8,
+    rm: &RegOrMem,
+) -> Option<usize> {
+    bytes.extend(prefix);
+    let (reg, field) = match reg {
+        RegField::Register(reg) => (Some(reg), register_index(&reg)),
+        RegField::Extension(extension) => (None, extension),
+    };
+    match rm {
+        RegOrMem::Register(rm) => {
+            encode_prefixes(bytes, bits, reg, *rm);
+            bytes.extend_from_slice(opcode);
+            bytes.push(0xC0 | ((field & 7) << 3) | (register_index(rm) & 7));
+            None
+        }
+        RegOrMem::Memory(memory) => {
+            encode_memory_prefixes(bytes, bits, reg, memory);
+            bytes.extend_from_slice(opcode);
+            encode_memory(bytes, field, memory)
+        }
+        RegOrMem::Immediate(_) => panic!("An immediate can't be written"),
+    }
+}
+
+/// Writes a scalar double instruction: `prefix`, then 0x0F and `opcode`, with the xmm or
+/// general-purpose register `reg` in the reg field. `bits` is the width of the
+/// general-purpose operand, if there's one, which takes a REX.W prefix for 64 bits, and 32
+/// otherwise.
+fn encode_sse(
+    bytes: &mut Vec<u8>,
+    prefix: u8,
+    opcode: u8,
+    reg: Register,
+    bits: u8,
+    rm: &RegOrMem,
+) -> Option<usize> {
+    let reg = RegField::Register(reg);
+    encode_modrm(bytes, Some(prefix), &[0x0F, opcode], reg, bits, rm)
+}
+
+/// Writes an arithmetic instruction `dest <- dest op src`, where `opcode` is the form writing
+/// its r/m operand from its reg operand, the one after it is the opposite, and `extension`
+/// picks the operation in the group of immediate forms
+fn encode_binary(
+    bytes: &mut Vec<u8>,
+    opcode: u8,
+    extension: u8,
+    dest: &RegOrMem,
+    src: &RegOrMem,
+) -> Option<usize> {
+    match (dest, src) {
+        (_, RegOrMem::Register(rs)) => {
+            encode_rm(bytes, opcode, RegField::Register(*rs), rs.bits(), dest)
+        }
+        (RegOrMem::Register(rd), RegOrMem::Memory(_)) => {
+            encode_rm(bytes, opcode + 2, RegField::Register(*rd), rd.bits(), src)
+        }
+        (_, RegOrMem::Immediate(imm)) => {
+            let bits = dest.bits().expect("An immediate can't be written");
+            if bits != 8 && fits_in_i8(*imm) {
+                // A byte, sign-extended to the operand
+                let displacement =
+                    encode_rm(bytes, 0x83, RegField::Extension(extension), bits, dest);
+                bytes.push(*imm as u8);
+                displacement
+            } else {
+                let displacement =
+                    encode_rm(bytes, 0x81, RegField::Extension(extension), bits, dest);
+                encode_immediate(bytes, *imm, bits);
+                displacement
+            }
+        }
+        _ => panic!("An instruction can't have two memory operands"),
     }
 }
 
-fn encode_modrm(reg1: &Register, reg2: &Register) -> u8 {
-    0xC0 | (register_index(reg1) << 3) | register_index(reg2)
+/// Writes an instruction of the group at `opcode` with one operand, picked by `extension`
+fn encode_unary(
+    bytes: &mut Vec<u8>,
+    opcode: u8,
+    extension: u8,
+    operand: &RegOrMem,
+) -> Option<usize> {
+    let bits = operand.bits().expect("An immediate can't be written");
+    encode_rm(bytes, opcode, RegField::Extension(extension), bits, operand)
+}
+
+/// Writes a shift of `dest` by `amount` bits, picked by `extension` in the group of shifts.
+/// Shifting by 1 has a form without the immediate.
+fn encode_shift(bytes: &mut Vec<u8>, extension: u8, dest: &RegOrMem, amount: u8) -> Option<usize> {
+    let bits = dest.bits().expect("An immediate can't be written");
+    let opcode = if amount == 1 { 0xD1 } else { 0xC1 };
+    let displacement = encode_rm(bytes, opcode, RegField::Extension(extension), bits, dest);
+    if amount != 1 {
+        bytes.push(amount);
+    }
+    displacement
 }
 
-fn encode_modrm_opcode(reg: &Register, opcode: u8) -> u8 {
-    0xC0 | (opcode << 3) | register_index(reg)
+/// Writes a jump by `offset` from the end of the instruction, if `condition` holds or
+/// unconditionally without one. Near jumps take a 32-bit offset, and short ones a byte.
+pub fn encode_jump(bytes: &mut Vec<u8>, condition: Option<Condition>, offset: i32, near: bool) {
+    match (condition, near) {
+        (None, false) => bytes.push(0xEB),
+        (None, true) => bytes.push(0xE9),
+        (Some(condition), false) => bytes.push(0x70 | condition as u8),
+        (Some(condition), true) => bytes.extend_from_slice(&[0x0F, 0x80 | condition as u8]),
+    }
+    if near {
+        bytes.extend_from_slice(&offset.to_le_bytes());
+    } else {
+        assert!(fits_in_i8(offset), "{} is too far for a short jump", offset);
+        bytes.push(offset as u8);
+    }
+}
+
+fn check_same_size(reg1: Register, reg2: Register) {
+    assert_eq!(
+        reg1.bits(),
+        reg2.bits(),
+        "operands {:?} and {:?} differ in size",
+        reg1,
+        reg2
+    );
+}
+
+/// Writes the prefixes of an instruction on operands of `bits`, with `reg` in the reg field of
+/// its ModRM byte, if it has one, and `rm` in the r/m field or the opcode
+fn encode_prefixes(bytes: &mut Vec<u8>, bits: u8, reg: Option<Register>, rm: Register) {
+    if bits == 16 {
+        bytes.push(0x66);
+    }
+    encode_rex_prefix(bytes, bits == 64, reg, rm);
+}
+
+/// Writes a REX prefix, if the registers or a `w` for 64-bit operands need one
+fn encode_rex_prefix(bytes: &mut Vec<u8>, w: bool, reg: Option<Register>, rm: Register) {
+    let reg_index = reg.map_or(0, |reg| register_index(&reg));
+    let rex = encode_rex(w, reg_index, 0, register_index(&rm));
+    push_rex(bytes, rex, reg.into_iter().chain([rm]));
+}
+
+/// Like `encode_prefixes`, for an instruction with `memory` in the r/m field of its ModRM byte
+fn encode_memory_prefixes(bytes: &mut Vec<u8>, bits: u8, reg: Option<Register>, memory: &Memory) {
+    if bits == 16 {
+        bytes.push(0x66);
+    }
+    let index_of = |reg: Option<Register>| reg.map_or(0, |reg| register_index(&reg));
+    let rex = encode_rex(
+        bits == 64,
+        index_of(reg),
+        index_of(memory.index),
+        index_of(memory.base),
+    );
+    push_rex(bytes, rex, reg.into_iter());
+}
+
+/// Writes `rex`, unless it's empty and none of the byte `registers` need it
+fn push_rex(bytes: &mut Vec<u8>, rex: u8, registers: impl Iterator<Item = Register> + Clone) {
+    if rex != 0x40 || registers.clone().any(Register::is_rex_byte) {
+        assert!(
+            !registers.clone().any(Register::is_high_byte),
+            "%ah, %bh, %ch and %dh can't be encoded with a REX prefix"
+        );
+        bytes.push(rex);
+    }
+}
+
+/// A REX prefix: W for 64-bit operands, and the top bits of the ModRM reg field, the SIB index
+/// and the ModRM r/m field, SIB base or opcode register
+fn encode_rex(w: bool, reg: u8, index: u8, base: u8) -> u8 {
+    0x40 | (u8::from(w) << 3) | ((reg >> 3) << 2) | ((index >> 3) << 1) | (base >> 3)
+}
+
+/// Writes the ModRM byte addressing `memory`, with `reg` in its reg field, then the SIB byte and
+/// displacement the address needs. The displacement takes one byte when it fits, and none when
+/// it's 0, except from %rbp or %r13, whose encoding with none means something else. Returns
+/// where the displacement starts, for an address relative to %rip.
+fn encode_memory(bytes: &mut Vec<u8>, reg: u8, memory: &Memory) -> Option<usize> {
+    let reg = (reg & 7) << 3;
+    for register in memory.base.iter().chain(&memory.index) {
+        assert_eq!(
+            register.bits(),
+            64,
+            "{:?} can't be used in an address",
+            register
+        );
+    }
+    if memory.rip_relative {
+        assert!(memory.base.is_none() && memory.index.is_none());
+        bytes.push(reg | 0b101);
+        let start = bytes.len();
+        bytes.extend_from_slice(&memory.displacement.to_le_bytes());
+        return Some(start);
+    }
+
+    // An index of 0b100 is no index, which is why %rsp can't be one
+    let index = match memory.index {
+        Some(Register::RSP) => panic!("%rsp can't be an index"),
+        Some(index) => register_index(&index) & 7,
+        None => 0b100,
+    };
+    let scale = match memory.scale.unwrap_or(1) {
+        1 => 0,
+        2 => 1,
+        4 => 2,
+        8 => 3,
+        scale => panic!("Scale {} isn't 1, 2, 4 or 8", scale),
+    };
+    let Some(base) = memory.base else {
+        // Without a base, a SIB byte with a base of 0b101 and mod 0 takes a 32-bit displacement
+        bytes.push(reg | 0b100);
+        bytes.push((scale << 6) | (index << 3) | 0b101);
+        bytes.extend_from_slice(&memory.displacement.to_le_bytes());
+        return None;
+    };
+
+    let base = register_index(&base) & 7;
+    let (mode, displacement) = if memory.displacement == 0 && base != 0b101 {
+        (0b00, &[][..])
+    } else if fits_in_i8(memory.displacement) {
+        (0b01, &memory.displacement.to_le_bytes()[..1])
+    } else {
+        (0b10, &memory.displacement.to_le_bytes()[..])
+    };
+    // An r/m of 0b100 means a SIB byte follows, so %rsp and %r12 can only be a base through one
+    if memory.index.is_some() || base == 0b100 {
+        bytes.push((mode << 6) | reg | 0b100);
+        bytes.push((scale << 6) | (index << 3) | base);
+    } else {
+        bytes.push((mode << 6) | reg | base);
+    }
+    bytes.extend_from_slice(displacement);
+    None
 }
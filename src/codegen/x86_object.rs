@@ -0,0 +1,515 @@
+//! Relocatable objects for x86-64, written without an external assembler: the functions'
+//! instructions, once registers are allocated and frames placed, are encoded by the crate's
+//! own assembler, and laid out with the constants and globals the way `emit_x86` lays them out
+//! in its assembly, except that globals starting out as zero go in `.bss`. Each function is
+//! encoded on its own, so that its code can be cached for incremental builds. Inline assembly,
+//! the global offset table and debug information need the system's assembler, so they're only
+//! written as assembly.
+
+use super::assembler::{assemble, Item, Relocation, RelocationKind};
+use super::context::{AsmLabel, Dest, Global, Operand, ShiftKind, StringTable};
+use super::frame::Frame;
+use super::object::{write_coff, write_elf, Format, Object, Section, Symbol, SymbolKind};
+use super::x86::{
+    double_symbol, string_symbol, Address, AluOp, Size, SseOp, UnaryOp, X86Condition, X86Function,
+    X86Instruction, X86Operand, X86Register,
+};
+use super::x86_encoding::{Condition, Memory, Op, RegOrMem, Register};
+use crate::sema::Type;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A function's machine code, encoded on its own from offset 0
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionCode {
+    pub symbol: String,
+    /// True if the function is `static`, and so not exported
+    pub is_static: bool,
+    pub bytes: Vec<u8>,
+    /// Relocations for the symbols the code refers to but doesn't define, including the other
+    /// functions, which the object resolves once it lays them out
+    pub relocations: Vec<Relocation>,
+    /// Double constants the code reads from memory, each at `double_symbol(value)`
+    pub doubles: Vec<f64>,
+}
+
+/// Writes `functions`, `globals` and `strings` to `outpath` as an object of `format`
+pub fn emit_x86_object(
+    outpath: &Path,
+    functions: &[FunctionCode],
+    globals: &[Global],
+    strings: &StringTable,
+    format: Format,
+) -> io::Result<()> {
+    let object = x86_object(functions, globals, strings);
+    let bytes = match format {
+        Format::Elf => write_elf(&object),
+        Format::Coff => write_coff(&object),
+    };
+    fs::write(outpath, bytes)
+}
+
+/// Lays out the code of `functions` one after the other, after which come the object's
+/// constants and globals
+fn x86_object(functions: &[FunctionCode], globals: &[Global], strings: &StringTable) -> Object {
+    let mut object = Object::default();
+    let mut relocations = Vec::new();
+    for function in functions {
+        let offset = object.text.len();
+        object.symbols.push(Symbol {
+            name: function.symbol.clone(),
+            section: Section::Text,
+            offset,
+            size: function.bytes.len(),
+            kind: SymbolKind::Function,
+            global: !function.is_static,
+        });
+        object.text.extend_from_slice(&function.bytes);
+        relocations.extend(function.relocations.iter().map(|relocation| Relocation {
+            offset: offset + relocation.offset,
+            ..relocation.clone()
+        }));
+    }
+    // Calls and addresses of the functions laid out here are filled in, as the assembler
+    // fills in those within a function, so the code is the same as if it was assembled whole
+    for relocation in relocations {
+        let symbol = object
+            .symbols
+            .iter()
+            .find(|symbol| symbol.name == relocation.symbol);
+        match (symbol, relocation.kind) {
+            (Some(symbol), RelocationKind::Pc32 | RelocationKind::Plt32) => {
+                let value = symbol.offset as i64 + relocation.addend - relocation.offset as i64;
+                let field = relocation.offset..relocation.offset + 4;
+                object.text[field].copy_from_slice(&i32::try_from(value).unwrap().to_le_bytes());
+            }
+            _ => object.relocations.push((Section::Text, relocation)),
+        }
+    }
+
+    let label = |name: String, offset: usize| Symbol {
+        name,
+        section: Section::Rodata,
+        offset,
+        size: 0,
+        kind: SymbolKind::Label,
+        global: false,
+    };
+    for (index, string) in strings.iter() {
+        object
+            .symbols
+            .push(label(string_symbol(index), object.rodata.len()));
+        object.rodata.extend_from_slice(string.as_bytes());
+        object.rodata.push(0);
+    }
+    let mut doubles: Vec<f64> = Vec::new();
+    for double in functions.iter().flat_map(|function| &function.doubles) {
+        if !doubles
+            .iter()
+            .any(|seen| seen.to_bits() == double.to_bits())
+        {
+            doubles.push(*double);
+        }
+    }
+    if !doubles.is_empty() {
+        object
+            .rodata
+            .resize(object.rodata.len().next_multiple_of(8), 0);
+    }
+    for double in doubles {
+        object
+            .symbols
+            .push(label(double_symbol(double), object.rodata.len()));
+        object
+            .rodata
+            .extend_from_slice(&double.to_bits().to_le_bytes());
+    }
+
+    for global in globals {
+        // Zeroed globals only take up space once the program is loaded
+        let zeroed_size = match &global.value {
+            Operand::Const(value) if *value as i32 == 0 => Some(4),
+            Operand::Double(value) if value.to_bits() == 0 => Some(8),
+            _ => None,
+        };
+        if let Some(size) = zeroed_size {
+            object.symbols.push(Symbol {
+                name: global.name.clone(),
+                section: Section::Bss,
+                offset: object.bss,
+                size,
+                kind: SymbolKind::Object,
+                global: !global.is_static,
+            });
+            object.bss += size;
+            continue;
+        }
+        let offset = object.data.len();
+        match &global.value {
+            Operand::Const(value) => object
+                .data
+                .extend_from_slice(&(*value as i32).to_le_bytes()),
+            Operand::Double(value) => object
+                .data
+                .extend_from_slice(&value.to_bits().to_le_bytes()),
+            Operand::Str(index) => {
+                object.relocations.push((
+                    Section::Data,
+                    Relocation {
+                        offset,
+                        symbol: string_symbol(*index),
+                        kind: RelocationKind::Abs64,
+                        addend: 0,
+                    },
+                ));
+                object.data.extend_from_slice(&[0; 8]);
+            }
+            Operand::Var(_) => unreachable!("globals are initialized with constants"),
+        }
+        object.symbols.push(Symbol {
+            name: global.name.clone(),
+            section: Section::Data,
+            offset,
+            size: object.data.len() - offset,
+            kind: SymbolKind::Object,
+            global: !global.is_static,
+        });
+    }
+    object
+}
+
+/// Assembles `function` on its own, starting at the label of its symbol
+pub fn assemble_function(function: &X86Function) -> io::Result<FunctionCode> {
+    let mut items = Vec::new();
+    function_items(function, &mut items)?;
+    let assembly =
+        assemble(items).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    Ok(FunctionCode {
+        symbol: function.symbol.clone(),
+        is_static: function.is_static,
+        bytes: assembly.bytes,
+        relocations: assembly.relocations,
+        doubles: function.doubles.clone(),
+    })
+}
+
+/// Adds the items of `function` to `items`: its label, the prologue, then each instruction,
+/// with the epilogue before each return
+fn function_items(function: &X86Function, items: &mut Vec<Item>) -> io::Result<()> {
+    items.push(Item::Label(function.symbol.clone()));
+    prologue(&function.frame, items);
+    for instruction in &function.instructions {
+        match instruction {
+            X86Instruction::GotLoad { .. } | X86Instruction::Asm { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!(
+                        "{} needs the system's assembler, for {}",
+                        function.name,
+                        match instruction {
+                            X86Instruction::Asm { .. } => "its inline assembly",
+                            _ => "position-independent code",
+                        }
+                    ),
+                ))
+            }
+            X86Instruction::Ret(_) => epilogue(&function.frame, items),
+            _ => {}
+        }
+        instruction_items(instruction, &function.symbol, items);
+    }
+    Ok(())
+}
+
+/// Items of `write_x86_prologue`'s instructions, without the debug information
+fn prologue(frame: &Frame, items: &mut Vec<Item>) {
+    if !frame.omit_frame_pointer {
+        items.push(Item::Op(Op::Push(RegOrMem::Register(Register::RBP))));
+        items.push(Item::Op(Op::Mov(
+            RegOrMem::Register(Register::RBP),
+            RegOrMem::Register(Register::RSP),
+        )));
+    }
+    for register in &frame.saved_registers {
+        let register = encoded_register(*register, Size::Quad);
+        items.push(Item::Op(Op::Push(RegOrMem::Register(register))));
+    }
+    if frame.size() > 0 {
+        let size = RegOrMem::Immediate(frame.size() as i32);
+        items.push(Item::Op(Op::Sub(RegOrMem::Register(Register::RSP), size)));
+    }
+}
+
+/// Items of `write_x86_epilogue`'s instructions, without the debug information
+fn epilogue(frame: &Frame, items: &mut Vec<Item>) {
+    if frame.size() > 0 {
+        let size = RegOrMem::Immediate(frame.size() as i32);
+        items.push(Item::Op(Op::Add(RegOrMem::Register(Register::RSP), size)));
+    }
+    for register in frame.saved_registers.iter().rev() {
+        let register = encoded_register(*register, Size::Quad);
+        items.push(Item::Op(Op::Pop(RegOrMem::Register(register))));
+    }
+    if !frame.omit_frame_pointer {
+        items.push(Item::Op(Op::Pop(RegOrMem::Register(Register::RBP))));
+    }
+}
+
+/// Adds the items `instruction` of the function `function` is encoded as to `items`
+fn instruction_items(instruction: &X86Instruction, function: &str, items: &mut Vec<Item>) {
+    let label = |label: &AsmLabel| format!(".L{}_{}", function, label.0);
+    let mut symbol = None;
+    let mut operand = |operand: &X86Operand, size: Size| match operand {
+        X86Operand::Reg(dest) => RegOrMem::Register(register(dest, size)),
+        X86Operand::Imm(value) => RegOrMem::Immediate(*value),
+        X86Operand::Mem(address) => {
+            let (memory, address_symbol) = memory(address, size);
+            symbol = address_symbol;
+            RegOrMem::Memory(memory)
+        }
+    };
+    let op = match instruction {
+        X86Instruction::Mov { size, dest, src } => {
+            Op::Mov(operand(dest, *size), operand(src, *size))
+        }
+        X86Instruction::Movsd { dest, src } => {
+            Op::MovsdXmm(operand(dest, Size::Quad), operand(src, Size::Quad))
+        }
+        X86Instruction::Lea {
+            size,
+            dest,
+            address,
+        } => {
+            let (address, address_symbol) = memory(address, *size);
+            symbol = address_symbol;
+            Op::Lea(register(dest, *size), address)
+        }
+        X86Instruction::Alu {
+            op,
+            size,
+            dest,
+            right,
+            ..
+        } => {
+            let right = operand(right, *size);
+            match op {
+                AluOp::Add => Op::Add(RegOrMem::Register(register(dest, *size)), right),
+                AluOp::Sub => Op::Sub(RegOrMem::Register(register(dest, *size)), right),
+                AluOp::Imul => Op::Imul(register(dest, *size), right),
+                AluOp::And => Op::And(RegOrMem::Register(register(dest, *size)), right),
+                AluOp::Or => Op::Or(RegOrMem::Register(register(dest, *size)), right),
+            }
+        }
+        X86Instruction::ImulImm { dest, src, imm } => {
+            Op::ImulImm(register(dest, Size::Long), operand(src, Size::Long), *imm)
+        }
+        X86Instruction::Shift {
+            kind, dest, amount, ..
+        } => {
+            let dest = RegOrMem::Register(register(dest, Size::Long));
+            match kind {
+                ShiftKind::Left => Op::Shl(dest, *amount as u8),
+                ShiftKind::ArithmeticRight => Op::Sar(dest, *amount as u8),
+                ShiftKind::LogicalRight => Op::Shr(dest, *amount as u8),
+            }
+        }
+        X86Instruction::Unary { op, dest, .. } => {
+            let dest = RegOrMem::Register(register(dest, Size::Long));
+            match op {
+                UnaryOp::Neg => Op::Neg(dest),
+                UnaryOp::Not => Op::Not(dest),
+            }
+        }
+        X86Instruction::Cdq => Op::Cdq,
+        X86Instruction::Idiv { divisor } => Op::Idiv(operand(divisor, Size::Long)),
+        X86Instruction::Sse {
+            op, dest, right, ..
+        } => {
+            let (dest, right) = (register(dest, Size::Quad), operand(right, Size::Quad));
+            match op {
+                SseOp::Add => Op::Addsd(dest, right),
+                SseOp::Sub => Op::Subsd(dest, right),
+                SseOp::Mul => Op::Mulsd(dest, right),
+                SseOp::Div => Op::Divsd(dest, right),
+            }
+        }
+        X86Instruction::Cvtsi2sd { dest, src } => {
+            Op::Cvtsi2sd(register(dest, Size::Quad), operand(src, Size::Long))
+        }
+        X86Instruction::Cvttsd2si { dest, src } => {
+            Op::Cvttsd2si(register(dest, Size::Long), operand(src, Size::Quad))
+        }
+        X86Instruction::Cmp { size, left, right } => {
+            Op::Cmp(operand(left, *size), operand(right, *size))
+        }
+        X86Instruction::Ucomisd { left, right } => {
+            Op::Ucomisd(register(left, Size::Quad), operand(right, Size::Quad))
+        }
+        X86Instruction::Set { condition, dest } => {
+            let byte = register(dest, Size::Byte);
+            let set = Op::Set(encoded_condition(*condition), RegOrMem::Register(byte));
+            items.push(Item::Op(set));
+            Op::Movzx(register(dest, Size::Long), RegOrMem::Register(byte))
+        }
+        X86Instruction::Jmp(target) => return items.push(Item::Jmp(label(target))),
+        X86Instruction::Jcc { condition, target } => {
+            let condition = encoded_condition(*condition);
+            return items.push(Item::Jcc(condition, label(target)));
+        }
+        X86Instruction::Label(name) => return items.push(Item::Label(label(name))),
+        X86Instruction::Loc { .. } => return,
+        X86Instruction::StoreArgument { ty, index, src } => {
+            let (address, _) = memory(&Frame::outgoing_argument(*index), Size::Quad);
+            match ty {
+                Type::Double => Op::MovsdXmm(RegOrMem::Memory(address), operand(src, Size::Quad)),
+                ty => {
+                    let address = address.with_bits(bits(Size::of(*ty)));
+                    Op::Mov(RegOrMem::Memory(address), operand(src, Size::of(*ty)))
+                }
+            }
+        }
+        X86Instruction::Call { function, .. } => return items.push(Item::Call(function.clone())),
+        X86Instruction::Ret(_) => Op::Ret,
+        X86Instruction::GotLoad { .. } | X86Instruction::Asm { .. } => {
+            unreachable!("only the system's assembler can write {:?}", instruction)
+        }
+    };
+    items.push(match symbol {
+        Some(symbol) => Item::Rip(op, symbol),
+        None => Item::Op(op),
+    });
+}
+
+/// The register `dest` was allocated, holding a value of `size`
+fn register(dest: &Dest, size: Size) -> Register {
+    match dest {
+        Dest::Register(index) => encoded_register(X86Register::from_index(*index), size),
+        Dest::Temp(temp) => unreachable!("%t{} has no register", temp),
+    }
+}
+
+/// `register` holding a value of `size`, or the xmm register itself
+fn encoded_register(register: X86Register, size: Size) -> Register {
+    const BYTES: [Register; 16] = [
+        Register::AL,
+        Register::CL,
+        Register::DL,
+        Register::BL,
+        Register::SPL,
+        Register::BPL,
+        Register::SIL,
+        Register::DIL,
+        Register::R8B,
+        Register::R9B,
+        Register::R10B,
+        Register::R11B,
+        Register::R12B,
+        Register::R13B,
+        Register::R14B,
+        Register::R15B,
+    ];
+    const LONGS: [Register; 16] = [
+        Register::EAX,
+        Register::ECX,
+        Register::EDX,
+        Register::EBX,
+        Register::ESP,
+        Register::EBP,
+        Register::ESI,
+        Register::EDI,
+        Register::R8D,
+        Register::R9D,
+        Register::R10D,
+        Register::R11D,
+        Register::R12D,
+        Register::R13D,
+        Register::R14D,
+        Register::R15D,
+    ];
+    const QUADS: [Register; 16] = [
+        Register::RAX,
+        Register::RCX,
+        Register::RDX,
+        Register::RBX,
+        Register::RSP,
+        Register::RBP,
+        Register::RSI,
+        Register::RDI,
+        Register::R8,
+        Register::R9,
+        Register::R10,
+        Register::R11,
+        Register::R12,
+        Register::R13,
+        Register::R14,
+        Register::R15,
+    ];
+    const XMM: [Register; 16] = [
+        Register::XMM0,
+        Register::XMM1,
+        Register::XMM2,
+        Register::XMM3,
+        Register::XMM4,
+        Register::XMM5,
+        Register::XMM6,
+        Register::XMM7,
+        Register::XMM8,
+        Register::XMM9,
+        Register::XMM10,
+        Register::XMM11,
+        Register::XMM12,
+        Register::XMM13,
+        Register::XMM14,
+        Register::XMM15,
+    ];
+    let index = register as usize;
+    if register.is_xmm() {
+        return XMM[index - X86Register::Xmm0 as usize];
+    }
+    match size {
+        Size::Byte => BYTES[index],
+        Size::Long => LONGS[index],
+        Size::Quad => QUADS[index],
+    }
+}
+
+fn bits(size: Size) -> u8 {
+    match size {
+        Size::Byte => 8,
+        Size::Long => 32,
+        Size::Quad => 64,
+    }
+}
+
+/// `address`, holding a value of `size`, and the symbol it's relative to, if it's one
+fn memory(address: &Address, size: Size) -> (Memory, Option<String>) {
+    let mut memory = match (&address.base, &address.index) {
+        _ if address.symbol.is_some() => Memory::rip(address.displacement),
+        (Some(base), None) => Memory::base(register(base, Size::Quad), address.displacement),
+        (base, Some((index, scale))) => Memory::indexed(
+            base.as_ref().map(|base| register(base, Size::Quad)),
+            register(index, Size::Quad),
+            *scale,
+            address.displacement,
+        ),
+        (None, None) => unreachable!("an address has a base, an index or a symbol"),
+    };
+    memory = memory.with_bits(bits(size));
+    (memory, address.symbol.clone())
+}
+
+fn encoded_condition(condition: X86Condition) -> Condition {
+    match condition {
+        X86Condition::E => Condition::E,
+        X86Condition::Ne => Condition::Ne,
+        X86Condition::L => Condition::L,
+        X86Condition::Le => Condition::Le,
+        X86Condition::G => Condition::G,
+        X86Condition::Ge => Condition::Ge,
+        X86Condition::A => Condition::A,
+        X86Condition::Ae => Condition::Ae,
+        X86Condition::P => Condition::P,
+        X86Condition::Np => Condition::Np,
+    }
+}
@@ -0,0 +1,219 @@
+//! Shell completion scripts, printed by `--completions=<shell>`.
+//!
+//! The scripts complete the command line's flags, the values of the flags that take one of a
+//! few, like `--target=` and `-f<pass>`, the codes `--explain` takes, and file names anywhere
+//! else. The passes, targets, warnings and codes are read from the compiler itself, so the
+//! scripts only need writing again when a flag is added.
+
+use crate::codegen::{pass_names, triples, MAX_OPT_LEVEL};
+use crate::diagnostic::Warning;
+use crate::explain::EXPLANATIONS;
+
+/// Name of the executable the scripts complete
+const PROGRAM: &str = "rust-compiler";
+
+/// Flags of the compiler, with what they do. Flags ending with `=` take a value typed after
+/// them; the ones with a fixed set of values are listed once for each.
+const FLAGS: &[(&str, &str)] = &[
+    ("-d", "check contracts at runtime"),
+    ("-g", "write debug information"),
+    (
+        "--reproducible",
+        "keep the build directory out of debug information",
+    ),
+    (
+        "--check-ub",
+        "run the program, stopping at undefined behavior",
+    ),
+    ("--explain", "describe an error code"),
+    ("--completions=bash", "print the bash completion script"),
+    ("--completions=zsh", "print the zsh completion script"),
+    ("--completions=fish", "print the fish completion script"),
+    ("--lib", "compile a program without main"),
+    ("-I", "directory to search for included files"),
+    (
+        "--allow-external-imports",
+        "include files outside the source directory",
+    ),
+    ("--ssa", "write functions in SSA form"),
+    ("--time-passes", "time each optimization pass"),
+    ("--stats-json", "write statistics of each function"),
+    ("--unroll-factor=", "copies of a loop body unrolling makes"),
+    ("--from-ir", "read abstract assembly"),
+    ("--dump-ir=after-all", "write the IR after each pass"),
+    ("--dump-ir-stdout", "write IR dumps to stdout"),
+    ("--dump-regalloc", "write interference graphs"),
+    ("--regalloc=graph", "allocate registers by graph coloring"),
+    ("--regalloc=linear", "allocate registers by linear scan"),
+    ("--fomit-frame-pointer", "leave the frame pointer alone"),
+    ("--red-zone", "keep leaf spill slots in the red zone"),
+    ("--pic", "write position-independent code"),
+    ("--mangle", "prefix symbols with _c0_"),
+    ("--mangle=", "prefix symbols with the given prefix"),
+    ("--zero-page=", "6502 zero page window, as first-last"),
+    ("--format=asm", "write assembly"),
+    ("--format=prg", "write a Commodore program"),
+    ("--format=nes", "write a NES ROM"),
+    ("--emit=asm", "write assembly"),
+    ("--emit=obj", "write an object file"),
+    ("--emit=llvm-ir", "write LLVM IR"),
+    ("--emit=c", "write C99"),
+    ("--incremental", "reuse unchanged functions from the cache"),
+    ("--verbose", "log what the backend does"),
+    ("--link", "link into an executable"),
+    ("-o", "path of the executable"),
+    ("--no-runtime", "link without the runtime"),
+    ("--sysroot=", "sysroot to link with"),
+    ("-Werror", "report warnings as errors"),
+    ("--error-format=human", "write diagnostics for people"),
+    ("--error-format=json", "write diagnostics as JSON"),
+    ("--color=auto", "color diagnostics on a terminal"),
+    ("--color=always", "always color diagnostics"),
+    ("--color=never", "never color diagnostics"),
+];
+
+/// Shells there are completion scripts for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    /// The shell named in `--completions=<name>`
+    pub fn from_name(name: &str) -> Option<Shell> {
+        match name {
+            "bash" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "fish" => Some(Shell::Fish),
+            _ => None,
+        }
+    }
+}
+
+/// Every flag the compiler takes, with what it does
+pub fn flags() -> Vec<(String, String)> {
+    let mut flags: Vec<(String, String)> = FLAGS
+        .iter()
+        .map(|&(flag, help)| (flag.to_string(), help.to_string()))
+        .collect();
+    for level in 0..=MAX_OPT_LEVEL {
+        flags.push((format!("-O{}", level), "optimization level".to_string()));
+    }
+    for pass in pass_names() {
+        flags.push((format!("-f{}", pass), format!("run the {} pass", pass)));
+        flags.push((format!("-fno-{}", pass), format!("skip the {} pass", pass)));
+    }
+    for triple in triples() {
+        flags.push((
+            format!("--target={}", triple),
+            "target to compile for".to_string(),
+        ));
+    }
+    for warning in Warning::ALL {
+        let name = warning.name();
+        flags.push((format!("-W{}", name), format!("warn about {}", name)));
+        flags.push((format!("-Wno-{}", name), format!("no {} warnings", name)));
+    }
+    flags
+}
+
+/// The completion script for `shell`
+pub fn script(shell: Shell) -> String {
+    let flags = flags();
+    let codes: Vec<&str> = EXPLANATIONS.iter().map(|&(code, _)| code).collect();
+    match shell {
+        Shell::Bash => bash(&flags, &codes),
+        Shell::Zsh => zsh(&flags, &codes),
+        Shell::Fish => fish(&flags, &codes),
+    }
+}
+
+/// Bash splits words at `=`, so the flag being completed is read from the line itself, and
+/// what's before its `=` is left out of the candidates
+fn bash(flags: &[(String, String)], codes: &[&str]) -> String {
+    let words: Vec<&str> = flags.iter().map(|(flag, _)| flag.as_str()).collect();
+    let function = format!("_{}", PROGRAM.replace('-', "_"));
+    format!(
+        r#"{function}() {{
+    local line="${{COMP_LINE:0:COMP_POINT}}"
+    local cur="${{line##*[[:space:]]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    case "$prev" in
+        --explain)
+            COMPREPLY=($(compgen -W "{codes}" -- "$cur"))
+            return ;;
+        -o)
+            COMPREPLY=($(compgen -f -- "$cur"))
+            return ;;
+    esac
+    if [[ $COMP_CWORD -gt 1 && "${{COMP_WORDS[1]}}" == stats-diff ]]; then
+        COMPREPLY=($(compgen -f -- "$cur"))
+    elif [[ "$cur" == -* ]]; then
+        COMPREPLY=($(compgen -W "{words}" -- "$cur"))
+        if [[ "$cur" == *=* && "$COMP_WORDBREAKS" == *=* ]]; then
+            COMPREPLY=("${{COMPREPLY[@]#"${{cur%=*}}="}}")
+        fi
+        [[ "${{COMPREPLY[0]}}" == *= ]] && compopt -o nospace
+    elif [[ $COMP_CWORD -eq 1 ]]; then
+        COMPREPLY=($(compgen -W "stats-diff" -- "$cur") $(compgen -f -- "$cur"))
+    else
+        COMPREPLY=($(compgen -f -- "$cur"))
+    fi
+}}
+complete -F {function} {program}
+"#,
+        function = function,
+        codes = codes.join(" "),
+        words = words.join(" "),
+        program = PROGRAM,
+    )
+}
+
+/// Zsh reads its flags' descriptions after a `:`, so a `:` in a flag is escaped
+fn zsh(flags: &[(String, String)], codes: &[&str]) -> String {
+    let flags: Vec<String> = flags
+        .iter()
+        .map(|(flag, help)| format!("    '{}:{}'", flag.replace(':', "\\:"), help))
+        .collect();
+    format!(
+        r#"#compdef {program}
+
+local -a flags
+flags=(
+{flags}
+)
+case "$words[CURRENT-1]" in
+    --explain) compadd -- {codes}; return ;;
+    -o) _files; return ;;
+esac
+if [[ "$words[2]" == stats-diff ]]; then
+    _files
+elif [[ "$PREFIX" == -* ]]; then
+    _describe -t flags flag flags
+else
+    (( CURRENT == 2 )) && compadd stats-diff
+    _files
+fi
+"#,
+        program = PROGRAM,
+        flags = flags.join("\n"),
+        codes = codes.join(" "),
+    )
+}
+
+/// Fish completes whole words, so a flag and its value are one candidate
+fn fish(flags: &[(String, String)], codes: &[&str]) -> String {
+    let mut script = format!(
+        "complete -c {program} -n __fish_is_first_arg -a stats-diff -d 'compare two statistics files'\n\
+         complete -c {program} -n '__fish_prev_arg_in --explain' -x -a '{codes}'\n\
+         complete -c {program} -n '__fish_prev_arg_in -o' -F\n",
+        program = PROGRAM,
+        codes = codes.join(" "),
+    );
+    for (flag, help) in flags {
+        script += &format!("complete -c {} -a '{}' -d '{}'\n", PROGRAM, flag, help);
+    }
+    script
+}
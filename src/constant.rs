@@ -0,0 +1,127 @@
+//! Compile-time evaluation of constant expressions.
+//!
+//! A constant expression is built from literals and `const` variables with operators, casts
+//! and parentheses. Semantic analysis requires one as the initializer of every `const` variable
+//! and every global, and desugaring replaces each global's initializer with its value, because
+//! globals are laid out before the program runs.
+
+use crate::lexer::Token;
+use crate::parser::{BinOp, Expr, UnOp};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Int(i32), // C0 ints are 32 bits and wrap around
+    Double(f64),
+    String(String),
+}
+
+impl Constant {
+    /// Literal with this value
+    pub fn to_literal(&self) -> Token {
+        match self {
+            Constant::Int(value) => Token::Number(*value as f64),
+            Constant::Double(value) => Token::DoubleLiteral(*value),
+            Constant::String(value) => Token::StringLiteral(value.clone()),
+        }
+    }
+
+    fn as_double(&self) -> Option<f64> {
+        match self {
+            Constant::Int(value) => Some(*value as f64),
+            Constant::Double(value) => Some(*value),
+            Constant::String(_) => None,
+        }
+    }
+}
+
+/// Value of `expr`, or None if it isn't a constant expression or can't be evaluated, like a
+/// division by zero. `lookup` gives the value of a `const` variable, and None for any other name.
+pub fn evaluate(expr: &Expr, lookup: &dyn Fn(&str) -> Option<Constant>) -> Option<Constant> {
+    match expr {
+        // An int is converted when it's combined with a double. Sema rejects one past the
+        // largest int, unless it's negated.
+        Expr::Literal(Token::Number(value)) => {
+            (*value <= i32::MAX as f64).then_some(Constant::Int(*value as i32))
+        }
+        Expr::Literal(Token::DoubleLiteral(value)) => Some(Constant::Double(*value)),
+        Expr::Literal(Token::StringLiteral(value)) => Some(Constant::String(value.clone())),
+        Expr::Literal(_) => None,
+        Expr::Variable(Token::Identifier(name)) => lookup(name),
+        Expr::Variable(_) => None,
+        Expr::Parentheses(inner) => evaluate(&inner.node, lookup),
+        Expr::Unary(UnOp::Neg, operand) if is_least_int_magnitude(&operand.node) => {
+            Some(Constant::Int(i32::MIN))
+        }
+        Expr::Unary(op, operand) => match (op, evaluate(&operand.node, lookup)?) {
+            (UnOp::Neg, Constant::Int(value)) => Some(Constant::Int(value.wrapping_neg())),
+            (UnOp::Neg, Constant::Double(value)) => Some(Constant::Double(-value)),
+            (UnOp::Not, Constant::Int(value)) => Some(Constant::Int((value == 0) as i32)),
+            (UnOp::BitNot, Constant::Int(value)) => Some(Constant::Int(!value)),
+            _ => None,
+        },
+        Expr::Binary(left, op, right) => binary(
+            evaluate(&left.node, lookup)?,
+            op,
+            evaluate(&right.node, lookup)?,
+        ),
+        Expr::Cast(type_token, operand) => {
+            let value = evaluate(&operand.node, lookup)?.as_double()?;
+            match type_token {
+                Token::Double => Some(Constant::Double(value)),
+                // Rust's conversion truncates toward zero like C0's
+                Token::Int | Token::Char => Some(Constant::Int(value as i32)),
+                _ => None,
+            }
+        }
+        Expr::Call(..)
+        | Expr::Assign(..)
+        | Expr::CompoundAssign(..)
+        | Expr::Result
+        | Expr::Old(_) => None,
+    }
+}
+
+/// Whether `expr` is the literal 2147483648, which is one past the largest int, and so only
+/// valid negated, as the least int
+pub fn is_least_int_magnitude(expr: &Expr) -> bool {
+    matches!(expr, Expr::Literal(Token::Number(value)) if *value == -(i32::MIN as f64))
+}
+
+fn binary(left: Constant, op: &BinOp, right: Constant) -> Option<Constant> {
+    if let (Constant::Int(left), Constant::Int(right)) = (&left, &right) {
+        let (left, right) = (*left, *right);
+        let value = match op {
+            BinOp::Add => left.wrapping_add(right),
+            BinOp::Sub => left.wrapping_sub(right),
+            BinOp::Mul => left.wrapping_mul(right),
+            // Division by zero and overflow are runtime errors in C0
+            BinOp::Div => left.checked_div(right)?,
+            _ => compare(op, left.partial_cmp(&right)?) as i32,
+        };
+        return Some(Constant::Int(value));
+    }
+    let (left, right) = (left.as_double()?, right.as_double()?);
+    match op {
+        BinOp::Add => Some(Constant::Double(left + right)),
+        BinOp::Sub => Some(Constant::Double(left - right)),
+        BinOp::Mul => Some(Constant::Double(left * right)),
+        BinOp::Div => Some(Constant::Double(left / right)),
+        _ => Some(Constant::Int(compare(op, left.partial_cmp(&right)?) as i32)),
+    }
+}
+
+/// Whether a comparison `op` holds for operands ordered as `ordering`
+fn compare(op: &BinOp, ordering: std::cmp::Ordering) -> bool {
+    use std::cmp::Ordering;
+    match op {
+        BinOp::Equal => ordering == Ordering::Equal,
+        BinOp::NotEqual => ordering != Ordering::Equal,
+        BinOp::Less => ordering == Ordering::Less,
+        BinOp::LessEqual => ordering != Ordering::Greater,
+        BinOp::Greater => ordering == Ordering::Greater,
+        BinOp::GreaterEqual => ordering != Ordering::Less,
+        BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div => {
+            unreachable!("arithmetic isn't a comparison")
+        }
+    }
+}
@@ -0,0 +1,317 @@
+//! Lowering of syntactic sugar into the core AST.
+//!
+//! Runs on the linked program, before semantic analysis. Afterwards the program contains no
+//! `for` loops, compound assignments, or `x++`/`x--` statements, and every `if` branch and loop
+//! body is a block. Sema, the C backend and codegen only need to handle the core constructs,
+//! and new sugar only needs a rule here. Global initializers that are constant expressions are
+//! also replaced with the literals they evaluate to.
+
+use crate::constant::{evaluate, Constant};
+use crate::lexer::Token;
+use crate::parser::{
+    BinOp, Block, Expr, FnDeclaration, LValue, PostfixOp, Program, Statement, VarDeclaration,
+};
+use crate::source_map::{Span, Spanned};
+use std::collections::HashMap;
+
+pub fn desugar(program: Program) -> Program {
+    // Values of the global constants folded so far
+    let mut constants = HashMap::new();
+    Program {
+        decl: program
+            .decl
+            .into_iter()
+            .map(|global| fold_global(desugar_var_declaration(global), &mut constants))
+            .collect(),
+        fns: program.fns.into_iter().map(desugar_function).collect(),
+        externs: program.externs,
+    }
+}
+
+fn desugar_function(function: FnDeclaration) -> FnDeclaration {
+    FnDeclaration {
+        requires: function.requires.into_iter().map(desugar_expr).collect(),
+        ensures: function.ensures.into_iter().map(desugar_expr).collect(),
+        body: desugar_block(function.body),
+        ..function
+    }
+}
+
+/// Removes every contract, for builds without dynamic checking. Asserts become empty blocks.
+pub fn strip_contracts(program: Program) -> Program {
+    Program {
+        decl: program.decl,
+        fns: program
+            .fns
+            .into_iter()
+            .map(|function| FnDeclaration {
+                requires: Vec::new(),
+                ensures: Vec::new(),
+                body: strip_block_contracts(function.body),
+                ..function
+            })
+            .collect(),
+        externs: program.externs,
+    }
+}
+
+fn strip_block_contracts(block: Block) -> Block {
+    Block {
+        statements: block
+            .statements
+            .into_iter()
+            .map(strip_statement_contracts)
+            .collect(),
+        span: block.span,
+    }
+}
+
+fn strip_statement_contracts(statement: Spanned<Statement>) -> Spanned<Statement> {
+    let span = statement.span;
+    let strip =
+        |statement: Box<Spanned<Statement>>| Box::new(strip_statement_contracts(*statement));
+    let node = match statement.node {
+        Statement::If(condition, then_branch, else_branch) => {
+            Statement::If(condition, strip(then_branch), else_branch.map(strip))
+        }
+        Statement::While(condition, _, body) => {
+            Statement::While(condition, Vec::new(), strip(body))
+        }
+        Statement::For(init, condition, step, _, body) => {
+            Statement::For(init, condition, step, Vec::new(), strip(body))
+        }
+        Statement::Block(block) => Statement::Block(strip_block_contracts(block)),
+        Statement::Assert(_) => Statement::Block(Block {
+            statements: Vec::new(),
+            span,
+        }),
+        other => other,
+    };
+    Spanned::new(node, span)
+}
+
+fn desugar_var_declaration(declaration: VarDeclaration) -> VarDeclaration {
+    VarDeclaration {
+        value: declaration.value.map(desugar_expr),
+        ..declaration
+    }
+}
+
+/// Replaces a global's initializer with its value, if it's a constant expression. Anything else
+/// is left for sema to reject.
+fn fold_global(
+    global: VarDeclaration,
+    constants: &mut HashMap<String, Constant>,
+) -> VarDeclaration {
+    let Some(value) = &global.value else {
+        return global;
+    };
+    let lookup = |name: &str| constants.get(name).cloned();
+    let constant = match evaluate(&value.node, &lookup) {
+        Some(Constant::Int(number)) if global.type_token == Token::Double => {
+            Constant::Double(number as f64)
+        }
+        Some(constant) => constant,
+        None => return global,
+    };
+    if let (true, Token::Identifier(name)) = (global.is_const, &global.identifier) {
+        constants.insert(name.clone(), constant.clone());
+    }
+    let span = value.span;
+    VarDeclaration {
+        value: Some(Spanned::new(Expr::Literal(constant.to_literal()), span)),
+        ..global
+    }
+}
+
+fn desugar_block(block: Block) -> Block {
+    Block {
+        statements: block
+            .statements
+            .into_iter()
+            .map(desugar_statement)
+            .collect(),
+        span: block.span,
+    }
+}
+
+fn desugar_statement(statement: Spanned<Statement>) -> Spanned<Statement> {
+    let span = statement.span;
+    let node = match statement.node {
+        Statement::Expression(expr) => Statement::Expression(desugar_expr(expr)),
+        Statement::VarDecl(declaration) => Statement::VarDecl(desugar_var_declaration(declaration)),
+        Statement::If(condition, then_branch, else_branch) => Statement::If(
+            Box::new(desugar_expr(*condition)),
+            Box::new(desugar_body(*then_branch)),
+            else_branch.map(|branch| Box::new(desugar_body(*branch))),
+        ),
+        Statement::While(condition, invariants, body) => Statement::While(
+            Box::new(desugar_expr(*condition)),
+            invariants.into_iter().map(desugar_expr).collect(),
+            Box::new(desugar_body(*body)),
+        ),
+        Statement::For(init, condition, step, invariants, body) => desugar_for(
+            init.map(|init| *init),
+            condition.map(|condition| *condition),
+            step.map(|step| *step),
+            invariants,
+            *body,
+            span,
+        ),
+        Statement::Postfix(target, op) => {
+            let operator = match op {
+                PostfixOp::Increment => BinOp::Add,
+                PostfixOp::Decrement => BinOp::Sub,
+            };
+            let one = Spanned::new(Expr::Literal(Token::Number(1.0)), span);
+            Statement::Expression(assign_with(target, operator, one, span))
+        }
+        Statement::Return(value) => {
+            Statement::Return(value.map(|value| Box::new(desugar_expr(*value))))
+        }
+        Statement::Block(block) => Statement::Block(desugar_block(block)),
+        Statement::Print(value) => Statement::Print(Box::new(desugar_expr(*value))),
+        Statement::PrintFormat(format, args) => {
+            Statement::PrintFormat(format, args.into_iter().map(desugar_expr).collect())
+        }
+        Statement::Scan(target) => Statement::Scan(target),
+        Statement::Break => Statement::Break,
+        Statement::Continue => Statement::Continue,
+        Statement::Assert(condition) => Statement::Assert(Box::new(desugar_expr(*condition))),
+        Statement::Check(condition) => Statement::Check(Box::new(desugar_expr(*condition))),
+        Statement::Asm(template, output, inputs, constraints) => Statement::Asm(
+            template,
+            output,
+            inputs.into_iter().map(desugar_expr).collect(),
+            constraints,
+        ),
+    };
+    Spanned::new(node, span)
+}
+
+/// Desugars an `if` branch or loop body, wrapping it in a block if it isn't one already
+fn desugar_body(statement: Spanned<Statement>) -> Spanned<Statement> {
+    let statement = desugar_statement(statement);
+    match statement.node {
+        Statement::Block(_) => statement,
+        _ => {
+            let span = statement.span;
+            let block = Block {
+                statements: vec![statement],
+                span,
+            };
+            Spanned::new(Statement::Block(block), span)
+        }
+    }
+}
+
+/// `for (init; condition; step) body` becomes `{ init; while (condition) { { body } step; } }`,
+/// keeping the loop invariants.
+/// The step must also run when the body continues, so each `continue` that belongs to this
+/// loop becomes `{ step; continue; }`. A missing condition is always true.
+fn desugar_for(
+    init: Option<Spanned<Statement>>,
+    condition: Option<Spanned<Expr>>,
+    step: Option<Spanned<Statement>>,
+    invariants: Vec<Spanned<Expr>>,
+    body: Spanned<Statement>,
+    span: Span,
+) -> Statement {
+    let init = init.map(desugar_statement);
+    let condition = condition
+        .map(desugar_expr)
+        .unwrap_or_else(|| Spanned::new(Expr::Literal(Token::Number(1.0)), span));
+    let step = step.map(desugar_statement);
+
+    let mut body = desugar_body(body);
+    if let Some(step) = &step {
+        step_before_continue(&mut body, step);
+    }
+
+    let mut loop_statements = vec![body];
+    loop_statements.extend(step);
+    let loop_body = Block {
+        statements: loop_statements,
+        span,
+    };
+    let while_loop = Statement::While(
+        Box::new(condition),
+        invariants.into_iter().map(desugar_expr).collect(),
+        Box::new(Spanned::new(Statement::Block(loop_body), span)),
+    );
+
+    // The outer block scopes a variable declared by `init` to the loop
+    let mut statements: Vec<Spanned<Statement>> = init.into_iter().collect();
+    statements.push(Spanned::new(while_loop, span));
+    Statement::Block(Block { statements, span })
+}
+
+/// Replaces each `continue` in `statement` that targets the enclosing loop with
+/// `{ step; continue; }`. Nested loops own their `continue`s and are left alone.
+fn step_before_continue(statement: &mut Spanned<Statement>, step: &Spanned<Statement>) {
+    match &mut statement.node {
+        Statement::Continue => {
+            let block = Block {
+                statements: vec![
+                    step.clone(),
+                    Spanned::new(Statement::Continue, statement.span),
+                ],
+                span: statement.span,
+            };
+            statement.node = Statement::Block(block);
+        }
+        Statement::Block(block) => {
+            for statement in &mut block.statements {
+                step_before_continue(statement, step);
+            }
+        }
+        Statement::If(_, then_branch, else_branch) => {
+            step_before_continue(then_branch, step);
+            if let Some(else_branch) = else_branch {
+                step_before_continue(else_branch, step);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn desugar_expr(expr: Spanned<Expr>) -> Spanned<Expr> {
+    let span = expr.span;
+    let node = match expr.node {
+        Expr::Literal(token) => Expr::Literal(token),
+        Expr::Unary(op, operand) => Expr::Unary(op, Box::new(desugar_expr(*operand))),
+        Expr::Binary(left, op, right) => Expr::Binary(
+            Box::new(desugar_expr(*left)),
+            op,
+            Box::new(desugar_expr(*right)),
+        ),
+        Expr::Parentheses(inner) => Expr::Parentheses(Box::new(desugar_expr(*inner))),
+        Expr::Variable(token) => Expr::Variable(token),
+        Expr::Call(callee, args) => Expr::Call(
+            Box::new(desugar_expr(*callee)),
+            args.into_iter().map(desugar_expr).collect(),
+        ),
+        Expr::Cast(type_token, operand) => Expr::Cast(type_token, Box::new(desugar_expr(*operand))),
+        Expr::Assign(target, value) => Expr::Assign(target, Box::new(desugar_expr(*value))),
+        Expr::CompoundAssign(target, op, value) => {
+            return assign_with(target, op, desugar_expr(*value), span);
+        }
+        Expr::Result => Expr::Result,
+        Expr::Old(inner) => Expr::Old(Box::new(desugar_expr(*inner))),
+    };
+    Spanned::new(node, span)
+}
+
+/// `target = target <operator> value`. Targets are plain variables, so reading one twice is safe.
+fn assign_with(target: LValue, operator: BinOp, value: Spanned<Expr>, span: Span) -> Spanned<Expr> {
+    let current = match &target {
+        LValue::Variable(name) => Expr::Variable(name.clone()),
+    };
+    let updated = Expr::Binary(
+        Box::new(Spanned::new(current, span)),
+        operator,
+        Box::new(value),
+    );
+    let assign = Expr::Assign(target, Box::new(Spanned::new(updated, span)));
+    Spanned::new(assign, span)
+}
@@ -0,0 +1,198 @@
+//! Errors and warnings reported to the user.
+//!
+//! Every phase's problems are turned into `Diagnostic`s and collected in one `DiagnosticSink`,
+//! which applies the command line's warning settings: each warning can be turned on or off by
+//! name, and `-Werror` promotes the warnings that are on to errors. Spans are offsets into the
+//! linked program; `render` turns them into file names, lines and columns, and shows the
+//! source lines they point at, given the program's source files.
+
+pub mod render;
+
+use crate::source_map::Span;
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note, // extra information that isn't a problem of its own
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// Warnings that can be turned on and off from the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Warning {
+    UnusedVariable,
+    UnusedParameter,
+}
+
+impl Warning {
+    pub const ALL: [Warning; 2] = [Warning::UnusedVariable, Warning::UnusedParameter];
+
+    /// Name used in `-W<name>` and `-Wno-<name>`
+    pub fn name(self) -> &'static str {
+        match self {
+            Warning::UnusedVariable => "unused-variable",
+            Warning::UnusedParameter => "unused-parameter",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Warning> {
+        Warning::ALL
+            .into_iter()
+            .find(|warning| warning.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Option<&'static str>, // like `E0102`, explained by `--explain`
+    pub message: String,
+    pub span: Option<Span>,    // where the problem is, if it's in one place
+    pub label: Option<String>, // short description shown under the span
+    pub fix: Option<(Span, String)>, // text to replace a span with, to fix the problem
+    pub notes: Vec<(Span, String)>, // other places that explain it
+    pub warning: Option<Warning>, // which warning this is, even once promoted to an error
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Option<Span>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code: None,
+            message: message.into(),
+            span,
+            label: None,
+            fix: None,
+            notes: Vec::new(),
+            warning: None,
+        }
+    }
+
+    pub fn warning(warning: Warning, message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            code: None,
+            message: message.into(),
+            span: Some(span),
+            label: None,
+            fix: None,
+            notes: Vec::new(),
+            warning: Some(warning),
+        }
+    }
+
+    pub fn note(message: impl Into<String>, span: Option<Span>) -> Self {
+        Diagnostic {
+            severity: Severity::Note,
+            ..Diagnostic::error(message, span)
+        }
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Suggests replacing `span` with `text`; an empty span inserts it
+    pub fn with_fix(mut self, span: Span, text: impl Into<String>) -> Self {
+        self.fix = Some((span, text.into()));
+        self
+    }
+
+    pub fn with_note(mut self, span: Span, note: impl Into<String>) -> Self {
+        self.notes.push((span, note.into()));
+        self
+    }
+}
+
+/// Which warnings are reported, and how
+#[derive(Debug, Default)]
+pub struct WarningOptions {
+    disabled: HashSet<Warning>,
+    /// Report warnings as errors (`-Werror`)
+    pub as_errors: bool,
+}
+
+impl WarningOptions {
+    pub fn enable(&mut self, warning: Warning) {
+        self.disabled.remove(&warning);
+    }
+
+    pub fn disable(&mut self, warning: Warning) {
+        self.disabled.insert(warning);
+    }
+
+    pub fn is_enabled(&self, warning: Warning) -> bool {
+        !self.disabled.contains(&warning)
+    }
+}
+
+/// Collects the diagnostics of a whole compilation
+#[derive(Debug, Default)]
+pub struct DiagnosticSink {
+    options: WarningOptions,
+    diagnostics: Vec<Diagnostic>,
+    omitted_errors: usize,
+}
+
+impl DiagnosticSink {
+    pub fn new(options: WarningOptions) -> Self {
+        DiagnosticSink {
+            options,
+            diagnostics: Vec::new(),
+            omitted_errors: 0,
+        }
+    }
+
+    /// Adds `diagnostic`, unless it's a warning that's turned off
+    pub fn report(&mut self, mut diagnostic: Diagnostic) {
+        if let Some(warning) = diagnostic.warning {
+            if !self.options.is_enabled(warning) {
+                return;
+            }
+            if self.options.as_errors {
+                diagnostic.severity = Severity::Error;
+            }
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// Counts `count` errors that were found but left out of the report
+    pub fn omit_errors(&mut self, count: usize) {
+        self.omitted_errors += count;
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error)
+            .count()
+            + self.omitted_errors
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.error_count() > 0
+    }
+
+    /// Everything reported so far, in order
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+}
@@ -0,0 +1,200 @@
+//! Turns diagnostics into the text shown to the user: either lines naming the file, line and
+//! column with the source lines they point at, or one JSON object per diagnostic.
+
+use super::{Diagnostic, Severity};
+use crate::json::json_string;
+use crate::preprocessor::Preprocessed;
+use crate::source_map::Span;
+
+/// One preprocessed file of the program. Spans in the linked program are offsets into all the
+/// files laid end to end, so each file's spans start at its `base`.
+pub struct SourceFile {
+    pub base: usize,
+    pub preprocessed: Preprocessed,
+}
+
+/// Where the next file's spans start. A gap is left so a span ending a file doesn't touch the
+/// next one.
+pub fn next_base(sources: &[SourceFile]) -> usize {
+    sources
+        .last()
+        .map(|last| last.base + last.preprocessed.source().len() + 1)
+        .unwrap_or(0)
+}
+
+/// ANSI escape codes for coloring diagnostics, which are all empty when they aren't colored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    error: &'static str,
+    warning: &'static str,
+    bold: &'static str,
+    reset: &'static str,
+}
+
+impl Palette {
+    pub const PLAIN: Palette = Palette {
+        error: "",
+        warning: "",
+        bold: "",
+        reset: "",
+    };
+    pub const ANSI: Palette = Palette {
+        error: "\x1b[1;31m",
+        warning: "\x1b[1;33m",
+        bold: "\x1b[1m",
+        reset: "\x1b[0m",
+    };
+
+    fn severity(&self, severity: Severity) -> &'static str {
+        match severity {
+            Severity::Error => self.error,
+            Severity::Warning => self.warning,
+            Severity::Note => self.bold,
+        }
+    }
+}
+
+/// Formats `diagnostic` as `file:line:column: severity[code]: message`, followed by the source line
+/// it points at and then its notes, which point at lines of their own
+pub fn render(sources: &[SourceFile], diagnostic: &Diagnostic, palette: Palette) -> String {
+    let color = palette.severity(diagnostic.severity);
+    // Like `error[E0102]`
+    let severity = match diagnostic.code {
+        Some(code) => format!(
+            "{}{}[{}]{}",
+            color, diagnostic.severity, code, palette.reset
+        ),
+        None => format!("{}{}{}", color, diagnostic.severity, palette.reset),
+    };
+    let Some(span) = diagnostic.span else {
+        return format!("{}: {}", severity, diagnostic.message);
+    };
+    let label = diagnostic.label.as_deref().unwrap_or("");
+    let mut text = format!(
+        "{}: {}: {}\n{}",
+        locate(sources, span.start),
+        severity,
+        diagnostic.message,
+        snippet(sources, span, label, color, palette)
+    );
+    for (span, note) in &diagnostic.notes {
+        text.push_str(&format!(
+            "\n{}: {}note{}: {}\n{}",
+            locate(sources, span.start),
+            palette.bold,
+            palette.reset,
+            note,
+            snippet(sources, *span, "", palette.bold, palette)
+        ));
+    }
+    text
+}
+
+/// The source line `span` starts on, with the span underlined in `color` and then `label`:
+///
+/// ```text
+///   |
+/// 2 |     return x;
+///   |            ^ label
+/// ```
+fn snippet(
+    sources: &[SourceFile],
+    span: Span,
+    label: &str,
+    color: &str,
+    palette: Palette,
+) -> String {
+    let (source, offset) = source_at(sources, span.start);
+    let (file, offset) = source.preprocessed.origin(offset);
+    let underline = file.underline(offset, span.end - span.start);
+    let gutter = " ".repeat(underline.line.to_string().len());
+    let text = format!(
+        "{gutter} |\n{} | {}\n{gutter} | {}{color}{}{} {label}",
+        underline.line,
+        underline.text,
+        underline.indent,
+        "^".repeat(underline.width),
+        palette.reset,
+    );
+    text.trim_end().to_string()
+}
+
+/// Formats `diagnostic` as one line of JSON, like
+/// `{"severity":"error","code":"E0102","message":"...","span":{"file":"a.c0",...},...}`.
+/// `code`, `span`, `label`, `warning` and `fix` are null when the diagnostic doesn't have them.
+pub fn render_json(sources: &[SourceFile], diagnostic: &Diagnostic) -> String {
+    let optional = |value: Option<&str>| value.map(json_string).unwrap_or("null".to_string());
+    let notes: Vec<String> = diagnostic
+        .notes
+        .iter()
+        .map(|(span, note)| {
+            format!(
+                "{{\"message\":{},\"span\":{}}}",
+                json_string(note),
+                json_span(sources, *span)
+            )
+        })
+        .collect();
+    let fix = match &diagnostic.fix {
+        Some((span, text)) => format!(
+            "{{\"span\":{},\"replacement\":{}}}",
+            json_span(sources, *span),
+            json_string(text)
+        ),
+        None => "null".to_string(),
+    };
+    format!(
+        "{{\"severity\":{},\"code\":{},\"message\":{},\"span\":{},\"label\":{},\"warning\":{},\"fix\":{},\"notes\":[{}]}}",
+        json_string(&diagnostic.severity.to_string()),
+        optional(diagnostic.code),
+        json_string(&diagnostic.message),
+        diagnostic
+            .span
+            .map(|span| json_span(sources, span))
+            .unwrap_or("null".to_string()),
+        optional(diagnostic.label.as_deref()),
+        optional(diagnostic.warning.map(|warning| warning.name())),
+        fix,
+        notes.join(",")
+    )
+}
+
+/// `span` as a JSON object with the file it's in, its byte range in that file and the line and
+/// column it starts at
+fn json_span(sources: &[SourceFile], span: Span) -> String {
+    let (source, offset) = source_at(sources, span.start);
+    let (file, start) = source.preprocessed.origin(offset);
+    let end = (start + span.end - span.start).min(file.source().len());
+    let (line, column) = file.line_col(start);
+    format!(
+        "{{\"file\":{},\"start\":{},\"end\":{},\"line\":{},\"column\":{}}}",
+        json_string(file.name()),
+        start,
+        end,
+        line,
+        column
+    )
+}
+
+/// `file:line:column` of an offset into the linked program
+fn locate(sources: &[SourceFile], offset: usize) -> String {
+    let (source, offset) = source_at(sources, offset);
+    location(&source.preprocessed, offset)
+}
+
+/// The file an offset into the linked program is in, and the offset into that file
+fn source_at(sources: &[SourceFile], offset: usize) -> (&SourceFile, usize) {
+    let index = sources
+        .partition_point(|source| source.base <= offset)
+        .saturating_sub(1);
+    let source = &sources[index];
+    (source, offset - source.base)
+}
+
+/// `file:line:column` of an offset into one preprocessed file
+fn location(preprocessed: &Preprocessed, offset: usize) -> String {
+    // The offset may be in an included file
+    let (file, offset) = preprocessed.origin(offset);
+    let (line, column) = file.line_col(offset);
+    format!("{}:{}:{}", file.name(), line, column)
+}
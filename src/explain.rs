@@ -0,0 +1,457 @@
+//! Longer descriptions of the error codes, shown by `--explain`.
+//!
+//! Codes are grouped by the phase reporting them: `E00xx` for the preprocessor, lexer and
+//! parser, `E01xx` for semantic analysis, `E02xx` for linking, `E03xx` for code generation and
+//! `E04xx` for what `--check-ub` finds running the program. A code is never reused for another
+//! error.
+
+/// Every error code, with its description and an example that reports it
+pub const EXPLANATIONS: &[(&str, &str)] = &[
+    (
+        "E0001",
+        "The parser found a token that can't appear at this point of the program.
+
+Erroneous example:
+
+    int main() {
+        else return 1;
+    }
+
+The error lists the tokens that could have come instead. It's often caused by a
+missing `;`, `)` or `}` just before the token.",
+    ),
+    (
+        "E0002",
+        "The file ended in the middle of a declaration or statement.
+
+Erroneous example:
+
+    int main() {
+        return 0;
+
+Check that every `{` and `(` is closed.",
+    ),
+    (
+        "E0003",
+        "An expression was expected, but what follows can't start one.
+
+Erroneous example:
+
+    int x = ;",
+    ),
+    (
+        "E0004",
+        "Only variables can be assigned to, incremented or decremented.
+
+Erroneous example:
+
+    int main() {
+        int x = 1;
+        x + 1 = 2;
+        return x;
+    }",
+    ),
+    (
+        "E0005",
+        "A format string passed to `print` is malformed, or doesn't have one argument for
+each conversion.
+
+Erroneous example:
+
+    print(\"%d and %d\\n\", 1);
+
+The supported conversions are `%d`, `%f`, `%c` and `%s`, and `%%` prints a `%`.",
+    ),
+    (
+        "E0006",
+        "`\\result` and `\\old` only have a meaning in a postcondition.
+
+Erroneous example:
+
+    //@requires \\result > 0;
+    int f() { return 1; }
+
+Use them in `//@ensures` instead.",
+    ),
+    (
+        "E0007",
+        "The source contains characters that don't start any token.
+
+Erroneous example:
+
+    int x = 1 @ 2;",
+    ),
+    (
+        "E0008",
+        "A `;` or `)` seems to be missing, because what follows starts something new.
+
+Erroneous example:
+
+    int main() {
+        int x = 1
+        return x;
+    }
+
+The error points at where the token goes. Parsing goes on as if it were there, so
+later errors are still reported.",
+    ),
+    (
+        "E0009",
+        "An `asm` statement has an operand constraint other than `\"=r\"` for its output
+or `\"r\"` for an input, or a register's letter after those, two inputs
+constrained to the same register, or its template refers to an operand it
+doesn't have.
+
+Erroneous example:
+
+    asm(\"movl %1, %0\" : \"=m\"(x) : \"r\"(y));
+
+Each operand is in a register, numbered from `%0` for the output. A constraint
+like `\"=a\"` or `\"c\"` names the register: `a`, `b`, `c`, `d`, `S` or `D`
+for %rax, %rbx, %rcx, %rdx, %rsi or %rdi.",
+    ),
+    (
+        "E0010",
+        "A preprocessor directive can't be carried out: it's unknown or malformed, the file
+it includes can't be found, is outside the project root or includes itself, or an
+`#ifdef` isn't closed with `#endif`.
+
+Erroneous example:
+
+    #ifdef DEBUG
+    int main() {
+        return 0;
+    }
+
+An included file is looked for next to the including file, then in each `-I`
+directory. Pass `--allow-external-imports` to include files outside the project.",
+    ),
+    (
+        "E0101",
+        "A function is called, but no function with that name is defined.
+
+Erroneous example:
+
+    int main() {
+        return helper();
+    }
+
+Define the function, in this file or another file of the program. A function defined
+outside the program, like `putchar` in the C library, is declared `extern` instead:
+
+    extern int putchar(int c);",
+    ),
+    (
+        "E0102",
+        "A variable is used, but no variable with that name is in scope.
+
+Erroneous example:
+
+    int main() {
+        {
+            int x = 1;
+        }
+        return x;
+    }
+
+A variable is only visible in the block it's declared in, after its declaration.",
+    ),
+    (
+        "E0103",
+        "The same name is defined twice in one scope.
+
+Erroneous example:
+
+    int main() {
+        int x = 1;
+        int x = 2;
+        return x;
+    }
+
+Globals and functions share one namespace. A local may hide a global or a local of
+an enclosing block, but not one in its own block.",
+    ),
+    (
+        "E0104",
+        "A value has a different type than expected.
+
+Erroneous example:
+
+    int main() {
+        double x = 1.5;
+        int y = x;
+        return y;
+    }
+
+An int is converted to a double implicitly, but a double only becomes an int
+through a cast, like `(int) x`.",
+    ),
+    (
+        "E0105",
+        "An operator is applied to a type it doesn't support.
+
+Erroneous example:
+
+    int main() {
+        string s = \"a\";
+        return -s;
+    }",
+    ),
+    (
+        "E0106",
+        "A function is called with the wrong number of arguments.
+
+Erroneous example:
+
+    int add(int a, int b) { return a + b; }
+    int main() { return add(1); }",
+    ),
+    (
+        "E0107",
+        "A constant is assigned to after its declaration.
+
+Erroneous example:
+
+    const int LIMIT = 10;
+    int main() {
+        LIMIT = 20;
+        return LIMIT;
+    }",
+    ),
+    (
+        "E0108",
+        "A variable or parameter is declared with type `void`, which has no values.
+
+Erroneous example:
+
+    void nothing;",
+    ),
+    (
+        "E0109",
+        "A declaration uses a type the compiler doesn't support yet.
+
+Erroneous example:
+
+    struct point p;",
+    ),
+    (
+        "E0110",
+        "Constants and globals must be initialized with constant expressions, built from
+literals, earlier constants, operators and casts.
+
+Erroneous example:
+
+    int main() {
+        int x = 1;
+        const int COPY = x;
+        return COPY;
+    }
+
+A division by zero isn't a constant expression either.",
+    ),
+    (
+        "E0111",
+        "A constant is declared without a value.
+
+Erroneous example:
+
+    const int LIMIT;",
+    ),
+    (
+        "E0112",
+        "`return;` is used in a function that returns a value.
+
+Erroneous example:
+
+    int f() {
+        return;
+    }",
+    ),
+    (
+        "E0113",
+        "A `void` function returns a value.
+
+Erroneous example:
+
+    void f() {
+        return 1;
+    }",
+    ),
+    (
+        "E0114",
+        "`\\result` is used in the contract of a `void` function, which has no result.
+
+Erroneous example:
+
+    //@ensures \\result > 0;
+    void f() {}",
+    ),
+    (
+        "E0115",
+        "The program starts at `main`, which must be declared as a non-static `int main()`.
+
+Erroneous example:
+
+    void main() {}
+
+The value `main` returns is the program's exit code.",
+    ),
+    (
+        "E0116",
+        "`break` or `continue` is used outside of a loop.
+
+Erroneous example:
+
+    int main() {
+        break;
+        return 0;
+    }",
+    ),
+    (
+        "E0117",
+        "A global's initializer reads a global defined after it. Globals are initialized in
+order, so an initializer only sees the constants before it.
+
+Erroneous example:
+
+    const int A = B + 1;
+    const int B = 2;
+
+Move the definition of `B` before `A`.",
+    ),
+    (
+        "E0118",
+        "A global's initializer calls a function. Globals are initialized before the program
+runs, so no function can run yet.
+
+Erroneous example:
+
+    int f() { return 1; }
+    int x = f();
+
+Initialize the global in `main` instead.",
+    ),
+    (
+        "E0119",
+        "Global initializers read each other, so none of them can be computed first.
+
+Erroneous example:
+
+    const int A = B;
+    const int B = A;",
+    ),
+    (
+        "E0120",
+        "A local declared without a value is read, but some path to the read doesn't assign it.
+
+Erroneous example:
+
+    int x;
+    if (n > 0) {
+        x = n;
+    }
+    return x;
+
+Assign the variable on every path, or give it a value where it's declared.",
+    ),
+    (
+        "E0121",
+        "A function that returns a value has a path that reaches the end of its body
+without a `return`, so what the caller gets is undefined.
+
+Erroneous example:
+
+    int sign(int n) {
+        if (n > 0) {
+            return 1;
+        }
+    }
+
+Return a value on every path, for instance with a `return` at the end.",
+    ),
+    (
+        "E0122",
+        "An int literal is larger than the largest int, 2147483647, so it can't be
+represented. The least int, -2147483648, can still be written, as the negation
+of 2147483648, which is the only place that literal is accepted.
+
+Erroneous example:
+
+    int main() {
+        return 2147483648;
+    }
+
+Write a value that fits in an int, or a double literal, like `2147483648.0`.",
+    ),
+    (
+        "E0201",
+        "Two files of the program define the same non-static function or global.
+
+Declare one of them `static` to keep it private to its file, or rename it.",
+    ),
+    (
+        "E0202",
+        "A file calls a function that isn't defined in any file of the program, or that's
+`static` in another file.
+
+Check that every file the program needs is passed to the compiler, and that a file calling
+a function defined outside the program declares it `extern`.",
+    ),
+    (
+        "E0203",
+        "The system's linker couldn't link the program into an executable, for `--link`.
+
+Erroneous example, without the files defining the functions `print` calls:
+
+    int main() {
+        print(\"%d\\n\", 1);
+        return 0;
+    }
+
+Pass the files defining them along with the program, like `runtime.c` or
+`runtime.o`. Set `CC` to link with a C compiler other than `cc`, `gcc` or
+`clang`, and `--sysroot=<dir>` to look for libraries in another root.",
+    ),
+    (
+        "E0204",
+        "A file declares a function `extern` with another return type or other parameter
+types than another file defines it with, or declares it with.
+
+Erroneous example, with `a.c0` declaring
+
+    extern int scale(int x);
+
+and `b.c0` defining
+
+    int scale(double x) {
+        return (int) (x * 2.0);
+    }
+
+Make the declaration match the definition.",
+    ),
+    (
+        "E0401",
+        "The program, run with `--check-ub`, did something whose result C0 doesn't define,
+which stops it when compiled. These are dividing an int by zero, dividing the smallest int
+by -1, whose quotient doesn't fit, and converting a double to an int when it's NaN or out of
+the range of int.
+
+Erroneous example, for an input of 0:
+
+    int main() {
+        int n;
+        scan(n);
+        return 100 / n;
+    }
+
+Check the divisor before dividing.",
+    ),
+];
+
+/// Description of `code`, like `E0102`
+pub fn explain(code: &str) -> Option<&'static str> {
+    EXPLANATIONS
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map(|(_, explanation)| *explanation)
+}
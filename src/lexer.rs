@@ -1,3 +1,6 @@
+use crate::diagnostic::Diagnostic;
+use crate::source_map::{Span, Spanned};
+use std::fmt;
 use std::fs::File;
 use std::io::{BufReader, Read};
 
@@ -6,7 +9,8 @@ pub enum Token {
     // Literals
     Identifier(String),
     StringLiteral(String),
-    Number(f64),
+    Number(f64),        // An int, written without a decimal point
+    DoubleLiteral(f64), // Written with one, like `1.0` or `2.`
 
     // Single-character tokens
     LeftParen,
@@ -15,6 +19,7 @@ pub enum Token {
     RightBrace,
     Dot,
     Comma,
+    Colon,
     Semicolon,
     Plus,
     Minus,
@@ -31,14 +36,23 @@ pub enum Token {
     GreaterEqual,
     Bang,
     BangEqual,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    PlusPlus,
+    MinusMinus,
 
     // Reserved Keywords
     Const,
+    Static,
+    Extern,
     Void,
     Int,
     Char,
     Double,
     Struct,
+    String,
     If,
     Else,
     Switch,
@@ -52,11 +66,46 @@ pub enum Token {
     Continue,
     Print,
     Scan,
+    Asm,
+    // `assert` outside annotations, which starts a statement checked even without `-d`
+    AssertStatement,
+
+    // Contract keywords, only recognized inside `//@` annotations
+    Requires,
+    Ensures,
+    LoopInvariant,
+    Assert,
+    Result, // `\result`
+    Old,    // `\old`
+
+    // Characters that don't start any token, kept so the parser can see where they were
+    Error(String),
 
     // EOF
     Eof,
 }
 
+/// Lexers stop reporting after this many errors; later ones are usually noise
+pub const MAX_REPORTED_ERRORS: usize = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexerError {
+    pub text: String,
+    pub span: Span,
+}
+
+impl fmt::Display for LexerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unexpected character(s): '{}'", self.text)
+    }
+}
+
+impl From<&LexerError> for Diagnostic {
+    fn from(error: &LexerError) -> Self {
+        Diagnostic::error(error.to_string(), Some(error.span)).with_code("E0007")
+    }
+}
+
 pub fn tokenize(file: File) -> Vec<Token> {
     let mut reader = BufReader::new(file);
     let mut contents = String::new();
@@ -67,40 +116,53 @@ pub fn tokenize(file: File) -> Vec<Token> {
 }
 
 pub fn tokenize_from_string(contents: &str) -> Vec<Token> {
+    tokenize_with_spans(contents)
+        .into_iter()
+        .map(|token| token.node)
+        .collect()
+}
+
+/// Tokenizes `contents`, recording the byte range each token came from
+pub fn tokenize_with_spans(contents: &str) -> Vec<Spanned<Token>> {
     let mut tokens = vec![];
-    let mut chars = contents.chars().peekable();
-    let mut current = String::new();
-    let mut in_string = false;
+    let bytes = contents.as_bytes();
+    let mut pos = 0;
+    // Offset where the current `//@` annotation line ends, if we're in one
+    let mut annotation_end = 0;
 
-    while let Some(c) = chars.next() {
-        if in_string {
-            if c == '"' {
-                tokens.push(Token::StringLiteral(current.clone()));
-                current.clear();
-                in_string = false;
-            } else {
-                current.push(c);
-            }
-            continue;
-        }
+    loop {
+        pos = skip_trivia(bytes, pos);
+        let Some(c) = contents[pos..].chars().next() else {
+            break;
+        };
+        let start = pos;
+        pos += c.len_utf8();
+        // Peeks at the byte after the current character
+        let next_is = |pos: usize, expected: u8| bytes.get(pos) == Some(&expected);
 
-        match c {
-            'a'..='z' | 'A'..='Z' => {
-                current.push(c);
-                while let Some(&next) = chars.peek() {
-                    if next.is_alphanumeric() || next == '_' {
-                        current.push(chars.next().unwrap());
-                    } else {
-                        break;
-                    }
+        let token = match c {
+            'a'..='z' | 'A'..='Z' | '_' => {
+                while pos < bytes.len()
+                    && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'_')
+                {
+                    pos += 1;
                 }
-                tokens.push(match current.as_str() {
+                let in_annotation = start < annotation_end;
+                match &contents[start..pos] {
+                    "requires" if in_annotation => Token::Requires,
+                    "ensures" if in_annotation => Token::Ensures,
+                    "loop_invariant" if in_annotation => Token::LoopInvariant,
+                    "assert" if in_annotation => Token::Assert,
+                    "assert" => Token::AssertStatement,
                     "const" => Token::Const,
+                    "static" => Token::Static,
+                    "extern" => Token::Extern,
                     "void" => Token::Void,
                     "int" => Token::Int,
                     "char" => Token::Char,
                     "double" => Token::Double,
                     "struct" => Token::Struct,
+                    "string" => Token::String,
                     "if" => Token::If,
                     "else" => Token::Else,
                     "switch" => Token::Switch,
@@ -114,73 +176,298 @@ pub fn tokenize_from_string(contents: &str) -> Vec<Token> {
                     "continue" => Token::Continue,
                     "print" => Token::Print,
                     "scan" => Token::Scan,
-                    _ => Token::Identifier(current.clone()),
-                });
-                current.clear();
+                    "asm" => Token::Asm,
+                    identifier => Token::Identifier(identifier.to_string()),
+                }
             }
             '0'..='9' => {
-                current.push(c);
-                while let Some(&next) = chars.peek() {
-                    if next.is_digit(10) || next == '.' {
-                        current.push(chars.next().unwrap());
-                    } else {
-                        break;
-                    }
+                // A second decimal point ends the literal, and starts the next token
+                let mut point = false;
+                while pos < bytes.len()
+                    && (bytes[pos].is_ascii_digit() || (bytes[pos] == b'.' && !point))
+                {
+                    point |= bytes[pos] == b'.';
+                    pos += 1;
+                }
+                let literal = &contents[start..pos];
+                match literal.parse::<f64>() {
+                    Ok(value) if point => Token::DoubleLiteral(value),
+                    Ok(value) => Token::Number(value),
+                    Err(_) => Token::Error(literal.to_string()),
                 }
-                tokens.push(Token::Number(current.parse::<f64>().unwrap()));
-                current.clear();
-            }
-            '"' => in_string = true,
-            '(' => tokens.push(Token::LeftParen),
-            ')' => tokens.push(Token::RightParen),
-            '{' => tokens.push(Token::LeftBrace),
-            '}' => tokens.push(Token::RightBrace),
-            '.' => tokens.push(Token::Dot),
-            ',' => tokens.push(Token::Comma),
-            ';' => tokens.push(Token::Semicolon),
-            '+' => tokens.push(Token::Plus),
-            '-' => tokens.push(Token::Minus),
-            '*' => tokens.push(Token::Star),
-            '/' => tokens.push(Token::Slash),
-            '~' => tokens.push(Token::Tilde),
+            }
+            '\\' if start < annotation_end => {
+                while pos < bytes.len() && bytes[pos].is_ascii_alphanumeric() {
+                    pos += 1;
+                }
+                match &contents[start..pos] {
+                    "\\result" => Token::Result,
+                    "\\old" => Token::Old,
+                    other => Token::Error(other.to_string()),
+                }
+            }
+            '"' => {
+                let (literal, end) = string_literal(contents, pos);
+                pos = end;
+                Token::StringLiteral(literal)
+            }
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '{' => Token::LeftBrace,
+            '}' => Token::RightBrace,
+            '.' => Token::Dot,
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            ';' => Token::Semicolon,
+            '+' => {
+                if next_is(pos, b'=') {
+                    pos += 1;
+                    Token::PlusEqual
+                } else if next_is(pos, b'+') {
+                    pos += 1;
+                    Token::PlusPlus
+                } else {
+                    Token::Plus
+                }
+            }
+            '-' => {
+                if next_is(pos, b'=') {
+                    pos += 1;
+                    Token::MinusEqual
+                } else if next_is(pos, b'-') {
+                    pos += 1;
+                    Token::MinusMinus
+                } else {
+                    Token::Minus
+                }
+            }
+            '*' => {
+                if next_is(pos, b'=') {
+                    pos += 1;
+                    Token::StarEqual
+                } else {
+                    Token::Star
+                }
+            }
+            // `skip_trivia` leaves `//` in place only when it starts an annotation. The
+            // annotation's contents are ordinary tokens, so just step over the marker.
+            '/' if next_is(pos, b'/') => {
+                pos += 2;
+                annotation_end = find_byte(b'\n', &bytes[pos..])
+                    .map(|i| pos + i)
+                    .unwrap_or(bytes.len());
+                continue;
+            }
+            '/' => {
+                if next_is(pos, b'=') {
+                    pos += 1;
+                    Token::SlashEqual
+                } else {
+                    Token::Slash
+                }
+            }
+            '~' => Token::Tilde,
             '<' => {
-                if let Some('=') = chars.peek() {
-                    chars.next();
-                    tokens.push(Token::LessEqual);
+                if next_is(pos, b'=') {
+                    pos += 1;
+                    Token::LessEqual
                 } else {
-                    tokens.push(Token::Less);
+                    Token::Less
                 }
             }
             '>' => {
-                if let Some('=') = chars.peek() {
-                    chars.next();
-                    tokens.push(Token::GreaterEqual);
+                if next_is(pos, b'=') {
+                    pos += 1;
+                    Token::GreaterEqual
                 } else {
-                    tokens.push(Token::Greater);
+                    Token::Greater
                 }
             }
             '=' => {
-                if let Some('=') = chars.peek() {
-                    chars.next();
-                    tokens.push(Token::EqualEqual);
+                if next_is(pos, b'=') {
+                    pos += 1;
+                    Token::EqualEqual
                 } else {
-                    tokens.push(Token::Equal);
+                    Token::Equal
                 }
             }
             '!' => {
-                if let Some('=') = chars.peek() {
-                    chars.next();
-                    tokens.push(Token::BangEqual);
+                if next_is(pos, b'=') {
+                    pos += 1;
+                    Token::BangEqual
                 } else {
-                    tokens.push(Token::Bang);
+                    Token::Bang
                 }
             }
-            ' ' | '\t' | '\r' | '\n' => {} // Ignore whitespace
             _ => {
-                eprintln!("Unexpected character: {}", c);
+                // Recover at the next whitespace or delimiter, so that one bad character
+                // becomes one error token instead of a cascade of confusing ones
+                while pos < bytes.len() && !is_whitespace(bytes[pos]) && !is_delimiter(bytes[pos]) {
+                    pos += contents[pos..].chars().next().unwrap().len_utf8();
+                }
+                Token::Error(contents[start..pos].to_string())
             }
+        };
+        tokens.push(Spanned::new(token, Span::new(start, pos)));
+    }
+    let end = contents.len();
+    tokens.push(Spanned::new(Token::Eof, Span::new(end, end)));
+    tokens
+}
+
+/// Skips whitespace, `// line` comments and `/* block */` comments starting at `pos`.
+/// `//@` starts a contract annotation rather than a comment, so it is not skipped.
+/// Returns the offset of the next significant byte.
+fn skip_trivia(bytes: &[u8], mut pos: usize) -> usize {
+    loop {
+        pos += whitespace_len(&bytes[pos..]);
+        match bytes.get(pos..pos + 2) {
+            Some(b"//") if bytes.get(pos + 2) != Some(&b'@') => {
+                pos = find_byte(b'\n', &bytes[pos..])
+                    .map(|i| pos + i + 1)
+                    .unwrap_or(bytes.len());
+            }
+            Some(b"/*") => {
+                // An unterminated block comment runs to the end of the file
+                pos = find_block_comment_end(&bytes[pos + 2..])
+                    .map(|i| pos + 2 + i + 2)
+                    .unwrap_or(bytes.len());
+            }
+            _ => return pos,
+        }
+    }
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\r' | b'\n')
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    matches!(byte, b'(' | b')' | b'{' | b'}' | b';' | b',')
+}
+
+/// Decodes a string literal whose opening quote ends at `pos`.
+/// Returns the decoded text and the offset just past the closing quote.
+/// An unterminated literal runs to the end of the file.
+fn string_literal(contents: &str, mut pos: usize) -> (String, usize) {
+    let mut literal = String::new();
+    let mut chars = contents[pos..].chars();
+    while let Some(c) = chars.next() {
+        pos += c.len_utf8();
+        match c {
+            '"' => break,
+            '\\' => {
+                let Some(escaped) = chars.next() else {
+                    break;
+                };
+                pos += escaped.len_utf8();
+                match escaped {
+                    'n' => literal.push('\n'),
+                    't' => literal.push('\t'),
+                    'r' => literal.push('\r'),
+                    '0' => literal.push('\0'),
+                    // `\\`, `\"` and `\'` stand for themselves; so does anything unknown
+                    other => {
+                        if !matches!(other, '\\' | '"' | '\'') {
+                            literal.push('\\');
+                        }
+                        literal.push(other);
+                    }
+                }
+            }
+            c => literal.push(c),
         }
     }
-    tokens.push(Token::Eof);
+    (literal, pos)
+}
+
+/// Collects the error tokens produced by `tokenize_with_spans`
+pub fn lexer_errors(tokens: &[Spanned<Token>]) -> Vec<LexerError> {
     tokens
+        .iter()
+        .filter_map(|token| match &token.node {
+            Token::Error(text) => Some(LexerError {
+                text: text.clone(),
+                span: token.span,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Number of leading whitespace bytes
+#[cfg(not(feature = "simd"))]
+fn whitespace_len(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .position(|&b| !is_whitespace(b))
+        .unwrap_or(bytes.len())
+}
+
+/// Number of leading whitespace bytes, checking eight bytes per step.
+/// Long runs of indentation and blank lines are the common case in real sources.
+#[cfg(feature = "simd")]
+fn whitespace_len(bytes: &[u8]) -> usize {
+    const LANES: usize = 8;
+    const ONES: u64 = u64::from_ne_bytes([0x01; LANES]);
+    const LOWS: u64 = u64::from_ne_bytes([0x7F; LANES]);
+    const HIGHS: u64 = u64::from_ne_bytes([0x80; LANES]);
+    // High bit set in exactly the byte lanes that equal `byte`.
+    // Masking off the high bits first keeps the addition from carrying between lanes.
+    let lanes_equal = |word: u64, byte: u8| {
+        let diff = word ^ (ONES * byte as u64);
+        !(((diff & LOWS) + LOWS) | diff) & HIGHS
+    };
+
+    // Between tokens there's mostly a single space, which isn't worth a word
+    if bytes.get(1).is_none_or(|&b| !is_whitespace(b)) {
+        return usize::from(bytes.first().is_some_and(|&b| is_whitespace(b)));
+    }
+    let mut pos = 0;
+    while let Some(chunk) = bytes.get(pos..pos + LANES) {
+        let word = u64::from_ne_bytes(chunk.try_into().unwrap());
+        let whitespace = lanes_equal(word, b' ')
+            | lanes_equal(word, b'\t')
+            | lanes_equal(word, b'\r')
+            | lanes_equal(word, b'\n');
+        if whitespace != HIGHS {
+            break;
+        }
+        pos += LANES;
+    }
+    pos + bytes[pos..]
+        .iter()
+        .position(|&b| !is_whitespace(b))
+        .unwrap_or(bytes.len() - pos)
+}
+
+#[cfg(not(feature = "simd"))]
+fn find_byte(needle: u8, haystack: &[u8]) -> Option<usize> {
+    haystack.iter().position(|&b| b == needle)
+}
+
+#[cfg(feature = "simd")]
+fn find_byte(needle: u8, haystack: &[u8]) -> Option<usize> {
+    memchr::memchr(needle, haystack)
+}
+
+/// Offset of the `*/` closing a block comment
+#[cfg(not(feature = "simd"))]
+fn find_block_comment_end(haystack: &[u8]) -> Option<usize> {
+    haystack.windows(2).position(|w| w == b"*/")
+}
+
+/// Searching for each `*` is quicker than setting up a substring search for every comment,
+/// which are short
+#[cfg(feature = "simd")]
+fn find_block_comment_end(haystack: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    while let Some(star) = memchr::memchr(b'*', &haystack[start..]) {
+        let star = start + star;
+        if haystack.get(star + 1) == Some(&b'/') {
+            return Some(star);
+        }
+        start = star + 1;
+    }
+    None
 }
@@ -1,3 +1,18 @@
+pub mod c99;
 pub mod codegen;
+pub mod completions;
+pub mod constant;
+pub mod desugar;
+pub mod diagnostic;
+pub mod explain;
+pub mod json;
 pub mod lexer;
+pub mod link;
 pub mod parser;
+pub mod preprocessor;
+pub mod sema;
+pub mod source_map;
+pub mod stats;
+pub mod symbol_table;
+pub mod toolchain;
+pub mod trace;
@@ -0,0 +1,548 @@
+//! Combines separately parsed source files into one program.
+//!
+//! Every file is parsed on its own into a `Module`. Linking checks that each function call
+//! names a function the calling file can see: one of its own, or a non-`static` function of
+//! another file. Names exported by two files are an error. A `static` function or global only
+//! needs to be unique within its file, so one that shares its name with a symbol of another
+//! file is renamed to `<module>.<name>`, along with every reference to it in its own file.
+//! A file may also call the `extern` functions it declares, which are defined outside the
+//! program, or by another file, as long as every file agrees on the function's signature.
+
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Token;
+use crate::parser::{
+    Block, Expr, ExternDeclaration, FnDeclaration, LValue, Parameter, Program, Statement,
+    VarDeclaration,
+};
+use crate::source_map::{Span, Spanned};
+use crate::symbol_table::SymbolTable;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// One source file's program
+pub struct Module {
+    pub name: String,
+    pub program: Program,
+}
+
+/// Where a module defines or declares a symbol
+#[derive(Debug, Clone, PartialEq)]
+pub struct Site {
+    pub module: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkError {
+    DuplicateDefinition {
+        name: String,
+        first: Site,  // where it's defined first
+        second: Site, // where it's defined again
+    },
+    UndefinedFunction {
+        name: String,
+        module: String, // module calling it
+        span: Span,     // the call
+    },
+    // A function declared `extern` with another signature than the one it's defined, or first
+    // declared, with in another module
+    MismatchedSignature {
+        name: String,
+        first: Site,
+        second: Site,
+    },
+}
+
+impl LinkError {
+    /// Error code, explained by `--explain`
+    pub fn code(&self) -> &'static str {
+        match self {
+            LinkError::DuplicateDefinition { .. } => "E0201",
+            LinkError::UndefinedFunction { .. } => "E0202",
+            LinkError::MismatchedSignature { .. } => "E0204",
+        }
+    }
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::DuplicateDefinition {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "'{}' is defined in both {} and {}",
+                name, first.module, second.module
+            ),
+            LinkError::UndefinedFunction { name, module, .. } => {
+                write!(f, "Undefined function '{}' called in {}", name, module)
+            }
+            LinkError::MismatchedSignature {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "'{}' is declared in {} with another signature than in {}",
+                name, second.module, first.module
+            ),
+        }
+    }
+}
+
+impl From<&LinkError> for Diagnostic {
+    fn from(error: &LinkError) -> Self {
+        let diagnostic = match error {
+            LinkError::DuplicateDefinition { first, second, .. } => {
+                Diagnostic::error(error.to_string(), Some(second.span))
+                    .with_label("defined again here")
+                    .with_note(first.span, "first defined here")
+            }
+            LinkError::UndefinedFunction { span, .. } => {
+                Diagnostic::error(error.to_string(), Some(*span)).with_label("not defined")
+            }
+            LinkError::MismatchedSignature { first, second, .. } => {
+                Diagnostic::error(error.to_string(), Some(second.span))
+                    .with_label("declared differently here")
+                    .with_note(first.span, "declared here")
+            }
+        };
+        diagnostic.with_code(error.code())
+    }
+}
+
+/// Links `modules` into a single program, in the order given
+pub fn link(modules: Vec<Module>) -> Result<Program, Vec<LinkError>> {
+    let mut errors = Vec::new();
+
+    // Non-static functions and globals, and where each is defined
+    let mut exported: HashMap<String, Site> = HashMap::new();
+    let mut exported_functions: HashSet<String> = HashSet::new();
+    for module in &modules {
+        let symbols = module
+            .program
+            .decl
+            .iter()
+            .filter(|global| !global.is_static)
+            .map(|global| (&global.identifier, global.span))
+            .chain(
+                module
+                    .program
+                    .fns
+                    .iter()
+                    .filter(|function| !function.is_static)
+                    .map(|function| (&function.identifier, function.span)),
+            );
+        for (name, span) in symbols {
+            let Some(name) = identifier_name(name) else {
+                continue;
+            };
+            let site = Site {
+                module: module.name.clone(),
+                span,
+            };
+            match exported.get(name) {
+                // Sema reports a name defined twice in one module, along with where
+                Some(first) if first.module == module.name => {}
+                Some(first) => errors.push(LinkError::DuplicateDefinition {
+                    name: name.to_string(),
+                    first: first.clone(),
+                    second: site,
+                }),
+                None => {
+                    exported.insert(name.to_string(), site);
+                }
+            }
+        }
+        exported_functions.extend(
+            module
+                .program
+                .fns
+                .iter()
+                .filter(|function| !function.is_static)
+                .filter_map(|function| identifier_name(&function.identifier))
+                .map(str::to_string),
+        );
+    }
+
+    check_signatures(&modules, &mut errors);
+
+    // Every name defined by each module, to tell which statics collide across modules
+    let defined: Vec<HashSet<String>> = modules
+        .iter()
+        .map(|module| {
+            module_symbols(&module.program)
+                .map(str::to_string)
+                .collect()
+        })
+        .collect();
+
+    let mut resolved = Vec::new();
+    for (index, module) in modules.into_iter().enumerate() {
+        let collides = |name: &str| {
+            defined
+                .iter()
+                .enumerate()
+                .any(|(other, names)| other != index && names.contains(name))
+        };
+        let renames = module
+            .program
+            .decl
+            .iter()
+            .filter(|global| global.is_static)
+            .map(|global| &global.identifier)
+            .chain(
+                module
+                    .program
+                    .fns
+                    .iter()
+                    .filter(|function| function.is_static)
+                    .map(|function| &function.identifier),
+            )
+            .filter_map(identifier_name)
+            .filter(|name| collides(name))
+            .map(|name| (name.to_string(), format!("{}.{}", module.name, name)))
+            .collect();
+        let mut functions = exported_functions.clone();
+        functions.extend(module_functions(&module.program).map(str::to_string));
+
+        let mut resolver = Resolver {
+            module: &module.name,
+            renames,
+            functions,
+            locals: SymbolTable::new(),
+            errors: &mut errors,
+        };
+        resolved.push(resolver.program(module.program));
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut program = Program {
+        decl: Vec::new(),
+        fns: Vec::new(),
+        externs: Vec::new(),
+    };
+    for module in resolved {
+        program.decl.extend(module.decl);
+        program.fns.extend(module.fns);
+        program.externs.extend(module.externs);
+    }
+    Ok(program)
+}
+
+/// Checks that each `extern` declaration agrees with the function's definition, or else with
+/// its first declaration, in other modules. Sema checks those within one module.
+fn check_signatures(modules: &[Module], errors: &mut Vec<LinkError>) {
+    // The signature each function is checked against, and where it's from
+    let mut signatures: HashMap<&str, (Signature, Site)> = HashMap::new();
+    for module in modules {
+        let definitions = module
+            .program
+            .fns
+            .iter()
+            .filter(|function| !function.is_static)
+            .map(|function| {
+                let signature = signature(&function.return_type, &function.params);
+                (&function.identifier, signature, function.span)
+            });
+        for (name, signature, span) in definitions {
+            if let Some(name) = identifier_name(name) {
+                let site = Site {
+                    module: module.name.clone(),
+                    span,
+                };
+                // A second definition is a duplicate, reported on its own
+                signatures.entry(name).or_insert((signature, site));
+            }
+        }
+    }
+    for module in modules {
+        for function in &module.program.externs {
+            let Some(name) = identifier_name(&function.identifier) else {
+                continue;
+            };
+            let signature = signature(&function.return_type, &function.params);
+            let second = Site {
+                module: module.name.clone(),
+                span: function.span,
+            };
+            match signatures.get(name) {
+                Some((first_signature, first))
+                    if first.module != module.name && *first_signature != signature =>
+                {
+                    errors.push(LinkError::MismatchedSignature {
+                        name: name.to_string(),
+                        first: first.clone(),
+                        second,
+                    })
+                }
+                Some(_) => {}
+                None => {
+                    signatures.insert(name, (signature, second));
+                }
+            }
+        }
+    }
+}
+
+/// Return type and parameter types of a function, as written
+type Signature<'a> = (&'a Token, Vec<&'a Token>);
+
+fn signature<'a>(return_type: &'a Token, params: &'a [Parameter]) -> Signature<'a> {
+    let params = params.iter().map(|param| &param.type_token).collect();
+    (return_type, params)
+}
+
+fn identifier_name(token: &Token) -> Option<&str> {
+    match token {
+        Token::Identifier(name) => Some(name),
+        _ => None,
+    }
+}
+
+/// Functions a module defines or declares `extern`
+fn module_functions(program: &Program) -> impl Iterator<Item = &str> {
+    program
+        .fns
+        .iter()
+        .map(|function| &function.identifier)
+        .chain(program.externs.iter().map(|function| &function.identifier))
+        .filter_map(identifier_name)
+}
+
+fn module_symbols(program: &Program) -> impl Iterator<Item = &str> {
+    program
+        .decl
+        .iter()
+        .map(|global| &global.identifier)
+        .chain(program.fns.iter().map(|function| &function.identifier))
+        .filter_map(identifier_name)
+}
+
+// Resolves the names used in one module
+struct Resolver<'a> {
+    module: &'a str,
+    // Statics of this module that have to be renamed, with their new names
+    renames: HashMap<String, String>,
+    // Functions this module can call, by their original names
+    functions: HashSet<String>,
+    // Local variables and parameters in scope
+    locals: SymbolTable<()>,
+    errors: &'a mut Vec<LinkError>,
+}
+
+impl Resolver<'_> {
+    fn program(&mut self, program: Program) -> Program {
+        Program {
+            decl: program
+                .decl
+                .into_iter()
+                .map(|global| self.global(global))
+                .collect(),
+            fns: program
+                .fns
+                .into_iter()
+                .map(|function| self.function(function))
+                .collect(),
+            externs: program
+                .externs
+                .into_iter()
+                .map(|function| ExternDeclaration {
+                    identifier: self.rename(function.identifier),
+                    ..function
+                })
+                .collect(),
+        }
+    }
+
+    fn global(&mut self, global: VarDeclaration) -> VarDeclaration {
+        VarDeclaration {
+            identifier: self.rename(global.identifier),
+            value: global.value.map(|value| self.expr(value)),
+            ..global
+        }
+    }
+
+    fn function(&mut self, function: FnDeclaration) -> FnDeclaration {
+        self.locals.push_scope();
+        for param in &function.params {
+            if let Some(name) = identifier_name(&param.identifier) {
+                self.locals.insert(name, ());
+            }
+        }
+        let function = FnDeclaration {
+            identifier: self.rename(function.identifier),
+            requires: self.exprs(function.requires),
+            ensures: self.exprs(function.ensures),
+            body: self.block(function.body),
+            ..function
+        };
+        self.locals.pop_scope();
+        function
+    }
+
+    fn block(&mut self, block: Block) -> Block {
+        self.locals.push_scope();
+        let statements = block
+            .statements
+            .into_iter()
+            .map(|statement| self.statement(statement))
+            .collect();
+        self.locals.pop_scope();
+        Block {
+            statements,
+            span: block.span,
+        }
+    }
+
+    fn statement(&mut self, statement: Spanned<Statement>) -> Spanned<Statement> {
+        let span = statement.span;
+        let node = match statement.node {
+            Statement::Expression(expr) => Statement::Expression(self.expr(expr)),
+            Statement::VarDecl(declaration) => {
+                // The initializer can't see the variable it initializes
+                let value = declaration.value.map(|value| self.expr(value));
+                if let Some(name) = identifier_name(&declaration.identifier) {
+                    self.locals.insert(name, ());
+                }
+                Statement::VarDecl(VarDeclaration {
+                    value,
+                    ..declaration
+                })
+            }
+            Statement::If(condition, then_branch, else_branch) => Statement::If(
+                Box::new(self.expr(*condition)),
+                self.scoped(*then_branch),
+                else_branch.map(|branch| self.scoped(*branch)),
+            ),
+            Statement::While(condition, invariants, body) => Statement::While(
+                Box::new(self.expr(*condition)),
+                self.exprs(invariants),
+                self.scoped(*body),
+            ),
+            Statement::For(init, condition, step, invariants, body) => {
+                // A variable declared by `init` is scoped to the loop
+                self.locals.push_scope();
+                let init = init.map(|init| Box::new(self.statement(*init)));
+                let statement = Statement::For(
+                    init,
+                    condition.map(|condition| Box::new(self.expr(*condition))),
+                    step.map(|step| Box::new(self.statement(*step))),
+                    self.exprs(invariants),
+                    self.scoped(*body),
+                );
+                self.locals.pop_scope();
+                statement
+            }
+            Statement::Postfix(target, op) => Statement::Postfix(self.lvalue(target), op),
+            Statement::Return(value) => {
+                Statement::Return(value.map(|value| Box::new(self.expr(*value))))
+            }
+            Statement::Block(block) => Statement::Block(self.block(block)),
+            Statement::Print(value) => Statement::Print(Box::new(self.expr(*value))),
+            Statement::PrintFormat(format, args) => {
+                Statement::PrintFormat(format, self.exprs(args))
+            }
+            Statement::Scan(target) => Statement::Scan(self.lvalue(target)),
+            Statement::Break => Statement::Break,
+            Statement::Continue => Statement::Continue,
+            Statement::Assert(condition) => Statement::Assert(Box::new(self.expr(*condition))),
+            Statement::Check(condition) => Statement::Check(Box::new(self.expr(*condition))),
+            Statement::Asm(template, output, inputs, constraints) => Statement::Asm(
+                template,
+                output.map(|output| self.lvalue(output)),
+                self.exprs(inputs),
+                constraints,
+            ),
+        };
+        Spanned::new(node, span)
+    }
+
+    /// Resolves an `if` branch or loop body, which gets its own scope even without braces
+    fn scoped(&mut self, statement: Spanned<Statement>) -> Box<Spanned<Statement>> {
+        self.locals.push_scope();
+        let statement = self.statement(statement);
+        self.locals.pop_scope();
+        Box::new(statement)
+    }
+
+    fn exprs(&mut self, exprs: Vec<Spanned<Expr>>) -> Vec<Spanned<Expr>> {
+        exprs.into_iter().map(|expr| self.expr(expr)).collect()
+    }
+
+    fn expr(&mut self, expr: Spanned<Expr>) -> Spanned<Expr> {
+        let span = expr.span;
+        let node = match expr.node {
+            Expr::Literal(token) => Expr::Literal(token),
+            Expr::Unary(op, operand) => Expr::Unary(op, Box::new(self.expr(*operand))),
+            Expr::Binary(left, op, right) => {
+                let left = Box::new(self.expr(*left));
+                Expr::Binary(left, op, Box::new(self.expr(*right)))
+            }
+            Expr::Parentheses(inner) => Expr::Parentheses(Box::new(self.expr(*inner))),
+            Expr::Variable(name) => Expr::Variable(self.variable(name)),
+            Expr::Call(callee, args) => {
+                let callee = match callee.node {
+                    Expr::Variable(name) => {
+                        if let Some(function) = identifier_name(&name) {
+                            if !self.is_local(function) && !self.functions.contains(function) {
+                                self.errors.push(LinkError::UndefinedFunction {
+                                    name: function.to_string(),
+                                    module: self.module.to_string(),
+                                    span: callee.span,
+                                });
+                            }
+                        }
+                        Spanned::new(Expr::Variable(self.variable(name)), callee.span)
+                    }
+                    _ => self.expr(*callee),
+                };
+                Expr::Call(Box::new(callee), self.exprs(args))
+            }
+            Expr::Cast(type_token, operand) => {
+                Expr::Cast(type_token, Box::new(self.expr(*operand)))
+            }
+            Expr::Assign(target, value) => {
+                let target = self.lvalue(target);
+                Expr::Assign(target, Box::new(self.expr(*value)))
+            }
+            Expr::CompoundAssign(target, op, value) => {
+                let target = self.lvalue(target);
+                Expr::CompoundAssign(target, op, Box::new(self.expr(*value)))
+            }
+            Expr::Result => Expr::Result,
+            Expr::Old(inner) => Expr::Old(Box::new(self.expr(*inner))),
+        };
+        Spanned::new(node, span)
+    }
+
+    fn lvalue(&mut self, target: LValue) -> LValue {
+        match target {
+            LValue::Variable(name) => LValue::Variable(self.variable(name)),
+        }
+    }
+
+    /// New name of a reference to `name`, which only changes if it refers to a renamed static
+    fn variable(&self, name: Token) -> Token {
+        match identifier_name(&name) {
+            Some(variable) if !self.is_local(variable) => self.rename(name),
+            _ => name,
+        }
+    }
+
+    /// New name of the module-level symbol `name`
+    fn rename(&self, name: Token) -> Token {
+        match identifier_name(&name).and_then(|symbol| self.renames.get(symbol)) {
+            Some(renamed) => Token::Identifier(renamed.clone()),
+            None => name,
+        }
+    }
+
+    fn is_local(&self, name: &str) -> bool {
+        self.locals.contains(name)
+    }
+}
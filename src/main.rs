@@ -1,92 +1,494 @@
-mod codegen;
-mod lexer;
-mod parser;
-
+use rust_compiler::codegen::CodegenFailure;
+use rust_compiler::completions::{self, Shell};
+use rust_compiler::diagnostic::render::{self, next_base, Palette, SourceFile};
+use rust_compiler::diagnostic::{Diagnostic, DiagnosticSink, Warning, WarningOptions};
+use rust_compiler::explain;
+use rust_compiler::link::{self, Module};
+use rust_compiler::preprocessor::Preprocessed;
+use rust_compiler::source_map::{LineTable, Span};
+use rust_compiler::stats::{self, FunctionStats, Stats};
+use rust_compiler::toolchain::{self, Toolchain};
+use rust_compiler::{c99, codegen, desugar, lexer, parser, preprocessor, sema, trace};
 use std::env;
 use std::error::Error;
 use std::fmt;
 use std::fs;
-use std::io;
-use std::path::PathBuf;
-
-fn main() {
-    let config = parse_args();
+use std::io::{self, IsTerminal, Read};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::rc::Rc;
 
-    match compile_the_thing(config) {
-        Ok(()) => {
-            println!("Compilation succeeded");
+fn main() -> ExitCode {
+    let result = parse_args().and_then(|config| {
+        init_logging(&config);
+        if let Some(code) = &config.explain {
+            print_explanation(code)
+        } else if let Some((old, new)) = &config.stats_diff {
+            print_stats_diff(old, new)
+        } else if let Some(shell) = config.completions {
+            print!("{}", completions::script(shell));
+            Ok(())
+        } else {
+            // A checked run's standard output is the program's own
+            let check_ub = config.check_ub;
+            compile_the_thing(config).map(|()| {
+                if !check_ub {
+                    println!("Compilation succeeded")
+                }
+            })
         }
-        Err(e) => {
-            // Pretty print the error
-            eprintln!("{}", e);
+    });
+    if let Err(e) = result {
+        // Pretty print the error
+        eprintln!("{}", e);
 
-            // Optionally, print the cause chain for detailed debugging
-            let mut source = e.source();
-            while let Some(cause) = source {
-                eprintln!("Caused by: {}", cause);
-                source = cause.source();
-            }
+        // Optionally, print the cause chain for detailed debugging
+        let mut source = e.source();
+        while let Some(cause) = source {
+            eprintln!("Caused by: {}", cause);
+            source = cause.source();
         }
+        return ExitCode::FAILURE;
     }
+    ExitCode::SUCCESS
 }
 
 pub struct Config {
-    pub filename: Option<String>,
+    pub filenames: Vec<String>,
     pub src_dir: String,
+    pub dynamic_checks: bool,
+    pub library: bool,
+    pub warnings: WarningOptions,
+    pub explain: Option<String>,
+    pub stats_diff: Option<(String, String)>,
+    pub completions: Option<Shell>,
+    pub error_format: ErrorFormat,
+    pub color: ColorChoice,
+    pub ssa: bool,
+    pub opt_level: u8,
+    pub passes: Vec<String>,
+    pub time_passes: bool,
+    pub stats_json: bool,
+    pub unroll_factor: usize,
+    pub from_ir: bool,
+    pub dump_ir: bool,
+    pub dump_ir_stdout: bool,
+    pub debug_names: bool,
+    pub check_ub: bool,
+    pub reproducible: bool,
+    pub target: &'static dyn codegen::Backend,
+    pub register_allocator: codegen::RegisterAllocator,
+    pub dump_regalloc: bool,
+    pub incremental: bool,
+    pub omit_frame_pointer: bool,
+    pub red_zone: bool,
+    pub pic: bool,
+    pub mangling: codegen::Mangling,
+    pub zero_page: RangeInclusive<u8>,
+    pub format: codegen::OutputFormat,
+    pub verbose: bool,
+    pub link: bool,
+    pub runtime: bool,
+    pub executable: Option<String>,
+    pub link_inputs: Vec<String>,
+    pub sysroot: Option<String>,
+    pub include_dirs: Vec<String>,
+    pub allow_external_imports: bool,
+}
+
+// How diagnostics are written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Human, // with the source lines they point at
+    Json,  // one JSON object per line, for editors and CI
+}
+
+// Whether human-readable diagnostics are colored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto, // only when stderr is a terminal
+    Always,
+    Never,
 }
 
 impl Config {
     fn default() -> Self {
         Config {
-            filename: None, // Source file to compile
+            filenames: Vec::new(), // Source files or directories to compile, linked into one output
             src_dir: String::from("samples"),
+            dynamic_checks: false, // Contracts are only checked with `-d`
+            library: false,        // With `--lib`, the program doesn't need a `main`
+            warnings: WarningOptions::default(), // Every warning is on, and none is an error
+            explain: None, // With `--explain <code>`, describe an error code instead of compiling
+            stats_diff: None, // With `stats-diff <old> <new>`, compare two `--stats-json` files
+            completions: None, // With `--completions=<shell>`, print the shell's completion script
+            error_format: ErrorFormat::Human,
+            color: ColorChoice::Auto,
+            ssa: false,                         // With `--ssa`, the output is in SSA form
+            opt_level: 0,          // `-O<level>` picks the passes, before any `-f<pass>`
+            passes: Vec::new(),    // Optimization passes to run, from the level and `-f<pass>`
+            time_passes: false,    // With `--time-passes`, how long each pass took is printed
+            stats_json: false,     // With `--stats-json`, each function's statistics are written
+            unroll_factor: 2, // Copies `-funroll-loops` makes of a loop with an unknown trip count
+            from_ir: false,   // With `--from-ir`, the input is abstract assembly in a `.o0` file
+            dump_ir: false,   // With `--dump-ir=after-all`, the program is written after each pass
+            dump_ir_stdout: false, // With `--dump-ir-stdout`, those dumps go to stdout, not files
+            debug_names: false, // With `-g`, temps are named after variables, with debug info
+            check_ub: false, // With `--check-ub`, the program is run, stopping at undefined behavior
+            reproducible: false, // With `--reproducible`, debug info records no absolute path
+            target: codegen::default_backend(), // `--target=x86_64` writes x86-64 assembly
+            register_allocator: codegen::RegisterAllocator::Graph, // `--regalloc=linear` for speed
+            dump_regalloc: false, // With `--dump-regalloc`, interference graphs are written too
+            incremental: false, // With `--incremental`, objects reuse unchanged functions' code
+            omit_frame_pointer: false, // With `--fomit-frame-pointer`, %rbp isn't set up
+            red_zone: false, // With `--red-zone`, leaf functions spill under %rsp
+            pic: false,      // With `--pic`, the output can be linked into a shared library
+            mangling: codegen::Mangling::None, // `--mangle` prefixes symbols with `_c0_`
+            zero_page: 0x02..=0x7f, // `--zero-page=<first>-<last>` for the 6502's, in hex
+            format: codegen::OutputFormat::Assembly, // `--format=prg|nes`, or `--emit=obj|llvm-ir|c`
+            verbose: false, // With `--verbose`, what the compiler does is logged to stderr
+            link: false,    // With `--link`, the output is linked into an executable
+            runtime: true,  // With `--no-runtime`, it's linked without the runtime
+            executable: None, // `-o <path>` names it, instead of `src_dir/target/<name>`
+            link_inputs: Vec::new(), // Other files to link with, like `runtime.c` or `lib.o`
+            sysroot: None,  // `--sysroot=<dir>`, instead of the C compiler's own
+            include_dirs: Vec::new(), // `-I<dir>`, searched in order for included files
+            allow_external_imports: false, // With it, files outside `src_dir` can be included
         }
     }
 }
 
-pub fn parse_args() -> Config {
-    let args: Vec<String> = env::args().collect();
+/// Logs what `RUST_LOG` asks for, and with `--verbose`, everything else at the debug level
+fn init_logging(config: &Config) {
+    let filter = trace::Filter::parse(&env::var("RUST_LOG").unwrap_or_default());
+    trace::init(match config.verbose {
+        true => filter.with_default(trace::Level::Debug),
+        false => filter,
+    });
+}
+
+/// Extensions of the files passed to the linker as they are
+const LINK_INPUTS: [&str; 6] = ["o", "a", "so", "c", "s", "S"];
+
+fn parse_args() -> Result<Config, CompileError> {
+    let mut args = env::args().skip(1);
     let mut config = Config::default();
-    for index in 1..args.len() {
-        match args[index].as_str() {
-            // Special flags go here
-            // Default: treat as filename
-            filename => {
-                config.filename = Some(filename.to_string());
+    // `stats-diff` is a tool of its own, taking the two files and nothing else
+    if env::args().nth(1).as_deref() == Some("stats-diff") {
+        let (Some(old), Some(new), None) = (args.nth(1), args.next(), args.next()) else {
+            return Err(CompileError::InvalidCommand {});
+        };
+        config.stats_diff = Some((old, new));
+        return Ok(config);
+    }
+    // `-f<pass>` and `-fno-<pass>` override the level's pipeline, wherever they come
+    let mut pass_flags: Vec<(String, bool)> = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-d" => config.dynamic_checks = true,
+            "-g" => config.debug_names = true,
+            "--reproducible" => config.reproducible = true,
+            "--check-ub" => config.check_ub = true,
+            "--explain" => {
+                let Some(code) = args.next() else {
+                    return Err(CompileError::InvalidCommand {});
+                };
+                config.explain = Some(code);
+            }
+            _ if arg.starts_with("--completions=") => {
+                let Some(shell) = Shell::from_name(&arg["--completions=".len()..]) else {
+                    return Err(CompileError::InvalidCommand {});
+                };
+                config.completions = Some(shell);
+            }
+            "--lib" => config.library = true,
+            "--ssa" => config.ssa = true,
+            "--time-passes" => config.time_passes = true,
+            "--stats-json" => config.stats_json = true,
+            "--from-ir" => config.from_ir = true,
+            "--dump-ir=after-all" => config.dump_ir = true,
+            "--dump-ir-stdout" => config.dump_ir_stdout = true,
+            "--dump-regalloc" => config.dump_regalloc = true,
+            "--verbose" => config.verbose = true,
+            "--link" => config.link = true,
+            "--no-runtime" => config.runtime = false,
+            "-o" => {
+                let Some(path) = args.next() else {
+                    return Err(CompileError::InvalidCommand {});
+                };
+                config.executable = Some(path);
+            }
+            "--allow-external-imports" => config.allow_external_imports = true,
+            "-I" => {
+                let Some(dir) = args.next() else {
+                    return Err(CompileError::InvalidCommand {});
+                };
+                config.include_dirs.push(dir);
+            }
+            _ if arg.starts_with("-I") => config.include_dirs.push(arg[2..].to_string()),
+            _ if arg.starts_with("--sysroot=") => {
+                config.sysroot = Some(arg["--sysroot=".len()..].to_string())
+            }
+            _ if arg.starts_with("--target=") => {
+                let triple = &arg["--target=".len()..];
+                let Some(backend) = codegen::backend(triple) else {
+                    return Err(CompileError::UnknownTarget {
+                        name: triple.to_string(),
+                    });
+                };
+                config.target = backend;
+            }
+            "--regalloc=graph" => config.register_allocator = codegen::RegisterAllocator::Graph,
+            "--regalloc=linear" => config.register_allocator = codegen::RegisterAllocator::Linear,
+            "--fomit-frame-pointer" => config.omit_frame_pointer = true,
+            "--red-zone" => config.red_zone = true,
+            "--pic" => config.pic = true,
+            "--mangle" => config.mangling = codegen::Mangling::Prefix("_c0_".to_string()),
+            _ if arg.starts_with("--mangle=") => {
+                let prefix = &arg["--mangle=".len()..];
+                if prefix.is_empty() {
+                    return Err(CompileError::InvalidCommand {});
+                }
+                config.mangling = codegen::Mangling::Prefix(prefix.to_string());
+            }
+            _ if arg.starts_with("--zero-page=") => {
+                let window =
+                    arg["--zero-page=".len()..]
+                        .split_once('-')
+                        .and_then(|(first, last)| {
+                            let byte =
+                                |hex: &str| u8::from_str_radix(hex.trim_start_matches("0x"), 16);
+                            Some(byte(first).ok()?..=byte(last).ok()?)
+                        });
+                match window {
+                    Some(window) if !window.is_empty() => config.zero_page = window,
+                    _ => return Err(CompileError::InvalidCommand {}),
+                }
+            }
+            "--format=asm" => config.format = codegen::OutputFormat::Assembly,
+            "--format=prg" => config.format = codegen::OutputFormat::Prg,
+            "--format=nes" => config.format = codegen::OutputFormat::Nes,
+            _ if arg.starts_with("--format=") => return Err(CompileError::InvalidCommand {}),
+            "--emit=asm" => config.format = codegen::OutputFormat::Assembly,
+            "--emit=obj" => config.format = codegen::OutputFormat::Object,
+            "--emit=llvm-ir" => config.format = codegen::OutputFormat::LlvmIr,
+            "--emit=c" => config.format = codegen::OutputFormat::C,
+            _ if arg.starts_with("--emit=") => return Err(CompileError::InvalidCommand {}),
+            "--incremental" => config.incremental = true,
+            "-Werror" => config.warnings.as_errors = true,
+            "--error-format=human" => config.error_format = ErrorFormat::Human,
+            "--error-format=json" => config.error_format = ErrorFormat::Json,
+            "--color=auto" => config.color = ColorChoice::Auto,
+            "--color=always" => config.color = ColorChoice::Always,
+            "--color=never" => config.color = ColorChoice::Never,
+            _ if arg.starts_with("-W") => {
+                let (name, enable) = match arg.strip_prefix("-Wno-") {
+                    Some(name) => (name, false),
+                    None => (&arg[2..], true),
+                };
+                let Some(warning) = Warning::from_name(name) else {
+                    return Err(CompileError::UnknownWarning {
+                        name: name.to_string(),
+                    });
+                };
+                if enable {
+                    config.warnings.enable(warning);
+                } else {
+                    config.warnings.disable(warning);
+                }
             }
+            _ if arg.starts_with("-O") => match arg[2..].parse() {
+                Ok(level) if level <= codegen::MAX_OPT_LEVEL => config.opt_level = level,
+                _ => return Err(CompileError::InvalidCommand {}),
+            },
+            _ if arg.starts_with("--unroll-factor=") => {
+                let factor = arg["--unroll-factor=".len()..].parse();
+                match factor {
+                    Ok(factor) if factor > 0 => config.unroll_factor = factor,
+                    _ => return Err(CompileError::InvalidCommand {}),
+                }
+            }
+            _ if arg.starts_with("-f") => {
+                let (name, enable) = match arg.strip_prefix("-fno-") {
+                    Some(name) => (name, false),
+                    None => (&arg[2..], true),
+                };
+                if !codegen::pass_names().contains(&name) {
+                    return Err(CompileError::UnknownPass {
+                        name: name.to_string(),
+                    });
+                }
+                pass_flags.push((name.to_string(), enable));
+            }
+            // C0 files are named without their extension, so a name with one is for the linker
+            _ if Path::new(&arg)
+                .extension()
+                .is_some_and(|ext| LINK_INPUTS.iter().any(|input| ext == *input)) =>
+            {
+                config.link_inputs.push(arg)
+            }
+            // Default: treat as filename
+            _ => config.filenames.push(arg),
+        }
+    }
+    let link_options =
+        config.executable.is_some() || !config.link_inputs.is_empty() || !config.runtime;
+    if link_options && !config.link {
+        return Err(CompileError::InvalidCommand {});
+    }
+    // C is compiled for the host, whatever the target
+    let c = config.format == codegen::OutputFormat::C;
+    if config.link && !c && config.target.object_format().is_none() {
+        return Err(CompileError::CannotLink {});
+    }
+    // Only assembly, objects and C are linked, and each target writes only some kinds of file,
+    // but for LLVM IR and C, which any can. C is translated from the source, so not from IR.
+    // A checked program is run rather than written out
+    if config.check_ub && (config.link || c || config.from_ir) {
+        return Err(CompileError::InvalidCommand {});
+    }
+    let assembly = config.format == codegen::OutputFormat::Assembly;
+    let object = config.format == codegen::OutputFormat::Object;
+    let llvm_ir = config.format == codegen::OutputFormat::LlvmIr;
+    if (config.link && !(assembly || object || c))
+        || !(llvm_ir || c || config.target.formats().contains(&config.format))
+        || (c && config.from_ir)
+        || (config.incremental && !object)
+    {
+        return Err(CompileError::InvalidCommand {});
+    }
+
+    config.passes = codegen::pipeline(config.opt_level)
+        .into_iter()
+        .map(String::from)
+        .collect();
+    for (name, enable) in pass_flags {
+        config.passes.retain(|pass| *pass != name);
+        if enable {
+            config.passes.push(name);
         }
     }
-    config
+    Ok(config)
 }
 
 #[derive(Debug)]
 enum CompileError {
     InvalidCommand {},
+    MissingMain {},
+    CannotLink {},
+    /// `--check-ub` found undefined behavior, which has been reported as a diagnostic
+    UndefinedBehavior {},
+    /// The program `--check-ub` ran aborted, like a failed contract or assert does
+    ProgramAborted {},
+    /// `--check-ub` couldn't finish running the program
+    RunFailed {
+        error: codegen::RuntimeError,
+    },
     FileNotFound {
         filename: String,
         source: io::Error,
     },
-    ParserError {
+    IrParseError {
+        filename: String,
+        error: codegen::IrParseError,
+    },
+    StatsError {
         filename: String,
-        source: parser::ParserError,
+        error: stats::StatsError,
+    },
+    UnknownWarning {
+        name: String,
+    },
+    UnknownErrorCode {
+        code: String,
+    },
+    UnknownPass {
+        name: String,
+    },
+    UnknownTarget {
+        name: String,
+    },
+    /// Errors reported as diagnostics, which have already been shown
+    Diagnostics {
+        errors: usize,
     },
     BinaryFileGenerationError {
         outpath: String,
         source: io::Error,
     },
+    /// A compiler bug caught by the checks run between passes in debug builds
+    InvalidAbstractAssembly {
+        after: String,
+        error: codegen::VerifyError,
+    },
 }
 
 impl fmt::Display for CompileError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             CompileError::InvalidCommand {} => {
-                write!(f, "Usage: <program> <filename>")
+                write!(
+                    f,
+                    "Usage: <program> [-d] [-g] [--reproducible] [--check-ub] [--lib] [-I<dir>]... [--allow-external-imports] [--ssa] [-O<level>] [-f[no-]<pass>] [--unroll-factor=<n>] [--time-passes] [--stats-json] [--dump-ir=after-all [--dump-ir-stdout]] [--from-ir] [--target=<triple>] [--regalloc=graph|linear] [--dump-regalloc] [--fomit-frame-pointer] [--red-zone] [--pic] [--mangle[=<prefix>]] [--zero-page=<first>-<last>] [--format=asm|prg|nes] [--emit=asm|obj|llvm-ir|c] [--incremental] [--verbose] [--link [-o <path>] [--no-runtime] [--sysroot=<dir>] <file.o|.a|.so|.c|.s|.S>...] [-W[no-]<warning>] [-Werror] [--error-format=human|json] [--color=auto|always|never] <filename or directory>...\n       <program> --explain <code>\n       <program> --completions=bash|zsh|fish\n       <program> stats-diff <old.json> <new.json>"
+                )
+            }
+            CompileError::MissingMain {} => {
+                write!(
+                    f,
+                    "No 'int main()' function found; pass --lib to compile without one"
+                )
+            }
+            CompileError::CannotLink {} => {
+                write!(
+                    f,
+                    "Only x86-64 and RISC-V assembly can be linked; pass --target=x86_64, --target=x86_64-pc-windows or --target=riscv32"
+                )
+            }
+            CompileError::UndefinedBehavior {} => {
+                write!(f, "The program stopped at undefined behavior")
             }
+            CompileError::ProgramAborted {} => write!(f, "The program aborted"),
+            CompileError::RunFailed { error } => write!(f, "Failed to run the program: {}", error),
             CompileError::FileNotFound { filename, source } => {
                 write!(f, "Failed to open file '{}': {}", filename, source)
             }
-            CompileError::ParserError { filename, source } => {
-                write!(f, "Error parsing file '{}': {}", filename, source)
+            CompileError::IrParseError { filename, error } => {
+                write!(
+                    f,
+                    "Error reading abstract assembly '{}', {}",
+                    filename, error
+                )
+            }
+            CompileError::StatsError { filename, error } => {
+                write!(f, "Error reading statistics '{}', {}", filename, error)
+            }
+            CompileError::UnknownWarning { name } => {
+                let known: Vec<&str> = Warning::ALL.iter().map(|warning| warning.name()).collect();
+                write!(
+                    f,
+                    "Unknown warning '{}'. Known warnings: {}",
+                    name,
+                    known.join(", ")
+                )
+            }
+            CompileError::UnknownErrorCode { code } => {
+                write!(f, "'{}' isn't an error code", code)
+            }
+            CompileError::UnknownPass { name } => write!(
+                f,
+                "Unknown pass '{}'. Known passes: {}",
+                name,
+                codegen::pass_names().join(", ")
+            ),
+            CompileError::UnknownTarget { name } => write!(
+                f,
+                "Unknown target '{}'. Known targets: {}",
+                name,
+                codegen::triples().join(", ")
+            ),
+            CompileError::Diagnostics { errors } => {
+                write!(f, "Compilation failed with {} error(s)", errors)
             }
             CompileError::BinaryFileGenerationError { outpath, source } => {
                 write!(
@@ -95,52 +497,583 @@ impl fmt::Display for CompileError {
                     outpath, source
                 )
             }
+            CompileError::InvalidAbstractAssembly { after, error } => {
+                write!(
+                    f,
+                    "Internal compiler error: invalid abstract assembly after {}: {}",
+                    after, error
+                )
+            }
         }
     }
 }
 
 impl Error for CompileError {}
 
-fn compile_the_thing(config: Config) -> Result<(), CompileError> {
-    match config.filename {
-        None => Err(CompileError::InvalidCommand {}),
-        Some(filename) => {
-            // Construct the full path: src_dir/filename.c0
-            let mut path = PathBuf::from(&config.src_dir);
-            path.push(&filename);
-            path.set_extension("c0");
-
-            // Open the file at the constructed path
-            let file = fs::File::open(&path).map_err(|e| CompileError::FileNotFound {
-                filename: path.to_string_lossy().into(),
-                source: e,
-            })?;
+/// Prints what got worse from the statistics in the file `old` to those in `new`, for
+/// `stats-diff`
+fn print_stats_diff(old: &str, new: &str) -> Result<(), CompileError> {
+    let read = |filename: &str| {
+        let text = fs::read_to_string(filename).map_err(|e| CompileError::FileNotFound {
+            filename: filename.to_string(),
+            source: e,
+        })?;
+        Stats::parse(&text).map_err(|error| CompileError::StatsError {
+            filename: filename.to_string(),
+            error,
+        })
+    };
+    print!("{}", stats::diff(&read(old)?, &read(new)?));
+    Ok(())
+}
 
-            let tokens = lexer::tokenize(file);
-            let program = parser::parse(tokens).map_err(|e| CompileError::ParserError {
-                filename: filename.to_string(),
-                source: e,
-            })?;
+/// Prints the description of an error code, for `--explain`
+fn print_explanation(code: &str) -> Result<(), CompileError> {
+    let explanation = explain::explain(code).ok_or_else(|| CompileError::UnknownErrorCode {
+        code: code.to_string(),
+    })?;
+    println!("{}", explanation);
+    Ok(())
+}
 
-            // Construct the output path: src_dir/target/filename.o0
-            let mut outpath = PathBuf::from(&config.src_dir);
-            outpath.push("target");
-            fs::create_dir_all(&outpath).map_err(|e| CompileError::FileNotFound {
-                filename: outpath.to_string_lossy().into(),
-                source: e,
-            })?;
-            outpath.push(&filename);
-            outpath.set_extension("S");
-
-            // Write the output file
-            codegen::generate_code(program, codegen::Target::AbstractAssembly, &outpath).map_err(
-                |e| CompileError::BinaryFileGenerationError {
-                    outpath: outpath.to_string_lossy().into(),
-                    source: e,
-                },
-            )?;
+fn compile_the_thing(mut config: Config) -> Result<(), CompileError> {
+    let mut sink = DiagnosticSink::new(std::mem::take(&mut config.warnings));
+    let mut sources = Vec::new();
+    let result = compile(&config, &mut sources, &mut sink);
+
+    let palette = match config.color {
+        ColorChoice::Auto if io::stderr().is_terminal() => Palette::ANSI,
+        ColorChoice::Auto | ColorChoice::Never => Palette::PLAIN,
+        ColorChoice::Always => Palette::ANSI,
+    };
+    // Warnings are shown even when compilation succeeds
+    for diagnostic in sink.diagnostics() {
+        match config.error_format {
+            ErrorFormat::Human => eprintln!("{}", render::render(&sources, diagnostic, palette)),
+            ErrorFormat::Json => eprintln!("{}", render::render_json(&sources, diagnostic)),
+        }
+    }
+    if config.error_format == ErrorFormat::Human {
+        print_explain_hint(&sink);
+    }
+    result
+}
+
+/// Points at `--explain` for the first error code reported, if any
+fn print_explain_hint(sink: &DiagnosticSink) {
+    if let Some(code) = sink
+        .diagnostics()
+        .iter()
+        .find_map(|diagnostic| diagnostic.code)
+    {
+        eprintln!(
+            "For more information about an error, try `--explain {}`",
+            code
+        );
+    }
+}
+
+/// Compiles the program, reporting problems to `sink` and recording the files read in `sources`
+fn compile(
+    config: &Config,
+    sources: &mut Vec<SourceFile>,
+    sink: &mut DiagnosticSink,
+) -> Result<(), CompileError> {
+    if config.from_ir {
+        return compile_ir(config, sink);
+    }
+    // The output is named after the first file or directory
+    let Some(output_name) = config.filenames.first() else {
+        return Err(CompileError::InvalidCommand {});
+    };
+
+    // Each file is parsed on its own, then linked into one program. Every file is parsed even
+    // after one fails, so that all their errors are reported together.
+    let mut modules = Vec::new();
+    for filename in source_files(config)? {
+        let name = Path::new(&filename)
+            .file_name()
+            .map(|name| name.to_string_lossy().into())
+            .unwrap_or_else(|| filename.clone());
+        if let Some(program) = parse_file(config, &filename, sources, sink)? {
+            modules.push(Module { name, program });
+        }
+    }
+    stop_on_errors(sink)?;
+
+    let program = match link::link(modules) {
+        Ok(program) => program,
+        Err(errors) => {
+            for error in &errors {
+                sink.report(error.into());
+            }
+            return stop_on_errors(sink);
+        }
+    };
+    let program = desugar::desugar(program);
+    match sema::check(&program) {
+        Ok(warnings) => {
+            for warning in &warnings {
+                sink.report(warning.into());
+            }
+        }
+        Err(errors) => {
+            for error in &errors {
+                sink.report(error.into());
+            }
+        }
+    }
+    // With -Werror, warnings stop the compilation too
+    stop_on_errors(sink)?;
+
+    // Sema has checked the signature of any `main`
+    let has_main = program
+        .fns
+        .iter()
+        .any(|function| function.identifier == lexer::Token::Identifier("main".to_string()));
+    if !has_main && !config.library {
+        return Err(CompileError::MissingMain {});
+    }
+
+    let program = if config.dynamic_checks {
+        program
+    } else {
+        desugar::strip_contracts(program)
+    };
+    if config.check_ub {
+        let outpath = output_path(config, output_name)?;
+        return check_undefined_behavior(config, sources, sink, program, &outpath);
+    }
+    if config.format == codegen::OutputFormat::C {
+        let outpath = output_path(config, output_name)?;
+        let result = c99::emit_c(&program, &config.mangling, &outpath)
+            .map(|()| codegen::CodegenReport::default())
+            .map_err(CodegenFailure::Io);
+        return finish_codegen(config, sink, result, &outpath);
+    }
+
+    let outpath = output_path(config, output_name)?;
+    let mut options = codegen_options(config, &outpath);
+    if config.debug_names {
+        options.line_table = Some(Rc::new(line_table(sources, config.reproducible)));
+    }
+    let result = codegen::generate_code(program, config.target, options, &outpath);
+    finish_codegen(config, sink, result, &outpath)
+}
+
+/// Runs the program on standard input, printing what it prints, and reports the line of any
+/// undefined behavior it runs into, for `--check-ub`. It runs after the optimization passes
+/// asked for, which mustn't change what it does.
+fn check_undefined_behavior(
+    config: &Config,
+    sources: &[SourceFile],
+    sink: &mut DiagnosticSink,
+    program: parser::Program,
+    outpath: &Path,
+) -> Result<(), CompileError> {
+    // Passes dump their IR next to where the output would have gone
+    let table = Rc::new(line_table(sources, config.reproducible));
+    let mut options = codegen_options(config, outpath);
+    options.line_table = Some(Rc::clone(&table));
+    let module = match codegen::lower(program, &options) {
+        Ok(module) => module,
+        Err(failure) => return finish_codegen(config, sink, Err(failure), outpath),
+    };
+    let mut input = Vec::new();
+    io::stdin()
+        .read_to_end(&mut input)
+        .map_err(|e| CompileError::FileNotFound {
+            filename: "<stdin>".to_string(),
+            source: e,
+        })?;
+    match codegen::run_with_io(&module, &input) {
+        Ok(result) => {
+            print!("{}", result.stdout);
+            match result.exit_code {
+                Some(_) => Ok(()),
+                None => Err(CompileError::ProgramAborted {}),
+            }
+        }
+        Err(codegen::RuntimeError::Undefined { behavior, location }) => {
+            // The line it's on, without its indentation
+            let span = location
+                .and_then(|(file, line)| table.offset(file, line))
+                .and_then(|start| {
+                    let source = sources.iter().rev().find(|source| source.base <= start)?;
+                    let text = &source.preprocessed.source()[start - source.base..];
+                    let line = text.split('\n').next()?;
+                    let indent = line.len() - line.trim_start().len();
+                    Some(Span::new(start + indent, start + line.trim_end().len()))
+                });
+            sink.report(
+                Diagnostic::error(format!("undefined behavior: {}", behavior.name()), span)
+                    .with_code("E0401")
+                    .with_label(format!(
+                        "`{}` where {}",
+                        behavior.operation(),
+                        behavior.condition()
+                    )),
+            );
+            Err(CompileError::UndefinedBehavior {})
+        }
+        Err(error) => Err(CompileError::RunFailed { error }),
+    }
+}
+
+/// Reads the abstract assembly in `src_dir/filename.o0` and writes it back out, through the
+/// optimization passes, for `--from-ir`
+fn compile_ir(config: &Config, sink: &mut DiagnosticSink) -> Result<(), CompileError> {
+    let [filename] = config.filenames.as_slice() else {
+        return Err(CompileError::InvalidCommand {});
+    };
+    let mut path = PathBuf::from(&config.src_dir);
+    path.push(filename);
+    path.set_extension("o0");
+    let text = fs::read_to_string(&path).map_err(|e| CompileError::FileNotFound {
+        filename: path.to_string_lossy().into(),
+        source: e,
+    })?;
+    let module = codegen::parse_ir(&text).map_err(|error| CompileError::IrParseError {
+        filename: path.to_string_lossy().into(),
+        error,
+    })?;
+
+    let outpath = output_path(config, filename)?;
+    let result = codegen::generate_from_ir(
+        module,
+        config.target,
+        codegen_options(config, &outpath),
+        &outpath,
+    );
+    finish_codegen(config, sink, result, &outpath)
+}
+
+/// Constructs the output path, src_dir/target/output_name.S (or `.prg` or `.nes`), creating
+/// its directory
+fn output_path(config: &Config, output_name: &str) -> Result<PathBuf, CompileError> {
+    let mut outpath = PathBuf::from(&config.src_dir);
+    outpath.push("target");
+    fs::create_dir_all(&outpath).map_err(|e| CompileError::FileNotFound {
+        filename: outpath.to_string_lossy().into(),
+        source: e,
+    })?;
+    outpath.push(output_name);
+    outpath.set_extension(config.format.extension());
+    Ok(outpath)
+}
+
+fn codegen_options(config: &Config, outpath: &Path) -> codegen::CodegenOptions {
+    // Dumps go next to the output, as `name.<number>.<pass>.o0`
+    let dump_ir = match (config.dump_ir, config.dump_ir_stdout) {
+        (false, _) => None,
+        (true, true) => Some(codegen::IrDump::Stdout),
+        (true, false) => Some(codegen::IrDump::Files(outpath.to_path_buf())),
+    };
+    codegen::CodegenOptions {
+        passes: config.passes.clone(),
+        ssa: config.ssa,
+        unroll_factor: config.unroll_factor,
+        dump_ir,
+        debug_names: config.debug_names,
+        register_allocator: config.register_allocator,
+        dump_regalloc: config.dump_regalloc.then(|| outpath.to_path_buf()),
+        omit_frame_pointer: config.omit_frame_pointer,
+        red_zone: config.red_zone,
+        pic: config.pic,
+        line_table: None,
+        mangling: config.mangling.clone(),
+        zero_page: config.zero_page.clone(),
+        format: config.format,
+        // Each function's machine code is cached next to the object, as `name.cache`
+        cache: config.incremental.then(|| outpath.with_extension("cache")),
+    }
+}
 
+/// Reports the outcome of writing the output file
+fn finish_codegen(
+    config: &Config,
+    sink: &mut DiagnosticSink,
+    result: Result<codegen::CodegenReport, CodegenFailure>,
+    outpath: &Path,
+) -> Result<(), CompileError> {
+    match result {
+        Ok(report) => {
+            if config.time_passes {
+                print_pass_timings(&report.timings);
+            }
+            if config.stats_json {
+                write_stats(config, report.functions, outpath)?;
+            }
+            if config.link {
+                link_executable(config, sink, outpath)?;
+            }
             Ok(())
         }
+        Err(CodegenFailure::Io(e)) => Err(CompileError::BinaryFileGenerationError {
+            outpath: outpath.to_string_lossy().into(),
+            source: e,
+        }),
+        Err(CodegenFailure::Invalid { after, error }) => {
+            Err(CompileError::InvalidAbstractAssembly { after, error })
+        }
+    }
+}
+
+/// Links the assembly or C at `outpath` and the other inputs into an executable, or with `--lib`
+/// and `--pic` into a shared library, named by `-o` or else next to the output without its
+/// extension, or with `.so` for a library. The linker's errors are reported to `sink`.
+fn link_executable(
+    config: &Config,
+    sink: &mut DiagnosticSink,
+    outpath: &Path,
+) -> Result<(), CompileError> {
+    let kind = match (config.pic, config.library) {
+        (false, _) => toolchain::Output::Executable,
+        (true, false) => toolchain::Output::PieExecutable,
+        (true, true) => toolchain::Output::SharedLibrary,
+    };
+    let executable = match (&config.executable, kind) {
+        (Some(path), _) => PathBuf::from(path),
+        (None, toolchain::Output::SharedLibrary) => outpath.with_extension("so"),
+        (None, _) => outpath.with_extension(""),
+    };
+    // Like the C0 files, the other inputs are in `src_dir`
+    let mut inputs = vec![outpath.to_path_buf()];
+    // C prints and aborts through the C library instead of the runtime
+    if config.runtime && config.format != codegen::OutputFormat::C {
+        let runtime = outpath.with_extension("runtime.S");
+        if let Err(e) = config.target.emit_runtime(&runtime) {
+            return Err(CompileError::BinaryFileGenerationError {
+                outpath: runtime.to_string_lossy().into(),
+                source: e,
+            });
+        }
+        inputs.push(runtime);
+    }
+    inputs.extend(
+        config
+            .link_inputs
+            .iter()
+            .map(|input| Path::new(&config.src_dir).join(input)),
+    );
+    let result = Toolchain::discover(config.sysroot.as_ref().map(PathBuf::from))
+        .and_then(|toolchain| toolchain.link(&inputs, &executable, kind));
+    if let Err(error) = result {
+        for diagnostic in error.diagnostics() {
+            sink.report(diagnostic);
+        }
+    }
+    stop_on_errors(sink)
+}
+
+/// Writes the statistics of each function next to the output, as `<name>.stats.json`, for
+/// `--stats-json`
+fn write_stats(
+    config: &Config,
+    functions: Vec<FunctionStats>,
+    outpath: &Path,
+) -> Result<(), CompileError> {
+    let stats = Stats {
+        target: config.target.triple().to_string(),
+        functions,
+    };
+    let path = outpath.with_extension("stats.json");
+    fs::write(&path, stats.to_json()).map_err(|e| CompileError::BinaryFileGenerationError {
+        outpath: path.to_string_lossy().into(),
+        source: e,
+    })
+}
+
+/// Writes how long each optimization pass took to stderr, for `--time-passes`
+fn print_pass_timings(timings: &[codegen::PassTiming]) {
+    let width = timings
+        .iter()
+        .map(|timing| timing.name.len())
+        .max()
+        .unwrap_or(0);
+    for timing in timings {
+        let millis = timing.time.as_secs_f64() * 1000.0;
+        eprintln!("{:width$}  {:>9.3} ms", timing.name, millis, width = width);
+    }
+}
+
+/// Names of the files to compile, relative to `src_dir` and without the `.c0` extension.
+/// A directory stands for all the `.c0` files directly inside it, in name order.
+fn source_files(config: &Config) -> Result<Vec<String>, CompileError> {
+    let mut files = Vec::new();
+    for filename in &config.filenames {
+        let dir = Path::new(&config.src_dir).join(filename);
+        if !dir.is_dir() {
+            files.push(filename.clone());
+            continue;
+        }
+        let entries = fs::read_dir(&dir).map_err(|e| CompileError::FileNotFound {
+            filename: dir.to_string_lossy().into(),
+            source: e,
+        })?;
+        let mut stems: Vec<String> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "c0"))
+            .filter_map(|path| path.file_stem().map(|stem| stem.to_string_lossy().into()))
+            .collect();
+        stems.sort();
+        files.extend(
+            stems
+                .into_iter()
+                .map(|stem| format!("{}/{}", filename, stem)),
+        );
+    }
+    Ok(files)
+}
+
+/// `error` as a diagnostic pointing at the line it's on. The file it's in didn't make it
+/// through the preprocessor, so it's added to `sources` as it is to point into.
+fn preprocessor_diagnostic(
+    sources: &mut Vec<SourceFile>,
+    error: &preprocessor::PreprocessorError,
+) -> Diagnostic {
+    let span = line_span(sources, &error.file, error.line);
+    let mut diagnostic = Diagnostic::error(error.kind.to_string(), span).with_code(error.code());
+    if let preprocessor::PreprocessorErrorKind::RecursiveInclude {
+        first_included: Some((file, line)),
+        ..
+    } = &error.kind
+    {
+        if let Some(span) = line_span(sources, file, *line) {
+            diagnostic = diagnostic.with_note(span, "already imported here");
+        }
+    }
+    diagnostic
+}
+
+/// Span of line `line` of the file at `path` without its indentation, reading the file into
+/// `sources`, or None if it can't be read
+fn line_span(sources: &mut Vec<SourceFile>, path: &str, line: usize) -> Option<Span> {
+    let text = fs::read_to_string(path).ok()?;
+    let start: usize = text
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+    let line = text[start..].split('\n').next()?;
+    let indent = line.len() - line.trim_start().len();
+    let base = next_base(sources);
+    sources.push(SourceFile {
+        base,
+        preprocessed: Preprocessed::verbatim(path, &text),
+    });
+    Some(Span::new(
+        base + start + indent,
+        base + start + line.trim_end().len(),
+    ))
+}
+
+/// Stops the compilation if anything reported so far is an error
+fn stop_on_errors(sink: &DiagnosticSink) -> Result<(), CompileError> {
+    if sink.has_errors() {
+        return Err(CompileError::Diagnostics {
+            errors: sink.error_count(),
+        });
+    }
+    Ok(())
+}
+
+/// The file and line of every line of `sources`, which included files may have added to. If
+/// `reproducible`, the table leaves out the directory the compiler runs in, and names the
+/// files in it relative to it.
+fn line_table(sources: &[SourceFile], reproducible: bool) -> LineTable {
+    let mut table = LineTable::new();
+    // The files were opened relative to the directory the compiler runs in
+    let directory = std::env::current_dir().unwrap_or_default();
+    if reproducible {
+        table.set_directory(".");
+    } else {
+        table.set_directory(&directory.to_string_lossy());
+    }
+    for source in sources {
+        let text = source.preprocessed.source();
+        let starts = std::iter::once(0).chain(text.match_indices('\n').map(|(i, _)| i + 1));
+        for start in starts {
+            let (file, offset) = source.preprocessed.origin(start);
+            let (line, _) = file.line_col(offset);
+            let name = match Path::new(file.name()).strip_prefix(&directory) {
+                Ok(relative) if reproducible => relative.to_string_lossy(),
+                _ => file.name().into(),
+            };
+            table.add_line(source.base + start, &name, line);
+        }
+    }
+    table
+}
+
+/// Where the files a program includes are looked for: next to the file including them, in the
+/// `-I` directories, then in the library directory `C0_LIBRARY_DIR` names. Only those under
+/// `src_dir` or the library directory can be included without `--allow-external-imports`.
+fn include_options(config: &Config) -> preprocessor::IncludeOptions {
+    preprocessor::IncludeOptions {
+        search_dirs: config.include_dirs.iter().map(PathBuf::from).collect(),
+        system_dir: env::var_os("C0_LIBRARY_DIR").map(PathBuf::from),
+        root: Some(PathBuf::from(&config.src_dir)),
+        allow_external: config.allow_external_imports,
+    }
+}
+
+/// Preprocesses, lexes and parses `src_dir/filename.c0`, adding it to `sources`. Its spans start
+/// after those of the files already there. Returns None if the file has errors, which are
+/// reported to `sink`.
+fn parse_file(
+    config: &Config,
+    filename: &str,
+    sources: &mut Vec<SourceFile>,
+    sink: &mut DiagnosticSink,
+) -> Result<Option<parser::Program>, CompileError> {
+    let mut path = PathBuf::from(&config.src_dir);
+    path.push(filename);
+    path.set_extension("c0");
+
+    // Read the file at the constructed path
+    let source = fs::read_to_string(&path).map_err(|e| CompileError::FileNotFound {
+        filename: path.to_string_lossy().into(),
+        source: e,
+    })?;
+
+    let preprocessed = match preprocessor::preprocess(&path, &source, &include_options(config)) {
+        Ok(preprocessed) => preprocessed,
+        Err(error) => {
+            sink.report(preprocessor_diagnostic(sources, &error));
+            return Ok(None);
+        }
+    };
+
+    let base = next_base(sources);
+    let mut tokens = lexer::tokenize_with_spans(preprocessed.source());
+    for token in &mut tokens {
+        token.span = Span::new(token.span.start + base, token.span.end + base);
+    }
+    sources.push(SourceFile { base, preprocessed });
+
+    let lexer_errors = lexer::lexer_errors(&tokens);
+    for error in lexer_errors.iter().take(lexer::MAX_REPORTED_ERRORS) {
+        sink.report(error.into());
+    }
+    let omitted = lexer_errors
+        .len()
+        .saturating_sub(lexer::MAX_REPORTED_ERRORS);
+    if omitted > 0 {
+        let message = format!("... and {} more lexer errors in '{}'", omitted, filename);
+        sink.report(Diagnostic::note(message, None));
+        sink.omit_errors(omitted);
+    }
+
+    match parser::parse_with_spans(tokens) {
+        Ok(program) if lexer_errors.is_empty() => Ok(Some(program)),
+        Ok(_) => Ok(None),
+        Err(errors) => {
+            // Parse errors at a lexer error token are just the same problem reported twice
+            for error in errors.iter().filter(|e| !e.node.is_caused_by_lexer_error()) {
+                sink.report(error.into());
+            }
+            Ok(None)
+        }
     }
 }
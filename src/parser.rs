@@ -1,66 +1,310 @@
+use crate::diagnostic::Diagnostic;
 use crate::lexer::Token;
+use crate::source_map::{Span, Spanned};
 use std::fmt;
 
 // Program is comprised of variables and functions
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Program {
     pub decl: Vec<VarDeclaration>,
     pub fns: Vec<FnDeclaration>,
+    pub externs: Vec<ExternDeclaration>,
 }
 
 // Example: `const int my_variable = !(2+3)`
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct VarDeclaration {
-    pub is_const: bool,    // true
-    pub type_token: Token, // `int`
-    pub identifier: Token, // `my_variable`
-    pub value: Expr,       // Unary(Bang, Parentheses(Binary(Number(2.0), Plus, Number(2.0))))
+    pub is_static: bool,              // false; only globals can be `static`
+    pub is_const: bool,               // true
+    pub type_token: Token,            // `int`
+    pub identifier: Token,            // `my_variable`
+    pub value: Option<Spanned<Expr>>, // Some(Unary(Bang, Parentheses(Binary(Number(2.0), Plus, Number(2.0)))))
+    pub span: Span,                   // from `const` up to and including `;`
 }
 
 // Function declaration with parameters and body
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FnDeclaration {
+    pub is_static: bool, // `static` functions are local to their file
     pub return_type: Token,
     pub identifier: Token,
     pub params: Vec<Parameter>,
+    pub requires: Vec<Spanned<Expr>>, // `//@requires` preconditions
+    pub ensures: Vec<Spanned<Expr>>,  // `//@ensures` postconditions
     pub body: Block,
+    pub span: Span, // signature only, from the return type up to and including `)`
+}
+
+// Function defined outside the program, like the C library's: `extern int putchar(int c);`
+#[derive(Debug, Clone)]
+pub struct ExternDeclaration {
+    pub return_type: Token,
+    pub identifier: Token,
+    pub params: Vec<Parameter>,
+    pub span: Span, // from `extern` up to and including `;`
 }
 
 // Function parameter
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Parameter {
     pub type_token: Token,
     pub identifier: Token,
+    pub span: Span,
 }
 
 // Block of statements
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Block {
-    pub statements: Vec<Statement>,
+    pub statements: Vec<Spanned<Statement>>,
+    pub span: Span, // including the braces
 }
 
 // Different types of statements
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Statement {
-    Expression(Expr),
+    Expression(Spanned<Expr>),
     VarDecl(VarDeclaration),
-    If(Box<Expr>, Box<Statement>, Option<Box<Statement>>), // condition, then-branch, else-branch
-    While(Box<Expr>, Box<Statement>),
-    Return(Option<Box<Expr>>),
+    // condition, then-branch, else-branch
+    If(
+        Box<Spanned<Expr>>,
+        Box<Spanned<Statement>>,
+        Option<Box<Spanned<Statement>>>,
+    ),
+    // condition, `//@loop_invariant`s, body
+    While(
+        Box<Spanned<Expr>>,
+        Vec<Spanned<Expr>>,
+        Box<Spanned<Statement>>,
+    ),
+    // init, condition, step, `//@loop_invariant`s, body; any of the first three may be left out
+    For(
+        Option<Box<Spanned<Statement>>>,
+        Option<Box<Spanned<Expr>>>,
+        Option<Box<Spanned<Statement>>>,
+        Vec<Spanned<Expr>>,
+        Box<Spanned<Statement>>,
+    ),
+    // like `x++`, which C0 only allows as a statement
+    Postfix(LValue, PostfixOp),
+    Return(Option<Box<Spanned<Expr>>>),
     Block(Block),
-    Print(Box<Expr>),
+    // like `print(x)`
+    Print(Box<Spanned<Expr>>),
+    // like `print("x = %d\n", x)`; the format string is split up at its conversions
+    PrintFormat(Vec<FormatPart>, Vec<Spanned<Expr>>),
+    // like `scan(x)`, which reads an int or a double from the input into x
+    Scan(LValue),
     Break,
     Continue,
+    // like `//@assert x > 0;`
+    Assert(Box<Spanned<Expr>>),
+    // like `assert(x > 0);`, which is checked whether or not contracts are
+    Check(Box<Spanned<Expr>>),
+    // like `asm("addl %1, %0" : "=r"(x) : "r"(y) : "rcx");`; the template, output and inputs,
+    // each of which lives in a register of its type, and what they're constrained to
+    Asm(String, Option<LValue>, Vec<Spanned<Expr>>, AsmConstraints),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Expr {
-    Literal(Token),                      // leaf node of the expression tree
-    Unary(Token, Box<Expr>),             // like `!expression`
-    Binary(Box<Expr>, Token, Box<Expr>), // like `2+3`
-    Parentheses(Box<Expr>),              // like `(expression)`
-    Variable(Token),                     // variable reference
-    Call(Box<Expr>, Vec<Expr>),          // function call with arguments
+    // leaf node of the expression tree
+    Literal(Token),
+    // like `!expression`
+    Unary(UnOp, Box<Spanned<Expr>>),
+    // like `2+3`
+    Binary(Box<Spanned<Expr>>, BinOp, Box<Spanned<Expr>>),
+    // like `(expression)`
+    Parentheses(Box<Spanned<Expr>>),
+    // variable reference
+    Variable(Token),
+    // function call with arguments
+    Call(Box<Spanned<Expr>>, Vec<Spanned<Expr>>),
+    // like `(int) expression`
+    Cast(Token, Box<Spanned<Expr>>),
+    // like `x = expression`
+    Assign(LValue, Box<Spanned<Expr>>),
+    // like `x += expression`
+    CompoundAssign(LValue, BinOp, Box<Spanned<Expr>>),
+    // `\result`, the value being returned; only in `//@ensures`
+    Result,
+    // like `\old(expression)`, the value of expression on entry; only in `//@ensures`
+    Old(Box<Spanned<Expr>>),
+}
+
+// Operator of a binary expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,          // `+`
+    Sub,          // `-`
+    Mul,          // `*`
+    Div,          // `/`
+    Equal,        // `==`
+    NotEqual,     // `!=`
+    Less,         // `<`
+    LessEqual,    // `<=`
+    Greater,      // `>`
+    GreaterEqual, // `>=`
+}
+
+impl BinOp {
+    fn from_token(token: &Token) -> Option<BinOp> {
+        match token {
+            Token::Plus => Some(BinOp::Add),
+            Token::Minus => Some(BinOp::Sub),
+            Token::Star => Some(BinOp::Mul),
+            Token::Slash => Some(BinOp::Div),
+            Token::EqualEqual => Some(BinOp::Equal),
+            Token::BangEqual => Some(BinOp::NotEqual),
+            Token::Less => Some(BinOp::Less),
+            Token::LessEqual => Some(BinOp::LessEqual),
+            Token::Greater => Some(BinOp::Greater),
+            Token::GreaterEqual => Some(BinOp::GreaterEqual),
+            _ => None,
+        }
+    }
+
+    /// Operator applied by a compound assignment token like `+=`
+    fn from_compound_assignment(token: &Token) -> Option<BinOp> {
+        match token {
+            Token::PlusEqual => Some(BinOp::Add),
+            Token::MinusEqual => Some(BinOp::Sub),
+            Token::StarEqual => Some(BinOp::Mul),
+            Token::SlashEqual => Some(BinOp::Div),
+            _ => None,
+        }
+    }
+}
+
+// Operator of a unary expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Not,    // `!`
+    Neg,    // `-`
+    BitNot, // `~`
+}
+
+impl UnOp {
+    fn from_token(token: &Token) -> Option<UnOp> {
+        match token {
+            Token::Bang => Some(UnOp::Not),
+            Token::Minus => Some(UnOp::Neg),
+            Token::Tilde => Some(UnOp::BitNot),
+            _ => None,
+        }
+    }
+}
+
+// Piece of a `print` format string
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatPart {
+    Text(String),    // printed as is, with `%%` already turned into `%`
+    Arg(FormatSpec), // the next argument
+}
+
+// Conversion of a format argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatSpec {
+    Int,    // `%d`
+    Double, // `%f`
+    Char,   // `%c`
+    String, // `%s`
+}
+
+impl FormatPart {
+    fn parse_format(format: &str) -> Result<Vec<FormatPart>, ParserError> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                text.push(c);
+                continue;
+            }
+            let spec = match chars.next() {
+                Some('%') => {
+                    text.push('%');
+                    continue;
+                }
+                Some('d') => FormatSpec::Int,
+                Some('f') => FormatSpec::Double,
+                Some('c') => FormatSpec::Char,
+                Some('s') => FormatSpec::String,
+                Some(other) => {
+                    return Err(ParserError::InvalidFormat {
+                        reason: format!("unknown conversion `%{}`", other),
+                    })
+                }
+                None => {
+                    return Err(ParserError::InvalidFormat {
+                        reason: "format ends in the middle of a conversion".to_string(),
+                    })
+                }
+            };
+            if !text.is_empty() {
+                parts.push(FormatPart::Text(std::mem::take(&mut text)));
+            }
+            parts.push(FormatPart::Arg(spec));
+        }
+        if !text.is_empty() {
+            parts.push(FormatPart::Text(text));
+        }
+        Ok(parts)
+    }
+}
+
+// Letters of the constraints naming a register, GCC's for x86-64: %rax, %rbx, %rcx, %rdx, %rsi
+// and %rdi
+pub const ASM_REGISTER_CONSTRAINTS: &str = "abcdSD";
+
+// Registers an `asm` statement's operands have to be in, and the registers it overwrites
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AsmConstraints {
+    // Constraint of the output, then of each input: `r` for any register of its type, or a
+    // letter of ASM_REGISTER_CONSTRAINTS
+    pub operands: Vec<char>,
+    // Registers the template overwrites besides the output's, like `rcx`, and `cc` or `memory`
+    pub clobbers: Vec<String>,
+}
+
+impl AsmConstraints {
+    // The letter of the register the operand at `index` has to be in, if it's constrained to one
+    pub fn register(&self, index: usize) -> Option<char> {
+        self.operands.get(index).copied().filter(|&c| c != 'r')
+    }
+
+    // Whether nothing is constrained beyond the operands being in registers
+    pub fn is_unconstrained(&self) -> bool {
+        self.operands.iter().all(|&c| c == 'r') && self.clobbers.is_empty()
+    }
+
+    // Whether an operand or clobber names a register, rather than leaving it to the compiler;
+    // `cc` and `memory` clobbers name none
+    pub fn names_registers(&self) -> bool {
+        let named = |clobber: &String| !matches!(clobber.as_str(), "cc" | "memory");
+        self.operands.iter().any(|&c| c != 'r') || self.clobbers.iter().any(named)
+    }
+}
+
+// Left-hand side of an assignment
+#[derive(Debug, Clone)]
+pub enum LValue {
+    Variable(Token), // like `x`
+}
+
+impl LValue {
+    fn from_expr(expr: Spanned<Expr>) -> Result<LValue, ParserError> {
+        match expr.node {
+            Expr::Variable(name) => Ok(LValue::Variable(name)),
+            _ => Err(ParserError::InvalidAssignmentTarget { target: expr }),
+        }
+    }
+}
+
+// Operator of a postfix statement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostfixOp {
+    Increment, // `++`
+    Decrement, // `--`
 }
 
 #[derive(Debug)]
@@ -68,6 +312,41 @@ pub enum ParserError {
     UnexpectedToken { found: Token, expected: Vec<Token> },
     UnexpectedEOF { expected: Vec<Token> },
     InvalidExpression,
+    InvalidAssignmentTarget { target: Spanned<Expr> },
+    InvalidFormat { reason: String },
+    InvalidAsm { reason: String },
+    OutsideEnsures { found: Token },
+    // A `;` or `)` left out before a token that can't continue the construct; parsing goes on
+    // as if it were there
+    MissingToken { expected: Token },
+}
+
+impl ParserError {
+    /// Error code, explained by `--explain`
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParserError::UnexpectedToken { .. } => "E0001",
+            ParserError::UnexpectedEOF { .. } => "E0002",
+            ParserError::InvalidExpression => "E0003",
+            ParserError::InvalidAssignmentTarget { .. } => "E0004",
+            ParserError::InvalidFormat { .. } => "E0005",
+            ParserError::InvalidAsm { .. } => "E0009",
+            ParserError::OutsideEnsures { .. } => "E0006",
+            ParserError::MissingToken { .. } => "E0008",
+        }
+    }
+
+    /// True if this error is just the parser tripping over a token the lexer
+    /// already reported, in which case it shouldn't be reported again
+    pub fn is_caused_by_lexer_error(&self) -> bool {
+        matches!(
+            self,
+            ParserError::UnexpectedToken {
+                found: Token::Error(_),
+                ..
+            }
+        )
+    }
 }
 
 impl fmt::Display for ParserError {
@@ -83,93 +362,285 @@ impl fmt::Display for ParserError {
             ParserError::UnexpectedEOF { expected } => {
                 write!(f, "Unexpected EOF. Expected one of: {:?}", expected)
             }
-            ParserError::InvalidExpression {} => {
+            ParserError::InvalidExpression => {
                 write!(f, "Invalid expression")
             }
+            ParserError::InvalidAssignmentTarget { .. } => {
+                write!(f, "Invalid assignment target")
+            }
+            ParserError::InvalidFormat { reason } => {
+                write!(f, "Invalid format string: {}", reason)
+            }
+            ParserError::InvalidAsm { reason } => {
+                write!(f, "Invalid asm statement: {}", reason)
+            }
+            ParserError::OutsideEnsures { found } => {
+                let name = if *found == Token::Old {
+                    "\\old"
+                } else {
+                    "\\result"
+                };
+                write!(f, "{} can only be used in //@ensures", name)
+            }
+            ParserError::MissingToken { expected } => {
+                write!(f, "Missing '{}'", missing_token_text(expected))
+            }
+        }
+    }
+}
+
+/// Source text of a token that `MissingToken` reports
+fn missing_token_text(token: &Token) -> &'static str {
+    match token {
+        Token::Semicolon => ";",
+        Token::RightParen => ")",
+        _ => unreachable!("only `;` and `)` are reported missing"),
+    }
+}
+
+impl From<&Spanned<ParserError>> for Diagnostic {
+    fn from(error: &Spanned<ParserError>) -> Self {
+        let diagnostic = Diagnostic::error(error.node.to_string(), Some(error.span))
+            .with_code(error.node.code());
+        match &error.node {
+            ParserError::UnexpectedToken { .. } => diagnostic.with_label("unexpected token"),
+            ParserError::InvalidAssignmentTarget { .. } => {
+                diagnostic.with_label("can't be assigned to")
+            }
+            ParserError::MissingToken { expected } => {
+                let text = missing_token_text(expected);
+                diagnostic
+                    .with_label(format!("insert `{}` here", text))
+                    .with_fix(error.span, text)
+            }
+            _ => diagnostic,
         }
     }
 }
 
 pub struct Parser {
-    tokens: Vec<Token>,
+    tokens: Vec<Spanned<Token>>,
     current: usize,
+    // Errors recovered from so far; parsing continues after each one
+    errors: Vec<Spanned<ParserError>>,
+    // True while parsing an `//@ensures` condition, where `\result` and `\old` are allowed
+    in_ensures: bool,
 }
 
 impl Parser {
+    /// Parser over tokens without source positions; every node gets an empty span
     pub fn new(tokens: Vec<Token>) -> Self {
-        Parser { tokens, current: 0 }
+        let tokens = tokens
+            .into_iter()
+            .map(|token| Spanned::new(token, Span::default()))
+            .collect();
+        Parser::with_spans(tokens)
+    }
+
+    pub fn with_spans(tokens: Vec<Spanned<Token>>) -> Self {
+        Parser {
+            tokens,
+            current: 0,
+            errors: Vec::new(),
+            in_ensures: false,
+        }
     }
 
-    pub fn parse(&mut self) -> Result<Program, ParserError> {
+    /// Parses the whole program, recovering from errors so that all of them are reported at once
+    pub fn parse(&mut self) -> Result<Program, Vec<Spanned<ParserError>>> {
         let mut declarations = Vec::new();
         let mut functions = Vec::new();
+        let mut externs = Vec::new();
 
         while !self.is_at_end() {
-            if self.match_token(&[Token::Const]) {
-                declarations.push(self.variable_declaration(true)?);
-            } else if self.check_type_token() {
-                if self.peek_ahead_for_lparen() {
-                    functions.push(self.function_declaration()?);
-                } else {
-                    declarations.push(self.variable_declaration(false)?);
+            if self.check(&Token::Extern) {
+                match self.extern_declaration() {
+                    Ok(declaration) => externs.push(declaration),
+                    Err(error) => {
+                        self.report(error);
+                        self.synchronize_declaration();
+                    }
                 }
-            } else {
-                // Error handling could be added here
-                self.advance();
+            } else if let Err(error) = self.declaration(&mut declarations, &mut functions) {
+                self.report(error);
+                self.synchronize_declaration();
             }
         }
 
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
         Ok(Program {
             decl: declarations,
             fns: functions,
+            externs,
         })
     }
 
+    fn declaration(
+        &mut self,
+        declarations: &mut Vec<VarDeclaration>,
+        functions: &mut Vec<FnDeclaration>,
+    ) -> Result<(), ParserError> {
+        // A `static` qualifier comes first, and belongs to the declaration
+        let start = self.current_span().start;
+        let is_static = self.match_token(&[Token::Static]);
+
+        if self.match_token(&[Token::Const]) {
+            let declaration = self.variable_declaration(true)?;
+            declarations.push(VarDeclaration {
+                is_static,
+                span: self.span_from(start),
+                ..declaration
+            });
+        } else if self.check_type_token() {
+            if self.peek_ahead_for_lparen() {
+                let function = self.function_declaration()?;
+                functions.push(FnDeclaration {
+                    is_static,
+                    span: Span::new(start, function.span.end),
+                    ..function
+                });
+            } else {
+                let declaration = self.variable_declaration(false)?;
+                declarations.push(VarDeclaration {
+                    is_static,
+                    span: self.span_from(start),
+                    ..declaration
+                });
+            }
+        } else {
+            return Err(ParserError::UnexpectedToken {
+                found: self.peek(),
+                expected: vec![
+                    Token::Extern,
+                    Token::Static,
+                    Token::Const,
+                    Token::Int,
+                    Token::Char,
+                    Token::Double,
+                    Token::Void,
+                    Token::Struct,
+                    Token::String,
+                ],
+            });
+        }
+        Ok(())
+    }
+
     fn variable_declaration(&mut self, is_const: bool) -> Result<VarDeclaration, ParserError> {
+        // A `const` qualifier has already been consumed, and belongs to the declaration
+        let start = if is_const {
+            self.previous_span().start
+        } else {
+            self.current_span().start
+        };
         let type_token = self.advance(); // Type token
         let identifier = self.consume_identifier()?;
 
-        self.consume(&Token::Equal)?; // Expect '='
-        let value = self.expression()?;
+        // The initializer is optional, as in `int x;`
+        let value = if self.match_token(&[Token::Equal]) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
         self.consume(&Token::Semicolon)?;
 
         Ok(VarDeclaration {
+            is_static: false,
             is_const,
             type_token,
             identifier,
             value,
+            span: self.span_from(start),
         })
     }
 
     fn function_declaration(&mut self) -> Result<FnDeclaration, ParserError> {
+        let start = self.current_span().start;
         let return_type = self.advance(); // Type token
         let identifier = self.consume_identifier()?;
 
-        self.consume(&Token::LeftParen);
+        self.consume(&Token::LeftParen)?;
         let params = self.parameters()?;
-        self.consume(&Token::RightParen);
+        self.consume(&Token::RightParen)?;
+        let span = self.span_from(start);
+
+        let mut requires = Vec::new();
+        let mut ensures = Vec::new();
+        loop {
+            if self.check(&Token::Requires) {
+                requires.push(self.contract(&Token::Requires)?);
+            } else if self.check(&Token::Ensures) {
+                ensures.push(self.contract(&Token::Ensures)?);
+            } else {
+                break;
+            }
+        }
 
         let body = self.block()?;
 
         Ok(FnDeclaration {
+            is_static: false,
             return_type,
             identifier,
             params,
+            requires,
+            ensures,
             body,
+            span,
         })
     }
 
+    fn extern_declaration(&mut self) -> Result<ExternDeclaration, ParserError> {
+        let start = self.current_span().start;
+        self.consume(&Token::Extern)?;
+        let return_type = self.consume_type()?;
+        let identifier = self.consume_identifier()?;
+        self.consume(&Token::LeftParen)?;
+        let params = self.parameters()?;
+        self.consume(&Token::RightParen)?;
+        self.consume(&Token::Semicolon)?;
+        Ok(ExternDeclaration {
+            return_type,
+            identifier,
+            params,
+            span: self.span_from(start),
+        })
+    }
+
+    /// `keyword expression;`, as in `//@requires n >= 0;`
+    fn contract(&mut self, keyword: &Token) -> Result<Spanned<Expr>, ParserError> {
+        self.consume(keyword)?;
+        self.in_ensures = keyword == &Token::Ensures;
+        let condition = self.expression();
+        self.in_ensures = false;
+        let condition = condition?;
+        self.consume(&Token::Semicolon)?;
+        Ok(condition)
+    }
+
+    fn loop_invariants(&mut self) -> Result<Vec<Spanned<Expr>>, ParserError> {
+        let mut invariants = Vec::new();
+        while self.check(&Token::LoopInvariant) {
+            invariants.push(self.contract(&Token::LoopInvariant)?);
+        }
+        Ok(invariants)
+    }
+
     fn parameters(&mut self) -> Result<Vec<Parameter>, ParserError> {
         let mut params = Vec::new();
 
         if !self.check(&Token::RightParen) {
             loop {
+                let start = self.current_span().start;
                 let type_token = self.consume_type()?;
                 let identifier = self.consume_identifier()?;
 
                 params.push(Parameter {
                     type_token,
                     identifier,
+                    span: self.span_from(start),
                 });
 
                 if !self.match_token(&[Token::Comma]) {
@@ -182,34 +653,66 @@ impl Parser {
     }
 
     fn block(&mut self) -> Result<Block, ParserError> {
-        self.consume(&Token::LeftBrace);
+        let start = self.current_span().start;
+        self.consume(&Token::LeftBrace)?;
         let mut statements = Vec::new();
 
         while !self.check(&Token::RightBrace) && !self.is_at_end() {
-            statements.push(self.statement()?);
+            let statement_start = self.current;
+            match self.statement() {
+                Ok(statement) => statements.push(statement),
+                Err(error) => {
+                    self.report(error);
+                    // A statement rejected after reading its `;` has nothing left to skip
+                    if self.current == statement_start || self.previous() != Token::Semicolon {
+                        self.synchronize();
+                    }
+                }
+            }
         }
 
-        self.consume(&Token::RightBrace);
-        Ok(Block { statements })
+        self.consume(&Token::RightBrace)?;
+        Ok(Block {
+            statements,
+            span: self.span_from(start),
+        })
+    }
+
+    fn statement(&mut self) -> Result<Spanned<Statement>, ParserError> {
+        let start = self.current_span().start;
+        let statement = self.statement_kind()?;
+        Ok(Spanned::new(statement, self.span_from(start)))
     }
 
-    fn statement(&mut self) -> Result<Statement, ParserError> {
+    fn statement_kind(&mut self) -> Result<Statement, ParserError> {
         if self.match_token(&[Token::If]) {
             self.if_statement()
         } else if self.match_token(&[Token::While]) {
             self.while_statement()
+        } else if self.match_token(&[Token::For]) {
+            self.for_statement()
         } else if self.match_token(&[Token::Return]) {
             self.return_statement()
         } else if self.match_token(&[Token::Break]) {
-            self.consume(&Token::Semicolon);
+            self.consume(&Token::Semicolon)?;
             Ok(Statement::Break)
         } else if self.match_token(&[Token::Continue]) {
-            self.consume(&Token::Semicolon);
+            self.consume(&Token::Semicolon)?;
             Ok(Statement::Continue)
         } else if self.match_token(&[Token::Print]) {
             self.print_statement()
+        } else if self.match_token(&[Token::Scan]) {
+            self.scan_statement()
+        } else if self.match_token(&[Token::Asm]) {
+            self.asm_statement()
+        } else if self.check(&Token::Assert) {
+            Ok(Statement::Assert(Box::new(self.contract(&Token::Assert)?)))
+        } else if self.match_token(&[Token::AssertStatement]) {
+            self.assert_statement()
         } else if self.check(&Token::LeftBrace) {
             Ok(Statement::Block(self.block()?))
+        } else if self.match_token(&[Token::Const]) {
+            Ok(Statement::VarDecl(self.variable_declaration(true)?))
         } else if self.check_type_token() {
             Ok(Statement::VarDecl(self.variable_declaration(false)?))
         } else {
@@ -217,6 +720,9 @@ impl Parser {
         }
     }
 
+    /// An `else` binds to the nearest `if` without one, so `if (a) if (b) x; else y;`
+    /// attaches `else y;` to `if (b)`. `else if` needs no special case: the else
+    /// branch is just another statement, which happens to be an `if`.
     fn if_statement(&mut self) -> Result<Statement, ParserError> {
         self.consume(&Token::LeftParen)?;
         let condition = self.expression()?;
@@ -224,8 +730,7 @@ impl Parser {
 
         let then_branch = Box::new(self.statement()?);
 
-        let else_branch = if self.peek() == Token::Else {
-            self.advance(); // consume the 'else' token
+        let else_branch = if self.match_token(&[Token::Else]) {
             Some(Box::new(self.statement()?))
         } else {
             None
@@ -238,8 +743,56 @@ impl Parser {
         self.consume(&Token::LeftParen)?;
         let condition = self.expression()?;
         self.consume(&Token::RightParen)?;
+        let invariants = self.loop_invariants()?;
+        let body = self.statement()?;
+        Ok(Statement::While(
+            Box::new(condition),
+            invariants,
+            Box::new(body),
+        ))
+    }
+
+    fn for_statement(&mut self) -> Result<Statement, ParserError> {
+        self.consume(&Token::LeftParen)?;
+
+        // Both kinds of initializer consume their own `;`
+        let init = if self.match_token(&[Token::Semicolon]) {
+            None
+        } else {
+            let start = self.current_span().start;
+            let init = if self.check_type_token() {
+                Statement::VarDecl(self.variable_declaration(false)?)
+            } else {
+                self.expression_statement()?
+            };
+            Some(Box::new(Spanned::new(init, self.span_from(start))))
+        };
+
+        let condition = if !self.check(&Token::Semicolon) {
+            Some(Box::new(self.expression()?))
+        } else {
+            None
+        };
+        self.consume(&Token::Semicolon)?;
+
+        let step = if !self.check(&Token::RightParen) {
+            let start = self.current_span().start;
+            let step = self.simple_statement()?;
+            Some(Box::new(Spanned::new(step, self.span_from(start))))
+        } else {
+            None
+        };
+        self.consume(&Token::RightParen)?;
+
+        let invariants = self.loop_invariants()?;
         let body = self.statement()?;
-        Ok(Statement::While(Box::new(condition), Box::new(body)))
+        Ok(Statement::For(
+            init,
+            condition,
+            step,
+            invariants,
+            Box::new(body),
+        ))
     }
 
     fn return_statement(&mut self) -> Result<Statement, ParserError> {
@@ -248,61 +801,237 @@ impl Parser {
         } else {
             None
         };
-        self.consume(&Token::Semicolon);
+        self.consume(&Token::Semicolon)?;
         Ok(Statement::Return(value))
     }
 
+    /// `assert(condition);`
+    fn assert_statement(&mut self) -> Result<Statement, ParserError> {
+        self.consume(&Token::LeftParen)?;
+        let condition = self.expression()?;
+        self.consume(&Token::RightParen)?;
+        self.consume(&Token::Semicolon)?;
+        Ok(Statement::Check(Box::new(condition)))
+    }
+
+    /// `print(expr)` prints one value. A string literal is a format instead,
+    /// with one conversion per remaining argument.
     fn print_statement(&mut self) -> Result<Statement, ParserError> {
-        self.consume(&Token::LeftParen);
+        self.consume(&Token::LeftParen)?;
         let expr = self.expression()?;
-        self.consume(&Token::RightParen);
-        self.consume(&Token::Semicolon);
-        Ok(Statement::Print(Box::new(expr)))
+        let mut args = Vec::new();
+        while self.match_token(&[Token::Comma]) {
+            args.push(self.expression()?);
+        }
+        self.consume(&Token::RightParen)?;
+        self.consume(&Token::Semicolon)?;
+
+        let format = match &expr.node {
+            Expr::Literal(Token::StringLiteral(format)) => FormatPart::parse_format(format)?,
+            _ if args.is_empty() => return Ok(Statement::Print(Box::new(expr))),
+            _ => {
+                return Err(ParserError::InvalidFormat {
+                    reason: "the format must be a string literal".to_string(),
+                })
+            }
+        };
+
+        let conversions = format
+            .iter()
+            .filter(|part| matches!(part, FormatPart::Arg(_)))
+            .count();
+        if conversions != args.len() {
+            return Err(ParserError::InvalidFormat {
+                reason: format!(
+                    "{} conversion(s) but {} argument(s)",
+                    conversions,
+                    args.len()
+                ),
+            });
+        }
+        Ok(Statement::PrintFormat(format, args))
+    }
+
+    /// `scan(x)` reads a value of x's type into it
+    fn scan_statement(&mut self) -> Result<Statement, ParserError> {
+        self.consume(&Token::LeftParen)?;
+        let target = LValue::from_expr(self.expression()?)?;
+        self.consume(&Token::RightParen)?;
+        self.consume(&Token::Semicolon)?;
+        Ok(Statement::Scan(target))
+    }
+
+    /// `asm("template")`, optionally followed by `: "=r"(x)` for the variable the template
+    /// writes, then by `: "r"(expr), ...` for the values it reads, then by `: "rcx", ...` for
+    /// the registers it overwrites. The output is `%0` in the template and the inputs are
+    /// numbered after it, as in GCC. A constraint can name the register an operand has to be
+    /// in, like `"=a"` or `"c"`.
+    fn asm_statement(&mut self) -> Result<Statement, ParserError> {
+        self.consume(&Token::LeftParen)?;
+        let template = self.asm_string()?;
+        let mut output = None;
+        let mut inputs = Vec::new();
+        let mut constraints = AsmConstraints::default();
+        if self.match_token(&[Token::Colon]) {
+            if let Token::StringLiteral(_) = self.peek() {
+                constraints.operands.push(self.asm_constraint("=")?);
+                self.consume(&Token::LeftParen)?;
+                output = Some(LValue::from_expr(self.expression()?)?);
+                self.consume(&Token::RightParen)?;
+            }
+            if self.match_token(&[Token::Colon]) {
+                while let Token::StringLiteral(_) = self.peek() {
+                    let constraint = self.asm_constraint("")?;
+                    let inputs_before = &constraints.operands[output.iter().count()..];
+                    if constraint != 'r' && inputs_before.contains(&constraint) {
+                        return Err(ParserError::InvalidAsm {
+                            reason: format!("two inputs are constrained to `{}`", constraint),
+                        });
+                    }
+                    constraints.operands.push(constraint);
+                    self.consume(&Token::LeftParen)?;
+                    inputs.push(self.expression()?);
+                    self.consume(&Token::RightParen)?;
+                    if !self.match_token(&[Token::Comma]) {
+                        break;
+                    }
+                }
+                if self.match_token(&[Token::Colon]) {
+                    loop {
+                        constraints.clobbers.push(self.asm_string()?);
+                        if !self.match_token(&[Token::Comma]) {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.consume(&Token::RightParen)?;
+        self.consume(&Token::Semicolon)?;
+
+        // Without operands, `%` isn't special
+        let operands = output.iter().count() + inputs.len();
+        if operands > 0 {
+            let mut rest = template.as_str();
+            while let Some(start) = rest.find('%') {
+                rest = &rest[start + 1..];
+                if let Some(after) = rest.strip_prefix('%') {
+                    rest = after;
+                    continue;
+                }
+                let digits =
+                    rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+                match rest[..digits].parse::<usize>() {
+                    Ok(index) if index >= operands => {
+                        return Err(ParserError::InvalidAsm {
+                            reason: format!("`%{}` but {} operand(s)", index, operands),
+                        })
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(Statement::Asm(template, output, inputs, constraints))
+    }
+
+    fn asm_string(&mut self) -> Result<String, ParserError> {
+        match self.peek() {
+            Token::StringLiteral(string) => {
+                self.advance();
+                Ok(string)
+            }
+            found => Err(ParserError::UnexpectedToken {
+                found,
+                expected: vec![Token::StringLiteral(String::new())],
+            }),
+        }
+    }
+
+    /// A constraint starting with `prefix`, `=` for the output, followed by `r` for any register
+    /// or the letter naming one, which is returned
+    fn asm_constraint(&mut self, prefix: &str) -> Result<char, ParserError> {
+        let constraint = self.asm_string()?;
+        let mut letters = constraint.strip_prefix(prefix).unwrap_or("?").chars();
+        match (letters.next(), letters.next()) {
+            (Some(letter), None) if letter == 'r' || ASM_REGISTER_CONSTRAINTS.contains(letter) => {
+                Ok(letter)
+            }
+            _ => Err(ParserError::InvalidAsm {
+                reason: format!(
+                    "constraint `{}` where `{}r` or a register's, like `{}a`, is expected",
+                    constraint, prefix, prefix
+                ),
+            }),
+        }
     }
 
     fn expression_statement(&mut self) -> Result<Statement, ParserError> {
+        let statement = self.simple_statement()?;
+        self.consume(&Token::Semicolon)?;
+        Ok(statement)
+    }
+
+    /// An expression or `x++`/`x--`, without the trailing `;`. This is also what a `for` step is.
+    fn simple_statement(&mut self) -> Result<Statement, ParserError> {
         let expr = self.expression()?;
-        self.consume(&Token::Semicolon);
+
+        if self.match_token(&[Token::PlusPlus, Token::MinusMinus]) {
+            let operator = if self.previous() == Token::PlusPlus {
+                PostfixOp::Increment
+            } else {
+                PostfixOp::Decrement
+            };
+            return Ok(Statement::Postfix(LValue::from_expr(expr)?, operator));
+        }
+
         Ok(Statement::Expression(expr))
     }
 
-    fn expression(&mut self) -> Result<Expr, ParserError> {
+    fn expression(&mut self) -> Result<Spanned<Expr>, ParserError> {
         self.assignment()
     }
 
-    fn assignment(&mut self) -> Result<Expr, ParserError> {
+    fn assignment(&mut self) -> Result<Spanned<Expr>, ParserError> {
         let expr = self.equality()?;
 
         if self.match_token(&[Token::Equal]) {
-            let equals = self.previous();
             let value = self.assignment()?;
 
-            if let Expr::Variable(name) = expr {
-                return Ok(Expr::Binary(
-                    Box::new(Expr::Variable(name)),
-                    equals,
-                    Box::new(value),
-                ));
-            }
-            return Err(ParserError::InvalidExpression);
+            let span = expr.span.to(value.span);
+            let target = LValue::from_expr(expr)?;
+            return Ok(Spanned::new(Expr::Assign(target, Box::new(value)), span));
+        }
+
+        if let Some(operator) = BinOp::from_compound_assignment(&self.peek()) {
+            self.advance();
+            let value = self.assignment()?;
+
+            let span = expr.span.to(value.span);
+            let target = LValue::from_expr(expr)?;
+            let expr = Expr::CompoundAssign(target, operator, Box::new(value));
+            return Ok(Spanned::new(expr, span));
         }
 
         Ok(expr)
     }
 
-    fn equality(&mut self) -> Result<Expr, ParserError> {
+    fn equality(&mut self) -> Result<Spanned<Expr>, ParserError> {
         let mut expr = self.comparison()?;
 
         while self.match_token(&[Token::BangEqual, Token::EqualEqual]) {
-            let operator = self.previous();
+            let operator = self.binary_operator();
             let right = self.comparison()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+            let span = expr.span.to(right.span);
+            expr = Spanned::new(
+                Expr::Binary(Box::new(expr), operator, Box::new(right)),
+                span,
+            );
         }
 
         Ok(expr)
     }
 
-    fn comparison(&mut self) -> Result<Expr, ParserError> {
+    fn comparison(&mut self) -> Result<Spanned<Expr>, ParserError> {
         let mut expr = self.term()?;
 
         while self.match_token(&[
@@ -311,70 +1040,115 @@ impl Parser {
             Token::Less,
             Token::LessEqual,
         ]) {
-            let operator = self.previous();
+            let operator = self.binary_operator();
             let right = self.term()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+            let span = expr.span.to(right.span);
+            expr = Spanned::new(
+                Expr::Binary(Box::new(expr), operator, Box::new(right)),
+                span,
+            );
         }
 
         Ok(expr)
     }
 
-    fn term(&mut self) -> Result<Expr, ParserError> {
+    fn term(&mut self) -> Result<Spanned<Expr>, ParserError> {
         let mut expr = self.factor()?;
 
         while self.match_token(&[Token::Plus, Token::Minus]) {
-            let operator = self.previous();
+            let operator = self.binary_operator();
             let right = self.factor()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+            let span = expr.span.to(right.span);
+            expr = Spanned::new(
+                Expr::Binary(Box::new(expr), operator, Box::new(right)),
+                span,
+            );
         }
 
         Ok(expr)
     }
 
-    fn factor(&mut self) -> Result<Expr, ParserError> {
+    fn factor(&mut self) -> Result<Spanned<Expr>, ParserError> {
         let mut expr = self.unary()?;
 
         while self.match_token(&[Token::Star, Token::Slash]) {
-            let operator = self.previous();
+            let operator = self.binary_operator();
             let right = self.unary()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+            let span = expr.span.to(right.span);
+            expr = Spanned::new(
+                Expr::Binary(Box::new(expr), operator, Box::new(right)),
+                span,
+            );
         }
 
         Ok(expr)
     }
 
-    fn unary(&mut self) -> Result<Expr, ParserError> {
+    fn unary(&mut self) -> Result<Spanned<Expr>, ParserError> {
+        let start = self.current_span().start;
+
         if self.match_token(&[Token::Bang, Token::Minus, Token::Tilde]) {
-            let operator = self.previous();
+            let operator = UnOp::from_token(&self.previous()).unwrap();
             let right = self.unary()?;
-            return Ok(Expr::Unary(operator, Box::new(right)));
+            let expr = Expr::Unary(operator, Box::new(right));
+            return Ok(Spanned::new(expr, self.span_from(start)));
+        }
+
+        if self.check_cast() {
+            self.advance(); // consume the '(' token
+            let type_token = self.advance();
+            self.consume(&Token::RightParen)?;
+            let operand = self.unary()?;
+            let expr = Expr::Cast(type_token, Box::new(operand));
+            return Ok(Spanned::new(expr, self.span_from(start)));
         }
 
         self.primary()
     }
 
-    fn primary(&mut self) -> Result<Expr, ParserError> {
+    fn primary(&mut self) -> Result<Spanned<Expr>, ParserError> {
+        let start = self.current_span().start;
         let token = self.peek();
         match token {
-            Token::Number(_) | Token::StringLiteral(_) => {
+            Token::Number(_) | Token::DoubleLiteral(_) | Token::StringLiteral(_) => {
                 self.advance();
-                Ok(Expr::Literal(token))
+                Ok(Spanned::new(Expr::Literal(token), self.span_from(start)))
             }
             Token::Identifier(_) => {
                 let identifier = self.advance();
+                let callee = Spanned::new(Expr::Variable(identifier), self.span_from(start));
                 if self.match_token(&[Token::LeftParen]) {
                     let args = self.arguments()?;
-                    self.consume(&Token::RightParen);
-                    Ok(Expr::Call(Box::new(Expr::Variable(identifier)), args))
+                    self.consume(&Token::RightParen)?;
+                    let expr = Expr::Call(Box::new(callee), args);
+                    Ok(Spanned::new(expr, self.span_from(start)))
                 } else {
-                    Ok(Expr::Variable(identifier))
+                    Ok(callee)
                 }
             }
             Token::LeftParen => {
                 self.advance();
                 let expr = self.expression()?;
-                self.consume(&Token::RightParen);
-                Ok(Expr::Parentheses(Box::new(expr)))
+                self.consume(&Token::RightParen)?;
+                let expr = Expr::Parentheses(Box::new(expr));
+                Ok(Spanned::new(expr, self.span_from(start)))
+            }
+            Token::Result | Token::Old => {
+                // Misplaced, but still well formed, so keep parsing
+                if !self.in_ensures {
+                    self.report(ParserError::OutsideEnsures {
+                        found: token.clone(),
+                    });
+                }
+                self.advance();
+                if token == Token::Result {
+                    return Ok(Spanned::new(Expr::Result, self.span_from(start)));
+                }
+                self.consume(&Token::LeftParen)?;
+                let expr = self.expression()?;
+                self.consume(&Token::RightParen)?;
+                let expr = Expr::Old(Box::new(expr));
+                Ok(Spanned::new(expr, self.span_from(start)))
             }
             _ => Err(ParserError::UnexpectedToken {
                 found: token.clone(),
@@ -385,6 +1159,7 @@ impl Parser {
                     // However, this means that we have to sync ExpectedToken with Token,
                     // which sounds like too much work for the sake of pretty error messages.
                     Token::Number(0.0),
+                    Token::DoubleLiteral(0.0),
                     Token::StringLiteral(String::from("placeholder")),
                     Token::Identifier(String::from("placeholder")),
                     Token::LeftParen,
@@ -393,7 +1168,7 @@ impl Parser {
         }
     }
 
-    fn arguments(&mut self) -> Result<Vec<Expr>, ParserError> {
+    fn arguments(&mut self) -> Result<Vec<Spanned<Expr>>, ParserError> {
         let mut args = Vec::new();
 
         if !self.check(&Token::RightParen) {
@@ -408,7 +1183,85 @@ impl Parser {
         Ok(args)
     }
 
+    // Error recovery
+    /// Skips the rest of a broken statement: past the next `;`, past the `}` closing a block
+    /// it opened, and any `else` after it, or up to the enclosing block's `}` or the keyword
+    /// starting the next statement. What's inside the blocks it skips belongs to it, however
+    /// many statements that looks like.
+    fn synchronize(&mut self) {
+        let mut depth = 0usize;
+        while !self.is_at_end() {
+            // The closing brace belongs to the enclosing block, so leave it for `block` to
+            // consume
+            if depth == 0 && self.check(&Token::RightBrace) {
+                return;
+            }
+            match self.advance() {
+                Token::LeftBrace => depth += 1,
+                Token::RightBrace => {
+                    depth -= 1;
+                    if depth == 0 && !self.check(&Token::Else) {
+                        return;
+                    }
+                }
+                Token::Semicolon if depth == 0 => return,
+                _ => {}
+            }
+            if depth == 0
+                && (self.check_type_token()
+                    || matches!(
+                        self.peek(),
+                        Token::If
+                            | Token::While
+                            | Token::For
+                            | Token::Return
+                            | Token::Break
+                            | Token::Continue
+                            | Token::Print
+                            | Token::Scan
+                            | Token::Asm
+                            | Token::Assert
+                            | Token::AssertStatement
+                    ))
+            {
+                return;
+            }
+        }
+    }
+
+    /// Skips the rest of a broken top-level declaration: past the next `;` or the `}` closing
+    /// a function body, or up to the start of the next declaration
+    fn synchronize_declaration(&mut self) {
+        let mut depth = 0usize;
+        while !self.is_at_end() {
+            match self.advance() {
+                Token::LeftBrace => depth += 1,
+                Token::RightBrace => {
+                    depth = depth.saturating_sub(1);
+                    if depth == 0 {
+                        return;
+                    }
+                }
+                Token::Semicolon if depth == 0 => return,
+                _ => {}
+            }
+            if depth == 0
+                && (self.check_type_token()
+                    || self.check(&Token::Const)
+                    || self.check(&Token::Static)
+                    || self.check(&Token::Extern))
+            {
+                return;
+            }
+        }
+    }
+
     // Helper methods
+    /// Operator of the token that was just matched by a binary-expression loop
+    fn binary_operator(&self) -> BinOp {
+        BinOp::from_token(&self.previous()).unwrap()
+    }
+
     fn match_token(&mut self, tokens: &[Token]) -> bool {
         for token in tokens {
             if self.check(token) {
@@ -427,6 +1280,7 @@ impl Parser {
         match (token, &self.peek()) {
             // Match variants regardless of their contained values
             (Token::Number(_), Token::Number(_))
+            | (Token::DoubleLiteral(_), Token::DoubleLiteral(_))
             | (Token::StringLiteral(_), Token::StringLiteral(_))
             | (Token::Identifier(_), Token::Identifier(_)) => true,
             // For all other tokens, exact match
@@ -446,11 +1300,38 @@ impl Parser {
     }
 
     fn peek(&self) -> Token {
-        self.tokens[self.current].clone()
+        self.tokens[self.current].node.clone()
     }
 
     fn previous(&self) -> Token {
-        self.tokens[self.current - 1].clone()
+        self.tokens[self.current - 1].node.clone()
+    }
+
+    /// Records an error found at the current token, or at the expression it's about
+    fn report(&mut self, error: ParserError) {
+        let span = match &error {
+            ParserError::InvalidAssignmentTarget { target } => target.span,
+            _ => self.current_span(),
+        };
+        self.errors.push(Spanned::new(error, span));
+    }
+
+    fn current_span(&self) -> Span {
+        self.tokens[self.current].span
+    }
+
+    fn previous_span(&self) -> Span {
+        self.tokens[self.current - 1].span
+    }
+
+    /// Span from `start` to the end of the last consumed token
+    fn span_from(&self, start: usize) -> Span {
+        let end = if self.current > 0 {
+            self.previous_span().end
+        } else {
+            start
+        };
+        Span::new(start, end.max(start))
     }
 
     fn consume(&mut self, token: &Token) -> Result<(), ParserError> {
@@ -463,12 +1344,54 @@ impl Parser {
                 expected: vec![token.clone()],
             });
         }
+        if self.is_missing(token) {
+            // Point between the previous token and the next one, where the missing one goes
+            let end = self.previous_span().end;
+            self.errors.push(Spanned::new(
+                ParserError::MissingToken {
+                    expected: token.clone(),
+                },
+                Span::new(end, end),
+            ));
+            return Ok(());
+        }
         Err(ParserError::UnexpectedToken {
             found: self.peek(),
             expected: vec![token.clone()],
         })
     }
 
+    /// True if `token` was likely left out: it's a `;` before the start of another statement
+    /// or declaration, or a `)` before a block or a `;`
+    fn is_missing(&self, token: &Token) -> bool {
+        if self.current == 0 {
+            return false;
+        }
+        match token {
+            Token::Semicolon => {
+                self.check_type_token()
+                    || matches!(
+                        self.peek(),
+                        Token::Identifier(_)
+                            | Token::RightBrace
+                            | Token::If
+                            | Token::While
+                            | Token::For
+                            | Token::Return
+                            | Token::Break
+                            | Token::Continue
+                            | Token::Print
+                            | Token::Scan
+                            | Token::Asm
+                            | Token::Assert
+                            | Token::AssertStatement
+                    )
+            }
+            Token::RightParen => matches!(self.peek(), Token::LeftBrace | Token::Semicolon),
+            _ => false,
+        }
+    }
+
     fn consume_identifier(&mut self) -> Result<Token, ParserError> {
         match &self.peek() {
             Token::Identifier(_) => Ok(self.advance()),
@@ -491,6 +1414,7 @@ impl Parser {
                     Token::Double,
                     Token::Void,
                     Token::Struct,
+                    Token::String,
                 ],
             })
         }
@@ -499,24 +1423,41 @@ impl Parser {
     fn check_type_token(&self) -> bool {
         matches!(
             self.peek(),
-            Token::Int | Token::Char | Token::Double | Token::Void | Token::Struct
+            Token::Int | Token::Char | Token::Double | Token::Void | Token::Struct | Token::String
         )
     }
 
+    /// A cast looks like `(type)`, which can't be confused with a parenthesized expression
+    /// because type names are reserved keywords
+    fn check_cast(&self) -> bool {
+        self.check(&Token::LeftParen)
+            && matches!(
+                self.tokens.get(self.current + 1).map(|t| &t.node),
+                Some(Token::Int | Token::Char | Token::Double)
+            )
+            && matches!(
+                self.tokens.get(self.current + 2).map(|t| &t.node),
+                Some(Token::RightParen)
+            )
+    }
+
+    /// Distinguishes `type name(` (function) from `type name =` (global),
+    /// looking only at the token right after the name so that parentheses
+    /// inside a global's initializer don't count
     fn peek_ahead_for_lparen(&self) -> bool {
-        let mut i = self.current;
-        while i < self.tokens.len() {
-            match self.tokens[i] {
-                Token::LeftParen => return true,
-                Token::Semicolon => return false,
-                _ => i += 1,
-            }
-        }
-        false
+        matches!(
+            self.tokens.get(self.current + 2).map(|t| &t.node),
+            Some(Token::LeftParen)
+        )
     }
 }
 
-pub fn parse(tokens: Vec<Token>) -> Result<Program, ParserError> {
+pub fn parse(tokens: Vec<Token>) -> Result<Program, Vec<Spanned<ParserError>>> {
     let mut parser = Parser::new(tokens);
     parser.parse()
 }
+
+pub fn parse_with_spans(tokens: Vec<Spanned<Token>>) -> Result<Program, Vec<Spanned<ParserError>>> {
+    let mut parser = Parser::with_spans(tokens);
+    parser.parse()
+}
@@ -0,0 +1,585 @@
+//! Preprocessing, run on the source text before it is lexed.
+//!
+//! Handles `#include "file.h0"`, object-like `#define NAME replacement`, and `#ifdef`/`#ifndef`
+//! conditionals with optional `#else`. Directives take up a whole line, starting with `#`.
+//! The result is a single source string plus a record of which file and offset each part of it
+//! came from, so diagnostics can point into the original files.
+//!
+//! An included file is looked for next to the file including it, then in each `-I` directory in
+//! order, then in the system library directory. Wherever it's found, it has to be under the
+//! project root unless external includes are allowed, so a program can't read arbitrary files
+//! while it's compiled. Files are told apart by their canonical path: one included a second
+//! time, by whatever path, is skipped, and one that includes itself, directly or not, is an
+//! error pointing at where it was first included.
+
+use crate::source_map::SourceMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Preprocessed source, ready for the lexer
+pub struct Preprocessed {
+    source: String,
+    /// Every file that was read, the main file first
+    files: Vec<SourceMap>,
+    /// Where each run of the output came from, ordered by `output_start`
+    origins: Vec<Origin>,
+}
+
+// A run of output copied from one place in one file
+struct Origin {
+    output_start: usize,
+    file: usize,        // index into `Preprocessed::files`
+    file_offset: usize, // where the run starts in that file
+    expansion: bool,    // true if the run is a macro expansion, which maps to its use site
+}
+
+impl Preprocessed {
+    /// `source`, the contents of the file `name`, taken as it is. Diagnostics about a file that
+    /// failed to preprocess point into it through this.
+    pub fn verbatim(name: &str, source: &str) -> Self {
+        Preprocessed {
+            source: source.to_string(),
+            files: vec![SourceMap::new(name, source)],
+            origins: vec![Origin {
+                output_start: 0,
+                file: 0,
+                file_offset: 0,
+                expansion: false,
+            }],
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The original file that `offset` into the preprocessed source came from, and the offset
+    /// into that file
+    pub fn origin(&self, offset: usize) -> (&SourceMap, usize) {
+        let index = self
+            .origins
+            .partition_point(|origin| origin.output_start <= offset)
+            .saturating_sub(1);
+        match self.origins.get(index) {
+            Some(origin) if origin.expansion => (&self.files[origin.file], origin.file_offset),
+            Some(origin) => (
+                &self.files[origin.file],
+                origin.file_offset + (offset - origin.output_start),
+            ),
+            // Only an empty main file has no origins
+            None => (&self.files[0], 0),
+        }
+    }
+}
+
+/// Where `#include` looks for files, and where it may read them from
+#[derive(Debug, Clone, Default)]
+pub struct IncludeOptions {
+    /// Directories given with `-I`, searched in order after the including file's own
+    pub search_dirs: Vec<PathBuf>,
+    /// The system library directory, searched last. Its files may be included from anywhere.
+    pub system_dir: Option<PathBuf>,
+    /// Directory every other included file has to be under, or None to allow any
+    pub root: Option<PathBuf>,
+    /// With `--allow-external-imports`, files outside `root` may be included too
+    pub allow_external: bool,
+}
+
+#[derive(Debug)]
+pub struct PreprocessorError {
+    pub file: String,
+    pub line: usize,
+    pub kind: PreprocessorErrorKind,
+}
+
+#[derive(Debug)]
+pub enum PreprocessorErrorKind {
+    UnknownDirective {
+        directive: String,
+    },
+    MalformedDirective {
+        directive: String,
+        reason: String,
+    },
+    IncludeNotFound {
+        path: String,
+        source: io::Error,
+    },
+    // `first_included` is the file and line that first included it, unless it's the main file
+    RecursiveInclude {
+        path: String,
+        first_included: Option<(String, usize)>,
+    },
+    // The included file is outside the project root
+    ExternalInclude {
+        path: String,
+        root: String,
+    },
+    // `#else` or `#endif` without an open `#ifdef`
+    UnmatchedConditional {
+        directive: String,
+    },
+    // `#ifdef` or `#ifndef` without an `#endif`
+    UnterminatedConditional,
+}
+
+impl PreprocessorError {
+    pub fn code(&self) -> &'static str {
+        "E0010"
+    }
+}
+
+impl fmt::Display for PreprocessorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.kind)?;
+        match &self.kind {
+            PreprocessorErrorKind::RecursiveInclude {
+                first_included: Some((file, line)),
+                ..
+            } => write!(f, "\n  {}:{}: already imported here", file, line),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for PreprocessorErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreprocessorErrorKind::UnknownDirective { directive } => {
+                write!(f, "Unknown directive #{}", directive)
+            }
+            PreprocessorErrorKind::MalformedDirective { directive, reason } => {
+                write!(f, "Malformed #{}: {}", directive, reason)
+            }
+            PreprocessorErrorKind::IncludeNotFound { path, source } => {
+                write!(f, "Failed to include '{}': {}", path, source)
+            }
+            PreprocessorErrorKind::RecursiveInclude { path, .. } => {
+                write!(f, "'{}' includes itself", path)
+            }
+            PreprocessorErrorKind::ExternalInclude { path, root } => write!(
+                f,
+                "'{}' is outside the project root '{}'; pass --allow-external-imports to include it",
+                path, root
+            ),
+            PreprocessorErrorKind::UnmatchedConditional { directive } => {
+                write!(f, "#{} without a matching #ifdef", directive)
+            }
+            PreprocessorErrorKind::UnterminatedConditional => {
+                write!(f, "Conditional is never closed with #endif")
+            }
+        }
+    }
+}
+
+/// Preprocesses `source`, the contents of the file at `path`, finding the files it includes as
+/// `options` says
+pub fn preprocess(
+    path: &Path,
+    source: &str,
+    options: &IncludeOptions,
+) -> Result<Preprocessed, PreprocessorError> {
+    let mut preprocessor = Preprocessor {
+        output: String::new(),
+        files: Vec::new(),
+        origins: Vec::new(),
+        macros: HashMap::new(),
+        include_stack: Vec::new(),
+        included: HashMap::new(),
+        options,
+    };
+    preprocessor.file(path, source)?;
+    Ok(Preprocessed {
+        source: preprocessor.output,
+        files: preprocessor.files,
+        origins: preprocessor.origins,
+    })
+}
+
+struct Preprocessor<'a> {
+    output: String,
+    files: Vec<SourceMap>,
+    origins: Vec<Origin>,
+    // Macro name to replacement text
+    macros: HashMap<String, String>,
+    // Canonical paths of the files currently being preprocessed, innermost last, to catch
+    // recursive includes
+    include_stack: Vec<PathBuf>,
+    // Canonical path of each file included so far, with the file and line first including it
+    included: HashMap<PathBuf, (String, usize)>,
+    options: &'a IncludeOptions,
+}
+
+// An `#ifdef` or `#ifndef` whose `#endif` hasn't been seen yet
+struct Conditional {
+    taken: bool,     // whether the current branch is kept
+    seen_else: bool, // whether `#else` has been seen
+    line: usize,     // line of the opening directive
+}
+
+impl Preprocessor<'_> {
+    fn file(&mut self, path: &Path, source: &str) -> Result<(), PreprocessorError> {
+        let file = self.files.len();
+        self.files
+            .push(SourceMap::new(&path.display().to_string(), source));
+        self.include_stack.push(canonical(path));
+
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        let mut in_comment = false;
+        let mut offset = 0;
+        for (index, line) in source.split_inclusive('\n').enumerate() {
+            let line_number = index + 1;
+            let active = conditionals.iter().all(|conditional| conditional.taken);
+            let error = |kind| PreprocessorError {
+                file: path.display().to_string(),
+                line: line_number,
+                kind,
+            };
+
+            match line.trim_start().strip_prefix('#') {
+                Some(directive) if !in_comment => {
+                    let directive = strip_line_comment(directive).trim();
+                    let (name, argument) = directive
+                        .split_once(char::is_whitespace)
+                        .map(|(name, argument)| (name, argument.trim()))
+                        .unwrap_or((directive, ""));
+                    match name {
+                        "ifdef" | "ifndef" => {
+                            let defined = self.macros.contains_key(macro_name(argument).map_err(
+                                |reason| {
+                                    error(PreprocessorErrorKind::MalformedDirective {
+                                        directive: name.to_string(),
+                                        reason,
+                                    })
+                                },
+                            )?);
+                            conditionals.push(Conditional {
+                                taken: defined == (name == "ifdef"),
+                                seen_else: false,
+                                line: line_number,
+                            });
+                        }
+                        "else" | "endif" if conditionals.is_empty() => {
+                            return Err(error(PreprocessorErrorKind::UnmatchedConditional {
+                                directive: name.to_string(),
+                            }));
+                        }
+                        "else" => {
+                            let conditional = conditionals.last_mut().unwrap();
+                            if conditional.seen_else {
+                                return Err(error(PreprocessorErrorKind::MalformedDirective {
+                                    directive: name.to_string(),
+                                    reason: "this conditional already has an #else".to_string(),
+                                }));
+                            }
+                            conditional.taken = !conditional.taken;
+                            conditional.seen_else = true;
+                        }
+                        "endif" => {
+                            conditionals.pop();
+                        }
+                        // Everything else is ignored in a branch that isn't taken
+                        _ if !active => {}
+                        "define" => self.define(argument).map_err(|reason| {
+                            error(PreprocessorErrorKind::MalformedDirective {
+                                directive: name.to_string(),
+                                reason,
+                            })
+                        })?,
+                        "include" => {
+                            let included = argument
+                                .strip_prefix('"')
+                                .and_then(|argument| argument.strip_suffix('"'))
+                                .ok_or_else(|| {
+                                    error(PreprocessorErrorKind::MalformedDirective {
+                                        directive: name.to_string(),
+                                        reason: "expected a quoted file name".to_string(),
+                                    })
+                                })?;
+                            let (included_path, identity) =
+                                self.find(path, included).map_err(&error)?;
+                            if self.include_stack.contains(&identity) {
+                                return Err(error(PreprocessorErrorKind::RecursiveInclude {
+                                    path: included_path.display().to_string(),
+                                    first_included: self.included.get(&identity).cloned(),
+                                }));
+                            }
+                            // Like an import, a file is only included once
+                            if self.included.contains_key(&identity) {
+                                offset += line.len();
+                                continue;
+                            }
+                            self.included
+                                .insert(identity, (path.display().to_string(), line_number));
+                            let included_source =
+                                fs::read_to_string(&included_path).map_err(|source| {
+                                    error(PreprocessorErrorKind::IncludeNotFound {
+                                        path: included_path.display().to_string(),
+                                        source,
+                                    })
+                                })?;
+                            self.file(&included_path, &included_source)?;
+                            // Keep the included file's last line apart from the next one
+                            if !self.output.is_empty() && !self.output.ends_with('\n') {
+                                self.output.push('\n');
+                            }
+                        }
+                        _ => {
+                            return Err(error(PreprocessorErrorKind::UnknownDirective {
+                                directive: name.to_string(),
+                            }));
+                        }
+                    }
+                }
+                _ if active => self.expand_line(file, offset, line, &mut in_comment),
+                _ => {}
+            }
+            offset += line.len();
+        }
+
+        self.include_stack.pop();
+        match conditionals.last() {
+            Some(unterminated) => Err(PreprocessorError {
+                file: path.display().to_string(),
+                line: unterminated.line,
+                kind: PreprocessorErrorKind::UnterminatedConditional,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Finds the file `#include "name"` in `path` names, returning its path and its canonical
+    /// path, which tells files apart
+    fn find(&self, path: &Path, name: &str) -> Result<(PathBuf, PathBuf), PreprocessorErrorKind> {
+        let here = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let dirs = std::iter::once(&here)
+            .chain(&self.options.search_dirs)
+            .chain(&self.options.system_dir);
+        let mut first_error = None;
+        for dir in dirs {
+            let candidate = dir.join(name);
+            match fs::canonicalize(&candidate) {
+                Ok(identity) => {
+                    self.check_sandbox(&candidate, &identity)?;
+                    return Ok((candidate, identity));
+                }
+                Err(error) => {
+                    first_error.get_or_insert((candidate, error));
+                }
+            }
+        }
+        let (candidate, source) = first_error.unwrap();
+        Err(PreprocessorErrorKind::IncludeNotFound {
+            path: candidate.display().to_string(),
+            source,
+        })
+    }
+
+    /// Checks that the file found at `path`, whose canonical path is `identity`, may be included:
+    /// it's under the project root or the system library directory
+    fn check_sandbox(&self, path: &Path, identity: &Path) -> Result<(), PreprocessorErrorKind> {
+        let Some(root) = &self.options.root else {
+            return Ok(());
+        };
+        let root = canonical(root);
+        let in_system_dir = (self.options.system_dir.as_ref())
+            .is_some_and(|system| identity.starts_with(canonical(system)));
+        if self.options.allow_external || identity.starts_with(&root) || in_system_dir {
+            return Ok(());
+        }
+        Err(PreprocessorErrorKind::ExternalInclude {
+            path: path.display().to_string(),
+            root: root.display().to_string(),
+        })
+    }
+
+    /// Records `#define NAME replacement`. Returns why the definition is malformed, if it is.
+    fn define(&mut self, argument: &str) -> Result<(), String> {
+        let name_end = argument
+            .find(|c: char| !is_identifier_char(c))
+            .unwrap_or(argument.len());
+        let (name, replacement) = argument.split_at(name_end);
+        macro_name(name)?;
+        if replacement.starts_with('(') {
+            return Err("function-like macros are not supported".to_string());
+        }
+        self.macros
+            .insert(name.to_string(), replacement.trim().to_string());
+        Ok(())
+    }
+
+    /// Copies one line of code to the output, expanding macros.
+    /// `in_comment` carries whether a `/* block comment */` is still open across lines.
+    fn expand_line(&mut self, file: usize, offset: usize, line: &str, in_comment: &mut bool) {
+        self.mark(file, offset, false);
+        let mut pos = 0;
+        while pos < line.len() {
+            if *in_comment {
+                let end = match line[pos..].find("*/") {
+                    Some(i) => {
+                        *in_comment = false;
+                        pos + i + 2
+                    }
+                    None => line.len(),
+                };
+                self.output.push_str(&line[pos..end]);
+                pos = end;
+                continue;
+            }
+            let (piece, end) = next_piece(line, pos);
+            let text = &line[pos..end];
+            match piece {
+                Piece::Identifier if self.macros.contains_key(text) => {
+                    self.mark(file, offset + pos, true);
+                    let expansion = self.expand(text, &mut Vec::new());
+                    self.output.push_str(&expansion);
+                    self.mark(file, offset + end, false);
+                }
+                Piece::BlockCommentStart => {
+                    *in_comment = true;
+                    self.output.push_str(text);
+                }
+                _ => self.output.push_str(text),
+            }
+            pos = end;
+        }
+    }
+
+    /// Replacement text of macro `name`, with the macros it uses expanded in turn.
+    /// A macro isn't expanded again inside its own expansion, so recursive definitions end.
+    fn expand(&self, name: &str, expanding: &mut Vec<String>) -> String {
+        let replacement = &self.macros[name];
+        expanding.push(name.to_string());
+        let mut expansion = String::new();
+        let mut pos = 0;
+        while pos < replacement.len() {
+            let (piece, end) = next_piece(replacement, pos);
+            let text = &replacement[pos..end];
+            match piece {
+                Piece::Identifier
+                    if self.macros.contains_key(text)
+                        && !expanding.iter().any(|active| active == text) =>
+                {
+                    expansion.push_str(&self.expand(text, expanding));
+                }
+                _ => expansion.push_str(text),
+            }
+            pos = end;
+        }
+        expanding.pop();
+        expansion
+    }
+
+    /// Notes that output from here on comes from `file_offset` in `file`,
+    /// unless that already follows from the previous origin
+    fn mark(&mut self, file: usize, file_offset: usize, expansion: bool) {
+        let output_start = self.output.len();
+        if let Some(last) = self.origins.last_mut() {
+            if last.output_start == output_start {
+                *last = Origin {
+                    output_start,
+                    file,
+                    file_offset,
+                    expansion,
+                };
+                return;
+            }
+            let continues = !last.expansion
+                && !expansion
+                && last.file == file
+                && last.file_offset + (output_start - last.output_start) == file_offset;
+            if continues {
+                return;
+            }
+        }
+        self.origins.push(Origin {
+            output_start,
+            file,
+            file_offset,
+            expansion,
+        });
+    }
+}
+
+// What a stretch of source text is, as far as macro expansion cares
+enum Piece {
+    Identifier,
+    BlockCommentStart, // `/*`; the comment may run past the end of the line
+    Other,             // anything copied as is: literals, line comments, operators
+}
+
+/// Classifies the text at `pos`, returning its kind and where it ends.
+/// String and character literals and `// line comments` are skipped whole, so macros aren't
+/// expanded inside them. `//@` annotations are code, so only their marker is skipped.
+fn next_piece(text: &str, pos: usize) -> (Piece, usize) {
+    let rest = &text[pos..];
+    let c = rest.chars().next().unwrap();
+    if c.is_ascii_alphabetic() || c == '_' {
+        let len = rest
+            .find(|c: char| !is_identifier_char(c))
+            .unwrap_or(rest.len());
+        return (Piece::Identifier, pos + len);
+    }
+    if rest.starts_with("//@") {
+        return (Piece::Other, pos + 3);
+    }
+    if rest.starts_with("//") {
+        return (Piece::Other, text.len());
+    }
+    if rest.starts_with("/*") {
+        return (Piece::BlockCommentStart, pos + 2);
+    }
+    if c == '"' || c == '\'' {
+        let mut chars = rest.char_indices().skip(1);
+        while let Some((i, next)) = chars.next() {
+            if next == '\\' {
+                chars.next();
+            } else if next == c || next == '\n' {
+                return (Piece::Other, pos + i + next.len_utf8());
+            }
+        }
+        return (Piece::Other, text.len());
+    }
+    (Piece::Other, pos + c.len_utf8())
+}
+
+/// `text` up to any `// comment` outside a literal
+fn strip_line_comment(text: &str) -> &str {
+    let mut pos = 0;
+    while pos < text.len() {
+        let (_, end) = next_piece(text, pos);
+        if text[pos..].starts_with("//") {
+            return &text[..pos];
+        }
+        pos = end;
+    }
+    text
+}
+
+/// Checks that `name` can name a macro, returning it
+fn macro_name(name: &str) -> Result<&str, String> {
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(is_identifier_char);
+    if valid {
+        Ok(name)
+    } else if name.is_empty() {
+        Err("expected a macro name".to_string())
+    } else {
+        Err(format!("'{}' is not a valid macro name", name))
+    }
+}
+
+/// `path` with symbolic links and `.` and `..` resolved, or as it is if it doesn't exist
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
@@ -0,0 +1,1464 @@
+//! Semantic analysis, run on the desugared program before code generation.
+//!
+//! Builds symbol tables for globals, functions and local scopes, then checks that every name is
+//! defined, every expression is well typed, and every call matches its function's signature.
+//! Local variables and parameters that are never read are reported as warnings, unless their
+//! name starts with `_`. A local declared without a value must be assigned on every path to a
+//! read of it.
+//! Functions may call any function, wherever it's defined, and the `extern` functions the
+//! program declares, which any definition of theirs must match. Globals are initialized before the
+//! program runs, so an initializer can't call functions and only sees the constants before it;
+//! initializers that depend on each other are reported as a cycle.
+//! Operands must have exactly the expected type, except that an int is implicitly converted
+//! wherever a `double` is expected or combined with one. A double only becomes an int through
+//! an explicit cast.
+
+use crate::constant::{evaluate, is_least_int_magnitude, Constant};
+use crate::diagnostic::{Diagnostic, Warning};
+use crate::lexer::Token;
+use crate::parser::{
+    BinOp, Expr, ExternDeclaration, FnDeclaration, FormatPart, FormatSpec, LValue, Parameter,
+    Program, Statement, UnOp, VarDeclaration,
+};
+use crate::source_map::{Span, Spanned};
+use crate::symbol_table::SymbolTable;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Double,
+    Char,
+    String,
+    Void,
+}
+
+impl Type {
+    fn is_numeric(self) -> bool {
+        matches!(self, Type::Int | Type::Double)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Type::Int => "int",
+            Type::Double => "double",
+            Type::Char => "char",
+            Type::String => "string",
+            Type::Void => "void",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SemaError {
+    pub function: Option<String>, // function the error is in, or None for a global
+    pub kind: SemaErrorKind,
+    pub span: Span, // where in the linked program's source the error is
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SemaErrorKind {
+    UndefinedVariable {
+        name: String,
+    },
+    UndefinedFunction {
+        name: String,
+    },
+    Redeclaration {
+        name: String,
+        previous: Span, // the earlier definition
+    },
+    TypeMismatch {
+        expected: Type,
+        found: Type,
+    },
+    // `operator` can't be applied to a value of type `found`
+    InvalidOperand {
+        operator: String,
+        found: Type,
+    },
+    ArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    AssignToConst {
+        name: String,
+    },
+    VoidVariable {
+        name: String,
+    },
+    UnsupportedType {
+        found: Token,
+    },
+    NonConstantInitializer {
+        name: String,
+    },
+    // A global initializer reads a global defined after it
+    UsedBeforeDefinition {
+        name: String,
+        definition: Span,
+    },
+    // A global initializer calls `name`
+    CallInGlobalInitializer {
+        name: String,
+    },
+    // Global initializers that read each other, in order; the first global is repeated last
+    CyclicInitializer {
+        cycle: Vec<String>,
+    },
+    ConstWithoutValue {
+        name: String,
+    },
+    // `return;` in a function that returns a value
+    MissingReturnValue,
+    // `return value;` in a void function
+    UnexpectedReturnValue,
+    // Some path through a function that returns a value reaches the end of its body
+    MissingReturn,
+    ResultInVoidFunction,
+    // `main` declared as anything but `int main()`
+    InvalidMain,
+    // `break` or `continue` outside of a loop
+    OutsideLoop {
+        statement: String,
+    },
+    // A local declared without a value is read where some path hasn't assigned it
+    UnassignedVariable {
+        name: String,
+    },
+    // An int literal past the largest int, other than the least int's negated
+    IntegerOutOfRange,
+}
+
+impl SemaError {
+    /// Error code, explained by `--explain`
+    pub fn code(&self) -> &'static str {
+        match &self.kind {
+            SemaErrorKind::UndefinedFunction { .. } => "E0101",
+            SemaErrorKind::UndefinedVariable { .. } => "E0102",
+            SemaErrorKind::Redeclaration { .. } => "E0103",
+            SemaErrorKind::TypeMismatch { .. } => "E0104",
+            SemaErrorKind::InvalidOperand { .. } => "E0105",
+            SemaErrorKind::ArgumentCount { .. } => "E0106",
+            SemaErrorKind::AssignToConst { .. } => "E0107",
+            SemaErrorKind::VoidVariable { .. } => "E0108",
+            SemaErrorKind::UnsupportedType { .. } => "E0109",
+            SemaErrorKind::NonConstantInitializer { .. } => "E0110",
+            SemaErrorKind::ConstWithoutValue { .. } => "E0111",
+            SemaErrorKind::MissingReturnValue => "E0112",
+            SemaErrorKind::UnexpectedReturnValue => "E0113",
+            SemaErrorKind::ResultInVoidFunction => "E0114",
+            SemaErrorKind::InvalidMain => "E0115",
+            SemaErrorKind::OutsideLoop { .. } => "E0116",
+            SemaErrorKind::UsedBeforeDefinition { .. } => "E0117",
+            SemaErrorKind::CallInGlobalInitializer { .. } => "E0118",
+            SemaErrorKind::CyclicInitializer { .. } => "E0119",
+            SemaErrorKind::UnassignedVariable { .. } => "E0120",
+            SemaErrorKind::MissingReturn => "E0121",
+            SemaErrorKind::IntegerOutOfRange => "E0122",
+        }
+    }
+
+    /// Short description of what's wrong at the error's span
+    pub fn label(&self) -> Option<String> {
+        match &self.kind {
+            SemaErrorKind::UndefinedVariable { .. } | SemaErrorKind::UndefinedFunction { .. } => {
+                Some("not defined".to_string())
+            }
+            SemaErrorKind::Redeclaration { .. } => Some("redefined here".to_string()),
+            SemaErrorKind::TypeMismatch { found, .. }
+            | SemaErrorKind::InvalidOperand { found, .. } => Some(format!("this is {}", found)),
+            SemaErrorKind::NonConstantInitializer { .. } => {
+                Some("not a constant expression".to_string())
+            }
+            SemaErrorKind::UsedBeforeDefinition { .. } => Some("used here".to_string()),
+            SemaErrorKind::UnassignedVariable { .. } => Some("read here".to_string()),
+            SemaErrorKind::MissingReturn => Some("reached without a return".to_string()),
+            SemaErrorKind::IntegerOutOfRange => Some("doesn't fit in an int".to_string()),
+            _ => None,
+        }
+    }
+
+    /// Another place in the source that explains the error, and what it is
+    pub fn note(&self) -> Option<(Span, &'static str)> {
+        match &self.kind {
+            SemaErrorKind::Redeclaration { previous, .. } => {
+                Some((*previous, "previously defined here"))
+            }
+            SemaErrorKind::UsedBeforeDefinition { definition, .. } => {
+                Some((*definition, "defined here"))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(function) = &self.function {
+            write!(f, "In function '{}': ", function)?;
+        }
+        match &self.kind {
+            SemaErrorKind::UndefinedVariable { name } => write!(f, "Undefined variable '{}'", name),
+            SemaErrorKind::UndefinedFunction { name } => write!(f, "Undefined function '{}'", name),
+            SemaErrorKind::Redeclaration { name, .. } => {
+                write!(f, "'{}' is already defined", name)
+            }
+            SemaErrorKind::TypeMismatch { expected, found } => {
+                write!(f, "Expected {}, found {}", expected, found)
+            }
+            SemaErrorKind::InvalidOperand { operator, found } => {
+                write!(f, "'{}' can't be applied to {}", operator, found)
+            }
+            SemaErrorKind::ArgumentCount {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "'{}' takes {} argument(s), but {} were given",
+                name, expected, found
+            ),
+            SemaErrorKind::AssignToConst { name } => {
+                write!(f, "Can't assign to '{}', which is declared const", name)
+            }
+            SemaErrorKind::VoidVariable { name } => write!(f, "'{}' can't have type void", name),
+            SemaErrorKind::UnsupportedType { found } => write!(f, "Unsupported type {:?}", found),
+            SemaErrorKind::NonConstantInitializer { name } => {
+                write!(
+                    f,
+                    "'{}' must be initialized with a constant expression",
+                    name
+                )
+            }
+            SemaErrorKind::UsedBeforeDefinition { name, .. } => {
+                write!(f, "'{}' is used before its definition", name)
+            }
+            SemaErrorKind::CallInGlobalInitializer { name } => write!(
+                f,
+                "Globals are initialized before the program runs, so they can't call '{}'",
+                name
+            ),
+            SemaErrorKind::CyclicInitializer { cycle } => write!(
+                f,
+                "The initializer of '{}' depends on itself: {}",
+                cycle[0],
+                cycle.join(" -> ")
+            ),
+            SemaErrorKind::ConstWithoutValue { name } => {
+                write!(f, "Constant '{}' needs a value", name)
+            }
+            SemaErrorKind::MissingReturnValue => write!(f, "Missing return value"),
+            SemaErrorKind::UnexpectedReturnValue => {
+                write!(f, "Void function can't return a value")
+            }
+            SemaErrorKind::MissingReturn => {
+                write!(
+                    f,
+                    "Control may reach the end of a function that returns a value"
+                )
+            }
+            SemaErrorKind::ResultInVoidFunction => {
+                write!(f, "\\result can't be used in a void function")
+            }
+            SemaErrorKind::InvalidMain => write!(f, "'main' must be declared as 'int main()'"),
+            SemaErrorKind::OutsideLoop { statement } => {
+                write!(f, "'{}' outside of a loop", statement)
+            }
+            SemaErrorKind::UnassignedVariable { name } => {
+                write!(f, "'{}' may be read before it's assigned", name)
+            }
+            SemaErrorKind::IntegerOutOfRange => write!(
+                f,
+                "Integer literal is larger than the largest int, {}",
+                i32::MAX
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SemaWarning {
+    pub function: String, // function the warning is in
+    pub kind: SemaWarningKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SemaWarningKind {
+    UnusedVariable { name: String },
+    UnusedParameter { name: String },
+}
+
+impl fmt::Display for SemaWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "In function '{}': ", self.function)?;
+        match &self.kind {
+            SemaWarningKind::UnusedVariable { name } => {
+                write!(f, "Variable '{}' is never read", name)
+            }
+            SemaWarningKind::UnusedParameter { name } => {
+                write!(f, "Parameter '{}' is never read", name)
+            }
+        }
+    }
+}
+
+impl From<&SemaError> for Diagnostic {
+    fn from(error: &SemaError) -> Self {
+        let mut diagnostic =
+            Diagnostic::error(error.to_string(), Some(error.span)).with_code(error.code());
+        if let Some(label) = error.label() {
+            diagnostic = diagnostic.with_label(label);
+        }
+        match error.note() {
+            Some((span, note)) => diagnostic.with_note(span, note),
+            None => diagnostic,
+        }
+    }
+}
+
+impl From<&SemaWarning> for Diagnostic {
+    fn from(warning: &SemaWarning) -> Self {
+        let kind = match warning.kind {
+            SemaWarningKind::UnusedVariable { .. } => Warning::UnusedVariable,
+            SemaWarningKind::UnusedParameter { .. } => Warning::UnusedParameter,
+        };
+        Diagnostic::warning(kind, warning.to_string(), warning.span)
+    }
+}
+
+/// Checks the whole program, reporting every error found. If there are none, returns the
+/// warnings instead.
+pub fn check(program: &Program) -> Result<Vec<SemaWarning>, Vec<SemaError>> {
+    let mut checker = Checker {
+        globals: HashMap::new(),
+        later_globals: HashMap::new(),
+        cyclic_globals: HashSet::new(),
+        functions: HashMap::new(),
+        locals: SymbolTable::new(),
+        function: None,
+        return_type: Type::Void,
+        loop_depth: 0,
+        errors: Vec::new(),
+        warnings: Vec::new(),
+    };
+
+    // Every function is visible everywhere, whatever order they're defined in
+    for function in &program.fns {
+        checker.signature(function);
+    }
+    for function in &program.externs {
+        checker.extern_signature(function);
+    }
+    // Functions see every global, but an initializer only sees the globals before it
+    for global in &program.decl {
+        if let Token::Identifier(name) = &global.identifier {
+            checker
+                .later_globals
+                .entry(name.clone())
+                .or_insert(global.span);
+        }
+    }
+    for cycle in initializer_cycles(&program.decl) {
+        let span = program.decl[cycle[0]].span;
+        let mut names: Vec<String> = cycle
+            .iter()
+            .filter_map(|&index| match &program.decl[index].identifier {
+                Token::Identifier(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        checker.cyclic_globals.extend(names.iter().cloned());
+        names.push(names[0].clone());
+        checker.error(SemaErrorKind::CyclicInitializer { cycle: names }, span);
+    }
+    for global in &program.decl {
+        checker.global(global);
+    }
+    for function in &program.fns {
+        checker.function(function);
+    }
+
+    if checker.errors.is_empty() {
+        Ok(checker.warnings)
+    } else {
+        Err(checker.errors)
+    }
+}
+
+// What the checker knows about a variable
+struct Variable {
+    ty: Type,
+    is_const: bool,
+    value: Option<Constant>, // known value of a constant
+    span: Span,              // declaration
+    is_param: bool,
+    is_read: bool, // whether any expression reads it; only tracked for locals
+}
+
+// Parameter and return types of a function
+struct Signature {
+    params: Vec<Type>,
+    return_type: Type,
+    span: Span, // declaration
+}
+
+struct Checker {
+    globals: HashMap<String, Variable>,
+    // Globals not checked yet, which initializers can't see
+    later_globals: HashMap<String, Span>,
+    // Globals whose initializers are part of a cycle, which was already reported
+    cyclic_globals: HashSet<String>,
+    functions: HashMap<String, Signature>,
+    // Local variables and parameters
+    locals: SymbolTable<Variable>,
+    // Name of the function being checked
+    function: Option<String>,
+    return_type: Type,
+    // Number of loops around the statement being checked
+    loop_depth: usize,
+    errors: Vec<SemaError>,
+    warnings: Vec<SemaWarning>,
+}
+
+impl Checker {
+    fn error(&mut self, kind: SemaErrorKind, span: Span) {
+        self.errors.push(SemaError {
+            function: self.function.clone(),
+            kind,
+            span,
+        });
+    }
+
+    fn global(&mut self, global: &VarDeclaration) {
+        let Token::Identifier(name) = &global.identifier else {
+            return;
+        };
+        self.later_globals.remove(name);
+        let Some(ty) = self.variable_type(&global.type_token, name, global.span) else {
+            return;
+        };
+        // Globals are laid out before the program runs, so their values must be known
+        let value = match &global.value {
+            Some(_) if self.cyclic_globals.contains(name) => None,
+            Some(value) => self.constant_initializer(name, ty, value),
+            None if global.is_const => {
+                self.error(
+                    SemaErrorKind::ConstWithoutValue { name: name.clone() },
+                    global.span,
+                );
+                None
+            }
+            None => None,
+        };
+        let previous = (self.globals.get(name).map(|global| global.span))
+            .or_else(|| self.functions.get(name).map(|function| function.span));
+        if let Some(previous) = previous {
+            self.error(
+                SemaErrorKind::Redeclaration {
+                    name: name.clone(),
+                    previous,
+                },
+                global.span,
+            );
+            return;
+        }
+        let variable = Variable {
+            ty,
+            is_const: global.is_const,
+            value: value.filter(|_| global.is_const),
+            span: global.span,
+            is_param: false,
+            is_read: false,
+        };
+        self.globals.insert(name.clone(), variable);
+    }
+
+    fn signature(&mut self, function: &FnDeclaration) {
+        let Token::Identifier(name) = &function.identifier else {
+            return;
+        };
+        let return_type = self
+            .resolve_type(&function.return_type, function.span)
+            .unwrap_or(Type::Void);
+        // A void parameter is reported along with the body
+        let params = self.parameter_types(&function.params);
+        // The program starts at `main`, which takes nothing and returns the exit code
+        let is_entry_point = return_type == Type::Int && function.params.is_empty();
+        if name == "main" && (!is_entry_point || function.is_static) {
+            self.error(SemaErrorKind::InvalidMain, function.span);
+        }
+        if let Some(previous) = self.functions.get(name) {
+            self.error(
+                SemaErrorKind::Redeclaration {
+                    name: name.clone(),
+                    previous: previous.span,
+                },
+                function.span,
+            );
+            return;
+        }
+        let signature = Signature {
+            params,
+            return_type,
+            span: function.span,
+        };
+        self.functions.insert(name.clone(), signature);
+    }
+
+    /// Records the `extern` function `function`, which may be declared more than once, and
+    /// defined, as long as every declaration agrees
+    fn extern_signature(&mut self, function: &ExternDeclaration) {
+        let Token::Identifier(name) = &function.identifier else {
+            return;
+        };
+        let return_type = self
+            .resolve_type(&function.return_type, function.span)
+            .unwrap_or(Type::Void);
+        let params = self.parameter_types(&function.params);
+        // Without a body, nothing else reports a void parameter
+        for param in &function.params {
+            if let (Some(Type::Void), Token::Identifier(param_name)) =
+                (type_of(&param.type_token), &param.identifier)
+            {
+                let name = param_name.clone();
+                self.error(SemaErrorKind::VoidVariable { name }, param.span);
+            }
+        }
+        match self.functions.get(name) {
+            Some(previous) if previous.params == params && previous.return_type == return_type => {}
+            Some(previous) => {
+                let previous = previous.span;
+                let name = name.clone();
+                self.error(
+                    SemaErrorKind::Redeclaration { name, previous },
+                    function.span,
+                );
+            }
+            None => {
+                let signature = Signature {
+                    params,
+                    return_type,
+                    span: function.span,
+                };
+                self.functions.insert(name.clone(), signature);
+            }
+        }
+    }
+
+    /// Types of `params`, with unsupported ones reported and taken as void
+    fn parameter_types(&mut self, params: &[Parameter]) -> Vec<Type> {
+        params
+            .iter()
+            .map(|param| {
+                self.resolve_type(&param.type_token, param.span)
+                    .unwrap_or(Type::Void)
+            })
+            .collect()
+    }
+
+    fn function(&mut self, function: &FnDeclaration) {
+        let Token::Identifier(name) = &function.identifier else {
+            return;
+        };
+        self.function = Some(name.clone());
+        // Unsupported types were already reported with the signature
+        self.return_type = type_of(&function.return_type).unwrap_or(Type::Void);
+
+        self.locals.push_scope();
+        for param in &function.params {
+            let Token::Identifier(param_name) = &param.identifier else {
+                continue;
+            };
+            match type_of(&param.type_token) {
+                Some(Type::Void) => self.error(
+                    SemaErrorKind::VoidVariable {
+                        name: param_name.clone(),
+                    },
+                    param.span,
+                ),
+                Some(ty) => {
+                    let variable = Variable {
+                        ty,
+                        is_const: false,
+                        value: None,
+                        span: param.span,
+                        is_param: true,
+                        is_read: false,
+                    };
+                    self.declare(param_name, variable);
+                }
+                None => {}
+            }
+        }
+        for condition in function.requires.iter().chain(&function.ensures) {
+            self.expect(Type::Int, condition);
+        }
+        for statement in &function.body.statements {
+            self.statement(statement);
+        }
+        self.pop_scope();
+        let paths = follow_paths(function);
+        for (name, span) in paths.reads {
+            self.error(SemaErrorKind::UnassignedVariable { name }, span);
+        }
+        // Reported at the closing brace, which such a path reaches
+        if paths.unassigned.is_some() && self.return_type != Type::Void {
+            let end = function.body.span.end;
+            self.error(
+                SemaErrorKind::MissingReturn,
+                Span::new(end.saturating_sub(1), end),
+            );
+        }
+        self.function = None;
+    }
+
+    fn statement(&mut self, statement: &Spanned<Statement>) {
+        match &statement.node {
+            Statement::Expression(expr) => {
+                self.expr(expr);
+            }
+            Statement::VarDecl(declaration) => {
+                let Token::Identifier(name) = &declaration.identifier else {
+                    return;
+                };
+                let ty = self.variable_type(&declaration.type_token, name, declaration.span);
+                // The initializer can't see the variable it initializes
+                let value = match (ty, &declaration.value) {
+                    (Some(ty), Some(value)) if declaration.is_const => {
+                        self.constant_initializer(name, ty, value)
+                    }
+                    (Some(ty), Some(value)) => {
+                        self.expect(ty, value);
+                        None
+                    }
+                    (None, Some(value)) => {
+                        self.expr(value);
+                        None
+                    }
+                    (_, None) if declaration.is_const => {
+                        self.error(
+                            SemaErrorKind::ConstWithoutValue { name: name.clone() },
+                            declaration.span,
+                        );
+                        None
+                    }
+                    (_, None) => None,
+                };
+                if let Some(ty) = ty {
+                    let variable = Variable {
+                        ty,
+                        is_const: declaration.is_const,
+                        value,
+                        span: declaration.span,
+                        is_param: false,
+                        is_read: false,
+                    };
+                    self.declare(name, variable);
+                }
+            }
+            Statement::If(condition, then_branch, else_branch) => {
+                self.expect(Type::Int, condition);
+                self.scoped(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.scoped(else_branch);
+                }
+            }
+            Statement::While(condition, invariants, body) => {
+                self.expect(Type::Int, condition);
+                for invariant in invariants {
+                    self.expect(Type::Int, invariant);
+                }
+                self.loop_body(body);
+            }
+            Statement::For(..) | Statement::Postfix(..) => {
+                unreachable!("{:?} is desugared before sema", statement)
+            }
+            Statement::Return(value) => match value {
+                Some(value) if self.return_type == Type::Void => {
+                    self.expr(value);
+                    self.error(SemaErrorKind::UnexpectedReturnValue, statement.span);
+                }
+                Some(value) => self.expect(self.return_type, value),
+                None if self.return_type != Type::Void => {
+                    self.error(SemaErrorKind::MissingReturnValue, statement.span);
+                }
+                None => {}
+            },
+            Statement::Block(block) => {
+                self.locals.push_scope();
+                for statement in &block.statements {
+                    self.statement(statement);
+                }
+                self.pop_scope();
+            }
+            Statement::Print(value) => {
+                if self.expr(value) == Some(Type::Void) {
+                    self.error(
+                        SemaErrorKind::InvalidOperand {
+                            operator: "print".to_string(),
+                            found: Type::Void,
+                        },
+                        value.span,
+                    );
+                }
+            }
+            Statement::PrintFormat(format, args) => {
+                let specs = format.iter().filter_map(|part| match part {
+                    FormatPart::Arg(spec) => Some(*spec),
+                    FormatPart::Text(_) => None,
+                });
+                // The parser already checked that there's one argument per conversion
+                for (spec, arg) in specs.zip(args) {
+                    let expected = match spec {
+                        FormatSpec::Int => Type::Int,
+                        FormatSpec::Double => Type::Double,
+                        FormatSpec::Char => Type::Char,
+                        FormatSpec::String => Type::String,
+                    };
+                    self.expect(expected, arg);
+                }
+            }
+            Statement::Scan(target) => {
+                // Only numbers can be read
+                match self.assignment_target(target, statement.span) {
+                    Some(found) if !found.is_numeric() => self.error(
+                        SemaErrorKind::InvalidOperand {
+                            operator: "scan".to_string(),
+                            found,
+                        },
+                        statement.span,
+                    ),
+                    _ => {}
+                }
+            }
+            Statement::Break | Statement::Continue if self.loop_depth == 0 => {
+                let keyword = if matches!(statement.node, Statement::Break) {
+                    "break"
+                } else {
+                    "continue"
+                };
+                self.error(
+                    SemaErrorKind::OutsideLoop {
+                        statement: keyword.to_string(),
+                    },
+                    statement.span,
+                );
+            }
+            Statement::Break | Statement::Continue => {}
+            Statement::Assert(condition) | Statement::Check(condition) => {
+                self.expect(Type::Int, condition)
+            }
+            Statement::Asm(_, output, inputs, constraints) => {
+                let output_type = output.as_ref().map(|output| {
+                    (
+                        self.assignment_target(output, statement.span),
+                        statement.span,
+                    )
+                });
+                let input_types: Vec<_> = inputs
+                    .iter()
+                    .map(|input| (self.expr(input), input.span))
+                    .collect();
+                for (index, (ty, span)) in output_type.into_iter().chain(input_types).enumerate() {
+                    // A register named by its constraint is a general-purpose one
+                    let operator = match constraints.register(index) {
+                        Some(letter) if ty == Some(Type::Double) => {
+                            format!("asm constraint {}", letter)
+                        }
+                        _ if ty == Some(Type::Void) => "asm".to_string(),
+                        _ => continue,
+                    };
+                    let found = ty.unwrap();
+                    self.error(SemaErrorKind::InvalidOperand { operator, found }, span);
+                }
+            }
+        }
+    }
+
+    /// Checks an `if` branch, which gets its own scope even without braces
+    fn scoped(&mut self, statement: &Spanned<Statement>) {
+        self.locals.push_scope();
+        self.statement(statement);
+        self.pop_scope();
+    }
+
+    fn loop_body(&mut self, body: &Spanned<Statement>) {
+        self.loop_depth += 1;
+        self.scoped(body);
+        self.loop_depth -= 1;
+    }
+
+    /// Checks that `expr` has type `expected`
+    fn expect(&mut self, expected: Type, expr: &Spanned<Expr>) {
+        if let Some(found) = self.expr(expr) {
+            if found != expected && !(expected == Type::Double && found == Type::Int) {
+                self.error(SemaErrorKind::TypeMismatch { expected, found }, expr.span);
+            }
+        }
+    }
+
+    /// Type of `expr`, or None if it has an error, which has already been reported
+    fn expr(&mut self, expr: &Spanned<Expr>) -> Option<Type> {
+        match &expr.node {
+            Expr::Literal(Token::Number(value)) => {
+                if *value > i32::MAX as f64 {
+                    self.error(SemaErrorKind::IntegerOutOfRange, expr.span);
+                    return None;
+                }
+                Some(Type::Int)
+            }
+            // The least int can only be written negated, since it's one past the largest
+            Expr::Unary(UnOp::Neg, operand) if is_least_int_magnitude(&operand.node) => {
+                Some(Type::Int)
+            }
+            Expr::Literal(Token::DoubleLiteral(_)) => Some(Type::Double),
+            Expr::Literal(Token::StringLiteral(_)) => Some(Type::String),
+            Expr::Literal(other) => {
+                self.error(
+                    SemaErrorKind::UnsupportedType {
+                        found: other.clone(),
+                    },
+                    expr.span,
+                );
+                None
+            }
+            Expr::Unary(op, operand) => {
+                let ty = self.expr(operand)?;
+                let (operator, valid) = match op {
+                    UnOp::Neg => ("-", ty.is_numeric()),
+                    UnOp::Not => ("!", ty == Type::Int),
+                    UnOp::BitNot => ("~", ty == Type::Int),
+                };
+                if !valid {
+                    self.error(
+                        SemaErrorKind::InvalidOperand {
+                            operator: operator.to_string(),
+                            found: ty,
+                        },
+                        expr.span,
+                    );
+                    return None;
+                }
+                Some(ty)
+            }
+            Expr::Binary(left, op, right) => {
+                let left_type = self.expr(left);
+                let right_type = self.expr(right);
+                let ty = self.operand_type(left, left_type?, right, right_type?)?;
+                let valid = match op {
+                    BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div => ty.is_numeric(),
+                    _ => ty.is_numeric() || ty == Type::Char,
+                };
+                if !valid {
+                    self.error(
+                        SemaErrorKind::InvalidOperand {
+                            operator: binop_symbol(op).to_string(),
+                            found: ty,
+                        },
+                        expr.span,
+                    );
+                    return None;
+                }
+                match op {
+                    BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div => Some(ty),
+                    // Comparisons produce a truth value
+                    _ => Some(Type::Int),
+                }
+            }
+            Expr::Parentheses(inner) => self.expr(inner),
+            Expr::Variable(Token::Identifier(name)) => match self.read(name) {
+                Some(variable) => Some(variable.ty),
+                None => {
+                    let kind = match self.later_globals.get(name) {
+                        Some(definition) => SemaErrorKind::UsedBeforeDefinition {
+                            name: name.clone(),
+                            definition: *definition,
+                        },
+                        None => SemaErrorKind::UndefinedVariable { name: name.clone() },
+                    };
+                    self.error(kind, expr.span);
+                    None
+                }
+            },
+            Expr::Variable(_) => None,
+            Expr::Call(callee, args) => {
+                let Expr::Variable(Token::Identifier(name)) = &callee.node else {
+                    unreachable!("the parser only produces calls to names");
+                };
+                // Outside of a function, this is a global's initializer
+                if self.function.is_none() {
+                    self.error(
+                        SemaErrorKind::CallInGlobalInitializer { name: name.clone() },
+                        callee.span,
+                    );
+                }
+                let Some(signature) = self.functions.get(name) else {
+                    self.error(
+                        SemaErrorKind::UndefinedFunction { name: name.clone() },
+                        callee.span,
+                    );
+                    for arg in args {
+                        self.expr(arg);
+                    }
+                    return None;
+                };
+                let params = signature.params.clone();
+                let return_type = signature.return_type;
+                if params.len() != args.len() {
+                    self.error(
+                        SemaErrorKind::ArgumentCount {
+                            name: name.clone(),
+                            expected: params.len(),
+                            found: args.len(),
+                        },
+                        expr.span,
+                    );
+                }
+                for (param, arg) in params.iter().zip(args) {
+                    self.expect(*param, arg);
+                }
+                Some(return_type)
+            }
+            Expr::Cast(type_token, operand) => {
+                // The parser only accepts casts to int, double and char
+                let target = type_of(type_token)?;
+                let ty = self.expr(operand)?;
+                if !(ty.is_numeric() || ty == Type::Char) {
+                    self.error(
+                        SemaErrorKind::InvalidOperand {
+                            operator: format!("({})", target),
+                            found: ty,
+                        },
+                        expr.span,
+                    );
+                    return None;
+                }
+                Some(target)
+            }
+            Expr::Assign(target, value) => {
+                let ty = self.assignment_target(target, expr.span);
+                match ty {
+                    Some(ty) => self.expect(ty, value),
+                    None => {
+                        self.expr(value);
+                    }
+                }
+                ty
+            }
+            Expr::CompoundAssign(..) => {
+                unreachable!("{:?} is desugared before sema", expr)
+            }
+            Expr::Result if self.return_type == Type::Void => {
+                self.error(SemaErrorKind::ResultInVoidFunction, expr.span);
+                None
+            }
+            Expr::Result => Some(self.return_type),
+            Expr::Old(inner) => self.expr(inner),
+        }
+    }
+
+    /// Common type of a binary operator's operands
+    fn operand_type(
+        &mut self,
+        left: &Spanned<Expr>,
+        left_type: Type,
+        right: &Spanned<Expr>,
+        right_type: Type,
+    ) -> Option<Type> {
+        match (left_type, right_type) {
+            _ if left_type == right_type => return Some(left_type),
+            (Type::Double, Type::Int) | (Type::Int, Type::Double) => return Some(Type::Double),
+            _ => {}
+        }
+        self.error(
+            SemaErrorKind::TypeMismatch {
+                expected: left_type,
+                found: right_type,
+            },
+            left.span.to(right.span),
+        );
+        None
+    }
+
+    /// Type of a variable being assigned to, which must exist and not be constant
+    fn assignment_target(&mut self, target: &LValue, span: Span) -> Option<Type> {
+        let LValue::Variable(Token::Identifier(name)) = target else {
+            return None;
+        };
+        match self.lookup(name) {
+            Some(variable) if variable.is_const => {
+                self.error(SemaErrorKind::AssignToConst { name: name.clone() }, span);
+                None
+            }
+            Some(variable) => Some(variable.ty),
+            None => {
+                self.error(
+                    SemaErrorKind::UndefinedVariable { name: name.clone() },
+                    span,
+                );
+                None
+            }
+        }
+    }
+
+    /// Adds a local variable to the innermost scope. Locals may shadow globals but not each other.
+    fn declare(&mut self, name: &str, variable: Variable) {
+        if let Some(previous) = self.locals.get(name) {
+            self.error(
+                SemaErrorKind::Redeclaration {
+                    name: name.to_string(),
+                    previous: previous.span,
+                },
+                variable.span,
+            );
+            return;
+        }
+        self.locals.insert(name, variable);
+    }
+
+    /// Checks that `value` is a constant expression of type `ty`, and returns its value
+    fn constant_initializer(
+        &mut self,
+        name: &str,
+        ty: Type,
+        value: &Spanned<Expr>,
+    ) -> Option<Constant> {
+        // Type errors are reported on their own
+        let errors = self.errors.len();
+        self.expect(ty, value);
+        if self.errors.len() > errors {
+            return None;
+        }
+        let lookup = |name: &str| {
+            self.lookup(name)
+                .and_then(|variable| variable.value.clone())
+        };
+        match evaluate(&value.node, &lookup) {
+            // An int may initialize a double
+            Some(Constant::Int(value)) if ty == Type::Double => {
+                Some(Constant::Double(value as f64))
+            }
+            Some(constant) => Some(constant),
+            None => {
+                self.error(
+                    SemaErrorKind::NonConstantInitializer {
+                        name: name.to_string(),
+                    },
+                    value.span,
+                );
+                None
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Variable> {
+        self.locals.get(name).or_else(|| self.globals.get(name))
+    }
+
+    /// Looks up a variable whose value is being used
+    fn read(&mut self, name: &str) -> Option<&Variable> {
+        if let Some(local) = self.locals.get_mut(name) {
+            local.is_read = true;
+        }
+        self.lookup(name)
+    }
+
+    /// Closes the innermost local scope, warning about its variables that were never read
+    fn pop_scope(&mut self) {
+        let mut unread: Vec<(String, Variable)> = self
+            .locals
+            .pop_scope()
+            .into_iter()
+            .filter(|(name, variable)| !variable.is_read && !name.starts_with('_'))
+            .collect();
+        unread.sort_by_key(|(_, variable)| variable.span.start);
+        for (name, variable) in unread {
+            let kind = if variable.is_param {
+                SemaWarningKind::UnusedParameter { name }
+            } else {
+                SemaWarningKind::UnusedVariable { name }
+            };
+            self.warnings.push(SemaWarning {
+                function: self.function.clone().unwrap_or_default(),
+                kind,
+                span: variable.span,
+            });
+        }
+    }
+
+    /// Type named by `token`, which must be one a variable can have
+    fn variable_type(&mut self, token: &Token, name: &str, span: Span) -> Option<Type> {
+        match self.resolve_type(token, span)? {
+            Type::Void => {
+                self.error(
+                    SemaErrorKind::VoidVariable {
+                        name: name.to_string(),
+                    },
+                    span,
+                );
+                None
+            }
+            ty => Some(ty),
+        }
+    }
+
+    /// Type named by `token`, reporting types the compiler doesn't support yet
+    fn resolve_type(&mut self, token: &Token, span: Span) -> Option<Type> {
+        let ty = type_of(token);
+        if ty.is_none() {
+            self.error(
+                SemaErrorKind::UnsupportedType {
+                    found: token.clone(),
+                },
+                span,
+            );
+        }
+        ty
+    }
+}
+
+/// Type named by `token`
+pub fn type_of(token: &Token) -> Option<Type> {
+    match token {
+        Token::Int => Some(Type::Int),
+        Token::Double => Some(Type::Double),
+        Token::Char => Some(Type::Char),
+        Token::String => Some(Type::String),
+        Token::Void => Some(Type::Void),
+        _ => None,
+    }
+}
+
+fn binop_symbol(op: &BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Div => "/",
+        BinOp::Equal => "==",
+        BinOp::NotEqual => "!=",
+        BinOp::Less => "<",
+        BinOp::LessEqual => "<=",
+        BinOp::Greater => ">",
+        BinOp::GreaterEqual => ">=",
+    }
+}
+
+/// Cycles of global initializers that read each other, as indices into `globals`. Each cycle
+/// starts at its first global.
+fn initializer_cycles(globals: &[VarDeclaration]) -> Vec<Vec<usize>> {
+    let mut indices = HashMap::new();
+    for (index, global) in globals.iter().enumerate() {
+        if let Token::Identifier(name) = &global.identifier {
+            indices.entry(name.as_str()).or_insert(index);
+        }
+    }
+    // The globals each initializer reads
+    let dependencies: Vec<Vec<usize>> = globals
+        .iter()
+        .map(|global| {
+            let mut names = Vec::new();
+            if let Some(value) = &global.value {
+                variables_read(&value.node, &mut names);
+            }
+            names
+                .iter()
+                .filter_map(|name| indices.get(name.as_str()).copied())
+                .collect()
+        })
+        .collect();
+
+    // Depth-first search, where reaching a global still on the path closes a cycle
+    fn visit(
+        index: usize,
+        dependencies: &[Vec<usize>],
+        visited: &mut [bool],
+        path: &mut Vec<usize>,
+        cycles: &mut Vec<Vec<usize>>,
+    ) {
+        visited[index] = true;
+        path.push(index);
+        for &next in &dependencies[index] {
+            if let Some(position) = path.iter().position(|&on_path| on_path == next) {
+                let mut cycle = path[position..].to_vec();
+                let first = (0..cycle.len()).min_by_key(|&i| cycle[i]).unwrap_or(0);
+                cycle.rotate_left(first);
+                cycles.push(cycle);
+            } else if !visited[next] {
+                visit(next, dependencies, visited, path, cycles);
+            }
+        }
+        path.pop();
+    }
+    let mut visited = vec![false; globals.len()];
+    let mut cycles = Vec::new();
+    for index in 0..globals.len() {
+        if !visited[index] {
+            visit(
+                index,
+                &dependencies,
+                &mut visited,
+                &mut Vec::new(),
+                &mut cycles,
+            );
+        }
+    }
+    cycles.sort();
+    cycles
+}
+
+/// Adds the names of the variables `expr` reads to `names`
+fn variables_read(expr: &Expr, names: &mut Vec<String>) {
+    match expr {
+        Expr::Variable(Token::Identifier(name)) => names.push(name.clone()),
+        Expr::Literal(_) | Expr::Variable(_) | Expr::Result => {}
+        Expr::Unary(_, operand) | Expr::Cast(_, operand) | Expr::Old(operand) => {
+            variables_read(&operand.node, names)
+        }
+        Expr::Parentheses(inner) => variables_read(&inner.node, names),
+        Expr::Binary(left, _, right) => {
+            variables_read(&left.node, names);
+            variables_read(&right.node, names);
+        }
+        // The callee is a function, not a variable
+        Expr::Call(_, args) => {
+            for arg in args {
+                variables_read(&arg.node, names);
+            }
+        }
+        Expr::Assign(_, value) | Expr::CompoundAssign(_, _, value) => {
+            variables_read(&value.node, names)
+        }
+    }
+}
+
+/// Follows every path through `function`'s body. The reads it finds are of locals that were
+/// declared without a value and that some path reaches without assigning, each with the span
+/// of the read; the state left is `None` if no path reaches the end of the body.
+fn follow_paths(function: &FnDeclaration) -> Assignments {
+    let mut assignments = Assignments {
+        scopes: vec![Vec::new()],
+        unassigned: Some(HashSet::new()),
+        loops: Vec::new(),
+        reads: Vec::new(),
+    };
+    for statement in &function.body.statements {
+        assignments.statement(statement);
+    }
+    assignments
+}
+
+// Where control leaves the innermost loop early
+struct LoopExits {
+    breaks: Vec<Option<HashSet<String>>>,
+    continues: Vec<Option<HashSet<String>>>,
+}
+
+/// Follows every path through a function body, tracking which locals might not be assigned yet
+struct Assignments {
+    // Names of the locals declared in each scope, innermost last
+    scopes: Vec<Vec<String>>,
+    // Locals in scope that some path reaches this point without assigning, or None if no path
+    // reaches it
+    unassigned: Option<HashSet<String>>,
+    loops: Vec<LoopExits>,
+    reads: Vec<(String, Span)>,
+}
+
+impl Assignments {
+    fn statement(&mut self, statement: &Spanned<Statement>) {
+        match &statement.node {
+            Statement::Expression(expr) => self.expr(expr),
+            Statement::Print(expr) | Statement::Assert(expr) | Statement::Check(expr) => {
+                self.expr(expr)
+            }
+            Statement::VarDecl(declaration) => {
+                if let Some(value) = &declaration.value {
+                    self.expr(value);
+                }
+                let Token::Identifier(name) = &declaration.identifier else {
+                    return;
+                };
+                // The declaration may shadow a global of the same name
+                self.scopes.last_mut().unwrap().push(name.clone());
+                if let Some(unassigned) = &mut self.unassigned {
+                    if declaration.value.is_none() {
+                        unassigned.insert(name.clone());
+                    } else {
+                        unassigned.remove(name);
+                    }
+                }
+            }
+            Statement::If(condition, then_branch, else_branch) => {
+                self.expr(condition);
+                let before = self.unassigned.clone();
+                self.scoped(then_branch);
+                let after_then = std::mem::replace(&mut self.unassigned, before);
+                if let Some(else_branch) = else_branch {
+                    self.scoped(else_branch);
+                }
+                self.join(after_then);
+            }
+            Statement::While(condition, invariants, body) => {
+                for invariant in invariants {
+                    self.expr(invariant);
+                }
+                self.expr(condition);
+                let exit = self.loop_exit(condition);
+                // Going around again only assigns more, so the body is checked once
+                self.loop_body(body);
+                let exits = self.loops.pop().unwrap();
+                self.unassigned = exit;
+                for state in exits.breaks {
+                    self.join(state);
+                }
+            }
+            Statement::For(..) | Statement::Postfix(..) => {
+                unreachable!("{:?} is desugared before sema", statement)
+            }
+            Statement::Return(value) => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+                self.unassigned = None;
+            }
+            Statement::Block(block) => {
+                self.scopes.push(Vec::new());
+                for statement in &block.statements {
+                    self.statement(statement);
+                }
+                self.pop_scope();
+            }
+            Statement::PrintFormat(_, args) => {
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            Statement::Break => {
+                let state = self.unassigned.take();
+                if let Some(exits) = self.loops.last_mut() {
+                    exits.breaks.push(state);
+                }
+            }
+            Statement::Continue => {
+                let state = self.unassigned.take();
+                if let Some(exits) = self.loops.last_mut() {
+                    exits.continues.push(state);
+                }
+            }
+            Statement::Scan(target) => self.assign(target),
+            Statement::Asm(_, output, inputs, _) => {
+                for input in inputs {
+                    self.expr(input);
+                }
+                // An output is only written
+                if let Some(output) = output {
+                    self.assign(output);
+                }
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Spanned<Expr>) {
+        match &expr.node {
+            Expr::Variable(Token::Identifier(name)) => self.read(name, expr.span),
+            Expr::Literal(_) | Expr::Variable(_) | Expr::Result | Expr::Old(_) => {}
+            Expr::Unary(_, operand) | Expr::Cast(_, operand) | Expr::Parentheses(operand) => {
+                self.expr(operand)
+            }
+            Expr::Binary(left, _, right) => {
+                self.expr(left);
+                self.expr(right);
+            }
+            Expr::Call(_, args) => {
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            Expr::Assign(target, value) => {
+                self.expr(value);
+                self.assign(target);
+            }
+            Expr::CompoundAssign(..) => {
+                unreachable!("{:?} is desugared before sema", expr)
+            }
+        }
+    }
+
+    /// Checks a statement that gets its own scope even without braces
+    fn scoped(&mut self, statement: &Spanned<Statement>) {
+        self.scopes.push(Vec::new());
+        self.statement(statement);
+        self.pop_scope();
+    }
+
+    fn loop_body(&mut self, body: &Spanned<Statement>) {
+        self.loops.push(LoopExits {
+            breaks: Vec::new(),
+            continues: Vec::new(),
+        });
+        self.scoped(body);
+    }
+
+    /// State when a loop's condition is false, which never happens if it's a nonzero constant
+    fn loop_exit(&self, condition: &Spanned<Expr>) -> Option<HashSet<String>> {
+        let forever = matches!(
+            evaluate(&condition.node, &|_| None),
+            Some(Constant::Int(value)) if value != 0
+        );
+        if forever {
+            None
+        } else {
+            self.unassigned.clone()
+        }
+    }
+
+    fn read(&mut self, name: &str, span: Span) {
+        if let Some(unassigned) = &mut self.unassigned {
+            // Reported once; later reads are taken to see the value
+            if unassigned.remove(name) {
+                self.reads.push((name.to_string(), span));
+            }
+        }
+    }
+
+    fn assign(&mut self, target: &LValue) {
+        let LValue::Variable(Token::Identifier(name)) = target else {
+            return;
+        };
+        if let Some(unassigned) = &mut self.unassigned {
+            unassigned.remove(name);
+        }
+    }
+
+    /// Merges in the state of another path to this point
+    fn join(&mut self, other: Option<HashSet<String>>) {
+        let Some(other) = other else {
+            return;
+        };
+        // A local declared on the other path may be out of scope here
+        let in_scope: HashSet<&String> = self.scopes.iter().flatten().collect();
+        let other = other.into_iter().filter(|name| in_scope.contains(name));
+        match &mut self.unassigned {
+            Some(unassigned) => unassigned.extend(other),
+            None => self.unassigned = Some(other.collect()),
+        }
+    }
+
+    /// Closes the innermost scope, whose locals are no longer visible
+    fn pop_scope(&mut self) {
+        let names = self.scopes.pop().unwrap();
+        if let Some(unassigned) = &mut self.unassigned {
+            for name in names {
+                unassigned.remove(&name);
+            }
+        }
+    }
+}
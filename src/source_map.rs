@@ -0,0 +1,190 @@
+//! Source positions.
+//!
+//! Tokens and AST nodes only carry byte offsets (`Span`). Line and column numbers are
+//! computed on demand from a `SourceMap`, which records where each line starts once per file.
+
+/// Half-open byte range `[start, end)` into a source file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// Smallest span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+/// A value together with the source range it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Spanned { node, span }
+    }
+}
+
+/// Line/column lookup table for one source file
+pub struct SourceMap {
+    /// File name, as shown to the user
+    name: String,
+    /// Full contents of the file
+    source: String,
+    /// Byte offset of the first character of each line; always starts with 0
+    line_starts: Vec<usize>,
+}
+
+impl SourceMap {
+    pub fn new(name: &str, source: &str) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+        SourceMap {
+            name: name.to_string(),
+            source: source.to_string(),
+            line_starts,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// Converts a byte offset into a 1-based (line, column) pair.
+    /// Columns count characters, not bytes. Offsets past the end clamp to the end of the file.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.source.len());
+        // Index of the last line start that is <= offset
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next_line) => next_line - 1,
+        };
+        let line_start = self.line_starts[line];
+        let column = self.source[line_start..offset].chars().count();
+        (line + 1, column + 1)
+    }
+
+    /// Text of the given 1-based line, without its trailing newline
+    pub fn line_text(&self, line: usize) -> &str {
+        if line == 0 || line > self.line_starts.len() {
+            return "";
+        }
+        let start = self.line_starts[line - 1];
+        let end = self
+            .line_starts
+            .get(line)
+            .map(|next| next - 1)
+            .unwrap_or(self.source.len());
+        self.source[start..end].trim_end_matches('\r')
+    }
+
+    /// Where to underline `len` characters from `offset`, which is at least one character and
+    /// not past the end of its line
+    pub fn underline(&self, offset: usize, len: usize) -> Underline<'_> {
+        let (line, column) = self.line_col(offset);
+        let text = self.line_text(line);
+        let indent = text
+            .chars()
+            .take(column - 1)
+            // Keep tabs so the underline lines up with the text above
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let rest = text.chars().count().saturating_sub(column - 1);
+        Underline {
+            line,
+            text,
+            indent,
+            width: len.min(rest).max(1),
+        }
+    }
+}
+
+/// A span underlined on the line it starts on
+#[derive(Debug, PartialEq)]
+pub struct Underline<'a> {
+    pub line: usize,    // 1-based
+    pub text: &'a str,  // the whole line, without its newline
+    pub indent: String, // whitespace as wide as the text before the span
+    pub width: usize,   // characters underlined
+}
+
+/// Source file and line of every offset into a linked program, which may span several files,
+/// for the debug information that lets a debugger step through the compiled program
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineTable {
+    /// Names of the files, as they were opened
+    files: Vec<String>,
+    /// Directory the files' relative names are relative to, which is empty if unknown
+    directory: String,
+    /// Offset each line starts at, with the index of its file and its 1-based line, in order
+    lines: Vec<(usize, usize, usize)>,
+}
+
+impl LineTable {
+    pub fn new() -> Self {
+        LineTable::default()
+    }
+
+    /// Records that line `line` of the file `name` starts at `offset`, which is past every
+    /// offset recorded so far
+    pub fn add_line(&mut self, offset: usize, name: &str, line: usize) {
+        let file = match self.files.iter().position(|file| file == name) {
+            Some(file) => file,
+            None => {
+                self.files.push(name.to_string());
+                self.files.len() - 1
+            }
+        };
+        self.lines.push((offset, file, line));
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    pub fn set_directory(&mut self, directory: &str) {
+        self.directory = directory.to_string();
+    }
+
+    pub fn directory(&self) -> &str {
+        &self.directory
+    }
+
+    /// Index of the file `offset` is in, and its 1-based line there, if it's in any
+    pub fn locate(&self, offset: usize) -> Option<(usize, usize)> {
+        let index = self
+            .lines
+            .partition_point(|&(start, _, _)| start <= offset)
+            .checked_sub(1)?;
+        let (_, file, line) = self.lines[index];
+        Some((file, line))
+    }
+
+    /// Offset line `line` of the file numbered `file` starts at, the first time it's recorded
+    pub fn offset(&self, file: usize, line: usize) -> Option<usize> {
+        self.lines
+            .iter()
+            .find(|&&(_, other_file, other_line)| (other_file, other_line) == (file, line))
+            .map(|&(offset, _, _)| offset)
+    }
+}
@@ -0,0 +1,409 @@
+//! Per-function statistics of a compilation, for judging optimization changes.
+//!
+//! `--stats-json` writes them next to the output as `<name>.stats.json`: for each function, how
+//! many abstract assembly instructions the optimization passes left, how many spill slots
+//! register allocation needed, and how many machine instructions the backend selected. The
+//! last two are null for targets that don't have them, like abstract assembly. `stats-diff`
+//! reads two such files back and prints what got worse between them.
+
+use crate::json::json_string;
+use std::collections::HashMap;
+use std::fmt;
+
+/// What code generation made of one function
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub name: String,
+    /// Abstract assembly instructions after the optimization passes, not counting line markers
+    pub instructions: usize,
+    /// Stack slots, or bytes of the spill area on the 6502, that the function's temps were
+    /// spilled to, for targets that allocate registers
+    pub spills: Option<usize>,
+    /// Machine instructions selected for the function, not counting labels, for targets that
+    /// select them
+    pub emitted: Option<usize>,
+}
+
+impl FunctionStats {
+    /// Statistics of a function of `instructions` abstract instructions, before the backend
+    /// has had its say
+    pub fn new(name: &str, instructions: usize) -> Self {
+        FunctionStats {
+            name: name.to_string(),
+            instructions,
+            spills: None,
+            emitted: None,
+        }
+    }
+
+    /// Each measure, by name, with the ones the target doesn't have left out
+    fn measures(&self) -> Vec<(&'static str, usize)> {
+        [
+            ("instructions", Some(self.instructions)),
+            ("spills", self.spills),
+            ("emitted", self.emitted),
+        ]
+        .into_iter()
+        .filter_map(|(measure, value)| value.map(|value| (measure, value)))
+        .collect()
+    }
+}
+
+/// The statistics of every function of a program compiled for `target`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub target: String,
+    pub functions: Vec<FunctionStats>,
+}
+
+impl Stats {
+    /// The statistics as JSON, one function per line, like
+    /// `{"name":"main","instructions":12,"spills":0,"emitted":20}`
+    pub fn to_json(&self) -> String {
+        let optional = |value: Option<usize>| value.map_or("null".to_string(), |v| v.to_string());
+        let functions: Vec<String> = self
+            .functions
+            .iter()
+            .map(|function| {
+                format!(
+                    "    {{\"name\":{},\"instructions\":{},\"spills\":{},\"emitted\":{}}}",
+                    json_string(&function.name),
+                    function.instructions,
+                    optional(function.spills),
+                    optional(function.emitted)
+                )
+            })
+            .collect();
+        format!(
+            "{{\n  \"target\": {},\n  \"functions\": [\n{}\n  ]\n}}\n",
+            json_string(&self.target),
+            functions.join(",\n")
+        )
+    }
+
+    /// Reads statistics back from what `to_json` writes
+    pub fn parse(text: &str) -> Result<Stats, StatsError> {
+        let mut parser = JsonParser { text, offset: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.offset < text.len() {
+            return Err(parser.error("trailing characters after the statistics"));
+        }
+        let Json::Object(fields) = value else {
+            return Err(StatsError::new(0, "the statistics aren't an object"));
+        };
+        let target = match fields.get("target") {
+            Some(Json::String(target)) => target.clone(),
+            _ => return Err(StatsError::new(0, "the target isn't a string")),
+        };
+        let Some(Json::Array(functions)) = fields.get("functions") else {
+            return Err(StatsError::new(0, "the functions aren't an array"));
+        };
+        let functions = functions
+            .iter()
+            .map(function_stats)
+            .collect::<Result<_, _>>()?;
+        Ok(Stats { target, functions })
+    }
+}
+
+/// One function's statistics, from its JSON object
+fn function_stats(value: &Json) -> Result<FunctionStats, StatsError> {
+    let Json::Object(fields) = value else {
+        return Err(StatsError::new(0, "a function isn't an object"));
+    };
+    let Some(Json::String(name)) = fields.get("name") else {
+        return Err(StatsError::new(0, "a function has no name"));
+    };
+    let measure = |measure: &str| match fields.get(measure) {
+        Some(Json::Number(value)) => Ok(Some(*value)),
+        Some(Json::Null) | None => Ok(None),
+        Some(_) => Err(StatsError::new(
+            0,
+            &format!("{} of '{}' isn't a count", measure, name),
+        )),
+    };
+    Ok(FunctionStats {
+        name: name.clone(),
+        instructions: measure("instructions")?.unwrap_or(0),
+        spills: measure("spills")?,
+        emitted: measure("emitted")?,
+    })
+}
+
+/// A measure of a function that differs between two compilations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub function: String,
+    pub measure: &'static str,
+    pub old: usize,
+    pub new: usize,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} {} -> {} ({:+})",
+            self.function,
+            self.measure,
+            self.old,
+            self.new,
+            self.new as i64 - self.old as i64
+        )
+    }
+}
+
+/// The measures of the functions in both `old` and `new` that grew from one to the other
+pub fn regressions(old: &Stats, new: &Stats) -> Vec<Change> {
+    let old_functions: HashMap<&str, &FunctionStats> = old
+        .functions
+        .iter()
+        .map(|function| (function.name.as_str(), function))
+        .collect();
+    let mut changes = Vec::new();
+    for function in &new.functions {
+        let Some(before) = old_functions.get(function.name.as_str()) else {
+            continue;
+        };
+        let before: HashMap<&str, usize> = before.measures().into_iter().collect();
+        for (measure, value) in function.measures() {
+            match before.get(measure) {
+                Some(&old) if value > old => changes.push(Change {
+                    function: function.name.clone(),
+                    measure,
+                    old,
+                    new: value,
+                }),
+                _ => {}
+            }
+        }
+    }
+    changes
+}
+
+/// The report `stats-diff` prints: each regression, the functions only one side has, and
+/// each measure summed over the functions both have
+pub fn diff(old: &Stats, new: &Stats) -> String {
+    let mut report = String::new();
+    if old.target != new.target {
+        report += &format!("targets differ: {} -> {}\n", old.target, new.target);
+    }
+    let regressions = regressions(old, new);
+    for change in &regressions {
+        report += &format!("{}\n", change);
+    }
+    let names = |stats: &Stats| -> Vec<String> {
+        stats
+            .functions
+            .iter()
+            .map(|function| function.name.clone())
+            .collect()
+    };
+    let (old_names, new_names) = (names(old), names(new));
+    for name in old_names.iter().filter(|name| !new_names.contains(name)) {
+        report += &format!("{}: removed\n", name);
+    }
+    for name in new_names.iter().filter(|name| !old_names.contains(name)) {
+        report += &format!("{}: added\n", name);
+    }
+    for measure in ["instructions", "spills", "emitted"] {
+        let total = |stats: &Stats, others: &[String]| -> Option<usize> {
+            stats
+                .functions
+                .iter()
+                .filter(|function| others.contains(&function.name))
+                .map(|function| {
+                    let measures: HashMap<&str, usize> = function.measures().into_iter().collect();
+                    measures.get(measure).copied()
+                })
+                .sum()
+        };
+        if let (Some(before), Some(after)) = (total(old, &new_names), total(new, &old_names)) {
+            report += &format!(
+                "total {}: {} -> {} ({:+})\n",
+                measure,
+                before,
+                after,
+                after as i64 - before as i64
+            );
+        }
+    }
+    report += &format!("{} regression(s)\n", regressions.len());
+    report
+}
+
+/// Why statistics couldn't be read back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsError {
+    /// Byte offset into the file where reading stopped
+    pub offset: usize,
+    pub message: String,
+}
+
+impl StatsError {
+    fn new(offset: usize, message: &str) -> Self {
+        StatsError {
+            offset,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for StatsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at byte {}: {}", self.offset, self.message)
+    }
+}
+
+/// The JSON values the statistics are made of. Numbers are counts, so only those without a
+/// sign, fraction or exponent are read.
+enum Json {
+    Null,
+    Number(usize),
+    String(String),
+    Array(Vec<Json>),
+    Object(HashMap<String, Json>),
+}
+
+struct JsonParser<'a> {
+    text: &'a str,
+    offset: usize,
+}
+
+impl JsonParser<'_> {
+    fn error(&self, message: &str) -> StatsError {
+        StatsError::new(self.offset, message)
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.offset..];
+        self.offset += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.offset..].chars().next()
+    }
+
+    /// Consumes `expected`, after any whitespace
+    fn expect(&mut self, expected: char) -> Result<(), StatsError> {
+        self.skip_whitespace();
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("expected '{}'", expected)));
+        }
+        self.offset += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, StatsError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => self.string().map(Json::String),
+            Some('0'..='9') => {
+                let rest = &self.text[self.offset..];
+                let digits =
+                    rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+                let number = rest[..digits]
+                    .parse()
+                    .map_err(|_| self.error("count out of range"))?;
+                self.offset += digits;
+                Ok(Json::Number(number))
+            }
+            _ if self.text[self.offset..].starts_with("null") => {
+                self.offset += "null".len();
+                Ok(Json::Null)
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, StatsError> {
+        self.expect('{')?;
+        let mut fields = HashMap::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.offset += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.insert(key, self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.offset += 1,
+                Some('}') => {
+                    self.offset += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, StatsError> {
+        self.expect('[')?;
+        let mut elements = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.offset += 1;
+            return Ok(Json::Array(elements));
+        }
+        loop {
+            elements.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.offset += 1,
+                Some(']') => {
+                    self.offset += 1;
+                    return Ok(Json::Array(elements));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, StatsError> {
+        if self.peek() != Some('"') {
+            return Err(self.error("expected a string"));
+        }
+        self.offset += 1;
+        let mut string = String::new();
+        let mut chars = self.text[self.offset..].char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.offset += index + 1;
+                    return Ok(string);
+                }
+                '\\' => {
+                    let escaped = match chars.next() {
+                        Some((_, 'u')) => {
+                            let hex: String = (0..4)
+                                .filter_map(|_| chars.next())
+                                .map(|(_, c)| c)
+                                .collect();
+                            u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+                        }
+                        Some((_, 'n')) => Some('\n'),
+                        Some((_, 't')) => Some('\t'),
+                        Some((_, 'r')) => Some('\r'),
+                        Some((_, c @ ('"' | '\\' | '/'))) => Some(c),
+                        _ => None,
+                    };
+                    let Some(escaped) = escaped else {
+                        self.offset += index;
+                        return Err(self.error("invalid escape"));
+                    };
+                    string.push(escaped);
+                }
+                c => string.push(c),
+            }
+        }
+        self.offset = self.text.len();
+        Err(self.error("unterminated string"))
+    }
+}
@@ -0,0 +1,61 @@
+//! Names in lexical scopes, shared by the passes that resolve variables.
+//!
+//! A scope is opened for each function body, block, branch and loop. A name declared in a scope
+//! hides any binding of it in the scopes around it, and disappears when its scope is closed, so
+//! the outer binding is visible again. Whether hiding a name is allowed at all is up to the pass:
+//! C0 forbids it between locals, which semantic analysis reports.
+
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub struct SymbolTable<T> {
+    // Innermost scope last
+    scopes: Vec<HashMap<String, T>>,
+}
+
+impl<T> Default for SymbolTable<T> {
+    fn default() -> Self {
+        SymbolTable { scopes: Vec::new() }
+    }
+}
+
+impl<T> SymbolTable<T> {
+    /// A table with no scopes open
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    /// Closes the innermost scope, returning everything declared in it
+    pub fn pop_scope(&mut self) -> HashMap<String, T> {
+        self.scopes.pop().expect("no scope to pop")
+    }
+
+    /// Binds `name` in the innermost scope. Returns the binding it replaces in that same
+    /// scope, if any; bindings in outer scopes are only hidden.
+    pub fn insert(&mut self, name: &str, value: T) -> Option<T> {
+        self.scopes
+            .last_mut()
+            .expect("no scope to declare in")
+            .insert(name.to_string(), value)
+    }
+
+    /// The innermost binding of `name`
+    pub fn get(&self, name: &str) -> Option<&T> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut T> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(name))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+}
@@ -0,0 +1,193 @@
+//! Links the compiler's output into an executable with the system's C toolchain.
+//!
+//! The C compiler driver, rather than `ld` itself, does the linking, since it knows where the C
+//! library and the startup files are. It's `$CC` if that's set, and otherwise the first of
+//! `cc`, `gcc` and `clang` on the `PATH`. Unless a sysroot is given, the driver is asked for
+//! its own with `-print-sysroot`, and the linker looks for libraries there.
+
+use crate::diagnostic::Diagnostic;
+use std::env;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Drivers tried in order when `$CC` isn't set
+const DRIVERS: [&str; 3] = ["cc", "gcc", "clang"];
+
+#[derive(Debug)]
+pub enum ToolchainError {
+    /// No C compiler driver was found
+    NotFound,
+    /// The driver couldn't be run
+    Io { driver: PathBuf, source: io::Error },
+    /// The driver ran, and reported these errors
+    LinkFailed { messages: Vec<String> },
+}
+
+impl ToolchainError {
+    /// Error code, explained by `--explain`
+    pub fn code(&self) -> &'static str {
+        "E0203"
+    }
+
+    /// One diagnostic for each error the linker reported, or else for this one
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let messages = match self {
+            ToolchainError::LinkFailed { messages } => messages.clone(),
+            _ => vec![self.to_string()],
+        };
+        messages
+            .into_iter()
+            .map(|message| Diagnostic::error(message, None).with_code(self.code()))
+            .collect()
+    }
+}
+
+impl fmt::Display for ToolchainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ToolchainError::NotFound => write!(
+                f,
+                "No C compiler to link with; set CC or install one of {}",
+                DRIVERS.join(", ")
+            ),
+            ToolchainError::Io { driver, source } => {
+                write!(f, "Failed to run '{}': {}", driver.display(), source)
+            }
+            ToolchainError::LinkFailed { messages } => {
+                write!(f, "Linking failed: {}", messages.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ToolchainError {}
+
+/// What the linker makes of its inputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// An executable, position-independent or not as the driver makes them by default
+    Executable,
+    /// An executable that can be loaded at any address, with `-pie`
+    PieExecutable,
+    /// A shared library, with `-shared`, which needs all its code position-independent
+    SharedLibrary,
+}
+
+/// A C compiler driver to link with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Toolchain {
+    pub driver: PathBuf,
+    pub sysroot: Option<PathBuf>,
+}
+
+impl Toolchain {
+    /// Finds the driver, and its sysroot unless `sysroot` is given
+    pub fn discover(sysroot: Option<PathBuf>) -> Result<Self, ToolchainError> {
+        let driver = match env::var_os("CC").filter(|cc| !cc.is_empty()) {
+            Some(cc) => PathBuf::from(cc),
+            None => DRIVERS
+                .iter()
+                .find_map(|name| find_program(name))
+                .ok_or(ToolchainError::NotFound)?,
+        };
+        let sysroot = sysroot.or_else(|| print_sysroot(&driver));
+        Ok(Toolchain { driver, sysroot })
+    }
+
+    /// Links `inputs`, which may be assembly, C or object files and libraries, into `output`,
+    /// which is an executable or a shared library as `kind` says
+    pub fn link(
+        &self,
+        inputs: &[PathBuf],
+        output: &Path,
+        kind: Output,
+    ) -> Result<(), ToolchainError> {
+        let mut command = Command::new(&self.driver);
+        if let Some(sysroot) = &self.sysroot {
+            command.arg(format!("--sysroot={}", sysroot.display()));
+        }
+        match kind {
+            Output::Executable => {}
+            Output::PieExecutable => {
+                command.arg("-pie");
+            }
+            // C inputs are compiled into the library, so they must be position-independent too
+            Output::SharedLibrary => {
+                command.args(["-shared", "-fPIC"]);
+            }
+        }
+        command.arg("-o").arg(output).args(inputs);
+        let result = command.output().map_err(|source| ToolchainError::Io {
+            driver: self.driver.clone(),
+            source,
+        })?;
+        if result.status.success() {
+            return Ok(());
+        }
+        let mut messages = linker_messages(&String::from_utf8_lossy(&result.stderr));
+        if messages.is_empty() {
+            messages.push(format!("'{}' {}", self.driver.display(), result.status));
+        }
+        Err(ToolchainError::LinkFailed { messages })
+    }
+}
+
+/// The file `name` in one of the directories on the `PATH`
+fn find_program(name: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|program| program.is_file())
+}
+
+/// The sysroot `driver` was configured with, if it has one
+fn print_sysroot(driver: &Path) -> Option<PathBuf> {
+    let output = Command::new(driver).arg("-print-sysroot").output().ok()?;
+    let sysroot = String::from_utf8(output.stdout).ok()?;
+    let sysroot = sysroot.trim();
+    (output.status.success() && !sysroot.is_empty()).then(|| PathBuf::from(sysroot))
+}
+
+/// The errors in what the driver wrote to stderr, one per message. The summaries `collect2`
+/// and `clang` add at the end are left out, and the lines naming the function an error is in
+/// are folded into the errors after them.
+pub fn linker_messages(stderr: &str) -> Vec<String> {
+    let mut messages = Vec::new();
+    let mut function = None;
+    for line in stderr.lines().map(strip_tool).map(str::trim) {
+        if line.is_empty()
+            || line.starts_with("collect2:")
+            || line.ends_with("ld returned 1 exit status")
+            || line.contains("linker command failed")
+        {
+            continue;
+        }
+        if let Some(rest) = line.strip_suffix("':") {
+            if let Some((_, name)) = rest.split_once("in function `") {
+                function = Some(name.to_string());
+                continue;
+            }
+        }
+        match &function {
+            Some(name) => messages.push(format!("{} (in function '{}')", line, name)),
+            None => messages.push(line.to_string()),
+        }
+    }
+    messages
+}
+
+/// `line` without the path of the linker that wrote it, as in `/usr/bin/ld: `
+fn strip_tool(line: &str) -> &str {
+    match line.split_once(": ") {
+        Some((tool, rest))
+            if Path::new(tool)
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("ld")) =>
+        {
+            rest
+        }
+        _ => line,
+    }
+}
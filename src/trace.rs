@@ -0,0 +1,134 @@
+//! Leveled logging, for following what the compiler does while debugging it. Messages are
+//! written to stderr when their level is enabled for the module they come from, by `--verbose`
+//! or by `RUST_LOG`. Like `env_logger`, `RUST_LOG` is a list of comma-separated directives,
+//! each a level, optionally after a module path and `=`, as in
+//! `RUST_LOG=info,rust_compiler::codegen::register_allocator=trace`.
+
+use std::fmt;
+use std::sync::OnceLock;
+
+/// How much a message matters, from the messages shown the most to the least
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The most detailed level enabled for each module
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    /// Each module path prefix, or every module for `None`, with its level
+    directives: Vec<(Option<String>, Level)>,
+}
+
+impl Filter {
+    /// Reads directives like `RUST_LOG`'s. Directives that aren't understood are left out.
+    pub fn parse(spec: &str) -> Self {
+        let directives = spec
+            .split(',')
+            .map(str::trim)
+            .filter_map(|directive| match directive.split_once('=') {
+                Some((module, level)) => Some((Some(module.to_string()), Level::from_name(level)?)),
+                None => Some((None, Level::from_name(directive)?)),
+            })
+            .collect();
+        Filter { directives }
+    }
+
+    /// Enables `level` for every module, unless a directive already sets a level for them all
+    pub fn with_default(mut self, level: Level) -> Self {
+        if !self.directives.iter().any(|(module, _)| module.is_none()) {
+            self.directives.push((None, level));
+        }
+        self
+    }
+
+    /// Whether messages of `level` from `module` are shown. The directive for the longest path
+    /// `module` starts with wins.
+    pub fn enabled(&self, level: Level, module: &str) -> bool {
+        let applies = |prefix: &str| {
+            module == prefix
+                || module
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with("::"))
+        };
+        self.directives
+            .iter()
+            .filter(|(prefix, _)| prefix.as_deref().is_none_or(applies))
+            .max_by_key(|(prefix, _)| prefix.as_ref().map_or(0, |prefix| prefix.len() + 1))
+            .is_some_and(|(_, enabled)| level <= *enabled)
+    }
+}
+
+static FILTER: OnceLock<Filter> = OnceLock::new();
+
+/// Shows messages as `filter` says, from now on. Only the first call has an effect; until
+/// then, nothing is shown.
+pub fn init(filter: Filter) {
+    let _ = FILTER.set(filter);
+}
+
+pub fn enabled(level: Level, module: &str) -> bool {
+    FILTER
+        .get()
+        .is_some_and(|filter| filter.enabled(level, module))
+}
+
+/// Writes a message, which the macros have checked is enabled
+pub fn write(level: Level, module: &str, message: fmt::Arguments) {
+    eprintln!("[{} {}] {}", level, module, message);
+}
+
+/// Logs a message at `level`, formatted like `format!` if it's shown
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::trace::enabled($level, module_path!()) {
+            $crate::trace::write($level, module_path!(), format_args!($($arg)+));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::trace::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::trace::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::trace::Level::Trace, $($arg)+) };
+}
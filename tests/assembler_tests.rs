@@ -0,0 +1,155 @@
+use rust_compiler::codegen::assembler::{
+    assemble, AssembleError, Item, Relocation, RelocationKind,
+};
+use rust_compiler::codegen::x86_encoding::{Condition, Memory, Op, RegOrMem, Register};
+
+fn label(name: &str) -> Item {
+    Item::Label(name.to_string())
+}
+
+fn nops(count: usize) -> Vec<Item> {
+    (0..count).map(|_| Item::Op(Op::Nop)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_jumps_forward_and_back() {
+        let mut items = vec![label("top"), Item::Jcc(Condition::E, "out".to_string())];
+        items.extend(nops(3));
+        items.push(Item::Jmp("top".to_string()));
+        items.push(label("out"));
+        items.push(Item::Op(Op::Ret));
+
+        let assembly = assemble(items).unwrap();
+        // je out; nop x3; jmp top; ret
+        assert_eq!(
+            assembly.bytes,
+            [0x74, 0x05, 0x90, 0x90, 0x90, 0xeb, 0xf9, 0xc3]
+        );
+        assert_eq!(
+            assembly.labels,
+            [("top".to_string(), 0), ("out".to_string(), 7)]
+        );
+        assert!(assembly.relocations.is_empty());
+    }
+
+    #[test]
+    fn test_jumps_too_far_are_lengthened() {
+        // 127 bytes away fits in a short jump, and 128 doesn't
+        let mut items = vec![Item::Jmp("near".to_string())];
+        items.extend(nops(127));
+        items.push(label("near"));
+        let assembly = assemble(items).unwrap();
+        assert_eq!(&assembly.bytes[..2], [0xeb, 0x7f]);
+
+        let mut items = vec![Item::Jcc(Condition::Ne, "far".to_string())];
+        items.extend(nops(128));
+        items.push(label("far"));
+        let assembly = assemble(items).unwrap();
+        assert_eq!(&assembly.bytes[..6], [0x0f, 0x85, 0x80, 0x00, 0x00, 0x00]);
+        assert_eq!(assembly.label("far"), Some(134));
+    }
+
+    #[test]
+    fn test_lengthening_moves_other_targets() {
+        // The first jump reaches its target, until the second one, in between, is lengthened
+        let mut items = vec![
+            Item::Jmp("middle".to_string()),
+            Item::Jmp("end".to_string()),
+        ];
+        items.extend(nops(123));
+        items.push(label("middle"));
+        items.extend(nops(10));
+        items.push(label("end"));
+        let assembly = assemble(items).unwrap();
+        assert_eq!(
+            &assembly.bytes[..10],
+            [0xe9, 0x80, 0x00, 0x00, 0x00, 0xe9, 0x85, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(assembly.label("middle"), Some(133));
+    }
+
+    #[test]
+    fn test_external_symbols_are_relocated() {
+        let items = vec![
+            label("main"),
+            Item::Call("c0_print_int".to_string()),
+            Item::Call("main".to_string()),
+            Item::Jmp("elsewhere".to_string()),
+        ];
+        let assembly = assemble(items).unwrap();
+        assert_eq!(
+            assembly.bytes,
+            [
+                0xe8, 0x00, 0x00, 0x00, 0x00, // call c0_print_int
+                0xe8, 0xf6, 0xff, 0xff, 0xff, // call main
+                0xe9, 0x00, 0x00, 0x00, 0x00, // jmp elsewhere
+            ]
+        );
+        assert_eq!(
+            assembly.relocations,
+            [
+                Relocation {
+                    offset: 1,
+                    symbol: "c0_print_int".to_string(),
+                    kind: RelocationKind::Plt32,
+                    addend: -4,
+                },
+                Relocation {
+                    offset: 11,
+                    symbol: "elsewhere".to_string(),
+                    kind: RelocationKind::Pc32,
+                    addend: -4,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rip_relative_addresses() {
+        let items = vec![
+            // leaq .LS0+8(%rip), %rdi
+            Item::Rip(Op::Lea(Register::RDI, Memory::rip(8)), ".LS0".to_string()),
+            // cmpl $1, local(%rip), with an immediate after the displacement
+            Item::Rip(
+                Op::Cmp(
+                    RegOrMem::Memory(Memory::rip(0).with_bits(32)),
+                    RegOrMem::Immediate(1),
+                ),
+                "local".to_string(),
+            ),
+            label("local"),
+            Item::Op(Op::Ret),
+        ];
+        let assembly = assemble(items).unwrap();
+        assert_eq!(
+            assembly.bytes,
+            [
+                0x48, 0x8d, 0x3d, 0x00, 0x00, 0x00, 0x00, // leaq .LS0+8(%rip), %rdi
+                0x83, 0x3d, 0x00, 0x00, 0x00, 0x00, 0x01, // cmpl $1, local(%rip)
+                0xc3,
+            ]
+        );
+        assert_eq!(
+            assembly.relocations,
+            [Relocation {
+                offset: 3,
+                symbol: ".LS0".to_string(),
+                kind: RelocationKind::Pc32,
+                addend: 4,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_labels() {
+        let items = vec![label("main"), Item::Op(Op::Ret), label("main")];
+        assert_eq!(
+            assemble(items).unwrap_err(),
+            AssembleError::DuplicateLabel("main".to_string())
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use rust_compiler::codegen::object::Format;
+use rust_compiler::codegen::{
+    backend, default_backend, generate_from_ir, parse_ir, triples, CodegenFailure, CodegenOptions,
+};
+use std::env;
+use std::fs;
+use std::io;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backends_are_found_by_triple() {
+        let mut seen = triples();
+        seen.dedup();
+        assert_eq!(seen, triples());
+        for triple in triples() {
+            assert_eq!(backend(triple).unwrap().triple(), triple);
+        }
+        assert_eq!(default_backend().triple(), "abstract");
+        assert!(backend("x86_64-apple-darwin").is_none());
+
+        // Only the x86-64 and RISC-V backends have output that links
+        let options = CodegenOptions::default();
+        let x86 = backend("x86_64").unwrap();
+        assert_eq!(x86.object_format(), Some(Format::Elf));
+        assert!(!x86.registers(&options).unwrap().int.is_empty());
+        let windows = backend("x86_64-pc-windows").unwrap();
+        assert_eq!(windows.object_format(), Some(Format::Coff));
+        // Fewer xmm registers survive a call under the Microsoft calling convention
+        let doubles = |triple| {
+            let registers = backend(triple).unwrap().registers(&options).unwrap();
+            registers.double.len()
+        };
+        assert!(doubles("x86_64-pc-windows") < doubles("x86_64"));
+        assert!(default_backend().registers(&options).is_none());
+        assert!(default_backend().object_format().is_none());
+
+        // The 6502's registers are the zero-page window's bytes, past the backend's own
+        let m6502 = backend("m6502").unwrap();
+        let options = CodegenOptions {
+            zero_page: 0x80..=0x9f,
+            ..CodegenOptions::default()
+        };
+        let registers = m6502.registers(&options).unwrap();
+        assert_eq!(registers.int, (0x87..=0x9f).collect::<Vec<usize>>());
+        assert_eq!(registers.callee_saved, registers.int);
+        assert!(registers.double.is_empty());
+        assert!(m6502.object_format().is_none());
+
+        // RISC-V keeps temps live across calls in s1 to s11, the callee-saved registers
+        let riscv = backend("riscv32").unwrap();
+        assert_eq!(riscv.object_format(), Some(Format::Elf));
+        let registers = riscv.registers(&options).unwrap();
+        assert_eq!(
+            registers.callee_saved,
+            [9].into_iter().chain(18..=27).collect::<Vec<_>>()
+        );
+        assert!(registers
+            .callee_saved
+            .iter()
+            .all(|register| registers.int.contains(register)));
+        assert!(registers.double.is_empty());
+    }
+
+    #[test]
+    fn test_each_backend_writes_the_program() {
+        let dir = env::temp_dir().join(format!("rust-compiler-backends-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for triple in [
+            "abstract",
+            "x86_64",
+            "x86_64-pc-windows",
+            "m6502",
+            "riscv32",
+        ] {
+            let module = parse_ir(".globl main\n.main\n%t0 <- $2\n%eax <- %t0\nret\n").unwrap();
+            let outpath = dir.join(triple);
+            let backend = backend(triple).unwrap();
+            generate_from_ir(module, backend, CodegenOptions::default(), &outpath).unwrap();
+            let output = fs::read_to_string(&outpath).unwrap();
+            assert!(output.contains("main"), "{}: {}", triple, output);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_m6502_rejects_what_it_cannot_compile() {
+        let dir = env::temp_dir().join(format!("rust-compiler-m6502-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let outpath = dir.join("m6502");
+        let m6502 = backend("m6502").unwrap();
+        let cases = [
+            (
+                ".globl main\n.main\n%t0 <- $2\n%eax <- %t0\nret\n",
+                0x02..=0x07,
+            ),
+            (
+                ".globl main\n.main\nprint %f $2.5\n%eax <- $0\nret\n",
+                0x02..=0x7f,
+            ),
+        ];
+        for (ir, zero_page) in cases {
+            let module = parse_ir(ir).unwrap();
+            let options = CodegenOptions {
+                zero_page,
+                ..CodegenOptions::default()
+            };
+            let Err(CodegenFailure::Io(error)) = generate_from_ir(module, m6502, options, &outpath)
+            else {
+                panic!("{} compiled for the 6502", ir);
+            };
+            assert!(matches!(
+                error.kind(),
+                io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported
+            ));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,567 @@
+mod common;
+
+use common::{compiler, setup_workdir};
+use rust_compiler::codegen::{
+    self, run_with_io, CodegenOptions, RunResult, RuntimeError, Undefined,
+};
+use rust_compiler::{desugar, lexer, parser, sema};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Checks that the C at `path` is C99 that compiles without warnings
+fn assert_strict_c99(path: &Path) {
+    let output = Command::new("cc")
+        .args([
+            "-std=c99",
+            "-pedantic-errors",
+            "-Wall",
+            "-Werror",
+            "-fsyntax-only",
+        ])
+        .arg(path)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}\n{}",
+        String::from_utf8_lossy(&output.stderr),
+        fs::read_to_string(path).unwrap()
+    );
+}
+
+/// Translates `source` to C with `flags`, and returns it
+fn translate(dirname: &str, source: &str, flags: &[&str]) -> String {
+    let workdir = setup_workdir(dirname, "sample", source);
+    let output = compiler(&workdir, "sample", &[&["--emit=c"], flags].concat());
+    assert!(output.status.success());
+    let path = workdir.join("samples").join("target").join("sample.c");
+    assert_strict_c99(&path);
+    let c = fs::read_to_string(path).unwrap();
+    fs::remove_dir_all(workdir).unwrap();
+    c
+}
+
+/// Translates `source` to C with `flags`, compiles it and runs it on `input`, returning what it
+/// printed and its exit code, which is None if it aborted
+fn run_c(dirname: &str, source: &str, flags: &[&str], input: &str) -> (String, Option<i32>) {
+    let workdir = setup_workdir(dirname, "sample", source);
+    let output = compiler(
+        &workdir,
+        "sample",
+        &[&["--emit=c", "--link"], flags].concat(),
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let target = workdir.join("samples").join("target");
+    assert_strict_c99(&target.join("sample.c"));
+    let mut child = Command::new(target.join("sample"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(input.as_bytes()).unwrap();
+    drop(stdin);
+    let run = child.wait_with_output().unwrap();
+    fs::remove_dir_all(workdir).unwrap();
+    (String::from_utf8(run.stdout).unwrap(), run.status.code())
+}
+
+/// Runs `source` in the interpreter on `input`, with contracts if `contracts`
+fn interpret_source(source: &str, contracts: bool, input: &str) -> Result<RunResult, RuntimeError> {
+    let program = parser::parse_with_spans(lexer::tokenize_with_spans(source)).unwrap();
+    let program = desugar::desugar(program);
+    assert!(sema::check(&program).is_ok());
+    let program = if contracts {
+        program
+    } else {
+        desugar::strip_contracts(program)
+    };
+    let module = codegen::lower(program, &CodegenOptions::default()).unwrap();
+    run_with_io(&module, input.as_bytes())
+}
+
+/// Checks that `source` compiled to C does what the interpreter does on `input`, with
+/// contracts if `contracts`
+fn assert_matches_interpreter(dirname: &str, source: &str, contracts: bool, input: &str) {
+    let expected = match interpret_source(source, contracts, input) {
+        Ok(result) => result,
+        Err(error) => panic!("{}", error),
+    };
+    let flags: &[&str] = if contracts { &["-d"] } else { &[] };
+    assert_eq!(
+        run_c(dirname, source, flags, input),
+        (expected.stdout, expected.exit_code)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = r#"
+extern int abs(int x);
+
+const int LIMIT = 3 * 4;
+double scale = 2;
+static const string NAME = "c0 \"compiler\"??!";
+
+int fib(int n) {
+    if (n < 2) return n;
+    return fib(n - 1) + fib(n - 2);
+}
+
+static double half(double x) {
+    return x / 2.0;
+}
+
+void greet(string name, char initial) {
+    print("%s %c\n", name, initial);
+}
+
+int main() {
+    greet("hello", (char)104);
+    print("%d %f\n", fib(10), half(3.0));
+    if (fib(3) > 100000) return abs(-3);
+    return fib(12);
+}
+"#;
+
+    #[test]
+    fn test_declarations() {
+        let c = translate("c99-declarations", PROGRAM, &[]);
+        assert!(
+            c.starts_with("#include <inttypes.h>\n#include <stdio.h>\n"),
+            "{}",
+            c
+        );
+        // Every function is declared before any is defined
+        for prototype in [
+            "int32_t abs(int32_t);\n",
+            "int32_t fib(int32_t n);\n",
+            "static double half(double x);\n",
+            "void greet(const char *name, unsigned char initial);\n",
+            "int main(void);\n",
+        ] {
+            assert!(c.contains(prototype), "{}", c);
+            assert!(
+                c.find(prototype) < c.find("int32_t fib(int32_t n) {"),
+                "{}",
+                c
+            );
+        }
+        assert!(c.contains("static double half(double x) {\n"), "{}", c);
+        assert!(c.contains("int main(void) {\n"), "{}", c);
+        // Globals are initialized with their values
+        assert!(c.contains("const int32_t LIMIT = 12;\n"), "{}", c);
+        assert!(c.contains("double scale = 2.0;\n"), "{}", c);
+        assert!(
+            c.contains(r#"static const char *const NAME = "c0 \"compiler\"?\?!";"#),
+            "{}",
+            c
+        );
+        assert!(
+            c.contains("printf(\"%s %c\\n\", name, initial);\n"),
+            "{}",
+            c
+        );
+        assert!(c.contains("return x / 2.0;\n"), "{}", c);
+        // Only the helpers used are written
+        assert!(c.contains("static int32_t c0_add(int32_t a, int32_t b) {"));
+        assert!(!c.contains("c0_div"), "{}", c);
+        assert!(!c.contains("c0_abort"), "{}", c);
+        assert!(!c.contains("c0_check"), "{}", c);
+    }
+
+    #[test]
+    fn test_runs_like_the_interpreter() {
+        let (output, code) = run_c("c99-program", PROGRAM, &[], "");
+        assert_eq!(output, "hello h\n55 1.500000\n");
+        assert_eq!(code, Some(144));
+        assert_matches_interpreter("c99-program-interpreter", PROGRAM, false, "");
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let source = r#"
+int main() {
+    int big = 2147483647;
+    double zero = 0.0;
+    double nan = zero / zero;
+    double huge = 100000.5 * 100000.5 * 100000.5;
+    char high = (char)200;
+    print("%d %d %d %d\n", big + 1, -big - 2, big * 3, -(-2147483648));
+    print("%d %d %d\n", 7 / 2, -7 / 2, (-2147483647 - 1) / 2);
+    print("%d %d %d\n", (int)2147483647.5, (int)(-2147483648.75), (int)2.75);
+    print("%c %d %d %c\n", (char)(65 + 256), (int)high, high > (char)10, (char)66.5);
+    print("%d %d %d\n", nan == nan, nan != nan, nan < 1.5);
+    print("%f %f %f %f\n", 5 / 2 * 1.5, 1 / 3.5, -(2.5), -huge);
+    print("%d %d %d\n", ~5, !0, !big);
+    print(1.5 + 1);
+    return big + 1;
+}
+"#;
+        assert_matches_interpreter("c99-arithmetic", source, false, "");
+        let c = translate("c99-arithmetic-text", source, &[]);
+        // The least int is written so that C doesn't negate one too large to be an int
+        assert!(c.contains("INT32_MIN"), "{}", c);
+        assert!(c.contains("c0_d2i(2.75)"), "{}", c);
+    }
+
+    #[test]
+    fn test_out_of_range_conversions_abort() {
+        let source = r#"
+int convert(double value) {
+    return (int)value;
+}
+
+int main() {
+    double zero = 0.0;
+    print("%d\n", convert(-2147483648.75));
+    print("%d\n", convert(zero / zero));
+    return 0;
+}
+"#;
+        assert_eq!(
+            interpret_source(source, false, ""),
+            Err(RuntimeError::Undefined {
+                behavior: Undefined::ConversionOverflow,
+                location: None
+            })
+        );
+        let (output, code) = run_c("c99-conversion", source, &[], "");
+        assert_eq!(output, "-2147483648\n");
+        assert_eq!(code, None);
+        for (value, expected) in [
+            ("10000000000.5", None),
+            ("2147483647.9", Some(255)),
+            ("(-2147483649.0)", None),
+        ] {
+            let source = format!("int main() {{\n    return (int){};\n}}\n", value);
+            let (_, code) = run_c("c99-conversion-range", &source, &[], "");
+            assert_eq!(code, expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_division_aborts() {
+        let source = r#"
+int divide(int a, int b) {
+    return a / b;
+}
+
+int main() {
+    print("%d\n", divide(7, 2));
+    print("%d\n", divide(-2147483647 - 1, -1));
+    return 0;
+}
+"#;
+        assert_eq!(
+            interpret_source(source, false, ""),
+            Err(RuntimeError::Undefined {
+                behavior: Undefined::DivisionOverflow,
+                location: None
+            })
+        );
+        let (output, code) = run_c("c99-division", source, &[], "");
+        // What was printed before is flushed
+        assert_eq!(output, "3\n");
+        assert_eq!(code, None);
+    }
+
+    #[test]
+    fn test_evaluation_order() {
+        let source = r#"
+int g = 1;
+
+int trace(int x) {
+    print("%d ", x);
+    return x;
+}
+
+int bump() {
+    g = g * 10;
+    return g;
+}
+
+int add(int a, int b) {
+    return a + b;
+}
+
+int main() {
+    int x = 1;
+    int y = trace(1) - trace(2) * trace(3);
+    print("\n%d\n", y);
+    y = (x = 5) + x * 2;
+    print("%d %d\n", x, y);
+    print("%d %d %d\n", trace(4), x += 1, x);
+    x = trace(7) + (x = 8);
+    print("\n%d %d\n", x, trace(y) + trace(x));
+    print("%d %d %d\n", add(g, bump()), g + bump(), bump() - g);
+    return trace(9) / trace(3);
+}
+"#;
+        assert_matches_interpreter("c99-order", source, false, "");
+        let c = translate("c99-order-text", source, &[]);
+        // Operands are saved in order when more than one calls a function
+        assert!(
+            c.contains("(c0_t0 = trace(1), c0_t3 = (c0_t1 = trace(2), c0_t2 = trace(3), "),
+            "{}",
+            c
+        );
+        // or when one calls a function and another reads a global it could write
+        assert!(c.contains(" = g, c0_t"), "{}", c);
+        assert!(c.contains(" = bump(), add(c0_t"), "{}", c);
+        assert!(c.contains(" = bump(), c0_add(c0_t"), "{}", c);
+        // but not when no operand does
+        assert!(
+            c.contains("printf(\"%\" PRId32 \" %\" PRId32 \"\\n\", x, y);"),
+            "{}",
+            c
+        );
+    }
+
+    #[test]
+    fn test_contracts() {
+        let source = r#"
+int sum(int n)
+//@requires n >= 0;
+//@ensures \result == \old(n) * (\old(n) + 1) / 2;
+{
+    int total = 0;
+    for (int i = 1; i <= n; i++)
+    //@loop_invariant i >= 1;
+    {
+        total += i;
+    }
+    n = 0;
+    return total;
+}
+
+void check(int x)
+//@ensures x > 0;
+{
+    if (x < 0) return;
+    print("%d\n", x);
+}
+
+int main() {
+    int i = 3;
+    print("%d\n", sum(10));
+    while (i > 0)
+    //@loop_invariant i >= 0;
+    {
+        i--;
+        //@assert i < 3;
+    }
+    check(5);
+    check(-1);
+    return sum(-1);
+}
+"#;
+        assert_matches_interpreter("c99-contracts", source, true, "");
+        assert_matches_interpreter("c99-no-contracts", source, false, "");
+        let (output, code) = run_c("c99-contracts-run", source, &["-d"], "");
+        assert_eq!(output, "55\n5\n@ensures annotation failed in check\n");
+        assert_eq!(code, None);
+        let c = translate("c99-contracts-text", source, &["-d"]);
+        assert!(
+            c.contains("c0_check(n >= 0, \"@requires annotation failed in sum\\n\");"),
+            "{}",
+            c
+        );
+        assert!(c.contains("int32_t c0_result = total;"), "{}", c);
+        // Without `-d`, contracts aren't checked
+        let c = translate("c99-contracts-unchecked", source, &[]);
+        assert!(!c.contains("c0_check"), "{}", c);
+    }
+
+    #[test]
+    fn test_assert_is_checked_without_d() {
+        let source = r#"
+int check(int x) {
+    assert(x > 0);
+    return x;
+}
+
+int main() {
+    print("%d\n", check(1));
+    return check(-1);
+}
+"#;
+        assert_matches_interpreter("c99-assert", source, false, "");
+        let (output, code) = run_c("c99-assert-run", source, &[], "");
+        assert_eq!(output, "1\nassert failed in check\n");
+        assert_eq!(code, None);
+    }
+
+    #[test]
+    fn test_asm_constraints_and_clobbers() {
+        let source = "int main() {\n    int n = 3;\n    int x;\n    int y;\n    asm(\"movl %1, %0; shll %%cl, %0\" : \"=a\"(x) : \"r\"(5), \"c\"(n) : \"cc\");\n    asm(\"movl $42, %%edx; movl %%edx, %0\" : \"=r\"(y) : : \"rdx\");\n    print(\"%d %d\\n\", x, y);\n    return 0;\n}\n";
+        let c = translate("c99-asm", source, &[]);
+        assert!(
+            c.contains(" : \"=a\"(x) : \"r\"(5), \"c\"(n) : \"cc\");"),
+            "{}",
+            c
+        );
+        assert!(c.contains(" : \"=r\"(y) : : \"rdx\");"), "{}", c);
+        assert_eq!(
+            run_c("c99-asm-run", source, &[], ""),
+            ("40 42\n".to_string(), Some(0))
+        );
+    }
+
+    #[test]
+    fn test_scan_reads_standard_input() {
+        let source = "double scale;\nint main() {\n    int count;\n    scan(count);\n    scan(scale);\n    while (count > 0) {\n        int value;\n        scan(value);\n        print(\"%d %f\\n\", value, value * scale);\n        count--;\n    }\n    return 0;\n}\n";
+        let c = translate("c99-scan", source, &[]);
+        assert!(c.contains("count = c0_scan_int();"), "{}", c);
+        assert!(c.contains("scale = c0_scan_double();"), "{}", c);
+        // Ints too big wrap around
+        let (output, code) = run_c("c99-scan-run", source, &[], "2 .5 -7\n4294967299");
+        assert_eq!(output, "-7 -3.500000\n3 1.500000\n");
+        assert_eq!(code, Some(0));
+        let (output, code) = run_c("c99-scan-abort", source, &[], "2 1.0 7");
+        assert_eq!(output, "7 7.000000\n");
+        assert_eq!(code, None);
+        for input in ["2 .5 -7\n4294967299", "2 1.0 7", "1 1e3 x"] {
+            assert_matches_interpreter("c99-scan-interpreter", source, false, input);
+        }
+    }
+
+    #[test]
+    fn test_names() {
+        let source = r#"
+int stdout = 3;
+double FILE = 1.5;
+
+int int32_t(int printf) {
+    return printf + 1;
+}
+
+int c0_add(int fflush) {
+    return fflush * 2;
+}
+
+int main() {
+    int int32_t = 4;
+    int c0_t0 = 2;
+    int _Bool = 1;
+    int __x = 1;
+    int unsigned_ = int32_t(c0_t0) + c0_add(_Bool);
+    double errno = 0.5;
+    print("%d %d %f\n", int32_t, unsigned_, errno);
+    return int32_t(__x) + c0_add(int32_t);
+}
+"#;
+        assert_matches_interpreter("c99-names", source, false, "");
+        let c = translate("c99-names-text", source, &[]);
+        assert!(
+            c.contains("int32_t c0_f_int32_t(int32_t c0_v_printf) {"),
+            "{}",
+            c
+        );
+        assert!(
+            c.contains("int32_t c0_f_c0_add(int32_t c0_v_fflush) {"),
+            "{}",
+            c
+        );
+        assert!(c.contains("double c0_g_FILE = 1.5;"), "{}", c);
+        assert!(c.contains("int32_t c0_g_stdout = 3;"), "{}", c);
+        assert!(c.contains("int32_t c0_v_int32_t = 4;"), "{}", c);
+    }
+
+    #[test]
+    fn test_mangling() {
+        let source = r#"
+extern int abs(int x);
+
+int fib(int n) {
+    if (n < 2) return n;
+    return fib(n - 1) + fib(n - 2);
+}
+
+int main() {
+    int _c0_fib = 7;
+    return fib(_c0_fib) + abs(-1);
+}
+"#;
+        let c = translate("c99-mangling", source, &["--mangle"]);
+        assert!(c.contains("int32_t _c0_fib(int32_t n) {"), "{}", c);
+        assert!(c.contains("int main(void) {"), "{}", c);
+        // Only the program's own functions are renamed, and variables make way for them
+        assert!(c.contains("abs(-1)"), "{}", c);
+        assert!(c.contains("int32_t c0_v__c0_fib = 7;"), "{}", c);
+        let (_, code) = run_c("c99-mangling-run", source, &["--mangle"], "");
+        assert_eq!(code, Some(14));
+    }
+
+    #[test]
+    fn test_functions_read_and_write_globals() {
+        let source = r#"
+const int STEP = 3;
+int count = 1;
+static double scale = 0.5;
+string FILE = "globals";
+
+int bump(int by) {
+    count = count + by * STEP;
+    scale *= 2.0;
+    return count;
+}
+
+int main() {
+    bump(1);
+    int total = bump(2);
+    {
+        int count = 100;
+        total += count;
+    }
+    print("%d %f %s\n", count, scale, FILE);
+    return total + count;
+}
+"#;
+        assert_matches_interpreter("c99-globals", source, false, "");
+        let c = translate("c99-globals-text", source, &["--mangle"]);
+        assert!(
+            c.contains("_c0_count = c0_add(_c0_count, c0_mul(by, _c0_STEP));"),
+            "{}",
+            c
+        );
+        // The local hides the global, and keeps its own name
+        assert!(c.contains("int32_t count = 100;"), "{}", c);
+        assert!(c.contains("total = c0_add(total, count);"), "{}", c);
+        let (output, code) = run_c("c99-globals-run", source, &["--mangle"], "");
+        assert_eq!(output, "10 2.000000 globals\n");
+        assert_eq!(code, Some(120));
+    }
+
+    #[test]
+    fn test_commands() {
+        let workdir = setup_workdir("c99-commands", "sample", PROGRAM);
+        // Any target can write C, and it's linked for the host
+        let output = compiler(
+            &workdir,
+            "sample",
+            &["--emit=c", "--target=m6502", "--link"],
+        );
+        assert!(output.status.success());
+        let target = workdir.join("samples").join("target");
+        assert!(!target.join("sample.runtime.S").exists());
+        let run = Command::new(target.join("sample")).output().unwrap();
+        assert_eq!(run.status.code(), Some(144));
+
+        // C is translated from source, so not from abstract assembly
+        fs::write(workdir.join("samples").join("copy.o0"), "").unwrap();
+        let output = compiler(&workdir, "copy", &["--from-ir", "--emit=c"]);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("Usage:"), "{}", stderr);
+        assert!(!target.join("copy.c").exists());
+        fs::remove_dir_all(workdir).unwrap();
+    }
+}
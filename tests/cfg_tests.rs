@@ -0,0 +1,124 @@
+mod common;
+
+use common::{branch, build, jmp, label, ret};
+use rust_compiler::codegen::cfg::ControlFlowGraph;
+use rust_compiler::codegen::{
+    AbstractAssemblyInstruction, Arithmetic, AsmLabel, Condition, Dest, Operand,
+};
+
+fn mov(temp: usize, value: i128) -> AbstractAssemblyInstruction {
+    AbstractAssemblyInstruction::Mov {
+        dest: Dest::Temp(temp),
+        src: Operand::Const(value),
+    }
+}
+
+fn compare(temp: usize, value: i128) -> AbstractAssemblyInstruction {
+    AbstractAssemblyInstruction::Compare {
+        arithmetic: Arithmetic::Int,
+        left: Operand::Var(Dest::Temp(temp)),
+        right: Operand::Const(value),
+        condition: Condition::Less,
+    }
+}
+
+/// Label numbers of `blocks`, in order
+fn labels(cfg: &ControlFlowGraph, blocks: &[usize]) -> Vec<usize> {
+    blocks
+        .iter()
+        .map(|&block| cfg.blocks[block].label.0)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_straight_line_code_is_one_block() {
+        let cfg = build(vec![mov(0, 1), mov(1, 2), ret(1)]);
+        assert_eq!(cfg.len(), 1);
+        assert_eq!(cfg.blocks[0].label.0, 100);
+        assert_eq!(cfg.blocks[0].instructions.len(), 3);
+        assert!(cfg.successors(0).is_empty());
+        assert!(cfg.predecessors(0).is_empty());
+    }
+
+    #[test]
+    fn test_blocks_split_at_labels_and_jumps() {
+        // if (t0 < 5) { t1 = 1 } else { t1 = 2 }; return t1
+        let cfg = build(vec![
+            mov(0, 3),
+            compare(0, 5),
+            branch(1, 2),
+            label(1),
+            mov(1, 1),
+            jmp(3),
+            label(2),
+            mov(1, 2),
+            label(3),
+            ret(1),
+        ]);
+        assert_eq!(labels(&cfg, &[0, 1, 2, 3]), vec![100, 1, 2, 3]);
+        assert_eq!(cfg.successors(0), [1, 2]);
+        assert_eq!(cfg.successors(1), [3]);
+        // The else block falls through into the join
+        assert_eq!(cfg.successors(2), [3]);
+        assert_eq!(cfg.predecessors(3), [1, 2]);
+        assert_eq!(cfg.block_of(AsmLabel(3)), Some(3));
+        assert_eq!(cfg.block_of(AsmLabel(7)), None);
+    }
+
+    #[test]
+    fn test_unreachable_blocks_are_dropped() {
+        let cfg = build(vec![
+            mov(0, 1),
+            ret(0),
+            // Code after a return, and a block nothing jumps to
+            mov(0, 2),
+            label(5),
+            mov(0, 3),
+            ret(0),
+        ]);
+        assert_eq!(cfg.len(), 1);
+        assert_eq!(cfg.blocks[0].instructions.len(), 2);
+    }
+
+    #[test]
+    fn test_reverse_postorder_puts_loop_header_first() {
+        // while (t0 < 5) { t0 = 1 }; return t0
+        let cfg = build(vec![
+            mov(0, 0),
+            label(0),
+            compare(0, 5),
+            branch(1, 2),
+            label(1),
+            mov(0, 1),
+            jmp(0),
+            label(2),
+            ret(0),
+        ]);
+        assert_eq!(cfg.predecessors(1), [0, 2]);
+        let order = cfg.reverse_postorder();
+        assert_eq!(order.len(), cfg.len());
+        assert_eq!(labels(&cfg, &order[..2]), vec![100, 0]);
+        // The body comes after the header it jumps back to
+        let position = |block| order.iter().position(|&b| b == block).unwrap();
+        assert!(position(1) < position(2));
+    }
+
+    #[test]
+    fn test_into_instructions_labels_every_block() {
+        let cfg = build(vec![mov(0, 1), label(4), ret(0)]);
+        let instructions = cfg.into_instructions();
+        assert_eq!(instructions.len(), 4);
+        assert!(matches!(
+            instructions[0],
+            AbstractAssemblyInstruction::Lbl(AsmLabel(100))
+        ));
+        assert!(matches!(
+            instructions[2],
+            AbstractAssemblyInstruction::Lbl(AsmLabel(4))
+        ));
+    }
+}
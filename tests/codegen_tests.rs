@@ -1,8 +1,14 @@
-use rust_compiler::codegen::generate_code;
+use rust_compiler::codegen::{lower, CodegenOptions};
 use rust_compiler::lexer::Token;
 use rust_compiler::parser::{
-    Block, Expr, FnDeclaration, Parameter, Program, Statement, VarDeclaration,
+    Block, Expr, FnDeclaration, Parameter, Program, Statement, UnOp, VarDeclaration,
 };
+use rust_compiler::source_map::{Span, Spanned};
+
+/// Wraps a hand-built AST node, which has no source position
+fn spanned<T>(node: T) -> Spanned<T> {
+    Spanned::new(node, Span::default())
+}
 
 #[test]
 fn test_sample_program() {
@@ -22,53 +28,76 @@ fn test_sample_program() {
         decl: vec![
             // int g0 = 42
             VarDeclaration {
+                is_static: false,
                 is_const: false,
                 type_token: Token::Int,
                 identifier: Token::Identifier(String::from("g0")),
-                value: Expr::Literal(Token::Number(42.0)),
+                value: Some(spanned(Expr::Literal(Token::Number(42.0)))),
+                span: Span::default(),
             },
             // double g1 = 1.0
             VarDeclaration {
+                is_static: false,
                 is_const: false,
-                type_token: Token::Number(1.0),
+                type_token: Token::Double,
                 identifier: Token::Identifier(String::from("g1")),
-                value: Expr::Literal(Token::Number(1.0)),
+                value: Some(spanned(Expr::Literal(Token::Number(1.0)))),
+                span: Span::default(),
             },
         ],
         fns: vec![
             // int fun(int num)
             FnDeclaration {
+                is_static: false,
                 return_type: Token::Int,
                 identifier: Token::Identifier(String::from("fun")),
                 params: vec![Parameter {
                     type_token: Token::Int,
                     identifier: Token::Identifier(String::from("num")),
+                    span: Span::default(),
                 }],
+                requires: vec![],
+                ensures: vec![],
                 body: Block {
                     statements: vec![
                         // return -num;
-                        Statement::Return(Some(Box::new(Expr::Unary(
-                            Token::Minus,
-                            Box::new(Expr::Variable(Token::Identifier(String::from("num")))),
-                        )))),
+                        spanned(Statement::Return(Some(Box::new(spanned(Expr::Unary(
+                            UnOp::Neg,
+                            Box::new(spanned(Expr::Variable(Token::Identifier(String::from(
+                                "num",
+                            ))))),
+                        )))))),
                     ],
+                    span: Span::default(),
                 },
+                span: Span::default(),
             },
             // int main()
             FnDeclaration {
+                is_static: false,
                 return_type: Token::Int,
                 identifier: Token::Identifier(String::from("main")),
                 params: vec![],
+                requires: vec![],
+                ensures: vec![],
                 body: Block {
                     statements: vec![
                         // return fun(-123456);
-                        Statement::Return(Some(Box::new(Expr::Call(
-                            Box::new(Expr::Variable(Token::Identifier(String::from("fun")))),
-                            vec![Expr::Literal(Token::Number(-123456.0))],
-                        )))),
+                        spanned(Statement::Return(Some(Box::new(spanned(Expr::Call(
+                            Box::new(spanned(Expr::Variable(Token::Identifier(String::from(
+                                "fun",
+                            ))))),
+                            vec![spanned(Expr::Literal(Token::Number(-123456.0)))],
+                        )))))),
                     ],
+                    span: Span::default(),
                 },
+                span: Span::default(),
             },
         ],
+        externs: Vec::new(),
     };
+
+    let module = lower(program, &CodegenOptions::default()).unwrap();
+    assert_eq!(module.function_names(), ["fun", "main"]);
 }
@@ -0,0 +1,59 @@
+//! Helpers the test crates share: running the compiler binary, and building control-flow
+//! graphs. Each test crate uses only some of them.
+#![allow(dead_code)]
+
+use rust_compiler::codegen::cfg::ControlFlowGraph;
+use rust_compiler::codegen::{AbstractAssemblyInstruction, AsmLabel, Condition, Dest, Operand};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// Creates a fresh working directory containing `samples/<name>.c0`
+pub fn setup_workdir(dirname: &str, name: &str, source: &str) -> PathBuf {
+    let workdir = env::temp_dir().join(format!("rust-compiler-{}-{}", dirname, std::process::id()));
+    let _ = fs::remove_dir_all(&workdir);
+    fs::create_dir_all(workdir.join("samples")).unwrap();
+    fs::write(workdir.join("samples").join(format!("{}.c0", name)), source).unwrap();
+    workdir
+}
+
+/// Runs the compiler binary on `name` with `flags` from `workdir`
+pub fn compiler(workdir: &Path, name: &str, flags: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+        .args(flags)
+        .arg(name)
+        .current_dir(workdir)
+        .output()
+        .unwrap()
+}
+
+pub fn branch(tgt_true: usize, tgt_false: usize) -> AbstractAssemblyInstruction {
+    AbstractAssemblyInstruction::JmpCondition {
+        condition: Condition::Less,
+        tgt_true: AsmLabel(tgt_true),
+        tgt_false: AsmLabel(tgt_false),
+    }
+}
+
+pub fn label(label: usize) -> AbstractAssemblyInstruction {
+    AbstractAssemblyInstruction::Lbl(AsmLabel(label))
+}
+
+pub fn jmp(label: usize) -> AbstractAssemblyInstruction {
+    AbstractAssemblyInstruction::Jmp(AsmLabel(label))
+}
+
+pub fn ret(temp: usize) -> AbstractAssemblyInstruction {
+    AbstractAssemblyInstruction::Return(Operand::Var(Dest::Temp(temp)))
+}
+
+/// Builds the graph, numbering the labels it adds from 100. If `instructions` start with a
+/// label and every block has one, block `n` is the `n`th label.
+pub fn build(instructions: Vec<AbstractAssemblyInstruction>) -> ControlFlowGraph {
+    let mut next = 100;
+    ControlFlowGraph::new(instructions, || {
+        next += 1;
+        AsmLabel(next - 1)
+    })
+}
@@ -0,0 +1,62 @@
+use rust_compiler::constant::{evaluate, Constant};
+use rust_compiler::lexer::tokenize_with_spans;
+use rust_compiler::parser::{parse_with_spans, Expr};
+
+/// Initializer of the only global in `source`
+fn initializer(source: &str) -> Expr {
+    let mut program = parse_with_spans(tokenize_with_spans(source)).unwrap();
+    program.decl.remove(0).value.unwrap().node
+}
+
+fn evaluate_global(source: &str) -> Option<Constant> {
+    let lookup = |name: &str| match name {
+        "LIMIT" => Some(Constant::Int(10)),
+        _ => None,
+    };
+    evaluate(&initializer(source), &lookup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_int_arithmetic() {
+        assert_eq!(
+            evaluate_global("int x = (LIMIT - 3) * 2 / 4;"),
+            Some(Constant::Int(3))
+        );
+        assert_eq!(evaluate_global("int x = -7 / 2;"), Some(Constant::Int(-3)));
+        // Ints wrap around at 32 bits
+        assert_eq!(
+            evaluate_global("int x = 2147483647 + 1;"),
+            Some(Constant::Int(i32::MIN))
+        );
+        // The least int is written negated, but a literal past the largest int isn't an int
+        assert_eq!(
+            evaluate_global("int x = -2147483648;"),
+            Some(Constant::Int(i32::MIN))
+        );
+        assert_eq!(evaluate_global("int x = 2147483648;"), None);
+    }
+
+    #[test]
+    fn test_doubles_and_casts() {
+        assert_eq!(
+            evaluate_global("double x = LIMIT / 2.5;"),
+            Some(Constant::Double(4.0))
+        );
+        assert_eq!(
+            evaluate_global("int x = (int) 2.9 + 1;"),
+            Some(Constant::Int(3))
+        );
+        assert_eq!(evaluate_global("int x = 1.5 < 2;"), Some(Constant::Int(1)));
+    }
+
+    #[test]
+    fn test_not_constant() {
+        assert_eq!(evaluate_global("int x = other;"), None);
+        assert_eq!(evaluate_global("int x = f(1);"), None);
+        assert_eq!(evaluate_global("int x = LIMIT / 0;"), None);
+    }
+}
@@ -0,0 +1,209 @@
+use rust_compiler::desugar::{desugar, strip_contracts};
+use rust_compiler::lexer::{tokenize_with_spans, Token};
+use rust_compiler::parser::{parse_with_spans, BinOp, Expr, LValue, Statement};
+use rust_compiler::source_map::Spanned;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Desugared body of the only function in `source`
+    fn desugared_body(source: &str) -> Vec<Spanned<Statement>> {
+        let program = parse_with_spans(tokenize_with_spans(source)).unwrap();
+        let mut program = desugar(program);
+        program.fns.remove(0).body.statements
+    }
+
+    /// Asserts that `expr` is `name = name <op> <rhs>` and returns the right-hand side
+    fn expect_update<'a>(expr: &'a Expr, name: &str, op: BinOp) -> &'a Expr {
+        match expr {
+            Expr::Assign(LValue::Variable(Token::Identifier(target)), value) => {
+                assert_eq!(target, name);
+                match &value.node {
+                    Expr::Binary(left, actual_op, right) => {
+                        assert!(
+                            matches!(&left.node, Expr::Variable(Token::Identifier(n)) if n == name)
+                        );
+                        assert_eq!(*actual_op, op);
+                        &right.node
+                    }
+                    other => panic!("Expected binary expression, got {:?}", other),
+                }
+            }
+            other => panic!("Expected assignment, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let body = desugared_body("int f(int x) { x *= 2 + 1; return x; }");
+        match &body[0].node {
+            Statement::Expression(expr) => {
+                let rhs = expect_update(&expr.node, "x", BinOp::Mul);
+                assert!(matches!(rhs, Expr::Binary(_, BinOp::Add, _)));
+            }
+            other => panic!("Expected expression statement, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_postfix() {
+        let body = desugared_body("int f(int x) { x++; x--; return x; }");
+        let ops = [BinOp::Add, BinOp::Sub];
+        for (statement, op) in body.iter().zip(ops) {
+            match &statement.node {
+                Statement::Expression(expr) => {
+                    let rhs = expect_update(&expr.node, "x", op);
+                    assert!(matches!(rhs, Expr::Literal(Token::Number(n)) if *n == 1.0));
+                }
+                other => panic!("Expected expression statement, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_implicit_blocks() {
+        let body =
+            desugared_body("int f(int x) { if (x) x = 1; else x = 2; while (x) x--; return x; }");
+        match &body[0].node {
+            Statement::If(_, then_branch, Some(else_branch)) => {
+                assert!(matches!(then_branch.node, Statement::Block(_)));
+                assert!(matches!(else_branch.node, Statement::Block(_)));
+            }
+            other => panic!("Expected if/else, got {:?}", other),
+        }
+        match &body[1].node {
+            Statement::While(_, _, loop_body) => {
+                assert!(matches!(loop_body.node, Statement::Block(_)))
+            }
+            other => panic!("Expected while loop, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let body = desugared_body(
+            "int f(int n) { int s = 0; for (int i = 0; i < n; i++) { if (i == 2) continue; s += i; } return s; }",
+        );
+
+        // { int i = 0; while (i < n) { { body } i = i + 1; } }
+        let Statement::Block(outer) = &body[1].node else {
+            panic!("Expected block, got {:?}", body[1].node);
+        };
+        assert!(matches!(outer.statements[0].node, Statement::VarDecl(_)));
+        let Statement::While(condition, _, loop_body) = &outer.statements[1].node else {
+            panic!("Expected while loop, got {:?}", outer.statements[1].node);
+        };
+        assert!(matches!(condition.node, Expr::Binary(_, BinOp::Less, _)));
+        let Statement::Block(loop_body) = &loop_body.node else {
+            panic!("Expected block, got {:?}", loop_body.node);
+        };
+        assert_eq!(loop_body.statements.len(), 2);
+        match &loop_body.statements[1].node {
+            Statement::Expression(step) => {
+                expect_update(&step.node, "i", BinOp::Add);
+            }
+            other => panic!("Expected step, got {:?}", other),
+        }
+
+        // The `continue` runs the step first
+        let Statement::Block(user_body) = &loop_body.statements[0].node else {
+            panic!("Expected block, got {:?}", loop_body.statements[0].node);
+        };
+        let Statement::If(_, then_branch, None) = &user_body.statements[0].node else {
+            panic!("Expected if, got {:?}", user_body.statements[0].node);
+        };
+        let Statement::Block(then_block) = &then_branch.node else {
+            panic!("Expected block, got {:?}", then_branch.node);
+        };
+        let Statement::Block(continue_block) = &then_block.statements[0].node else {
+            panic!("Expected block, got {:?}", then_block.statements[0].node);
+        };
+        assert!(matches!(
+            continue_block.statements[0].node,
+            Statement::Expression(_)
+        ));
+        assert!(matches!(
+            continue_block.statements[1].node,
+            Statement::Continue
+        ));
+    }
+
+    #[test]
+    fn test_for_loop_leaves_inner_continue_alone() {
+        let body =
+            desugared_body("int f(int n) { for (;; n--) { while (n) { continue; } } return n; }");
+
+        let Statement::Block(outer) = &body[0].node else {
+            panic!("Expected block, got {:?}", body[0].node);
+        };
+        // No initializer, and a missing condition is always true
+        assert_eq!(outer.statements.len(), 1);
+        let Statement::While(condition, _, loop_body) = &outer.statements[0].node else {
+            panic!("Expected while loop, got {:?}", outer.statements[0].node);
+        };
+        assert!(matches!(condition.node, Expr::Literal(Token::Number(n)) if n == 1.0));
+
+        let Statement::Block(loop_body) = &loop_body.node else {
+            panic!("Expected block, got {:?}", loop_body.node);
+        };
+        let Statement::Block(user_body) = &loop_body.statements[0].node else {
+            panic!("Expected block, got {:?}", loop_body.statements[0].node);
+        };
+        let Statement::While(_, _, inner_body) = &user_body.statements[0].node else {
+            panic!(
+                "Expected while loop, got {:?}",
+                user_body.statements[0].node
+            );
+        };
+        let Statement::Block(inner_body) = &inner_body.node else {
+            panic!("Expected block, got {:?}", inner_body.node);
+        };
+        assert!(matches!(inner_body.statements[0].node, Statement::Continue));
+    }
+
+    #[test]
+    fn test_strip_contracts() {
+        let source = "int f(int n)\n//@requires n >= 0;\n{\n    for (; n > 0; n--)\n    //@loop_invariant n >= 0;\n    {\n        //@assert n > 0;\n    }\n    return n;\n}";
+        let program = parse_with_spans(tokenize_with_spans(source)).unwrap();
+        let function = &strip_contracts(program).fns[0];
+
+        assert!(function.requires.is_empty());
+        match &function.body.statements[0].node {
+            Statement::For(_, _, _, invariants, body) => {
+                assert!(invariants.is_empty());
+                match &body.node {
+                    Statement::Block(block) => assert!(matches!(
+                        &block.statements[0].node,
+                        Statement::Block(empty) if empty.statements.is_empty()
+                    )),
+                    other => panic!("Expected block, got {:?}", other),
+                }
+            }
+            other => panic!("Expected for loop, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_global_initializers_are_folded() {
+        let source =
+            "const int LIMIT = 4;\nconst double HALF = (double) LIMIT / 8;\nint start = -LIMIT * 2;";
+        let program = desugar(parse_with_spans(tokenize_with_spans(source)).unwrap());
+        let values: Vec<&Token> = program
+            .decl
+            .iter()
+            .map(|global| match &global.value.as_ref().unwrap().node {
+                Expr::Literal(token) => token,
+                other => panic!("Expected literal, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            values,
+            [
+                &Token::Number(4.0),
+                &Token::DoubleLiteral(0.5),
+                &Token::Number(-8.0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use rust_compiler::diagnostic::render::{next_base, render, render_json, Palette, SourceFile};
+use rust_compiler::diagnostic::{Diagnostic, DiagnosticSink, Severity, Warning, WarningOptions};
+use rust_compiler::preprocessor::Preprocessed;
+use rust_compiler::source_map::Span;
+
+fn unused_variable() -> Diagnostic {
+    Diagnostic::warning(
+        Warning::UnusedVariable,
+        "Variable 'x' is never read",
+        Span::new(0, 1),
+    )
+}
+
+/// `a.c0` and then `b.c0`, laid end to end like the files of a linked program
+fn two_files() -> Vec<SourceFile> {
+    let mut sources = vec![SourceFile {
+        base: 0,
+        preprocessed: Preprocessed::verbatim("a.c0", "int main() {\n    return x;\n}\n"),
+    }];
+    let base = next_base(&sources);
+    sources.push(SourceFile {
+        base,
+        preprocessed: Preprocessed::verbatim("b.c0", "int x;\n"),
+    });
+    sources
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_warnings_are_dropped() {
+        let mut options = WarningOptions::default();
+        options.disable(Warning::UnusedVariable);
+        let mut sink = DiagnosticSink::new(options);
+
+        sink.report(unused_variable());
+        assert!(sink.diagnostics().is_empty());
+
+        sink.report(Diagnostic::error("Undefined variable 'y'", None));
+        assert_eq!(sink.error_count(), 1);
+    }
+
+    #[test]
+    fn test_warnings_as_errors() {
+        let mut sink = DiagnosticSink::new(WarningOptions::default());
+        sink.report(unused_variable());
+        assert!(!sink.has_errors());
+        assert_eq!(sink.diagnostics()[0].severity, Severity::Warning);
+
+        let mut options = WarningOptions::default();
+        options.as_errors = true;
+        let mut sink = DiagnosticSink::new(options);
+        sink.report(unused_variable());
+        assert!(sink.has_errors());
+        // It's still known to be a warning
+        assert_eq!(sink.diagnostics()[0].warning, Some(Warning::UnusedVariable));
+    }
+
+    #[test]
+    fn test_warning_names() {
+        for warning in Warning::ALL {
+            assert_eq!(Warning::from_name(warning.name()), Some(warning));
+        }
+        assert_eq!(Warning::from_name("unused"), None);
+    }
+
+    #[test]
+    fn test_render_points_at_the_source_line() {
+        let sources = two_files();
+        let diagnostic = Diagnostic::error("Undefined variable 'x'", Some(Span::new(24, 25)))
+            .with_code("E0102")
+            .with_label("not declared")
+            .with_note(Span::new(30, 35), "declared after it's used");
+
+        assert_eq!(
+            render(&sources, &diagnostic, Palette::PLAIN),
+            "a.c0:2:12: error[E0102]: Undefined variable 'x'\n\
+             \x20 |\n\
+             2 |     return x;\n\
+             \x20 |            ^ not declared\n\
+             b.c0:1:1: note: declared after it's used\n\
+             \x20 |\n\
+             1 | int x;\n\
+             \x20 | ^^^^^"
+        );
+    }
+
+    #[test]
+    fn test_render_colors() {
+        let sources = two_files();
+        let diagnostic = unused_variable();
+        let text = render(&sources, &diagnostic, Palette::ANSI);
+        assert!(text.contains("\x1b[1;33mwarning\x1b[0m: Variable 'x' is never read"));
+        assert!(text.contains("| \x1b[1;33m^\x1b[0m"));
+
+        let error = Diagnostic::error("Missing main", None).with_code("E0201");
+        assert_eq!(
+            render(&sources, &error, Palette::ANSI),
+            "\x1b[1;31merror[E0201]\x1b[0m: Missing main"
+        );
+        assert_eq!(
+            render(&sources, &error, Palette::PLAIN),
+            "error[E0201]: Missing main"
+        );
+    }
+
+    #[test]
+    fn test_render_json() {
+        let sources = two_files();
+        let diagnostic = unused_variable().with_fix(Span::new(34, 35), "_x");
+        assert_eq!(
+            render_json(&sources, &diagnostic),
+            "{\"severity\":\"warning\",\"code\":null,\"message\":\"Variable 'x' is never read\",\
+             \"span\":{\"file\":\"a.c0\",\"start\":0,\"end\":1,\"line\":1,\"column\":1},\
+             \"label\":null,\"warning\":\"unused-variable\",\
+             \"fix\":{\"span\":{\"file\":\"b.c0\",\"start\":4,\"end\":5,\"line\":1,\"column\":5},\
+             \"replacement\":\"_x\"},\"notes\":[]}"
+        );
+
+        let error = Diagnostic::error("Missing \"main\"", None);
+        assert_eq!(
+            render_json(&sources, &error),
+            "{\"severity\":\"error\",\"code\":null,\"message\":\"Missing \\\"main\\\"\",\
+             \"span\":null,\"label\":null,\"warning\":null,\"fix\":null,\"notes\":[]}"
+        );
+    }
+}
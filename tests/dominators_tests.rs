@@ -0,0 +1,94 @@
+mod common;
+
+use common::{branch, build, jmp, label, ret};
+use rust_compiler::codegen::dominators::DominatorTree;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diamond() {
+        // 0 -> 1, 2 -> 3
+        let cfg = build(vec![
+            label(0),
+            branch(1, 2),
+            label(1),
+            jmp(3),
+            label(2),
+            jmp(3),
+            label(3),
+            ret(0),
+        ]);
+        let tree = DominatorTree::new(&cfg);
+        assert_eq!(tree.idom(0), None);
+        assert_eq!(tree.idom(1), Some(0));
+        assert_eq!(tree.idom(2), Some(0));
+        assert_eq!(tree.idom(3), Some(0));
+        assert_eq!(tree.children(0), [1, 2, 3]);
+
+        assert!(tree.dominates(0, 3));
+        assert!(tree.dominates(3, 3));
+        assert!(!tree.strictly_dominates(3, 3));
+        assert!(!tree.dominates(1, 3));
+        assert!(!tree.dominates(1, 2));
+
+        assert_eq!(tree.frontier(1), [3]);
+        assert_eq!(tree.frontier(2), [3]);
+        assert!(tree.frontier(0).is_empty());
+        assert!(tree.frontier(3).is_empty());
+    }
+
+    #[test]
+    fn test_loop() {
+        // 0 -> 1 (header) -> 2 (body) -> 3 -> 1, and 1 -> 4 (exit)
+        let cfg = build(vec![
+            label(0),
+            jmp(1),
+            label(1),
+            branch(2, 4),
+            label(2),
+            branch(3, 3),
+            label(3),
+            jmp(1),
+            label(4),
+            ret(0),
+        ]);
+        let tree = DominatorTree::new(&cfg);
+        assert_eq!(tree.idom(1), Some(0));
+        assert_eq!(tree.idom(2), Some(1));
+        assert_eq!(tree.idom(3), Some(2));
+        assert_eq!(tree.idom(4), Some(1));
+
+        assert!(tree.dominates(1, 3));
+        assert!(tree.strictly_dominates(2, 3));
+        assert!(!tree.dominates(2, 4));
+        assert!(!tree.dominates(3, 1));
+
+        // Values defined in the loop meet the ones from before it at the header
+        assert_eq!(tree.frontier(2), [1]);
+        assert_eq!(tree.frontier(3), [1]);
+        assert_eq!(tree.frontier(1), [1]);
+        assert!(tree.frontier(4).is_empty());
+    }
+
+    #[test]
+    fn test_join_of_unequal_paths() {
+        // 0 -> 1 -> 2 -> 3, and 0 -> 3 directly
+        let cfg = build(vec![
+            label(0),
+            branch(1, 3),
+            label(1),
+            jmp(2),
+            label(2),
+            jmp(3),
+            label(3),
+            ret(0),
+        ]);
+        let tree = DominatorTree::new(&cfg);
+        assert_eq!(tree.idom(2), Some(1));
+        assert_eq!(tree.idom(3), Some(0));
+        assert_eq!(tree.frontier(1), [3]);
+        assert_eq!(tree.frontier(2), [3]);
+    }
+}
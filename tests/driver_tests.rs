@@ -0,0 +1,2596 @@
+mod common;
+
+use common::{compiler, setup_workdir};
+use regex::Regex;
+use rust_compiler::codegen::{parse_ir, run_with_io};
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const SAMPLE: &str = r#"
+int limit = 10;
+
+int main() {
+    int x = 0;
+    x = x + 3;
+    if (x == 3) {
+        return x;
+    } else {
+        return -x;
+    }
+}
+"#;
+
+/// Captures of the first match of `pattern` in `text`, panicking with the text if there's none
+fn captures(pattern: &str, text: &str) -> Vec<String> {
+    let captures = Regex::new(pattern)
+        .unwrap()
+        .captures(text)
+        .unwrap_or_else(|| panic!("no match for {:?} in\n{}", pattern, text));
+    captures
+        .iter()
+        .map(|capture| capture.map_or(String::new(), |capture| capture.as_str().to_string()))
+        .collect()
+}
+
+/// A loop in which `count` variables prefixed with `prefix` are all live, summed up after
+/// it into `sum<prefix>`
+fn register_pressure(prefix: &str, count: usize) -> String {
+    let mut source = String::new();
+    for i in 0..count {
+        source += &format!("    int {}{} = {};\n", prefix, i, i * 7);
+    }
+    source += "    for (int i = 0; i < 10; i = i + 1) {\n";
+    for i in 0..count {
+        let next = (i + 1) % count;
+        source += &format!("        {p}{i} = {p}{i} + {p}{next} / 3;\n", p = prefix);
+    }
+    source += "    }\n";
+    let terms: Vec<String> = (0..count).map(|i| format!("{}{}", prefix, i)).collect();
+    source += &format!("    int sum{} = {};\n", prefix, terms.join(" + "));
+    source
+}
+
+/// Number of distinct stack slots the assembly uses
+fn stack_slots(x86: &str) -> usize {
+    let slots: HashSet<&str> = Regex::new(r"-?\d*\(%rsp\)")
+        .unwrap()
+        .find_iter(x86)
+        .map(|slot| slot.as_str())
+        .collect();
+    slots.len()
+}
+
+/// Stand-ins for the functions compiled programs call to print and abort
+const RUNTIME: &str = r#"
+#include <stdio.h>
+#include <stdlib.h>
+void c0_print_int(int value) { printf("%d", value); }
+void c0_print_char(int value) { putchar(value); }
+void c0_print_double(double value) { printf("%f", value); }
+void c0_print_string(const char *value) { fputs(value, stdout); }
+void c0_abort(void) { fflush(stdout); abort(); }
+"#;
+
+/// Assembles and links the x86-64 assembly of `samples/target/<name>.S` with `RUNTIME`, and runs
+/// the program
+fn run_x86(workdir: &Path, name: &str) -> std::process::Output {
+    let runtime = workdir.join("runtime.c");
+    fs::write(&runtime, RUNTIME).unwrap();
+    let program = workdir.join(name);
+    let status = Command::new("cc")
+        .arg("-o")
+        .arg(&program)
+        .arg(
+            workdir
+                .join("samples")
+                .join("target")
+                .join(format!("{}.S", name)),
+        )
+        .arg(&runtime)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    Command::new(program).output().unwrap()
+}
+
+/// Runs the compiler binary from `workdir` and returns the emitted file
+fn compile_in(workdir: &Path, name: &str) -> Vec<u8> {
+    compile_with_flags(workdir, name, &[])
+}
+
+fn compile_with_flags(workdir: &Path, name: &str, flags: &[&str]) -> Vec<u8> {
+    let status = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+        .args(flags)
+        .arg(name)
+        .current_dir(workdir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    fs::read(
+        workdir
+            .join("samples")
+            .join("target")
+            .join(format!("{}.S", name)),
+    )
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_is_reproducible_across_directories() {
+        let first = setup_workdir("repro-a", "sample", SAMPLE);
+        let second = setup_workdir("repro-b", "sample", SAMPLE);
+
+        let first_output = compile_in(&first, "sample");
+        let second_output = compile_in(&second, "sample");
+        assert_eq!(first_output, second_output);
+
+        // Repeated builds in the same directory must also agree
+        assert_eq!(compile_in(&first, "sample"), first_output);
+
+        // No part of the build location may leak into the artifact
+        let text = String::from_utf8(first_output).unwrap();
+        assert!(!text.contains(first.to_string_lossy().as_ref()));
+
+        // Debug information records where the compiler ran, unless the build is reproducible
+        let flags = ["-g", "--target=x86_64"];
+        let debug = String::from_utf8(compile_with_flags(&first, "sample", &flags)).unwrap();
+        assert!(debug.contains(first.to_string_lossy().as_ref()));
+        let flags = ["-g", "--target=x86_64", "--reproducible"];
+        let first_output = compile_with_flags(&first, "sample", &flags);
+        assert_eq!(first_output, compile_with_flags(&second, "sample", &flags));
+        let text = String::from_utf8(first_output).unwrap();
+        assert!(!text.contains(first.to_string_lossy().as_ref()), "{}", text);
+        assert!(text.contains("\t.string \".\"\n"), "{}", text);
+
+        fs::remove_dir_all(first).unwrap();
+        fs::remove_dir_all(second).unwrap();
+    }
+
+    #[test]
+    fn test_static_symbols_are_not_exported() {
+        let source = "static int hidden = 1;\nint shared = 2;\nstatic int helper() { return 0; }\nint main() { return 0; }\n";
+        let workdir = setup_workdir("static", "sample", source);
+
+        let text = String::from_utf8(compile_in(&workdir, "sample")).unwrap();
+        let exported: Vec<&str> = text
+            .lines()
+            .filter_map(|line| line.strip_prefix(".globl "))
+            .collect();
+        assert_eq!(exported, ["shared", "main"]);
+        assert!(text.contains(".helper\n"));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_contracts_are_checked_only_with_d() {
+        let source = "int main()\n//@ensures 1 > 0;\n{\n    int x = 1;\n    //@assert x == 1;\n    return x;\n}\n";
+        let workdir = setup_workdir("contracts", "sample", source);
+
+        let unchecked = String::from_utf8(compile_in(&workdir, "sample")).unwrap();
+        assert!(!unchecked.contains("abort"));
+
+        let checked = String::from_utf8(compile_with_flags(&workdir, "sample", &["-d"])).unwrap();
+        assert_eq!(checked.matches("abort\n").count(), 2);
+        assert!(checked.contains("@assert annotation failed in main"));
+        assert!(checked.contains("@ensures annotation failed in main"));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_assert_is_checked_without_d() {
+        let source = "int check(int x) {\n    assert(x > 0);\n    return x;\n}\nint main() {\n    print(\"%d\\n\", check(1));\n    print(\"%d\\n\", check(-1));\n    return 0;\n}\n";
+        let workdir = setup_workdir("assert", "sample", source);
+
+        // The runtime flushes what was printed before it aborts
+        let program = workdir.join("samples").join("target").join("sample");
+        for flags in [
+            &["--target=x86_64", "--link"][..],
+            &["--target=x86_64", "--link", "-d"],
+        ] {
+            compile_with_flags(&workdir, "sample", flags);
+            let output = Command::new(&program).output().unwrap();
+            assert_eq!(output.status.code(), None, "{:?}", flags);
+            assert_eq!(
+                String::from_utf8(output.stdout).unwrap(),
+                "1\nassert failed in check\n",
+                "{:?}",
+                flags
+            );
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_directory_is_linked_into_one_output() {
+        let workdir = setup_workdir("directory", "unused", "");
+        let project = workdir.join("samples").join("project");
+        fs::create_dir_all(&project).unwrap();
+        fs::write(
+            project.join("a.c0"),
+            "static int helper() { return 1; }\nint main() { return 0; }\n",
+        )
+        .unwrap();
+        fs::write(project.join("b.c0"), "static int helper() { return 2; }\n").unwrap();
+
+        let text = String::from_utf8(compile_in(&workdir, "project")).unwrap();
+        let functions: Vec<&str> = text
+            .lines()
+            .filter(|line| line.starts_with('.') && !line.starts_with(".globl"))
+            .collect();
+        assert_eq!(functions, [".a.helper", ".main", ".b.helper"]);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_link_errors_point_at_both_files() {
+        let workdir = setup_workdir("link-errors", "sample", "int main() {\n    return 0;\n}\n");
+        fs::write(
+            workdir.join("samples").join("other.c0"),
+            "extern int scale(int x);\n\nint main() {\n    return scale(1);\n}\n",
+        )
+        .unwrap();
+        fs::write(
+            workdir.join("samples").join("scale.c0"),
+            "int scale(double x) {\n    return 2;\n}\n",
+        )
+        .unwrap();
+
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["sample", "other", "scale"])
+            .current_dir(&workdir)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.contains(
+                "samples/other.c0:3:1: error[E0201]: 'main' is defined in both sample and other"
+            ),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("3 | int main() {\n  | ^^^^^^^^^^ defined again here"));
+        assert!(stderr.contains("samples/sample.c0:1:1: note: first defined here"));
+        assert!(stderr.contains("samples/other.c0:1:1: error[E0204]: 'scale' is declared in other with another signature than in scale"));
+        assert!(stderr.contains("samples/scale.c0:1:1: note: declared here"));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_sema_errors_report_their_location() {
+        let workdir = setup_workdir("undefined", "sample", "int main() {\n    return x;\n}\n");
+        fs::write(
+            workdir.join("samples").join("other.c0"),
+            "int f() {\n    int a = 1;\n    return a + y;\n}\n",
+        )
+        .unwrap();
+
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["sample", "other"])
+            .current_dir(&workdir)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains(
+            "samples/sample.c0:2:12: error[E0102]: In function 'main': Undefined variable 'x'"
+        ));
+        assert!(stderr.contains(
+            "samples/other.c0:3:16: error[E0102]: In function 'f': Undefined variable 'y'"
+        ));
+        // Each error shows the line it's on
+        assert!(stderr.contains("2 |     return x;\n  |            ^ not defined"));
+        assert!(stderr.contains("For more information about an error, try `--explain E0102`"));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_explain() {
+        let run = |code: &str| {
+            Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .args(["--explain", code])
+                .output()
+                .unwrap()
+        };
+
+        let output = run("E0102");
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(
+            stdout.starts_with("A variable is used, but no variable with that name is in scope.")
+        );
+        assert!(!stdout.contains("Compilation succeeded"));
+
+        let stderr = String::from_utf8(run("E9999").stderr).unwrap();
+        assert!(stderr.contains("'E9999' isn't an error code"));
+    }
+
+    #[test]
+    fn test_completions() {
+        let run = |shell: &str| {
+            Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .arg(format!("--completions={}", shell))
+                .output()
+                .unwrap()
+        };
+        let script = String::from_utf8(run("bash").stdout).unwrap();
+        assert!(!script.contains("Compilation succeeded"));
+        // Completes the line typed so far, split into words the way bash splits them
+        let complete = |line: &str, words: &[&str]| {
+            let output = Command::new("bash")
+                .arg("-c")
+                .arg(format!(
+                    "{}\nCOMP_LINE='{}'; COMP_POINT={}; COMP_WORDS=({}); COMP_CWORD={}\n\
+                     _rust_compiler 2>/dev/null; echo \"${{COMPREPLY[*]}}\"",
+                    script,
+                    line,
+                    line.len(),
+                    words.join(" "),
+                    words.len() - 1
+                ))
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        assert_eq!(complete("c0 -fno-in", &["c0", "-fno-in"]), "-fno-inline");
+        assert_eq!(
+            complete("c0 --target=risc", &["c0", "--target", "=", "risc"]),
+            "riscv32"
+        );
+        assert_eq!(
+            complete("c0 --regalloc=", &["c0", "--regalloc", "="]),
+            "graph linear"
+        );
+        assert_eq!(
+            complete("c0 --explain E0102", &["c0", "--explain", "E0102"]),
+            "E0102"
+        );
+        assert_eq!(complete("c0 stats-d", &["c0", "stats-d"]), "stats-diff");
+
+        let script = String::from_utf8(run("zsh").stdout).unwrap();
+        assert!(script.starts_with("#compdef rust-compiler\n"));
+        assert!(script.contains("    '-funroll-loops:run the unroll-loops pass'\n"));
+        let script = String::from_utf8(run("fish").stdout).unwrap();
+        assert!(script.contains("complete -c rust-compiler -a '-Wno-unused-variable' -d "));
+
+        let stderr = String::from_utf8(run("tcsh").stderr).unwrap();
+        assert!(stderr.starts_with("Usage: "));
+    }
+
+    #[test]
+    fn test_json_diagnostics() {
+        let source = "int main() {\n    int unused = 1;\n    return \"x\";\n}\n";
+        let workdir = setup_workdir("json", "sample", source);
+        let stderr_of = |workdir: &Path| {
+            let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .args(["--error-format=json", "sample"])
+                .current_dir(workdir)
+                .output()
+                .unwrap();
+            String::from_utf8(output.stderr).unwrap()
+        };
+
+        let stderr = stderr_of(&workdir);
+        assert_eq!(
+            stderr.lines().next().unwrap(),
+            r#"{"severity":"error","code":"E0104","message":"In function 'main': Expected int, found string","span":{"file":"samples/sample.c0","start":44,"end":47,"line":3,"column":12},"label":"this is string","warning":null,"fix":null,"notes":[]}"#
+        );
+        // Only the diagnostics are JSON, not the source lines or hints
+        assert!(!stderr.contains("--explain"));
+
+        let source = "int main() {\n    int unused = 1;\n    return 0;\n}\n";
+        let workdir = setup_workdir("json", "sample", source);
+        assert_eq!(
+            stderr_of(&workdir).trim_end(),
+            r#"{"severity":"warning","code":null,"message":"In function 'main': Variable 'unused' is never read","span":{"file":"samples/sample.c0","start":17,"end":32,"line":2,"column":5},"label":null,"warning":"unused-variable","fix":null,"notes":[]}"#
+        );
+
+        // Preprocessor errors are diagnostics too
+        let source = "int main() {\n    return 0;\n}\n#include \"missing.h0\"\n";
+        let workdir = setup_workdir("json", "sample", source);
+        let stderr = stderr_of(&workdir);
+        assert!(stderr.starts_with(
+            r#"{"severity":"error","code":"E0010","message":"Failed to include 'samples/missing.h0': "#
+        ));
+        assert!(stderr.contains(
+            r#""span":{"file":"samples/sample.c0","start":29,"end":50,"line":4,"column":1}"#
+        ));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_lexer_errors_past_the_limit_are_counted() {
+        let source = format!("int main() {{\n    return 0 {};\n}}\n", ["$"; 24].join(" "));
+        let workdir = setup_workdir("lexer-limit", "sample", &source);
+
+        let output = compiler(&workdir, "sample", &[]);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert_eq!(stderr.matches("error[E0007]").count(), 20);
+        assert!(stderr.contains("note: ... and 4 more lexer errors in 'sample'"));
+        assert!(stderr.contains("Compilation failed with 24 error(s)"));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_semicolon_hint() {
+        let source = "int main() {\n    int x = 1\n    return x;\n}\n";
+        let workdir = setup_workdir("missing", "sample", source);
+        let stderr_with = |flags: &[&str]| {
+            let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .args(flags)
+                .arg("sample")
+                .current_dir(&workdir)
+                .output()
+                .unwrap();
+            String::from_utf8(output.stderr).unwrap()
+        };
+
+        assert!(stderr_with(&[]).contains(
+            "samples/sample.c0:2:14: error[E0008]: Missing ';'\n  |\n2 |     int x = 1\n  |              ^ insert `;` here"
+        ));
+        assert!(stderr_with(&["--error-format=json"]).contains(
+            r#""fix":{"span":{"file":"samples/sample.c0","start":26,"end":26,"line":2,"column":14},"replacement":";"}"#
+        ));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_colored_diagnostics() {
+        let source = "int main() {\n    int unused = 1;\n    return x;\n}\n";
+        let workdir = setup_workdir("color", "sample", source);
+        let stderr_with = |flags: &[&str]| {
+            let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .args(flags)
+                .arg("sample")
+                .current_dir(&workdir)
+                .output()
+                .unwrap();
+            String::from_utf8(output.stderr).unwrap()
+        };
+
+        let stderr = stderr_with(&["--color=always"]);
+        assert!(stderr.contains("\x1b[1;31merror[E0102]\x1b[0m: In function 'main'"));
+        assert!(stderr.contains("|            \x1b[1;31m^\x1b[0m not defined"));
+        // Output to a pipe isn't colored unless asked for
+        assert!(!stderr_with(&[]).contains('\x1b'));
+        assert!(!stderr_with(&["--color=never"]).contains('\x1b'));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_functions_read_and_write_globals() {
+        let source = "int count = 1;\nstatic double scale = 0.5;\nstring name = \"c0\";\n\nvoid bump(int by) {\n    count = count + by;\n}\n\nint main() {\n    bump(2);\n    bump(count);\n    scale = scale * count;\n    name = \"globals\";\n    int total = count = count + 1;\n    print(\"%d %d %f %s\\n\", count, total, scale, name);\n    return 0;\n}\n";
+        let workdir = setup_workdir("x86-globals", "sample", source);
+        for level in ["-O0", "-O2"] {
+            compile_with_flags(&workdir, "sample", &["--target=x86_64", level]);
+            let output = run_x86(&workdir, "sample");
+            assert!(output.status.success());
+            assert_eq!(
+                String::from_utf8(output.stdout).unwrap(),
+                "7 7 3.000000 globals\n",
+                "{}",
+                level
+            );
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_warning_flags() {
+        let source = "int main() {\n    int unused = 1;\n    return 0;\n}\n";
+        let workdir = setup_workdir("warnings", "sample", source);
+        let outpath = workdir.join("samples").join("target").join("sample.S");
+        let stderr_with = |flags: &[&str]| {
+            let _ = fs::remove_file(&outpath);
+            let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .args(flags)
+                .arg("sample")
+                .current_dir(&workdir)
+                .output()
+                .unwrap();
+            String::from_utf8(output.stderr).unwrap()
+        };
+
+        let warning =
+            "samples/sample.c0:2:5: warning: In function 'main': Variable 'unused' is never read";
+        assert!(stderr_with(&[]).contains(warning));
+        assert!(outpath.exists());
+
+        let stderr = stderr_with(&["-Wno-unused-variable"]);
+        assert!(!stderr.contains("unused"), "{}", stderr);
+        assert!(outpath.exists());
+
+        // -Werror fails the compilation instead of writing the output
+        let stderr = stderr_with(&["-Werror"]);
+        assert!(
+            stderr.contains("samples/sample.c0:2:5: error: In function 'main'"),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("Compilation failed with 1 error(s)"));
+        assert!(!outpath.exists());
+
+        assert!(stderr_with(&["-Wno-such-thing"]).contains("Unknown warning 'such-thing'"));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let source = "int main() {\n    int unused = 1;\n    return 0;\n}\n";
+        let workdir = setup_workdir("exit-status", "sample", source);
+        fs::write(
+            workdir.join("samples").join("broken.c0"),
+            "int main() {\n    return x;\n}\n",
+        )
+        .unwrap();
+        let status = |args: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .args(args)
+                .current_dir(&workdir)
+                .status()
+                .unwrap()
+                .code()
+        };
+
+        assert_eq!(status(&["sample"]), Some(0));
+        assert_eq!(status(&["broken"]), Some(1));
+        // A warning only fails the compilation with -Werror
+        assert_eq!(status(&["-Werror", "sample"]), Some(1));
+        assert_eq!(status(&["missing"]), Some(1));
+        assert_eq!(status(&["--explain", "E0102"]), Some(0));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_return_is_an_error() {
+        let source =
+            "int f(int a) {\n    if (a > 0) return 1;\n}\n\nint main() {\n    int x = f(1);\n}\n";
+        let workdir = setup_workdir("missing-return", "sample", source);
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["--target=x86_64", "sample"])
+            .current_dir(&workdir)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        // Both functions are reported, at their closing brace, and nothing is written
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert_eq!(stderr.matches("error[E0121]").count(), 2, "{}", stderr);
+        assert!(stderr.contains("In function 'f': Control may reach the end"));
+        assert!(stderr.contains("samples/sample.c0:3:1"), "{}", stderr);
+        assert!(stderr.contains("samples/sample.c0:7:1"), "{}", stderr);
+        assert!(!workdir
+            .join("samples")
+            .join("target")
+            .join("sample.S")
+            .exists());
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_includes_stay_in_the_project() {
+        let source = "#include \"/etc/hostname\"\nint main() {\n    return 0;\n}\n";
+        let workdir = setup_workdir("include-sandbox", "sample", source);
+        let output = compiler(&workdir, "sample", &[]);
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.contains("'/etc/hostname' is outside the project root"),
+            "{}",
+            stderr
+        );
+
+        // Found through `-I`, a header outside `samples` needs `--allow-external-imports`
+        fs::create_dir_all(workdir.join("include")).unwrap();
+        fs::write(
+            workdir.join("include").join("answer.h0"),
+            "#define ANSWER 42\n",
+        )
+        .unwrap();
+        let source = "#include \"answer.h0\"\nint main() {\n    return ANSWER;\n}\n";
+        fs::write(workdir.join("samples").join("sample.c0"), source).unwrap();
+        let output = compiler(&workdir, "sample", &["-I", "include"]);
+        assert_eq!(output.status.code(), Some(1));
+        let output = compiler(
+            &workdir,
+            "sample",
+            &["-Iinclude", "--allow-external-imports"],
+        );
+        assert!(output.status.success(), "{:?}", output);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_ints_are_converted_where_doubles_are_expected() {
+        let source = "int main() {\n    int n = 3;\n    double x = n * 1.5;\n    if (x > 2) {\n        print(x);\n    }\n    return (int) x;\n}\n";
+        let workdir = setup_workdir("doubles", "sample", source);
+
+        let text = String::from_utf8(compile_in(&workdir, "sample")).unwrap();
+        let body: Vec<&str> = text.lines().skip(2).collect();
+        assert_eq!(
+            body,
+            [
+                "%t0 <- $3",
+                "%t2 <- i2d %t0",
+                "%t3 <- %t2 *d $1.5",
+                "%t1 <- %t3",
+                "cmpd %t1 is_g $2.0",
+                "jmp is_g L0 L1",
+                "L0:",
+                "print %f %t1",
+                "L1:",
+                "%t4 <- d2i %t1",
+                "%eax <- %t4",
+                "ret",
+            ]
+        );
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_main_is_required_unless_compiling_a_library() {
+        let workdir = setup_workdir("library", "sample", "int helper() { return 1; }\n");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .arg("sample")
+            .current_dir(&workdir)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("No 'int main()' function found"));
+
+        let text = String::from_utf8(compile_with_flags(&workdir, "sample", &["--lib"])).unwrap();
+        assert!(text.contains(".helper\n"));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_ssa_output_assigns_each_temp_once() {
+        let source = r#"
+int main() {
+    int sum = 0;
+    int i = 0;
+    while (i < 5) {
+        if (i == 3) {
+            sum = sum + 10;
+        } else {
+            sum = sum + i;
+        }
+        i = i + 1;
+    }
+    return sum;
+}
+"#;
+        let workdir = setup_workdir("ssa", "sample", source);
+        let text = String::from_utf8(compile_with_flags(&workdir, "sample", &["--ssa"])).unwrap();
+
+        let mut assigned = Vec::new();
+        for line in text.lines() {
+            let dest = line
+                .strip_prefix("phi ")
+                .or_else(|| line.strip_prefix("set "))
+                .unwrap_or(line);
+            if dest.starts_with("%t") {
+                assigned.push(dest.split(' ').next().unwrap().to_string());
+            }
+        }
+        let mut unique = assigned.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), assigned.len(), "{}", text);
+
+        // `sum` and `i` merge at the loop header, and `sum` after the `if`
+        let phis: Vec<&str> = text.lines().filter(|l| l.starts_with("phi ")).collect();
+        assert_eq!(phis.len(), 3, "{}", text);
+        assert!(phis.iter().all(|phi| phi.matches(", L").count() == 2));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_constant_folding_pass() {
+        let source = r#"
+int main() {
+    int x = 2 + 3 * 4;
+    if (x > 10) {
+        x = x - 1;
+    }
+    return x / 0;
+}
+"#;
+        let workdir = setup_workdir("fold", "sample", source);
+
+        let text = String::from_utf8(compile_in(&workdir, "sample")).unwrap();
+        assert!(text.contains(" * "));
+
+        let text = String::from_utf8(compile_with_flags(
+            &workdir,
+            "sample",
+            &["-ffold-constants"],
+        ))
+        .unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(!text.contains(" * "), "{}", text);
+        assert!(lines.contains(&"%t0 <- $14"));
+        // The branch is always taken, and the division by zero is left to fail at runtime
+        assert!(lines.contains(&"jmp L0"));
+        assert!(lines.contains(&"%t4 <- %t0 / $0"));
+
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["-fno-such-pass", "sample"])
+            .current_dir(&workdir)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("Unknown pass 'such-pass'. Known passes: "));
+        assert!(stderr.contains("fold-constants"));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_out_of_range_casts_abort() {
+        let source = "int main() {\n    print(\"%d %d %d\\n\", (int)2.9, (int)-2.9, (int)-2147483648.75);\n    double zero = 0.0;\n    print(\"%d\\n\", (int)(zero / zero));\n    print(\"%d\\n\", (int)10000000000.5);\n    return 0;\n}\n";
+        let workdir = setup_workdir("fold-casts", "sample", source);
+
+        // Only the casts in range are folded
+        let text = compile_with_flags(&workdir, "sample", &["-ffold-constants"]);
+        let text = String::from_utf8(text).unwrap();
+        assert_eq!(text.matches(" <- d2i ").count(), 2, "{}", text);
+
+        // The rest abort, after what was printed, whether or not the program is optimized
+        for level in ["-O0", "-O2"] {
+            compile_with_flags(&workdir, "sample", &["--target=x86_64", level]);
+            let output = run_x86(&workdir, "sample");
+            assert_eq!(
+                String::from_utf8(output.stdout).unwrap(),
+                "2 -2 -2147483648\n",
+                "{}",
+                level
+            );
+            assert_eq!(output.status.code(), None, "{}", level);
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_dead_code_elimination_pass() {
+        let source = r#"
+int main() {
+    int sum = 0;
+    int i = 0;
+    int unused = sum * 2;
+    int fails = i / 0;
+    while (i < 10) {
+        sum = sum + i;
+        i = i + 1;
+    }
+    return sum;
+}
+"#;
+        let workdir = setup_workdir("dce", "sample", source);
+        let text = String::from_utf8(compile_with_flags(&workdir, "sample", &["-fdce"])).unwrap();
+        assert_eq!(
+            text.lines().collect::<Vec<_>>(),
+            [
+                ".globl main",
+                ".main",
+                "%t0 <- $0",
+                "%t1 <- $0",
+                // Kept, since it fails at runtime
+                "%t4 <- %t1 / $0",
+                "L0:",
+                "cmp %t1 is_l $10",
+                "jmp is_l L1 L2",
+                "L1:",
+                "%t0 <- %t0 + %t1",
+                "%t1 <- %t1 + $1",
+                "jmp L0",
+                "L2:",
+                "%eax <- %t0",
+                "ret",
+            ]
+        );
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_strength_reduction_pass() {
+        let source = r#"
+int f(int x) {
+    int a = x * 8;
+    int b = x / 4;
+    int c = x + 0;
+    int d = x - x;
+    return a + b + c + d;
+}
+
+int main() {
+    return 0;
+}
+"#;
+        let workdir = setup_workdir("strength", "sample", source);
+        let text = String::from_utf8(compile_with_flags(
+            &workdir,
+            "sample",
+            &["--lib", "-fstrength-reduce", "-fdce"],
+        ))
+        .unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[2..lines.iter().position(|&l| l == ".globl main").unwrap()],
+            [
+                "%t2 <- %t0 << $3",
+                // Rounded toward zero by adding 3 to negative dividends
+                "%t12 <- %t0 >> $31",
+                "%t13 <- %t12 >>> $30",
+                "%t14 <- %t0 + %t13",
+                "%t4 <- %t14 >> $2",
+                // `x - x`
+                "%t8 <- $0",
+                "%t9 <- %t2 + %t4",
+                "%t10 <- %t9 + %t0",
+                "%t11 <- %t10 + %t8",
+                "%eax <- %t11",
+                "ret",
+            ]
+        );
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_loop_unrolling_pass() {
+        let source = r#"
+int main() {
+    int sum = 0;
+    for (int i = 0; i < 3; i = i + 1) {
+        sum = sum + i;
+    }
+    int j = 0;
+    while (j < sum) {
+        j = j + 2;
+    }
+    return sum + j;
+}
+"#;
+        let workdir = setup_workdir("unroll", "sample", source);
+        let count = |text: &str, line: &str| text.lines().filter(|&l| l == line).count();
+
+        let text =
+            String::from_utf8(compile_with_flags(&workdir, "sample", &["-funroll-loops"])).unwrap();
+        // The `for` loop runs 3 times, so it's replaced with 3 copies of its body
+        assert_eq!(count(&text, "%t2 <- %t0 + %t1"), 3, "{}", text);
+        assert_eq!(count(&text, "cmp %t1 is_l $3"), 0);
+        // The `while` loop's trip count isn't known, so each copy still tests the condition
+        assert_eq!(count(&text, "%t5 <- %t4 + $2"), 2);
+        assert_eq!(count(&text, "cmp %t4 is_l %t0"), 2);
+
+        let text = String::from_utf8(compile_with_flags(
+            &workdir,
+            "sample",
+            &["-funroll-loops", "--unroll-factor=1"],
+        ))
+        .unwrap();
+        assert_eq!(count(&text, "%t2 <- %t0 + %t1"), 3);
+        assert_eq!(count(&text, "cmp %t4 is_l %t0"), 1);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_inlining_pass() {
+        let source = r#"
+int square(int x) {
+    return x * x;
+}
+
+int fact(int n) {
+    if (n < 2) return 1;
+    return n * fact(n - 1);
+}
+
+void report(int value) {
+    if (value > 100) {
+        print("big\n");
+        return;
+    }
+    print("%d\n", value);
+}
+
+int main() {
+    int y = square(3) + square(4);
+    report(y);
+    return fact(y);
+}
+"#;
+        let workdir = setup_workdir("inline", "sample", source);
+
+        let text =
+            String::from_utf8(compile_with_flags(&workdir, "sample", &["-finline"])).unwrap();
+        let main = &text[text.find(".main").unwrap()..];
+        // Small functions are copied into the caller, with each return jumping past the copy
+        assert!(!main.contains("call square"), "{}", text);
+        assert!(!main.contains("call report"), "{}", text);
+        assert_eq!(main.matches("jmp L2").count(), 2, "{}", text);
+        // A recursive function never is
+        assert!(main.contains("call fact %t0"), "{}", text);
+        assert_eq!(text.matches("call fact").count(), 2);
+
+        // Inlined, the arguments are constants for the other passes
+        let text = String::from_utf8(compile_with_flags(&workdir, "sample", &["-O2"])).unwrap();
+        let main = &text[text.find(".main").unwrap()..];
+        assert!(main.contains("%t0 <- $25"), "{}", text);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_simplify_cfg_pass() {
+        let source = r#"
+int main() {
+    int x = 3;
+    while (x < 10) {
+        if (1 < 2) {
+            x = x + 1;
+        } else {
+            x = x - 1;
+        }
+    }
+    return x;
+}
+"#;
+        let workdir = setup_workdir("simplify_cfg", "sample", source);
+
+        let text = String::from_utf8(compile_in(&workdir, "sample")).unwrap();
+        assert!(text.contains("cmp $1 is_l $2"));
+
+        let text =
+            String::from_utf8(compile_with_flags(&workdir, "sample", &["-fsimplify-cfg"])).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        // The `if` always takes its first branch, so the other one is deleted, and the loop
+        // body jumps straight back to the test instead of through the end of the `if`
+        assert!(!text.contains("cmp $1 is_l $2"), "{}", text);
+        assert!(!text.contains(" - "));
+        assert_eq!(
+            lines[lines.len() - 9..],
+            [
+                "cmp %t0 is_l $10",
+                "jmp is_l L3 L2",
+                "L3:",
+                "%t1 <- %t0 + $1",
+                "%t0 <- %t1",
+                "jmp L0",
+                "L2:",
+                "%eax <- %t0",
+                "ret",
+            ]
+        );
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_optimization_levels() {
+        let source = r#"
+int main() {
+    int x = 3;
+    while (x < 10) {
+        if (1 < 2) {
+            x = x * 2;
+        }
+    }
+    return x;
+}
+"#;
+        let workdir = setup_workdir("opt_levels", "sample", source);
+        let compile = |flags: &[&str]| compile_with_flags(&workdir, "sample", flags);
+
+        assert_eq!(compile(&["-O0"]), compile(&[]));
+        assert_eq!(
+            compile(&["-O1"]),
+            compile(&["-fsimplify-cfg", "-ffold-constants", "-fdce"])
+        );
+        assert_eq!(
+            compile(&["-O2"]),
+            compile(&[
+                "-fsimplify-cfg",
+                "-finline",
+                "-funroll-loops",
+                "-ffold-constants",
+                "-fstrength-reduce",
+                "-fdce",
+            ])
+        );
+        // `-fno-<pass>` takes a pass out of the level's pipeline, whichever comes first
+        assert_eq!(
+            compile(&["-fno-dce", "-O1"]),
+            compile(&["-fsimplify-cfg", "-ffold-constants"])
+        );
+        assert_eq!(
+            compile(&["-O1", "-fstrength-reduce"]),
+            compile(&["-O2", "-fno-inline", "-fno-unroll-loops"])
+        );
+
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["-O1", "--time-passes", "sample"])
+            .current_dir(&workdir)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let timed: Vec<&str> = stderr
+            .lines()
+            .filter(|line| line.ends_with(" ms"))
+            .filter_map(|line| line.split_whitespace().next())
+            .collect();
+        assert_eq!(timed, ["simplify-cfg", "fold-constants", "dce"]);
+
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["-O3", "sample"])
+            .current_dir(&workdir)
+            .output()
+            .unwrap();
+        assert!(String::from_utf8(output.stderr).unwrap().contains("Usage:"));
+
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["--target=x86_64-apple-darwin", "sample"])
+            .current_dir(&workdir)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr
+            .contains("Unknown target 'x86_64-apple-darwin'. Known targets: abstract, x86_64"));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_passes_keep_abstract_assembly_valid() {
+        let source = r#"
+int main() {
+    int s = 0;
+    for (int i = 0; i < 4; i = i + 1) {
+        for (int j = 0; j < i; j = j + 1) {
+            if (j < 2) { s = s + j; } else { s = s - 1; }
+        }
+        while (s > 100) { s = s / 2; }
+    }
+    int k;
+    k = s;
+    while (1 < 2) { k = k + 1; if (k > 3) { return s + k; } }
+    return s;
+}
+"#;
+        let workdir = setup_workdir("verify", "sample", source);
+
+        // Debug builds of the compiler verify the instructions after every pass, and fail
+        // if one leaves them broken
+        for flags in [
+            &["-O1"][..],
+            &["-O2"],
+            &["-O2", "--ssa"],
+            &["-O2", "--unroll-factor=4", "-fno-simplify-cfg"],
+            &["--ssa"],
+        ] {
+            let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .args(flags)
+                .arg("sample")
+                .current_dir(&workdir)
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8(output.stderr).unwrap();
+            assert!(output.status.success(), "{:?}: {}", flags, stderr);
+            assert!(!stderr.contains("invalid abstract assembly"));
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_from_ir_reads_back_emitted_abstract_assembly() {
+        let source = r#"
+int limit = 10;
+static double scale = 2.5;
+
+static double half(double x) {
+    return -x / 2.0;
+}
+
+int main() {
+    int x = 0;
+    double d = 1.5;
+    while (x < 10) {
+        x = x + 3;
+        d = d * 2.0;
+    }
+    print("x=%d d=%f\n", x, d);
+    return x;
+}
+"#;
+        let workdir = setup_workdir("from_ir", "sample", source);
+
+        for flags in [&[][..], &["--ssa"], &["-O2"]] {
+            let emitted = compile_with_flags(&workdir, "sample", flags);
+            fs::write(workdir.join("samples").join("copy.o0"), &emitted).unwrap();
+            let reread = compile_with_flags(&workdir, "copy", &["--from-ir"]);
+            assert_eq!(
+                String::from_utf8(reread).unwrap(),
+                String::from_utf8(emitted).unwrap()
+            );
+        }
+
+        // Passes run on the IR read back like on the IR the compiler generates
+        let emitted = compile_in(&workdir, "sample");
+        fs::write(workdir.join("samples").join("copy.o0"), emitted).unwrap();
+        assert_eq!(
+            compile_with_flags(&workdir, "copy", &["--from-ir", "-O2"]),
+            compile_with_flags(&workdir, "sample", &["-O2"])
+        );
+
+        fs::write(
+            workdir.join("samples").join("broken.o0"),
+            ".main\n%t0 <- $1\njmp L7\n",
+        )
+        .unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["--from-ir", "broken"])
+            .current_dir(&workdir)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.contains("line 3: in function 'main': jump to L7, which isn't defined"),
+            "{}",
+            stderr
+        );
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_dump_ir_after_each_pass() {
+        let workdir = setup_workdir("dump-ir", "sample", SAMPLE);
+        let output = compile_with_flags(&workdir, "sample", &["-O1", "--dump-ir=after-all"]);
+
+        // One file per pass, numbered in the order they ran, which `--from-ir` reads back
+        let target = workdir.join("samples").join("target");
+        let mut dumps: Vec<String> = fs::read_dir(&target)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".o0"))
+            .collect();
+        dumps.sort();
+        assert_eq!(
+            dumps,
+            [
+                "sample.01.simplify-cfg.o0",
+                "sample.02.fold-constants.o0",
+                "sample.03.dce.o0"
+            ]
+        );
+        let last = fs::read_to_string(target.join("sample.03.dce.o0")).unwrap();
+        assert_eq!(last.as_bytes(), output);
+
+        let stdout = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["-O1", "--dump-ir=after-all", "--dump-ir-stdout", "sample"])
+            .current_dir(&workdir)
+            .output()
+            .unwrap()
+            .stdout;
+        let stdout = String::from_utf8(stdout).unwrap();
+        let headers: Vec<&str> = stdout
+            .lines()
+            .filter(|line| line.starts_with("# After pass"))
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                "# After pass 1 'simplify-cfg'",
+                "# After pass 2 'fold-constants'",
+                "# After pass 3 'dce'"
+            ]
+        );
+        assert!(stdout.contains(&last));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_debug_names_follow_variables() {
+        let source = "int main() {\n    int sum = 0;\n    for (int i = 0; i < 4; i = i + 1) {\n        sum = sum + i;\n    }\n    double half = sum / 2.0;\n    return (int) half;\n}\n";
+        let workdir = setup_workdir("debug-names", "sample", source);
+
+        let plain = String::from_utf8(compile_in(&workdir, "sample")).unwrap();
+        assert!(!plain.contains("%t0."));
+        let named = String::from_utf8(compile_with_flags(&workdir, "sample", &["-g"])).unwrap();
+        assert!(named.contains("%t0.sum <- $0\n"), "{}", named);
+        assert!(named.contains("cmp %t1.i is_l $4\n"), "{}", named);
+        // Each statement is marked with its line
+        assert!(named.contains("loc 0 2\n%t0.sum <- $0\n"), "{}", named);
+        let unmarked: String = named
+            .lines()
+            .filter(|line| !line.starts_with("loc "))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        // Temps that hold no variable stay anonymous
+        assert_eq!(
+            unmarked
+                .replace(".sum", "")
+                .replace(".i ", " ")
+                .replace(".i\n", "\n")
+                .replace(".half", ""),
+            plain
+        );
+
+        // Every SSA version of a variable keeps its name
+        let ssa =
+            String::from_utf8(compile_with_flags(&workdir, "sample", &["-g", "--ssa"])).unwrap();
+        let phis: Vec<&str> = ssa.lines().filter(|line| line.starts_with("phi")).collect();
+        assert_eq!(phis.len(), 2, "{}", ssa);
+        assert!(phis
+            .iter()
+            .all(|phi| phi.matches(".sum").count() == 3 || phi.matches(".i").count() == 3));
+
+        // The names are read back
+        fs::write(workdir.join("samples").join("copy.o0"), &ssa).unwrap();
+        let reread = compile_with_flags(&workdir, "copy", &["-g", "--from-ir"]);
+        assert_eq!(String::from_utf8(reread).unwrap(), ssa);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_instruction_selection_tiles_trees() {
+        let source = "int main() {\n    int a = 5;\n    int s = 0;\n    for (int i = 0; i < 10; i = i + 1) {\n        s = s + a + i * 4 + 8;\n    }\n    double d = 1.5;\n    if (d < 2.0) {\n        s = s / 3;\n    }\n    return s - 1;\n}\n";
+        let workdir = setup_workdir("x86-isel", "sample", source);
+
+        let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &["--target=x86_64"]))
+            .unwrap();
+        // The scaled index and the constant fold into one `lea`
+        captures(r"\tleal 8\(%r\w+,%r\w+,4\), %e\w+\n", &x86);
+        assert!(!x86.contains("imull"), "{}", x86);
+        // Constants are immediates, and doubles are read from memory
+        captures(r"\tcmpl \$10, %e\w+\n", &x86);
+        captures(r"\tmovsd \.LD3ff8000000000000\(%rip\), %\w+\n", &x86);
+        assert!(x86.contains(".LD3ff8000000000000:\n\t.quad 0x3ff8000000000000\n"));
+        captures(r"\tcltd\n\tidivl %e\w+\n", &x86);
+        captures(r"\tleal -1\(%r\w+\), %eax\n(\tpopq %r\w+\n)+\tret\n", &x86);
+
+        // Phis are replaced with copies
+        let ssa = String::from_utf8(compile_with_flags(
+            &workdir,
+            "sample",
+            &["--target=x86_64", "--ssa"],
+        ))
+        .unwrap();
+        assert!(!ssa.contains("phi"), "{}", ssa);
+        captures(r"\tleal 8\(%r\w+,%r\w+,4\), %e\w+\n", &ssa);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_arithmetic_is_two_address() {
+        let source = "int main() {\n    int a = 5;\n    int b = 9;\n    double d = 2.0;\n    double e = 7.0;\n    for (int i = 0; i < 3; i = i + 1) {\n        a = b - a;\n        b = a * b;\n        d = e / d;\n    }\n    print(\"%f\\n\", d);\n    return a + b;\n}\n";
+        let workdir = setup_workdir("x86-two-address", "sample", source);
+
+        let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &["--target=x86_64"]))
+            .unwrap();
+        // Each instruction overwrites its first operand, even when the result is the second one
+        let negated = captures(r"\tnegl (%\w+)\n\taddl %\w+, (%\w+)\n", &x86);
+        assert_eq!(negated[1], negated[2]);
+        captures(r"\timull %\w+, %\w+\n", &x86);
+        let divided = captures(
+            r"\tmovsd (%\w+), (%\w+)\n\tmovsd %\w+, (%\w+)\n\tdivsd (%\w+), (%\w+)\n",
+            &x86,
+        );
+        // The divisor is copied aside before the quotient's temp is overwritten
+        assert_eq!((&divided[1], &divided[2]), (&divided[3], &divided[4]));
+        assert_eq!(divided[3], divided[5]);
+        captures(
+            r"(\tmovl %\w+, %eax\n)?\taddl %\w+, %eax\n(\tpopq %r\w+\n)*\tret\n",
+            &x86,
+        );
+        assert!(x86
+            .lines()
+            .filter(|line| !line.contains("call") && !line.starts_with("\t."))
+            .all(|line| line.matches(',').count() <= 1 || line.contains('(')));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_int_temps_get_registers() {
+        let source = "int main() {\n    int a = 5;\n    int b = 7;\n    int sum = 0;\n    for (int i = 0; i < a * b; i = i + 1) {\n        sum = sum + i / b;\n    }\n    return sum - a;\n}\n";
+        let workdir = setup_workdir("x86-registers", "sample", source);
+
+        let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &["--target=x86_64"]))
+            .unwrap();
+        assert!(!x86.contains("%t"), "{}", x86);
+        // The divisor can't be in the registers `idiv` overwrites
+        let divisor = captures(r"\tidivl (%e\w+)\n", &x86);
+        assert!(!["%eax", "%edx"].contains(&divisor[1].as_str()), "{}", x86);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_spills_when_registers_run_out() {
+        // Twenty variables live through the loop, more than there are registers
+        let source = format!(
+            "int main() {{\n{}    return sumv;\n}}\n",
+            register_pressure("v", 20)
+        );
+        let workdir = setup_workdir("x86-spills", "sample", &source);
+
+        let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &["--target=x86_64"]))
+            .unwrap();
+        assert!(!x86.contains("%t"), "{}", x86);
+        // Spilled temps are stored after they're written and reloaded before they're read
+        let stored = captures(r"\tmovl %e\w+, (\d*\(%rsp\))\n", &x86);
+        assert!(
+            x86.contains(&format!("\tmovl {}, %e", stored[1])),
+            "{}",
+            x86
+        );
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_spilled_temps_share_stack_slots() {
+        let one = format!(
+            "int main() {{\n{}    return sumv;\n}}\n",
+            register_pressure("v", 20)
+        );
+        let two = format!(
+            "int main() {{\n{}{}    return sumv + sumw;\n}}\n",
+            register_pressure("v", 20),
+            register_pressure("w", 20)
+        );
+        let workdir = setup_workdir("x86-stack-slots", "one", &one);
+        fs::write(workdir.join("samples").join("two.c0"), two).unwrap();
+
+        let one =
+            String::from_utf8(compile_with_flags(&workdir, "one", &["--target=x86_64"])).unwrap();
+        let two =
+            String::from_utf8(compile_with_flags(&workdir, "two", &["--target=x86_64"])).unwrap();
+        // The second loop's spills reuse the first's slots, which are dead by then
+        assert!(stack_slots(&one) > 0, "{}", one);
+        assert!(stack_slots(&two) <= stack_slots(&one) + 2, "{}", two);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_constants_are_recomputed_instead_of_spilled() {
+        // Twenty constants live through the loop, more than there are registers
+        let mut source = String::from("int main() {\n");
+        for i in 0..20 {
+            source += &format!("    int k{} = {};\n", i, 1000 + i);
+        }
+        source += "    int sum = 0;\n    for (int i = 0; i < 10; i = i + 1) {\n";
+        for i in 0..20 {
+            source += &format!("        sum = sum + k{} / 7;\n", i);
+        }
+        source += "    }\n    return sum;\n}\n";
+        let workdir = setup_workdir("x86-rematerialize", "sample", &source);
+
+        let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &["--target=x86_64"]))
+            .unwrap();
+        assert!(!x86.contains("%t"), "{}", x86);
+        assert_eq!(stack_slots(&x86), 0, "{}", x86);
+        // Some constant is loaded inside the loop, right where it's divided
+        captures(r"\tmovl \$10\d\d, %eax\n\tcltd\n", &x86);
+        assert!(!x86.contains("\tmovl %eax, %eax\n"), "{}", x86);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_calls_take_arguments_in_registers() {
+        let source = "int main() {\n    int a = 5;\n    int b = 7;\n    for (int i = 0; i < a; i = i + 1) {\n        print(\"%d %s\\n\", i * b, \"x\");\n        b = b + a;\n    }\n    return a + b;\n}\n";
+        let workdir = setup_workdir("x86-calls", "sample", source);
+
+        let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &["--target=x86_64"]))
+            .unwrap();
+        captures(r"\tmovl %e\w+, %edi\n\tcall c0_print_int\n", &x86);
+        captures(r"\tmovq %r\w+, %rdi\n\tcall c0_print_string\n", &x86);
+        // a, b and i live across the calls, so they're kept where calls leave them alone
+        let sum = captures(r"\taddl (%\w+), (%\w+)\n\tleal 1\((%\w+)\), %\w+\n", &x86);
+        let caller_saved = [
+            "%eax", "%ecx", "%edx", "%esi", "%edi", "%r8d", "%r9d", "%r10d", "%r11d", "%rax",
+            "%rcx", "%rdx", "%rsi", "%rdi", "%r8", "%r9", "%r10", "%r11",
+        ];
+        for register in &sum[1..] {
+            assert!(!caller_saved.contains(&register.as_str()), "{}", x86);
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_saves_the_callee_saved_registers_it_uses() {
+        let source = "int main() {\n    int a = 5;\n    int b = 7;\n    for (int i = 0; i < a; i = i + 1) {\n        print(\"%d\\n\", b);\n        b = b * a;\n    }\n    return b;\n}\n";
+        let workdir = setup_workdir("x86-callee-saved", "sample", source);
+
+        let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &["--target=x86_64"]))
+            .unwrap();
+        // a, b and i live across the call, so they're in registers the function has to restore.
+        // With the return address and the frame pointer, that's 40 bytes pushed, and the stack is
+        // padded back to a multiple of 16 for the call.
+        let pushed = captures(
+            r"main:\n\tpushq %rbp\n\tmovq %rsp, %rbp\n\tpushq (%\w+)\n\tpushq (%\w+)\n\tpushq (%\w+)\n\tsubq \$8, %rsp\n\.L",
+            &x86,
+        );
+        let popped = captures(
+            r"\taddq \$8, %rsp\n\tpopq (%\w+)\n\tpopq (%\w+)\n\tpopq (%\w+)\n\tpopq %rbp\n\tret\n",
+            &x86,
+        );
+        assert_eq!(
+            (&pushed[1], &pushed[2], &pushed[3]),
+            (&popped[3], &popped[2], &popped[1])
+        );
+        for register in &pushed[1..] {
+            let callee_saved = ["%rbx", "%r12", "%r13", "%r14", "%r15"];
+            assert!(callee_saved.contains(&register.as_str()), "{}", x86);
+        }
+
+        // Without calls, the registers a call leaves alone aren't needed; only the frame pointer
+        // is saved
+        let source = "int main() {\n    int a = 5;\n    int b = 7;\n    for (int i = 0; i < a; i = i + 1) {\n        b = b * a;\n    }\n    return b;\n}\n";
+        let workdir = setup_workdir("x86-callee-saved", "sample", source);
+        let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &["--target=x86_64"]))
+            .unwrap();
+        assert_eq!(x86.matches("push").count(), 1, "{}", x86);
+        assert!(x86.contains("\tpopq %rbp\n\tret\n"), "{}", x86);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_double_temps_get_xmm_registers() {
+        let source = "int main() {\n    double d = 2.0;\n    double e = 7.0;\n    int n = 3;\n    for (int i = 0; i < n; i = i + 1) {\n        d = e / d + 1.5;\n        print(\"%f\\n\", d);\n        n = n + (int) d;\n    }\n    return (int) (d * e) + n;\n}\n";
+        let workdir = setup_workdir("x86-xmm", "sample", source);
+
+        let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &["--target=x86_64"]))
+            .unwrap();
+        assert!(!x86.contains("%t"), "{}", x86);
+        captures(r"\tdivsd %xmm\d+, %xmm\d+\n", &x86);
+        captures(r"\tcvttsd2si %xmm\d+, %e\w+\n", &x86);
+        // Calls may overwrite every xmm register, so d is kept on the stack across them
+        captures(r"\tmovsd %xmm\d+, \d*\(%rsp\)\n", &x86);
+        captures(
+            r"\tmovsd \d*\(%rsp\), %xmm0\n\tcall c0_print_double\n",
+            &x86,
+        );
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_linear_scan_allocates_like_graph_coloring() {
+        let source = format!(
+            "int main() {{\n{}    double d = 2.0;\n    for (int i = 0; i < 3; i = i + 1) {{\n        d = d * 1.5;\n        print(\"%f %d\\n\", d, sumv);\n    }}\n    return sumv + (int) d;\n}}\n",
+            register_pressure("v", 20)
+        );
+        let workdir = setup_workdir("x86-linear-scan", "sample", &source);
+
+        let graph =
+            compile_with_flags(&workdir, "sample", &["--target=x86_64", "--regalloc=graph"]);
+        let graph = String::from_utf8(graph).unwrap();
+        let linear = compile_with_flags(
+            &workdir,
+            "sample",
+            &["--target=x86_64", "--regalloc=linear"],
+        );
+        let linear = String::from_utf8(linear).unwrap();
+        for x86 in [&graph, &linear] {
+            assert!(!x86.contains("%t"), "{}", x86);
+            assert!(stack_slots(x86) > 0, "{}", x86);
+            captures(r"\tmovl %\w+, %edi\n\tcall c0_print_int\n", x86);
+            captures(
+                r"\tmovsd %xmm\d+, %xmm0\n\tcall c0_print_double\n|\tmovsd \d*\(%rsp\), %xmm0\n\tcall c0_print_double\n",
+                x86,
+            );
+        }
+        // Only the allocation differs; the calls and divisions are the same
+        let mnemonics = |x86: &str| -> Vec<String> {
+            x86.lines()
+                .filter(|line| line.contains("call") || line.contains("idiv"))
+                .map(|line| {
+                    line.split_whitespace()
+                        .take(2)
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .map(|line| line.split('%').next().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(mnemonics(&graph), mnemonics(&linear));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_output_assembles_and_runs() {
+        let source = format!(
+            "int main() {{\n{}    double d = 1.5;\n    for (int i = 0; i < 3; i = i + 1) {{\n        d = d * 2.0;\n        print(\"%d %s\\n\", sumv / (i + 1), \"x\");\n    }}\n    print(\"%f\\n\", d);\n    return sumv - sumv / 100 * 100 + (int) d;\n}}\n",
+            register_pressure("v", 20)
+        );
+        let workdir = setup_workdir("x86-run", "sample", &source);
+        // What the loop of `register_pressure` leaves in sumv
+        let mut v: Vec<i32> = (0..20).map(|i| i * 7).collect();
+        for _ in 0..10 {
+            for i in 0..20 {
+                v[i] += v[(i + 1) % 20] / 3;
+            }
+        }
+        let sum: i32 = v.iter().sum();
+
+        for allocator in ["--regalloc=graph", "--regalloc=linear"] {
+            let x86 = compile_with_flags(&workdir, "sample", &["--target=x86_64", allocator]);
+            // Enough is live that some of it is on the stack
+            assert!(stack_slots(&String::from_utf8(x86).unwrap()) > 0);
+            let output = run_x86(&workdir, "sample");
+            assert_eq!(
+                String::from_utf8(output.stdout).unwrap(),
+                format!("{} x\n{} x\n{} x\n12.000000\n", sum, sum / 2, sum / 3)
+            );
+            assert_eq!(output.status.code(), Some(sum % 100 + 12));
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_not_makes_any_nonzero_int_false() {
+        let source = "int main() {\n    int x = 7;\n    int zero = 0;\n    print(\"%d %d %d %d\\n\", !x, !zero, !(x - 7), !-1);\n    return !x;\n}\n";
+        let workdir = setup_workdir("x86-not", "sample", source);
+        // Folded at -O2, and computed at -O0
+        for level in ["-O0", "-O2"] {
+            compile_with_flags(&workdir, "sample", &["--target=x86_64", level]);
+            let output = run_x86(&workdir, "sample");
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), "0 1 1 0\n");
+            assert_eq!(output.status.code(), Some(0));
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_calls_pass_arguments_in_registers_and_on_the_stack() {
+        let weigh = "int weigh(int a, int b, int c, int d, int e, int f, int g, int h) {\n    return a + 2 * b + 3 * c + 4 * d + 5 * e + 6 * f + 7 * g + 8 * h;\n}\n";
+        let blend = "double blend(double a, int b, double c, double d, double e, double f, double g, double h, double i, double j, int k) {\n    return a + b + c + d + e + f + g + h + i * 10.0 + j * 100.0 + k;\n}\n";
+        let fact = "int fact(int n) {\n    if (n <= 1) return 1;\n    return n * fact(n - 1);\n}\n";
+        let source = format!(
+            "{}{}{}int main() {{\n{}    int w = weigh(v0, v1, v2, v3, v4, v5, v6, v7) + weigh(v8, v9, v10, v11, v12, v13, v14, v15);\n    print(\"%d\\n\", w + sumv + v16 + v17 + v18 + v19);\n    print(\"%f\\n\", blend(1.5, 2, 3, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 0.5, 3));\n    return fact(5);\n}}\n",
+            weigh,
+            blend,
+            fact,
+            register_pressure("v", 20)
+        );
+        let workdir = setup_workdir("x86-calls", "sample", &source);
+        let mut v: Vec<i32> = (0..20).map(|i| i * 7).collect();
+        for _ in 0..10 {
+            for i in 0..20 {
+                v[i] += v[(i + 1) % 20] / 3;
+            }
+        }
+        let weigh = |args: &[i32]| -> i32 { (1..).zip(args).map(|(k, arg)| k * arg).sum() };
+        let expected = weigh(&v[0..8])
+            + weigh(&v[8..16])
+            + v.iter().sum::<i32>()
+            + v[16..].iter().sum::<i32>();
+
+        for allocator in ["--regalloc=graph", "--regalloc=linear"] {
+            let x86 = compile_with_flags(&workdir, "sample", &["--target=x86_64", allocator]);
+            let x86 = String::from_utf8(x86).unwrap();
+            // The seventh and eighth ints, and the ninth and tenth doubles, go on the stack
+            assert!(x86.contains("\tmovl %edi, "), "{}", x86);
+            assert!(x86.contains("\tmovl 24(%rbp), "), "{}", x86);
+            assert!(x86.contains("\tmovsd 16(%rbp), "), "{}", x86);
+            assert!(x86.contains("\tcall weigh\n"), "{}", x86);
+            // Spilled temps are kept above the arguments
+            assert!(stack_slots(&x86) > 2, "{}", x86);
+            let output = run_x86(&workdir, "sample");
+            assert_eq!(
+                String::from_utf8(output.stdout).unwrap(),
+                format!("{}\n179.500000\n", expected)
+            );
+            assert_eq!(output.status.code(), Some(120));
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_frame_pointer_omission_and_red_zone() {
+        let source = format!(
+            "int churn(int a, int b, int c, int d, int e, int f, int g, int h) {{\n{}    return sumv + a + 2 * b + 3 * c + 4 * d + 5 * e + 6 * f + 7 * g + 8 * h;\n}}\nint main() {{\n    print(\"%d\\n\", churn(1, 2, 3, 4, 5, 6, 7, 8));\n    return 0;\n}}\n",
+            register_pressure("v", 14)
+        );
+        let workdir = setup_workdir("x86-frames", "sample", &source);
+        let churn = |x86: &str| {
+            let start = x86.find("churn:\n").unwrap();
+            x86[start..start + x86[start..].find("\tret\n").unwrap()].to_string()
+        };
+
+        let mut outputs = Vec::new();
+        for flags in [
+            &[][..],
+            &["--fomit-frame-pointer"],
+            &["--red-zone"],
+            &["--fomit-frame-pointer", "--red-zone"],
+        ] {
+            let x86 =
+                compile_with_flags(&workdir, "sample", &[&["--target=x86_64"], flags].concat());
+            let x86 = String::from_utf8(x86).unwrap();
+            let omitted = flags.contains(&"--fomit-frame-pointer");
+            // %rbp frames are the default; without them, the stack arguments are found from %rsp
+            assert_eq!(x86.contains("\tpushq %rbp\n"), !omitted, "{}", x86);
+            assert_eq!(x86.contains("(%rbp)"), !omitted, "{}", x86);
+            // A function calling nothing spills under %rsp, without reserving anything
+            let churn = churn(&x86);
+            assert!(stack_slots(&churn) > 0, "{}", churn);
+            let red_zone = flags.contains(&"--red-zone");
+            assert_eq!(churn.contains("-8(%rsp)"), red_zone, "{}", churn);
+            assert_eq!(churn.contains("\tsubq $"), !red_zone, "{}", churn);
+            // main calls, so it keeps its frame aligned either way
+            assert!(
+                x86.contains("main:\n") && !x86[x86.find("main:").unwrap()..].contains("-8(%rsp)")
+            );
+            let output = run_x86(&workdir, "sample");
+            assert!(output.status.success());
+            outputs.push(output.stdout);
+        }
+        assert!(outputs.iter().all(|output| *output == outputs[0]));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_windows_target_uses_the_microsoft_calling_convention() {
+        let source = "int mix(int a, double b, int c, double d, int e) {\n    return a + (int) b + c + (int) d + e;\n}\nvoid shout(int x) {\n    print(\"%d\\n\", x);\n}\nint main() {\n    shout(mix(1, 2.5, 3, 4.5, 5));\n    return 0;\n}\n";
+        let workdir = setup_workdir("x86-windows", "sample", source);
+        let x86 = compile_with_flags(&workdir, "sample", &["--target=x86_64-pc-windows"]);
+        let x86 = String::from_utf8(x86).unwrap();
+        // Arguments take the registers of their position, whatever their type
+        assert!(x86.contains(", %ecx\n"), "{}", x86);
+        assert!(x86.contains(", %xmm1\n"), "{}", x86);
+        assert!(x86.contains(", %r8d\n"), "{}", x86);
+        assert!(x86.contains(", %xmm3\n"), "{}", x86);
+        // The fifth goes above the 32 bytes of shadow space, which the callee finds above its
+        // return address and saved frame pointer
+        assert!(x86.contains(", 32(%rsp)\n"), "{}", x86);
+        assert!(x86.contains("\tmovl 48(%rbp), "), "{}", x86);
+        // Shadow space is reserved for any call, and the stack is kept 16-byte aligned
+        let shout = &x86[x86.find("shout:\n").unwrap()..];
+        let shout = &shout[..shout.find("\tret\n").unwrap()];
+        assert!(shout.contains("\tsubq $32, %rsp\n"), "{}", x86);
+        // COFF's directives, and no ELF sections
+        assert!(
+            x86.contains("\t.def main; .scl 2; .type 32; .endef\n"),
+            "{}",
+            x86
+        );
+        assert!(x86.contains("\t.section .rdata,\"dr\"\n"), "{}", x86);
+        assert!(!x86.contains("@function") && !x86.contains(".note.GNU-stack"));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_functions_can_be_called_from_c() {
+        let source = "double average(int a, int b, int c, int d, int e, int f, int g, double scale) {\n    return (a + b + c + d + e + f + g) / 7 * scale;\n}\nstring greeting(char initial) {\n    print(\"%c\", initial);\n    return \"ello\";\n}\n";
+        let workdir = setup_workdir("x86-from-c", "sample", source);
+        compile_with_flags(&workdir, "sample", &["--target=x86_64", "--lib"]);
+        let main = workdir.join("main.c");
+        fs::write(
+            &main,
+            "#include <stdio.h>\ndouble average(int, int, int, int, int, int, int, double);\nconst char *greeting(int);\nint main(void) {\n    printf(\"%.2f\\n\", average(1, 2, 3, 4, 5, 6, 7, 0.5));\n    printf(\"%s\\n\", greeting('h'));\n    return 0;\n}\n",
+        )
+        .unwrap();
+        fs::write(workdir.join("runtime.c"), RUNTIME).unwrap();
+        let program = workdir.join("program");
+        let status = Command::new("cc")
+            .arg("-o")
+            .arg(&program)
+            .arg(workdir.join("samples").join("target").join("sample.S"))
+            .arg(&main)
+            .arg(workdir.join("runtime.c"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let output = Command::new(program).output().unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "2.00\nhello\n");
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_link() {
+        let source = "int main() {\n    print(\"%d\\n\", 7);\n    return 3;\n}\n";
+        let workdir = setup_workdir("link", "sample", source);
+        let link = |args: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .args(["sample", "--target=x86_64", "--link"])
+                .args(args)
+                .current_dir(&workdir)
+                .output()
+                .unwrap()
+        };
+
+        // Without the runtime, the linker's errors are reported like the compiler's
+        let stderr = String::from_utf8(link(&["--no-runtime"]).stderr).unwrap();
+        assert!(stderr.contains("error[E0203]:"));
+        assert!(stderr.contains("undefined reference to `c0_print_int' (in function 'main')"));
+        assert!(stderr.contains("Compilation failed with 2 error(s)"));
+        assert!(!workdir
+            .join("samples")
+            .join("target")
+            .join("sample")
+            .exists());
+
+        // A runtime of one's own replaces the compiler's
+        fs::write(workdir.join("samples").join("runtime.c"), RUNTIME).unwrap();
+        let output = link(&["--no-runtime", "runtime.c"]);
+        assert!(output.status.success());
+        let program = workdir.join("samples").join("target").join("sample");
+        let run = Command::new(&program).output().unwrap();
+        assert_eq!(String::from_utf8(run.stdout).unwrap(), "7\n");
+        assert_eq!(run.status.code(), Some(3));
+
+        assert!(link(&[]).status.success());
+        let run = Command::new(&program).output().unwrap();
+        assert_eq!(String::from_utf8(run.stdout).unwrap(), "7\n");
+
+        // `-o` names the executable
+        link(&["-o", "program"]);
+        let run = Command::new(workdir.join("program")).output().unwrap();
+        assert_eq!(run.status.code(), Some(3));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_objects_run_like_the_assembly() {
+        let source = "int counter = 7;\nstatic double scale = 0.5;\nstring name = \"obj\";\nint zero;\n\nint mix(int a, int b, int c, int d, int e, int f, int g, double x) {\n    return a * 3 + b * c - d / e + f / g * 5 + (int) (x * scale);\n}\n\nstatic int odd(int n) {\n    return n - n / 2 * 2;\n}\n\nint main() {\n    int total = zero;\n    double d = 1.25;\n    for (int i = 1; i < 40; i++) {\n        total += mix(i, i + 1, i - 2, total, i, -i, 7, d);\n        if (d < 100.0) {\n            if (total > -5) {\n                d = d * 1.5 - 0.25;\n            }\n        }\n        if (odd(i) == 1) {\n            counter = counter + total / 4 - (int) d;\n        }\n    }\n    print(\"%d %f %s %d %d %c\\n\", total, d / 3.0, name, counter, !odd(total), (char) 104);\n    return total - total / 64 * 64;\n}\n";
+        let workdir = setup_workdir("x86-object", "sample", source);
+        let target = workdir.join("samples").join("target");
+        let run = |flags: &[&str]| {
+            let status = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .args(["sample", "--target=x86_64", "--link"])
+                .args(flags)
+                .current_dir(&workdir)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            Command::new(target.join("sample")).output().unwrap()
+        };
+
+        // The object is assembled from the instructions the assembly holds, so the programs
+        // behave the same
+        for level in ["-O0", "-O2"] {
+            let assembly = run(&[level]);
+            let object = run(&[level, "--emit=obj"]);
+            assert!(fs::read(target.join("sample.o"))
+                .unwrap()
+                .starts_with(b"\x7fELF"));
+            assert_eq!(
+                String::from_utf8(object.stdout).unwrap(),
+                String::from_utf8(assembly.stdout).unwrap()
+            );
+            assert_eq!(object.status.code(), assembly.status.code());
+        }
+        // The zeroed global takes no space in the file
+        let output = Command::new("objdump")
+            .arg("-h")
+            .arg(target.join("sample.o"))
+            .output()
+            .unwrap();
+        let headers = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(captures(r"\.bss +([0-9a-f]+)", &headers)[1], "00000004");
+
+        // Debug information is only written in assembly
+        let status = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["sample", "--target=x86_64", "--emit=obj", "-g"])
+            .current_dir(&workdir)
+            .status()
+            .unwrap();
+        assert!(!status.success());
+        // and the abstract assembly has no object
+        let status = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["sample", "--emit=obj"])
+            .current_dir(&workdir)
+            .status()
+            .unwrap();
+        assert!(!status.success());
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_incremental_objects_reuse_unchanged_functions() {
+        let source = "double half = 0.5;\n\nint twice(int n) {\n    return n * 2;\n}\n\nint scaled(int n) {\n    return (int) (n * half * 3.0);\n}\n\nint main() {\n    print(\"%d %d\\n\", twice(3), scaled(twice(5)));\n    return 0;\n}\n";
+        let workdir = setup_workdir("incremental", "sample", source);
+        let target = workdir.join("samples").join("target");
+        let build = |flags: &[&str]| {
+            let output = compiler(
+                &workdir,
+                "sample",
+                &[&["--target=x86_64", "--emit=obj", "--verbose"], flags].concat(),
+            );
+            assert!(output.status.success());
+            let object = fs::read(target.join("sample.o")).unwrap();
+            (String::from_utf8(output.stderr).unwrap(), object)
+        };
+        let reused = |log: &str| -> Vec<String> {
+            Regex::new(r"reusing the machine code of '(\w+)'")
+                .unwrap()
+                .captures_iter(log)
+                .map(|captures| captures[1].to_string())
+                .collect()
+        };
+
+        // The first build caches every function, and the next reuses them all, for the same
+        // object as without the cache. Mangled symbols are cached apart from the others.
+        for flags in [&["--mangle"][..], &[]] {
+            let (_, plain) = build(flags);
+            let (log, first) = build(&[flags, &["--incremental"]].concat());
+            assert!(reused(&log).is_empty());
+            assert_eq!(first, plain);
+            let (log, second) = build(&[flags, &["--incremental"]].concat());
+            assert_eq!(reused(&log), ["twice", "scaled", "main"]);
+            assert_eq!(second, plain);
+        }
+
+        // After an edit, only the function that changed is compiled again
+        let edited = source.replace("n * 2", "n * 4");
+        fs::write(workdir.join("samples").join("sample.c0"), &edited).unwrap();
+        let (log, incremental) = build(&["--incremental"]);
+        assert_eq!(reused(&log), ["scaled", "main"]);
+        let (_, plain) = build(&[]);
+        assert_eq!(incremental, plain);
+        let output = compiler(
+            &workdir,
+            "sample",
+            &["--target=x86_64", "--emit=obj", "--incremental", "--link"],
+        );
+        assert!(output.status.success());
+        let run = Command::new(target.join("sample")).output().unwrap();
+        assert_eq!(String::from_utf8(run.stdout).unwrap(), "12 30\n");
+
+        // A cache entry that can't be read is compiled again
+        for entry in fs::read_dir(target.join("sample.cache")).unwrap() {
+            fs::write(entry.unwrap().path(), "symbol").unwrap();
+        }
+        let (log, rebuilt) = build(&["--incremental"]);
+        assert!(reused(&log).is_empty());
+        assert_eq!(rebuilt, plain);
+
+        // Only objects are cached
+        let output = compiler(&workdir, "sample", &["--target=x86_64", "--incremental"]);
+        assert!(!output.status.success());
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_windows_target_writes_coff_objects() {
+        let source = "int count = 7;\nstring name = \"coff\";\n\nstatic int twice(int n) {\n    return n * 2;\n}\n\nint main() {\n    print(\"%s %d\\n\", name, twice(count));\n    return 0;\n}\n";
+        let workdir = setup_workdir("windows-object", "sample", source);
+        let status = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["sample", "--target=x86_64-pc-windows", "--emit=obj"])
+            .current_dir(&workdir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let output = Command::new("objdump")
+            .args(["-r", "-t"])
+            .arg(workdir.join("samples").join("target").join("sample.o"))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let dump = String::from_utf8(output.stdout).unwrap();
+        assert!(dump.contains("file format pe-x86-64"), "{}", dump);
+        // Static functions are COFF's static class 3, and the others external, class 2
+        let class = |name: &str| {
+            let line = dump
+                .lines()
+                .find(|line| line.ends_with(&format!(" {}", name)))
+                .unwrap();
+            captures(r"\(scl +(\d+)\)", line)[1].clone()
+        };
+        assert_eq!(class("twice"), "3");
+        assert_eq!(class("main"), "2");
+        // The runtime is called through relocations, and the global string points at its
+        // constant
+        let relocation = |kind: &str, symbol: &str| {
+            dump.lines()
+                .any(|line| line.split_whitespace().skip(1).eq([kind, symbol]))
+        };
+        assert!(
+            relocation("IMAGE_REL_AMD64_REL32", "c0_print_int"),
+            "{}",
+            dump
+        );
+        assert!(relocation("IMAGE_REL_AMD64_ADDR64", ".LS0"), "{}", dump);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_dump_regalloc() {
+        let source = format!(
+            "int main() {{\n{}    return sumv;\n}}\n",
+            register_pressure("v", 20)
+        );
+        let workdir = setup_workdir("x86-dump-regalloc", "sample", &source);
+        compile_with_flags(&workdir, "sample", &["--target=x86_64", "--dump-regalloc"]);
+
+        let target = workdir.join("samples").join("target");
+        let dot = fs::read_to_string(target.join("sample.regalloc.dot")).unwrap();
+        assert!(dot.starts_with("graph regalloc {\n"), "{}", dot);
+        assert!(dot.contains("label=\"main\""), "{}", dot);
+        // Temps are labeled with their registers, and edges join interfering nodes
+        captures(
+            r#"\[label="%t\d+\\n%r\w+", shape=ellipse, fillcolor="[\d. ]+"\]"#,
+            &dot,
+        );
+        captures(r"f0n\d+ -- f0n\d+;", &dot);
+
+        let report = fs::read_to_string(target.join("sample.regalloc.txt")).unwrap();
+        let spilled = captures(
+            r"main\n  spilled: (%t\d+( %t\d+)*)\n  stack slots: (\d+)\n",
+            &report,
+        );
+        assert!(spilled[3].parse::<usize>().unwrap() > 0, "{}", report);
+        captures(
+            r"  moves coalesced: \d+\n  max pressure of ints: (\d+)\n",
+            &report,
+        );
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_allocation_is_the_same_every_run() {
+        // Enough variables to spill, so that every tie in the allocator is broken somewhere
+        let source = format!(
+            "int main() {{\n{}    double d = 1.5;\n    for (int i = 0; i < 3; i = i + 1) {{\n        d = d * 2.0;\n        print(\"%f\\n\", d);\n    }}\n    return sumv + (int) d;\n}}\n",
+            register_pressure("v", 20)
+        );
+        let workdir = setup_workdir("x86-deterministic", "sample", &source);
+
+        for allocator in ["--regalloc=graph", "--regalloc=linear"] {
+            let flags = ["--target=x86_64", allocator, "--dump-regalloc"];
+            let first = compile_with_flags(&workdir, "sample", &flags);
+            let target = workdir.join("samples").join("target");
+            let dot = fs::read(target.join("sample.regalloc.dot")).unwrap();
+            // Each run is a new process, with hash tables seeded differently
+            for _ in 0..4 {
+                assert_eq!(compile_with_flags(&workdir, "sample", &flags), first);
+                assert_eq!(fs::read(target.join("sample.regalloc.dot")).unwrap(), dot);
+            }
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_verbose_logs_to_stderr() {
+        let workdir = setup_workdir("verbose", "sample", SAMPLE);
+        let run = |flags: &[&str], log: Option<&str>| {
+            let mut command = Command::new(env!("CARGO_BIN_EXE_rust-compiler"));
+            command
+                .args(flags)
+                .arg("sample")
+                .current_dir(&workdir)
+                .env_remove("RUST_LOG");
+            if let Some(log) = log {
+                command.env("RUST_LOG", log);
+            }
+            let output = command.output().unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stderr).unwrap()
+        };
+
+        let quiet = run(&["--target=x86_64"], None);
+        assert!(!quiet.contains("[DEBUG"), "{}", quiet);
+
+        let verbose = run(&["--target=x86_64", "--verbose"], None);
+        captures(
+            r"\[DEBUG rust_compiler::codegen::register_allocator\] main: \d+ temps in registers",
+            &verbose,
+        );
+        assert!(!verbose.contains("interference graph:"), "{}", verbose);
+
+        let traced = run(
+            &["--target=x86_64"],
+            Some("rust_compiler::codegen::register_allocator=trace"),
+        );
+        assert!(traced
+            .contains("[TRACE rust_compiler::codegen::register_allocator] interference graph:"));
+        assert!(
+            !traced.contains("[DEBUG rust_compiler::codegen::pass]"),
+            "{}",
+            traced
+        );
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_debug_information_maps_lines_and_variables() {
+        let source = format!(
+            "int square(int x) {{\n    return x * x;\n}}\nint churn() {{\n{}    return sumv;\n}}\nint main() {{\n    int sum = 0;\n    for (int i = 0; i < 4; i = i + 1) {{\n        sum = sum + square(i);\n    }}\n    print(\"%d\\n\", sum + churn());\n    return 0;\n}}\n",
+            register_pressure("v", 20)
+        );
+        let workdir = setup_workdir("x86-debug-info", "sample", &source);
+        let plain = compile_with_flags(&workdir, "sample", &["--target=x86_64"]);
+        let plain = String::from_utf8(plain).unwrap();
+        assert!(!plain.contains(".loc") && !plain.contains(".cfi") && !plain.contains(".debug"));
+        let x86 = compile_with_flags(&workdir, "sample", &["-g", "--target=x86_64"]);
+        let x86 = String::from_utf8(x86).unwrap();
+        assert!(
+            x86.starts_with("\t.file 1 \"samples/sample.c0\"\n"),
+            "{}",
+            x86
+        );
+        // The file's name is relative to the directory the compiler ran in
+        let directory = format!("\t.string \"{}\"\n", workdir.display());
+        assert!(x86.contains(&directory), "{}", x86);
+        // The function's line covers its prologue, and each statement has its own
+        assert!(x86.contains("square:\n\t.cfi_startproc\n\t.loc 1 1\n\tpushq %rbp\n"));
+        assert!(x86.contains("\t.loc 1 10\n"), "{}", x86);
+
+        let output = run_x86(&workdir, "sample");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "23489\n");
+        let program = workdir.join("sample");
+        let dump = |option: &str| {
+            let output = Command::new("objdump")
+                .arg(option)
+                .arg(&program)
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+        let lines: HashSet<String> = dump("--dwarf=decodedline")
+            .lines()
+            .filter_map(
+                |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                    ["sample.c0", line, ..] => Some(line.to_string()),
+                    _ => None,
+                },
+            )
+            .collect();
+        for line in ["1", "2", "8", "9", "10", "12"] {
+            assert!(lines.contains(line), "{:?}", lines);
+        }
+        // Variables are found in their registers, or in their stack slots when spilled
+        let info = dump("--dwarf=info");
+        let location = |name: &str| {
+            let entry = &info[info
+                .find(&format!("DW_AT_name        : {}\n", name))
+                .unwrap()..];
+            entry.lines().nth(2).unwrap().to_string()
+        };
+        assert!(location("x").contains("DW_OP_reg"), "{}", info);
+        assert!(location("sum").contains("DW_OP_reg"), "{}", info);
+        assert!(
+            (0..20).any(|i| location(&format!("v{}", i)).contains("DW_OP_breg7 (rsp)")),
+            "{}",
+            info
+        );
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_mangling_keeps_functions_apart_from_libc() {
+        // Without mangling, the runtime's call to putchar would reach this function instead
+        let source = "int count = 3;\nint putchar(int c) {\n    return c + 1;\n}\nint main() {\n    print(\"%c%d\\n\", (char)111, putchar(6));\n    return 0;\n}\n";
+        let workdir = setup_workdir("x86-mangling", "sample", source);
+        let x86 = compile_with_flags(&workdir, "sample", &["--target=x86_64", "--mangle"]);
+        let x86 = String::from_utf8(x86).unwrap();
+        assert!(x86.contains("\t.globl _c0_putchar\n"), "{}", x86);
+        assert!(x86.contains("\tcall _c0_putchar\n"), "{}", x86);
+        assert!(x86.contains("\t.globl _c0_count\n"), "{}", x86);
+        // The C startup code calls main, and the runtime's functions are defined in C
+        assert!(x86.contains("\nmain:\n"), "{}", x86);
+        assert!(x86.contains("\tcall c0_print_char\n"), "{}", x86);
+        let output = run_x86(&workdir, "sample");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "o7\n");
+
+        let x86 = compile_with_flags(&workdir, "sample", &["--target=x86_64", "--mangle=my_"]);
+        let x86 = String::from_utf8(x86).unwrap();
+        assert!(x86.contains("\tcall my_putchar\n"), "{}", x86);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_pic_links_into_a_shared_library() {
+        let source = "static int twice(int x) {\n    return 2 * x;\n}\nint scale(int x) {\n    print(\"%d \", x);\n    return twice(x) + 1;\n}\nint api(int x) {\n    return scale(x) * 3;\n}\n";
+        let workdir = setup_workdir("x86-pic", "sample", source);
+        let flags = ["--lib", "--pic", "--target=x86_64", "--link"];
+        let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &flags)).unwrap();
+        // Calls that another object could answer go through the PLT, and static ones don't
+        assert!(x86.contains("\tcall scale@PLT\n"), "{}", x86);
+        assert!(x86.contains("\tcall c0_print_int@PLT\n"), "{}", x86);
+        assert!(x86.contains("\tcall twice\n"), "{}", x86);
+
+        let library = workdir.join("samples").join("target").join("sample.so");
+        let main = workdir.join("main.c");
+        fs::write(&main, "int api(int);\nint main(void) { return api(4); }\n").unwrap();
+        let program = workdir.join("main");
+        let status = Command::new("cc")
+            .arg("-o")
+            .arg(&program)
+            .arg(&main)
+            .arg(&library)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let output = Command::new(program)
+            .env("LD_LIBRARY_PATH", library.parent().unwrap())
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "4 ");
+        assert_eq!(output.status.code(), Some(27));
+
+        // Without `--pic`, the assembler picks the relocations
+        let x86 = compile_with_flags(&workdir, "sample", &["--lib", "--target=x86_64"]);
+        assert!(!String::from_utf8(x86).unwrap().contains("@PLT"));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_pic_reaches_globals_through_the_got() {
+        let source = "int count = 1;\nstatic int calls = 0;\nint bump(int by) {\n    calls = calls + 1;\n    count = count + by;\n    return count * 10 + calls;\n}\n";
+        let workdir = setup_workdir("x86-pic-globals", "sample", source);
+        let flags = ["--lib", "--pic", "--target=x86_64", "--link"];
+        let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &flags)).unwrap();
+        // Another object could define `count`, but `calls` can only be the one here
+        assert!(x86.contains("\tmovq count@GOTPCREL(%rip), %"), "{}", x86);
+        assert!(!x86.contains("count(%rip)"), "{}", x86);
+        assert!(x86.contains("calls(%rip)"), "{}", x86);
+        assert!(!x86.contains("calls@GOTPCREL"), "{}", x86);
+
+        // The program's own `count` is the one the library reads and writes
+        let library = workdir.join("samples").join("target").join("sample.so");
+        let main = workdir.join("main.c");
+        let program = "extern int count;\nint bump(int);\nint main(void) {\n    count = 5;\n    int result = bump(2);\n    return count == 7 ? result : 0;\n}\n";
+        fs::write(&main, program).unwrap();
+        let executable = workdir.join("main");
+        let status = Command::new("cc")
+            .arg("-o")
+            .arg(&executable)
+            .arg(&main)
+            .arg(&library)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let output = Command::new(executable)
+            .env("LD_LIBRARY_PATH", library.parent().unwrap())
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(71));
+
+        // Without `--pic`, every global is reached relative to %rip
+        let x86 = compile_with_flags(&workdir, "sample", &["--lib", "--target=x86_64"]);
+        let x86 = String::from_utf8(x86).unwrap();
+        assert!(x86.contains("count(%rip)"), "{}", x86);
+        assert!(!x86.contains("@GOTPCREL"), "{}", x86);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_runtime_prints_like_the_interpreter() {
+        let source = "int main() {\n    double d = 2.5;\n    print(\"%d %c %f %s 100%%\\n\", -42, (char)104, d, \"str\");\n    //@assert d < 1.0;\n    return 0;\n}\n";
+        let workdir = setup_workdir("x86-runtime", "sample", source);
+        // The abstract assembly replaces the x86, once the program is linked
+        for target in ["--target=x86_64", "--target=abstract"] {
+            let status = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .args(["sample", "-d", target])
+                .args((target == "--target=x86_64").then_some("--link"))
+                .current_dir(&workdir)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        let target = workdir.join("samples").join("target");
+        let runtime = fs::read_to_string(target.join("sample.runtime.S")).unwrap();
+        assert!(
+            runtime.contains("c0_print_double:\n\tmovl $1, %eax\n"),
+            "{}",
+            runtime
+        );
+        assert!(runtime.contains("\tjmp printf@PLT\n"), "{}", runtime);
+
+        // What's printed before the failed assertion is flushed before the program aborts
+        let output = Command::new(target.join("sample")).output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(
+            stdout,
+            "-42 h 2.500000 str 100%\n@assert annotation failed in main\n"
+        );
+        assert_eq!(output.status.code(), None);
+        let program = fs::read_to_string(target.join("sample.S")).unwrap();
+        let module = parse_ir(&program).unwrap();
+        let interpreted = run_with_io(&module, &[]).unwrap();
+        assert_eq!(interpreted.stdout, stdout);
+        assert_eq!(interpreted.exit_code, None);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_scan_reads_standard_input() {
+        let source = "double scale;\nint main() {\n    int count;\n    scan(count);\n    scan(scale);\n    int total = 0;\n    while (count > 0) {\n        int value;\n        scan(value);\n        total += value;\n        print(\"%d %f\\n\", total, value * scale);\n        count--;\n    }\n    return total;\n}\n";
+        let workdir = setup_workdir("x86-scan", "sample", source);
+        let x86 = String::from_utf8(compile_with_flags(
+            &workdir,
+            "sample",
+            &["--target=x86_64", "-O2", "--link"],
+        ))
+        .unwrap();
+        assert!(x86.contains("\tcall c0_scan_double\n"), "{}", x86);
+        // The interpreter reads what the program does
+        let ir = compile_with_flags(&workdir, "sample", &["--target=abstract", "-O2"]);
+        let module = parse_ir(&String::from_utf8(ir).unwrap()).unwrap();
+
+        let program = workdir.join("samples").join("target").join("sample");
+        for (input, expected, code) in [
+            (" 2 1e1\n7\t8", "7 70.000000\n15 80.000000\n", Some(15)),
+            // Running out of numbers aborts, after what was printed
+            ("3 0.5\n4\n-10 x", "4 2.000000\n-6 -5.000000\n", None),
+        ] {
+            let mut child = Command::new(&program)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(input.as_bytes())
+                .unwrap();
+            let output = child.wait_with_output().unwrap();
+            assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+            assert_eq!(output.status.code(), code);
+            let interpreted = run_with_io(&module, input.as_bytes()).unwrap();
+            assert_eq!(interpreted.stdout, expected);
+            assert_eq!(interpreted.exit_code, code);
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_check_ub_fails_when_the_program_aborts() {
+        let source = "int inc(int x)\n//@requires x > 0;\n{\n    return x + 1;\n}\n\nint main() {\n    print(\"%d\\n\", inc(1));\n    return inc(0);\n}\n";
+        let workdir = setup_workdir("check-ub-abort", "sample", source);
+
+        let output = compiler(&workdir, "sample", &["--check-ub", "-d"]);
+        assert!(!output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "2\n@requires annotation failed in inc\n"
+        );
+        assert!(String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("The program aborted"));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_check_ub_reports_the_line() {
+        let source = "int divide(int a, int b) {\n    return a / b;\n}\n\nint main() {\n    int n;\n    scan(n);\n    print(\"%d\\n\", divide(100, n));\n    return 3;\n}\n";
+        let workdir = setup_workdir("check-ub", "sample", source);
+        let check = |input: &str, flags: &[&str]| {
+            let mut child = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .arg("--check-ub")
+                .args(flags)
+                .arg("sample")
+                .current_dir(&workdir)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(input.as_bytes())
+                .unwrap();
+            child.wait_with_output().unwrap()
+        };
+
+        // A run that's fine prints what the program does, and nothing else
+        let output = check("4", &[]);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "25\n");
+
+        // Whatever the passes do, the division is where it's reported
+        for level in ["-O0", "-O2"] {
+            let output = check("0", &[level]);
+            assert!(!output.status.success());
+            let stderr = String::from_utf8(output.stderr).unwrap();
+            assert!(
+                stderr.contains(
+                    "samples/sample.c0:2:5: error[E0401]: undefined behavior: division-by-zero"
+                ),
+                "{}",
+                stderr
+            );
+            assert!(stderr.contains("^^^^^^^^^^^^^ `int /` where the divisor is 0"));
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_calls_extern_functions_in_the_c_library() {
+        let source = "extern int putchar(int c);\nextern int abs(int x);\nextern double atof(string s);\nint main() {\n    putchar(abs(-72));\n    print(\" %f\\n\", atof(\"2.25\") * 2.0);\n    return 0;\n}\n";
+        let workdir = setup_workdir("x86-extern", "sample", source);
+        let flags = ["--target=x86_64", "--mangle", "--link"];
+        let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &flags)).unwrap();
+        // The C library's functions keep their names, and the double comes back in %xmm0
+        assert!(x86.contains("\tcall putchar\n"), "{}", x86);
+        captures(r"\tcall atof\n\tmovsd %xmm0, ", &x86);
+
+        let program = workdir.join("samples").join("target").join("sample");
+        let output = Command::new(program).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "H 4.500000\n");
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_inline_assembly() {
+        let source = "int main() {\n    int a = 6;\n    int b = 7;\n    int sum;\n    double root;\n    asm(\"nop\");\n    asm(\"leal (%1,%2), %0\" : \"=r\"(sum) : \"r\"(a), \"r\"(b));\n    asm(\"sqrtsd %1, %0\" : \"=r\"(root) : \"r\"(2.25));\n    print(\"%d %f\\n\", sum, root);\n    return 0;\n}\n";
+        let workdir = setup_workdir("x86-asm", "sample", source);
+        for level in ["-O0", "-O2"] {
+            let flags = ["--target=x86_64", level, "--link"];
+            let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &flags)).unwrap();
+            // The operands are named for their types, and the optimizations keep the templates
+            assert!(x86.contains("\tnop\n"), "{}", x86);
+            captures(r"\tleal \(%e\w+,%e\w+\), %e\w+\n", &x86);
+            captures(r"\tsqrtsd %xmm\d+, %xmm\d+\n", &x86);
+
+            let program = workdir.join("samples").join("target").join("sample");
+            let output = Command::new(program).output().unwrap();
+            assert_eq!(String::from_utf8_lossy(&output.stdout), "13 1.500000\n");
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_x86_inline_assembly_constraints_and_clobbers() {
+        // The values live across the last template, which overwrites every register it names
+        // but the stack and frame pointers
+        let mut source =
+            String::from("int main() {\n    int n = 3;\n    int shifted;\n    int answer;\n");
+        for i in 0..8 {
+            source += &format!("    int v{} = n * {};\n", i, i + 2);
+        }
+        source += "    asm(\"movl %1, %0; shll %%cl, %0\" : \"=a\"(shifted) : \"r\"(5), \"c\"(n) : \"cc\");\n";
+        source += "    asm(\"movl $42, %%edx; movl %%edx, %0\" : \"=r\"(answer) : : \"rdx\");\n";
+        source += "    asm(\"";
+        let clobbers = [
+            "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+            "r14", "r15",
+        ];
+        for clobber in clobbers {
+            source += &format!("movq $-1, %%{}; ", clobber);
+        }
+        source += "\" : : : ";
+        let names: Vec<String> = clobbers
+            .iter()
+            .map(|name| format!("\"{}\"", name))
+            .collect();
+        source += &names.join(", ");
+        source += ");\n    print(\"%d %d";
+        for _ in 0..8 {
+            source += " %d";
+        }
+        source += "\\n\", shifted, answer";
+        for i in 0..8 {
+            source += &format!(", v{}", i);
+        }
+        source += ");\n    return 0;\n}\n";
+        let workdir = setup_workdir("x86-asm-clobbers", "sample", &source);
+        for flags in [
+            ["--target=x86_64", "-O0", "--link"],
+            ["--target=x86_64", "-O2", "--link"],
+            ["--target=x86_64", "--regalloc=linear", "--link"],
+        ] {
+            let x86 = String::from_utf8(compile_with_flags(&workdir, "sample", &flags)).unwrap();
+            // The input constrained to %rcx is moved there, and the callee-saved registers the
+            // template clobbers are saved
+            assert!(x86.contains("shll %cl, %eax"), "{}", x86);
+            assert!(x86.contains("\tpushq %rbx\n"), "{}", x86);
+            assert!(x86.contains("\tpushq %r15\n"), "{}", x86);
+
+            let program = workdir.join("samples").join("target").join("sample");
+            let output = Command::new(program).output().unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&output.stdout),
+                "40 42 6 9 12 15 18 21 24 27\n",
+                "{:?}",
+                flags
+            );
+        }
+
+        // Clobbering the stack pointer, or a register an operand is in, is an error
+        for (template, message) in [
+            ("asm(\"nop\" : : : \"rsp\");", "can't clobber %rsp"),
+            (
+                "asm(\"nop\" : : \"c\"(1) : \"ecx\");",
+                "clobbers %rcx, which an operand is in",
+            ),
+            (
+                "asm(\"nop\" : : : \"rzz\");",
+                "clobbers `rzz`, which isn't a register",
+            ),
+        ] {
+            let source = format!("int main() {{\n    {}\n    return 0;\n}}\n", template);
+            fs::write(workdir.join("samples").join("sample.c0"), source).unwrap();
+            let output = compiler(&workdir, "sample", &["--target=x86_64"]);
+            assert!(!output.status.success());
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(stderr.contains(message), "{}", stderr);
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_m6502_zero_page_window() {
+        let mut source = String::from("int main() {\n");
+        for i in 0..12 {
+            source += &format!("    int v{} = {};\n", i, i * 7);
+        }
+        source += "    for (int i = 0; i < 10; i++) {\n";
+        for i in 0..12 {
+            source += &format!("        v{} = v{} + v{};\n", i, i, (i + 1) % 12);
+        }
+        source += "    }\n    return v0 + v5 + v11;\n}\n";
+        let workdir = setup_workdir("m6502-zero-page", "sample", &source);
+
+        // Every temp fits in the default window
+        let m6502 = compile_with_flags(&workdir, "sample", &["--target=m6502"]);
+        let m6502 = String::from_utf8(m6502).unwrap();
+        assert!(m6502.contains("c0_sp = $02\n"), "{}", m6502);
+        assert!(!m6502.contains("c0_spill"), "{}", m6502);
+        captures(r"\tadc \$[0-9a-f]{2}\n", &m6502);
+
+        // A smaller window spills the rest to absolute memory, which each function saves too
+        let flags = ["--target=m6502", "--zero-page=0x40-0x5f"];
+        let m6502 = String::from_utf8(compile_with_flags(&workdir, "sample", &flags)).unwrap();
+        assert!(m6502.contains("c0_sp = $40\n"), "{}", m6502);
+        captures(r"\tadc c0_spill\+\d+\n", &m6502);
+        captures(r"\tlda c0_spill,y\n", &m6502);
+        captures(r"\nc0_spill:\t\.res \d+\n", &m6502);
+        let zero_page: Vec<u8> = Regex::new(r"[ (]\$([0-9a-f]{2})\b")
+            .unwrap()
+            .captures_iter(&m6502)
+            .map(|capture| u8::from_str_radix(&capture[1], 16).unwrap())
+            .collect();
+        assert!(zero_page
+            .iter()
+            .all(|byte| (0x40..=0x5f).contains(byte) || *byte < 0x10));
+
+        for window in ["--zero-page=0x7f-0x02", "--zero-page=0x02"] {
+            let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .args(["--target=m6502", window, "sample"])
+                .current_dir(&workdir)
+                .output()
+                .unwrap();
+            assert!(String::from_utf8(output.stderr).unwrap().contains("Usage:"));
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_stats_json_and_diff() {
+        let source = r#"
+int sum(int n) {
+    int total = 0;
+    for (int i = 0; i < n; i++) total += i;
+    return total;
+}
+
+int main() {
+    return sum(10);
+}
+"#;
+        let workdir = setup_workdir("stats", "sample", source);
+        let target = workdir.join("samples").join("target");
+
+        compile_with_flags(&workdir, "sample", &["--stats-json", "--target=x86_64"]);
+        fs::rename(
+            target.join("sample.stats.json"),
+            target.join("old.stats.json"),
+        )
+        .unwrap();
+        let json = fs::read_to_string(target.join("old.stats.json")).unwrap();
+        assert!(json.contains("\"target\": \"x86_64"), "{}", json);
+        assert!(
+            json.contains("{\"name\":\"sum\",\"instructions\":"),
+            "{}",
+            json
+        );
+        assert!(
+            json.contains("{\"name\":\"main\",\"instructions\":"),
+            "{}",
+            json
+        );
+
+        compile_with_flags(
+            &workdir,
+            "sample",
+            &["--stats-json", "--target=x86_64", "-O2"],
+        );
+        let diff = |old: &str, new: &str| {
+            let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .args(["stats-diff", old, new])
+                .current_dir(&target)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap()
+        };
+        // Inlining `sum` and unrolling its loop make both functions bigger at -O2
+        let report = diff("old.stats.json", "sample.stats.json");
+        assert!(report.starts_with("sum: instructions "), "{}", report);
+        assert!(report.contains("\nmain: instructions 2 -> "), "{}", report);
+        assert!(report.contains("\ntotal spills: "), "{}", report);
+        assert!(report.ends_with("\n4 regression(s)\n"), "{}", report);
+        let report = diff("sample.stats.json", "old.stats.json");
+        assert!(report.ends_with("\n0 regression(s)\n"), "{}", report);
+
+        // Anything else than two files is a usage error
+        let status = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["stats-diff", "old.stats.json"])
+            .current_dir(&target)
+            .status()
+            .unwrap();
+        assert!(!status.success());
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use rust_compiler::explain::{explain, EXPLANATIONS};
+use rust_compiler::lexer::Token;
+use rust_compiler::link::{LinkError, Site};
+use rust_compiler::parser::ParserError;
+use rust_compiler::preprocessor::{PreprocessorError, PreprocessorErrorKind};
+use rust_compiler::source_map::Span;
+use rust_compiler::toolchain::ToolchainError;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_are_unique_and_in_order() {
+        let codes: Vec<&str> = EXPLANATIONS.iter().map(|(code, _)| *code).collect();
+        let mut sorted = codes.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(codes, sorted);
+        for code in codes {
+            assert!(code.len() == 5 && code.starts_with('E'), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_errors_are_explained() {
+        let parser_errors = [
+            ParserError::UnexpectedToken {
+                found: Token::Else,
+                expected: Vec::new(),
+            },
+            ParserError::UnexpectedEOF {
+                expected: Vec::new(),
+            },
+            ParserError::InvalidExpression,
+            ParserError::InvalidFormat {
+                reason: String::new(),
+            },
+            ParserError::InvalidAsm {
+                reason: String::new(),
+            },
+            ParserError::OutsideEnsures { found: Token::Old },
+            ParserError::MissingToken {
+                expected: Token::Semicolon,
+            },
+        ];
+        for error in &parser_errors {
+            assert!(explain(error.code()).is_some(), "{:?}", error);
+        }
+        let site = Site {
+            module: "a".to_string(),
+            span: Span::default(),
+        };
+        let link_errors = [
+            LinkError::DuplicateDefinition {
+                name: "f".to_string(),
+                first: site.clone(),
+                second: site.clone(),
+            },
+            LinkError::UndefinedFunction {
+                name: "f".to_string(),
+                module: "a".to_string(),
+                span: Span::default(),
+            },
+            LinkError::MismatchedSignature {
+                name: "f".to_string(),
+                first: site.clone(),
+                second: site,
+            },
+        ];
+        for error in &link_errors {
+            assert!(explain(error.code()).is_some(), "{:?}", error);
+        }
+        let preprocessor_error = PreprocessorError {
+            file: "a.c0".to_string(),
+            line: 1,
+            kind: PreprocessorErrorKind::UnterminatedConditional,
+        };
+        assert!(explain(preprocessor_error.code()).is_some());
+        assert!(explain(ToolchainError::NotFound.code()).is_some());
+        assert_eq!(explain("E9999"), None);
+    }
+}
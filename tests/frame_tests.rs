@@ -0,0 +1,37 @@
+use rust_compiler::codegen::frame::Frame;
+use rust_compiler::codegen::X86Register;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_keeps_calls_aligned() {
+        // Past the return address and %rbp, an odd number of saved registers needs 8 bytes of
+        // padding
+        let mut frame = Frame::default();
+        assert_eq!(frame.size(), 0);
+        frame.saved_registers = vec![X86Register::Rbx];
+        assert_eq!(frame.size(), 8);
+        frame.spill_slots = 1;
+        assert_eq!(frame.size(), 8);
+        frame.outgoing_slots = 4;
+        assert_eq!(frame.size(), 40);
+        frame.saved_registers.push(X86Register::R12);
+        assert_eq!(frame.size(), 48);
+    }
+
+    #[test]
+    fn test_spill_slots_are_above_the_outgoing_arguments() {
+        let mut frame = Frame {
+            outgoing_slots: 2,
+            ..Frame::default()
+        };
+        let first = frame.new_spill_slot();
+        let second = frame.new_spill_slot();
+        assert_eq!((first.displacement, second.displacement), (16, 24));
+        assert_eq!(frame.spill_slot_at(&second), Some(1));
+        assert_eq!(frame.spill_slot_at(&Frame::outgoing_argument(1)), None);
+        assert_eq!(Frame::incoming_argument(0).displacement, 16);
+    }
+}
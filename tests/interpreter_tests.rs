@@ -0,0 +1,219 @@
+use rust_compiler::codegen::{
+    self, interpret, run_with_io, CodegenOptions, Execution, IrModule, RunResult, RuntimeError,
+    Undefined, Value,
+};
+use rust_compiler::source_map::LineTable;
+use rust_compiler::{desugar, lexer, parser, sema};
+use std::rc::Rc;
+
+/// Compiles `source` to abstract assembly with `options`, with contracts if `contracts`
+fn lower(source: &str, options: &CodegenOptions, contracts: bool) -> IrModule {
+    let program = parser::parse_with_spans(lexer::tokenize_with_spans(source)).unwrap();
+    let program = desugar::desugar(program);
+    assert!(sema::check(&program).is_ok());
+    let program = if contracts {
+        program
+    } else {
+        desugar::strip_contracts(program)
+    };
+    codegen::lower(program, options).unwrap()
+}
+
+/// Compiles `source` to abstract assembly with `options` and runs its `main`
+fn run_with(
+    source: &str,
+    options: &CodegenOptions,
+    contracts: bool,
+) -> Result<Execution, RuntimeError> {
+    interpret(&lower(source, options, contracts), "main", &[])
+}
+
+/// Compiles `source` and runs it as a program reading `input`
+fn run_program(source: &str, options: &CodegenOptions, input: &str) -> RunResult {
+    run_with_io(&lower(source, options, false), input.as_bytes()).unwrap()
+}
+
+fn run(source: &str) -> Execution {
+    run_with(source, &CodegenOptions::default(), false).unwrap()
+}
+
+/// Options for `-O<level>`, optionally with `--ssa`
+fn options(level: u8, ssa: bool) -> CodegenOptions {
+    CodegenOptions {
+        passes: codegen::pipeline(level)
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        ssa,
+        ..CodegenOptions::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_returns_what_main_computes() {
+        let source = "int main() {\n    int sum = 0;\n    for (int i = 1; i <= 10; i = i + 1) {\n        sum = sum + i * i;\n    }\n    return sum;\n}\n";
+        assert_eq!(run(source).value, Some(Value::Int(385)));
+    }
+
+    #[test]
+    fn test_collects_printed_output() {
+        let source = "int main() {\n    int x = 7;\n    double d = x / 2 * 1.5;\n    print(\"x=%d d=%f\\n\", x, d);\n    return 0;\n}\n";
+        let execution = run(source);
+        assert_eq!(execution.output, "x=7 d=4.500000\n");
+        assert_eq!(execution.value, Some(Value::Int(0)));
+    }
+
+    #[test]
+    fn test_literals_with_a_decimal_point_are_doubles() {
+        let source = "int main() {\n    print(5.0 / 2);\n    print(\" \");\n    print(1.0);\n    print(\" %d\\n\", 5 / 2);\n    return 0;\n}\n";
+        assert_eq!(run(source).output, "2.500000 1.000000 2\n");
+    }
+
+    #[test]
+    fn test_ints_wrap_around() {
+        let source = "int main() {\n    int x = 2147483647;\n    return x + 1;\n}\n";
+        assert_eq!(run(source).value, Some(Value::Int(i32::MIN)));
+    }
+
+    #[test]
+    fn test_optimizations_keep_the_results() {
+        let source = "int main() {\n    int total = 0;\n    for (int i = 0; i < 12; i = i + 1) {\n        if (i * 4 > 20) {\n            total = total + i / 2;\n        } else {\n            total = total - 1;\n        }\n        print(\"%d \", total);\n    }\n    return total * 8;\n}\n";
+        let expected = run(source);
+        for level in 0..=codegen::MAX_OPT_LEVEL {
+            for ssa in [false, true] {
+                let execution = run_with(source, &options(level, ssa), false).unwrap();
+                assert_eq!(execution, expected, "-O{} with ssa {}", level, ssa);
+            }
+        }
+    }
+
+    #[test]
+    fn test_calls_run_the_function_called() {
+        let source = "int fib(int n) {\n    if (n < 2) return n;\n    return fib(n - 1) + fib(n - 2);\n}\ndouble half(int x) {\n    print(\"%d \", x);\n    return x * 0.5;\n}\nint main() {\n    print(\"%f\\n\", half(fib(10)));\n    return fib(15);\n}\n";
+        let expected = run(source);
+        assert_eq!(expected.output, "55 27.500000\n");
+        assert_eq!(expected.value, Some(Value::Int(610)));
+        for level in 0..=codegen::MAX_OPT_LEVEL {
+            let execution = run_with(source, &options(level, true), false).unwrap();
+            assert_eq!(execution, expected, "-O{}", level);
+        }
+
+        let source = "int forever(int n) {\n    return forever(n + 1);\n}\nint main() {\n    return forever(0);\n}\n";
+        assert_eq!(
+            run_with(source, &CodegenOptions::default(), false),
+            Err(RuntimeError::DepthLimit)
+        );
+    }
+
+    #[test]
+    fn test_functions_share_globals() {
+        let source = "int calls;\ndouble total = 0.5;\nint count() {\n    calls = calls + 1;\n    return calls;\n}\nint main() {\n    count();\n    total = total + count();\n    int shadowed = calls;\n    {\n        int calls = 10;\n        shadowed = shadowed + calls;\n    }\n    print(\"%d %f %d\\n\", calls, total, shadowed);\n    return calls = 5;\n}\n";
+        let expected = run(source);
+        assert_eq!(expected.output, "2 2.500000 12\n");
+        assert_eq!(expected.value, Some(Value::Int(5)));
+        for level in 0..=codegen::MAX_OPT_LEVEL {
+            for ssa in [false, true] {
+                let execution = run_with(source, &options(level, ssa), false).unwrap();
+                assert_eq!(execution, expected, "-O{} with ssa {}", level, ssa);
+            }
+        }
+    }
+
+    #[test]
+    fn test_programs_read_input_and_exit() {
+        let source = "int main() {\n    int count;\n    double total = 0;\n    scan(count);\n    for (int i = 0; i < count; i++) {\n        double value;\n        scan(value);\n        total += value;\n    }\n    print(\"%f\\n\", total);\n    return count * 100;\n}\n";
+        // The exit code is the low byte of what `main` returns
+        let expected = run_program(source, &CodegenOptions::default(), " 3\n1.5 -2e1\t.25");
+        assert_eq!(expected.stdout, "-18.250000\n");
+        assert_eq!(expected.exit_code, Some(300 & 0xff));
+        for level in 1..=codegen::MAX_OPT_LEVEL {
+            let result = run_program(source, &options(level, true), " 3\n1.5 -2e1\t.25");
+            assert_eq!(result.stdout, expected.stdout, "-O{}", level);
+            assert_eq!(result.exit_code, expected.exit_code, "-O{}", level);
+            assert!(result.steps < expected.steps, "-O{}", level);
+        }
+
+        // Running out of numbers aborts, with what was printed kept
+        let source = "int main() {\n    int x;\n    scan(x);\n    print(\"%d \", x);\n    scan(x);\n    return x;\n}\n";
+        let result = run_program(source, &CodegenOptions::default(), "-2147483649 x");
+        assert_eq!(result.stdout, "2147483647 ");
+        assert_eq!(result.exit_code, None);
+        let result = run_program(source, &CodegenOptions::default(), "1 +500x");
+        assert_eq!(result.exit_code, Some(500 & 0xff));
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let source = "int main() {\n    int zero = 0;\n    return 10 / zero;\n}\n";
+        assert_eq!(
+            run_with(source, &CodegenOptions::default(), false),
+            Err(RuntimeError::Undefined {
+                behavior: Undefined::DivisionByZero,
+                location: None
+            })
+        );
+
+        let source = "int main() {\n    int x = 1;\n    print(\"checking\\n\");\n    //@assert x == 2;\n    return x;\n}\n";
+        assert_eq!(run(source).value, Some(Value::Int(1)));
+        match run_with(source, &CodegenOptions::default(), true) {
+            Err(RuntimeError::Aborted { output }) => assert!(output.starts_with("checking\n")),
+            other => panic!("expected an abort, got {:?}", other),
+        }
+
+        // With nothing to read, `scan` aborts
+        let source =
+            "int main() {\n    int x;\n    print(\"reading\");\n    scan(x);\n    return x;\n}\n";
+        assert_eq!(
+            run_with(source, &CodegenOptions::default(), false),
+            Err(RuntimeError::Aborted {
+                output: "reading".to_string()
+            })
+        );
+
+        let source =
+            "int main() {\n    int x;\n    asm(\"movl $1, %0\" : \"=r\"(x));\n    return x;\n}\n";
+        assert_eq!(
+            run_with(source, &CodegenOptions::default(), false),
+            Err(RuntimeError::InlineAssembly)
+        );
+    }
+
+    #[test]
+    fn test_undefined_behavior_is_located() {
+        let source = "int divide(int a, int b) {\n    return a / b;\n}\n\nint main() {\n    print(\"%d\\n\", divide(7, 2));\n    return divide(-2147483647 - 1, -1);\n}\n";
+        // As `-g` marks each statement, with the line table of the file
+        let mut table = LineTable::new();
+        let starts = std::iter::once(0).chain(source.match_indices('\n').map(|(i, _)| i + 1));
+        for (line, start) in starts.enumerate() {
+            table.add_line(start, "sample.c0", line + 1);
+        }
+        for level in [0, 2] {
+            let options = CodegenOptions {
+                line_table: Some(Rc::new(table.clone())),
+                ..options(level, false)
+            };
+            // The division is in `divide`, even once it's inlined into `main`
+            assert_eq!(
+                run_with(source, &options, false),
+                Err(RuntimeError::Undefined {
+                    behavior: Undefined::DivisionOverflow,
+                    location: Some((0, 2))
+                }),
+                "-O{}",
+                level
+            );
+        }
+        assert_eq!(
+            Undefined::ALL.map(Undefined::name),
+            [
+                "division-by-zero",
+                "division-overflow",
+                "conversion-overflow"
+            ]
+        );
+    }
+}
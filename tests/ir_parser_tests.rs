@@ -0,0 +1,205 @@
+use rust_compiler::codegen::{parse_ir, IrParseError, IrParseErrorKind, VerifyErrorKind};
+
+/// Parses `text`, expecting it to fail
+fn parse_error(text: &str) -> IrParseError {
+    match parse_ir(text) {
+        Ok(_) => panic!("expected an error parsing:\n{}", text),
+        Err(error) => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_what_emit_abstract_writes() {
+        let text = r#"
+.data
+.globl limit
+limit <- $10
+scale <- $2.5
+.rodata
+S0 <- "hi \"there\"\n"
+.half %t0
+%t1 <- -d %t0
+%t2 <- %t1 /d $2.0
+%eax <- %t2
+ret
+.globl main
+.main
+%t0 <- $0
+L0:
+cmp %t0 is_l $10
+jmp is_l L1 L2
+L1:
+%t1 <- %t0 << $2
+%t0 <- -%t1
+jmp L0
+L2:
+print %s $S0
+print %d %t0
+%t3 <- call half $1.5
+%t5 <- load scale
+%t8 <- scan %d
+%t9 <- scan %f
+%t6 <- %t5 *d $2.0
+store scale %t6
+store limit $0
+call main
+asm "nop"
+%t4 <- asm "leal (%1,%2), %0 # \"<-\"" %t0 $2
+%t7 <- asm "shll %%cl, %0" "=a,c,~{rdx},~{cc}" %t0
+asm "cpuid" "~{rax},~{rbx}"
+%eax <- %t0
+ret
+"#;
+        let module = parse_ir(text).unwrap();
+        assert_eq!(module.function_names(), ["half", "main"]);
+    }
+
+    #[test]
+    fn test_errors_point_at_their_line() {
+        let error = parse_error(".main\n%t0 <- $1\n%t1 <- %t0 % $2\nret\n");
+        assert_eq!(error.line, 3);
+        assert_eq!(
+            error.kind,
+            IrParseErrorKind::InvalidOperand {
+                text: "%".to_string()
+            }
+        );
+
+        let error = parse_error(".main\n\n# comment\nfrobnicate %t0\n");
+        assert_eq!(error.line, 4);
+        assert!(matches!(
+            error.kind,
+            IrParseErrorKind::UnknownInstruction { .. }
+        ));
+
+        // Parameters are the first temps, in order
+        let error = parse_error(".f %t0 %t2\nret\n");
+        assert_eq!(error.line, 1);
+        assert_eq!(
+            error.kind,
+            IrParseErrorKind::InvalidOperand {
+                text: "%t2".to_string()
+            }
+        );
+
+        assert_eq!(
+            parse_error("%t0 <- $1\n").kind,
+            IrParseErrorKind::OutsideFunction
+        );
+        assert_eq!(
+            parse_error(".main\n%eax <- $1\n%t0 <- $2\n").kind,
+            IrParseErrorKind::MissingRet
+        );
+        assert_eq!(
+            parse_error(".rodata\nS1 <- \"a\"\n").kind,
+            IrParseErrorKind::StringOutOfOrder {
+                label: "S1".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_functions_are_verified() {
+        let error = parse_error(".main\n%t0 <- $1\njmp L3\nL2:\nret\n");
+        assert_eq!(error.line, 3);
+        assert_eq!(
+            error.kind,
+            IrParseErrorKind::Invalid {
+                function: "main".to_string(),
+                kind: VerifyErrorKind::UndefinedLabel { label: 3 },
+            }
+        );
+
+        let error = parse_error(".main\nL0:\nret\nL0:\nret\n");
+        assert!(matches!(
+            error.kind,
+            IrParseErrorKind::Invalid {
+                kind: VerifyErrorKind::DuplicateLabel { label: 0 },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_reads_are_written_on_every_path() {
+        let source =
+            ".main\nL0:\ncmp $1 is_l $2\njmp is_l L1 L2\nL1:\n%t0 <- $1\nL2:\n%eax <- %t0\nret\n";
+        let error = parse_error(source);
+        assert_eq!(error.line, 8);
+        assert_eq!(
+            error.kind,
+            IrParseErrorKind::Invalid {
+                function: "main".to_string(),
+                kind: VerifyErrorKind::Undefined { temp: 0 },
+            }
+        );
+        // Written before the branch, it's written on both paths
+        let written = source.replace("L0:\n", "L0:\n%t0 <- $0\n");
+        assert!(parse_ir(&written).is_ok());
+    }
+
+    #[test]
+    fn test_ssa_functions_are_verified_as_ssa() {
+        let valid = r#"
+.main
+L0:
+%t0 <- $1
+cmp %t0 is_l $2
+jmp is_l L1 L2
+L1:
+%t1 <- $2
+L2:
+phi %t2 (%t0, L0), (%t1, L1)
+%eax <- %t2
+ret
+"#;
+        assert!(parse_ir(valid).is_ok());
+
+        // A source for each predecessor, and none from anywhere else
+        let missing = valid.replace(", (%t1, L1)", "");
+        let error = parse_error(&missing);
+        assert_eq!(error.line, 10);
+        assert!(matches!(
+            error.kind,
+            IrParseErrorKind::Invalid {
+                kind: VerifyErrorKind::PhiSources,
+                ..
+            }
+        ));
+
+        let redefined = valid.replace("%t1 <- $2", "%t0 <- $2");
+        assert!(matches!(
+            parse_error(&redefined).kind,
+            IrParseErrorKind::Invalid {
+                kind: VerifyErrorKind::Redefined { temp: 0 },
+                ..
+            }
+        ));
+
+        // Each source is read at the end of the block it comes from
+        let late = valid.replace(
+            "%t1 <- $2\nL2:\nphi %t2 (%t0, L0), (%t1, L1)\n",
+            "L2:\nphi %t2 (%t0, L0), (%t1, L1)\n%t1 <- $2\n",
+        );
+        assert!(matches!(
+            parse_error(&late).kind,
+            IrParseErrorKind::Invalid {
+                kind: VerifyErrorKind::Undefined { temp: 1 },
+                ..
+            }
+        ));
+
+        let misplaced = valid.replace("L2:\nphi", "L2:\n%t3 <- $0\nphi");
+        assert!(matches!(
+            parse_error(&misplaced).kind,
+            IrParseErrorKind::Invalid {
+                kind: VerifyErrorKind::MisplacedPhi,
+                ..
+            }
+        ));
+    }
+}
@@ -1,4 +1,7 @@
-use rust_compiler::lexer::{tokenize_from_string, Token};
+use rust_compiler::lexer::{
+    lexer_errors, tokenize_from_string, tokenize_with_spans, LexerError, Token,
+};
+use rust_compiler::source_map::Span;
 
 #[cfg(test)]
 mod tests {
@@ -23,7 +26,7 @@ mod tests {
             Token::LeftBrace,
             Token::Identifier("printf".to_string()),
             Token::LeftParen,
-            Token::StringLiteral("Hello, world!\\n".to_string()),
+            Token::StringLiteral("Hello, world!\n".to_string()),
             Token::RightParen,
             Token::Semicolon,
             Token::Return,
@@ -35,4 +38,250 @@ mod tests {
 
         assert_eq!(tokens, expected_tokens);
     }
+
+    #[test]
+    fn test_lexer_underscore_identifiers() {
+        let tokens = tokenize_from_string("_unused x_1");
+        assert_eq!(
+            tokens,
+            [
+                Token::Identifier("_unused".to_string()),
+                Token::Identifier("x_1".to_string()),
+                Token::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lexer_comments() {
+        let source = r#"
+        // line comment
+        int x = 1; /* block
+        comment */ return x; // trailing
+        /* unterminated"#;
+
+        let tokens = tokenize_from_string(source);
+
+        let expected_tokens = vec![
+            Token::Int,
+            Token::Identifier("x".to_string()),
+            Token::Equal,
+            Token::Number(1.0),
+            Token::Semicolon,
+            Token::Return,
+            Token::Identifier("x".to_string()),
+            Token::Semicolon,
+            Token::Eof,
+        ];
+
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    // Differential check for trivia skipping (including the `simd` fast path): padding tokens
+    // with whitespace and comments of every length and alignment must not change the output.
+    #[test]
+    fn test_lexer_trivia_padding() {
+        let words = ["int", "x", "=", "!", "y", ";", "/", "z", "*", "w"];
+        let plain = tokenize_from_string(&words.join(" "));
+
+        for len in 0..20 {
+            for filler in [" ", "\t", "\r\n", " \n\t "] {
+                let padding = filler.repeat(len + 1);
+                let padded = words.join(&padding);
+                assert_eq!(
+                    tokenize_from_string(&padded),
+                    plain,
+                    "padding {:?}",
+                    padding
+                );
+
+                let commented = words.join(&format!("{}/* c */{}// c\n", padding, padding));
+                assert_eq!(
+                    tokenize_from_string(&commented),
+                    plain,
+                    "padding {:?}",
+                    padding
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_lexer_spans() {
+        let source = "x <= \"hi\" /* c */ 12.5";
+        let spans: Vec<Span> = tokenize_with_spans(source)
+            .into_iter()
+            .map(|token| token.span)
+            .collect();
+
+        assert_eq!(
+            spans,
+            vec![
+                Span::new(0, 1),   // x
+                Span::new(2, 4),   // <=
+                Span::new(5, 9),   // "hi"
+                Span::new(18, 22), // 12.5
+                Span::new(22, 22), // EOF
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lexer_error_recovery() {
+        let source = "x = 3 @@y; z = $(1);";
+        let tokens = tokenize_with_spans(source);
+
+        // Each run of bad characters becomes a single error token,
+        // and lexing resumes at the next whitespace or delimiter
+        let expected_tokens = vec![
+            Token::Identifier("x".to_string()),
+            Token::Equal,
+            Token::Number(3.0),
+            Token::Error("@@y".to_string()),
+            Token::Semicolon,
+            Token::Identifier("z".to_string()),
+            Token::Equal,
+            Token::Error("$".to_string()),
+            Token::LeftParen,
+            Token::Number(1.0),
+            Token::RightParen,
+            Token::Semicolon,
+            Token::Eof,
+        ];
+        let kinds: Vec<Token> = tokens.iter().map(|token| token.node.clone()).collect();
+        assert_eq!(kinds, expected_tokens);
+
+        assert_eq!(
+            lexer_errors(&tokens),
+            vec![
+                LexerError {
+                    text: "@@y".to_string(),
+                    span: Span::new(6, 9),
+                },
+                LexerError {
+                    text: "$".to_string(),
+                    span: Span::new(15, 16),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lexer_string_escapes() {
+        let tokens = tokenize_from_string(r#"string s = "tab\there \"quoted\" \\ \q";"#);
+        assert_eq!(tokens[0], Token::String);
+        assert_eq!(
+            tokens[3],
+            Token::StringLiteral("tab\there \"quoted\" \\ \\q".to_string())
+        );
+        assert_eq!(tokens[4], Token::Semicolon);
+    }
+
+    #[test]
+    fn test_lexer_unterminated_string() {
+        let source = "\"abc\\";
+        let tokens = tokenize_with_spans(source);
+        assert_eq!(tokens[0].node, Token::StringLiteral("abc".to_string()));
+        assert_eq!(tokens[0].span, Span::new(0, source.len()));
+        assert_eq!(tokens[1].node, Token::Eof);
+    }
+
+    #[test]
+    fn test_lexer_assignment_operators() {
+        let tokens = tokenize_from_string("a += b -= c *= d /= e++ - -f--;");
+        let expected_tokens = vec![
+            Token::Identifier("a".to_string()),
+            Token::PlusEqual,
+            Token::Identifier("b".to_string()),
+            Token::MinusEqual,
+            Token::Identifier("c".to_string()),
+            Token::StarEqual,
+            Token::Identifier("d".to_string()),
+            Token::SlashEqual,
+            Token::Identifier("e".to_string()),
+            Token::PlusPlus,
+            Token::Minus,
+            Token::Minus,
+            Token::Identifier("f".to_string()),
+            Token::MinusMinus,
+            Token::Semicolon,
+            Token::Eof,
+        ];
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn test_lexer_number_literals() {
+        // A decimal point makes a literal a double, whatever its value
+        let tokens = tokenize_from_string("1 1.0 2. 0.5");
+        let expected_tokens = vec![
+            Token::Number(1.0),
+            Token::DoubleLiteral(1.0),
+            Token::DoubleLiteral(2.0),
+            Token::DoubleLiteral(0.5),
+            Token::Eof,
+        ];
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn test_lexer_malformed_number_literals() {
+        // A literal ends at its second decimal point, so these lex instead of crashing, and
+        // the parser reports the stray dots
+        let tokens = tokenize_with_spans("1..2 3.7.5");
+        let expected_tokens = vec![
+            Token::DoubleLiteral(1.0),
+            Token::Dot,
+            Token::Number(2.0),
+            Token::DoubleLiteral(3.7),
+            Token::Dot,
+            Token::Number(5.0),
+            Token::Eof,
+        ];
+        let kinds: Vec<Token> = tokens.iter().map(|token| token.node.clone()).collect();
+        assert_eq!(kinds, expected_tokens);
+        assert_eq!(tokens[1].span, Span::new(2, 3));
+        assert!(lexer_errors(&tokens).is_empty());
+    }
+
+    #[test]
+    fn test_lexer_annotations() {
+        let source = "// requires is a comment here\n//@requires n > 0;\nint requires;";
+        let tokens = tokenize_from_string(source);
+        let expected_tokens = vec![
+            Token::Requires,
+            Token::Identifier("n".to_string()),
+            Token::Greater,
+            Token::Number(0.0),
+            Token::Semicolon,
+            // The annotation ends with its line
+            Token::Int,
+            Token::Identifier("requires".to_string()),
+            Token::Semicolon,
+            Token::Eof,
+        ];
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn test_lexer_result_and_old() {
+        let source = "//@ensures \\result > \\old(x);\nx \\result;";
+        let tokens = tokenize_from_string(source);
+        let expected_tokens = vec![
+            Token::Ensures,
+            Token::Result,
+            Token::Greater,
+            Token::Old,
+            Token::LeftParen,
+            Token::Identifier("x".to_string()),
+            Token::RightParen,
+            Token::Semicolon,
+            // Outside an annotation, a backslash doesn't start any token
+            Token::Identifier("x".to_string()),
+            Token::Error("\\result".to_string()),
+            Token::Semicolon,
+            Token::Eof,
+        ];
+        assert_eq!(tokens, expected_tokens);
+    }
 }
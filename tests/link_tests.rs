@@ -0,0 +1,228 @@
+use rust_compiler::lexer::{tokenize_with_spans, Token};
+use rust_compiler::link::{link, LinkError, Module, Site};
+use rust_compiler::parser::{parse_with_spans, Expr, LValue, Program, Statement};
+use rust_compiler::source_map::Span;
+
+fn module(name: &str, source: &str) -> Module {
+    Module {
+        name: name.to_string(),
+        program: parse_with_spans(tokenize_with_spans(source)).unwrap(),
+    }
+}
+
+fn site(module: &str, start: usize, end: usize) -> Site {
+    Site {
+        module: module.to_string(),
+        span: Span::new(start, end),
+    }
+}
+
+fn function_names(program: &Program) -> Vec<String> {
+    program
+        .fns
+        .iter()
+        .map(|function| match &function.identifier {
+            Token::Identifier(name) => name.clone(),
+            other => panic!("Expected identifier, got {:?}", other),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_concatenates_modules() {
+        let program = link(vec![
+            module("main", "int main() { return twice(2); }"),
+            module("util", "int limit = 1;\nint twice(int x) { return x * 2; }"),
+        ])
+        .unwrap();
+
+        assert_eq!(function_names(&program), ["main", "twice"]);
+        assert_eq!(program.decl.len(), 1);
+    }
+
+    #[test]
+    fn test_undefined_function() {
+        let errors = link(vec![
+            module("main", "int main() { return helper(); }"),
+            module("util", "static int helper() { return 0; }"),
+        ])
+        .unwrap_err();
+
+        // Another module's static function isn't visible
+        assert_eq!(
+            errors,
+            [LinkError::UndefinedFunction {
+                name: "helper".to_string(),
+                module: "main".to_string(),
+                span: Span::new(20, 26),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_extern_functions() {
+        let program = link(vec![
+            module("main", "extern int putchar(int c);\nextern int twice(int x);\nint main() { return putchar(twice(36)); }"),
+            module("util", "extern int putchar(int c);\nint twice(int x) { return x * 2; }"),
+        ])
+        .unwrap();
+
+        // Each declaration is kept for sema, which checks that they agree
+        assert_eq!(function_names(&program), ["main", "twice"]);
+        assert_eq!(program.externs.len(), 3);
+
+        // A declaration only lets its own module call the function
+        let errors = link(vec![
+            module("main", "int main() { return putchar(72); }"),
+            module("util", "extern int putchar(int c);"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            [LinkError::UndefinedFunction {
+                name: "putchar".to_string(),
+                module: "main".to_string(),
+                span: Span::new(20, 27),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_definition() {
+        let errors = link(vec![
+            module("a", "int shared = 1;\nint main() { return 0; }"),
+            module("b", "int shared = 2;"),
+        ])
+        .unwrap_err();
+
+        assert_eq!(
+            errors,
+            [LinkError::DuplicateDefinition {
+                name: "shared".to_string(),
+                first: site("a", 0, 15),
+                second: site("b", 0, 15),
+            }]
+        );
+
+        // Each file's `main` is pointed at
+        let errors = link(vec![
+            module("a", "int main() { return 0; }"),
+            module("b", "int f() { return 1; }\nint main() { return f(); }"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            [LinkError::DuplicateDefinition {
+                name: "main".to_string(),
+                first: site("a", 0, 10),
+                second: site("b", 22, 32),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_mismatched_signature() {
+        let errors = link(vec![
+            module(
+                "a",
+                "extern int scale(int x);\nint main() { return scale(2); }",
+            ),
+            module("b", "int scale(double x) { return (int) (x * 2.0); }"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            [LinkError::MismatchedSignature {
+                name: "scale".to_string(),
+                first: site("b", 0, 19),
+                second: site("a", 0, 24),
+            }]
+        );
+
+        // Without a definition, the declarations have to agree with each other
+        let errors = link(vec![
+            module(
+                "a",
+                "extern int abs(int x);\nint main() { return abs(-1); }",
+            ),
+            module("b", "extern double abs(int x);"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            [LinkError::MismatchedSignature {
+                name: "abs".to_string(),
+                first: site("a", 0, 22),
+                second: site("b", 0, 25),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_colliding_statics_are_renamed() {
+        let program = link(vec![
+            module(
+                "a",
+                "static int count = 0;\nstatic int next() { count += 1; return count; }\nint main() { return next(); }",
+            ),
+            module(
+                "b",
+                "static int count = 5;\nstatic int next(int count) { return count; }\nstatic int unique() { return 0; }",
+            ),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            function_names(&program),
+            ["a.next", "main", "b.next", "unique"]
+        );
+        let globals: Vec<&Token> = program
+            .decl
+            .iter()
+            .map(|global| &global.identifier)
+            .collect();
+        assert_eq!(
+            globals,
+            [
+                &Token::Identifier("a.count".to_string()),
+                &Token::Identifier("b.count".to_string())
+            ]
+        );
+
+        // References to the static global are renamed too
+        let statements = &program.fns[0].body.statements;
+        assert!(matches!(
+            &statements[0].node,
+            Statement::Expression(expr) if matches!(
+                &expr.node,
+                Expr::CompoundAssign(LValue::Variable(Token::Identifier(name)), _, _)
+                    if name == "a.count"
+            )
+        ));
+
+        // ...and so are calls to the static function
+        match &program.fns[1].body.statements[0].node {
+            Statement::Return(Some(value)) => assert!(matches!(
+                &value.node,
+                Expr::Call(callee, _) if matches!(
+                    &callee.node,
+                    Expr::Variable(Token::Identifier(name)) if name == "a.next"
+                )
+            )),
+            other => panic!("Expected return, got {:?}", other),
+        }
+
+        // ...but not a parameter that shadows a renamed global
+        match &program.fns[2].body.statements[0].node {
+            Statement::Return(Some(value)) => assert!(matches!(
+                &value.node,
+                Expr::Variable(Token::Identifier(name)) if name == "count"
+            )),
+            other => panic!("Expected return, got {:?}", other),
+        }
+    }
+}
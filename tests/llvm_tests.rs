@@ -0,0 +1,287 @@
+mod common;
+
+use common::{compiler, setup_workdir};
+use std::fs;
+
+/// Compiles `source` to LLVM IR with `flags`, and returns it
+fn compile(dirname: &str, source: &str, flags: &[&str]) -> String {
+    let workdir = setup_workdir(dirname, "sample", source);
+    let output = compiler(&workdir, "sample", &[&["--emit=llvm-ir"], flags].concat());
+    assert!(output.status.success());
+    let path = workdir.join("samples").join("target").join("sample.ll");
+    let ir = fs::read_to_string(path).unwrap();
+    fs::remove_dir_all(workdir).unwrap();
+    ir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = r#"
+extern int abs(int x);
+
+int fib(int n) {
+    if (n < 2) return n;
+    return fib(n - 1) + fib(n - 2);
+}
+
+static double half(double x) {
+    return x / 2.0;
+}
+
+void greet(string name, char initial) {
+    print("%s %c\n", name, initial);
+}
+
+int main() {
+    int big = 100000;
+    greet("hello \"you\"", (char)104);
+    print("%d %f\n", fib(10), half(3.0));
+    if (fib(3) > big) return abs(-3);
+    return fib(12) + big;
+}
+"#;
+
+    #[test]
+    fn test_functions_are_typed() {
+        for flags in [&[][..], &["-O2"], &["--ssa", "-O1"]] {
+            let ir = compile("llvm-program", PROGRAM, flags);
+            assert!(ir.contains("define i32 @fib(i32 %p0) {"), "{}", ir);
+            assert!(
+                ir.contains("define internal double @half(double %p0) {"),
+                "{}",
+                ir
+            );
+            assert!(
+                ir.contains("define void @greet(ptr %p0, i8 %p1) {"),
+                "{}",
+                ir
+            );
+            assert!(ir.contains("define i32 @main() {"), "{}", ir);
+            // The extern is declared as it's called, alongside what printing and aborting call
+            assert!(ir.contains("declare i32 @abs(i32)\n"), "{}", ir);
+            assert!(ir.contains("declare i32 @printf(ptr, ...)\n"), "{}", ir);
+            // Every block ends in a terminator, so none falls into a label
+            let lines: Vec<&str> = ir.lines().collect();
+            for pair in lines.windows(2) {
+                if pair[1].ends_with(':') && !pair[0].ends_with('{') {
+                    let last = pair[0].trim_start();
+                    assert!(
+                        ["br ", "ret ", "ret void", "unreachable"]
+                            .iter()
+                            .any(|terminator| last.starts_with(terminator)),
+                        "{}\n{}",
+                        pair[0],
+                        ir
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_constants() {
+        let ir = compile("llvm-constants", PROGRAM, &[]);
+        assert!(
+            ir.contains(r#"= private unnamed_addr constant [12 x i8] c"hello \22you\22\00""#),
+            "{}",
+            ir
+        );
+        assert!(
+            ir.contains(r#"@.format.char = private unnamed_addr constant [3 x i8] c"%c\00""#),
+            "{}",
+            ir
+        );
+        // Doubles are written by their bits, which is exact
+        assert!(ir.contains("fdiv double %v"), "{}", ir);
+        assert!(ir.contains("0x4000000000000000"), "{}", ir);
+        // A char is printed as an int, as variadic arguments are
+        assert!(ir.contains("zext i8"), "{}", ir);
+    }
+
+    #[test]
+    fn test_target_triple() {
+        let ir = compile("llvm-abstract", PROGRAM, &[]);
+        assert!(!ir.contains("target triple"), "{}", ir);
+        let ir = compile("llvm-x86", PROGRAM, &["--target=x86_64"]);
+        assert!(ir.starts_with("target triple = \"x86_64-unknown-linux-gnu\"\n"));
+        let ir = compile("llvm-windows", PROGRAM, &["--target=x86_64-pc-windows"]);
+        assert!(ir.starts_with("target triple = \"x86_64-pc-windows-gnu\"\n"));
+        let ir = compile("llvm-riscv", PROGRAM, &["--target=riscv32"]);
+        assert!(ir.starts_with("target triple = \"riscv32-unknown-linux-gnu\"\n"));
+        // Any target can write IR, even the one that can't compile doubles
+        let ir = compile("llvm-m6502", PROGRAM, &["--target=m6502"]);
+        assert!(ir.contains("define i32 @main() {"), "{}", ir);
+    }
+
+    #[test]
+    fn test_mangling() {
+        let ir = compile("llvm-mangling", PROGRAM, &["--mangle"]);
+        assert!(ir.contains("define i32 @_c0_fib(i32 %p0) {"), "{}", ir);
+        assert!(ir.contains("call i32 @_c0_fib(i32 "), "{}", ir);
+        assert!(ir.contains("define i32 @main() {"), "{}", ir);
+        // Only the program's own functions are renamed
+        assert!(ir.contains("call i32 @abs(i32 %v"), "{}", ir);
+        assert!(!ir.contains("@_c0_abs"), "{}", ir);
+    }
+
+    #[test]
+    fn test_division_checks() {
+        let source = r#"
+int divide(int a, int b) {
+    return a / b;
+}
+
+int main() {
+    int x = 100;
+    return divide(x, 3) + x / 7;
+}
+"#;
+        let ir = compile("llvm-division", source, &[]);
+        // Only the division by a variable is checked for dividing by zero or overflowing
+        assert_eq!(ir.matches("sdiv i32").count(), 2, "{}", ir);
+        assert_eq!(ir.matches("icmp eq i32 %v1, 0").count(), 1, "{}", ir);
+        assert!(
+            ir.contains(&format!("icmp eq i32 %v0, {}", i32::MIN)),
+            "{}",
+            ir
+        );
+        assert_eq!(ir.matches("call void @abort()").count(), 1, "{}", ir);
+        assert!(ir.contains("call i32 @fflush(ptr null)"), "{}", ir);
+    }
+
+    #[test]
+    fn test_conversion_checks() {
+        let source = r#"
+int convert(double value) {
+    return (int)value;
+}
+
+int main() {
+    return convert(2.5) + (int)7.5;
+}
+"#;
+        let ir = compile("llvm-conversion", source, &[]);
+        // Only the conversion of a variable is checked for NaN or leaving int's range
+        assert_eq!(ir.matches("fptosi double").count(), 2, "{}", ir);
+        assert_eq!(ir.matches("fcmp ogt double").count(), 1, "{}", ir);
+        assert_eq!(ir.matches("fcmp olt double").count(), 1, "{}", ir);
+        assert_eq!(ir.matches("call void @abort()").count(), 1, "{}", ir);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let source = r#"
+int main() {
+    double zero = 0.0;
+    double nan = zero / zero;
+    char low = (char)10;
+    char high = (char)200;
+    int count = 0;
+    if (nan != nan) count++;
+    if (high > low) count++;
+    if (count < 2) return 1;
+    return 0;
+}
+"#;
+        let ir = compile("llvm-comparisons", source, &[]);
+        // Unordered doubles don't compare less, equal or greater
+        assert!(ir.contains("fcmp uno double"), "{}", ir);
+        // Chars compare unsigned, and ints signed
+        assert!(ir.contains("icmp ugt i8"), "{}", ir);
+        assert!(ir.contains("icmp slt i32"), "{}", ir);
+    }
+
+    #[test]
+    fn test_asm() {
+        let source = r#"
+int main() {
+    int a = 3;
+    int b = 4;
+    int sum = 0;
+    asm("movl %1, %0; addl %2, %0 # 100%% of $x" : "=r"(sum) : "r"(a), "r"(b));
+    asm("shll %%cl, %0" : "=a"(sum) : "c"(a) : "rdx", "cc");
+    return sum;
+}
+"#;
+        let ir = compile("llvm-asm", source, &[]);
+        assert!(
+            ir.contains(
+                r#"call i32 asm sideeffect "movl $1, $0; addl $2, $0 # 100% of $$x", "=r,r,r"(i32 %v"#
+            ),
+            "{}",
+            ir
+        );
+        // Registers named by the constraints, and the clobbers, as LLVM writes them
+        assert!(
+            ir.contains(r#"call i32 asm sideeffect "shll %cl, $0", "=a,c,~{rdx},~{cc}"(i32 %v"#),
+            "{}",
+            ir
+        );
+    }
+
+    #[test]
+    fn test_scan() {
+        let source =
+            "double d;\nint main() {\n    int x;\n    scan(x);\n    scan(d);\n    return x;\n}\n";
+        let ir = compile("llvm-scan", source, &[]);
+        // Each number is read straight into its temp's slot, as `printf` would print it but
+        // for doubles, and the program aborts without one
+        assert!(
+            ir.contains("call i32 (ptr, ...) @scanf(ptr @.format.int, ptr %t0)"),
+            "{}",
+            ir
+        );
+        assert!(
+            ir.contains("@scanf(ptr @.format.scan.double, ptr %t"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("c\"%lf\\00\""), "{}", ir);
+        assert!(ir.contains("declare i32 @scanf(ptr, ...)\n"), "{}", ir);
+        assert_eq!(ir.matches("call void @abort()").count(), 2, "{}", ir);
+    }
+
+    #[test]
+    fn test_from_ir() {
+        let workdir = setup_workdir("llvm-from-ir", "sample", PROGRAM);
+        assert!(compiler(&workdir, "sample", &[]).status.success());
+        let target = workdir.join("samples").join("target");
+        fs::copy(
+            target.join("sample.S"),
+            workdir.join("samples").join("copy.o0"),
+        )
+        .unwrap();
+        assert!(compiler(&workdir, "sample", &["--emit=llvm-ir"])
+            .status
+            .success());
+        let output = compiler(&workdir, "copy", &["--from-ir", "--emit=llvm-ir"]);
+        assert!(output.status.success());
+        // The program read back is written like the one it was generated from
+        assert_eq!(
+            fs::read_to_string(target.join("copy.ll")).unwrap(),
+            fs::read_to_string(target.join("sample.ll")).unwrap()
+        );
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        let workdir = setup_workdir("llvm-invalid", "sample", PROGRAM);
+        for flags in [
+            &["--emit=llvm-ir", "--target=x86_64", "--link"][..],
+            &["--emit=bitcode"],
+        ] {
+            let output = compiler(&workdir, "sample", flags);
+            let stderr = String::from_utf8(output.stderr).unwrap();
+            assert!(stderr.contains("Usage:"), "{}", stderr);
+            assert!(!workdir
+                .join("samples")
+                .join("target")
+                .join("sample.ll")
+                .exists());
+        }
+        fs::remove_dir_all(workdir).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use common::{branch, build, jmp, label, ret};
+use rust_compiler::codegen::dominators::DominatorTree;
+use rust_compiler::codegen::loops::LoopInfo;
+use rust_compiler::codegen::AbstractAssemblyInstruction;
+
+/// Loop analysis of `instructions`, which start with a label, so that block `n` is the `n`th
+/// label
+fn analyze(instructions: Vec<AbstractAssemblyInstruction>) -> LoopInfo {
+    let cfg = build(instructions);
+    let dominators = DominatorTree::new(&cfg);
+    LoopInfo::new(&cfg, &dominators)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_straight_line_code_has_no_loops() {
+        let info = analyze(vec![
+            label(0),
+            branch(1, 2),
+            label(1),
+            jmp(2),
+            label(2),
+            ret(0),
+        ]);
+        assert!(info.loops().is_empty());
+        assert!(info.back_edges().is_empty());
+        assert_eq!(info.loop_depth(1), 0);
+    }
+
+    #[test]
+    fn test_nested_loops() {
+        // 1 is the outer header and 2 the inner one:
+        // 0 -> 1 -> 2 -> 3 -> 2, 3 -> 4 -> 1, and 1 -> 5 (exit)
+        let info = analyze(vec![
+            label(0),
+            jmp(1),
+            label(1),
+            branch(2, 5),
+            label(2),
+            jmp(3),
+            label(3),
+            branch(2, 4),
+            label(4),
+            jmp(1),
+            label(5),
+            ret(0),
+        ]);
+        let loops = info.loops();
+        assert_eq!(loops.len(), 2);
+        assert_eq!(loops[0].header, 1);
+        assert_eq!(loops[0].blocks, [1, 2, 3, 4]);
+        assert_eq!(loops[0].latches, [4]);
+        assert_eq!(loops[0].parent, None);
+        assert_eq!(loops[1].header, 2);
+        assert_eq!(loops[1].blocks, [2, 3]);
+        assert_eq!(loops[1].parent, Some(0));
+        assert_eq!(loops[1].depth, 2);
+
+        let depths: Vec<usize> = (0..6).map(|block| info.loop_depth(block)).collect();
+        assert_eq!(depths, [0, 1, 2, 2, 1, 0]);
+        assert_eq!(info.innermost_loop(3), Some(1));
+        assert_eq!(info.innermost_loop(4), Some(0));
+        assert!(info.is_header(2));
+        assert!(!info.is_header(3));
+        assert_eq!(info.back_edges(), [(4, 1), (3, 2)]);
+    }
+
+    #[test]
+    fn test_back_edges_to_one_header_make_one_loop() {
+        // Like a `continue`: both 2 and 3 jump back to the header 1
+        let info = analyze(vec![
+            label(0),
+            jmp(1),
+            label(1),
+            branch(2, 4),
+            label(2),
+            branch(1, 3),
+            label(3),
+            jmp(1),
+            label(4),
+            ret(0),
+        ]);
+        assert_eq!(info.loops().len(), 1);
+        assert_eq!(info.loops()[0].blocks, [1, 2, 3]);
+        assert_eq!(info.loops()[0].latches, [2, 3]);
+    }
+}
@@ -0,0 +1,567 @@
+mod common;
+
+use common::setup_workdir;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+const CARRY: u8 = 0x01;
+const ZERO: u8 = 0x02;
+const INTERRUPT: u8 = 0x04;
+const DECIMAL: u8 = 0x08;
+const BREAK: u8 = 0x10;
+const UNUSED: u8 = 0x20;
+const OVERFLOW: u8 = 0x40;
+const NEGATIVE: u8 = 0x80;
+
+/// The C64 KERNAL's routine that writes the character in A
+const CHROUT: u16 = 0xffd2;
+/// Where a NES cartridge writes the characters it prints
+const NES_OUTPUT_PORT: u16 = 0x4018;
+
+/// A 6502 with 64K of RAM, enough of one to run the compiler's output. Decimal mode isn't
+/// implemented, since the startup code clears it.
+struct Cpu {
+    a: u8,
+    x: u8,
+    y: u8,
+    sp: u8,
+    p: u8,
+    pc: u16,
+    memory: Vec<u8>,
+    /// Characters written to CHROUT or the NES's output port
+    output: Vec<u8>,
+}
+
+/// Where an instruction's operand is
+enum Operand {
+    Accumulator,
+    Memory(u16),
+}
+
+impl Cpu {
+    fn new() -> Self {
+        Cpu {
+            a: 0,
+            x: 0,
+            y: 0,
+            sp: 0xff,
+            p: UNUSED | INTERRUPT,
+            pc: 0,
+            memory: vec![0; 0x10000],
+            output: Vec::new(),
+        }
+    }
+
+    fn read_word(&self, address: u16) -> u16 {
+        let high = self.memory[address.wrapping_add(1) as usize];
+        u16::from_le_bytes([self.memory[address as usize], high])
+    }
+
+    /// Reads a pointer from the zero page, wrapping within it
+    fn read_zero_page_word(&self, address: u8) -> u16 {
+        let high = self.memory[address.wrapping_add(1) as usize];
+        u16::from_le_bytes([self.memory[address as usize], high])
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let byte = self.memory[self.pc as usize];
+        self.pc = self.pc.wrapping_add(1);
+        byte
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        let low = self.fetch();
+        u16::from_le_bytes([low, self.fetch()])
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.memory[0x100 + self.sp as usize] = byte;
+        self.sp = self.sp.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
+        self.memory[0x100 + self.sp as usize]
+    }
+
+    fn flag(&self, flag: u8) -> bool {
+        self.p & flag != 0
+    }
+
+    fn set_flag(&mut self, flag: u8, value: bool) {
+        if value {
+            self.p |= flag;
+        } else {
+            self.p &= !flag;
+        }
+    }
+
+    fn set_nz(&mut self, value: u8) -> u8 {
+        self.set_flag(ZERO, value == 0);
+        self.set_flag(NEGATIVE, value & 0x80 != 0);
+        value
+    }
+
+    fn load(&self, operand: &Operand) -> u8 {
+        match operand {
+            Operand::Accumulator => self.a,
+            Operand::Memory(address) => self.memory[*address as usize],
+        }
+    }
+
+    fn store(&mut self, operand: &Operand, value: u8) {
+        match operand {
+            Operand::Accumulator => self.a = value,
+            Operand::Memory(NES_OUTPUT_PORT) => self.output.push(value),
+            Operand::Memory(address) => self.memory[*address as usize] = value,
+        }
+    }
+
+    fn add(&mut self, value: u8) {
+        let sum = self.a as u16 + value as u16 + self.flag(CARRY) as u16;
+        let result = sum as u8;
+        let overflow = (self.a ^ result) & (value ^ result) & 0x80 != 0;
+        self.set_flag(CARRY, sum > 0xff);
+        self.set_flag(OVERFLOW, overflow);
+        self.a = self.set_nz(result);
+    }
+
+    fn compare(&mut self, register: u8, value: u8) {
+        self.set_flag(CARRY, register >= value);
+        self.set_nz(register.wrapping_sub(value));
+    }
+
+    /// Operand of an instruction in one of the three regular groups, by its `bbb` bits
+    fn operand(&mut self, opcode: u8) -> Operand {
+        let (mode, group) = ((opcode >> 2) & 7, opcode & 3);
+        // LDX and STX index with Y where the others index with X
+        let index = if group == 2 && (opcode & 0xc0) == 0x80 {
+            self.y
+        } else {
+            self.x
+        };
+        let address = match (group, mode) {
+            (1, 0) => {
+                let pointer = self.fetch().wrapping_add(self.x);
+                self.read_zero_page_word(pointer)
+            }
+            (1, 2) | (_, 0) => {
+                let address = self.pc;
+                self.pc = self.pc.wrapping_add(1);
+                address
+            }
+            (_, 1) => self.fetch() as u16,
+            (2, 2) => return Operand::Accumulator,
+            (_, 3) => self.fetch_word(),
+            (1, 4) => {
+                let pointer = self.fetch();
+                self.read_zero_page_word(pointer)
+                    .wrapping_add(self.y as u16)
+            }
+            (_, 5) => self.fetch().wrapping_add(index) as u16,
+            (1, 6) => self.fetch_word().wrapping_add(self.y as u16),
+            (_, 7) => self.fetch_word().wrapping_add(index as u16),
+            _ => panic!("no 6502 opcode ${:02x} at ${:04x}", opcode, self.pc - 1),
+        };
+        Operand::Memory(address)
+    }
+
+    /// Runs one instruction
+    fn step(&mut self) {
+        let opcode = self.fetch();
+        match opcode {
+            0x00 => {
+                let return_address = self.pc.wrapping_add(1);
+                self.push((return_address >> 8) as u8);
+                self.push(return_address as u8);
+                self.push(self.p | BREAK);
+                self.p |= INTERRUPT;
+                self.pc = self.read_word(0xfffe);
+            }
+            0x20 => {
+                let target = self.fetch_word();
+                let return_address = self.pc.wrapping_sub(1);
+                self.push((return_address >> 8) as u8);
+                self.push(return_address as u8);
+                self.pc = target;
+            }
+            0x40 => {
+                self.p = self.pull() | UNUSED;
+                let low = self.pull();
+                self.pc = u16::from_le_bytes([low, self.pull()]);
+            }
+            0x60 => {
+                let low = self.pull();
+                self.pc = u16::from_le_bytes([low, self.pull()]).wrapping_add(1);
+            }
+            0x4c => self.pc = self.fetch_word(),
+            0x6c => {
+                // The indirect JMP never carries into the pointer's high byte
+                let pointer = self.fetch_word();
+                let high = (pointer & 0xff00) | (pointer as u8).wrapping_add(1) as u16;
+                let low = self.memory[pointer as usize];
+                self.pc = u16::from_le_bytes([low, self.memory[high as usize]]);
+            }
+            _ if opcode & 0x1f == 0x10 => {
+                let flag = [NEGATIVE, OVERFLOW, CARRY, ZERO][(opcode >> 6) as usize];
+                let offset = self.fetch() as i8;
+                if self.flag(flag) == (opcode & 0x20 != 0) {
+                    self.pc = self.pc.wrapping_add(offset as u16);
+                }
+            }
+            0x08 => self.push(self.p | BREAK | UNUSED),
+            0x28 => self.p = (self.pull() & !BREAK) | UNUSED,
+            0x48 => self.push(self.a),
+            0x68 => {
+                let value = self.pull();
+                self.a = self.set_nz(value);
+            }
+            0x18 => self.set_flag(CARRY, false),
+            0x38 => self.set_flag(CARRY, true),
+            0x58 => self.set_flag(INTERRUPT, false),
+            0x78 => self.set_flag(INTERRUPT, true),
+            0xb8 => self.set_flag(OVERFLOW, false),
+            0xd8 => self.set_flag(DECIMAL, false),
+            0xf8 => panic!("decimal mode isn't emulated"),
+            0x88 => self.y = self.set_nz(self.y.wrapping_sub(1)),
+            0xc8 => self.y = self.set_nz(self.y.wrapping_add(1)),
+            0xca => self.x = self.set_nz(self.x.wrapping_sub(1)),
+            0xe8 => self.x = self.set_nz(self.x.wrapping_add(1)),
+            0x98 => self.a = self.set_nz(self.y),
+            0xa8 => self.y = self.set_nz(self.a),
+            0x8a => self.a = self.set_nz(self.x),
+            0xaa => self.x = self.set_nz(self.a),
+            0x9a => self.sp = self.x,
+            0xba => self.x = self.set_nz(self.sp),
+            0xea => {}
+            _ => self.regular(opcode),
+        }
+    }
+
+    /// Runs an instruction of the three groups whose opcodes are `aaabbbcc`
+    fn regular(&mut self, opcode: u8) {
+        let operand = self.operand(opcode);
+        // Stores read their operand too, which is harmless without I/O registers
+        let value = self.load(&operand);
+        match (opcode & 3, opcode >> 5) {
+            (1, 0) => self.a = self.set_nz(self.a | value),
+            (1, 1) => self.a = self.set_nz(self.a & value),
+            (1, 2) => self.a = self.set_nz(self.a ^ value),
+            (1, 3) => self.add(value),
+            (1, 4) => self.store(&operand, self.a),
+            (1, 5) => self.a = self.set_nz(value),
+            (1, 6) => self.compare(self.a, value),
+            (1, 7) => self.add(!value),
+            (2, 0..=3) => {
+                let carry_in = self.flag(CARRY) as u8;
+                let (result, carry) = match opcode >> 5 {
+                    0 => (value << 1, value & 0x80),
+                    1 => (value << 1 | carry_in, value & 0x80),
+                    2 => (value >> 1, value & 1),
+                    _ => (value >> 1 | carry_in << 7, value & 1),
+                };
+                self.set_flag(CARRY, carry != 0);
+                let result = self.set_nz(result);
+                self.store(&operand, result);
+            }
+            (2, 4) => self.store(&operand, self.x),
+            (2, 5) => self.x = self.set_nz(value),
+            (2, 6 | 7) => {
+                let result = match opcode >> 5 {
+                    6 => value.wrapping_sub(1),
+                    _ => value.wrapping_add(1),
+                };
+                let result = self.set_nz(result);
+                self.store(&operand, result);
+            }
+            (0, 1) => {
+                self.set_flag(ZERO, self.a & value == 0);
+                self.set_flag(NEGATIVE, value & NEGATIVE != 0);
+                self.set_flag(OVERFLOW, value & OVERFLOW != 0);
+            }
+            (0, 4) => self.store(&operand, self.y),
+            (0, 5) => self.y = self.set_nz(value),
+            (0, 6) => self.compare(self.y, value),
+            (0, 7) => self.compare(self.x, value),
+            _ => panic!("no 6502 opcode ${:02x}", opcode),
+        }
+    }
+
+    /// Runs until the program counter reaches `stop` or an instruction jumps to itself. Calls to
+    /// CHROUT are recorded; the routine is an RTS for the caller to put there.
+    fn run(&mut self, stop: u16) {
+        for _ in 0..10_000_000 {
+            let pc = self.pc;
+            if pc == CHROUT {
+                self.output.push(self.a);
+            }
+            self.step();
+            if self.pc == stop || self.pc == pc {
+                return;
+            }
+        }
+        panic!("the program ran for too long");
+    }
+}
+
+/// Runs an iNES cartridge from its reset vector until it halts, returning the machine
+fn run_nes(nes: &[u8]) -> Cpu {
+    let mut cpu = Cpu::new();
+    cpu.memory[0x8000..].copy_from_slice(&nes[16..16 + 0x8000]);
+    cpu.pc = cpu.read_word(0xfffc);
+    cpu.run(0);
+    cpu
+}
+
+/// Loads a C64 program and runs it as `SYS` would, with `zero_page` filling the zero page
+/// the way BASIC left it, until it returns
+fn run_prg(prg: &[u8], zero_page: u8) -> Cpu {
+    let mut cpu = Cpu::new();
+    cpu.memory[2..0x100].fill(zero_page);
+    cpu.memory[CHROUT as usize] = 0x60;
+    let load_address = u16::from_le_bytes([prg[0], prg[1]]) as usize;
+    cpu.memory[load_address..load_address + prg.len() - 2].copy_from_slice(&prg[2..]);
+    // BASIC's SYS comes back to $ffff + 1
+    cpu.push(0xff);
+    cpu.push(0xff);
+    cpu.pc = 2061;
+    cpu.run(0);
+    cpu
+}
+
+/// The int main returned, which the startup code leaves in the result bytes of the default
+/// zero-page window
+fn result(cpu: &Cpu) -> i32 {
+    i32::from_le_bytes(cpu.memory[4..8].try_into().unwrap())
+}
+
+/// Compiles `name` for the 6502 in the file `format` names, and returns the file
+fn compile_to(workdir: &Path, name: &str, format: &str) -> Vec<u8> {
+    let status = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+        .args(["--target=m6502", &format!("--format={}", format), name])
+        .current_dir(workdir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let extension = if format == "asm" { "S" } else { format };
+    fs::read(
+        workdir
+            .join("samples")
+            .join("target")
+            .join(format!("{}.{}", name, extension)),
+    )
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = r#"
+int fib(int n) {
+    if (n < 2) return n;
+    return fib(n - 1) + fib(n - 2);
+}
+
+int sum(int from, int to, int step) {
+    int total = 0;
+    for (int i = from; i != to; i += step) {
+        total += i;
+    }
+    return total;
+}
+
+int main() {
+    int big = 100000;
+    if (sum(5, -5, -1) != 5) return -1;
+    if (-3 > 2) return -2;
+    if (big < 65536) return -3;
+    return fib(12) + big - sum(0, 10, 1);
+}
+"#;
+
+    #[test]
+    fn test_nes_cartridge_runs() {
+        let workdir = setup_workdir("m6502-nes", "sample", PROGRAM);
+        let nes = compile_to(&workdir, "sample", "nes");
+        assert_eq!(nes.len(), 16 + 0x8000);
+        assert_eq!(&nes[..8], b"NES\x1a\x02\x00\x00\x00");
+        assert!(nes[8..16].iter().all(|&byte| byte == 0));
+
+        let cpu = run_nes(&nes);
+        assert_eq!(result(&cpu), 144 + 100000 - 45);
+        // Interrupts land on an RTI
+        for vector in [0xfffa, 0xfffe] {
+            assert_eq!(cpu.memory[cpu.read_word(vector) as usize], 0x40);
+        }
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_c64_program_returns_to_basic() {
+        let workdir = setup_workdir("m6502-prg", "sample", PROGRAM);
+        let prg = compile_to(&workdir, "sample", "prg");
+        // Loaded at $0801, with `10 SYS 2061` in front of the code
+        assert_eq!(&prg[..2], &[0x01, 0x08]);
+        assert_eq!(&prg[2..14], b"\x0b\x08\x0a\x00\x9e2061\x00\x00\x00");
+
+        let cpu = run_prg(&prg, 0xa5);
+        assert_eq!(cpu.sp, 0xff);
+        // BASIC's zero page is as it was
+        assert!(cpu.memory[2..0x100].iter().all(|&byte| byte == 0xa5));
+
+        // The assembly still leaves the variables to the linker
+        let assembly = String::from_utf8(compile_to(&workdir, "sample", "asm")).unwrap();
+        assert!(
+            assembly.contains("\nc0_stack:\t.res 1024\n"),
+            "{}",
+            assembly
+        );
+        assert!(!assembly.contains("c0_saved_zp"), "{}", assembly);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    const ARITHMETIC: &str = r#"
+int mul(int a, int b) {
+    return a * b;
+}
+
+int div(int a, int b) {
+    return a / b;
+}
+
+int main() {
+    print("%d %d %d %d\n", mul(6, 7), mul(-6, 7), mul(-1000, -1000), mul(65536, 65536));
+    print("%d %d %d %d\n", div(100, 7), div(-100, 7), div(100, -7), div(-100, -7));
+    print("%s %d %d\n", "limits", 2147483647, -2147483647 - 1);
+    return div(mul(12345, -6789), 1000);
+}
+"#;
+
+    #[test]
+    fn test_runtime_multiplies_divides_and_prints() {
+        let workdir = setup_workdir("m6502-runtime", "sample", ARITHMETIC);
+        let cpu = run_nes(&compile_to(&workdir, "sample", "nes"));
+        let expected = "42 -42 1000000 0\n14 -14 -14 14\nlimits 2147483647 -2147483648\n";
+        assert_eq!(String::from_utf8(cpu.output.clone()).unwrap(), expected);
+        assert_eq!(result(&cpu), 12345 * -6789 / 1000);
+
+        // The C64 prints through the KERNAL, in uppercase with carriage returns
+        let cpu = run_prg(&compile_to(&workdir, "sample", "prg"), 0);
+        let expected = expected.to_uppercase().replace('\n', "\r");
+        assert_eq!(String::from_utf8(cpu.output).unwrap(), expected);
+
+        // Plain assembly leaves printing characters to the platform
+        let assembly = String::from_utf8(compile_to(&workdir, "sample", "asm")).unwrap();
+        assert!(
+            assembly.starts_with("\t.import c0_putchar\n"),
+            "{}",
+            assembly
+        );
+        for routine in ["c0_mul", "c0_div", "c0_print_int", "c0_print_string"] {
+            assert!(
+                assembly.contains(&format!("\n{}:\n", routine)),
+                "{}",
+                assembly
+            );
+        }
+        assert!(!assembly.contains("c0_print_char"), "{}", assembly);
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_runtime_is_included_only_when_used() {
+        let workdir = setup_workdir("m6502-no-runtime", "sample", PROGRAM);
+        let assembly = String::from_utf8(compile_to(&workdir, "sample", "asm")).unwrap();
+        for symbol in ["c0_mul", "c0_div", "c0_print", "c0_putchar", "c0_scratch"] {
+            assert!(!assembly.contains(symbol), "{}", assembly);
+        }
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_division_by_zero_aborts() {
+        let source = r#"
+int div(int a, int b) {
+    return a / b;
+}
+
+int main() {
+    print("%d\n", div(-7, 2));
+    print("%d\n", div(1, 0));
+    return 1;
+}
+"#;
+        let workdir = setup_workdir("m6502-divide-by-zero", "sample", source);
+        let cpu = run_nes(&compile_to(&workdir, "sample", "nes"));
+        // The abort unwinds the hardware stack, and main never returns
+        assert_eq!(cpu.output, b"-3\n");
+        assert_eq!(cpu.sp, 0xff);
+        assert_ne!(result(&cpu), 1);
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_functions_read_and_write_globals() {
+        let source = r#"
+int count = 70000;
+string name = "globals";
+
+int bump(int by) {
+    count = count + by;
+    return count;
+}
+
+int main() {
+    bump(5);
+    print("%d %s\n", count, name);
+    name = "changed";
+    print("%s\n", name);
+    return bump(-70000);
+}
+"#;
+        let workdir = setup_workdir("m6502-globals", "sample", source);
+        let expected = "70005 globals\nchanged\n";
+        let nes = compile_to(&workdir, "sample", "nes");
+        let cpu = run_nes(&nes);
+        assert_eq!(String::from_utf8(cpu.output.clone()).unwrap(), expected);
+        assert_eq!(result(&cpu), 5);
+        // The cartridge keeps its globals in RAM, and its ROM is never written
+        assert_eq!(&cpu.memory[0x8000..], &nes[16..16 + 0x8000]);
+
+        let cpu = run_prg(&compile_to(&workdir, "sample", "prg"), 0);
+        assert_eq!(
+            String::from_utf8(cpu.output).unwrap(),
+            expected.to_uppercase().replace('\n', "\r")
+        );
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_formats_are_checked_against_the_target() {
+        let workdir = setup_workdir("m6502-formats", "sample", "int main() { return 0; }");
+        for flags in [
+            &["--format=prg", "sample"][..],
+            &["--target=m6502", "--format=elf", "sample"],
+            &["--target=m6502", "--format=nes", "--link", "sample"],
+        ] {
+            let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+                .args(flags)
+                .current_dir(&workdir)
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8(output.stderr).unwrap();
+            assert!(
+                stderr.contains("Usage:") || stderr.contains("linked"),
+                "{}",
+                stderr
+            );
+        }
+        fs::remove_dir_all(workdir).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+use rust_compiler::codegen::assembler::{assemble, Item, Relocation, RelocationKind};
+use rust_compiler::codegen::object::{write_coff, write_elf, Object, Section, Symbol, SymbolKind};
+use rust_compiler::codegen::x86_encoding::{Memory, Op, RegOrMem, Register};
+use std::env;
+use std::fs;
+use std::process::Command;
+
+fn symbol(name: &str, section: Section, offset: usize, size: usize, kind: SymbolKind) -> Symbol {
+    Symbol {
+        name: name.to_string(),
+        section,
+        offset,
+        size,
+        kind,
+        global: false,
+    }
+}
+
+/// An object whose `main` prints "hello" twice with `puts`, once through a pointer in `.data`,
+/// and returns 42 from a global in `.data` plus a zeroed one in `.bss`
+fn hello() -> Object {
+    let rip = |op: Op, symbol: &str| Item::Rip(op, symbol.to_string());
+    let from = |register: Register| RegOrMem::Register(register);
+    let address = || RegOrMem::Memory(Memory::rip(0));
+    let items = vec![
+        Item::Op(Op::Push(from(Register::RBP))),
+        rip(Op::Lea(Register::RDI, Memory::rip(0)), "message"),
+        Item::Call("puts".to_string()),
+        rip(Op::Mov(from(Register::RDI), address()), "pointer"),
+        Item::Call("puts".to_string()),
+        rip(Op::Mov(from(Register::EAX), address()), "counter"),
+        rip(Op::Add(from(Register::EAX), address()), "zeroed"),
+        Item::Op(Op::Pop(from(Register::RBP))),
+        Item::Op(Op::Ret),
+    ];
+    let text = assemble(items).unwrap();
+
+    let mut data = 42u32.to_le_bytes().to_vec();
+    data.extend_from_slice(&[0; 12]);
+    let mut relocations: Vec<(Section, Relocation)> = text
+        .relocations
+        .into_iter()
+        .map(|relocation| (Section::Text, relocation))
+        .collect();
+    relocations.push((
+        Section::Data,
+        Relocation {
+            offset: 8,
+            symbol: "message".to_string(),
+            kind: RelocationKind::Abs64,
+            addend: 0,
+        },
+    ));
+    let main = Symbol {
+        global: true,
+        ..symbol(
+            "main",
+            Section::Text,
+            0,
+            text.bytes.len(),
+            SymbolKind::Function,
+        )
+    };
+    let counter = Symbol {
+        global: true,
+        ..symbol("counter", Section::Data, 0, 4, SymbolKind::Object)
+    };
+    Object {
+        text: text.bytes,
+        data,
+        bss: 4,
+        rodata: b"hello\0".to_vec(),
+        symbols: vec![
+            main,
+            counter,
+            symbol("pointer", Section::Data, 8, 8, SymbolKind::Object),
+            symbol("zeroed", Section::Bss, 0, 4, SymbolKind::Object),
+            symbol("message", Section::Rodata, 0, 0, SymbolKind::Label),
+        ],
+        relocations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let elf = write_elf(&hello());
+        // 64-bit, little-endian, relocatable, for x86-64
+        assert_eq!(&elf[..8], [0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        assert_eq!(u16::from_le_bytes([elf[16], elf[17]]), 1);
+        assert_eq!(u16::from_le_bytes([elf[18], elf[19]]), 62);
+        // The section headers are where the header says, and the last is the one with the names
+        let headers = u64::from_le_bytes(elf[40..48].try_into().unwrap()) as usize;
+        let count = u16::from_le_bytes([elf[60], elf[61]]) as usize;
+        let names = u16::from_le_bytes([elf[62], elf[63]]) as usize;
+        assert_eq!(headers + 64 * count, elf.len());
+        assert_eq!(names, count - 1);
+        // Symbols the code refers to without defining are named for the linker
+        assert!(elf.windows(6).any(|window| window == b"\0puts\0"));
+    }
+
+    #[test]
+    fn test_object_links_and_runs() {
+        let workdir = env::temp_dir().join(format!("rust-compiler-object-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&workdir);
+        fs::create_dir_all(&workdir).unwrap();
+        fs::write(workdir.join("hello.o"), write_elf(&hello())).unwrap();
+
+        // `.bss` is zeroed data of the size the object says, and only the code and `.data`
+        // are relocated
+        let output = Command::new("objdump")
+            .arg("-h")
+            .arg(workdir.join("hello.o"))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let headers = String::from_utf8(output.stdout).unwrap();
+        // The line with the section's size, and the one with its flags after it
+        let section = |name: &str| {
+            let lines: Vec<&str> = headers.lines().collect();
+            let index = lines
+                .iter()
+                .position(|line| line.split_whitespace().nth(1) == Some(name))
+                .unwrap_or_else(|| panic!("no {} in\n{}", name, headers));
+            let size = lines[index].split_whitespace().nth(2).unwrap().to_string();
+            (size, lines[index + 1].to_string())
+        };
+        let (size, flags) = section(".bss");
+        assert_eq!(size, "00000004");
+        assert!(!flags.contains("CONTENTS") && !flags.contains("RELOC"));
+        assert!(section(".text").1.contains("RELOC"));
+        assert!(section(".data").1.contains("RELOC"));
+        assert!(!section(".rodata").1.contains("RELOC"));
+        // Sections without relocations don't get an empty relocation section
+        let output = Command::new("readelf")
+            .arg("-S")
+            .arg(workdir.join("hello.o"))
+            .output()
+            .unwrap();
+        let sections = String::from_utf8(output.stdout).unwrap();
+        assert!(sections.contains(".rela.text") && sections.contains(".rela.data"));
+        assert!(!sections.contains(".rela.bss") && !sections.contains(".rela.rodata"));
+
+        let status = Command::new("cc")
+            .arg("-o")
+            .arg(workdir.join("hello"))
+            .arg(workdir.join("hello.o"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let output = Command::new(workdir.join("hello")).output().unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "hello\nhello\n");
+        assert_eq!(output.status.code(), Some(42));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn test_coff_header() {
+        let coff = write_coff(&hello());
+        // For x86-64, with the four sections and no optional header
+        assert_eq!(u16::from_le_bytes([coff[0], coff[1]]), 0x8664);
+        assert_eq!(u16::from_le_bytes([coff[2], coff[3]]), 4);
+        assert_eq!(u16::from_le_bytes([coff[16], coff[17]]), 0);
+        assert_eq!(&coff[20 + 3 * 40..20 + 3 * 40 + 8], b".rdata\0\0");
+        // The symbols are where the header says, and the string table after them runs to the
+        // end, with main, counter, pointer, zeroed, message and puts
+        let symbols = u32::from_le_bytes(coff[8..12].try_into().unwrap()) as usize;
+        let count = u32::from_le_bytes(coff[12..16].try_into().unwrap()) as usize;
+        assert_eq!(count, 6);
+        let strings = symbols + 18 * count;
+        let size = u32::from_le_bytes(coff[strings..strings + 4].try_into().unwrap()) as usize;
+        assert_eq!(strings + size, coff.len());
+        // Names of up to 8 bytes are in the symbols themselves
+        assert_eq!(
+            &coff[symbols + 18 * 5..symbols + 18 * 5 + 8],
+            b"puts\0\0\0\0"
+        );
+    }
+
+    #[test]
+    fn test_coff_relocations() {
+        let workdir = env::temp_dir().join(format!("rust-compiler-coff-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&workdir);
+        fs::create_dir_all(&workdir).unwrap();
+        fs::write(workdir.join("hello.obj"), write_coff(&hello())).unwrap();
+
+        let output = Command::new("objdump")
+            .arg("-r")
+            .arg(workdir.join("hello.obj"))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let relocations = String::from_utf8(output.stdout).unwrap();
+        assert!(relocations.contains("file format pe-x86-64"));
+        let has = |relocation: [&str; 3]| {
+            relocations
+                .lines()
+                .any(|line| line.split_whitespace().eq(relocation))
+        };
+        // Calls go straight to puts, and the pointer in .data holds the address of the message
+        assert!(has(["0000000000000009", "IMAGE_REL_AMD64_REL32", "puts"]));
+        assert!(has([
+            "0000000000000008",
+            "IMAGE_REL_AMD64_ADDR64",
+            "message"
+        ]));
+
+        fs::remove_dir_all(workdir).unwrap();
+    }
+}
@@ -1,7 +1,17 @@
 #[cfg(test)]
 mod tests {
-    use rust_compiler::lexer::Token;
-    use rust_compiler::parser::{parse, Expr, Program, Statement};
+    use rust_compiler::lexer::{tokenize_with_spans, Token};
+    use rust_compiler::parser::{
+        parse, parse_with_spans, AsmConstraints, BinOp, Expr, FormatPart, FormatSpec, LValue,
+        ParserError, PostfixOp, Statement,
+    };
+    use rust_compiler::source_map::Span;
+
+    /// The errors of parsing `source`, without their spans
+    fn parse_errors(source: &str) -> Vec<ParserError> {
+        let errors = parse_with_spans(tokenize_with_spans(source)).unwrap_err();
+        errors.into_iter().map(|error| error.node).collect()
+    }
 
     #[test]
     fn test_hello_world() {
@@ -62,8 +72,8 @@ mod tests {
             Token::Identifier("MAX_SIZE".to_string())
         );
 
-        match &var_decl.value {
-            Expr::Literal(Token::Number(n)) => assert_eq!(*n, 100.0),
+        match var_decl.value.as_ref().map(|value| &value.node) {
+            Some(Expr::Literal(Token::Number(n))) => assert_eq!(*n, 100.0),
             _ => panic!("Expected number literal"),
         }
     }
@@ -140,28 +150,103 @@ mod tests {
         assert_eq!(abs_fn.identifier, Token::Identifier("abs".to_string()));
 
         let statements = &abs_fn.body.statements;
-        match &statements[0] {
+        match &statements[0].node {
             Statement::If(condition, then_branch, else_branch) => {
-                match &**condition {
+                match &condition.node {
                     Expr::Binary(left, op, right) => {
-                        match &**left {
+                        match &left.node {
                             Expr::Variable(Token::Identifier(name)) => assert_eq!(name, "x"),
                             _ => panic!("Expected variable reference"),
                         }
-                        assert_eq!(*op, Token::Less);
-                        match &**right {
+                        assert_eq!(*op, BinOp::Less);
+                        match &right.node {
                             Expr::Literal(Token::Number(n)) => assert_eq!(*n, 0.0),
                             _ => panic!("Expected number literal"),
                         }
                     }
                     _ => panic!("Expected binary expression"),
                 }
+                assert!(matches!(then_branch.node, Statement::Return(Some(_))));
                 assert!(else_branch.is_none());
             }
             _ => panic!("Expected if statement"),
         }
     }
 
+    /// Returns the first statement of the only function in `source`
+    fn first_statement(source: &str) -> Statement {
+        let mut program = parse_with_spans(tokenize_with_spans(source)).unwrap();
+        program.fns.remove(0).body.statements.remove(0).node
+    }
+
+    #[test]
+    fn test_if_else() {
+        let statement =
+            first_statement("int f(int x) { if (x) return 1; else return 2; return 3; }");
+        match statement {
+            Statement::If(_, then_branch, Some(else_branch)) => {
+                assert!(matches!(then_branch.node, Statement::Return(Some(_))));
+                assert!(matches!(else_branch.node, Statement::Return(Some(_))));
+            }
+            other => panic!("Expected if/else, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_else_if_chain() {
+        let statement = first_statement(
+            "int sign(int x) { if (x < 0) return -1; else if (x > 0) return 1; else return 0; }",
+        );
+        match statement {
+            Statement::If(_, _, Some(else_branch)) => match else_branch.node {
+                Statement::If(condition, _, Some(last)) => {
+                    assert!(matches!(condition.node, Expr::Binary(_, BinOp::Greater, _)));
+                    assert!(matches!(last.node, Statement::Return(Some(_))));
+                }
+                other => panic!("Expected nested if/else, got {:?}", other),
+            },
+            other => panic!("Expected if/else, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_dangling_else() {
+        // The else belongs to the inner if
+        let statement =
+            first_statement("int f(int a, int b) { if (a) if (b) return 1; else return 2; }");
+        match statement {
+            Statement::If(_, then_branch, None) => {
+                assert!(matches!(then_branch.node, Statement::If(_, _, Some(_))))
+            }
+            other => panic!("Expected if without else, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_if_without_else_keeps_next_statement() {
+        let source = "int f(int x) { if (x) return 1; return 2; }";
+        let program = parse_with_spans(tokenize_with_spans(source)).unwrap();
+        let statements = &program.fns[0].body.statements;
+        assert_eq!(statements.len(), 2);
+        assert!(matches!(statements[0].node, Statement::If(_, _, None)));
+        assert!(matches!(statements[1].node, Statement::Return(Some(_))));
+    }
+
+    #[test]
+    fn test_else_without_if() {
+        let source = "int f(int x) { else return 1; }";
+        let errors = parse_with_spans(tokenize_with_spans(source)).unwrap_err();
+        assert!(matches!(
+            errors[0].node,
+            ParserError::UnexpectedToken {
+                found: Token::Else,
+                ..
+            }
+        ));
+        // The error points at the `else`
+        assert_eq!(errors[0].span, Span::new(15, 19));
+    }
+
     #[test]
     fn test_while_loop() {
         let tokens = vec![
@@ -196,22 +281,623 @@ mod tests {
         assert_eq!(countdown_fn.return_type, Token::Void);
 
         let statements = &countdown_fn.body.statements;
-        match &statements[0] {
-            Statement::While(condition, body) => match &**condition {
-                Expr::Binary(left, op, right) => {
-                    match &**left {
-                        Expr::Variable(Token::Identifier(name)) => assert_eq!(name, "n"),
-                        _ => panic!("Expected variable reference"),
-                    }
-                    assert_eq!(*op, Token::Greater);
-                    match &**right {
-                        Expr::Literal(Token::Number(n)) => assert_eq!(*n, 0.0),
-                        _ => panic!("Expected number literal"),
+        match &statements[0].node {
+            Statement::While(condition, _invariants, body) => {
+                assert!(matches!(body.node, Statement::Block(_)));
+                match &condition.node {
+                    Expr::Binary(left, op, right) => {
+                        match &left.node {
+                            Expr::Variable(Token::Identifier(name)) => assert_eq!(name, "n"),
+                            _ => panic!("Expected variable reference"),
+                        }
+                        assert_eq!(*op, BinOp::Greater);
+                        match &right.node {
+                            Expr::Literal(Token::Number(n)) => assert_eq!(*n, 0.0),
+                            _ => panic!("Expected number literal"),
+                        }
                     }
+                    _ => panic!("Expected binary expression"),
                 }
-                _ => panic!("Expected binary expression"),
-            },
+            }
             _ => panic!("Expected while statement"),
         }
     }
+
+    #[test]
+    fn test_cast_expression() {
+        // int x = (int) (y) + 1;
+        let tokens = vec![
+            Token::Int,
+            Token::Identifier("x".to_string()),
+            Token::Equal,
+            Token::LeftParen,
+            Token::Int,
+            Token::RightParen,
+            Token::LeftParen,
+            Token::Identifier("y".to_string()),
+            Token::RightParen,
+            Token::Plus,
+            Token::Number(1.0),
+            Token::Semicolon,
+            Token::Eof,
+        ];
+
+        let program = parse(tokens).unwrap();
+
+        // The cast binds tighter than `+`, and `(y)` stays a parenthesized expression
+        match program.decl[0].value.as_ref().map(|value| &value.node) {
+            Some(Expr::Binary(left, op, _)) => {
+                assert_eq!(*op, BinOp::Add);
+                match &left.node {
+                    Expr::Cast(type_token, operand) => {
+                        assert_eq!(*type_token, Token::Int);
+                        assert!(matches!(&operand.node, Expr::Parentheses(_)));
+                    }
+                    _ => panic!("Expected cast expression"),
+                }
+            }
+            _ => panic!("Expected binary expression"),
+        }
+    }
+
+    #[test]
+    fn test_declaration_without_initializer() {
+        // int g; int main() { int x; x = 1; return x; }
+        let tokens = vec![
+            Token::Int,
+            Token::Identifier("g".to_string()),
+            Token::Semicolon,
+            Token::Int,
+            Token::Identifier("main".to_string()),
+            Token::LeftParen,
+            Token::RightParen,
+            Token::LeftBrace,
+            Token::Int,
+            Token::Identifier("x".to_string()),
+            Token::Semicolon,
+            Token::Identifier("x".to_string()),
+            Token::Equal,
+            Token::Number(1.0),
+            Token::Semicolon,
+            Token::Return,
+            Token::Identifier("x".to_string()),
+            Token::Semicolon,
+            Token::RightBrace,
+            Token::Eof,
+        ];
+
+        let program = parse(tokens).unwrap();
+
+        assert_eq!(program.decl.len(), 1);
+        assert!(program.decl[0].value.is_none());
+
+        let statements = &program.fns[0].body.statements;
+        assert_eq!(statements.len(), 3);
+        match &statements[0].node {
+            Statement::VarDecl(decl) => {
+                assert_eq!(decl.identifier, Token::Identifier("x".to_string()));
+                assert!(decl.value.is_none());
+            }
+            _ => panic!("Expected variable declaration"),
+        }
+    }
+
+    #[test]
+    fn test_assignment() {
+        // int main() { x = y = 1; }
+        let tokens = vec![
+            Token::Int,
+            Token::Identifier("main".to_string()),
+            Token::LeftParen,
+            Token::RightParen,
+            Token::LeftBrace,
+            Token::Identifier("x".to_string()),
+            Token::Equal,
+            Token::Identifier("y".to_string()),
+            Token::Equal,
+            Token::Number(1.0),
+            Token::Semicolon,
+            Token::RightBrace,
+            Token::Eof,
+        ];
+
+        let program = parse(tokens).unwrap();
+
+        // Assignment is right-associative
+        let statement = &program.fns[0].body.statements[0].node;
+        let Statement::Expression(expr) = statement else {
+            panic!("Expected expression statement");
+        };
+        match &expr.node {
+            Expr::Assign(LValue::Variable(x), value) => {
+                assert_eq!(*x, Token::Identifier("x".to_string()));
+                match &value.node {
+                    Expr::Assign(LValue::Variable(y), _) => {
+                        assert_eq!(*y, Token::Identifier("y".to_string()))
+                    }
+                    _ => panic!("Expected nested assignment"),
+                }
+            }
+            _ => panic!("Expected assignment"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_assignment_target() {
+        // int main() { x + 1 = 2; }
+        let tokens = vec![
+            Token::Int,
+            Token::Identifier("main".to_string()),
+            Token::LeftParen,
+            Token::RightParen,
+            Token::LeftBrace,
+            Token::Identifier("x".to_string()),
+            Token::Plus,
+            Token::Number(1.0),
+            Token::Equal,
+            Token::Number(2.0),
+            Token::Semicolon,
+            Token::RightBrace,
+            Token::Eof,
+        ];
+
+        let errors: Vec<ParserError> = parse(tokens)
+            .unwrap_err()
+            .into_iter()
+            .map(|error| error.node)
+            .collect();
+        match errors.as_slice() {
+            [error @ ParserError::InvalidAssignmentTarget { target }] => {
+                assert!(matches!(target.node, Expr::Binary(_, BinOp::Add, _)));
+                assert_eq!(error.to_string(), "Invalid assignment target");
+            }
+            other => panic!("Expected invalid assignment target, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_spans() {
+        let source = "const int g = 1 + 2;\nint main() {\n    return -g;\n}";
+        let program = parse_with_spans(tokenize_with_spans(source)).unwrap();
+
+        let text = |span: Span| &source[span.start..span.end];
+
+        let global = &program.decl[0];
+        assert_eq!(text(global.span), "const int g = 1 + 2;");
+        assert_eq!(text(global.value.as_ref().unwrap().span), "1 + 2");
+
+        let main_fn = &program.fns[0];
+        assert_eq!(text(main_fn.span), "int main()");
+        assert_eq!(text(main_fn.body.span), "{\n    return -g;\n}");
+
+        let statement = &main_fn.body.statements[0];
+        assert_eq!(text(statement.span), "return -g;");
+        match &statement.node {
+            Statement::Return(Some(value)) => assert_eq!(text(value.span), "-g"),
+            _ => panic!("Expected return statement"),
+        }
+    }
+
+    #[test]
+    fn test_parse_error_at_lexer_error() {
+        // int x = @;
+        let tokens = vec![
+            Token::Int,
+            Token::Identifier("x".to_string()),
+            Token::Equal,
+            Token::Error("@".to_string()),
+            Token::Semicolon,
+            Token::Eof,
+        ];
+
+        let errors = parse(tokens).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].node.is_caused_by_lexer_error());
+    }
+
+    #[test]
+    fn test_recovers_from_statement_errors() {
+        let source = "int main() {
+    x = ;
+    int y = 1;
+    return );
+    if (y) { y = y + ; }
+    return y;
+}";
+        let errors = parse_errors(source);
+
+        // One error per broken statement, including the one nested in the `if` block
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        for error in &errors {
+            assert!(matches!(
+                error,
+                ParserError::UnexpectedToken {
+                    found: Token::Semicolon | Token::RightParen,
+                    ..
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn test_recovery_skips_the_blocks_of_a_broken_statement() {
+        let source = "int main() {
+    int w = 0;
+    while (w < 10) {
+        if (w 7 == 3) { w += 5; } else { w++; }
+    }
+    return w;
+}";
+        let errors = parse_errors(source);
+
+        // The `;` and `}` in the `if`'s blocks don't end the `while` early
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(matches!(
+            errors[0],
+            ParserError::UnexpectedToken {
+                found: Token::Number(_),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_missing_tokens() {
+        let source = "int main() {\n    int x = 1\n    if (x { x = 2; }\n    return x\n}";
+        let errors = parse_with_spans(tokenize_with_spans(source)).unwrap_err();
+
+        // Each is reported where it goes, and parsing carries on as if it were there
+        let missing: Vec<(Token, Span)> = errors
+            .into_iter()
+            .map(|error| match error.node {
+                ParserError::MissingToken { expected } => (expected, error.span),
+                other => panic!("Expected a missing token, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            missing,
+            [
+                (Token::Semicolon, Span::new(26, 26)),
+                (Token::RightParen, Span::new(36, 36)),
+                (Token::Semicolon, Span::new(60, 60)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recovers_from_declaration_errors() {
+        let source = "int = 1;
+@ garbage
+int f(int) { return 0; }
+int g = 2;
+int main() { return g; }";
+        let errors = parse_errors(source);
+
+        // Bad global, stray tokens, bad parameter list; `g` and `main` parse fine
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(matches!(
+            errors[1],
+            ParserError::UnexpectedToken {
+                found: Token::Error(_),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_unterminated_block() {
+        let source = "int main() {
+    return 0;";
+        let errors = parse_errors(source);
+        assert!(matches!(
+            errors.as_slice(),
+            [ParserError::UnexpectedEOF { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_string_declaration_and_print() {
+        let source = "string greeting = \"hi\";\nint main() { string s = greeting; print(\"bye\\n\"); return 0; }";
+        let program = parse_with_spans(tokenize_with_spans(source)).unwrap();
+
+        assert_eq!(program.decl[0].type_token, Token::String);
+        let statements = &program.fns[0].body.statements;
+        match &statements[0].node {
+            Statement::VarDecl(declaration) => assert_eq!(declaration.type_token, Token::String),
+            other => panic!("Expected string declaration, got {:?}", other),
+        }
+        match &statements[1].node {
+            Statement::PrintFormat(format, args) => {
+                assert_eq!(format, &[FormatPart::Text("bye\n".to_string())]);
+                assert!(args.is_empty());
+            }
+            other => panic!("Expected print statement, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let statement = first_statement("int f(int n) { for (int i = 0; i < n; i++) n -= 1; }");
+        match statement {
+            Statement::For(Some(init), Some(condition), Some(step), _, body) => {
+                assert!(matches!(init.node, Statement::VarDecl(_)));
+                assert!(matches!(condition.node, Expr::Binary(_, BinOp::Less, _)));
+                assert!(matches!(
+                    step.node,
+                    Statement::Postfix(LValue::Variable(_), PostfixOp::Increment)
+                ));
+                match &body.node {
+                    Statement::Expression(expr) => assert!(matches!(
+                        expr.node,
+                        Expr::CompoundAssign(LValue::Variable(_), BinOp::Sub, _)
+                    )),
+                    other => panic!("Expected expression statement, got {:?}", other),
+                }
+            }
+            other => panic!("Expected for loop, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_for_loop_empty_clauses() {
+        let statement = first_statement("int f(int n) { for (;;) { } }");
+        assert!(matches!(statement, Statement::For(None, None, None, _, _)));
+    }
+
+    #[test]
+    fn test_invalid_postfix_target() {
+        let source = "int f(int n) { (n + 1)++; }";
+        let errors = parse_errors(source);
+        assert!(matches!(
+            errors.as_slice(),
+            [ParserError::InvalidAssignmentTarget { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_static_declarations() {
+        let source = "static const int n = 1;\nint shared = 2;\nstatic int helper() { return n; }";
+        let program = parse_with_spans(tokenize_with_spans(source)).unwrap();
+        let text = |span: Span| &source[span.start..span.end];
+
+        assert!(program.decl[0].is_static);
+        assert!(program.decl[0].is_const);
+        assert_eq!(text(program.decl[0].span), "static const int n = 1;");
+        assert!(!program.decl[1].is_static);
+
+        assert!(program.fns[0].is_static);
+        assert_eq!(text(program.fns[0].span), "static int helper()");
+    }
+
+    #[test]
+    fn test_extern_declarations() {
+        let source = "extern int putchar(int c);\nextern void abort();\nint main() { return 0; }";
+        let program = parse_with_spans(tokenize_with_spans(source)).unwrap();
+        let text = |span: Span| &source[span.start..span.end];
+
+        assert_eq!(program.externs.len(), 2);
+        assert_eq!(program.fns.len(), 1);
+        let putchar = &program.externs[0];
+        assert_eq!(putchar.identifier, Token::Identifier("putchar".to_string()));
+        assert_eq!(putchar.return_type, Token::Int);
+        assert_eq!(putchar.params.len(), 1);
+        assert_eq!(text(putchar.span), "extern int putchar(int c);");
+        assert!(program.externs[1].params.is_empty());
+
+        // A declaration has no body, and nothing else can be `extern`
+        assert!(
+            parse_with_spans(tokenize_with_spans("extern int f(int c) { return c; }")).is_err()
+        );
+        assert!(parse_with_spans(tokenize_with_spans("extern int x;")).is_err());
+    }
+
+    #[test]
+    fn test_scan_statements() {
+        match first_statement("int f() { scan(x); }") {
+            Statement::Scan(LValue::Variable(target)) => {
+                assert_eq!(target, Token::Identifier("x".to_string()))
+            }
+            other => panic!("expected a scan statement, found {:?}", other),
+        }
+        // What's read goes in a variable
+        assert!(parse_with_spans(tokenize_with_spans("int f() { scan(x + 1); }")).is_err());
+        assert!(parse_with_spans(tokenize_with_spans("int f() { scan(); }")).is_err());
+    }
+
+    #[test]
+    fn test_asm_statements() {
+        match first_statement("int f() { asm(\"nop\"); }") {
+            Statement::Asm(template, None, inputs, constraints) => {
+                assert_eq!(template, "nop");
+                assert!(inputs.is_empty());
+                assert_eq!(constraints, AsmConstraints::default());
+            }
+            other => panic!("expected an asm statement, found {:?}", other),
+        }
+        let source =
+            "int f(int a) { asm(\"leal 1(%1,%2), %0\" : \"=r\"(a) : \"r\"(a), \"r\"(2)); }";
+        match first_statement(source) {
+            Statement::Asm(_, Some(LValue::Variable(output)), inputs, constraints) => {
+                assert_eq!(output, Token::Identifier("a".to_string()));
+                assert_eq!(inputs.len(), 2);
+                assert_eq!(constraints.operands, ['r', 'r', 'r']);
+            }
+            other => panic!("expected an asm statement, found {:?}", other),
+        }
+        // Registers named by the constraints, and the clobbers after the inputs
+        let source = "int f(int a) { asm(\"shll %%cl, %0\" : \"=a\"(a) : \"c\"(a), \"r\"(1) : \"rdx\", \"cc\"); }";
+        match first_statement(source) {
+            Statement::Asm(_, Some(_), _, constraints) => {
+                assert_eq!(constraints.operands, ['a', 'c', 'r']);
+                assert_eq!(constraints.clobbers, ["rdx", "cc"]);
+                assert_eq!(constraints.register(0), Some('a'));
+                assert_eq!(constraints.register(2), None);
+            }
+            other => panic!("expected an asm statement, found {:?}", other),
+        }
+        match first_statement("int f() { asm(\"cpuid\" : : : \"rax\", \"rbx\"); }") {
+            Statement::Asm(_, None, inputs, constraints) => {
+                assert!(inputs.is_empty());
+                assert_eq!(constraints.clobbers, ["rax", "rbx"]);
+            }
+            other => panic!("expected an asm statement, found {:?}", other),
+        }
+        // Inputs without an output
+        assert!(parse_with_spans(tokenize_with_spans(
+            "int f(int a) { asm(\"pushq %0; popq %%rax\" : : \"r\"(a)); }"
+        ))
+        .is_ok());
+
+        for source in [
+            // Only registers are supported
+            "int f(int a) { int x; asm(\"movl %1, %0\" : \"=m\"(x) : \"r\"(a)); }",
+            "int f(int a) { int x; asm(\"movl %1, %0\" : \"=r\"(x) : \"i\"(a)); }",
+            "int f(int a) { int x; asm(\"movl %1, %0\" : \"a\"(x) : \"r\"(a)); }",
+            "int f(int a) { int x; asm(\"movl %1, %0\" : \"=r\"(x) : \"=a\"(a)); }",
+            // Two inputs can't be in the same register
+            "int f(int a) { asm(\"addl %0, %1\" : : \"c\"(a), \"c\"(a)); }",
+            // There's no `%2`
+            "int f(int a) { int x; asm(\"movl %2, %0\" : \"=r\"(x) : \"r\"(a)); }",
+        ] {
+            assert!(
+                matches!(parse_errors(source)[..], [ParserError::InvalidAsm { .. }]),
+                "{}",
+                source
+            );
+        }
+        // The output has to be assignable
+        assert!(matches!(
+            parse_errors("int f(int a) { asm(\"movl %1, %0\" : \"=r\"(a + 1) : \"r\"(a)); }")[..],
+            [ParserError::InvalidAssignmentTarget { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_static_local_is_rejected() {
+        let source = "int main() { static int x = 1; return x; }";
+        assert!(parse_with_spans(tokenize_with_spans(source)).is_err());
+    }
+
+    #[test]
+    fn test_print_format() {
+        let statement =
+            first_statement("int f(int x) { print(\"%d%% of %s: %c\\n\", x, \"all\", x); }");
+        match statement {
+            Statement::PrintFormat(format, args) => {
+                assert_eq!(
+                    format,
+                    [
+                        FormatPart::Arg(FormatSpec::Int),
+                        FormatPart::Text("% of ".to_string()),
+                        FormatPart::Arg(FormatSpec::String),
+                        FormatPart::Text(": ".to_string()),
+                        FormatPart::Arg(FormatSpec::Char),
+                        FormatPart::Text("\n".to_string()),
+                    ]
+                );
+                assert_eq!(args.len(), 3);
+            }
+            other => panic!("Expected formatted print, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_print_value() {
+        let statement = first_statement("int f(int x) { print(x + 1); }");
+        assert!(matches!(statement, Statement::Print(_)));
+    }
+
+    #[test]
+    fn test_invalid_print_formats() {
+        let source = "int f(int x) {\n print(\"%d %d\", x);\n print(\"%q\", x);\n print(\"50%\");\n print(x, x);\n}";
+        let errors = parse_errors(source);
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+        assert!(errors
+            .iter()
+            .all(|error| matches!(error, ParserError::InvalidFormat { .. })));
+    }
+
+    #[test]
+    fn test_contracts() {
+        let source = "int f(int n)\n//@requires n >= 0;\n//@ensures n > 0;\n//@requires n < 10;\n{\n    while (n < 10)\n    //@loop_invariant n >= 0;\n    {\n        n++;\n    }\n    //@assert n == 10;\n    return n;\n}";
+        let program = parse_with_spans(tokenize_with_spans(source)).unwrap();
+
+        let function = &program.fns[0];
+        assert_eq!(function.requires.len(), 2);
+        assert_eq!(function.ensures.len(), 1);
+        assert!(matches!(
+            function.requires[1].node,
+            Expr::Binary(_, BinOp::Less, _)
+        ));
+
+        let statements = &function.body.statements;
+        match &statements[0].node {
+            Statement::While(_, invariants, _) => assert_eq!(invariants.len(), 1),
+            other => panic!("Expected while loop, got {:?}", other),
+        }
+        assert!(matches!(statements[1].node, Statement::Assert(_)));
+    }
+
+    #[test]
+    fn test_assert_statement() {
+        let statement = first_statement("int f(int x) { assert(x > 0); }");
+        match statement {
+            Statement::Check(condition) => {
+                assert!(matches!(condition.node, Expr::Binary(_, BinOp::Greater, _)))
+            }
+            other => panic!("Expected assert, got {:?}", other),
+        }
+        // Only inside an annotation is `assert` a contract
+        let statement = first_statement("int f(int x) {\n    //@assert x > 0;\n}");
+        assert!(matches!(statement, Statement::Assert(_)));
+    }
+
+    #[test]
+    fn test_misplaced_contract() {
+        let source = "int f(int n) {\n    //@requires n >= 0;\n    return n;\n}";
+        let errors = parse_errors(source);
+        assert!(matches!(
+            errors.as_slice(),
+            [ParserError::UnexpectedToken {
+                found: Token::Requires,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn test_result_and_old() {
+        let source =
+            "int inc(int x)\n//@ensures \\result == \\old(x) + 1;\n{\n    return x + 1;\n}";
+        let program = parse_with_spans(tokenize_with_spans(source)).unwrap();
+
+        match &program.fns[0].ensures[0].node {
+            Expr::Binary(left, BinOp::Equal, right) => {
+                assert!(matches!(left.node, Expr::Result));
+                match &right.node {
+                    Expr::Binary(old, BinOp::Add, _) => assert!(matches!(
+                        &old.node,
+                        Expr::Old(inner) if matches!(inner.node, Expr::Variable(_))
+                    )),
+                    other => panic!("Expected addition, got {:?}", other),
+                }
+            }
+            other => panic!("Expected comparison, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_result_outside_ensures() {
+        let source = "int f(int x)\n//@requires \\result > 0;\n{\n    //@assert \\old(x) > 0;\n    return x;\n}";
+        let errors = parse_errors(source);
+        assert!(matches!(
+            errors.as_slice(),
+            [
+                ParserError::OutsideEnsures {
+                    found: Token::Result
+                },
+                ParserError::OutsideEnsures { found: Token::Old },
+            ]
+        ));
+    }
 }
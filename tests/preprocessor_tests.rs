@@ -0,0 +1,284 @@
+use rust_compiler::preprocessor::{preprocess, IncludeOptions, PreprocessorErrorKind};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Creates a fresh directory holding the given `(name, contents)` files
+fn setup_dir(dirname: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = env::temp_dir().join(format!(
+        "rust-compiler-pp-{}-{}",
+        dirname,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    for (name, contents) in files {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_define() {
+        let source = "#define LIMIT 10\n#define TWICE LIMIT * 2\nint x = TWICE; // LIMIT\nprint(\"LIMIT\");\n//@assert x < LIMIT;\n";
+        let preprocessed =
+            preprocess(Path::new("main.c0"), source, &IncludeOptions::default()).unwrap();
+        assert_eq!(
+            preprocessed.source(),
+            "int x = 10 * 2; // LIMIT\nprint(\"LIMIT\");\n//@assert x < 10;\n"
+        );
+    }
+
+    #[test]
+    fn test_recursive_define() {
+        let source = "#define A B\n#define B A\nA B\n";
+        let preprocessed =
+            preprocess(Path::new("main.c0"), source, &IncludeOptions::default()).unwrap();
+        assert_eq!(preprocessed.source(), "A B\n");
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let source = "#define DEBUG\n#ifdef DEBUG\na\n#ifndef DEBUG\nb\n#else\nc\n#endif\n#else\nd\n#endif\n/*\n#endif */\n";
+        let preprocessed =
+            preprocess(Path::new("main.c0"), source, &IncludeOptions::default()).unwrap();
+        assert_eq!(preprocessed.source(), "a\nc\n/*\n#endif */\n");
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = setup_dir(
+            "include",
+            &[
+                (
+                    "lib/consts.h0",
+                    "#ifndef CONSTS\n#define CONSTS\nint limit = 1;\n#endif",
+                ),
+                (
+                    "main.c0",
+                    "#include \"lib/consts.h0\"\n#include \"lib/consts.h0\"\nint x;\n",
+                ),
+            ],
+        );
+        let main = dir.join("main.c0");
+        let source = fs::read_to_string(&main).unwrap();
+        let preprocessed = preprocess(&main, &source, &IncludeOptions::default()).unwrap();
+        assert_eq!(preprocessed.source(), "int limit = 1;\nint x;\n");
+
+        // Offsets map back to the file each line came from
+        let (file, offset) = preprocessed.origin(4);
+        assert_eq!(file.name(), dir.join("lib/consts.h0").display().to_string());
+        assert_eq!(file.line_col(offset), (3, 5));
+        let (file, offset) = preprocessed.origin(19);
+        assert_eq!(file.name(), main.display().to_string());
+        assert_eq!(file.line_col(offset), (3, 5));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_origin_after_expansion() {
+        let source = "#define VALUE 12345\nint x = VALUE + y;\n";
+        let preprocessed =
+            preprocess(Path::new("main.c0"), source, &IncludeOptions::default()).unwrap();
+        assert_eq!(preprocessed.source(), "int x = 12345 + y;\n");
+
+        // Inside the expansion, the use site; after it, the original position
+        let (file, offset) = preprocessed.origin(10);
+        assert_eq!(file.line_col(offset), (2, 9));
+        let (file, offset) = preprocessed.origin(16);
+        assert_eq!(file.line_col(offset), (2, 17));
+    }
+
+    #[test]
+    fn test_recursive_include() {
+        let dir = setup_dir("recursive", &[("main.c0", "#include \"main.c0\"\n")]);
+        let main = dir.join("main.c0");
+        let error = preprocess(&main, "#include \"main.c0\"\n", &IncludeOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(error.line, 1);
+        assert!(matches!(
+            error.kind,
+            PreprocessorErrorKind::RecursiveInclude { .. }
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_cycle_notes_first_include() {
+        let dir = setup_dir(
+            "cycle",
+            &[
+                ("main.c0", "#include \"a.h0\"\n"),
+                ("a.h0", "int a;\n#include \"./b.h0\"\n"),
+                ("b.h0", "#include \"a.h0\"\n"),
+            ],
+        );
+        let main = dir.join("main.c0");
+        let source = fs::read_to_string(&main).unwrap();
+        let error = preprocess(&main, &source, &IncludeOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(error.file, dir.join("./b.h0").display().to_string());
+        assert_eq!(error.line, 1);
+        assert!(matches!(
+            &error.kind,
+            PreprocessorErrorKind::RecursiveInclude { first_included: Some((file, 1)), .. }
+                if *file == main.display().to_string()
+        ));
+        assert!(error
+            .to_string()
+            .contains("main.c0:1: already imported here"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_diamond_include() {
+        // Both headers include the same file, by different paths; it's only included once
+        let dir = setup_dir(
+            "diamond",
+            &[
+                (
+                    "main.c0",
+                    "#include \"a.h0\"\n#include \"lib/b.h0\"\nint x;\n",
+                ),
+                ("a.h0", "#include \"lib/common.h0\"\n"),
+                ("lib/b.h0", "#include \"../lib/common.h0\"\n"),
+                ("lib/common.h0", "int common;\n"),
+            ],
+        );
+        let main = dir.join("main.c0");
+        let source = fs::read_to_string(&main).unwrap();
+        let preprocessed = preprocess(&main, &source, &IncludeOptions::default()).unwrap();
+        assert_eq!(preprocessed.source(), "int common;\nint x;\n");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_search_order() {
+        let dir = setup_dir(
+            "search",
+            &[
+                (
+                    "src/main.c0",
+                    "#include \"a.h0\"\n#include \"b.h0\"\n#include \"c.h0\"\n",
+                ),
+                ("src/a.h0", "int here;\n"),
+                ("first/a.h0", "int first_a;\n"),
+                ("first/b.h0", "int first_b;\n"),
+                ("second/b.h0", "int second_b;\n"),
+                ("second/c.h0", "int second_c;\n"),
+                ("system/c.h0", "int system_c;\n"),
+            ],
+        );
+        let main = dir.join("src/main.c0");
+        let source = fs::read_to_string(&main).unwrap();
+        let options = IncludeOptions {
+            search_dirs: vec![dir.join("first"), dir.join("second")],
+            system_dir: Some(dir.join("system")),
+            root: None,
+            allow_external: false,
+        };
+        let preprocessed = preprocess(&main, &source, &options).unwrap();
+        assert_eq!(
+            preprocessed.source(),
+            "int here;\nint first_b;\nint second_c;\n"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_sandbox() {
+        let dir = setup_dir(
+            "sandbox",
+            &[
+                ("project/main.c0", ""),
+                ("project/inside.h0", "int inside;\n"),
+                ("outside.h0", "int outside;\n"),
+                ("system/lib.h0", "int lib;\n"),
+            ],
+        );
+        let main = dir.join("project/main.c0");
+        let mut options = IncludeOptions {
+            search_dirs: Vec::new(),
+            system_dir: Some(dir.join("system")),
+            root: Some(dir.join("project")),
+            allow_external: false,
+        };
+        let included = |source: &str, options: &IncludeOptions| {
+            preprocess(&main, source, options).map(|preprocessed| preprocessed.source().to_string())
+        };
+
+        assert_eq!(
+            included("#include \"inside.h0\"\n#include \"lib.h0\"\n", &options).unwrap(),
+            "int inside;\nint lib;\n"
+        );
+        for escape in ["../outside.h0", "/etc/hostname"] {
+            let error = included(&format!("#include \"{}\"\n", escape), &options).unwrap_err();
+            assert!(
+                matches!(error.kind, PreprocessorErrorKind::ExternalInclude { .. }),
+                "{}",
+                escape
+            );
+        }
+        // A `-I` directory outside the project doesn't let its files in either
+        options.search_dirs.push(dir.clone());
+        assert!(matches!(
+            included("#include \"outside.h0\"\n", &options)
+                .unwrap_err()
+                .kind,
+            PreprocessorErrorKind::ExternalInclude { .. }
+        ));
+
+        options.allow_external = true;
+        assert_eq!(
+            included("#include \"outside.h0\"\n", &options).unwrap(),
+            "int outside;\n"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_directive_errors() {
+        let error = |source: &str| {
+            preprocess(Path::new("main.c0"), source, &IncludeOptions::default())
+                .err()
+                .unwrap()
+        };
+
+        assert!(matches!(
+            error("int x;\n#pragma once\n").kind,
+            PreprocessorErrorKind::UnknownDirective { directive } if directive == "pragma"
+        ));
+        assert!(matches!(
+            error("#define F(x) x\n").kind,
+            PreprocessorErrorKind::MalformedDirective { .. }
+        ));
+        assert!(matches!(
+            error("#include \"missing.h0\"\n").kind,
+            PreprocessorErrorKind::IncludeNotFound { .. }
+        ));
+        assert!(matches!(
+            error("#endif\n").kind,
+            PreprocessorErrorKind::UnmatchedConditional { .. }
+        ));
+
+        let unterminated = error("#ifdef A\n#endif\n#ifdef B\nint x;\n");
+        assert_eq!(unterminated.line, 3);
+        assert!(matches!(
+            unterminated.kind,
+            PreprocessorErrorKind::UnterminatedConditional
+        ));
+    }
+}
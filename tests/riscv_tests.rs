@@ -0,0 +1,592 @@
+mod common;
+
+use common::setup_workdir;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Where the strings and globals are laid out, in the order they're written
+const DATA: u32 = 0x1000;
+/// Bytes of memory, with the stack growing down from the end
+const MEMORY: u32 = 0x10_0000;
+/// Return address `main` is called with, which no instruction has
+const EXIT: u32 = u32::MAX;
+/// What the runtime leaves in the registers a call may overwrite, and the callee-saved ones
+/// start with
+const GARBAGE: u32 = 0xdead_beef;
+
+const REGISTERS: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+const A0: usize = 10;
+const SP: usize = 2;
+const RA: usize = 1;
+
+#[derive(Debug)]
+enum Arg {
+    Register(usize),
+    Immediate(i64),
+    /// Offset and base register
+    Memory(i32, usize),
+    Symbol(String),
+}
+
+#[derive(Debug)]
+struct Line {
+    mnemonic: String,
+    args: Vec<Arg>,
+}
+
+/// An RV32IM hart running the compiler's assembly a line at a time, with the runtime's
+/// functions done natively
+struct Machine {
+    registers: [u32; 32],
+    memory: Vec<u8>,
+    code: Vec<Line>,
+    /// Index of the line each code label is at
+    labels: HashMap<String, u32>,
+    /// Address of each data label
+    symbols: HashMap<String, u32>,
+    output: String,
+    aborted: bool,
+    /// Ints left for `scan` to read
+    input: Vec<i32>,
+}
+
+fn parse_arg(arg: &str) -> Arg {
+    let register = |name: &str| REGISTERS.iter().position(|register| *register == name);
+    if let Some(index) = register(arg) {
+        Arg::Register(index)
+    } else if let Ok(value) = arg.parse() {
+        Arg::Immediate(value)
+    } else if let Some((offset, base)) = arg.strip_suffix(')').and_then(|arg| arg.split_once('(')) {
+        Arg::Memory(offset.parse().unwrap(), register(base).unwrap())
+    } else {
+        Arg::Symbol(arg.to_string())
+    }
+}
+
+/// The bytes of a `.string` directive's operand, with the zero ending it
+fn unescape(quoted: &str) -> Vec<u8> {
+    let inner = &quoted[1..quoted.len() - 1];
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.push(c as u8);
+            continue;
+        }
+        match chars.next().unwrap() {
+            digit @ '0'..='7' => {
+                let octal: String = [digit, chars.next().unwrap(), chars.next().unwrap()]
+                    .iter()
+                    .collect();
+                bytes.push(u8::from_str_radix(&octal, 8).unwrap());
+            }
+            other => bytes.push(other as u8),
+        }
+    }
+    bytes.push(0);
+    bytes
+}
+
+impl Machine {
+    fn load(assembly: &str) -> Self {
+        let mut machine = Machine {
+            registers: [0; 32],
+            memory: vec![0; MEMORY as usize],
+            code: Vec::new(),
+            labels: HashMap::new(),
+            symbols: HashMap::new(),
+            output: String::new(),
+            aborted: false,
+            input: Vec::new(),
+        };
+        let mut in_text = true;
+        let mut address = DATA;
+        let mut words = Vec::new();
+        for line in assembly.lines() {
+            let line = line.trim();
+            if let Some(label) = line.strip_suffix(':') {
+                match in_text {
+                    true => machine
+                        .labels
+                        .insert(label.to_string(), machine.code.len() as u32),
+                    false => machine.symbols.insert(label.to_string(), address),
+                };
+                continue;
+            }
+            let (mnemonic, rest) = line.split_once(' ').unwrap_or((line, ""));
+            match mnemonic {
+                ".text" => in_text = true,
+                ".data" | ".section" => in_text = false,
+                ".string" => {
+                    for byte in unescape(rest) {
+                        machine.memory[address as usize] = byte;
+                        address += 1;
+                    }
+                }
+                ".word" => {
+                    words.push((address, rest.to_string()));
+                    address += 4;
+                }
+                ".p2align" => address = (address + 3) & !3,
+                ".globl" | ".type" | ".size" => {}
+                _ => machine.code.push(Line {
+                    mnemonic: mnemonic.to_string(),
+                    args: rest
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|arg| !arg.is_empty())
+                        .map(parse_arg)
+                        .collect(),
+                }),
+            }
+        }
+        for (address, word) in words {
+            let value = word
+                .parse::<i32>()
+                .map(|value| value as u32)
+                .unwrap_or_else(|_| machine.symbols[&word]);
+            machine.store(address, value);
+        }
+        machine
+    }
+
+    fn read(&self, address: u32) -> u32 {
+        let address = address as usize;
+        u32::from_le_bytes(self.memory[address..address + 4].try_into().unwrap())
+    }
+
+    fn store(&mut self, address: u32, value: u32) {
+        let address = address as usize;
+        self.memory[address..address + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&self, mut address: u32) -> String {
+        let mut string = String::new();
+        while self.memory[address as usize] != 0 {
+            string.push(self.memory[address as usize] as char);
+            address += 1;
+        }
+        string
+    }
+
+    /// Calls `main` and runs until it returns or the program aborts. Every callee-saved
+    /// register has to be as it was by then.
+    fn run(&mut self) {
+        for (index, name) in REGISTERS.iter().enumerate() {
+            if name.starts_with('s') {
+                self.registers[index] = GARBAGE.wrapping_add(index as u32);
+            }
+        }
+        self.registers[SP] = MEMORY;
+        self.registers[RA] = EXIT;
+        let saved = self.registers;
+        let mut pc = self.labels["main"];
+        let mut steps = 0;
+        while pc != EXIT && !self.aborted {
+            steps += 1;
+            assert!(steps < 10_000_000, "the program doesn't end");
+            pc = self.step(pc);
+        }
+        if !self.aborted {
+            for (index, name) in REGISTERS.iter().enumerate() {
+                if name.starts_with('s') {
+                    assert_eq!(self.registers[index], saved[index], "{} changed", name);
+                }
+            }
+        }
+    }
+
+    fn result(&self) -> i32 {
+        self.registers[A0] as i32
+    }
+
+    fn set(&mut self, register: usize, value: u32) {
+        if register != 0 {
+            self.registers[register] = value;
+        }
+    }
+
+    /// Does the runtime function `symbol`, then overwrites the registers a call may, but for
+    /// the one it returns in
+    fn runtime(&mut self, symbol: &str) {
+        let a0 = self.registers[A0];
+        let mut returned = None;
+        match symbol {
+            "c0_print_int" => self.output += &(a0 as i32).to_string(),
+            "c0_print_char" => self.output.push(a0 as u8 as char),
+            "c0_print_string" => self.output += &self.string(a0),
+            "c0_scan_int" if self.input.is_empty() => self.aborted = true,
+            "c0_scan_int" => returned = Some(self.input.remove(0) as u32),
+            "c0_abort" => self.aborted = true,
+            _ => panic!("call to undefined {}", symbol),
+        }
+        for (index, name) in REGISTERS.iter().enumerate() {
+            if name.starts_with('t') || name.starts_with('a') {
+                self.registers[index] = GARBAGE;
+            }
+        }
+        if let Some(value) = returned {
+            self.registers[A0] = value;
+        }
+    }
+
+    /// Runs the line at `pc`, and returns the next one's
+    fn step(&mut self, pc: u32) -> u32 {
+        let line = &self.code[pc as usize];
+        let register = |index: usize| match line.args[index] {
+            Arg::Register(register) => register,
+            _ => panic!("{:?} takes a register", line),
+        };
+        let value = |index: usize| match line.args[index] {
+            Arg::Register(register) => self.registers[register],
+            Arg::Immediate(value) => value as u32,
+            _ => panic!("{:?} takes a value", line),
+        };
+        let symbol = |index: usize| match &line.args[index] {
+            Arg::Symbol(symbol) => symbol.clone(),
+            _ => panic!("{:?} takes a symbol", line),
+        };
+        let address = |index: usize| match line.args[index] {
+            Arg::Memory(offset, base) => self.registers[base].wrapping_add(offset as u32),
+            _ => panic!("{:?} takes an address", line),
+        };
+        let mnemonic = line.mnemonic.as_str();
+        let branch = |taken: bool, target: String| match taken {
+            true => self.labels[&target],
+            false => pc + 1,
+        };
+        let signed = |index: usize| value(index) as i32;
+        let dest = match mnemonic {
+            "add" | "addi" => Some(value(1).wrapping_add(value(2))),
+            "sub" => Some(value(1).wrapping_sub(value(2))),
+            "mul" => Some(value(1).wrapping_mul(value(2))),
+            "div" => Some(match (signed(1), signed(2)) {
+                (_, 0) => u32::MAX,
+                (left, right) => left.wrapping_div(right) as u32,
+            }),
+            "andi" => Some(value(1) & value(2)),
+            "xor" | "xori" => Some(value(1) ^ value(2)),
+            "slli" => Some(value(1) << value(2)),
+            "srli" => Some(value(1) >> value(2)),
+            "srai" => Some((signed(1) >> value(2)) as u32),
+            "slt" => Some((signed(1) < signed(2)) as u32),
+            "li" | "mv" => Some(value(1)),
+            "lla" => Some(self.symbols[&symbol(1)]),
+            "lw" => Some(self.read(address(1))),
+            "neg" => Some(value(1).wrapping_neg()),
+            "not" => Some(!value(1)),
+            "seqz" => Some((value(1) == 0) as u32),
+            "snez" => Some((value(1) != 0) as u32),
+            "sltz" => Some((signed(1) < 0) as u32),
+            "sgtz" => Some((signed(1) > 0) as u32),
+            _ => None,
+        };
+        if let Some(result) = dest {
+            self.set(register(0), result);
+            return pc + 1;
+        }
+        match mnemonic {
+            "sw" => {
+                let (value, address) = (value(0), address(1));
+                self.store(address, value);
+                pc + 1
+            }
+            "beqz" => branch(signed(0) == 0, symbol(1)),
+            "bnez" => branch(signed(0) != 0, symbol(1)),
+            "bltz" => branch(signed(0) < 0, symbol(1)),
+            "bgez" => branch(signed(0) >= 0, symbol(1)),
+            "bgtz" => branch(signed(0) > 0, symbol(1)),
+            "blez" => branch(signed(0) <= 0, symbol(1)),
+            "bne" => branch(value(0) != value(1), symbol(2)),
+            "j" => branch(true, symbol(0)),
+            "call" => {
+                let callee = symbol(0);
+                match self.labels.get(&callee) {
+                    Some(&target) => {
+                        self.set(RA, pc + 1);
+                        target
+                    }
+                    None => {
+                        self.runtime(&callee);
+                        pc + 1
+                    }
+                }
+            }
+            "ret" => self.registers[RA],
+            _ => panic!("unknown instruction {:?}", line),
+        }
+    }
+}
+
+/// Compiles `name` for RISC-V with `flags`, and returns the assembly
+fn compile(workdir: &Path, name: &str, flags: &[&str]) -> String {
+    let status = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+        .arg("--target=riscv32")
+        .args(flags)
+        .arg(name)
+        .current_dir(workdir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let path = workdir
+        .join("samples")
+        .join("target")
+        .join(format!("{}.S", name));
+    fs::read_to_string(path).unwrap()
+}
+
+/// Compiles `source` and runs it
+fn run(dirname: &str, source: &str, flags: &[&str]) -> (Machine, String) {
+    let workdir = setup_workdir(dirname, "sample", source);
+    let assembly = compile(&workdir, "sample", flags);
+    fs::remove_dir_all(workdir).unwrap();
+    let mut machine = Machine::load(&assembly);
+    machine.run();
+    (machine, assembly)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = r#"
+int fib(int n) {
+    if (n < 2) return n;
+    return fib(n - 1) + fib(n - 2);
+}
+
+int sum(int from, int to, int step) {
+    int total = 0;
+    for (int i = from; i != to; i += step) {
+        total += i;
+    }
+    return total;
+}
+
+int main() {
+    int big = 100000;
+    string greeting = "hello";
+    print("%s %d\n", greeting, fib(10));
+    if (sum(5, -5, -1) != 5) return -1;
+    if (-3 > 2) return -2;
+    if (big <= 65536) return -3;
+    if (!(big >= 100000)) return -4;
+    return fib(12) + big - sum(0, 10, 1);
+}
+"#;
+
+    #[test]
+    fn test_programs_run() {
+        for flags in [&[][..], &["-O2"], &["--ssa", "-O1"]] {
+            let (machine, assembly) = run("riscv-program", PROGRAM, flags);
+            assert!(!machine.aborted, "{}", assembly);
+            assert_eq!(machine.output, "hello 55\n", "{}", assembly);
+            assert_eq!(machine.result(), 144 + 100000 - 45, "{}", assembly);
+        }
+    }
+
+    #[test]
+    fn test_arguments_past_eight_go_on_the_stack() {
+        let source = r#"
+int weigh(int a, int b, int c, int d, int e, int f, int g, int h, int i, int j) {
+    return a + 2 * b + 3 * c + 4 * d + 5 * e + 6 * f + 7 * g + 8 * h + 9 * i + 10 * j;
+}
+
+int main() {
+    int x = 3;
+    int y = weigh(1, 1, 1, 1, 1, 1, 1, 1, 1, 1);
+    return weigh(x, y, 0, 0, 0, 0, 0, 0, x + y, y - x) + y;
+}
+"#;
+        let (machine, assembly) = run("riscv-arguments", source, &[]);
+        // The ninth and tenth arguments are passed at the bottom of the caller's frame, and
+        // found at the top of the callee's
+        assert!(assembly.contains("sw t0, 4(sp)"), "{}", assembly);
+        assert!(assembly.contains("4(s0)"), "{}", assembly);
+        assert_eq!(machine.result(), 3 + 2 * 55 + 9 * 58 + 10 * 52 + 55);
+    }
+
+    #[test]
+    fn test_temps_spill_to_the_stack() {
+        // Twenty values live across a call don't fit the eleven callee-saved registers
+        let mut source = String::from("int one() { return 1; }\nint main() {\n");
+        for index in 0..20 {
+            source += &format!("    int v{0} = one() + {0};\n", index);
+        }
+        source += "    int total = one();\n";
+        for index in 0..20 {
+            source += &format!("    total = total * 3 + v{};\n", index);
+        }
+        source += "    return total;\n}\n";
+        let (machine, assembly) = run("riscv-spills", &source, &[]);
+        assert!(assembly.contains("sw s11, -52(s0)"), "{}", assembly);
+        assert!(assembly.contains("sw t0, -56(s0)"), "{}", assembly);
+        assert!(assembly.contains("lw t1, -56(s0)"), "{}", assembly);
+        let expected = (0..20).fold(1i32, |total, index| {
+            total.wrapping_mul(3).wrapping_add(index + 1)
+        });
+        assert_eq!(machine.result(), expected);
+    }
+
+    const DIVISION: &str = r#"
+int div(int a, int b) {
+    return a / b;
+}
+
+int main() {
+    print("%d %d %d %d\n", div(100, 7), div(-100, 7), div(100, -7), div(-100, -7));
+    print("%d\n", div(-2147483647 - 1, 1));
+    print("%d\n", div(DIVIDEND, DIVISOR));
+    return 1;
+}
+"#;
+
+    #[test]
+    fn test_division_by_zero_and_overflow_abort() {
+        let cases = [
+            ("5", "2", false, "2\n"),
+            ("1", "0", true, ""),
+            ("-2147483647 - 1", "-1", true, ""),
+        ];
+        for (dividend, divisor, aborts, last) in cases {
+            let source = DIVISION
+                .replace("DIVIDEND", dividend)
+                .replace("DIVISOR", divisor);
+            let (machine, assembly) = run("riscv-division", &source, &[]);
+            assert_eq!(machine.aborted, aborts, "{}", assembly);
+            let expected = format!("14 -14 -14 14\n-2147483648\n{}", last);
+            assert_eq!(machine.output, expected, "{}", assembly);
+        }
+
+        // Dividing by a constant needs no checks
+        let source = "int main() { int a = 100; return a / 7; }";
+        let (machine, assembly) = run("riscv-constant-division", source, &[]);
+        assert_eq!(machine.result(), 14);
+        assert!(!assembly.contains("c0_abort"), "{}", assembly);
+    }
+
+    #[test]
+    fn test_asm_operands_are_registers() {
+        let source = r#"
+int main() {
+    int a = 6;
+    int b = 7;
+    int sum;
+    asm("add %0, %1, %2" : "=r"(sum) : "r"(a), "r"(b));
+    return sum;
+}
+"#;
+        let (machine, assembly) = run("riscv-asm", source, &[]);
+        assert!(assembly.contains("\tadd t"), "{}", assembly);
+        assert_eq!(machine.result(), 13);
+    }
+
+    #[test]
+    fn test_strings_and_mangling() {
+        let source = r#"
+int read(string s) {
+    print("%s", s);
+    return 2;
+}
+
+int main() {
+    return read("mangled\n");
+}
+"#;
+        let (machine, assembly) = run("riscv-mangle", source, &["--mangle"]);
+        assert!(assembly.contains("\tcall _c0_read\n"), "{}", assembly);
+        assert!(assembly.contains("\t.globl main\n"), "{}", assembly);
+        assert!(
+            assembly.contains("\t.string \"mangled\\012\"\n"),
+            "{}",
+            assembly
+        );
+        assert_eq!(machine.output, "mangled\n");
+        assert_eq!(machine.result(), 2);
+    }
+
+    #[test]
+    fn test_functions_read_and_write_globals() {
+        let source = r#"
+int count = 1;
+static string name = "before";
+
+void bump(int by) {
+    count = count + by;
+}
+
+int main() {
+    bump(2);
+    bump(count);
+    print("%s ", name);
+    name = "after";
+    print("%s\n", name);
+    return count;
+}
+"#;
+        for flags in [&["--mangle"][..], &["-O2"]] {
+            let (machine, assembly) = run("riscv-globals", source, flags);
+            assert_eq!(machine.output, "before after\n", "{}", assembly);
+            assert_eq!(machine.result(), 6, "{}", assembly);
+        }
+        let (_, assembly) = run("riscv-globals", source, &["--mangle"]);
+        assert!(assembly.contains("\tlla t2, _c0_count\n"), "{}", assembly);
+    }
+
+    #[test]
+    fn test_scan_reads_ints_until_the_input_ends() {
+        let source = r#"
+int total;
+
+int main() {
+    int count;
+    scan(count);
+    for (int i = 0; i < count; i++) {
+        int value;
+        scan(value);
+        scan(total);
+        total += value;
+        print("%d ", total);
+    }
+    return count;
+}
+"#;
+        let workdir = setup_workdir("riscv-scan", "sample", source);
+        let assembly = compile(&workdir, "sample", &["-O2"]);
+        fs::remove_dir_all(workdir).unwrap();
+        for (input, output, aborted) in [
+            (vec![2, 1, 10, -5, 20], "11 15 ", false),
+            (vec![3, 1, 10], "11 ", true),
+        ] {
+            let mut machine = Machine::load(&assembly);
+            machine.input = input;
+            machine.run();
+            assert_eq!(machine.output, output, "{}", assembly);
+            assert_eq!(machine.aborted, aborted, "{}", assembly);
+        }
+    }
+
+    #[test]
+    fn test_doubles_are_rejected() {
+        let workdir = setup_workdir(
+            "riscv-doubles",
+            "sample",
+            "int main() { double d = 1.5; return 0; }",
+        );
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-compiler"))
+            .args(["--target=riscv32", "sample"])
+            .current_dir(&workdir)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("doubles"), "{}", stderr);
+        fs::remove_dir_all(workdir).unwrap();
+    }
+}
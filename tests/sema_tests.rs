@@ -0,0 +1,613 @@
+use rust_compiler::desugar::desugar;
+use rust_compiler::lexer::{tokenize_with_spans, Token};
+use rust_compiler::parser::parse_with_spans;
+use rust_compiler::sema::{check, SemaError, SemaErrorKind, SemaWarning, SemaWarningKind, Type};
+use rust_compiler::source_map::Span;
+
+fn check_source(source: &str) -> Result<Vec<SemaWarning>, Vec<SemaError>> {
+    check(&desugar(
+        parse_with_spans(tokenize_with_spans(source)).unwrap(),
+    ))
+}
+
+/// Kinds of the errors reported for `source`
+fn error_kinds(source: &str) -> Vec<SemaErrorKind> {
+    check_source(source)
+        .unwrap_err()
+        .into_iter()
+        .map(|error| error.kind)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_program() {
+        let source = r#"
+const int LIMIT = 10;
+string greeting = "hi";
+
+int square(int x)
+//@requires x < LIMIT;
+//@ensures \result >= 0;
+{
+    return x * x;
+}
+
+void greet() {
+    print("%s %d\n", greeting, square(3));
+}
+
+int main() {
+    double total = 0;
+    for (int i = 0; i < LIMIT; i++) {
+        total = total + 1.5;
+        if (i == 5) {
+            continue;
+        }
+    }
+    greet();
+    return (int) total;
+}
+"#;
+        assert_eq!(check_source(source), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_undefined_names() {
+        let kinds = error_kinds("int main() { x = 1; return missing(2); }");
+        assert_eq!(
+            kinds,
+            [
+                SemaErrorKind::UndefinedVariable {
+                    name: "x".to_string()
+                },
+                SemaErrorKind::UndefinedFunction {
+                    name: "missing".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scopes() {
+        // A block's variables end with it, and locals can't shadow each other
+        let source = "int main() {\n{ int x = 1; }\nint y = x;\nint y = 2;\nreturn 0;\n}";
+        let errors = check_source(source).unwrap_err();
+        assert_eq!(
+            errors,
+            [
+                SemaError {
+                    function: Some("main".to_string()),
+                    kind: SemaErrorKind::UndefinedVariable {
+                        name: "x".to_string()
+                    },
+                    span: Span::new(36, 37),
+                },
+                SemaError {
+                    function: Some("main".to_string()),
+                    kind: SemaErrorKind::Redeclaration {
+                        name: "y".to_string(),
+                        previous: Span::new(28, 38),
+                    },
+                    span: Span::new(39, 49),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_type_mismatches() {
+        let source = "int main() {\nint x = \"one\";\ndouble d = 2;\nint y = d + 1;\nstring s = \"a\";\nif (s) { return 1; }\nreturn x;\n}";
+        let kinds = error_kinds(source);
+        assert_eq!(
+            kinds,
+            [
+                SemaErrorKind::TypeMismatch {
+                    expected: Type::Int,
+                    found: Type::String
+                },
+                // A whole number literal can stand for a double, but the sum is still a double
+                SemaErrorKind::TypeMismatch {
+                    expected: Type::Int,
+                    found: Type::Double
+                },
+                SemaErrorKind::TypeMismatch {
+                    expected: Type::Int,
+                    found: Type::String
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_calls() {
+        let source = "void log(int x) { print(x); }\nint main() { log(1, 2); int y = log(1); log(\"a\"); return 0; }";
+        let kinds = error_kinds(source);
+        assert_eq!(
+            kinds,
+            [
+                SemaErrorKind::ArgumentCount {
+                    name: "log".to_string(),
+                    expected: 1,
+                    found: 2
+                },
+                SemaErrorKind::TypeMismatch {
+                    expected: Type::Int,
+                    found: Type::Void
+                },
+                SemaErrorKind::TypeMismatch {
+                    expected: Type::Int,
+                    found: Type::String
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_returns() {
+        let source =
+            "void f() { return 1; }\nint g() { return; }\nvoid h()\n//@ensures \\result > 0;\n{ }";
+        let kinds = error_kinds(source);
+        assert_eq!(
+            kinds,
+            [
+                SemaErrorKind::UnexpectedReturnValue,
+                SemaErrorKind::MissingReturnValue,
+                SemaErrorKind::ResultInVoidFunction,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_declarations() {
+        let source = "const int LIMIT = 1;\nconst int NONE;\nint copy = main();\nvoid nothing;\nstruct thing;\nint main() { LIMIT = 2; break; return 0; }";
+        let kinds = error_kinds(source);
+        assert_eq!(
+            kinds,
+            [
+                SemaErrorKind::ConstWithoutValue {
+                    name: "NONE".to_string()
+                },
+                SemaErrorKind::CallInGlobalInitializer {
+                    name: "main".to_string()
+                },
+                SemaErrorKind::VoidVariable {
+                    name: "nothing".to_string()
+                },
+                SemaErrorKind::UnsupportedType {
+                    found: Token::Struct
+                },
+                SemaErrorKind::AssignToConst {
+                    name: "LIMIT".to_string()
+                },
+                SemaErrorKind::OutsideLoop {
+                    statement: "break".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_spans() {
+        let source = "int main() {\n    int x = 1;\n    return x + y;\n}";
+        let errors = check_source(source).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(&source[errors[0].span.start..errors[0].span.end], "y");
+    }
+
+    #[test]
+    fn test_constants() {
+        let source = "const int LIMIT = 4;\nconst double HALF = (double) LIMIT / 8;\nint start = -LIMIT;\nint main() {\nconst int TWICE = LIMIT * 2;\nint x = TWICE;\nconst int COPY = x;\nconst int NONE;\nTWICE += 1;\nx++;\nreturn 0;\n}";
+        let kinds = error_kinds(source);
+        assert_eq!(
+            kinds,
+            [
+                SemaErrorKind::NonConstantInitializer {
+                    name: "COPY".to_string()
+                },
+                SemaErrorKind::ConstWithoutValue {
+                    name: "NONE".to_string()
+                },
+                SemaErrorKind::AssignToConst {
+                    name: "TWICE".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sugar_is_checked_as_desugared() {
+        // `s += "b"` is checked as `s = s + "b"`
+        let source = "void f() {\nstring s = \"a\";\ns += \"b\";\n}";
+        let errors = check_source(source).unwrap_err();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(
+            errors[0].kind,
+            SemaErrorKind::InvalidOperand {
+                operator: "+".to_string(),
+                found: Type::String,
+            }
+        );
+        assert_eq!(
+            &source[errors[0].span.start..errors[0].span.end],
+            "s += \"b\""
+        );
+    }
+
+    #[test]
+    fn test_constant_division_by_zero() {
+        let kinds = error_kinds("const int ZERO = 0;\nconst int BAD = 1 / ZERO;");
+        assert_eq!(
+            kinds,
+            [SemaErrorKind::NonConstantInitializer {
+                name: "BAD".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_global_initialization_order() {
+        // Functions may use anything, but an initializer only sees the constants before it
+        let source = "const int A = B + 1;\nconst int B = 2;\nint c = f();\nint f() { return g() + B; }\nint g() { return A; }";
+        let errors = check_source(source).unwrap_err();
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert_eq!(
+            errors[0].kind,
+            SemaErrorKind::UsedBeforeDefinition {
+                name: "B".to_string(),
+                definition: Span::new(21, 37)
+            }
+        );
+        assert_eq!(errors[0].note(), Some((Span::new(21, 37), "defined here")));
+        assert_eq!(
+            errors[1].kind,
+            SemaErrorKind::CallInGlobalInitializer {
+                name: "f".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_cyclic_initializers() {
+        let source = "int main() { return 0; }\nconst int A = C * 2;\nconst int B = 1;\nconst int C = A - B;\nconst int D = D;";
+        let errors = check_source(source).unwrap_err();
+        let kinds: Vec<SemaErrorKind> = errors.iter().map(|error| error.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            [
+                SemaErrorKind::CyclicInitializer {
+                    cycle: vec!["A".to_string(), "C".to_string(), "A".to_string()]
+                },
+                SemaErrorKind::CyclicInitializer {
+                    cycle: vec!["D".to_string(), "D".to_string()]
+                },
+            ]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "The initializer of 'A' depends on itself: A -> C -> A"
+        );
+    }
+
+    #[test]
+    fn test_int_to_double_promotion() {
+        // An int widens to a double implicitly, but narrowing needs a cast
+        let source = "double half(int n) { return n / 2.0; }\nint main() {\nint n = 3;\ndouble x = n;\nif (x > n) { x = n * x; }\nint y = (int) half(n);\nint z = x;\nreturn y;\n}";
+        let kinds = error_kinds(source);
+        assert_eq!(
+            kinds,
+            [SemaErrorKind::TypeMismatch {
+                expected: Type::Int,
+                found: Type::Double
+            }]
+        );
+    }
+
+    #[test]
+    fn test_whole_double_literals_are_doubles() {
+        let source = "int main() {\nint x = 1.0;\nreturn x;\n}";
+        assert_eq!(
+            error_kinds(source),
+            [SemaErrorKind::TypeMismatch {
+                expected: Type::Int,
+                found: Type::Double
+            }]
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_int_literals() {
+        let source = "int x = 99999999999999999999;\nint main() {\nreturn 2147483648;\n}";
+        let errors = check_source(source).unwrap_err();
+        let kinds: Vec<_> = errors.iter().map(|error| error.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            [
+                SemaErrorKind::IntegerOutOfRange,
+                SemaErrorKind::IntegerOutOfRange
+            ]
+        );
+        assert_eq!(errors[1].span, Span::new(50, 60));
+        assert_eq!(errors[1].code(), "E0122");
+        // Negated, the literal past the largest int is the least one, but only then
+        let source = "int main() {\nint least = -2147483648;\nint most = 2147483647;\nreturn least + most;\n}";
+        assert!(check_source(source).is_ok());
+        let source = "int main() {\nreturn -(2147483648);\n}";
+        assert_eq!(error_kinds(source), [SemaErrorKind::IntegerOutOfRange]);
+    }
+
+    #[test]
+    fn test_format_arguments() {
+        // `%f` takes an int, but `%d` doesn't take a double, however whole
+        let source = "int main() {\nprint(\"%f %d\\n\", 1, 1.0);\nreturn 0;\n}";
+        assert_eq!(
+            error_kinds(source),
+            [SemaErrorKind::TypeMismatch {
+                expected: Type::Int,
+                found: Type::Double
+            }]
+        );
+    }
+
+    #[test]
+    fn test_unassigned_reads() {
+        let unassigned = |name: &str| SemaErrorKind::UnassignedVariable {
+            name: name.to_string(),
+        };
+        assert_eq!(
+            error_kinds("int main() {\nint x;\nreturn x;\n}"),
+            [unassigned("x")]
+        );
+        // Assigned on only one path, or read by its own compound assignment
+        let source =
+            "int f(int n) {\nint x;\nif (n > 0) { x = n; }\nint y;\ny += 1;\nreturn x + y;\n}";
+        assert_eq!(error_kinds(source), [unassigned("y"), unassigned("x")]);
+        // The loop body might not run, but nothing follows a `while (1)` without a `break`
+        let source = "int f(int n) {\nint x;\nwhile (n > 0) { x = n; n--; }\nreturn x;\n}";
+        assert_eq!(error_kinds(source), [unassigned("x")]);
+        let source = "int f(int n) {\nint x;\nfor (int i = 0; i < n; i++) { if (i > 2) { break; } x = i; }\nreturn x;\n}";
+        assert_eq!(error_kinds(source), [unassigned("x")]);
+    }
+
+    #[test]
+    fn test_assigned_on_every_path() {
+        let source = r#"
+int g;
+
+int f(int n) {
+    int x;
+    if (n > 0) { x = n; } else { x = -n; }
+    int y;
+    while (1) {
+        if (n > 3) { y = 1; break; }
+        n++;
+    }
+    int z;
+    if (n > 0) { z = 1; } else { return 0; }
+    int w;
+    asm("movl $1, %0" : "=r"(w));
+    { int g; g = 2; x += g; }
+    return x + y + z + w + g;
+}
+
+int main() {
+    return f(2);
+}
+"#;
+        assert!(check_source(source).is_ok());
+    }
+
+    #[test]
+    fn test_missing_return() {
+        assert_eq!(
+            error_kinds("int main() {\nint x = 1;\n}"),
+            [SemaErrorKind::MissingReturn]
+        );
+        let source = "int f(int a) {\n    if (a > 0) return 1;\n}\nint main() { return f(1); }";
+        let errors = check_source(source).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].function.as_deref(), Some("f"));
+        // Reported at the closing brace
+        assert_eq!(errors[0].span, Span::new(40, 41));
+        assert_eq!(&source[40..41], "}");
+        // A break out of an endless loop reaches the end too
+        assert_eq!(
+            error_kinds("int f() {\nwhile (1) { break; }\n}"),
+            [SemaErrorKind::MissingReturn]
+        );
+    }
+
+    #[test]
+    fn test_every_path_returns() {
+        let source = r#"
+int sign(int n) {
+    if (n > 0) { return 1; } else if (n < 0) { return -1; } else { return 0; }
+}
+
+int forever(int n) {
+    while (1) {
+        if (n > 10) return n;
+        n++;
+    }
+}
+
+void nothing() {
+}
+
+int main() {
+    nothing();
+    return sign(2) + forever(3);
+}
+"#;
+        assert!(check_source(source).is_ok());
+    }
+
+    #[test]
+    fn test_redefinitions() {
+        let source =
+            "int f(int a, int a) { return a; }\nint g = 1;\nint f() { return 0; }\ndouble g;";
+        let errors = check_source(source).unwrap_err();
+        let redefinitions: Vec<(&str, &str)> = errors
+            .iter()
+            .map(|error| {
+                let (previous, _) = error.note().unwrap();
+                (
+                    &source[error.span.start..error.span.end],
+                    &source[previous.start..previous.end],
+                )
+            })
+            .collect();
+        assert_eq!(
+            redefinitions,
+            [
+                ("int f()", "int f(int a, int a)"),
+                ("double g;", "int g = 1;"),
+                ("int a", "int a"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extern_functions() {
+        // Declarations may repeat, and a definition may follow one
+        let source = "extern int putchar(int c);\nextern int putchar(int c);\nextern int twice(int x);\nint twice(int x) { return 2 * x; }\nint main() {\n    putchar(twice(33));\n    return 0;\n}\n";
+        assert_eq!(check_source(source), Ok(Vec::new()));
+        assert_eq!(
+            error_kinds("int main() { return putchar(72); }"),
+            [SemaErrorKind::UndefinedFunction {
+                name: "putchar".to_string()
+            }]
+        );
+
+        // Calls are checked against the declaration
+        assert_eq!(
+            error_kinds("extern int putchar(int c);\nint main() { return putchar(); }"),
+            [SemaErrorKind::ArgumentCount {
+                name: "putchar".to_string(),
+                expected: 1,
+                found: 0,
+            }]
+        );
+        // Declarations disagreeing with each other or the definition are redeclarations
+        let source = "int twice(int x) { return 2 * x; }\nextern double twice(int x);";
+        let errors = check_source(source).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        let (previous, _) = errors[0].note().unwrap();
+        assert_eq!(&source[previous.start..previous.end], "int twice(int x)");
+        assert_eq!(
+            &source[errors[0].span.start..errors[0].span.end],
+            "extern double twice(int x);"
+        );
+        assert_eq!(
+            error_kinds("extern int f(void v);"),
+            [SemaErrorKind::VoidVariable {
+                name: "v".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_scan_targets() {
+        // Reading a variable assigns it
+        let source =
+            "double d;\nint main() {\n    int x;\n    scan(x);\n    scan(d);\n    return x;\n}\n";
+        assert_eq!(check_source(source), Ok(Vec::new()));
+        // Only numbers are read, and not into constants
+        assert_eq!(
+            error_kinds("int main() {\n    string s;\n    scan(s);\n    const int c = 1;\n    scan(c);\n    scan(y);\n    return 0;\n}\n"),
+            [
+                SemaErrorKind::InvalidOperand {
+                    operator: "scan".to_string(),
+                    found: Type::String,
+                },
+                SemaErrorKind::AssignToConst {
+                    name: "c".to_string()
+                },
+                SemaErrorKind::UndefinedVariable {
+                    name: "y".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_asm_operands() {
+        let source = "int main() {\n    int x;\n    double d = 2.0;\n    asm(\"sqrtsd %1, %0\" : \"=r\"(d) : \"r\"(d));\n    asm(\"movl %1, %0\" : \"=r\"(x) : \"r\"(1));\n    return x;\n}\n";
+        assert_eq!(check_source(source), Ok(Vec::new()));
+
+        // The output is assigned, and the inputs need a value
+        assert_eq!(
+            error_kinds("int main() {\n    asm(\"movl $1, %0\" : \"=r\"(y));\n    return 0;\n}\n"),
+            [SemaErrorKind::UndefinedVariable {
+                name: "y".to_string()
+            }]
+        );
+        assert_eq!(
+            error_kinds("int main() {\n    const int x = 1;\n    asm(\"movl $1, %0\" : \"=r\"(x));\n    return x;\n}\n"),
+            [SemaErrorKind::AssignToConst {
+                name: "x".to_string()
+            }]
+        );
+        assert_eq!(
+            error_kinds("void f() {}\nint main() {\n    asm(\"pushq %0\" : : \"r\"(f()));\n    return 0;\n}\n"),
+            [SemaErrorKind::InvalidOperand {
+                operator: "asm".to_string(),
+                found: Type::Void,
+            }]
+        );
+        // A register named by a constraint is a general-purpose one, which can't hold a double
+        assert_eq!(
+            error_kinds("int main() {\n    double d;\n    asm(\"movsd %1, %0\" : \"=r\"(d) : \"a\"(1.5));\n    asm(\"nop\" : \"=c\"(d));\n    return 0;\n}\n"),
+            [
+                SemaErrorKind::InvalidOperand {
+                    operator: "asm constraint a".to_string(),
+                    found: Type::Double,
+                },
+                SemaErrorKind::InvalidOperand {
+                    operator: "asm constraint c".to_string(),
+                    found: Type::Double,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_main_signature() {
+        assert_eq!(
+            error_kinds("void main(int argc) { }"),
+            [SemaErrorKind::InvalidMain]
+        );
+        assert_eq!(
+            error_kinds("static int main() { return 0; }"),
+            [SemaErrorKind::InvalidMain]
+        );
+    }
+
+    #[test]
+    fn test_unused_variables() {
+        let source = "int f(int used, int unused, int _ignored) {\nint x = 1;\nint y;\ny = used;\n{ int z = 2; }\nint _w = 3;\nreturn x;\n}";
+        let warnings: Vec<SemaWarningKind> = check_source(source)
+            .unwrap()
+            .into_iter()
+            .map(|warning| warning.kind)
+            .collect();
+        // Assigning to a variable doesn't count as reading it
+        assert_eq!(
+            warnings,
+            [
+                SemaWarningKind::UnusedVariable {
+                    name: "z".to_string()
+                },
+                SemaWarningKind::UnusedParameter {
+                    name: "unused".to_string()
+                },
+                SemaWarningKind::UnusedVariable {
+                    name: "y".to_string()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use rust_compiler::source_map::{SourceMap, Span};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "int main() {\n    return 0;\n}\n";
+
+    #[test]
+    fn test_line_col() {
+        let map = SourceMap::new("main.c0", SOURCE);
+
+        assert_eq!(map.line_col(0), (1, 1));
+        assert_eq!(map.line_col(4), (1, 5)); // `main`
+        assert_eq!(map.line_col(13), (2, 1)); // start of second line
+        assert_eq!(map.line_col(17), (2, 5)); // `return`
+        assert_eq!(map.line_col(27), (3, 1)); // `}`
+    }
+
+    #[test]
+    fn test_line_col_past_end() {
+        let map = SourceMap::new("main.c0", SOURCE);
+        assert_eq!(map.line_col(1000), (4, 1));
+    }
+
+    #[test]
+    fn test_line_col_counts_chars() {
+        let map = SourceMap::new("utf8.c0", "print(\"é\"); x");
+        // `é` is two bytes but one column
+        assert_eq!(map.line_col(13), (1, 13));
+    }
+
+    #[test]
+    fn test_line_text() {
+        let map = SourceMap::new("main.c0", "a;\r\nb;\nc;");
+
+        assert_eq!(map.line_count(), 3);
+        assert_eq!(map.line_text(1), "a;");
+        assert_eq!(map.line_text(2), "b;");
+        assert_eq!(map.line_text(3), "c;");
+        assert_eq!(map.line_text(4), "");
+    }
+
+    #[test]
+    fn test_span_to() {
+        let span = Span::new(4, 8).to(Span::new(2, 5));
+        assert_eq!(span, Span::new(2, 8));
+    }
+    #[test]
+    fn test_underline() {
+        let map = SourceMap::new("main.c0", "int main() {\n\treturn 0;\n}\n");
+
+        // `return`, after a tab
+        let underline = map.underline(14, 6);
+        assert_eq!(underline.line, 2);
+        assert_eq!(underline.text, "\treturn 0;");
+        assert_eq!(underline.indent, "\t");
+        assert_eq!(underline.width, 6);
+        // The underline stops at the end of the line, and there's always some
+        assert_eq!(map.underline(0, 100).width, 12);
+        assert_eq!(map.underline(21, 0).width, 1);
+    }
+}
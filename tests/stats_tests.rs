@@ -0,0 +1,111 @@
+use rust_compiler::stats::{diff, regressions, Change, FunctionStats, Stats};
+
+fn function(name: &str, instructions: usize, spills: usize, emitted: usize) -> FunctionStats {
+    FunctionStats {
+        name: name.to_string(),
+        instructions,
+        spills: Some(spills),
+        emitted: Some(emitted),
+    }
+}
+
+fn stats(functions: Vec<FunctionStats>) -> Stats {
+    Stats {
+        target: "x86_64-unknown-linux-gnu".to_string(),
+        functions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let stats = Stats {
+            target: "abstract".to_string(),
+            functions: vec![
+                function("main", 12, 0, 20),
+                FunctionStats::new("odd \"name\"", 3),
+                FunctionStats::new("line\nbreak\ttab\r", 1),
+            ],
+        };
+        let json = stats.to_json();
+        assert!(json.contains("\"line\\nbreak\\ttab\\r\""), "{}", json);
+        assert_eq!(Stats::parse(&json), Ok(stats));
+    }
+
+    #[test]
+    fn test_regressions() {
+        let old = stats(vec![function("main", 10, 2, 20), function("f", 5, 0, 8)]);
+        let new = stats(vec![function("main", 9, 3, 20), function("f", 6, 0, 7)]);
+        assert_eq!(
+            regressions(&old, &new),
+            vec![
+                Change {
+                    function: "main".to_string(),
+                    measure: "spills",
+                    old: 2,
+                    new: 3,
+                },
+                Change {
+                    function: "f".to_string(),
+                    measure: "instructions",
+                    old: 5,
+                    new: 6,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = stats(vec![function("main", 10, 2, 20), function("f", 5, 0, 8)]);
+        let new = stats(vec![function("main", 12, 1, 18), function("g", 1, 0, 1)]);
+        assert_eq!(
+            diff(&old, &new),
+            "main: instructions 10 -> 12 (+2)\n\
+             f: removed\n\
+             g: added\n\
+             total instructions: 10 -> 12 (+2)\n\
+             total spills: 2 -> 1 (-1)\n\
+             total emitted: 20 -> 18 (-2)\n\
+             1 regression(s)\n"
+        );
+    }
+
+    #[test]
+    fn test_diff_leaves_out_missing_measures() {
+        let old = Stats {
+            target: "abstract".to_string(),
+            functions: vec![FunctionStats::new("main", 4)],
+        };
+        let new = stats(vec![function("main", 4, 0, 6)]);
+        assert_eq!(
+            diff(&old, &new),
+            "targets differ: abstract -> x86_64-unknown-linux-gnu\n\
+             total instructions: 4 -> 4 (+0)\n\
+             0 regression(s)\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = Stats::parse("{\"target\": \"abstract\", \"functions\": [}").unwrap_err();
+        assert_eq!(error.offset, 37);
+
+        let error = Stats::parse("[]").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "at byte 0: the statistics aren't an object"
+        );
+
+        let error = Stats::parse(
+            "{\"target\": \"abstract\", \"functions\": [{\"name\": \"f\", \"spills\": \"x\"}]}",
+        )
+        .unwrap_err();
+        assert_eq!(error.message, "spills of 'f' isn't a count");
+
+        assert!(Stats::parse("{\"target\": \"abstract\", \"functions\": []} x").is_err());
+    }
+}
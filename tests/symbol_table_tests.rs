@@ -0,0 +1,43 @@
+use rust_compiler::symbol_table::SymbolTable;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inner_scope_hides_outer() {
+        let mut table = SymbolTable::new();
+        table.push_scope();
+        table.insert("x", 1);
+
+        table.push_scope();
+        assert_eq!(table.get("x"), Some(&1));
+        // Hiding an outer binding doesn't replace it
+        assert_eq!(table.insert("x", 2), None);
+        assert_eq!(table.get("x"), Some(&2));
+
+        table.pop_scope();
+        assert_eq!(table.get("x"), Some(&1));
+    }
+
+    #[test]
+    fn test_declarations_end_with_their_scope() {
+        let mut table = SymbolTable::new();
+        table.push_scope();
+        table.push_scope();
+        table.insert("inner", ());
+        assert!(table.contains("inner"));
+
+        table.pop_scope();
+        assert!(!table.contains("inner"));
+    }
+
+    #[test]
+    fn test_redeclaration_in_same_scope() {
+        let mut table = SymbolTable::new();
+        table.push_scope();
+        assert_eq!(table.insert("x", 1), None);
+        assert_eq!(table.insert("x", 2), Some(1));
+        assert_eq!(table.get("x"), Some(&2));
+    }
+}
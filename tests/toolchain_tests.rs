@@ -0,0 +1,42 @@
+use rust_compiler::toolchain::{linker_messages, ToolchainError};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linker_messages() {
+        let stderr = "/usr/bin/ld: /tmp/ccQ1.o: in function `main':
+t.S:(.text+0xa): undefined reference to `c0_print_int'
+/usr/bin/ld: t.S:(.text+0x19): undefined reference to `c0_print_string'
+collect2: error: ld returned 1 exit status
+";
+        assert_eq!(
+            linker_messages(stderr),
+            [
+                "t.S:(.text+0xa): undefined reference to `c0_print_int' (in function 'main')",
+                "t.S:(.text+0x19): undefined reference to `c0_print_string' (in function 'main')",
+            ]
+        );
+
+        let stderr = "ld.lld: error: unable to find library -lmissing
+clang: error: linker command failed with exit code 1 (use -v to see invocation)
+";
+        assert_eq!(
+            linker_messages(stderr),
+            ["error: unable to find library -lmissing"]
+        );
+    }
+
+    #[test]
+    fn test_link_failures_are_diagnostics() {
+        let error = ToolchainError::LinkFailed {
+            messages: vec!["one".to_string(), "two".to_string()],
+        };
+        let diagnostics = error.diagnostics();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[1].message, "two");
+        assert_eq!(diagnostics[1].code, Some("E0203"));
+        assert_eq!(ToolchainError::NotFound.diagnostics().len(), 1);
+    }
+}
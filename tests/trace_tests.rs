@@ -0,0 +1,48 @@
+use rust_compiler::trace::{Filter, Level};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALLOCATOR: &str = "rust_compiler::codegen::register_allocator";
+
+    #[test]
+    fn test_level_applies_to_every_module() {
+        let filter = Filter::parse("info");
+        assert!(filter.enabled(Level::Error, ALLOCATOR));
+        assert!(filter.enabled(Level::Info, "rust_compiler"));
+        assert!(!filter.enabled(Level::Debug, ALLOCATOR));
+        assert!(!Filter::parse("").enabled(Level::Error, ALLOCATOR));
+    }
+
+    #[test]
+    fn test_longest_module_prefix_wins() {
+        let filter =
+            Filter::parse("warn,rust_compiler::codegen=trace,rust_compiler::codegen::pass=info");
+        assert!(filter.enabled(Level::Trace, ALLOCATOR));
+        assert!(filter.enabled(Level::Trace, "rust_compiler::codegen"));
+        assert!(!filter.enabled(Level::Debug, "rust_compiler::codegen::pass"));
+        assert!(filter.enabled(Level::Info, "rust_compiler::codegen::pass"));
+        assert!(!filter.enabled(Level::Info, "rust_compiler::parser"));
+        // A prefix only matches whole path segments
+        assert!(!filter.enabled(Level::Trace, "rust_compiler::codegenerator"));
+    }
+
+    #[test]
+    fn test_unknown_directives_are_ignored() {
+        let filter = Filter::parse("loud, rust_compiler=chatty ,debug");
+        assert_eq!(filter, Filter::parse("debug"));
+    }
+
+    #[test]
+    fn test_default_level() {
+        let filter = Filter::parse(&format!("{}=trace", ALLOCATOR)).with_default(Level::Debug);
+        assert!(filter.enabled(Level::Trace, ALLOCATOR));
+        assert!(filter.enabled(Level::Debug, "rust_compiler::codegen::pass"));
+        assert!(!filter.enabled(Level::Trace, "rust_compiler::codegen::pass"));
+
+        // A level for every module that's already set is kept
+        let filter = Filter::parse("error").with_default(Level::Debug);
+        assert!(!filter.enabled(Level::Warn, ALLOCATOR));
+    }
+}
@@ -0,0 +1,565 @@
+use rust_compiler::codegen::x86_encoding::{
+    serialize_op, Condition, Memory, Op, RegOrMem, Register,
+};
+
+/// Bytes `op` is encoded as
+fn encode(op: Op) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    serialize_op(&mut bytes, op);
+    bytes
+}
+
+fn reg(register: Register) -> RegOrMem {
+    RegOrMem::Register(register)
+}
+
+fn mem(base: Register, displacement: i32) -> RegOrMem {
+    RegOrMem::Memory(Memory::base(base, displacement))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The expected bytes are what GNU as assembles the instruction in each comment to
+
+    #[test]
+    fn test_mov_between_registers() {
+        // movq %rsi, %rax
+        assert_eq!(
+            encode(Op::Mov(reg(Register::RAX), reg(Register::RSI))),
+            [0x48, 0x89, 0xf0]
+        );
+        // movq %rax, %r8
+        assert_eq!(
+            encode(Op::Mov(reg(Register::R8), reg(Register::RAX))),
+            [0x49, 0x89, 0xc0]
+        );
+        // movl %r9d, %eax
+        assert_eq!(
+            encode(Op::Mov(reg(Register::EAX), reg(Register::R9D))),
+            [0x44, 0x89, 0xc8]
+        );
+        // movl %eax, %r15d
+        assert_eq!(
+            encode(Op::Mov(reg(Register::R15D), reg(Register::EAX))),
+            [0x41, 0x89, 0xc7]
+        );
+        // movl %ecx, %edx has no prefix
+        assert_eq!(
+            encode(Op::Mov(reg(Register::EDX), reg(Register::ECX))),
+            [0x89, 0xca]
+        );
+        // movw %r11w, %cx
+        assert_eq!(
+            encode(Op::Mov(reg(Register::CX), reg(Register::R11W))),
+            [0x66, 0x44, 0x89, 0xd9]
+        );
+    }
+
+    #[test]
+    fn test_byte_registers() {
+        // movb %sil, %al needs an empty REX prefix, or it would be %dh
+        assert_eq!(
+            encode(Op::Mov(reg(Register::AL), reg(Register::SIL))),
+            [0x40, 0x88, 0xf0]
+        );
+        // movb %dh, %al
+        assert_eq!(
+            encode(Op::Mov(reg(Register::AL), reg(Register::DH))),
+            [0x88, 0xf0]
+        );
+        // movb %al, %r10b
+        assert_eq!(
+            encode(Op::Mov(reg(Register::R10B), reg(Register::AL))),
+            [0x41, 0x88, 0xc2]
+        );
+        // movb $7, %dil
+        assert_eq!(
+            encode(Op::Mov(reg(Register::DIL), RegOrMem::Immediate(7))),
+            [0x40, 0xb7, 0x07]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "REX prefix")]
+    fn test_high_byte_registers_cannot_take_rex() {
+        encode(Op::Mov(reg(Register::AH), reg(Register::SIL)));
+    }
+
+    #[test]
+    fn test_mov_immediate() {
+        // movl $5, %r12d
+        assert_eq!(
+            encode(Op::Mov(reg(Register::R12D), RegOrMem::Immediate(5))),
+            [0x41, 0xbc, 0x05, 0x00, 0x00, 0x00]
+        );
+        // movq $-1, %rax sign-extends a 32-bit immediate
+        assert_eq!(
+            encode(Op::Mov(reg(Register::RAX), RegOrMem::Immediate(-1))),
+            [0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff]
+        );
+        // movq $-1, %r13
+        assert_eq!(
+            encode(Op::Mov(reg(Register::R13), RegOrMem::Immediate(-1))),
+            [0x49, 0xc7, 0xc5, 0xff, 0xff, 0xff, 0xff]
+        );
+        // movw $300, %r9w
+        assert_eq!(
+            encode(Op::Mov(reg(Register::R9W), RegOrMem::Immediate(300))),
+            [0x66, 0x41, 0xb9, 0x2c, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_push_and_pop() {
+        // pushq %rbx
+        assert_eq!(encode(Op::Push(reg(Register::RBX))), [0x53]);
+        // pushq %r12
+        assert_eq!(encode(Op::Push(reg(Register::R12))), [0x41, 0x54]);
+        // popq %r15
+        assert_eq!(encode(Op::Pop(reg(Register::R15))), [0x41, 0x5f]);
+        // popw %r8w
+        assert_eq!(encode(Op::Pop(reg(Register::R8W))), [0x66, 0x41, 0x58]);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't be pushed")]
+    fn test_push_takes_64_bits() {
+        encode(Op::Push(reg(Register::EAX)));
+    }
+
+    #[test]
+    fn test_add() {
+        // addq %r8, %r9
+        assert_eq!(
+            encode(Op::Add(reg(Register::R9), reg(Register::R8))),
+            [0x4d, 0x01, 0xc1]
+        );
+        // addl $1000, %r14d
+        assert_eq!(
+            encode(Op::Add(reg(Register::R14D), RegOrMem::Immediate(1000))),
+            [0x41, 0x81, 0xc6, 0xe8, 0x03, 0x00, 0x00]
+        );
+        // addq $-3, %rsp
+        assert_eq!(
+            encode(Op::Add(reg(Register::RSP), RegOrMem::Immediate(-3))),
+            [0x48, 0x83, 0xc4, 0xfd]
+        );
+        // addb $1, %r11b
+        assert_eq!(
+            encode(Op::Add(reg(Register::R11B), RegOrMem::Immediate(1))),
+            [0x41, 0x80, 0xc3, 0x01]
+        );
+        // addw $300, %bx
+        assert_eq!(
+            encode(Op::Add(reg(Register::BX), RegOrMem::Immediate(300))),
+            [0x66, 0x81, 0xc3, 0x2c, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_base_displacements() {
+        // movq (%rax), %rcx
+        assert_eq!(
+            encode(Op::Mov(reg(Register::RCX), mem(Register::RAX, 0))),
+            [0x48, 0x8b, 0x08]
+        );
+        // movl 8(%rbp), %eax
+        assert_eq!(
+            encode(Op::Mov(reg(Register::EAX), mem(Register::RBP, 8))),
+            [0x8b, 0x45, 0x08]
+        );
+        // movq %r8, 1024(%rbx)
+        assert_eq!(
+            encode(Op::Mov(mem(Register::RBX, 1024), reg(Register::R8))),
+            [0x4c, 0x89, 0x83, 0x00, 0x04, 0x00, 0x00]
+        );
+        // leal -1(%rdi), %eax
+        assert_eq!(
+            encode(Op::Lea(Register::EAX, Memory::base(Register::RDI, -1))),
+            [0x8d, 0x47, 0xff]
+        );
+    }
+
+    #[test]
+    fn test_bases_with_special_encodings() {
+        // movq (%rbp), %rdx and movq (%r13), %rdx take a displacement of 0
+        assert_eq!(
+            encode(Op::Mov(reg(Register::RDX), mem(Register::RBP, 0))),
+            [0x48, 0x8b, 0x55, 0x00]
+        );
+        assert_eq!(
+            encode(Op::Mov(reg(Register::RDX), mem(Register::R13, 0))),
+            [0x49, 0x8b, 0x55, 0x00]
+        );
+        // movq (%rsp), %rdi and movl 16(%r12), %r9d take a SIB byte
+        assert_eq!(
+            encode(Op::Mov(reg(Register::RDI), mem(Register::RSP, 0))),
+            [0x48, 0x8b, 0x3c, 0x24]
+        );
+        assert_eq!(
+            encode(Op::Mov(reg(Register::R9D), mem(Register::R12, 16))),
+            [0x45, 0x8b, 0x4c, 0x24, 0x10]
+        );
+        // movl %eax, -8(%rsp)
+        assert_eq!(
+            encode(Op::Mov(mem(Register::RSP, -8), reg(Register::EAX))),
+            [0x89, 0x44, 0x24, 0xf8]
+        );
+    }
+
+    #[test]
+    fn test_scaled_indexes() {
+        // movq 12(%rax,%rcx,4), %rsi
+        let address = Memory::indexed(Some(Register::RAX), Register::RCX, 4, 12);
+        assert_eq!(
+            encode(Op::Mov(reg(Register::RSI), RegOrMem::Memory(address))),
+            [0x48, 0x8b, 0x74, 0x88, 0x0c]
+        );
+        // movq (%r8,%r15,8), %r10
+        let address = Memory::indexed(Some(Register::R8), Register::R15, 8, 0);
+        assert_eq!(
+            encode(Op::Mov(reg(Register::R10), RegOrMem::Memory(address))),
+            [0x4f, 0x8b, 0x14, 0xf8]
+        );
+        // leaq 8(%rbx,%r12), %rax
+        let address = Memory::indexed(Some(Register::RBX), Register::R12, 1, 8);
+        assert_eq!(
+            encode(Op::Lea(Register::RAX, address)),
+            [0x4a, 0x8d, 0x44, 0x23, 0x08]
+        );
+        // movl 64(,%rdx,2), %eax has no base, and a 32-bit displacement
+        let address = Memory::indexed(None, Register::RDX, 2, 64);
+        assert_eq!(
+            encode(Op::Mov(reg(Register::EAX), RegOrMem::Memory(address))),
+            [0x8b, 0x04, 0x55, 0x40, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "%rsp can't be an index")]
+    fn test_rsp_is_not_an_index() {
+        let address = Memory::indexed(Some(Register::RAX), Register::RSP, 1, 0);
+        encode(Op::Lea(Register::RAX, address));
+    }
+
+    #[test]
+    fn test_rip_relative_and_absolute_addresses() {
+        // leaq 100(%rip), %rdi
+        assert_eq!(
+            encode(Op::Lea(Register::RDI, Memory::rip(100))),
+            [0x48, 0x8d, 0x3d, 0x64, 0x00, 0x00, 0x00]
+        );
+        // movl 0x12345678, %eax
+        let address = Memory {
+            base: None,
+            index: None,
+            scale: None,
+            displacement: 0x12345678,
+            rip_relative: false,
+            bits: 32,
+        };
+        assert_eq!(
+            encode(Op::Mov(reg(Register::EAX), RegOrMem::Memory(address))),
+            [0x8b, 0x04, 0x25, 0x78, 0x56, 0x34, 0x12]
+        );
+    }
+
+    #[test]
+    fn test_memory_operand_sizes() {
+        // movb (%rsi), %sil
+        assert_eq!(
+            encode(Op::Mov(reg(Register::SIL), mem(Register::RSI, 0))),
+            [0x40, 0x8a, 0x36]
+        );
+        // movw %r9w, 2(%rax)
+        assert_eq!(
+            encode(Op::Mov(mem(Register::RAX, 2), reg(Register::R9W))),
+            [0x66, 0x44, 0x89, 0x48, 0x02]
+        );
+        // addq 8(%rsp), %rax
+        assert_eq!(
+            encode(Op::Add(reg(Register::RAX), mem(Register::RSP, 8))),
+            [0x48, 0x03, 0x44, 0x24, 0x08]
+        );
+        // addl %ecx, (%r14)
+        assert_eq!(
+            encode(Op::Add(mem(Register::R14, 0), reg(Register::ECX))),
+            [0x41, 0x01, 0x0e]
+        );
+        // pushq 8(%rbp) and popq (%r11)
+        assert_eq!(encode(Op::Push(mem(Register::RBP, 8))), [0xff, 0x75, 0x08]);
+        assert_eq!(encode(Op::Pop(mem(Register::R11, 0))), [0x41, 0x8f, 0x03]);
+    }
+
+    #[test]
+    fn test_sub_and_cmp() {
+        // subq %rbx, %rax
+        assert_eq!(
+            encode(Op::Sub(reg(Register::RAX), reg(Register::RBX))),
+            [0x48, 0x29, 0xd8]
+        );
+        // subl $5, %r10d
+        assert_eq!(
+            encode(Op::Sub(reg(Register::R10D), RegOrMem::Immediate(5))),
+            [0x41, 0x83, 0xea, 0x05]
+        );
+        // subq 8(%rbp), %rcx
+        assert_eq!(
+            encode(Op::Sub(reg(Register::RCX), mem(Register::RBP, 8))),
+            [0x48, 0x2b, 0x4d, 0x08]
+        );
+        // subl $100000, (%rdi)
+        let address = Memory::base(Register::RDI, 0).with_bits(32);
+        assert_eq!(
+            encode(Op::Sub(
+                RegOrMem::Memory(address),
+                RegOrMem::Immediate(100000)
+            )),
+            [0x81, 0x2f, 0xa0, 0x86, 0x01, 0x00]
+        );
+        // cmpl %esi, %edi
+        assert_eq!(
+            encode(Op::Cmp(reg(Register::EDI), reg(Register::ESI))),
+            [0x39, 0xf7]
+        );
+        // cmpq $0, 16(%rsp)
+        assert_eq!(
+            encode(Op::Cmp(mem(Register::RSP, 16), RegOrMem::Immediate(0))),
+            [0x48, 0x83, 0x7c, 0x24, 0x10, 0x00]
+        );
+        // cmpb $65, %bl
+        assert_eq!(
+            encode(Op::Cmp(reg(Register::BL), RegOrMem::Immediate(65))),
+            [0x80, 0xfb, 0x41]
+        );
+        // cmpq (%rax), %r9
+        assert_eq!(
+            encode(Op::Cmp(reg(Register::R9), mem(Register::RAX, 0))),
+            [0x4c, 0x3b, 0x08]
+        );
+    }
+
+    #[test]
+    fn test_test() {
+        // testl %eax, %eax
+        assert_eq!(
+            encode(Op::Test(reg(Register::EAX), reg(Register::EAX))),
+            [0x85, 0xc0]
+        );
+        // testq %r8, (%rdx), either way around
+        assert_eq!(
+            encode(Op::Test(mem(Register::RDX, 0), reg(Register::R8))),
+            [0x4c, 0x85, 0x02]
+        );
+        assert_eq!(
+            encode(Op::Test(reg(Register::R8), mem(Register::RDX, 0))),
+            [0x4c, 0x85, 0x02]
+        );
+        // testb $1, %cl
+        assert_eq!(
+            encode(Op::Test(reg(Register::CL), RegOrMem::Immediate(1))),
+            [0xf6, 0xc1, 0x01]
+        );
+        // testl $256, %r11d
+        assert_eq!(
+            encode(Op::Test(reg(Register::R11D), RegOrMem::Immediate(256))),
+            [0x41, 0xf7, 0xc3, 0x00, 0x01, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_one_operand_arithmetic() {
+        // mulq %rcx
+        assert_eq!(encode(Op::Mul(reg(Register::RCX))), [0x48, 0xf7, 0xe1]);
+        // divl %r12d
+        assert_eq!(encode(Op::Div(reg(Register::R12D))), [0x41, 0xf7, 0xf4]);
+        // negl %eax
+        assert_eq!(encode(Op::Neg(reg(Register::EAX))), [0xf7, 0xd8]);
+        // negq 8(%rbp)
+        assert_eq!(
+            encode(Op::Neg(mem(Register::RBP, 8))),
+            [0x48, 0xf7, 0x5d, 0x08]
+        );
+        // incl %r15d
+        assert_eq!(encode(Op::Inc(reg(Register::R15D))), [0x41, 0xff, 0xc7]);
+        // decq %rax
+        assert_eq!(encode(Op::Dec(reg(Register::RAX))), [0x48, 0xff, 0xc8]);
+        // incb (%rsi)
+        let address = Memory::base(Register::RSI, 0).with_bits(8);
+        assert_eq!(encode(Op::Inc(RegOrMem::Memory(address))), [0xfe, 0x06]);
+    }
+
+    #[test]
+    fn test_conditional_jumps() {
+        assert_eq!(encode(Op::Je(0x10)), [0x74, 0x10]);
+        assert_eq!(encode(Op::Jne(-16)), [0x75, 0xf0]);
+        assert_eq!(encode(Op::Jge(0x1e)), [0x7d, 0x1e]);
+        assert_eq!(encode(Op::Jle(0)), [0x7e, 0x00]);
+        // Offsets that don't fit in a byte take the near form
+        assert_eq!(encode(Op::Jl(0x1000)), [0x0f, 0x8c, 0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(encode(Op::Jg(0x2fa)), [0x0f, 0x8f, 0xfa, 0x02, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_frames_strings_and_system() {
+        // enter $32, $0
+        assert_eq!(encode(Op::Enter(32, 0)), [0xc8, 0x20, 0x00, 0x00]);
+        assert_eq!(encode(Op::Leave), [0xc9]);
+        // rep movsb
+        let mut bytes = Vec::new();
+        serialize_op(&mut bytes, Op::Rep);
+        serialize_op(&mut bytes, Op::Movsb);
+        assert_eq!(bytes, [0xf3, 0xa4]);
+        // movsw and movsl
+        assert_eq!(encode(Op::Movsw), [0x66, 0xa5]);
+        assert_eq!(encode(Op::Movsd), [0xa5]);
+        // int $0x80
+        assert_eq!(encode(Op::Int(0x80)), [0xcd, 0x80]);
+        assert_eq!(encode(Op::Syscall), [0x0f, 0x05]);
+    }
+
+    #[test]
+    fn test_immediates_to_memory() {
+        // movl $7, 8(%rbx)
+        let address = Memory::base(Register::RBX, 8).with_bits(32);
+        assert_eq!(
+            encode(Op::Mov(RegOrMem::Memory(address), RegOrMem::Immediate(7))),
+            [0xc7, 0x43, 0x08, 0x07, 0x00, 0x00, 0x00]
+        );
+        // movq $-1, (%rsp)
+        assert_eq!(
+            encode(Op::Mov(mem(Register::RSP, 0), RegOrMem::Immediate(-1))),
+            [0x48, 0xc7, 0x04, 0x24, 0xff, 0xff, 0xff, 0xff]
+        );
+        // movb $3, (%rax)
+        let address = Memory::base(Register::RAX, 0).with_bits(8);
+        assert_eq!(
+            encode(Op::Mov(RegOrMem::Memory(address), RegOrMem::Immediate(3))),
+            [0xc6, 0x00, 0x03]
+        );
+        // addw $3, 4(%rdx)
+        let address = Memory::base(Register::RDX, 4).with_bits(16);
+        assert_eq!(
+            encode(Op::Add(RegOrMem::Memory(address), RegOrMem::Immediate(3))),
+            [0x66, 0x83, 0x42, 0x04, 0x03]
+        );
+    }
+
+    #[test]
+    fn test_multiply_divide_and_logic() {
+        // imull %r9d, %eax
+        assert_eq!(
+            encode(Op::Imul(Register::EAX, reg(Register::R9D))),
+            [0x41, 0x0f, 0xaf, 0xc1]
+        );
+        // imull $100, 8(%rbp), %ecx, with the immediate in a byte
+        let address = Memory::base(Register::RBP, 8).with_bits(32);
+        assert_eq!(
+            encode(Op::ImulImm(Register::ECX, RegOrMem::Memory(address), 100)),
+            [0x6b, 0x4d, 0x08, 0x64]
+        );
+        // imull $1000, %esi, %r10d
+        assert_eq!(
+            encode(Op::ImulImm(Register::R10D, reg(Register::ESI), 1000)),
+            [0x44, 0x69, 0xd6, 0xe8, 0x03, 0x00, 0x00]
+        );
+        // cltd; idivl %r11d
+        assert_eq!(encode(Op::Cdq), [0x99]);
+        assert_eq!(encode(Op::Idiv(reg(Register::R11D))), [0x41, 0xf7, 0xfb]);
+        // andl $-8, %edi
+        assert_eq!(
+            encode(Op::And(reg(Register::EDI), RegOrMem::Immediate(-8))),
+            [0x83, 0xe7, 0xf8]
+        );
+        // orl %ecx, %r12d
+        assert_eq!(
+            encode(Op::Or(reg(Register::R12D), reg(Register::ECX))),
+            [0x41, 0x09, 0xcc]
+        );
+        // notl %eax
+        assert_eq!(encode(Op::Not(reg(Register::EAX))), [0xf7, 0xd0]);
+    }
+
+    #[test]
+    fn test_shifts_and_sets() {
+        // sall $3, %ebx
+        assert_eq!(encode(Op::Shl(reg(Register::EBX), 3)), [0xc1, 0xe3, 0x03]);
+        // sarl $1, %edx has a form without the immediate
+        assert_eq!(encode(Op::Sar(reg(Register::EDX), 1)), [0xd1, 0xfa]);
+        // shrl $31, %r8d
+        assert_eq!(
+            encode(Op::Shr(reg(Register::R8D), 31)),
+            [0x41, 0xc1, 0xe8, 0x1f]
+        );
+        // setl %sil needs an empty REX prefix, and setp %al none
+        assert_eq!(
+            encode(Op::Set(Condition::L, reg(Register::SIL))),
+            [0x40, 0x0f, 0x9c, 0xc6]
+        );
+        assert_eq!(
+            encode(Op::Set(Condition::P, reg(Register::AL))),
+            [0x0f, 0x9a, 0xc0]
+        );
+        // movzbl %sil, %esi
+        assert_eq!(
+            encode(Op::Movzx(Register::ESI, reg(Register::SIL))),
+            [0x40, 0x0f, 0xb6, 0xf6]
+        );
+    }
+
+    #[test]
+    fn test_double_instructions() {
+        // movsd 16(%rsp), %xmm9
+        assert_eq!(
+            encode(Op::MovsdXmm(reg(Register::XMM9), mem(Register::RSP, 16))),
+            [0xf2, 0x44, 0x0f, 0x10, 0x4c, 0x24, 0x10]
+        );
+        // movsd %xmm1, (%rax)
+        assert_eq!(
+            encode(Op::MovsdXmm(mem(Register::RAX, 0), reg(Register::XMM1))),
+            [0xf2, 0x0f, 0x11, 0x08]
+        );
+        // movsd %xmm3, %xmm0
+        assert_eq!(
+            encode(Op::MovsdXmm(reg(Register::XMM0), reg(Register::XMM3))),
+            [0xf2, 0x0f, 0x10, 0xc3]
+        );
+        // addsd %xmm1, %xmm2
+        assert_eq!(
+            encode(Op::Addsd(Register::XMM2, reg(Register::XMM1))),
+            [0xf2, 0x0f, 0x58, 0xd1]
+        );
+        // subsd 8(%rbp), %xmm15
+        assert_eq!(
+            encode(Op::Subsd(Register::XMM15, mem(Register::RBP, 8))),
+            [0xf2, 0x44, 0x0f, 0x5c, 0x7d, 0x08]
+        );
+        // mulsd %xmm8, %xmm0
+        assert_eq!(
+            encode(Op::Mulsd(Register::XMM0, reg(Register::XMM8))),
+            [0xf2, 0x41, 0x0f, 0x59, 0xc0]
+        );
+        // divsd %xmm2, %xmm3
+        assert_eq!(
+            encode(Op::Divsd(Register::XMM3, reg(Register::XMM2))),
+            [0xf2, 0x0f, 0x5e, 0xda]
+        );
+        // cvtsi2sdl %r13d, %xmm4
+        assert_eq!(
+            encode(Op::Cvtsi2sd(Register::XMM4, reg(Register::R13D))),
+            [0xf2, 0x41, 0x0f, 0x2a, 0xe5]
+        );
+        // cvttsd2si %xmm12, %eax
+        assert_eq!(
+            encode(Op::Cvttsd2si(Register::EAX, reg(Register::XMM12))),
+            [0xf2, 0x41, 0x0f, 0x2c, 0xc4]
+        );
+        // ucomisd %xmm1, %xmm10
+        assert_eq!(
+            encode(Op::Ucomisd(Register::XMM10, reg(Register::XMM1))),
+            [0x66, 0x44, 0x0f, 0x2e, 0xd1]
+        );
+    }
+}